@@ -0,0 +1,57 @@
+//! Benchmark of the parallel export of wide records (see `Program::set_export_jobs`).
+//!
+//! Export records with an increasing number of fields, each of them costly to evaluate, with an
+//! increasing number of threads, and print the time taken by each export. Run with `cargo bench
+//! --bench wide_records`.
+use nickel::program::Program;
+use nickel::serialize::ExportOptions;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/// The numbers of fields of the exported records.
+const WIDTHS: [usize; 3] = [8, 32, 128];
+/// The numbers of threads of the export.
+const JOBS: [usize; 4] = [1, 2, 4, 8];
+/// The number of exports of each record, the best of which is kept.
+const RUNS: usize = 3;
+
+/// A record of `width` fields, each summing a list of numbers with a contract on each element.
+fn wide_record(width: usize) -> String {
+    let fields: Vec<String> = (0..width)
+        .map(|i| {
+            format!(
+                "f{} = lists.foldl (fun acc x => acc + x) {} (map (fun x => Assume(Num, x)) (listRange 0 200 1))",
+                i, i
+            )
+        })
+        .collect();
+    format!("{{ {} }}", fields.join("; "))
+}
+
+/// Export a program with `jobs` threads, and return the time taken.
+fn export(source: &str, jobs: usize) -> Duration {
+    let mut p = Program::new_from_source(Cursor::new(source), "<bench>").unwrap();
+    p.set_export_jobs(jobs);
+    let mut out = Vec::new();
+
+    let start = Instant::now();
+    p.export(&ExportOptions::default(), &mut out).unwrap();
+    start.elapsed()
+}
+
+fn main() {
+    println!("{:>8} {:>6} {:>12}", "fields", "jobs", "time (ms)");
+
+    for width in WIDTHS.iter() {
+        let source = wide_record(*width);
+        for jobs in JOBS.iter() {
+            let best = (0..RUNS).map(|_| export(&source, *jobs)).min().unwrap();
+            println!(
+                "{:>8} {:>6} {:>12.1}",
+                width,
+                jobs,
+                best.as_secs_f64() * 1000.
+            );
+        }
+    }
+}
@@ -0,0 +1,17 @@
+//! Typecheck and evaluate arbitrary programs.
+//!
+//! Most random inputs are rejected by the parser. To reach the typechecker and the evaluator more
+//! often, run this target with the dictionary of Nickel tokens and the examples as a seed corpus:
+//!
+//! ```
+//! cargo +nightly fuzz run eval ../src/examples -- -dict=nickel.dict
+//! ```
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use nickel::program::Program;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut program) = Program::new_from_source(data, "<fuzz>") {
+        let _ = program.eval();
+    }
+});
@@ -0,0 +1,18 @@
+//! Feed arbitrary strings to the lexer and the parser, and build the diagnostics of parse errors.
+#![no_main]
+use codespan::Files;
+use libfuzzer_sys::fuzz_target;
+use nickel::error::{ParseError, ToDiagnostic};
+use nickel::parser::grammar::TermParser;
+use nickel::parser::lexer::Lexer;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(src) = std::str::from_utf8(data) {
+        let mut files = Files::new();
+        let file_id = files.add("<fuzz>", String::from(src));
+
+        if let Err(err) = TermParser::new().parse(file_id, Lexer::new(src)) {
+            ParseError::from_lalrpop(err, file_id).to_diagnostic(&mut files, None);
+        }
+    }
+});
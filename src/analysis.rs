@@ -0,0 +1,1002 @@
+//! Static analyses of Nickel terms.
+//!
+//! These analyses are run on the parsed program before its evaluation. They do not reject any
+//! program, but produce [warnings](../error/enum.Warning.html) about code which is valid, but
+//! likely not to behave as intended.
+//!
+//! # Unused contracts
+//!
+//! Nickel is lazy: a contract application is only checked when its result is forced. A contract
+//! applied to an expression whose value is discarded is thus never checked, which may give a false
+//! sense of validation coverage. The following situations are detected:
+//!
+//! - A contract appears in the definition of a variable which is never used, as in `let x =
+//!   Assume(Num, value) in body`, where `x` does not appear in `body`, or in the argument of a
+//!   function which ignores it
+//! - A contract appears in a default value which is always overridden by a merge, as in `merge {a =
+//!   Default(Assume(Num, value))} {a = 1}`
+//!
+//! # Dead fields
+//!
+//! Configurations tend to accumulate definitions which do not matter anymore. A definition of a
+//! field is dead when it never contributes to the final record, because it is shadowed: a default
+//! value merged with a definition of the same field, as in `merge {a = Default(1)} {a = 2}`, or
+//! any definition of a field overridden from the outside of the program. Every field of the
+//! final record is exported, so shadowed definitions are the only ones which cannot be observed.
+//! Records are followed through merges and imports (see [`dead_fields`](fn.dead_fields.html)).
+//!
+//! # Arity mismatches
+//!
+//! Outside of `Promise` blocks, applying a function to the wrong number of arguments is only
+//! detected at run time, if ever: a partial application is a valid value, and only fails when it
+//! is used as a number or a string. The number of parameters of functions bound by a `let` to a
+//! `fun` literal, or applied directly, is statically known, and the following situations are
+//! detected (see [`arity_mismatches`](fn.arity_mismatches.html)):
+//!
+//! - A function whose body is not a function, such as a record or an arithmetic operation, or a
+//!   primitive operation which does not return a function, is applied to more arguments than it
+//!   takes, as in `let f = fun x => x + 1 in f 1 2`
+//! - A function is applied to fewer arguments than it takes, and the result is used where a
+//!   function is never valid, as in `let f = fun x y => x + y in f 1 + 2`
+//!
+//! Primitive operations are always fully applied by the parser, and cannot be given too few
+//! arguments.
+//!
+//! # Completion of field values
+//!
+//! The contracts of a record field are used to suggest its value while it is being written: the
+//! tags of an enum contract, or the fields required by a record contract which are not defined
+//! yet (see [`completions`](fn.completions.html)). Contracts are gathered statically, from the
+//! annotations of the field and from the other definitions it is merged with, as in
+//! `merge {color = Contract(< (| red, green, |) >)} {color = `red}`. Contracts defined by arbitrary
+//! expressions are not evaluated, and thus ignored.
+use crate::collections::{HashMap, HashSet};
+use crate::error::Warning;
+use crate::identifier::Ident;
+use crate::position::RawSpan;
+use crate::term::{
+    BinaryOp, MergePriority, MetaValue, RecordData, RichTerm, StrChunk, Term, UnaryOp,
+};
+use crate::types::{AbsType, Types};
+use std::path::PathBuf;
+
+/// Compute the set of free variables of a term (see the
+/// [`free_vars`](../transformations/free_vars/index.html) module).
+pub fn free_vars(rt: &RichTerm) -> HashSet<Ident> {
+    crate::transformations::free_vars::free_vars(rt)
+}
+
+/// Detect contract applications whose result is never used, and which are hence never checked.
+pub fn unused_contracts(rt: &RichTerm) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    unused_contracts_(rt, &mut warnings);
+    warnings
+}
+
+fn unused_contracts_(rt: &RichTerm, warnings: &mut Vec<Warning>) {
+    match rt.as_ref() {
+        Term::Let(id, t1, t2) | Term::LetRec(id, t1, t2) if !free_vars(t2).contains(id) => {
+            contracts_of(t1).into_iter().for_each(|span| {
+                warnings.push(Warning::UnusedContract(id.clone(), span, rt.pos.clone()))
+            });
+        }
+        Term::App(f, arg) => {
+            if let Term::Fun(id, body) = f.as_ref() {
+                if !free_vars(body).contains(id) {
+                    contracts_of(arg).into_iter().for_each(|span| {
+                        warnings.push(Warning::UnusedContract(id.clone(), span, f.pos.clone()))
+                    });
+                }
+            }
+        }
+        Term::Op2(BinaryOp::Merge(), t1, t2) => {
+            overridden_defaults(t1, t2, warnings);
+            overridden_defaults(t2, t1, warnings);
+        }
+        _ => (),
+    }
+
+    for t in subterms(rt.as_ref()) {
+        unused_contracts_(t, warnings);
+    }
+}
+
+/// Detect the default values of the record literal `rec` which are overridden by a definition
+/// of the record literal `other` when both are merged.
+fn overridden_defaults(rec: &RichTerm, other: &RichTerm, warnings: &mut Vec<Warning>) {
+    let (fields, other_fields) = match (record_fields(rec), record_fields(other)) {
+        (Some(fields), Some(other_fields)) => (fields, other_fields),
+        _ => return,
+    };
+
+    for (id, t) in fields.iter() {
+        let other_t = match other_fields.get(id) {
+            Some(other_t) => other_t,
+            None => continue,
+        };
+
+        match (default_value(t), other_t.as_ref()) {
+            (Some(default), other) if !other.is_enriched() => {
+                contracts_of(default).into_iter().for_each(|span| {
+                    warnings.push(Warning::OverriddenDefaultContract(
+                        id.clone(),
+                        span,
+                        other_t.pos.clone(),
+                    ))
+                });
+            }
+            (None, _) => overridden_defaults(t, other_t, warnings),
+            _ => (),
+        }
+    }
+}
+
+/// Detect the definitions of the fields of the final record which are shadowed, and thus never
+/// contribute to its value.
+///
+/// `overrides` are the paths of the fields overridden from the outside of the program. `load` is
+/// called on imports, with the path of the import and the path of the importing file if any. It
+/// returns the parsed imported term together with its path, or `None` if it cannot be loaded, in
+/// which case the fields it defines are ignored.
+pub fn dead_fields<F>(rt: &RichTerm, overrides: &[Vec<Ident>], load: &mut F) -> Vec<Warning>
+where
+    F: FnMut(&str, Option<PathBuf>) -> Option<(RichTerm, PathBuf)>,
+{
+    let mut loader = Loader {
+        load,
+        visiting: Vec::new(),
+    };
+    let mut fields = Fields::default();
+    loader.fields(rt, &None, &mut fields);
+
+    let mut warnings = Vec::new();
+    dead_fields_(
+        fields,
+        &mut Vec::new(),
+        overrides,
+        &mut loader,
+        &mut warnings,
+    );
+    warnings
+}
+
+/// The definitions of the fields of a record, gathered across merges and imports. Each definition
+/// comes with the path of the file it was imported from, if any.
+type Fields = HashMap<Ident, Vec<(RichTerm, Option<PathBuf>)>>;
+
+/// Gather the definitions of the fields of records, loading the imported files.
+struct Loader<'a, F> {
+    load: &'a mut F,
+    /// The imported files being visited, to stop on cyclic imports.
+    visiting: Vec<PathBuf>,
+}
+
+impl<'a, F> Loader<'a, F>
+where
+    F: FnMut(&str, Option<PathBuf>) -> Option<(RichTerm, PathBuf)>,
+{
+    /// Add the definitions of the fields of `rt` to `acc`, if it is statically known to be a
+    /// record. `parent` is the path of the file `rt` comes from.
+    fn fields(&mut self, rt: &RichTerm, parent: &Option<PathBuf>, acc: &mut Fields) {
+        match rt.as_ref() {
+            Term::Record(map) | Term::RecRecord(map) => {
+                for (id, t) in map.iter() {
+                    acc.entry(id.clone())
+                        .or_default()
+                        .push((t.clone(), parent.clone()));
+                }
+            }
+            Term::Op2(BinaryOp::Merge(), t1, t2) => {
+                self.fields(t1, parent, acc);
+                self.fields(t2, parent, acc);
+            }
+            Term::Promise(_, _, t)
+            | Term::Assume(_, _, t)
+            | Term::MetaValue(MetaValue {
+                value: Some(t),
+                priority: MergePriority::Normal,
+                ..
+            })
+            | Term::Let(_, _, t)
+            | Term::LetRec(_, _, t)
+            | Term::LetPattern(_, _, t) => self.fields(t, parent, acc),
+            Term::Import(path) => {
+                if let Some((t, path_buf)) = (self.load)(path, parent.clone()) {
+                    if !self.visiting.contains(&path_buf) {
+                        self.visiting.push(path_buf.clone());
+                        self.fields(&t, &Some(path_buf), acc);
+                        self.visiting.pop();
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn dead_fields_<F>(
+    fields: Fields,
+    path: &mut Vec<Ident>,
+    overrides: &[Vec<Ident>],
+    loader: &mut Loader<F>,
+    warnings: &mut Vec<Warning>,
+) where
+    F: FnMut(&str, Option<PathBuf>) -> Option<(RichTerm, PathBuf)>,
+{
+    let mut fields: Vec<_> = fields.into_iter().collect();
+    fields.sort_by(|(Ident(id1), _), (Ident(id2), _)| id1.cmp(id2));
+
+    for (id, defs) in fields {
+        path.push(id);
+        let name = path
+            .iter()
+            .map(|Ident(id)| id.as_str())
+            .collect::<Vec<_>>()
+            .join(".");
+        // Contracts do not define a value, and are never shadowed.
+        let (defaults, values): (Vec<_>, Vec<_>) = defs
+            .into_iter()
+            .filter(|(t, _)| !is_contract(t))
+            .partition(|(t, _)| default_value(t).is_some());
+
+        if overrides.iter().any(|o| o == path) {
+            for (t, _) in defaults.iter().chain(values.iter()) {
+                warnings.push(Warning::DeadField(name.clone(), t.pos.clone(), None));
+            }
+        } else {
+            if let Some((value, _)) = values.first() {
+                for (t, _) in defaults.iter() {
+                    warnings.push(Warning::DeadField(
+                        name.clone(),
+                        t.pos.clone(),
+                        value.pos.clone(),
+                    ));
+                }
+            }
+
+            let live = if values.is_empty() { defaults } else { values };
+            let mut subfields = Fields::default();
+            for (t, parent) in live.iter() {
+                loader.fields(t, parent, &mut subfields);
+            }
+            dead_fields_(subfields, path, overrides, loader, warnings);
+        }
+
+        path.pop();
+    }
+}
+
+/// Return true if a term is a contract without a value.
+fn is_contract(rt: &RichTerm) -> bool {
+    matches!(rt.as_ref(), Term::MetaValue(meta) if meta.value.is_none() && !meta.contracts.is_empty())
+}
+
+/// Return the fields of a term if it is a record literal.
+fn record_fields(rt: &RichTerm) -> Option<&RecordData> {
+    match rt.as_ref() {
+        Term::Record(map) | Term::RecRecord(map) => Some(map),
+        _ => None,
+    }
+}
+
+/// Return the default value of an enriched value, if any.
+fn default_value(rt: &RichTerm) -> Option<&RichTerm> {
+    match rt.as_ref() {
+        Term::MetaValue(MetaValue {
+            value: Some(t),
+            priority: MergePriority::Default,
+            ..
+        }) => Some(t),
+        _ => None,
+    }
+}
+
+/// Return the positions of the contract applications inside a term.
+fn contracts_of(rt: &RichTerm) -> Vec<Option<RawSpan>> {
+    let mut spans = Vec::new();
+    contracts_of_(rt, &mut spans);
+    spans
+}
+
+fn contracts_of_(rt: &RichTerm, spans: &mut Vec<Option<RawSpan>>) {
+    match rt.as_ref() {
+        Term::Promise(..) | Term::Assume(..) => spans.push(rt.pos.clone()),
+        Term::MetaValue(meta) if meta.value.is_some() && !meta.contracts.is_empty() => {
+            spans.push(rt.pos.clone())
+        }
+        _ => subterms(rt.as_ref())
+            .into_iter()
+            .for_each(|t| contracts_of_(t, spans)),
+    }
+}
+
+/// A function whose number of parameters is statically known.
+#[derive(Clone)]
+struct Arity {
+    params: usize,
+    /// If the body of the function is known not to be a function, in which case a full
+    /// application cannot be applied to more arguments.
+    returns_value: bool,
+    pos: Option<RawSpan>,
+}
+
+/// Detect the applications of functions and primitive operations to a wrong number of arguments,
+/// outside of `Promise` blocks.
+pub fn arity_mismatches(rt: &RichTerm) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    arity_mismatches_(rt, &HashMap::default(), &mut warnings);
+    warnings
+}
+
+fn arity_mismatches_(rt: &RichTerm, env: &HashMap<Ident, Arity>, warnings: &mut Vec<Warning>) {
+    match rt.as_ref() {
+        // Applications inside a `Promise` block are checked by the typechecker.
+        Term::Promise(..) => return,
+        Term::Let(id, t1, t2) => {
+            arity_mismatches_(t1, env, warnings);
+            let mut env = env.clone();
+            match arity_of(t1) {
+                Some(arity) => env.insert(id.clone(), arity),
+                None => env.remove(id),
+            };
+            arity_mismatches_(t2, &env, warnings);
+            return;
+        }
+        Term::LetRec(id, t1, t2) => {
+            let mut env = env.clone();
+            match arity_of(t1) {
+                Some(arity) => env.insert(id.clone(), arity),
+                None => env.remove(id),
+            };
+            arity_mismatches_(t1, &env, warnings);
+            arity_mismatches_(t2, &env, warnings);
+            return;
+        }
+        Term::LetPattern(pat, t1, t2) => {
+            arity_mismatches_(t1, env, warnings);
+            let mut env = env.clone();
+            for id in pat.bindings() {
+                env.remove(id);
+            }
+            arity_mismatches_(t2, &env, warnings);
+            return;
+        }
+        Term::Fun(id, t) => {
+            let mut env = env.clone();
+            env.remove(id);
+            arity_mismatches_(t, &env, warnings);
+            return;
+        }
+        Term::RecRecord(map) => {
+            let mut env = env.clone();
+            map.keys().for_each(|id| {
+                env.remove(id);
+            });
+            map.values()
+                .for_each(|t| arity_mismatches_(t, &env, warnings));
+            return;
+        }
+        Term::App(..) => {
+            let (head, args) = spine(rt);
+            // The operands of a primitive operation are part of the head of the application.
+            let (name, arity, applied) = match head.as_ref() {
+                Term::Var(id) => (Some(id.clone()), env.get(id).cloned(), args.len()),
+                Term::Op1(op, _) if returns_value_op1(op) => {
+                    (None, Some(primop_arity(1)), args.len() + 1)
+                }
+                Term::Op2(op, _, _) if returns_value_op2(op) => {
+                    (None, Some(primop_arity(2)), args.len() + 2)
+                }
+                _ => (None, arity_of(head), args.len()),
+            };
+
+            if let Some(arity) = arity {
+                if arity.returns_value && applied > arity.params {
+                    warnings.push(Warning::ArityMismatch(
+                        name,
+                        arity.params,
+                        applied,
+                        arity.pos,
+                        rt.pos.clone(),
+                    ));
+                }
+            }
+
+            arity_mismatches_(head, env, warnings);
+            args.into_iter()
+                .for_each(|t| arity_mismatches_(t, env, warnings));
+            return;
+        }
+        Term::Op1(op, t) if expects_value_op1(op) => partial_application(t, env, warnings),
+        Term::Op2(op, t1, t2) if expects_value_op2(op) => {
+            partial_application(t1, env, warnings);
+            partial_application(t2, env, warnings);
+        }
+        Term::StrChunks(chunks) => chunks.iter().for_each(|chunk| {
+            if let StrChunk::Expr(t) = chunk {
+                partial_application(t, env, warnings)
+            }
+        }),
+        _ => (),
+    }
+
+    for t in subterms(rt.as_ref()) {
+        arity_mismatches_(t, env, warnings);
+    }
+}
+
+/// Warn if `rt`, which is used where a function is never valid, is a partial application of a
+/// function whose number of parameters is known.
+fn partial_application(rt: &RichTerm, env: &HashMap<Ident, Arity>, warnings: &mut Vec<Warning>) {
+    let (head, args) = spine(rt);
+    let (name, arity) = match head.as_ref() {
+        Term::Var(id) => (Some(id.clone()), env.get(id).cloned()),
+        _ => (None, arity_of(head)),
+    };
+
+    if let Some(arity) = arity {
+        if args.len() < arity.params {
+            warnings.push(Warning::ArityMismatch(
+                name,
+                arity.params,
+                args.len(),
+                arity.pos,
+                rt.pos.clone(),
+            ));
+        }
+    }
+}
+
+/// Decompose an application into its head and its arguments, in order.
+fn spine(rt: &RichTerm) -> (&RichTerm, Vec<&RichTerm>) {
+    let mut head = rt;
+    let mut args = Vec::new();
+
+    while let Term::App(f, arg) = head.as_ref() {
+        args.push(arg);
+        head = f;
+    }
+
+    args.reverse();
+    (head, args)
+}
+
+/// Return the number of parameters of a `fun` literal, which may be annotated or documented.
+fn arity_of(rt: &RichTerm) -> Option<Arity> {
+    match rt.as_ref() {
+        Term::Promise(_, _, t)
+        | Term::Assume(_, _, t)
+        | Term::MetaValue(MetaValue {
+            value: Some(t),
+            priority: MergePriority::Normal,
+            ..
+        }) => arity_of(t),
+        Term::Fun(..) => {
+            let mut body = rt;
+            let mut params = 0;
+
+            while let Term::Fun(_, t) = body.as_ref() {
+                params += 1;
+                body = t;
+            }
+
+            Some(Arity {
+                params,
+                returns_value: is_value(body),
+                pos: rt.pos.clone(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The arity of a saturated primitive operation which does not return a function.
+fn primop_arity(params: usize) -> Arity {
+    Arity {
+        params,
+        returns_value: true,
+        pos: None,
+    }
+}
+
+/// Return true if a term is known to never evaluate to a function.
+fn is_value(rt: &RichTerm) -> bool {
+    match rt.as_ref() {
+        Term::Bool(_)
+        | Term::Num(_)
+        | Term::Str(_)
+        | Term::StrChunks(_)
+        | Term::Enum(_)
+        | Term::Lbl(_)
+        | Term::Sym(_)
+        | Term::List(_)
+        | Term::Record(_)
+        | Term::RecRecord(_) => true,
+        Term::Let(_, _, t)
+        | Term::LetRec(_, _, t)
+        | Term::LetPattern(_, _, t)
+        | Term::MetaValue(MetaValue {
+            value: Some(t),
+            priority: MergePriority::Normal,
+            ..
+        }) => is_value(t),
+        Term::Op1(op, _) => returns_value_op1(op),
+        Term::Op2(op, _, _) => returns_value_op2(op),
+        Term::App(..) => match spine(rt) {
+            (head, args) if args.len() == 2 => {
+                matches!(head.as_ref(), Term::Op1(UnaryOp::Ite(), _))
+                    && args.into_iter().all(is_value)
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Return true if a unary operation never returns a function.
+fn returns_value_op1(op: &UnaryOp<RichTerm>) -> bool {
+    matches!(
+        op,
+        UnaryOp::IsZero()
+            | UnaryOp::IsNum()
+            | UnaryOp::IsBool()
+            | UnaryOp::IsStr()
+            | UnaryOp::IsFun()
+            | UnaryOp::IsList()
+            | UnaryOp::IsRecord()
+            | UnaryOp::TypeOf()
+            | UnaryOp::FieldsOf()
+            | UnaryOp::FieldsWithMetadata()
+            | UnaryOp::FieldMetadata(_)
+            | UnaryOp::ListTail()
+            | UnaryOp::ListLength()
+            | UnaryOp::ListUnique()
+            | UnaryOp::EnumToStr()
+            | UnaryOp::PathBasename()
+            | UnaryOp::PathExtension()
+    )
+}
+
+/// Return true if a binary operation never returns a function.
+fn returns_value_op2(op: &BinaryOp<RichTerm>) -> bool {
+    matches!(
+        op,
+        BinaryOp::Plus()
+            | BinaryOp::PlusStr()
+            | BinaryOp::Eq()
+            | BinaryOp::HasField()
+            | BinaryOp::DynExtend(_)
+            | BinaryOp::DynRemove()
+            | BinaryOp::RecordIntersect()
+            | BinaryOp::RecordDifference()
+            | BinaryOp::RecordFindFields()
+            | BinaryOp::ListConcat()
+            | BinaryOp::ListMap()
+            | BinaryOp::ListZip()
+            | BinaryOp::ListGroupBy()
+            | BinaryOp::ListPartition()
+            | BinaryOp::ListSort()
+            | BinaryOp::ListSortBy()
+            | BinaryOp::PathJoin()
+    )
+}
+
+/// Return true if a function is never a valid operand of a unary operation.
+fn expects_value_op1(op: &UnaryOp<RichTerm>) -> bool {
+    matches!(
+        op,
+        UnaryOp::Ite()
+            | UnaryOp::IsZero()
+            | UnaryOp::StaticAccess(_)
+            | UnaryOp::FieldsOf()
+            | UnaryOp::FieldsWithMetadata()
+            | UnaryOp::ListHead()
+            | UnaryOp::ListTail()
+            | UnaryOp::ListLength()
+    )
+}
+
+/// Return true if a function is never a valid operand of a binary operation.
+fn expects_value_op2(op: &BinaryOp<RichTerm>) -> bool {
+    matches!(
+        op,
+        BinaryOp::Plus()
+            | BinaryOp::PlusStr()
+            | BinaryOp::ListConcat()
+            | BinaryOp::ListElemAt()
+            | BinaryOp::Merge()
+    )
+}
+
+/// A suggestion for the value of a record field, derived from its contracts.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Completion {
+    /// A tag allowed by an enum contract.
+    Tag(Ident),
+    /// A field required by a record contract, and not defined yet.
+    Field(Ident),
+}
+
+/// Suggest values for the record field being written at `span`, from the contracts which apply
+/// to the innermost expression containing `span`. Return an empty list if there is no such
+/// contract, or if it does not constrain the shape of the value.
+pub fn completions(rt: &RichTerm, span: &RawSpan) -> Vec<Completion> {
+    let mut result = None;
+    completions_(rt, span, Shape::default(), &mut result);
+    result.unwrap_or_default()
+}
+
+fn completions_(
+    rt: &RichTerm,
+    span: &RawSpan,
+    inherited: Shape,
+    result: &mut Option<Vec<Completion>>,
+) {
+    if let Some(pos) = &rt.pos {
+        if pos.src_id != span.src_id || pos.start > span.start || pos.end < span.end {
+            return;
+        }
+    }
+
+    let shape = inherited.union(shape_of(rt));
+    if rt.pos.is_some() && shape.has_contracts() {
+        *result = Some(shape.completions(record_fields(rt)));
+    }
+
+    match rt.as_ref() {
+        Term::Record(fields) | Term::RecRecord(fields) => {
+            for (id, t) in fields.iter() {
+                completions_(t, span, shape.field(id), result);
+            }
+        }
+        Term::Op2(BinaryOp::Merge(), t1, t2) => {
+            completions_(t1, span, shape.clone(), result);
+            completions_(t2, span, shape, result);
+        }
+        Term::Promise(_, _, t)
+        | Term::Assume(_, _, t)
+        | Term::MetaValue(MetaValue { value: Some(t), .. }) => completions_(t, span, shape, result),
+        t => subterms(t)
+            .into_iter()
+            .for_each(|t| completions_(t, span, Shape::default(), result)),
+    }
+}
+
+/// The contracts which statically apply to a term, and to its fields if it is a record.
+#[derive(Debug, Default, Clone)]
+struct Shape {
+    contracts: Vec<Types>,
+    fields: HashMap<Ident, Shape>,
+}
+
+impl Shape {
+    /// Combine the contracts of two shapes, as a merge does.
+    fn union(mut self, other: Shape) -> Shape {
+        for ty in other.contracts {
+            if !self.contracts.contains(&ty) {
+                self.contracts.push(ty);
+            }
+        }
+
+        for (id, shape) in other.fields {
+            let merged = match self.fields.remove(&id) {
+                Some(prev) => prev.union(shape),
+                None => shape,
+            };
+            self.fields.insert(id, merged);
+        }
+
+        self
+    }
+
+    fn has_contracts(&self) -> bool {
+        !self.contracts.is_empty() || self.fields.values().any(Shape::has_contracts)
+    }
+
+    /// The shape of the field `id`, given by the record contracts and by the fields of the shape.
+    fn field(&self, id: &Ident) -> Shape {
+        let contracts = self
+            .contracts
+            .iter()
+            .filter_map(|ty| match &ty.0 {
+                AbsType::StaticRecord(row) => row.row_find(id),
+                AbsType::DynRecord(ty) => Some((**ty).clone()),
+                _ => None,
+            })
+            .collect();
+        let shape = Shape {
+            contracts,
+            fields: HashMap::default(),
+        };
+
+        match self.fields.get(id) {
+            Some(field) => shape.union(field.clone()),
+            None => shape,
+        }
+    }
+
+    /// The completions of a value of this shape. If the value is a record literal, the fields it
+    /// already defines are not suggested.
+    fn completions(&self, defined: Option<&RecordData>) -> Vec<Completion> {
+        let mut completions = Vec::new();
+        let mut push = |completion| {
+            if !completions.contains(&completion) {
+                completions.push(completion)
+            }
+        };
+        let is_defined = |id: &Ident| matches!(defined, Some(fields) if fields.contains_key(id));
+
+        for ty in self.contracts.iter() {
+            ty.enum_tags()
+                .into_iter()
+                .flatten()
+                .for_each(|id| push(Completion::Tag(id)));
+            ty.record_fields()
+                .into_iter()
+                .flatten()
+                .filter(|id| !is_defined(id))
+                .for_each(|id| push(Completion::Field(id)));
+        }
+
+        // The fields with a contract defined by another part of a merge are required too.
+        let mut fields: Vec<&Ident> = self
+            .fields
+            .iter()
+            .filter(|(id, shape)| shape.has_contracts() && !is_defined(id))
+            .map(|(id, _)| id)
+            .collect();
+        fields.sort_by(|Ident(id1), Ident(id2)| id1.cmp(id2));
+        fields
+            .into_iter()
+            .for_each(|id| push(Completion::Field(id.clone())));
+
+        completions
+    }
+}
+
+/// Return the contracts declared by a term itself, by annotations or by the parts of a merge.
+fn shape_of(rt: &RichTerm) -> Shape {
+    let contract = |ty: &Types| Shape {
+        contracts: vec![ty.clone()],
+        fields: HashMap::default(),
+    };
+
+    match rt.as_ref() {
+        Term::Promise(ty, _, t) | Term::Assume(ty, _, t) => contract(ty).union(shape_of(t)),
+        Term::MetaValue(meta) => meta
+            .contracts
+            .iter()
+            .map(|ctr| contract(&ctr.types))
+            .chain(meta.value.iter().map(shape_of))
+            .fold(Shape::default(), Shape::union),
+        Term::Op2(BinaryOp::Merge(), t1, t2) => shape_of(t1).union(shape_of(t2)),
+        Term::Record(fields) | Term::RecRecord(fields) => Shape {
+            contracts: Vec::new(),
+            fields: fields
+                .iter()
+                .map(|(id, t)| (id.clone(), shape_of(t)))
+                .collect(),
+        },
+        _ => Shape::default(),
+    }
+}
+
+/// Return the direct subterms of a term, without the contracts inside types (see
+/// [`Term::children`](../term/enum.Term.html#method.children)).
+pub fn subterms(t: &Term) -> Vec<&RichTerm> {
+    t.children()
+        .into_iter()
+        .filter(|child| !child.in_type)
+        .map(|child| child.term)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::grammar::TermParser;
+    use crate::parser::lexer::Lexer;
+    use codespan::{ByteIndex, Files};
+
+    fn parse(s: &str) -> RichTerm {
+        let id = Files::new().add("<test>", String::from(s));
+        TermParser::new().parse(id, Lexer::new(s)).unwrap()
+    }
+
+    fn count_unused(s: &str) -> usize {
+        unused_contracts(&parse(s)).len()
+    }
+
+    #[test]
+    fn dead_fields() {
+        let dead = |s: &str, overrides: &[&str]| {
+            let overrides: Vec<Vec<Ident>> = overrides
+                .iter()
+                .map(|path| path.split('.').map(Ident::from).collect())
+                .collect();
+            super::dead_fields(&parse(s), &overrides, &mut |_, _| None)
+                .into_iter()
+                .map(|w| match w {
+                    Warning::DeadField(path, ..) => path,
+                    w => panic!("unexpected warning {:?}", w),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(dead("merge {a = Default(1)} {a = 2}", &[]), vec!["a"]);
+        assert_eq!(
+            dead("merge {a = {b = Default(1); c = 1}} {a = {b = 2}}", &[]),
+            vec!["a.b"]
+        );
+        assert_eq!(dead("{a = Default(1); b = 2}", &[]), Vec::<String>::new());
+        assert_eq!(
+            dead("merge {a = Contract(Num)} {a = 2}", &[]),
+            Vec::<String>::new()
+        );
+        assert_eq!(dead("{a = 1; b = 2}", &["b"]), vec!["b"]);
+        assert_eq!(dead("{a = {b = 1; c = 2}}", &["a.c"]), vec!["a.c"]);
+        // Imports which cannot be loaded are ignored.
+        assert_eq!(
+            dead("merge (import \"a.ncl\") {a = 2}", &[]),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn field_completions() {
+        let complete = |s: &str, snippet: &str| {
+            let id = Files::new().add("<test>", String::from(s));
+            let rt = TermParser::new().parse(id, Lexer::new(s)).unwrap();
+            let start = s.find(snippet).unwrap();
+            let span = RawSpan {
+                src_id: id,
+                start: ByteIndex::from(start as u32),
+                end: ByteIndex::from((start + snippet.len()) as u32),
+            };
+            completions(&rt, &span)
+        };
+        let tags = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| Completion::Tag(Ident::from(*id)))
+                .collect::<Vec<_>>()
+        };
+        let fields = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| Completion::Field(Ident::from(*id)))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            complete(
+                "{color = Contract(< (| red, green, |) >); color &= `re;}",
+                "`re"
+            ),
+            tags(&["red", "green"])
+        );
+        assert_eq!(
+            complete(
+                "merge {a = {color = Contract(< (| red, |) >)}} {a = {color = `r}}",
+                "`r"
+            ),
+            tags(&["red"])
+        );
+        assert_eq!(
+            complete(
+                "Assume({ {| a : Num, b : < (| x, y, |) >, |} }, {a = 1})",
+                "{a = 1"
+            ),
+            fields(&["b"])
+        );
+        assert_eq!(
+            complete(
+                "Assume({ {| a : Num, b : < (| x, y, |) >, |} }, {a = 1; b = `z})",
+                "`z"
+            ),
+            tags(&["x", "y"])
+        );
+        assert_eq!(
+            complete(
+                "merge {server = {port = Contract(Num)}} {server = {host = 1}}",
+                "{host"
+            ),
+            fields(&["port"])
+        );
+        assert_eq!(complete("{a = Contract(Num); a &= 1;}", "1;"), Vec::new());
+        assert_eq!(complete("{a = `b}", "`b"), Vec::new());
+    }
+
+    #[test]
+    fn free_variables() {
+        let fv = free_vars(&parse(
+            "let x = y in {a = x; b = a; c = fun z => z + w} $[x = Assume(#c, v)]",
+        ));
+        let expected: HashSet<Ident> = vec!["y", "w", "c", "v"]
+            .into_iter()
+            .map(Ident::from)
+            .collect();
+        assert_eq!(fv, expected);
+    }
+
+    #[test]
+    fn unused_bindings() {
+        assert_eq!(count_unused("let x = Assume(Num, 1) in 2"), 1);
+        assert_eq!(count_unused("(fun x => 2) {a = Promise(Num, 1)}"), 1);
+        assert_eq!(count_unused("let x = Assume(Num, 1) in x"), 0);
+        assert_eq!(count_unused("let x = Assume(Num, 1) in let x = 2 in x"), 1);
+        assert_eq!(count_unused("let x = 1 in 2"), 0);
+    }
+
+    #[test]
+    fn overridden_defaults() {
+        assert_eq!(
+            count_unused("merge {a = Default(Assume(Num, 1))} {a = 2}"),
+            1
+        );
+        assert_eq!(
+            count_unused("merge {a = {b = 2}} {a = {b = Default(Assume(Num, 1))}}"),
+            1
+        );
+        assert_eq!(
+            count_unused("merge {a = Default(Assume(Num, 1))} {a = Contract(Num)}"),
+            0
+        );
+        assert_eq!(
+            count_unused("merge {a = Default(Assume(Num, 1))} {b = 2}"),
+            0
+        );
+    }
+
+    #[test]
+    fn arity_mismatches() {
+        let mismatches = |s: &str| -> Vec<(Option<String>, usize, usize, bool)> {
+            super::arity_mismatches(&parse(s))
+                .into_iter()
+                .map(|w| match w {
+                    Warning::ArityMismatch(name, params, args, def_span, Some(_)) => {
+                        (name.map(|Ident(id)| id), params, args, def_span.is_some())
+                    }
+                    w => panic!("unexpected warning {:?}", w),
+                })
+                .collect()
+        };
+        let f = Some(String::from("f"));
+
+        assert_eq!(
+            mismatches("let f = fun x => x + 1 in f 1 2"),
+            vec![(f.clone(), 1, 2, true)]
+        );
+        assert_eq!(
+            mismatches("let f = fun x y => x + y in f 1 + 2"),
+            vec![(f.clone(), 2, 1, true)]
+        );
+        assert_eq!(
+            mismatches("let f = fun x y => {a = x} in (f 1).a"),
+            vec![(f.clone(), 2, 1, true)]
+        );
+        assert_eq!(
+            mismatches("(fun x => if x then 1 else 2) true 3"),
+            vec![(None, 1, 2, true)]
+        );
+        assert_eq!(mismatches("(1 + 2) 3"), vec![(None, 2, 3, false)]);
+        assert_eq!(
+            mismatches("let f = fun x y => x in \"a${f 1}\""),
+            vec![(f, 2, 1, true)]
+        );
+
+        // The result of the function may be a function.
+        assert_eq!(mismatches("let f = fun x => x in f 1 2"), vec![]);
+        // Partial applications are valid values.
+        assert_eq!(mismatches("let f = fun x y => x in [f 1]"), vec![]);
+        // Shadowed functions are not known anymore.
+        assert_eq!(
+            mismatches("let f = fun x => 1 in let g = fun f => f 1 2 in g"),
+            vec![]
+        );
+        assert_eq!(mismatches("let f = fun x => 1 in let f = 2 in f 1"), vec![]);
+        // Typed code is left to the typechecker.
+        assert_eq!(
+            mismatches("let f = fun x => 1 in Promise(Num, f 1 2)"),
+            vec![]
+        );
+    }
+}
@@ -0,0 +1,531 @@
+//! A high-level interface to evaluate Nickel programs from Rust.
+//!
+//! [`Nickel`](struct.Nickel.html) configures an evaluation with a builder, runs it, and returns
+//! the result as a [`Value`](struct.Value.html), which can then be inspected or exported.
+//! Contrary to [`Program`](../program/struct.Program.html), which it is built on, errors are
+//! returned as [`NickelError`](struct.NickelError.html)s, which carry the diagnostic of the error
+//! already rendered and implement `std::error::Error`:
+//!
+//! ```no_run
+//! use nickel::api::Nickel;
+//! use nickel::serialize::ExportFormat;
+//!
+//! # fn main() -> Result<(), nickel::api::NickelError> {
+//! let yaml = Nickel::new()
+//!     .with_import_paths(vec!["lib"])
+//!     .with_override("server.port", 8080)
+//!     .with_stack_limit(Some(10_000))
+//!     .eval_path("main.ncl")?
+//!     .export(ExportFormat::Yaml)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Large configurations can also be evaluated lazily, such that only the fields which are
+//! accessed are evaluated (see [`LazyRecord`](struct.LazyRecord.html)).
+use crate::collections::HashMap;
+use crate::error::{Error, EvalError, ImportError};
+use crate::eval::{Closure, Environment};
+use crate::identifier::Ident;
+use crate::preview;
+use crate::program::Program;
+use crate::serialize::{self, ExportFormat, ExportOptions};
+use crate::term::{RecordData, Term};
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The configuration of the evaluation of a Nickel program.
+pub struct Nickel {
+    import_paths: Vec<PathBuf>,
+    overrides: Vec<(Vec<Ident>, Term)>,
+    canonicalize_imports: bool,
+    stack_limit: Option<usize>,
+    export_options: ExportOptions,
+    trace_sink: Option<Box<dyn Write>>,
+}
+
+impl Default for Nickel {
+    fn default() -> Self {
+        Nickel {
+            import_paths: Vec::new(),
+            overrides: Vec::new(),
+            canonicalize_imports: true,
+            stack_limit: Some(crate::eval::DEFAULT_STACK_LIMIT),
+            export_options: ExportOptions::default(),
+            trace_sink: None,
+        }
+    }
+}
+
+impl Nickel {
+    /// Create a configuration with the default settings of
+    /// [`Program`](../program/struct.Program.html).
+    pub fn new() -> Self {
+        Nickel::default()
+    }
+
+    /// Add directories in which imports are searched when they are not found relatively to the
+    /// importing file (see [`Program::set_import_paths`]).
+    ///
+    /// [`Program::set_import_paths`]: ../program/struct.Program.html#method.set_import_paths
+    pub fn with_import_paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.import_paths.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Override the field at `path`, a list of field names separated by dots such as
+    /// `server.port`, with `value` (see [`Program::add_override`]).
+    ///
+    /// [`Program::add_override`]: ../program/struct.Program.html#method.add_override
+    pub fn with_override(mut self, path: &str, value: impl Into<Term>) -> Self {
+        let path = path.split('.').map(|id| Ident(String::from(id))).collect();
+        self.overrides.push((path, value.into()));
+        self
+    }
+
+    /// Enable or disable the canonicalization of import paths (see
+    /// [`Program::set_canonicalize_imports`]).
+    ///
+    /// [`Program::set_canonicalize_imports`]: ../program/struct.Program.html#method.set_canonicalize_imports
+    pub fn with_canonicalize_imports(mut self, canonicalize: bool) -> Self {
+        self.canonicalize_imports = canonicalize;
+        self
+    }
+
+    /// Set the maximum size of the stack of the abstract machine (see
+    /// [`Program::set_stack_limit`]).
+    ///
+    /// [`Program::set_stack_limit`]: ../program/struct.Program.html#method.set_stack_limit
+    pub fn with_stack_limit(mut self, limit: Option<usize>) -> Self {
+        self.stack_limit = limit;
+        self
+    }
+
+    /// Set the options used by [`Value::export`](struct.Value.html#method.export). Their format
+    /// is overridden by the one given to `export`.
+    pub fn with_export_options(mut self, options: ExportOptions) -> Self {
+        self.export_options = options;
+        self
+    }
+
+    /// Redirect the trace output of the program, which goes to the standard error by default (see
+    /// [`Program::set_trace_sink`]).
+    ///
+    /// [`Program::set_trace_sink`]: ../program/struct.Program.html#method.set_trace_sink
+    pub fn with_trace_sink<W: Write + 'static>(mut self, sink: W) -> Self {
+        self.trace_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Fully evaluate the program of the file at `path`.
+    pub fn eval_path<P: AsRef<Path>>(self, path: P) -> Result<Value, NickelError> {
+        let program = load_path(path.as_ref())?;
+        self.eval_program(program)
+    }
+
+    /// Fully evaluate the program `source`. `name` is the name of the source in diagnostics.
+    pub fn eval_source(
+        self,
+        source: &str,
+        name: impl Into<OsString>,
+    ) -> Result<Value, NickelError> {
+        self.eval_program(load_source(source, name))
+    }
+
+    /// Evaluate the program of the file at `path` lazily: if it is a record, its fields are only
+    /// evaluated when they are accessed (see [`LazyRecord`](struct.LazyRecord.html)).
+    pub fn eval_lazy_path<P: AsRef<Path>>(self, path: P) -> Result<LazyValue, NickelError> {
+        let program = load_path(path.as_ref())?;
+        self.eval_program_lazy(program)
+    }
+
+    /// Evaluate the program `source` lazily (see [`eval_lazy_path`](#method.eval_lazy_path)).
+    pub fn eval_lazy_source(
+        self,
+        source: &str,
+        name: impl Into<OsString>,
+    ) -> Result<LazyValue, NickelError> {
+        self.eval_program_lazy(load_source(source, name))
+    }
+
+    /// Apply the configuration to a program, and return the export options.
+    fn configure(self, program: &mut Program) -> ExportOptions {
+        program.set_import_paths(self.import_paths);
+        program.set_canonicalize_imports(self.canonicalize_imports);
+        program.set_stack_limit(self.stack_limit);
+        if let Some(sink) = self.trace_sink {
+            program.set_trace_sink(sink);
+        }
+        for (path, value) in self.overrides {
+            program.add_override(path, value.into());
+        }
+
+        self.export_options
+    }
+
+    fn eval_program(self, mut program: Program) -> Result<Value, NickelError> {
+        let export_options = self.configure(&mut program);
+
+        match program.eval_full() {
+            Ok(term) => Ok(Value {
+                term,
+                program,
+                export_options,
+            }),
+            Err(error) => Err(NickelError::new(error, &mut program)),
+        }
+    }
+
+    fn eval_program_lazy(self, mut program: Program) -> Result<LazyValue, NickelError> {
+        self.configure(&mut program);
+
+        match program.eval_closure() {
+            Ok((clos, global_env)) => {
+                let session = Rc::new(RefCell::new(Session {
+                    program,
+                    global_env,
+                }));
+                LazyValue::new(clos, &session)
+            }
+            Err(error) => Err(NickelError::new(error, &mut program)),
+        }
+    }
+}
+
+/// Create a program from the file at `path`.
+fn load_path(path: &Path) -> Result<Program, NickelError> {
+    Program::new_from_file(path).map_err(|err| {
+        let name = path.to_string_lossy().into_owned();
+        NickelError {
+            message: format!("error: could not read {}: {}", name, err),
+            error: Box::new(Error::ImportError(ImportError::IOError(
+                name,
+                format!("{}", err),
+                None,
+            ))),
+        }
+    })
+}
+
+/// Create a program from a string.
+fn load_source(source: &str, name: impl Into<OsString>) -> Program {
+    Program::new_from_source(Cursor::new(source), name).expect("reading from a string cannot fail")
+}
+
+/// A program being evaluated lazily, shared by the records it evaluated to.
+struct Session {
+    program: Program,
+    global_env: Environment,
+}
+
+/// The result of a lazy evaluation: either a record whose fields are evaluated on demand, or any
+/// other value, which is fully evaluated.
+pub enum LazyValue {
+    Record(LazyRecord),
+    Value(Term),
+}
+
+impl LazyValue {
+    /// Build a lazy value from a closure in weak head normal form.
+    fn new(clos: Closure, session: &Rc<RefCell<Session>>) -> Result<Self, NickelError> {
+        match *clos.body.term {
+            Term::Record(fields) => Ok(LazyValue::Record(LazyRecord {
+                fields,
+                env: clos.env,
+                session: Rc::clone(session),
+            })),
+            _ => {
+                let Session {
+                    ref mut program,
+                    ref global_env,
+                } = *session.borrow_mut();
+                program
+                    .force_full(clos, global_env)
+                    .map(LazyValue::Value)
+                    .map_err(|error| NickelError::new(error, program))
+            }
+        }
+    }
+
+    /// Return the record, or `None` if the value is not a record.
+    pub fn into_record(self) -> Option<LazyRecord> {
+        match self {
+            LazyValue::Record(record) => Some(record),
+            LazyValue::Value(_) => None,
+        }
+    }
+
+    /// Return the value, or `None` if the value is a record.
+    pub fn into_term(self) -> Option<Term> {
+        match self {
+            LazyValue::Record(_) => None,
+            LazyValue::Value(term) => Some(term),
+        }
+    }
+}
+
+/// A record whose fields are only evaluated when they are accessed.
+///
+/// Fields are evaluated once: accessing the same field again, or a field which was already
+/// evaluated because another one depends on it, does not evaluate it again.
+pub struct LazyRecord {
+    fields: RecordData,
+    env: Environment,
+    session: Rc<RefCell<Session>>,
+}
+
+impl LazyRecord {
+    /// The names of the fields of the record, in alphabetical order.
+    pub fn fields(&self) -> Vec<&str> {
+        self.fields.iter().map(|(id, _)| id.0.as_str()).collect()
+    }
+
+    /// Render the field `name` in at most `max_len` characters, without evaluating it, or return
+    /// `None` if there is no such field. See the [`preview`](../preview/index.html) module.
+    pub fn preview(&self, name: &str, max_len: usize) -> Option<String> {
+        let clos = Closure {
+            body: self.fields.get(&Ident(String::from(name)))?.clone(),
+            env: self.env.clone(),
+        };
+        Some(preview::preview_closure(
+            &clos,
+            preview::DEFAULT_DEPTH,
+            max_len,
+        ))
+    }
+
+    /// Evaluate the field `name`. If it is a record, its own fields are in turn evaluated on
+    /// demand.
+    pub fn field(&self, name: &str) -> Result<LazyValue, NickelError> {
+        let id = Ident(String::from(name));
+        let mut session = self.session.borrow_mut();
+        let Session {
+            ref mut program,
+            ref global_env,
+        } = *session;
+
+        let t = match self.fields.get(&id) {
+            Some(t) => t.clone(),
+            None => {
+                let record = Term::Record(self.fields.clone()).into();
+                let error = EvalError::FieldMissing(id.0, String::from("field"), record, None);
+                return Err(NickelError::new(error.into(), program));
+            }
+        };
+
+        let clos = Closure {
+            body: t,
+            env: self.env.clone(),
+        };
+        let clos = program
+            .force(clos, global_env)
+            .map_err(|error| NickelError::new(error, program))?;
+        drop(session);
+        LazyValue::new(clos, &self.session)
+    }
+}
+
+/// The result of the evaluation of a program.
+pub struct Value {
+    term: Term,
+    program: Program,
+    export_options: ExportOptions,
+}
+
+impl Value {
+    /// The fully evaluated term (see [`eval::eval_full`](../eval/fn.eval_full.html)).
+    pub fn term(&self) -> &Term {
+        &self.term
+    }
+
+    /// Consume the value and return the fully evaluated term.
+    pub fn into_term(self) -> Term {
+        self.term
+    }
+
+    /// Serialize the value in the given format.
+    pub fn export(&mut self, format: ExportFormat) -> Result<String, NickelError> {
+        let options = ExportOptions {
+            format,
+            ..self.export_options.clone()
+        };
+        let mut out = Vec::new();
+
+        // The term is already evaluated and closed: it does not need the global environment.
+        let result = serialize::to_writer(
+            self.term.clone().into(),
+            &HashMap::default(),
+            &mut self.program,
+            None,
+            &options,
+            &mut out,
+        );
+
+        match result {
+            Ok(()) => Ok(String::from_utf8(out).expect("the export only writes valid UTF-8")),
+            Err(error) => Err(NickelError::new(error, &mut self.program)),
+        }
+    }
+}
+
+/// An error returned by the [`Nickel`](struct.Nickel.html) interface, together with its
+/// diagnostic rendered as text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NickelError {
+    error: Box<Error>,
+    message: String,
+}
+
+impl NickelError {
+    fn new(error: Error, program: &mut Program) -> Self {
+        let message = program.report_to_string(error.clone());
+        NickelError {
+            error: Box::new(error),
+            message,
+        }
+    }
+
+    /// The underlying error.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    /// Consume the error and return the underlying error.
+    pub fn into_error(self) -> Error {
+        *self.error
+    }
+
+    /// The diagnostic of the error, as [`Program::report`] would print it, without colors.
+    ///
+    /// [`Program::report`]: ../program/struct.Program.html#method.report
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for NickelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message.trim_end())
+    }
+}
+
+impl std::error::Error for NickelError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn builder() {
+        let dir = std::env::temp_dir().join(format!("nickel-api-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib").join("defaults.ncl"), "{ port = 80 }").unwrap();
+        fs::write(
+            dir.join("main.ncl"),
+            "{ server = import \"defaults.ncl\"; name = \"a\" }",
+        )
+        .unwrap();
+
+        let json = Nickel::new()
+            .with_import_paths(vec![dir.join("lib")])
+            .with_override("server.port", 8080)
+            .with_override("server.host", "localhost")
+            .with_override("debug", true)
+            .eval_path(dir.join("main.ncl"))
+            .unwrap()
+            .export(ExportFormat::Json)
+            .unwrap();
+        assert_eq!(
+            json,
+            "{\n  \"debug\": true,\n  \"name\": \"a\",\n  \"server\": {\n    \"host\": \"localhost\",\n    \"port\": 8080\n  }\n}\n"
+        );
+
+        // Without the import path, the import cannot be resolved.
+        let err = Nickel::new().eval_path(dir.join("main.ncl")).err().unwrap();
+        assert!(matches!(err.error(), Error::ImportError(_)));
+        assert!(format!("{}", err).contains("defaults.ncl"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lazy() {
+        let src = "{ database = { host = \"db\"; port = 5000 + 432 }; broken = 1 + \"a\"; n = 1 }";
+        let record = Nickel::new()
+            .eval_lazy_source(src, "<test>")
+            .unwrap()
+            .into_record()
+            .unwrap();
+        assert_eq!(record.fields(), vec!["broken", "database", "n"]);
+        assert_eq!(
+            record.preview("broken", 20),
+            Some(String::from("1 + \"a\""))
+        );
+        assert_eq!(
+            record.preview("database", 40),
+            Some(String::from("{ host = \"db\"; port = 5000 + 432 }"))
+        );
+        assert_eq!(record.preview("missing", 20), None);
+
+        let database = record.field("database").unwrap().into_record().unwrap();
+        assert_eq!(database.fields(), vec!["host", "port"]);
+        assert_eq!(
+            database.field("port").unwrap().into_term(),
+            Some(Term::Num(5432.))
+        );
+        assert_eq!(record.field("n").unwrap().into_term(), Some(Term::Num(1.)));
+
+        // Errors only occur when the faulty field is accessed.
+        let err = record.field("broken").err().unwrap();
+        assert!(matches!(err.error(), Error::EvalError(_)));
+        let err = record.field("missing").err().unwrap();
+        assert!(matches!(
+            err.error(),
+            Error::EvalError(EvalError::FieldMissing(..))
+        ));
+
+        let value = Nickel::new().eval_lazy_source("[1, 2]", "<test>").unwrap();
+        assert!(value.into_term().is_some());
+    }
+
+    #[test]
+    fn errors() {
+        let err = Nickel::new()
+            .eval_source("1 + true", "<test>")
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.error(),
+            Error::TypecheckError(_) | Error::EvalError(_)
+        ));
+        assert!(err.message().contains("<test>"));
+
+        // Overrides are checked by the contracts of the program.
+        let err = Nickel::new()
+            .with_override("a.b", "x")
+            .eval_source("{ a = { b = Contract(Num) } }", "<test>")
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.error(),
+            Error::EvalError(EvalError::BlameError(..))
+        ));
+
+        let mut value = Nickel::new()
+            .eval_source("{ f = fun x => x }", "<test>")
+            .unwrap();
+        assert!(matches!(
+            value.export(ExportFormat::Json).err().unwrap().error(),
+            Error::SerializationError(_)
+        ));
+    }
+}
@@ -0,0 +1,77 @@
+//! Hash maps and sets with a deterministic iteration order.
+//!
+//! The hash maps of the standard library are seeded randomly, such that the order in which their
+//! elements are iterated changes from one run to another. This order leaks into the behavior of
+//! the interpreter, for example in the order in which the fields of a record or the constraints on
+//! a row are reported in error messages. The maps of this module use a hasher with a fixed seed
+//! instead: the same program always gives the same output, which is required for reproducible
+//! results and snapshot tests.
+//!
+//! The hasher is [FNV-1a](http://www.isthe.com/chongo/tech/comp/fnv/), implemented here, rather
+//! than the default hasher of the standard library, whose algorithm is unspecified and may change
+//! from one release of Rust to another.
+//!
+//! They are drop-in replacements for the standard ones, except that they are created with
+//! `default()` instead of `new()`.
+use std::hash::{BuildHasherDefault, Hasher};
+
+/// The 64-bit FNV-1a hasher.
+#[derive(Debug, Clone, Copy)]
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        FnvHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// The deterministic hasher used by the maps and sets of this module.
+pub type BuildHasher = BuildHasherDefault<FnvHasher>;
+
+/// A hash map with a deterministic iteration order.
+pub type HashMap<K, V> = std::collections::HashMap<K, V, BuildHasher>;
+
+/// A hash set with a deterministic iteration order.
+pub type HashSet<T> = std::collections::HashSet<T, BuildHasher>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deterministic_order() {
+        // Two maps of the standard library would be seeded differently, and would most likely
+        // be iterated in different orders.
+        let keys = || (0..100).map(|i| format!("field{}", i));
+        let m1: HashMap<String, ()> = keys().map(|k| (k, ())).collect();
+        let m2: HashMap<String, ()> = keys().map(|k| (k, ())).collect();
+
+        assert!(m1.keys().eq(m2.keys()));
+    }
+
+    #[test]
+    fn fnv() {
+        // Reference values of the FNV-1a specification.
+        let hash = |bytes: &[u8]| {
+            let mut hasher = FnvHasher::default();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+}
@@ -0,0 +1,267 @@
+//! Dependency graph of the imports of a program.
+//!
+//! The graph has a node for each file of the import closure of the program, that is the main file
+//! and the files it imports, directly or transitively, and an edge for each import. Files are
+//! parsed but not evaluated, and imports are followed wherever they appear in a file, even in a
+//! branch which is never taken at run time.
+//!
+//! Each import is also classified by the way the typechecker handles it (see
+//! [`ImportTyping`](enum.ImportTyping.html)), which tells which parts of a configuration are
+//! statically checked against the interface of their dependencies. The graph can be exported to
+//! the DOT format of Graphviz, or to JSON for other tools, such as build systems.
+use crate::analysis;
+use crate::position::RawSpan;
+use crate::term::{RichTerm, Term};
+use serde_json::json;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// How the typechecker handles an import.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportTyping {
+    /// The import is inside a `Promise` block and has an interface file: its uses are statically
+    /// checked against the type declared by the interface.
+    Static,
+    /// The import is inside a `Promise` block but has no interface file: it has the type `Dyn`,
+    /// and its uses are only checked at run time.
+    Dynamic,
+    /// The import is outside of any `Promise` block, and is not typechecked.
+    Untyped,
+}
+
+impl ImportTyping {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImportTyping::Static => "static",
+            ImportTyping::Dynamic => "dynamic",
+            ImportTyping::Untyped => "untyped",
+        }
+    }
+}
+
+/// An edge of the import graph.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    /// The index of the importing file.
+    pub from: usize,
+    /// The index of the imported file.
+    pub to: usize,
+    /// The path of the import, as written in the importing file.
+    pub path: String,
+    pub typing: ImportTyping,
+}
+
+/// The import graph of a program. The first file is the main one.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ImportGraph {
+    pub files: Vec<PathBuf>,
+    pub imports: Vec<Import>,
+}
+
+/// A file loaded by [`import_graph`](fn.import_graph.html).
+pub struct LoadedFile {
+    /// The normalized path of the file, which identifies its node.
+    pub path: PathBuf,
+    /// The parsed file, or `None` if it cannot be read or parsed, or if it is a directory. Its
+    /// imports are then ignored.
+    pub term: Option<RichTerm>,
+    /// If the file has an interface file.
+    pub interface: bool,
+}
+
+/// Build the import graph of the program `rt`, whose file is at `path`.
+///
+/// `load` is called on each import, with the path of the import and the path of the importing
+/// file, or `None` for the main file. Each file is only visited once, even if it is imported
+/// several times, or if the imports are cyclic.
+pub fn import_graph<F>(rt: &RichTerm, path: PathBuf, load: &mut F) -> ImportGraph
+where
+    F: FnMut(&str, Option<PathBuf>) -> LoadedFile,
+{
+    let mut graph = ImportGraph::default();
+    graph.files.push(path);
+    let mut queue = vec![(0, rt.clone())];
+
+    while let Some((from, rt)) = queue.pop() {
+        let parent = if from == 0 {
+            None
+        } else {
+            Some(graph.files[from].clone())
+        };
+        let mut found = Vec::new();
+        imports(&rt, false, &mut found);
+        // Record fields are not ordered: follow the order of the source instead.
+        found.sort_by_key(|(_, _, pos)| pos.as_ref().map(|span| span.start));
+
+        for (import_path, strict, _) in found {
+            let loaded = load(&import_path, parent.clone());
+            let to = match graph.files.iter().position(|file| *file == loaded.path) {
+                Some(to) => to,
+                None => {
+                    graph.files.push(loaded.path);
+                    if let Some(t) = loaded.term {
+                        queue.push((graph.files.len() - 1, t));
+                    }
+                    graph.files.len() - 1
+                }
+            };
+            let typing = match (strict, loaded.interface) {
+                (true, true) => ImportTyping::Static,
+                (true, false) => ImportTyping::Dynamic,
+                (false, _) => ImportTyping::Untyped,
+            };
+
+            graph.imports.push(Import {
+                from,
+                to,
+                path: import_path,
+                typing,
+            });
+        }
+    }
+
+    graph
+}
+
+/// Gather the imports of a term, together with the typechecking mode they are in, strict inside
+/// a `Promise` block and non strict outside or inside an `Assume` block, and their position.
+fn imports(rt: &RichTerm, strict: bool, acc: &mut Vec<(String, bool, Option<RawSpan>)>) {
+    match rt.as_ref() {
+        Term::Import(path) => acc.push((path.clone(), strict, rt.pos.clone())),
+        Term::Promise(_, _, t) => imports(t, true, acc),
+        Term::Assume(_, _, t) => imports(t, false, acc),
+        t => analysis::subterms(t)
+            .into_iter()
+            .for_each(|t| imports(t, strict, acc)),
+    }
+}
+
+impl ImportGraph {
+    /// Render the graph in the DOT format. Imports which are not typechecked are dashed, and the
+    /// ones checked against an interface are bold.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph imports {\n");
+
+        for (i, file) in self.files.iter().enumerate() {
+            writeln!(out, "  {} [label={:?}];", i, file.to_string_lossy()).unwrap();
+        }
+
+        for import in self.imports.iter() {
+            let style = match import.typing {
+                ImportTyping::Static => "bold",
+                ImportTyping::Dynamic => "solid",
+                ImportTyping::Untyped => "dashed",
+            };
+            writeln!(
+                out,
+                "  {} -> {} [label={:?}, style={}];",
+                import.from, import.to, import.path, style
+            )
+            .unwrap();
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    /// Render the graph in JSON, as an object with the list of the paths of the files and the
+    /// list of the imports, which refer to files by their index in the former.
+    pub fn to_json(&self) -> String {
+        let files: Vec<_> = self
+            .files
+            .iter()
+            .map(|file| file.to_string_lossy().into_owned())
+            .collect();
+        let imports: Vec<_> = self
+            .imports
+            .iter()
+            .map(|import| {
+                json!({
+                    "from": import.from,
+                    "to": import.to,
+                    "path": import.path,
+                    "typing": import.typing.name(),
+                })
+            })
+            .collect();
+
+        serde_json::to_string_pretty(&json!({ "files": files, "imports": imports })).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::grammar::TermParser;
+    use crate::parser::lexer::Lexer;
+    use codespan::Files;
+
+    fn parse(s: &str) -> RichTerm {
+        let id = Files::new().add("<test>", String::from(s));
+        TermParser::new().parse(id, Lexer::new(s)).unwrap()
+    }
+
+    #[test]
+    fn import_graph() {
+        // In-memory files, as (path, source, has an interface).
+        let files = [
+            (
+                "a.ncl",
+                "Assume(Num, import \"c.ncl\") + (import \"b.ncl\")",
+                false,
+            ),
+            ("b.ncl", "import \"a.ncl\"", false),
+            ("c.ncl", "1", true),
+        ];
+        let main = parse(
+            "{x = import \"a.ncl\"; y = Promise(Num, import \"c.ncl\"); \
+             z = Promise(Dyn, import \"b.ncl\"); w = import \"missing.ncl\";}",
+        );
+        let mut loaded = Vec::new();
+        let graph = super::import_graph(&main, PathBuf::from("main.ncl"), &mut |path, parent| {
+            loaded.push((String::from(path), parent));
+            let file = files.iter().find(|(name, _, _)| *name == path);
+            LoadedFile {
+                path: PathBuf::from(path),
+                term: file.map(|(_, source, _)| parse(source)),
+                interface: matches!(file, Some((_, _, true))),
+            }
+        });
+
+        let names: Vec<_> = graph.files.iter().map(|f| f.to_str().unwrap()).collect();
+        assert_eq!(
+            names,
+            vec!["main.ncl", "a.ncl", "c.ncl", "b.ncl", "missing.ncl"]
+        );
+
+        let edges: Vec<_> = graph
+            .imports
+            .iter()
+            .map(|i| (i.from, i.to, i.typing))
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                (0, 1, ImportTyping::Untyped),
+                (0, 2, ImportTyping::Static),
+                (0, 3, ImportTyping::Dynamic),
+                (0, 4, ImportTyping::Untyped),
+                // The cycle between `a.ncl` and `b.ncl` is only followed once.
+                (3, 1, ImportTyping::Untyped),
+                (1, 2, ImportTyping::Untyped),
+                (1, 3, ImportTyping::Untyped),
+            ]
+        );
+        assert_eq!(loaded[0], (String::from("a.ncl"), None));
+        assert_eq!(
+            loaded[4],
+            (String::from("a.ncl"), Some(PathBuf::from("b.ncl")))
+        );
+
+        let dot = graph.to_dot();
+        assert!(dot.contains("0 -> 2 [label=\"c.ncl\", style=bold];"));
+        let json: serde_json::Value = serde_json::from_str(&graph.to_json()).unwrap();
+        assert_eq!(json["files"][4], "missing.ncl");
+        assert_eq!(json["imports"][2]["typing"], "dynamic");
+    }
+}
@@ -0,0 +1,185 @@
+//! Structural diff between two configurations.
+//!
+//! Both configurations are fully evaluated (see [`eval_full`](../eval/fn.eval_full.html)), and
+//! the resulting values are compared recursively. Records are compared field by field and lists
+//! element by element, such that a change deep inside a configuration is reported at its precise
+//! location, as in:
+//!
+//! ```
+//! + server.ports[2]: 8080
+//! - server.debug: true
+//! ~ server.name: "staging" -> "production"
+//! ```
+use crate::error::Error;
+use crate::identifier::Ident;
+use crate::program::Program;
+use crate::term::Term;
+use std::fmt;
+
+/// An element of a path inside a value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathElem {
+    /// A record field.
+    Field(Ident),
+    /// A list index.
+    Index(usize),
+}
+
+/// A path from the root of a value to one of its subvalues.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path(pub Vec<PathElem>);
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "<root>");
+        }
+
+        for (i, elem) in self.0.iter().enumerate() {
+            match elem {
+                PathElem::Field(Ident(id)) if i == 0 => write!(f, "{}", id)?,
+                PathElem::Field(Ident(id)) => write!(f, ".{}", id)?,
+                PathElem::Index(n) => write!(f, "[{}]", n)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A difference between two values.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// A subvalue only present in the new value.
+    Added(Path, Term),
+    /// A subvalue only present in the old value.
+    Removed(Path, Term),
+    /// A subvalue present in both values, but which is different. The values are boxed, such that
+    /// a change is not twice as large as a term.
+    Changed(Path, Box<Term>, Box<Term>),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Added(path, t) => write!(f, "+ {}: {}", path, t.shallow_repr()),
+            Change::Removed(path, t) => write!(f, "- {}: {}", path, t.shallow_repr()),
+            Change::Changed(path, old, new) => write!(
+                f,
+                "~ {}: {} -> {}",
+                path,
+                old.shallow_repr(),
+                new.shallow_repr()
+            ),
+        }
+    }
+}
+
+/// Compute the structural diff between two fully evaluated values.
+///
+/// Changes are listed in a deterministic order: the fields of records are visited in alphabetical
+/// order, and the elements of lists by increasing index.
+pub fn diff(old: &Term, new: &Term) -> Vec<Change> {
+    let mut changes = Vec::new();
+    diff_(&mut Vec::new(), old, new, &mut changes);
+    changes
+}
+
+fn diff_(path: &mut Vec<PathElem>, old: &Term, new: &Term, changes: &mut Vec<Change>) {
+    match (old, new) {
+        (Term::Record(old_map), Term::Record(new_map)) => {
+            let mut fields: Vec<&Ident> = old_map.keys().chain(new_map.keys()).collect();
+            fields.sort_by(|Ident(id1), Ident(id2)| id1.cmp(id2));
+            fields.dedup();
+
+            for id in fields {
+                path.push(PathElem::Field(id.clone()));
+                match (old_map.get(id), new_map.get(id)) {
+                    (Some(t1), Some(t2)) => diff_(path, t1.as_ref(), t2.as_ref(), changes),
+                    (Some(t1), None) => {
+                        changes.push(Change::Removed(Path(path.clone()), t1.as_ref().clone()))
+                    }
+                    (None, Some(t2)) => {
+                        changes.push(Change::Added(Path(path.clone()), t2.as_ref().clone()))
+                    }
+                    (None, None) => (),
+                }
+                path.pop();
+            }
+        }
+        (Term::List(old_ts), Term::List(new_ts)) => {
+            for i in 0..std::cmp::max(old_ts.len(), new_ts.len()) {
+                path.push(PathElem::Index(i));
+                match (old_ts.get(i), new_ts.get(i)) {
+                    (Some(t1), Some(t2)) => diff_(path, t1.as_ref(), t2.as_ref(), changes),
+                    (Some(t1), None) => {
+                        changes.push(Change::Removed(Path(path.clone()), t1.as_ref().clone()))
+                    }
+                    (None, Some(t2)) => {
+                        changes.push(Change::Added(Path(path.clone()), t2.as_ref().clone()))
+                    }
+                    (None, None) => (),
+                }
+                path.pop();
+            }
+        }
+        (t1, t2) if t1 == t2 => (),
+        (t1, t2) => changes.push(Change::Changed(
+            Path(path.clone()),
+            Box::new(t1.clone()),
+            Box::new(t2.clone()),
+        )),
+    }
+}
+
+/// Fully evaluate two programs and compute the diff between their values.
+pub fn diff_programs(old: &mut Program, new: &mut Program) -> Result<Vec<Change>, Error> {
+    let old_value = old.eval_full()?;
+    let new_value = new.eval_full()?;
+    Ok(diff(&old_value, &new_value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn diff_sources(old: &str, new: &str) -> Vec<String> {
+        let mut old = Program::new_from_source(Cursor::new(old), "<old>").unwrap();
+        let mut new = Program::new_from_source(Cursor::new(new), "<new>").unwrap();
+        diff_programs(&mut old, &mut new)
+            .unwrap()
+            .iter()
+            .map(|change| format!("{}", change))
+            .collect()
+    }
+
+    #[test]
+    fn no_changes() {
+        assert!(diff_sources("{a = 1; b = [1, 2]}", "{b = [(0 + 1), 2]; a = 2 + (-1)}").is_empty());
+    }
+
+    #[test]
+    fn nested_changes() {
+        assert_eq!(
+            diff_sources(
+                "{server = {name = \"staging\"; debug = true; ports = [80, 443]}}",
+                "let n = \"prod\" in {server = {name = n; ports = [80, 443, 8080]}}",
+            ),
+            vec![
+                "- server.debug: true",
+                "~ server.name: \"staging\" -> \"prod\"",
+                "+ server.ports[2]: 8080",
+            ]
+        );
+    }
+
+    #[test]
+    fn root_changes() {
+        assert_eq!(diff_sources("1", "2"), vec!["~ <root>: 1 -> 2"]);
+        assert_eq!(
+            diff_sources("{a = 1}", "[1]"),
+            vec!["~ <root>: { ... } -> [ ... ]"]
+        );
+    }
+}
@@ -22,6 +22,7 @@ pub enum Error {
     TypecheckError(TypecheckError),
     ParseError(ParseError),
     ImportError(ImportError),
+    SerializationError(SerializationError),
 }
 
 /// An error occurring during evaluation.
@@ -63,12 +64,35 @@ pub enum EvalError {
         /* right operand */ RichTerm,
         /* original merge */ Option<RawSpan>,
     ),
+    /// Two different default values were merged, and none of them can be chosen over the other.
+    ConflictingDefaults(
+        /* left default value */ RichTerm,
+        /* right default value */ RichTerm,
+        /* original merge */ Option<RawSpan>,
+    ),
     /// An unbound identifier was referenced.
     UnboundIdentifier(Ident, Option<RawSpan>),
+    /// The stack of the abstract machine exceeded its maximum size, or primitive operations calling
+    /// Nickel functions were nested too deeply, which usually denotes an unbounded recursion.
+    StackOverflow(
+        /* maximum size of the stack */ usize,
+        /* innermost elements of the call stack */ CallStack,
+        /* position of the expression being evaluated */ Option<RawSpan>,
+    ),
+    /// A primitive operation built a list longer than the limit of the evaluation (see
+    /// [`eval_closure_bounded`](../eval/fn.eval_closure_bounded.html)).
+    ListTooLong(
+        /* length */ usize,
+        /* limit */ usize,
+        /* position of the operation */ Option<RawSpan>,
+    ),
     /// An unexpected internal error.
     InternalError(String, Option<RawSpan>),
     /// Errors occurring rarely enough to not deserve a dedicated variant.
     Other(String, Option<RawSpan>),
+    /// An entry of an imported directory, which is only loaded when accessed, could not be
+    /// imported.
+    ImportError(ImportError),
 }
 
 /// An error occurring during the static typechecking phase.
@@ -158,6 +182,26 @@ pub enum TypecheckError {
         /* the error on the subtype unification */ Box<TypecheckError>,
         Option<RawSpan>,
     ),
+    /// A type variable introduced by a `forall` was unified with a type defined outside of the
+    /// scope of this `forall`.
+    ///
+    /// This happens when a polymorphic annotation is used on an expression whose type actually
+    /// depends on its context, as in:
+    ///
+    /// ```
+    /// fun y => Promise(forall a. a -> a, fun x => if true then x else y)
+    /// ```
+    ConstantEscape(
+        /* the escaping type variable */ Types,
+        /* the type it was unified with */ Types,
+        Option<RawSpan>,
+    ),
+    /// The typechecking was cancelled before its end (see
+    /// [`CancellationToken`](../typecheck/struct.CancellationToken.html)). The position is the one
+    /// of the expression being checked when the cancellation was noticed.
+    Cancelled(Option<RawSpan>),
+    /// An unexpected internal error.
+    InternalError(String, Option<RawSpan>),
 }
 
 /// An error occurring during parsing.
@@ -177,8 +221,22 @@ pub enum ParseError {
     UnmatchedCloseBrace(RawSpan),
     /// An alphanumeric character directly follows a number literal.
     NumThenIdent(RawSpan),
+    /// A hexadecimal, octal or binary literal is invalid or too large.
+    InvalidNumLiteral(RawSpan),
     /// Invalid escape sequence in a string literal.
     InvalidEscapeSequence(RawSpan),
+    /// A field is defined twice in a record literal.
+    DuplicateField(
+        Ident,
+        /* first definition */ RawSpan,
+        /* second definition */ RawSpan,
+    ),
+    /// A file in a foreign data format, such as JSON or YAML, could not be parsed.
+    ForeignFormatError(
+        FileId,
+        /* name of the format */ String,
+        /* error message */ String,
+    ),
 }
 
 /// An error occurring during the resolution of an import.
@@ -197,6 +255,69 @@ pub enum ImportError {
     ),
 }
 
+/// An error occurring during the serialization of a value to a foreign data format.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SerializationError {
+    /// A value has no counterpart in the target format, as a function.
+    NonSerializable(RichTerm),
+    /// A number is NaN or infinite, which has no JSON representation.
+    NonFiniteNumber(f64, Option<RawSpan>),
+    /// An IO error occurred when writing the output.
+    IOError(/* error message */ String),
+    /// Contracts were broken during an export in aggregate mode. Each blame error comes with the
+    /// path of the value which broke the contract.
+    ContractViolations(Vec<(/* path */ String, EvalError)>),
+    /// A list of the output has more elements than allowed by the export options.
+    ListTooLong(
+        /* path */ String,
+        /* length */ usize,
+        /* limit */ usize,
+        Option<RawSpan>,
+    ),
+    /// The output exceeds the size allowed by the export options, in bytes.
+    SizeLimitExceeded(/* path being written */ String, /* limit */ usize),
+}
+
+/// A warning, reported on code which is valid but likely to not behave as intended.
+///
+/// Warnings are produced by the [static analyses](../analysis/index.html), and do not prevent the
+/// evaluation of a program.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Warning {
+    /// A contract is applied inside an expression bound to a variable which is never used. The
+    /// contract is thus never checked.
+    UnusedContract(
+        /* the unused variable */ Ident,
+        /* position of the contract application */ Option<RawSpan>,
+        /* position of the binding */ Option<RawSpan>,
+    ),
+    /// A contract is applied inside a default value which is always overridden by a merge. The
+    /// contract is thus never checked.
+    OverriddenDefaultContract(
+        /* the field */ Ident,
+        /* position of the contract application */ Option<RawSpan>,
+        /* position of the overriding definition */ Option<RawSpan>,
+    ),
+    /// A definition of a field of the final record is shadowed, by a merge or by an override
+    /// from the outside of the program, and never contributes to its value.
+    DeadField(
+        /* the path of the field */ String,
+        /* position of the dead definition */ Option<RawSpan>,
+        /* position of the shadowing definition, if it is part of the program */
+        Option<RawSpan>,
+    ),
+    /// A function or a primitive operation is applied to more arguments than it takes, while its
+    /// result is not a function, or to fewer arguments than it takes, while its result is used
+    /// where a function is not valid.
+    ArityMismatch(
+        /* the variable the function is bound to, if any */ Option<Ident>,
+        /* the number of parameters */ usize,
+        /* the number of arguments */ usize,
+        /* position of the function, if it is not a primitive operation */ Option<RawSpan>,
+        /* position of the application */ Option<RawSpan>,
+    ),
+}
+
 impl From<EvalError> for Error {
     fn from(error: EvalError) -> Error {
         Error::EvalError(error)
@@ -221,7 +342,84 @@ impl From<ImportError> for Error {
     }
 }
 
+impl From<SerializationError> for Error {
+    fn from(error: SerializationError) -> Error {
+        Error::SerializationError(error)
+    }
+}
+
+impl Error {
+    /// The code of the error in the [catalog](../error_codes/index.html), if it has one.
+    pub fn code(&self) -> Option<&'static str> {
+        match self {
+            Error::EvalError(err) => Some(err.code()),
+            Error::TypecheckError(err) => Some(err.code()),
+            Error::ParseError(err) | Error::ImportError(ImportError::ParseError(err, _)) => {
+                Some(err.code())
+            }
+            Error::ImportError(_) | Error::SerializationError(_) => None,
+        }
+    }
+}
+
+impl EvalError {
+    /// The code of the error in the [catalog](../error_codes/index.html).
+    pub fn code(&self) -> &'static str {
+        match self {
+            EvalError::BlameError(..) => "E0001",
+            EvalError::TypeError(..) => "E0002",
+            EvalError::NotAFunc(..) => "E0003",
+            EvalError::FieldMissing(..) => "E0004",
+            EvalError::NotEnoughArgs(..) => "E0005",
+            EvalError::MergeIncompatibleArgs(..) => "E0006",
+            EvalError::ConflictingDefaults(..) => "E0007",
+            EvalError::UnboundIdentifier(..) => "E0008",
+            EvalError::StackOverflow(..) => "E0009",
+            EvalError::InternalError(..) => "E0010",
+            EvalError::Other(..) => "E0011",
+            EvalError::ListTooLong(..) => "E0012",
+            EvalError::ImportError(..) => "E0013",
+        }
+    }
+}
+
+impl TypecheckError {
+    /// The code of the error in the [catalog](../error_codes/index.html).
+    pub fn code(&self) -> &'static str {
+        match self {
+            TypecheckError::UnboundIdentifier(..) => "E0101",
+            TypecheckError::IllformedType(..) => "E0102",
+            TypecheckError::MissingRow(..) => "E0103",
+            TypecheckError::ExtraRow(..) => "E0104",
+            TypecheckError::UnboundTypeVariable(..) => "E0105",
+            TypecheckError::TypeMismatch(..) => "E0106",
+            TypecheckError::RowKindMismatch(..) => "E0107",
+            TypecheckError::RowMismatch(..) => "E0108",
+            TypecheckError::RowConflict(..) => "E0109",
+            TypecheckError::ArrowTypeMismatch(..) => "E0110",
+            TypecheckError::ConstantEscape(..) => "E0111",
+            TypecheckError::InternalError(..) => "E0112",
+            TypecheckError::Cancelled(..) => "E0113",
+        }
+    }
+}
+
 impl ParseError {
+    /// The code of the error in the [catalog](../error_codes/index.html).
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseError::UnexpectedEOF(..) => "E0201",
+            ParseError::UnexpectedToken(..) => "E0202",
+            ParseError::ExtraToken(..) => "E0203",
+            ParseError::UnmatchedCloseBrace(..) => "E0204",
+            ParseError::NumThenIdent(..) => "E0205",
+            ParseError::InvalidNumLiteral(..) => "E0206",
+            ParseError::InvalidEscapeSequence(..) => "E0207",
+            ParseError::DuplicateField(..) => "E0208",
+            ParseError::ForeignFormatError(..) => "E0209",
+        }
+    }
+
     pub fn from_lalrpop<T>(
         error: lalrpop_util::ParseError<usize, T, LexicalError>,
         file_id: FileId,
@@ -253,9 +451,15 @@ impl ParseError {
             lalrpop_util::ParseError::User {
                 error: LexicalError::NumThenIdent(location),
             } => ParseError::NumThenIdent(mk_span(file_id, location, location + 1)),
+            lalrpop_util::ParseError::User {
+                error: LexicalError::InvalidNumLiteral(start, end),
+            } => ParseError::InvalidNumLiteral(mk_span(file_id, start, end)),
             lalrpop_util::ParseError::User {
                 error: LexicalError::InvalidEscapeSequence(location),
             } => ParseError::InvalidEscapeSequence(mk_span(file_id, location, location + 1)),
+            lalrpop_util::ParseError::User {
+                error: LexicalError::DuplicateField(id, (l1, r1), (l2, r2)),
+            } => ParseError::DuplicateField(id, mk_span(file_id, l1, r1), mk_span(file_id, l2, r2)),
         }
     }
 }
@@ -296,6 +500,16 @@ fn primary(span: &RawSpan) -> Label<FileId> {
     Label::primary(span.src_id, span.start.to_usize()..span.end.to_usize())
 }
 
+/// Attach an error code to the first diagnostic of a list, which is the main one when several are
+/// generated.
+fn with_code(mut diagnostics: Vec<Diagnostic<FileId>>, code: &str) -> Vec<Diagnostic<FileId>> {
+    if let Some(diagnostic) = diagnostics.first_mut() {
+        diagnostic.code = Some(String::from(code));
+    }
+
+    diagnostics
+}
+
 /// Create a secondary label from a span.
 fn secondary(span: &RawSpan) -> Label<FileId> {
     Label::secondary(span.src_id, span.start.to_usize()..span.end.to_usize())
@@ -417,6 +631,8 @@ fn report_ty_path(l: &label::Label, files: &mut Files<String>) -> (Label<FileId>
 
     let (msg, notes) = if l.path.is_empty() {
         (String::from("expected type"), Vec::new())
+    } else if let Some(ty_path::Elem::Field(Ident(id))) = l.path.last() {
+        (format!("expected type of the field `{}`", id), Vec::new())
     }
     // If the path is only composed of codomains, polarity is necessarily true and the cause of the
     // blame is the return value of the function
@@ -480,6 +696,7 @@ fn report_ty_path(l: &label::Label, files: &mut Files<String>) -> (Label<FileId>
                         end_note,
                     ])
                 }
+                ty_path::Elem::Field(_) => unreachable!(),
             }
     };
 
@@ -493,6 +710,81 @@ fn report_ty_path(l: &label::Label, files: &mut Files<String>) -> (Label<FileId>
     (label, notes)
 }
 
+/// Explain in plain words which party is responsible for a blame, using the polarity and the type
+/// path of the label.
+///
+/// The responsible party is described relatively to the function bound by the contract, say `f`.
+/// Its name and the position of its application are taken from `calls`, the processed callstack
+/// (see [`process_callstack`](fn.process_callstack.html)). The calls located inside the contract
+/// itself are the ones done by the body of `f`, when it is written inline. Any call more nested
+/// than them is done by a function `f` was applied to, such that the call to `f` is the most
+/// nested one which is outside of the contract and less nested than all the calls inside the
+/// contract. Return `None` for a blame which is not on a function, that is when the type path has
+/// no domain nor codomain.
+fn blame_explanation(
+    l: &label::Label,
+    calls: &[(Option<Ident>, RawSpan)],
+    files: &Files<String>,
+) -> Option<String> {
+    // The fields of records only narrow down the blamed part of the type: the responsible party
+    // is given by the last function type of the path.
+    let last = l
+        .path
+        .iter()
+        .rev()
+        .find(|elem| !matches!(elem, ty_path::Elem::Field(_)))?;
+    let inside = |pos: &RawSpan| *pos <= l.span;
+    let outer = calls
+        .iter()
+        .rposition(|(_, pos)| inside(pos))
+        .map_or(0, |i| i + 1);
+    let call = calls[outer..]
+        .iter()
+        .chain(calls.iter())
+        .find(|(_, pos)| !inside(pos));
+
+    let mut func = match call {
+        Some((Some(Ident(id)), _)) => format!("`{}`", id),
+        _ => String::from("the function"),
+    };
+    if let Some((_, pos)) = call {
+        if let Ok(loc) = files.location(pos.src_id, pos.start) {
+            write!(
+                &mut func,
+                " at {}:{}:{}",
+                files.name(pos.src_id).to_string_lossy(),
+                loc.line.number(),
+                loc.column.number()
+            )
+            .unwrap();
+        }
+    }
+
+    let explanation = match (l.polarity, last) {
+        (false, ty_path::Elem::Domain) => {
+            format!("the caller of {} provided an invalid argument.", func)
+        }
+        (false, ty_path::Elem::Codomain) => format!(
+            "the caller of {} provided a function which returned an invalid value.",
+            func
+        ),
+        (true, ty_path::Elem::Codomain) if ty_path::is_only_codom(&l.path) => {
+            format!("the function {} returned an invalid value.", func)
+        }
+        (true, ty_path::Elem::Domain) => format!(
+            "the function {} applied one of its function arguments to an invalid value.",
+            func
+        ),
+        (true, ty_path::Elem::Codomain) => format!(
+            "the function {} passed a function which returned an invalid value to one of its function arguments.",
+            func
+        ),
+        (_, ty_path::Elem::Field(_)) => unreachable!(),
+    };
+
+    Some(format!("Blame: {}", explanation))
+}
+
 /// Process a raw callstack by grouping elements belonging to the same call and getting rid of
 /// elements that are not associated to a call.
 ///
@@ -647,6 +939,7 @@ impl ToDiagnostic<FileId> for Error {
             Error::TypecheckError(err) => err.to_diagnostic(files, contract_id),
             Error::EvalError(err) => err.to_diagnostic(files, contract_id),
             Error::ImportError(err) => err.to_diagnostic(files, contract_id),
+            Error::SerializationError(err) => err.to_diagnostic(files, contract_id),
         }
     }
 }
@@ -657,191 +950,264 @@ impl ToDiagnostic<FileId> for EvalError {
         files: &mut Files<String>,
         contract_id: Option<FileId>,
     ) -> Vec<Diagnostic<FileId>> {
-        match self {
-            EvalError::BlameError(l, cs_opt) => {
-                let mut msg = String::from("Blame error: ");
-
-                // Writing in a string should not raise an error, whence the fearless `unwrap()`
-                if l.path.is_empty() {
-                    // An empty path necessarily corresponds to a positive blame
-                    assert!(l.polarity);
-                    write!(&mut msg, "contract broken by a value").unwrap();
-                } else {
-                    if l.polarity {
-                        write!(&mut msg, "contract broken by a function").unwrap();
+        let diagnostics =
+            match self {
+                EvalError::BlameError(l, cs_opt) => {
+                    let mut msg = String::from("Blame error: ");
+
+                    // Writing in a string should not raise an error, whence the fearless `unwrap()`
+                    if l.path.is_empty() {
+                        // An empty path necessarily corresponds to a positive blame
+                        assert!(l.polarity);
+                        write!(&mut msg, "contract broken by a value").unwrap();
                     } else {
-                        write!(&mut msg, "contract broken by the caller").unwrap();
+                        if l.polarity {
+                            write!(&mut msg, "contract broken by a function").unwrap();
+                        } else {
+                            write!(&mut msg, "contract broken by the caller").unwrap();
+                        }
                     }
-                }
 
-                if !l.tag.is_empty() {
-                    write!(&mut msg, " [{}].", l.tag).unwrap();
-                } else {
-                    write!(&mut msg, ".").unwrap();
-                }
+                    if !l.tag.is_empty() {
+                        write!(&mut msg, " [{}].", l.tag).unwrap();
+                    } else {
+                        write!(&mut msg, ".").unwrap();
+                    }
 
-                let (path_label, notes) = report_ty_path(&l, files);
-                let labels = vec![
-                    path_label,
-                    Label::primary(
-                        l.span.src_id,
-                        l.span.start.to_usize()..l.span.end.to_usize(),
-                    )
-                    .with_message("bound here"),
-                ];
+                    let (path_label, notes) = report_ty_path(&l, files);
+                    let mut labels = vec![
+                        path_label,
+                        Label::primary(
+                            l.span.src_id,
+                            l.span.start.to_usize()..l.span.end.to_usize(),
+                        )
+                        .with_message("bound here"),
+                    ];
 
-                let mut diagnostics = vec![Diagnostic::error()
-                    .with_message(msg)
-                    .with_labels(labels)
-                    .with_notes(notes)];
-
-                match contract_id {
-                    Some(id) if !ty_path::is_only_codom(&l.path) => {
-                        let diags_opt =
-                            cs_opt
-                                .as_ref()
-                                .map(|cs| process_callstack(cs, id))
-                                .map(|calls| {
-                                    calls.into_iter().enumerate().map(|(i, (id_opt, pos))| {
-                                        let name = id_opt
-                                            .map(|Ident(id)| id.clone())
-                                            .unwrap_or(String::from("<func>"));
-                                        Diagnostic::note().with_labels(vec![secondary(&pos)
-                                            .with_message(format!("({}) calling {}", i + 1, name))])
-                                    })
-                                });
-
-                        if let Some(diags) = diags_opt {
-                            diagnostics.extend(diags);
-                        }
+                    if let Some(ref arg_pos) = l.arg_pos {
+                        labels.push(secondary(arg_pos).with_message("applied to this value"));
                     }
-                    _ => (),
-                }
 
-                diagnostics
-            }
-            EvalError::TypeError(expd, msg, orig_pos_opt, t) => {
-                let label = format!(
-                    "This expression has type {}, but {} was expected",
-                    t.term.type_of().unwrap_or(String::from("<unevaluated>")),
-                    expd,
-                );
-
-                let labels = match orig_pos_opt {
-                    Some(pos) if orig_pos_opt != &t.pos => vec![
-                        primary(pos).with_message(label),
-                        secondary_term(&t, files).with_message("evaluated to this"),
-                    ],
-                    _ => vec![primary_term(&t, files).with_message(label)],
-                };
+                    let mut diagnostics = vec![Diagnostic::error()
+                        .with_message(msg)
+                        .with_labels(labels)
+                        .with_notes(notes)];
+
+                    let calls = match (contract_id, cs_opt) {
+                        (Some(id), Some(cs)) => process_callstack(cs, id),
+                        _ => Vec::new(),
+                    };
+                    if let Some(explanation) = blame_explanation(l, &calls, files) {
+                        diagnostics[0].notes.insert(0, explanation);
+                    }
 
-                vec![Diagnostic::error()
-                    .with_message("Type error")
-                    .with_labels(labels)
-                    .with_notes(vec![msg.clone()])]
-            }
-            EvalError::NotAFunc(t, arg, pos_opt) => vec![Diagnostic::error()
-                .with_message("Not a function")
-                .with_labels(vec![
-                    primary_term(&t, files)
-                        .with_message("this term is applied, but it is not a function"),
-                    secondary_alt(
-                        &pos_opt,
-                        format!(
-                            "({}) ({})",
-                            (*t.term).shallow_repr(),
-                            (*arg.term).shallow_repr()
-                        ),
-                        files,
-                    )
-                    .with_message("applied here"),
-                ])],
-            EvalError::FieldMissing(field, op, t, span_opt) => {
-                let mut labels = Vec::new();
-                let mut notes = Vec::new();
+                    if !ty_path::is_only_codom(&l.path) {
+                        diagnostics.extend(calls.into_iter().enumerate().map(
+                            |(i, (id_opt, pos))| {
+                                let name = id_opt
+                                    .map(|Ident(id)| id.clone())
+                                    .unwrap_or(String::from("<func>"));
+                                Diagnostic::note().with_labels(vec![secondary(&pos)
+                                    .with_message(format!("({}) calling {}", i + 1, name))])
+                            },
+                        ));
+                    }
 
-                if let Some(span) = span_opt {
-                    labels.push(
-                        Label::primary(span.src_id, span.start.to_usize()..span.end.to_usize())
-                            .with_message(format!("this requires field {} to exist", field)),
+                    diagnostics
+                }
+                EvalError::TypeError(expd, msg, orig_pos_opt, t) => {
+                    let label = format!(
+                        "This expression has type {}, but {} was expected",
+                        t.term.type_of().unwrap_or(String::from("<unevaluated>")),
+                        expd,
                     );
-                } else {
-                    notes.push(format!(
-                        "Field {} was required by the operator {}",
-                        field, op
-                    ));
+
+                    let labels = match orig_pos_opt {
+                        Some(pos) if orig_pos_opt != &t.pos => vec![
+                            primary(pos).with_message(label),
+                            secondary_term(&t, files).with_message("evaluated to this"),
+                        ],
+                        _ => vec![primary_term(&t, files).with_message(label)],
+                    };
+
+                    vec![Diagnostic::error()
+                        .with_message("Type error")
+                        .with_labels(labels)
+                        .with_notes(vec![msg.clone()])]
                 }
+                EvalError::NotAFunc(t, arg, pos_opt) => vec![Diagnostic::error()
+                    .with_message("Not a function")
+                    .with_labels(vec![
+                        primary_term(&t, files)
+                            .with_message("this term is applied, but it is not a function"),
+                        secondary_alt(
+                            &pos_opt,
+                            format!(
+                                "({}) ({})",
+                                (*t.term).shallow_repr(),
+                                (*arg.term).shallow_repr()
+                            ),
+                            files,
+                        )
+                        .with_message("applied here"),
+                    ])],
+                EvalError::FieldMissing(field, op, t, span_opt) => {
+                    let mut labels = Vec::new();
+                    let mut notes = Vec::new();
+
+                    if let Some(span) = span_opt {
+                        labels.push(
+                            Label::primary(span.src_id, span.start.to_usize()..span.end.to_usize())
+                                .with_message(format!("this requires field {} to exist", field)),
+                        );
+                    } else {
+                        notes.push(format!(
+                            "Field {} was required by the operator {}",
+                            field, op
+                        ));
+                    }
+
+                    if let Some(ref span) = t.pos {
+                        labels.push(
+                            secondary(span)
+                                .with_message(format!("field {} is missing here", field)),
+                        );
+                    }
 
-                if let Some(ref span) = t.pos {
-                    labels.push(
-                        secondary(span).with_message(format!("field {} is missing here", field)),
+                    vec![Diagnostic::error()
+                        .with_message("Missing field")
+                        .with_labels(labels)]
+                }
+                EvalError::NotEnoughArgs(count, op, span_opt) => {
+                    let mut labels = Vec::new();
+                    let mut notes = Vec::new();
+                    let msg = format!(
+                        "{} expects {} arguments, but not enough were provided",
+                        op, count
                     );
+
+                    if let Some(span) = span_opt {
+                        labels.push(
+                            Label::primary(span.src_id, span.start.to_usize()..span.end.to_usize())
+                                .with_message(msg),
+                        );
+                    } else {
+                        notes.push(msg);
+                    }
+
+                    vec![Diagnostic::error()
+                        .with_message("Not enough arguments")
+                        .with_labels(labels)
+                        .with_notes(notes)]
                 }
+                EvalError::MergeIncompatibleArgs(t1, t2, span_opt) => {
+                    let mut labels = vec![
+                        primary_term(&t1, files).with_message("cannot merge this expression"),
+                        primary_term(&t2, files).with_message("with this expression"),
+                    ];
 
-                vec![Diagnostic::error()
-                    .with_message("Missing field")
-                    .with_labels(labels)]
-            }
-            EvalError::NotEnoughArgs(count, op, span_opt) => {
-                let mut labels = Vec::new();
-                let mut notes = Vec::new();
-                let msg = format!(
-                    "{} expects {} arguments, but not enough were provided",
-                    op, count
-                );
+                    if let Some(span) = span_opt {
+                        labels.push(secondary(&span).with_message("merged here"));
+                    }
 
-                if let Some(span) = span_opt {
-                    labels.push(
-                        Label::primary(span.src_id, span.start.to_usize()..span.end.to_usize())
-                            .with_message(msg),
-                    );
-                } else {
-                    notes.push(msg);
+                    vec![Diagnostic::error()
+                        .with_message("Non mergeable terms")
+                        .with_labels(labels)]
                 }
+                EvalError::ConflictingDefaults(t1, t2, span_opt) => {
+                    let mut labels = vec![
+                        primary_term(t1, files).with_message("this default value"),
+                        primary_term(t2, files).with_message("conflicts with this one"),
+                    ];
 
-                vec![Diagnostic::error()
-                    .with_message("Not enough arguments")
-                    .with_labels(labels)
-                    .with_notes(notes)]
-            }
-            EvalError::MergeIncompatibleArgs(t1, t2, span_opt) => {
-                let mut labels = vec![
-                    primary_term(&t1, files).with_message("cannot merge this expression"),
-                    primary_term(&t2, files).with_message("with this expression"),
-                ];
+                    if let Some(span) = span_opt {
+                        labels.push(secondary(span).with_message("merged here"));
+                    }
 
-                if let Some(span) = span_opt {
-                    labels.push(secondary(&span).with_message("merged here"));
+                    vec![Diagnostic::error()
+                        .with_message("Conflicting default values")
+                        .with_labels(labels)
+                        .with_notes(vec![String::from(
+                            "Give the field a definitive value to choose between the defaults",
+                        )])]
                 }
+                EvalError::UnboundIdentifier(Ident(ident), span_opt) => vec![Diagnostic::error()
+                    .with_message("Unbound identifier")
+                    .with_labels(vec![primary_alt(span_opt, ident.clone(), files)
+                        .with_message("this identifier is unbound")])],
+                EvalError::StackOverflow(limit, calls, span_opt) => {
+                    let labels = span_opt
+                        .as_ref()
+                        .map(|span| {
+                            vec![primary(span).with_message("while evaluating this expression")]
+                        })
+                        .unwrap_or_default();
+
+                    let mut diagnostics = vec![Diagnostic::error()
+                        .with_message("Stack overflow")
+                        .with_labels(labels)
+                        .with_notes(vec![format!(
+                            "The evaluation exceeded the maximum stack size of {}, or nested too \
+many calls of functions by primitive operations. This usually denotes an unbounded recursion.",
+                            limit
+                        )])];
+
+                    diagnostics.extend(calls.iter().rev().filter_map(|elem| match elem {
+                        StackElem::Var(_, Ident(id), Some(pos)) => {
+                            Some(Diagnostic::note().with_labels(vec![
+                                secondary(pos).with_message(format!("calling {}", id)),
+                            ]))
+                        }
+                        StackElem::App(Some(pos)) => {
+                            Some(Diagnostic::note().with_labels(vec![
+                                secondary(pos).with_message("applying a function"),
+                            ]))
+                        }
+                        _ => None,
+                    }));
 
-                vec![Diagnostic::error()
-                    .with_message("Non mergeable terms")
-                    .with_labels(labels)]
-            }
-            EvalError::UnboundIdentifier(Ident(ident), span_opt) => vec![Diagnostic::error()
-                .with_message("Unbound identifier")
-                .with_labels(vec![primary_alt(span_opt, ident.clone(), files)
-                    .with_message("this identifier is unbound")])],
-            EvalError::Other(msg, span_opt) => {
-                let labels = span_opt
-                    .as_ref()
-                    .map(|span| vec![primary(span).with_message("here")])
-                    .unwrap_or(Vec::new());
+                    diagnostics
+                }
+                EvalError::ListTooLong(len, max, span_opt) => {
+                    let labels = span_opt
+                        .as_ref()
+                        .map(|span| {
+                            vec![primary(span)
+                                .with_message(format!("builds a list of {} elements", len))]
+                        })
+                        .unwrap_or_default();
+
+                    vec![Diagnostic::error()
+                        .with_message("List too long")
+                        .with_labels(labels)
+                        .with_notes(vec![format!(
+                            "The evaluation is limited to lists of at most {} elements",
+                            max
+                        )])]
+                }
+                EvalError::Other(msg, span_opt) => {
+                    let labels = span_opt
+                        .as_ref()
+                        .map(|span| vec![primary(span).with_message("here")])
+                        .unwrap_or(Vec::new());
 
-                vec![Diagnostic::error().with_message(msg).with_labels(labels)]
-            }
-            EvalError::InternalError(msg, span_opt) => {
-                let labels = span_opt
-                    .as_ref()
-                    .map(|span| vec![primary(span).with_message("here")])
-                    .unwrap_or(Vec::new());
+                    vec![Diagnostic::error().with_message(msg).with_labels(labels)]
+                }
+                EvalError::InternalError(msg, span_opt) => {
+                    let labels = span_opt
+                        .as_ref()
+                        .map(|span| vec![primary(span).with_message("here")])
+                        .unwrap_or(Vec::new());
+
+                    vec![Diagnostic::error()
+                        .with_message(format!("Internal error ({})", msg))
+                        .with_labels(labels)
+                        .with_notes(vec![String::from(INTERNAL_ERROR_MSG)])]
+                }
+                EvalError::ImportError(err) => err.to_diagnostic(files, contract_id),
+            };
 
-                vec![Diagnostic::error()
-                    .with_message(format!("Internal error ({})", msg))
-                    .with_labels(labels)
-                    .with_notes(vec![String::from(INTERNAL_ERROR_MSG)])]
-            }
-        }
+        with_code(diagnostics, self.code())
     }
 }
 
@@ -870,12 +1236,36 @@ impl ToDiagnostic<FileId> for ParseError {
             ParseError::NumThenIdent(span) => Diagnostic::error()
                 .with_message("Invalid character in a number literal")
                 .with_labels(vec![primary(span)]),
+            ParseError::InvalidNumLiteral(span) => Diagnostic::error()
+                .with_message("Invalid number literal")
+                .with_labels(vec![primary(span)])
+                .with_notes(vec![String::from(
+                    "Hexadecimal, octal and binary literals must have at least one valid digit, \
+                    and must not exceed 2^53 to be represented exactly.",
+                )]),
             ParseError::InvalidEscapeSequence(span) => Diagnostic::error()
                 .with_message("Invalid escape sequence")
                 .with_labels(vec![primary(span)]),
+            ParseError::DuplicateField(Ident(id), first, second) => Diagnostic::error()
+                .with_message(format!("Duplicate definition of the field `{}`", id))
+                .with_labels(vec![
+                    primary(second).with_message("redefined here"),
+                    secondary(first).with_message("first defined here"),
+                ])
+                .with_notes(vec![format!(
+                    "Use `{} &= value` to merge several definitions of a field",
+                    id
+                )]),
+            ParseError::ForeignFormatError(file_id, format, msg) => Diagnostic::error()
+                .with_message(format!(
+                    "Could not parse {} as {}",
+                    files.name(*file_id).to_string_lossy(),
+                    format
+                ))
+                .with_notes(vec![msg.clone()]),
         };
 
-        vec![diagnostic]
+        vec![diagnostic.with_code(self.code())]
     }
 }
 
@@ -892,7 +1282,7 @@ impl ToDiagnostic<FileId> for TypecheckError {
                 .unwrap_or(Vec::new())
         }
 
-        match self {
+        let diagnostics = match self {
             TypecheckError::UnboundIdentifier(ident, pos_opt) =>
             // Use the same diagnostic as `EvalError::UnboundIdentifier` for consistency.
             {
@@ -1069,7 +1459,34 @@ vec![
 
                 diags
             }
-        }
+            TypecheckError::ConstantEscape(cst, ty, span_opt) => {
+                let note = if ty == cst {
+                    String::from("But it was unified with a type defined outside of this `forall`")
+                } else {
+                    format!("But it appears in the type `{}`, which was unified with a type defined outside of this `forall`", ty)
+                };
+
+                vec![Diagnostic::error()
+                    .with_message(format!("Type variable `{}` escapes its scope", cst))
+                    .with_labels(mk_expr_label(span_opt))
+                    .with_notes(vec![
+                        format!("The type variable `{}` is only in scope in the body of its `forall`", cst),
+                        note,
+                        String::from("The expression is not as polymorphic as its annotation claims"),
+                    ])]
+            }
+            TypecheckError::Cancelled(span_opt) => vec![Diagnostic::note()
+                .with_message("Typechecking cancelled")
+                .with_labels(mk_expr_label(span_opt))],
+            TypecheckError::InternalError(msg, span_opt) =>
+            // Use the same diagnostic as `EvalError::InternalError` for consistency.
+            {
+                EvalError::InternalError(msg.clone(), span_opt.clone())
+                    .to_diagnostic(files, contract_id)
+            }
+        };
+
+        with_code(diagnostics, self.code())
     }
 }
 
@@ -1104,3 +1521,191 @@ impl ToDiagnostic<FileId> for ImportError {
         }
     }
 }
+
+impl ToDiagnostic<FileId> for SerializationError {
+    fn to_diagnostic(
+        &self,
+        files: &mut Files<String>,
+        contract_id: Option<FileId>,
+    ) -> Vec<Diagnostic<FileId>> {
+        match self {
+            SerializationError::NonSerializable(rt) => {
+                let labels = rt
+                    .pos
+                    .as_ref()
+                    .map(|span| vec![primary(span).with_message("cannot be serialized")])
+                    .unwrap_or_default();
+
+                vec![Diagnostic::error()
+                    .with_message("Non serializable term")
+                    .with_labels(labels)
+                    .with_notes(vec![format!(
+                        "Only booleans, numbers, strings, lists and records can be serialized, \
+but this value is of type {}",
+                        rt.term.type_of().unwrap_or_else(|| String::from("Dyn"))
+                    )])]
+            }
+            SerializationError::NonFiniteNumber(n, span_opt) => {
+                let labels = span_opt
+                    .as_ref()
+                    .map(|span| vec![primary(span).with_message(format!("evaluates to {}", n))])
+                    .unwrap_or_default();
+
+                vec![Diagnostic::error()
+                    .with_message("Non finite number")
+                    .with_labels(labels)
+                    .with_notes(vec![String::from(
+                        "NaN and infinite numbers cannot be represented in JSON",
+                    )])]
+            }
+            SerializationError::IOError(msg) => vec![Diagnostic::error()
+                .with_message(format!("Could not write the serialized output: {}", msg))],
+            SerializationError::ContractViolations(violations) => {
+                let mut diags = vec![Diagnostic::error().with_message(format!(
+                    "{} contract violation(s) during the export",
+                    violations.len()
+                ))];
+
+                for (path, err) in violations.iter() {
+                    let mut err_diags = err.to_diagnostic(files, contract_id);
+                    if let Some(diag) = err_diags.first_mut() {
+                        diag.notes.push(format!("In the exported field {}", path));
+                    }
+                    diags.extend(err_diags);
+                }
+
+                diags
+            }
+            SerializationError::ListTooLong(path, len, max, span_opt) => {
+                let labels = span_opt
+                    .as_ref()
+                    .map(|span| vec![primary(span).with_message(format!("has {} elements", len))])
+                    .unwrap_or_default();
+                let mut notes = vec![format!(
+                    "The export is limited to lists of at most {} elements",
+                    max
+                )];
+                if !path.is_empty() {
+                    notes.push(format!("In the exported field {}", path));
+                }
+
+                vec![Diagnostic::error()
+                    .with_message("List too long")
+                    .with_labels(labels)
+                    .with_notes(notes)]
+            }
+            SerializationError::SizeLimitExceeded(path, max) => {
+                let mut notes = vec![format!("The output is limited to {} bytes", max)];
+                if !path.is_empty() {
+                    notes.push(format!(
+                        "The limit was reached in the exported field {}",
+                        path
+                    ));
+                }
+
+                vec![Diagnostic::error()
+                    .with_message("Exported document too large")
+                    .with_notes(notes)]
+            }
+        }
+    }
+}
+
+impl ToDiagnostic<FileId> for Warning {
+    fn to_diagnostic(
+        &self,
+        _files: &mut Files<String>,
+        _contract_id: Option<FileId>,
+    ) -> Vec<Diagnostic<FileId>> {
+        let contract_label = |span: &Option<RawSpan>| {
+            span.as_ref()
+                .map(|span| primary(span).with_message("this contract is never checked"))
+        };
+
+        let (msg, main_label, other_label, note) = match self {
+            Warning::UnusedContract(Ident(id), contract_span, binding_span) => (
+                String::from("Unused contract"),
+                contract_label(contract_span),
+                binding_span
+                    .as_ref()
+                    .map(|span| secondary(span).with_message(format!("`{}` is never used", id))),
+                format!(
+                    "The value bound to `{}` is never used, hence this contract is never checked",
+                    id
+                ),
+            ),
+            Warning::OverriddenDefaultContract(Ident(id), contract_span, def_span) => (
+                String::from("Contract in an overridden default value"),
+                contract_label(contract_span),
+                def_span.as_ref().map(|span| {
+                    secondary(span).with_message(format!("`{}` is overridden here", id))
+                }),
+                format!(
+                    "The default value of `{}` is always overridden, hence this contract is never checked",
+                    id
+                ),
+            ),
+            Warning::DeadField(path, def_span, shadow_span) => (
+                format!("Dead definition of the field `{}`", path),
+                def_span
+                    .as_ref()
+                    .map(|span| primary(span).with_message("this definition is never used")),
+                shadow_span.as_ref().map(|span| {
+                    secondary(span).with_message(format!("`{}` is defined here", path))
+                }),
+                if shadow_span.is_some() {
+                    format!("The definition of `{}` always wins over this one", path)
+                } else {
+                    format!("`{}` is overridden from the outside of the program", path)
+                },
+            ),
+            Warning::ArityMismatch(name, params, args, def_span, app_span) => {
+                let plural = |n: &usize| {
+                    if *n == 1 {
+                        String::from("1 argument")
+                    } else {
+                        format!("{} arguments", n)
+                    }
+                };
+                let callee = match (name, def_span) {
+                    (Some(Ident(id)), _) => format!("`{}`", id),
+                    (None, Some(_)) => String::from("this function"),
+                    (None, None) => String::from("this primitive operation"),
+                };
+
+                (
+                    if args > params {
+                        format!("Too many arguments for {}", callee)
+                    } else {
+                        format!("Missing arguments for {}", callee)
+                    },
+                    app_span.as_ref().map(|span| {
+                        primary(span).with_message(format!("applied to {} here", plural(args)))
+                    }),
+                    def_span.as_ref().map(|span| {
+                        secondary(span).with_message(format!("takes {}", plural(params)))
+                    }),
+                    if args > params {
+                        format!(
+                            "The full application of {} does not return a function, hence it cannot be applied to more arguments",
+                            callee
+                        )
+                    } else {
+                        format!(
+                            "A partial application of {} is a function, which is not valid here",
+                            callee
+                        )
+                    },
+                )
+            }
+        };
+
+        let mut labels: Vec<Label<FileId>> = main_label.into_iter().collect();
+        labels.extend(other_label);
+
+        vec![Diagnostic::warning()
+            .with_message(msg)
+            .with_labels(labels)
+            .with_notes(vec![note])]
+    }
+}
@@ -0,0 +1,318 @@
+//! Catalog of error codes.
+//!
+//! Each variant of [`EvalError`](../error/enum.EvalError.html),
+//! [`TypecheckError`](../error/enum.TypecheckError.html) and
+//! [`ParseError`](../error/enum.ParseError.html) is identified by a stable code, such as `E0002`,
+//! which is displayed in diagnostics. Codes are grouped by phase:
+//!
+//! - `E00xx`: evaluation errors,
+//! - `E01xx`: typechecking errors,
+//! - `E02xx`: parse errors.
+//!
+//! A code is never reused for another error once assigned, even if the corresponding variant is
+//! removed. Tools can thus refer to them, and retrieve an extended explanation with
+//! [`explain`](fn.explain.html).
+
+/// The codes of the catalog, together with their extended explanation.
+const CATALOG: &[(&str, &str)] = &[
+    (
+        "E0001",
+        "A contract was broken.
+
+A value was checked against a contract, introduced by an `Assume` or by a contract field of a
+record, and did not satisfy it. The blame is put either on the value (positive blame) or, for
+functions, on the caller which provided an invalid argument (negative blame).
+
+    Assume(Num, \"a\")
+
+Check the value against the contract, or fix the contract if the value is intended.",
+    ),
+    (
+        "E0002",
+        "A primitive operation was applied to a value of the wrong type.
+
+    1 + \"a\"
+
+The addition expects two numbers, but its second argument is a string. Convert the value, or
+check the expression which produced it.",
+    ),
+    (
+        "E0003",
+        "A value which is not a function was applied to an argument.
+
+    let x = 1 in x 2
+
+Only functions can be applied. This is often caused by a missing operator or parenthesis
+between two expressions.",
+    ),
+    (
+        "E0004",
+        "A field was accessed on a record which does not define it.
+
+    {a = 1;}.b
+
+Check the name of the field, or give it a default value in the record.",
+    ),
+    (
+        "E0005",
+        "A builtin operation was not given enough arguments.
+
+Builtin operations must be fully applied. Provide the missing arguments.",
+    ),
+    (
+        "E0006",
+        "Two values which cannot be merged were merged.
+
+    merge 1 2
+
+Merging only succeeds on records, which are combined field by field, on equal simple values,
+and on enriched values such as default values and contracts.",
+    ),
+    (
+        "E0007",
+        "Two different default values were merged.
+
+    (merge {a = Default(1);} {a = Default(2);}).a
+
+None of the default values has priority over the other. Define a value without `Default` on one
+side to override them.",
+    ),
+    (
+        "E0008",
+        "An identifier was evaluated but is not defined.
+
+Unbound identifiers are usually reported before evaluation, by the typechecker (see E0101). Check
+the name of the identifier, and that it is in scope: let bindings are not recursive, and the
+fields of a record are only in scope inside this record.",
+    ),
+    (
+        "E0009",
+        "The evaluation exceeded the maximum size of the stack.
+
+This usually denotes an unbounded recursion. Check that recursive functions have a base case
+which is eventually reached.",
+    ),
+    (
+        "E0010",
+        "An internal error of the interpreter occurred during evaluation.
+
+This is a bug of the interpreter, and not of the program. Please report it.",
+    ),
+    (
+        "E0011",
+        "An evaluation error without a more specific code.
+
+The message of the error describes its cause.",
+    ),
+    (
+        "E0012",
+        "A list longer than the limit of the evaluation was built.
+
+    listRange 0 1000000 1
+
+The length of lists is limited when exporting with a maximum list length. A list which grows
+unexpectedly is often the result of an accidental cartesian product. Raise the limit if the list
+is legitimately that long.",
+    ),
+    (
+        "E0013",
+        "A file of an imported directory could not be loaded.
+
+    (import \"config/\").database
+
+The files of an imported directory are only read and parsed when the corresponding field is
+accessed, so that a malformed file only causes an error if it is used.",
+    ),
+    (
+        "E0101",
+        "An identifier was used but is not defined.
+
+    let x = 1 in y
+
+Check the name of the identifier, and that it is in scope: let bindings are not recursive, and
+the fields of a record are only in scope inside this record.",
+    ),
+    (
+        "E0102",
+        "A type is ill-formed.
+
+For example, a type which is not a row appears in a row type. Check the syntax of the type
+annotation.",
+    ),
+    (
+        "E0103",
+        "A field or an enum tag was expected in a row type, but is missing.
+
+    Promise({ {| a : Num, |} } -> Num, fun r => r.b)
+
+The annotated or inferred type lacks a row required by the expression, here the field `b`. Add
+the missing field or tag, or fix the annotation.",
+    ),
+    (
+        "E0104",
+        "A row type has a field or an enum tag which was not expected.
+
+    Promise({ {| |} }, {a = 1;})
+
+The annotated or inferred type has a row absent from its expected type. Remove the field or
+tag, or fix the annotation.",
+    ),
+    (
+        "E0105",
+        "A type variable is not bound.
+
+    Promise(a -> a, fun x => x)
+
+Type variables must be introduced by a `forall`, as in `forall a. a -> a`.",
+    ),
+    (
+        "E0106",
+        "The type of an expression is incompatible with its expected type.
+
+    Promise(Num, \"a\")
+
+Fix the expression or the type annotation.",
+    ),
+    (
+        "E0107",
+        "A field of a row type is used both as a record field and as an enum tag.
+
+Rows of records and rows of enums cannot be mixed. Check the uses of the identifier.",
+    ),
+    (
+        "E0108",
+        "A field of a row type has incompatible types.
+
+    Promise({ {| a : Num, |} }, {a = \"a\";})
+
+The details of the error indicate why the types of the field are incompatible.",
+    ),
+    (
+        "E0109",
+        "A row type was extended with a field it must not contain.
+
+This happens when the tail of a row type is inferred to be unified with a type which defines a
+field already present in this row. Check the uses of the field.",
+    ),
+    (
+        "E0110",
+        "A part of a function type is incompatible with its expected type.
+
+    let f = Promise(Num -> Num, fun x => x) in Promise(Bool -> Num, f)
+
+The error indicates the domain or the codomain which failed to unify, and why. This is often
+caused by using a function where a function of another type is expected: here, `f` takes a
+number, but a function taking a boolean is expected.",
+    ),
+    (
+        "E0111",
+        "A polymorphic type variable escapes its scope.
+
+    fun y => Promise(forall a. a -> a, fun x => if true then x else y)
+
+A type variable introduced by a `forall` was unified with a type defined outside of its scope.
+The expression is not as polymorphic as its annotation claims.",
+    ),
+    (
+        "E0112",
+        "An internal error of the interpreter occurred during typechecking.
+
+This is a bug of the interpreter, and not of the program. Please report it.",
+    ),
+    (
+        "E0113",
+        "The typechecking was cancelled before its end.
+
+This is not an error of the program: the tool which requested the typechecking, such as an
+editor, interrupted it because its result was no longer needed.",
+    ),
+    (
+        "E0201",
+        "The end of the file was reached before the end of an expression.
+
+This is often caused by an unclosed parenthesis, brace or string.",
+    ),
+    (
+        "E0202",
+        "The parser encountered a token which is not valid at this place.
+
+The error lists the tokens which were expected instead.",
+    ),
+    (
+        "E0203",
+        "A token was found after the end of the expression.
+
+A program is a single expression. Remove the superfluous tokens, or combine the expressions.",
+    ),
+    ("E0204", "A closing brace does not match any opening brace."),
+    (
+        "E0205",
+        "An alphanumeric character directly follows a number literal.
+
+    1a
+
+Separate the number from the identifier, or fix the literal.",
+    ),
+    (
+        "E0206",
+        "A hexadecimal, octal or binary number literal is invalid or too large.
+
+    0xZ
+
+Check the digits of the literal.",
+    ),
+    (
+        "E0207",
+        "A string literal contains an invalid escape sequence.
+
+    \"\\q\"
+
+Escape the backslash itself to include it in the string.",
+    ),
+    (
+        "E0208",
+        "A field is defined twice in a record literal.
+
+    {a = 1; a = 2;}
+
+Remove one of the definitions, or merge the values explicitly.",
+    ),
+    (
+        "E0209",
+        "An imported file in a foreign format, such as JSON or YAML, could not be parsed.
+
+The message of the error is the one of the parser of this format.",
+    ),
+];
+
+/// Return the extended explanation of an error code, or `None` if the code is unknown. The code
+/// is case-insensitive.
+pub fn explain(code: &str) -> Option<&'static str> {
+    CATALOG
+        .iter()
+        .find(|(c, _)| c.eq_ignore_ascii_case(code))
+        .map(|(_, explanation)| *explanation)
+}
+
+/// All the codes of the catalog, in increasing order.
+pub fn codes() -> impl Iterator<Item = &'static str> {
+    CATALOG.iter().map(|(code, _)| *code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalog() {
+        let codes: Vec<&str> = codes().collect();
+        let mut sorted = codes.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(codes, sorted);
+
+        assert!(explain("E0004").unwrap().contains("does not define"));
+        assert_eq!(explain("e0004"), explain("E0004"));
+        assert_eq!(explain("E9999"), None);
+    }
+}
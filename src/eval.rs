@@ -24,20 +24,36 @@
 //! - **App(func, arg)**: a closure containing the argument and the current environment is pushed
 //! on the stack, and the applied term `func` is evaluated
 //! - **Let(id, term, body)**: `term` is bound to `id` in the environment, and the machine proceeds with the evaluation of the body
+//! - **LetRec(id, term, body)**: same as `Let`, but the environment of the closure of `term`
+//! contains the binding of `id` itself, such that `term` may refer to `id`
 //! - **Fun(id, body)**: Try to pop an argument from the stack. If there is some, we bound it to
 //! `id` in the environment, and proceed with the body of the function. Otherwise, we are done: the
-//! end result is an unapplied function
+//! end result is an unapplied function. If the body is itself a function, as in `fun x y => body`,
+//! the next arguments are bound in the same step, as long as there are some on the top of the
+//! stack
 //! - **Thunk on stack**: If the evaluation of the current term is done, and there is one (or
 //! several) thunk on the stack, this means we have to perform an update. Consecutive thunks are
 //! popped from the stack and are updated to point to the current evaluated term.
-//! - **Import**: Import must have been resolved before the evaluation starts. An unresolved import
-//! causes an [`InternalError`](../error/enum.EvalError.html#variant.InternalError). A resolved
-//! import, identified by a `FileId`, is retrieved from the import resolver and evaluation proceeds.
+//! - **LetPattern**: Patterns must have been desugared to plain let bindings before the
+//! evaluation starts. A remaining pattern causes an `InternalError`.
+//! - **Import**: Imports are resolved before the evaluation starts, except for the entries of an
+//! imported directory, which are only resolved when evaluated: the file is loaded and transformed
+//! by the import resolver, and a failure causes an
+//! [`ImportError`](../error/enum.EvalError.html#variant.ImportError). A resolved import,
+//! identified by a `FileId`, is retrieved from the import resolver and evaluation proceeds.
+//! - **External(name)**: the machine is suspended and the control is given back to the host,
+//! together with the saved state of the machine (see [`Suspension`](struct.Suspension.html)). Once
+//! the host provides a value, the evaluation proceeds with this value in place of the external
+//! term.
 //!
 //! ## Contracts
 //!
 //! - **`Assume(type, label, term)`** (or `Promise(type, label, term)`): replace the current term
 //! with the contract corresponding to `types`, applied to label and term (`contract label term`).
+//! - **Blame**: when a contract fails, the stack is unwound up to the innermost `catchBlame`
+//!   continuation, and the machine proceeds with the fallback it saved, applied to the label of
+//!   the error. Thunks popped on the way are not updated, and keep their original content. If
+//!   there is no such continuation, the evaluation stops with a blame error.
 //!
 //! ## Operators
 //!
@@ -68,16 +84,16 @@
 //!
 //! The evaluation of enriched values is controlled by the parameter `enriched_strict`. If it is
 //! set to true (which is usually the case), the machine tries to extract a simple value from it:
-//!  - **Contract**: raise an error. This usually means that an access to a field was attempted,
-//!  and that this field had a contract to satisfy, but it was never defined.
-//!  - **Default(value)**: an access to a field which has a default value. Proceed with the
-//!  evaluation of this value
-//!  - **ContractDefault(type, label, value)**: same as above, but the field also has an attached
-//!  contract.  Proceed with the evaluation of `Assume(type, label, value)` to ensure that the
-//!  default value satisfies this contract.
+//!  - if the metavalue has no value, raise an error. This usually means that an access to a field
+//!  was attempted, and that this field had a contract to satisfy, but it was never defined.
+//!  - otherwise, proceed with the evaluation of the value, wrapped in an `Assume` for each of the
+//!  attached contracts to ensure that it satisfies them.
 //!
-//!  If `enriched_strict` is set to false, as it is when evaluating `merge`, the machine does not
-//!  evaluate enriched values further, and consider the term evaluated.
+//!  If `enriched_strict` is set to false, as it is when evaluating the operands of `merge` or the
+//!  fields inspected by `fieldsWithMetadata`, the machine does not evaluate enriched values
+//!  further, and consider the term evaluated. Operators which are not strict (see
+//!  `BinaryOp::is_strict` and `UnaryOp::is_strict`) set it for the evaluation of their operands,
+//!  and the previous value is restored afterwards.
 //!
 //! # Garbage collection
 //!
@@ -87,16 +103,19 @@
 //! probably suboptimal for a functional language and is unable to collect cyclic data, which may
 //! appear inside recursive records in the future. An adapted garbage collector is probably
 //! something to consider at some point.
+use crate::collections::HashMap;
 use crate::error::EvalError;
 use crate::identifier::Ident;
 use crate::operation::{continuate_operation, OperationCont};
 use crate::position::RawSpan;
-use crate::program::ImportResolver;
+use crate::program::{ImportResolver, ResolvedTerm};
 use crate::stack::Stack;
-use crate::term::{RichTerm, StrChunk, Term, UnaryOp};
+use crate::term::{MetaValue, RecordData, RichTerm, StrChunk, Term, UnaryOp};
+use crate::transformations;
+use codespan::FileId;
 use std::cell::RefCell;
-use std::collections::HashMap;
 use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 
 /// An environment, which is a mapping from identifiers to closures.
 pub type Environment = HashMap<Ident, (Rc<RefCell<Closure>>, IdentKind)>;
@@ -135,7 +154,7 @@ impl Closure {
     pub fn atomic_closure(body: RichTerm) -> Closure {
         Closure {
             body,
-            env: HashMap::new(),
+            env: HashMap::default(),
         }
     }
 }
@@ -148,12 +167,369 @@ fn should_update(t: &Term) -> bool {
     !t.is_whnf() && !t.is_enriched()
 }
 
-/// The main loop of evaluation.
+/// The default maximum size of the stack of the abstract machine.
+pub const DEFAULT_STACK_LIMIT: usize = 100_000;
+
+/// The number of innermost elements of the call stack kept in a stack overflow error.
+const OVERFLOW_FRAMES: usize = 10;
+
+/// The maximum nesting of the evaluations started by primitive operations calling Nickel
+/// functions, such as `iterate` or `listSort`, when the stack is limited. Each nested evaluation
+/// runs on the stack of the host, which would overflow well before the stack of the abstract
+/// machine in a recursion going through such an operation.
+const MAX_NESTED_EVALS: usize = 16;
+
+/// Statistics about an evaluation, to monitor the cost of evaluating a program.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EvalStats {
+    /// The number of steps of the abstract machine, including the ones of the functions called by
+    /// primitive operations, such as the comparison function of `listSort`.
+    pub reductions: usize,
+    /// The number of variables whose content was evaluated, as opposed to variables whose content
+    /// was already evaluated.
+    pub thunk_forcings: usize,
+    /// The largest number of variables in a local environment.
+    pub peak_env_size: usize,
+    /// The duration of the evaluation.
+    pub wall_time: Duration,
+    /// The number of steps spent on the terms of each file, by the position of the term being
+    /// evaluated. Terms without a position, such as the ones generated by the interpreter, are
+    /// attributed to the file of the last term which has one.
+    pub reductions_by_file: HashMap<FileId, usize>,
+}
+
+/// The state of the abstract machine, minus the current closure, saved when the evaluation is
+/// suspended.
+#[derive(Debug)]
+struct MachineState {
+    stack: Stack,
+    call_stack: CallStack,
+    enriched_strict: bool,
+    /// The maximum size of the stack, if any.
+    stack_limit: Option<usize>,
+    /// The total size of the stacks of the machines waiting for this one, if it runs a nested
+    /// evaluation, which counts toward the stack limit.
+    stack_base: usize,
+    /// The number of machines waiting for this one.
+    nesting: usize,
+    /// The maximum length of the lists built by primitive operations, if any.
+    max_list_length: Option<usize>,
+}
+
+impl MachineState {
+    fn new(stack_limit: Option<usize>) -> Self {
+        MachineState {
+            stack: Stack::new(),
+            call_stack: CallStack::new(),
+            enriched_strict: true,
+            stack_limit,
+            stack_base: 0,
+            nesting: 0,
+            max_list_length: None,
+        }
+    }
+}
+
+/// The result of an evaluation which may be suspended on an external term.
+#[derive(Debug)]
+pub enum EvalStatus {
+    /// The evaluation is over.
+    Done(Term),
+    /// The evaluation hit an external term and is waiting for the host to provide its value.
+    Suspended(Suspension),
+}
+
+/// The raw result of the main loop, which keeps the environment of the final term.
+enum Outcome {
+    Done(Closure),
+    Suspended(Suspension),
+}
+
+impl From<Outcome> for EvalStatus {
+    fn from(outcome: Outcome) -> Self {
+        match outcome {
+            Outcome::Done(Closure { body, .. }) => EvalStatus::Done(*body.term),
+            Outcome::Suspended(susp) => EvalStatus::Suspended(susp),
+        }
+    }
+}
+
+/// A suspended evaluation.
 ///
-/// Implement the evaluation of the core language, which includes application, thunk update,
-/// evaluation of the arguments of operations, and a few others. The specific implementations of
-/// primitive operations is delegated to the modules [operation](../operation/index.html) and
-/// [merge](../merge/index.html).
+/// When the abstract machine reaches a term `%external% "name"`, it stops and saves its state in a
+/// suspension, which gives control back to the host. The host can fetch the requested value in
+/// any way it sees fit, and then continue the evaluation where it stopped with
+/// [`resume`](#method.resume).
+#[derive(Debug)]
+pub struct Suspension {
+    /// The name of the requested external value.
+    pub name: String,
+    /// The position of the external term.
+    pub pos: Option<RawSpan>,
+    global_env: Environment,
+    state: MachineState,
+}
+
+impl Suspension {
+    /// Resume the evaluation, substituting `value` for the external term that caused the
+    /// suspension.
+    ///
+    /// `value` is evaluated in an empty local environment, and must thus be a closed term. If the
+    /// external term was bound to a variable, the variable is updated with `value` as usual, such
+    /// that the host is only asked once for each occurrence of an external term.
+    pub fn resume<R>(self, value: RichTerm, resolver: &mut R) -> Result<EvalStatus, EvalError>
+    where
+        R: ImportResolver,
+    {
+        run(
+            Closure::atomic_closure(value),
+            self.state,
+            &self.global_env,
+            resolver,
+            &mut EvalStats::default(),
+        )
+        .map(EvalStatus::from)
+    }
+
+    /// Turn a suspension that cannot be handled into an error.
+    fn into_error(self) -> EvalError {
+        EvalError::Other(
+            format!("no value was provided for the external \"{}\"", self.name),
+            self.pos,
+        )
+    }
+}
+
+/// Evaluate a term, which must not contain external terms.
+///
+/// See [`eval_resumable`](fn.eval_resumable.html). Reaching an external term results in an error.
+pub fn eval<R>(
+    t0: RichTerm,
+    global_env: Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+) -> Result<Term, EvalError>
+where
+    R: ImportResolver,
+{
+    match eval_resumable(t0, global_env, resolver, stack_limit)? {
+        EvalStatus::Done(t) => Ok(t),
+        EvalStatus::Suspended(susp) => Err(susp.into_error()),
+    }
+}
+
+/// Fully evaluate a term, which must not contain external terms.
+///
+/// Contrary to [`eval`](fn.eval.html), which stops at a weak head normal form, lists and records
+/// are recursively evaluated, and the result is a closed term which does not contain any variable
+/// pointing to an unevaluated expression. Functions are not evaluated further.
+pub fn eval_full<R>(
+    t0: RichTerm,
+    global_env: Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+) -> Result<Term, EvalError>
+where
+    R: ImportResolver,
+{
+    eval_closure_full(
+        Closure::atomic_closure(t0),
+        &global_env,
+        resolver,
+        stack_limit,
+    )
+}
+
+/// Same as [`eval_full`](fn.eval_full.html), but for a closure, such as a subterm of the result
+/// of [`eval_closure`](fn.eval_closure.html).
+pub fn eval_closure_full<R>(
+    clos: Closure,
+    global_env: &Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+) -> Result<Term, EvalError>
+where
+    R: ImportResolver,
+{
+    let var = Ident(String::from("%full"));
+    // let %full = t0 in deepSeq %full %full
+    let wrapper = Term::Let(
+        var.clone(),
+        clos.body,
+        RichTerm::app(
+            Term::Op1(UnaryOp::DeepSeq(), Term::Var(var.clone()).into()).into(),
+            Term::Var(var).into(),
+        ),
+    );
+
+    match run(
+        Closure {
+            body: wrapper.into(),
+            env: clos.env,
+        },
+        MachineState::new(stack_limit),
+        global_env,
+        resolver,
+        &mut EvalStats::default(),
+    )? {
+        Outcome::Done(Closure { body, env }) => Ok(*subst(body, &env).term),
+        Outcome::Suspended(susp) => Err(susp.into_error()),
+    }
+}
+
+/// Evaluate a closure to a weak head normal form, and return it together with its environment.
+///
+/// Contrary to [`eval`](fn.eval.html), the variables of the result are not lost, such that the
+/// subterms of a list or a record can in turn be evaluated, independently of each other. The
+/// closure must not contain external terms.
+pub fn eval_closure<R>(
+    clos: Closure,
+    global_env: &Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+) -> Result<Closure, EvalError>
+where
+    R: ImportResolver,
+{
+    eval_closure_bounded(clos, global_env, resolver, stack_limit, None)
+}
+
+/// Same as [`eval_closure`](fn.eval_closure.html), but fail with a
+/// [`ListTooLong`](../error/enum.EvalError.html#variant.ListTooLong) error as soon as a primitive
+/// operation, such as a concatenation, builds a list of more than `max_list_length` elements.
+///
+/// The check is done on each intermediate list, such that a list blowing up, as an accidental
+/// cartesian product, is stopped before it is built in full.
+pub fn eval_closure_bounded<R>(
+    clos: Closure,
+    global_env: &Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+    max_list_length: Option<usize>,
+) -> Result<Closure, EvalError>
+where
+    R: ImportResolver,
+{
+    match run(
+        clos,
+        MachineState {
+            max_list_length,
+            ..MachineState::new(stack_limit)
+        },
+        global_env,
+        resolver,
+        &mut EvalStats::default(),
+    )? {
+        Outcome::Done(clos) => Ok(clos),
+        Outcome::Suspended(susp) => Err(susp.into_error()),
+    }
+}
+
+/// The result of a partial evaluation (see [`eval_partial`](fn.eval_partial.html)).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialResult {
+    /// The fully evaluated term. The fields whose evaluation failed are replaced by a metavalue
+    /// without value, as a field which is declared but not defined.
+    pub term: Term,
+    /// The errors of the fields which failed, together with their path from the root, sorted by
+    /// path.
+    pub errors: Vec<(Vec<Ident>, EvalError)>,
+}
+
+/// Fully evaluate a term as [`eval_full`](fn.eval_full.html), but do not stop at the first error
+/// in a record field.
+///
+/// Each field of a record is evaluated independently of its siblings. If it fails, the error is
+/// recorded together with the path of the field, and the evaluation continues with the next
+/// field. This lets a tool display the valid parts of a configuration which is partially broken.
+/// Lists are evaluated as a whole, such that an error in an element is recorded for the field
+/// containing the list. The evaluation still fails if the root of the term cannot be evaluated,
+/// or on an internal error of the interpreter.
+pub fn eval_partial<R>(
+    t0: RichTerm,
+    global_env: Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+) -> Result<PartialResult, EvalError>
+where
+    R: ImportResolver,
+{
+    let mut errors = Vec::new();
+    let term = eval_closure_partial(
+        Closure::atomic_closure(t0),
+        &global_env,
+        resolver,
+        stack_limit,
+        &mut Vec::new(),
+        &mut errors,
+    )?;
+
+    Ok(PartialResult { term, errors })
+}
+
+/// Partially evaluate a closure located at `path` (see [`eval_partial`](fn.eval_partial.html)),
+/// accumulating the errors of the fields in `errors`.
+fn eval_closure_partial<R>(
+    clos: Closure,
+    global_env: &Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+    path: &mut Vec<Ident>,
+    errors: &mut Vec<(Vec<Ident>, EvalError)>,
+) -> Result<Term, EvalError>
+where
+    R: ImportResolver,
+{
+    let Closure { body, env } = eval_closure(clos, global_env, resolver, stack_limit)?;
+
+    match *body.term {
+        Term::Record(fields) => {
+            let mut result = RecordData::new();
+            // The fields are iterated in the order of their names, such that the errors are
+            // sorted by path.
+            for (id, t) in fields {
+                path.push(id.clone());
+                let field = Closure {
+                    body: t,
+                    env: env.clone(),
+                };
+                let value = match eval_closure_partial(
+                    field,
+                    global_env,
+                    resolver,
+                    stack_limit,
+                    path,
+                    errors,
+                ) {
+                    Ok(value) => value,
+                    Err(err @ EvalError::InternalError(..)) => return Err(err),
+                    Err(err) => {
+                        errors.push((path.clone(), err));
+                        Term::MetaValue(MetaValue::default())
+                    }
+                };
+                path.pop();
+                result.insert(id, value.into());
+            }
+
+            Ok(Term::Record(result))
+        }
+        term => eval_closure_full(
+            Closure {
+                body: RichTerm {
+                    term: Box::new(term),
+                    ..body
+                },
+                env,
+            },
+            global_env,
+            resolver,
+            stack_limit,
+        ),
+    }
+}
+
+/// Evaluate a term, suspending the evaluation each time an external term is reached.
 ///
 /// # Arguments
 ///
@@ -161,24 +537,158 @@ fn should_update(t: &Term) -> bool {
 /// - `global_env`: the global environment containing the builtin functions of the language. Accessible from anywhere in the
 /// program.
 /// - `resolver`: the interface to fetch imports.
-pub fn eval<R>(t0: RichTerm, global_env: Environment, resolver: &mut R) -> Result<Term, EvalError>
+/// - `stack_limit`: the maximum size of the stack of the abstract machine. Exceeding it, which
+///   usually denotes an unbounded recursion, results in a
+///   [`StackOverflow`](../error/enum.EvalError.html#variant.StackOverflow) error. `None` means no
+///   limit.
+pub fn eval_resumable<R>(
+    t0: RichTerm,
+    global_env: Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+) -> Result<EvalStatus, EvalError>
+where
+    R: ImportResolver,
+{
+    run(
+        Closure::atomic_closure(t0),
+        MachineState::new(stack_limit),
+        &global_env,
+        resolver,
+        &mut EvalStats::default(),
+    )
+    .map(EvalStatus::from)
+}
+
+/// Same as [`eval`](fn.eval.html), but also return statistics about the evaluation, whether it
+/// succeeded or not.
+pub fn eval_with_stats<R>(
+    t0: RichTerm,
+    global_env: Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+) -> (Result<Term, EvalError>, EvalStats)
 where
     R: ImportResolver,
 {
-    let mut clos = Closure::atomic_closure(t0);
-    let mut call_stack = CallStack::new();
-    let mut stack = Stack::new();
-    let mut enriched_strict = true;
+    let mut stats = EvalStats::default();
+    let start = Instant::now();
+    let result = run(
+        Closure::atomic_closure(t0),
+        MachineState::new(stack_limit),
+        &global_env,
+        resolver,
+        &mut stats,
+    )
+    .and_then(|outcome| match EvalStatus::from(outcome) {
+        EvalStatus::Done(t) => Ok(t),
+        EvalStatus::Suspended(susp) => Err(susp.into_error()),
+    });
+    stats.wall_time = start.elapsed();
+
+    (result, stats)
+}
+
+/// The main loop of evaluation.
+///
+/// Implement the evaluation of the core language, which includes application, thunk update,
+/// evaluation of the arguments of operations, and a few others. The specific implementations of
+/// primitive operations is delegated to the modules [operation](../operation/index.html) and
+/// [merge](../merge/index.html). The counters of `stats` are updated along the way.
+fn run<R>(
+    clos: Closure,
+    state: MachineState,
+    global_env: &Environment,
+    resolver: &mut R,
+    stats: &mut EvalStats,
+) -> Result<Outcome, EvalError>
+where
+    R: ImportResolver,
+{
+    let mut file = FileSteps {
+        file: None,
+        start: stats.reductions,
+    };
+    let result = run_(clos, state, global_env, resolver, stats, &mut file);
+    file.flush(stats);
+    result
+}
+
+/// The steps of the abstract machine spent on the current file, which are only added to
+/// [`EvalStats::reductions_by_file`](struct.EvalStats.html#structfield.reductions_by_file) when
+/// the evaluation moves to another file, to keep the cost of the statistics low.
+struct FileSteps {
+    file: Option<FileId>,
+    /// The value of `EvalStats::reductions` when the evaluation entered the current file.
+    start: usize,
+}
+
+impl FileSteps {
+    /// Attribute the steps since the last change to the current file, and switch to `file`.
+    fn enter(&mut self, file: FileId, stats: &mut EvalStats) {
+        if self.file != Some(file) {
+            self.flush(stats);
+            self.file = Some(file);
+        }
+    }
+
+    fn flush(&mut self, stats: &mut EvalStats) {
+        if let Some(file) = self.file {
+            *stats.reductions_by_file.entry(file).or_insert(0) += stats.reductions - self.start;
+        }
+        self.start = stats.reductions;
+    }
+}
+
+fn run_<R>(
+    mut clos: Closure,
+    state: MachineState,
+    global_env: &Environment,
+    resolver: &mut R,
+    stats: &mut EvalStats,
+    file: &mut FileSteps,
+) -> Result<Outcome, EvalError>
+where
+    R: ImportResolver,
+{
+    let MachineState {
+        mut stack,
+        mut call_stack,
+        mut enriched_strict,
+        stack_limit,
+        stack_base,
+        nesting,
+        max_list_length,
+    } = state;
 
     loop {
         let Closure {
-            body: RichTerm {
-                term: boxed_term,
-                pos,
-            },
+            body:
+                RichTerm {
+                    term: boxed_term,
+                    pos,
+                    ..
+                },
             mut env,
         } = clos;
         let term = *boxed_term;
+        if let Some(span) = &pos {
+            file.enter(span.src_id, stats);
+        }
+        stats.reductions += 1;
+        stats.peak_env_size = stats.peak_env_size.max(env.len());
+
+        if let Some(limit) = stack_limit {
+            if stack_base + stack.len() > limit || nesting > MAX_NESTED_EVALS {
+                let frames_start = call_stack.len().saturating_sub(OVERFLOW_FRAMES);
+                return Err(EvalError::StackOverflow(
+                    limit,
+                    call_stack.split_off(frames_start),
+                    pos,
+                ));
+            }
+        }
+
         clos = match term {
             Term::Var(x) => {
                 let (thunk, id_kind) = env
@@ -191,6 +701,7 @@ where
                     .ok_or(EvalError::UnboundIdentifier(x.clone(), pos.clone()))?;
                 std::mem::drop(env); // thunk may be a 1RC pointer
                 if should_update(&thunk.borrow().body.term) {
+                    stats.thunk_forcings += 1;
                     stack.push_thunk(Rc::downgrade(&thunk));
                 }
                 call_stack.push(StackElem::Var(id_kind, x, pos));
@@ -223,13 +734,29 @@ where
                 env.insert(x, (Rc::clone(&thunk), IdentKind::Let()));
                 Closure { body: t, env }
             }
+            Term::LetRec(x, s, t) => {
+                let thunk = Rc::new(RefCell::new(Closure {
+                    body: s,
+                    env: HashMap::default(),
+                }));
+                env.insert(x, (Rc::clone(&thunk), IdentKind::Let()));
+                // The thunk and its environment form a cycle, as for recursive records.
+                thunk.borrow_mut().env = env.clone();
+                Closure { body: t, env }
+            }
             Term::Op1(op, t) => {
                 let op = op.map(|t| Closure {
                     body: t,
                     env: env.clone(),
                 });
 
-                stack.push_op_cont(OperationCont::Op1(op, t.pos.clone()), call_stack.len(), pos);
+                let prev_strict = enriched_strict;
+                enriched_strict = enriched_strict && op.is_strict();
+                stack.push_op_cont(
+                    OperationCont::Op1(op, t.pos.clone(), prev_strict),
+                    call_stack.len(),
+                    pos,
+                );
                 Closure { body: t, env }
             }
             Term::Op2(op, fst, snd) => {
@@ -258,7 +785,7 @@ where
             Term::StrChunks(mut chunks) => match chunks.pop() {
                 None => Closure {
                     body: Term::Str(String::new()).into(),
-                    env: HashMap::new(),
+                    env: HashMap::default(),
                 },
                 Some(chunk) => {
                     let arg = match chunk {
@@ -267,13 +794,10 @@ where
                     };
 
                     Closure {
-                        body: RichTerm {
-                            term: Box::new(Term::Op1(
-                                UnaryOp::ChunksConcat(String::new(), chunks),
-                                arg,
-                            )),
+                        body: RichTerm::with_pos(
+                            Box::new(Term::Op1(UnaryOp::ChunksConcat(String::new(), chunks), arg)),
                             pos,
-                        },
+                        ),
                         env,
                     }
                 }
@@ -295,9 +819,10 @@ where
             Term::RecRecord(ts) => {
                 // Thanks to the share normal form transformation, the content is either a constant or a
                 // variable.
-                let rec_env =
-                    ts.iter()
-                        .try_fold(HashMap::new(), |mut rec_env, (id, rt)| match rt.as_ref() {
+                let rec_env = ts
+                    .iter()
+                    .try_fold(HashMap::default(), |mut rec_env, (id, rt)| {
+                        match rt.as_ref() {
                             &Term::Var(ref var_id) => {
                                 let (thunk, id_kind) = env.get(var_id).ok_or(
                                     EvalError::UnboundIdentifier(var_id.clone(), rt.pos.clone()),
@@ -311,7 +836,7 @@ where
                                 // environment, which is it is dropped.
                                 let closure = Closure {
                                     body: rt.clone(),
-                                    env: HashMap::new(),
+                                    env: HashMap::default(),
                                 };
                                 rec_env.insert(
                                     id.clone(),
@@ -319,47 +844,38 @@ where
                                 );
                                 Ok(rec_env)
                             }
-                        })?;
+                        }
+                    })?;
 
                 let new_ts = ts.into_iter().map(|(id, rt)| {
-                    let RichTerm { term, pos } = rt;
+                    let RichTerm { term, pos, .. } = rt;
                     match *term {
                         Term::Var(var_id) => {
                             // We already checked for unbound identifier in the previous fold, so this
                             // get should always succeed.
                             let (thunk, _) = env.get(&var_id).unwrap();
                             thunk.borrow_mut().env.extend(rec_env.clone());
-                            (
-                                id,
-                                RichTerm {
-                                    term: Box::new(Term::Var(var_id)),
-                                    pos,
-                                },
-                            )
+                            (id, RichTerm::with_pos(Box::new(Term::Var(var_id)), pos))
                         }
-                        _ => (id, RichTerm { term, pos }),
+                        _ => (id, RichTerm::with_pos(term, pos)),
                     }
                 });
                 Closure {
-                    body: RichTerm {
-                        term: Box::new(Term::Record(new_ts.collect())),
-                        pos,
-                    },
+                    body: RichTerm::with_pos(Box::new(Term::Record(new_ts.collect())), pos),
                     env,
                 }
             }
             // Unwrapping of enriched terms
-            Term::Contract(_, _) if enriched_strict => {
-                return Err(EvalError::Other(
-                    String::from(
-                        "Expected a simple term, got a Contract. Contracts cannot be evaluated",
-                    ),
-                    pos,
-                ));
-            }
-            enriched @ Term::DefaultValue(_) | enriched @ Term::Docstring(_, _)
-                if enriched_strict =>
-            {
+            Term::MetaValue(meta) if enriched_strict => {
+                if meta.value.is_none() {
+                    return Err(EvalError::Other(
+                        String::from(
+                            "Expected a simple term, got a Contract. Contracts cannot be evaluated",
+                        ),
+                        pos,
+                    ));
+                }
+
                 /* Since we are forcing an enriched value, we are morally breaking subject
                  * reduction (i.e., the type of the current term changes from `enriched something`
                  * to just `something`). Updating a thunk after having performed this forcing may
@@ -368,32 +884,18 @@ where
                  * that their content remains an enriched value.
                  */
                 let update_closure = Closure {
-                    body: RichTerm {
-                        term: Box::new(enriched),
-                        pos,
-                    },
+                    body: RichTerm::with_pos(Box::new(Term::MetaValue(meta)), pos),
                     env,
                 };
                 update_thunks(&mut stack, &update_closure);
 
-                let Closure {
-                    body:
-                        RichTerm {
-                            term: enriched_box,
-                            pos: _,
-                        },
-                    env,
-                } = update_closure;
-                let t = match *enriched_box {
-                    Term::DefaultValue(t) | Term::Docstring(_, t) => t,
-                    _ => panic!("eval::eval(): previous match enforced that a term is a default or a docstring, but matched something else")
+                let Closure { body, env } = update_closure;
+                let t = match *body.term {
+                    Term::MetaValue(meta) => meta.checked_value().unwrap(),
+                    _ => panic!("eval::eval(): previous match enforced that a term is a meta value, but matched something else")
                 };
                 Closure { body: t, env }
             }
-            Term::ContractWithDefault(ty, label, t) if enriched_strict => Closure {
-                body: Term::Assume(ty, label, t).into(),
-                env,
-            },
             Term::ResolvedImport(id) => {
                 if let Some(t) = resolver.get(id) {
                     Closure::atomic_closure(t)
@@ -405,47 +907,126 @@ where
                 }
             }
             Term::Import(path) => {
+                let (resolved, id) = resolver
+                    .resolve(&path, None, &pos)
+                    .map_err(EvalError::ImportError)?;
+                if let ResolvedTerm::FromFile(t, parent) = resolved {
+                    let t = transformations::transform_from(t, Some(parent), resolver)
+                        .map_err(EvalError::ImportError)?;
+                    resolver.insert(id, t);
+                }
+
+                if let Some(t) = resolver.get(id) {
+                    Closure::atomic_closure(t)
+                } else {
+                    return Err(EvalError::InternalError(
+                        format!("Resolved import not found ({:?})", id),
+                        pos,
+                    ));
+                }
+            }
+            Term::LetPattern(pat, _, _) => {
                 return Err(EvalError::InternalError(
-                    format!("Unresolved import ({})", path),
+                    format!("Unexpanded pattern ({})", pat),
                     pos,
                 ))
             }
+            Term::External(name) => {
+                let state = MachineState {
+                    stack,
+                    call_stack,
+                    enriched_strict,
+                    stack_limit,
+                    stack_base,
+                    nesting,
+                    max_list_length,
+                };
+                return Ok(Outcome::Suspended(Suspension {
+                    name,
+                    pos,
+                    global_env: global_env.clone(),
+                    state,
+                }));
+            }
             // Continuation of operations and thunk update
             _ if stack.is_top_thunk() || stack.is_top_cont() => {
                 clos = Closure {
-                    body: RichTerm {
-                        term: Box::new(term),
-                        pos,
-                    },
+                    body: RichTerm::with_pos(Box::new(term), pos),
                     env,
                 };
                 if stack.is_top_thunk() {
                     update_thunks(&mut stack, &clos);
                     clos
                 } else {
+                    let nested_base = stack_base + stack.len();
                     let cont_result = continuate_operation(
                         clos,
                         &mut stack,
                         &mut call_stack,
                         &mut enriched_strict,
+                        max_list_length,
+                        // The nested machine shares the global environment, the statistics and the
+                        // stack limit of this one.
+                        &mut |clos| {
+                            let state = MachineState {
+                                stack_base: nested_base,
+                                nesting: nesting + 1,
+                                max_list_length,
+                                ..MachineState::new(stack_limit)
+                            };
+                            match run_(clos, state, global_env, resolver, stats, file)? {
+                                Outcome::Done(clos) => Ok(clos),
+                                Outcome::Suspended(susp) => Err(susp.into_error()),
+                            }
+                        },
                     );
 
-                    if let Err(EvalError::BlameError(l, _)) = cont_result {
-                        return Err(EvalError::BlameError(l, Some(call_stack)));
+                    match cont_result {
+                        Err(EvalError::BlameError(l, _)) => match stack.unwind_to_catch() {
+                            Some((fallback, cs_len, prev_strict)) => {
+                                call_stack.truncate(cs_len);
+                                if let Some(prev_strict) = prev_strict {
+                                    enriched_strict = prev_strict;
+                                }
+                                stack.push_arg(Closure::atomic_closure(Term::Lbl(l).into()), None);
+                                fallback
+                            }
+                            None => return Err(EvalError::BlameError(l, Some(call_stack))),
+                        },
+                        cont_result => cont_result?,
                     }
-                    cont_result?
                 }
             }
             // Function call
             Term::Fun(x, t) => {
                 if 0 < stack.count_args() {
-                    let (arg, pos_app) = stack.pop_arg().expect("Condition already checked.");
-                    call_stack.push(StackElem::App(pos_app));
-                    let thunk = Rc::new(RefCell::new(arg));
-                    env.insert(x, (thunk, IdentKind::Lam()));
-                    Closure { body: t, env }
+                    // The arguments of a function of several parameters, such as `fun x y z =>
+                    // body`, are bound at once, instead of going through one step per parameter.
+                    let mut x = x;
+                    let mut body = t;
+                    loop {
+                        let (arg, pos_app) = stack.pop_arg().expect("Condition already checked.");
+                        call_stack.push(StackElem::App(pos_app));
+                        let thunk = Rc::new(RefCell::new(arg));
+                        env.insert(x, (thunk, IdentKind::Lam()));
+
+                        if stack.count_args() == 0 || !matches!(body.as_ref(), Term::Fun(..)) {
+                            break Closure { body, env };
+                        }
+
+                        match *body.term {
+                            Term::Fun(y, next) => {
+                                x = y;
+                                body = next;
+                            }
+                            _ => unreachable!("expected a function"),
+                        }
+                    }
                 } else {
-                    return Ok(Term::Fun(x, t));
+                    return Ok(Outcome::Done(Closure {
+                        body: RichTerm::with_pos(Box::new(Term::Fun(x, t)), pos),
+                        env,
+                    }));
                 }
             }
             // Otherwise, this is either an ill-formed application, or we are done
@@ -453,21 +1034,52 @@ where
                 if 0 < stack.count_args() {
                     let (arg, pos_app) = stack.pop_arg().expect("Condition already checked.");
                     return Err(EvalError::NotAFunc(
-                        RichTerm {
-                            term: Box::new(t),
-                            pos,
-                        },
+                        RichTerm::with_pos(Box::new(t), pos),
                         arg.body,
                         pos_app,
                     ));
                 } else {
-                    return Ok(t);
+                    return Ok(Outcome::Done(Closure {
+                        body: RichTerm::with_pos(Box::new(t), pos),
+                        env,
+                    }));
                 }
             }
         }
     }
 }
 
+/// Substitute the variables of an evaluated term with their content, recursively.
+///
+/// Lists and records are traversed, such that a deeply evaluated term (see `deepSeq`) gives back a
+/// closed term which does not depend on an environment anymore. Meta values are replaced by their
+/// value, which has been checked by their contracts when it was forced. Other terms, such as
+/// functions, are returned as they are.
+pub fn subst(rt: RichTerm, env: &Environment) -> RichTerm {
+    let RichTerm { term, pos, .. } = rt;
+    match *term {
+        Term::Var(id) => match env.get(&id) {
+            Some((thunk, _)) => {
+                let Closure { body, env } = thunk.borrow().clone();
+                subst(body, &env)
+            }
+            None => RichTerm::with_pos(Box::new(Term::Var(id)), pos),
+        },
+        Term::List(ts) => RichTerm::with_pos(
+            Box::new(Term::List(ts.into_iter().map(|t| subst(t, env)).collect())),
+            pos,
+        ),
+        Term::Record(map) => RichTerm::with_pos(
+            Box::new(Term::Record(
+                map.into_iter().map(|(id, t)| (id, subst(t, env))).collect(),
+            )),
+            pos,
+        ),
+        Term::MetaValue(MetaValue { value: Some(t), .. }) => subst(t, env),
+        t => RichTerm::with_pos(Box::new(t), pos),
+    }
+}
+
 /// Pop and update all the thunks on the top of the stack with the given closure.
 fn update_thunks(stack: &mut Stack, closure: &Closure) {
     while let Some(thunk) = stack.pop_thunk() {
@@ -484,12 +1096,12 @@ mod tests {
     use crate::label::Label;
     use crate::program::resolvers::{DummyResolver, SimpleResolver};
     use crate::term::StrChunk;
-    use crate::term::{BinaryOp, UnaryOp};
+    use crate::term::{BinaryOp, MergePriority, UnaryOp};
     use crate::transformations::transform;
 
     /// Evaluate a term without import support.
     fn eval_no_import(t: RichTerm) -> Result<Term, EvalError> {
-        eval(t, HashMap::new(), &mut DummyResolver {})
+        eval(t, HashMap::default(), &mut DummyResolver {}, None)
     }
 
     #[test]
@@ -593,13 +1205,21 @@ mod tests {
         assert_eq!(Ok(Term::Bool(true)), eval_no_import(lambda));
     }
 
+    fn default(t: RichTerm) -> RichTerm {
+        Term::MetaValue(MetaValue {
+            priority: MergePriority::Default,
+            ..MetaValue::from_value(t)
+        })
+        .into()
+    }
+
     #[test]
     fn enriched_terms_unwrapping() {
-        let t = Term::DefaultValue(
-            Term::DefaultValue(Term::Docstring("a".to_string(), Term::Bool(false).into()).into())
-                .into(),
-        )
-        .into();
+        let doc = Term::MetaValue(MetaValue {
+            doc: Some("a".to_string()),
+            ..MetaValue::from_value(Term::Bool(false).into())
+        });
+        let t = default(default(doc.into()));
         assert_eq!(Ok(Term::Bool(false)), eval_no_import(t));
     }
 
@@ -608,7 +1228,7 @@ mod tests {
         let t = Term::Op2(
             BinaryOp::Merge(),
             Term::Num(1.0).into(),
-            Term::DefaultValue(Term::Num(2.0).into()).into(),
+            default(Term::Num(2.0).into()),
         )
         .into();
         assert_eq!(Ok(Term::Num(1.0)), eval_no_import(t));
@@ -618,8 +1238,8 @@ mod tests {
     fn merge_incompatible_defaults() {
         let t = Term::Op2(
             BinaryOp::Merge(),
-            Term::DefaultValue(Term::Num(1.0).into()).into(),
-            Term::DefaultValue(Term::Num(2.0).into()).into(),
+            default(Term::Num(1.0).into()),
+            default(Term::Num(2.0).into()),
         )
         .into();
 
@@ -683,8 +1303,9 @@ mod tests {
         assert_eq!(
             eval(
                 mk_import("x", "two", RichTerm::var(String::from("x")), &mut resolver).unwrap(),
-                HashMap::new(),
-                &mut resolver
+                HashMap::default(),
+                &mut resolver,
+                None
             )
             .unwrap(),
             Term::Num(2.0)
@@ -700,8 +1321,9 @@ mod tests {
                     &mut resolver
                 )
                 .unwrap(),
-                HashMap::new(),
-                &mut resolver
+                HashMap::default(),
+                &mut resolver,
+                None
             )
             .unwrap(),
             Term::Num(3.0)
@@ -721,8 +1343,9 @@ mod tests {
                     &mut resolver,
                 )
                 .unwrap(),
-                HashMap::new(),
-                &mut resolver
+                HashMap::default(),
+                &mut resolver,
+                None
             )
             .unwrap(),
             Term::Bool(true)
@@ -742,8 +1365,9 @@ mod tests {
                     &mut resolver,
                 )
                 .unwrap(),
-                HashMap::new(),
-                &mut resolver
+                HashMap::default(),
+                &mut resolver,
+                None
             )
             .unwrap(),
             Term::Num(1.0)
@@ -815,11 +1439,11 @@ mod tests {
 
     #[test]
     fn global_env() {
-        let mut global_env = HashMap::new();
+        let mut global_env = HashMap::default();
         let mut resolver = DummyResolver {};
         let thunk = Rc::new(RefCell::new(Closure {
             body: Term::Num(1.0).into(),
-            env: HashMap::new(),
+            env: HashMap::default(),
         }));
         global_env.insert(Ident::from("g"), (Rc::clone(&thunk), IdentKind::Let()));
 
@@ -829,7 +1453,7 @@ mod tests {
             Term::Var(Ident::from("x")).into(),
         );
         assert_eq!(
-            eval(t, global_env.clone(), &mut resolver),
+            eval(t, global_env.clone(), &mut resolver, None),
             Ok(Term::Num(2.0))
         );
 
@@ -839,7 +1463,7 @@ mod tests {
             Term::Var(Ident::from("g")).into(),
         );
         assert_eq!(
-            eval(t, global_env.clone(), &mut resolver),
+            eval(t, global_env.clone(), &mut resolver, None),
             Ok(Term::Num(1.0))
         );
 
@@ -850,7 +1474,7 @@ mod tests {
             Term::Var(Ident::from("g")).into(),
         );
         assert_eq!(
-            eval(t, global_env.clone(), &mut resolver),
+            eval(t, global_env.clone(), &mut resolver, None),
             Ok(Term::Num(2.0))
         );
     }
@@ -1,7 +1,7 @@
 //! Define the type of an identifier.
 use std::fmt;
 
-#[derive(Debug, Eq, Hash, PartialEq, Clone)]
+#[derive(Debug, Eq, Hash, PartialEq, PartialOrd, Ord, Clone)]
 pub struct Ident(pub String);
 
 impl fmt::Display for Ident {
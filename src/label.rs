@@ -29,6 +29,10 @@ pub mod ty_path {
     //! This is the information encoded by a type path: what part of the original type is currently
     //! being checked by this label. It is then reported to the user in case of a blame.
     //!
+    //! A path may also go through the fields of record types, when the contract of a record is
+    //! [compiled](../../transformations/contract_compilation/index.html) and checks each field with
+    //! a label of its own.
+    //!
     //! Paths are encoded as lists of elements, specifying if the next step is either to go to the **domain**
     //! or to the **codomain**.
 
@@ -36,17 +40,18 @@ pub mod ty_path {
     use crate::identifier::Ident;
 
     /// An element of a path type.
-    #[derive(Debug, Clone, Copy, PartialEq)]
+    #[derive(Debug, Clone, PartialEq)]
     pub enum Elem {
         Domain,
         Codomain,
+        Field(Ident),
     }
 
     pub type Path = Vec<Elem>;
 
-    /// Determine if the path has only `Codomain` components.
+    /// Determine if the path has only `Codomain` components, not counting the fields of records.
     pub fn is_only_codom(p: &Path) -> bool {
-        p.iter().all(|elt| *elt == Elem::Codomain)
+        p.iter().all(|elt| *elt != Elem::Domain)
     }
 
     /// Return the position span encoded by a type path in the string representation of the
@@ -120,9 +125,40 @@ pub mod ty_path {
                         let offset = (paren_offset * 2) + 4 + dom_end + forall_offset;
                         (codom_start + offset, codom_end + offset)
                     }
+                    // A field of a function type: we fallback to the whole type.
+                    Elem::Field(_) => {
+                        let repr = format!("{}", ty);
+                        (forall_offset, forall_offset + repr.len())
+                    }
+                }
+            }
+            (AbsType::StaticRecord(row), Some(Elem::Field(id))) => {
+                // The representation of the row, which starts after "{ {| ", ends with the one of
+                // the row starting at the field.
+                let row_len = format!("{}", row).len();
+                let mut field = row.as_ref();
+                while let AbsType::RowExtend(other, ty_opt, tail) = &field.0 {
+                    match ty_opt {
+                        Some(ty) if other == id => {
+                            let offset = forall_offset + 5 + row_len - format!("{}", field).len();
+                            // The type of the field follows its name and ": ".
+                            let offset = offset + id.0.len() + 2;
+                            let (start, end) = span(path_it, ty);
+                            return (start + offset, end + offset);
+                        }
+                        _ => field = tail,
+                    }
                 }
+
+                let repr = format!("{}", ty);
+                (forall_offset, forall_offset + repr.len())
+            }
+            // The path goes through a type whose inner structure is not tracked by type paths, or
+            // which doesn't match the path: we fallback to the whole type.
+            _ => {
+                let repr = format!("{}", ty);
+                (forall_offset, forall_offset + repr.len())
             }
-            _ => panic!(),
         }
     }
 }
@@ -177,6 +213,9 @@ pub struct Label {
     pub polarity: bool,
     /// The path of the type being currently checked in the original type.
     pub path: ty_path::Path,
+    /// The position of the value which broke the contract, if known. It is set by the builtin
+    /// contracts right before blaming.
+    pub arg_pos: Option<RawSpan>,
 }
 
 #[cfg(test)]
@@ -196,6 +235,7 @@ impl Label {
             },
             polarity: false,
             path: Vec::new(),
+            arg_pos: None,
         }
     }
 }
@@ -0,0 +1,32 @@
+//! The Nickel interpreter as a library.
+//!
+//! The `nickel` binary is a thin wrapper around this crate. Exposing the interpreter as a library
+//! makes it possible to embed it in other programs, and to drive it from external harnesses such as
+//! the fuzzing targets of the `fuzz` directory.
+pub mod analysis;
+pub mod api;
+pub mod collections;
+pub mod deps;
+pub mod diff;
+pub mod error;
+pub mod error_codes;
+pub mod eval;
+pub mod identifier;
+pub mod label;
+pub mod manifest;
+pub mod merge;
+pub mod operation;
+pub mod parser;
+pub mod paths;
+pub mod position;
+pub mod preview;
+pub mod program;
+pub mod serialize;
+pub mod specialize;
+pub mod stack;
+pub mod stdlib;
+pub mod term;
+pub mod transformations;
+pub mod typecheck;
+pub mod types;
+pub mod watch;
@@ -1,30 +1,215 @@
 //! Entry point of the program.
-mod error;
-mod eval;
-mod identifier;
-mod label;
-mod merge;
-mod operation;
-mod parser;
-mod position;
-mod program;
-mod stack;
-mod stdlib;
-mod term;
-mod transformations;
-mod typecheck;
-mod types;
-
-use crate::program::Program;
-
-extern crate either;
+//!
+//! The program is read from the standard input and evaluated. With `--export`, the result is
+//! instead serialized to the standard output, in the format given by `--format` (`json` or
+//! `yaml`, JSON by default). `--header <text>` writes a header comment at the beginning of the
+//! output, if the format supports comments. `--max-size <bytes>` and `--max-list-length <n>` abort
+//! the export when the output grows too large. `--newline crlf` writes Windows line endings.
+//! `--precision <digits>` rounds the exported numbers to the given number of significant digits.
+//! `--no-inline` disables the inlining of small functions, which is useful to debug the
+//! interpreter. `--import-graph <format>` prints the graph of the imports of the program, in the
+//! `dot` or `json` format, instead of evaluating it. `--manifest <file>` writes the files read
+//! and the values obtained from the host during the evaluation to `file`, in JSON (see the
+//! `manifest` module). `--trace-imports` prints, after the evaluation, the time spent loading each
+//! imported file, the number of imports served from the cache, and the share of the evaluation
+//! spent on each file. The latter is not measured when exporting. `--jobs <n>` exports the fields
+//! of the top-level record in `n` threads. `--error-format json` prints the errors and the
+//! warnings on the standard error in JSON, together with their error codes, instead of rendering
+//! them for a terminal.
+//!
+//! `nickel explain <code>` prints the extended explanation of an error code, such as `E0002`,
+//! instead of running a program.
+use codespan::FileId;
+use nickel::error::ToDiagnostic;
+use nickel::error_codes;
+use nickel::eval::EvalStats;
+use nickel::program::Program;
+use nickel::serialize::{ExportFormat, ExportOptions, Newline};
+use std::io;
+
+/// The command line options.
+#[derive(Default)]
+struct Options {
+    /// Export the result instead of printing it.
+    export: bool,
+    /// Disable the inlining of small functions, for debugging.
+    no_inline: bool,
+    /// Explain the given error code instead of running a program.
+    explain: Option<String>,
+    /// Print the import graph in the given format instead of running the program.
+    import_graph: Option<GraphFormat>,
+    /// Write the manifest of the evaluation to this file.
+    manifest: Option<String>,
+    /// Print a report on the cost of the imports after the evaluation.
+    trace_imports: bool,
+    /// The number of threads of the export.
+    jobs: Option<usize>,
+    /// Print the errors in JSON.
+    json_errors: bool,
+    export_options: ExportOptions,
+}
+
+/// The output formats of the import graph.
+enum GraphFormat {
+    Dot,
+    Json,
+}
+
+/// Parse the value of a limit given by the option `name`.
+fn parse_limit(name: &str, args: &mut impl Iterator<Item = String>) -> Result<usize, String> {
+    let value = args
+        .next()
+        .ok_or_else(|| format!("missing value for {}", name))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {} for {}", value, name))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "explain" if options.explain.is_none() => {
+                options.explain = Some(args.next().ok_or("missing error code for explain")?);
+            }
+            "--export" => options.export = true,
+            "--no-inline" => options.no_inline = true,
+            "--trace-imports" => options.trace_imports = true,
+            "--format" => {
+                let name = args.next().ok_or("missing value for --format")?;
+                options.export_options.format = ExportFormat::from_name(&name)
+                    .ok_or_else(|| format!("unknown export format {}", name))?;
+            }
+            "--error-format" => {
+                let name = args.next().ok_or("missing value for --error-format")?;
+                options.json_errors = match name.as_str() {
+                    "human" => false,
+                    "json" => true,
+                    _ => return Err(format!("unknown error format {}", name)),
+                };
+            }
+            "--newline" => {
+                let name = args.next().ok_or("missing value for --newline")?;
+                options.export_options.newline = Newline::from_name(&name)
+                    .ok_or_else(|| format!("unknown newline convention {}", name))?;
+            }
+            "--import-graph" => {
+                let name = args.next().ok_or("missing value for --import-graph")?;
+                options.import_graph = match name.as_str() {
+                    "dot" => Some(GraphFormat::Dot),
+                    "json" => Some(GraphFormat::Json),
+                    _ => return Err(format!("unknown graph format {}", name)),
+                };
+            }
+            "--manifest" => {
+                options.manifest = Some(args.next().ok_or("missing value for --manifest")?);
+            }
+            "--header" => {
+                options.export_options.header =
+                    Some(args.next().ok_or("missing value for --header")?);
+            }
+            "--max-size" => options.export_options.max_size = Some(parse_limit(&arg, &mut args)?),
+            "--jobs" => options.jobs = Some(parse_limit(&arg, &mut args)?),
+            "--precision" => {
+                options.export_options.num_format.precision = Some(parse_limit(&arg, &mut args)?)
+            }
+            "--max-list-length" => {
+                options.export_options.max_list_length = Some(parse_limit(&arg, &mut args)?)
+            }
+            arg => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+/// Report an error or a warning on the standard error, in JSON if `json` is true.
+fn report<E>(p: &mut Program, error: E, json: bool)
+where
+    E: ToDiagnostic<FileId>,
+{
+    if json {
+        eprintln!("{}", p.report_to_json(error));
+    } else {
+        p.report(error);
+    }
+}
 
 fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("Error when parsing the arguments: {}", msg);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(code) = options.explain {
+        match error_codes::explain(&code) {
+            Some(explanation) => println!("{}", explanation),
+            None => {
+                eprintln!("Unknown error code {}", code);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     match Program::new_from_stdin() {
-        Ok(mut p) => match p.eval() {
-            Ok(t) => println!("Done: {:?}", t),
-            Err(err) => p.report(err),
-        },
+        Ok(mut p) => {
+            if options.no_inline {
+                p.set_inline_threshold(None);
+            }
+            if let Some(jobs) = options.jobs {
+                p.set_export_jobs(jobs);
+            }
+
+            if let Some(format) = options.import_graph {
+                match p.import_graph() {
+                    Ok(graph) => match format {
+                        GraphFormat::Dot => print!("{}", graph.to_dot()),
+                        GraphFormat::Json => println!("{}", graph.to_json()),
+                    },
+                    Err(err) => report(&mut p, err, options.json_errors),
+                }
+                return;
+            }
+
+            if let Ok(warnings) = p.warnings() {
+                warnings
+                    .into_iter()
+                    .for_each(|w| report(&mut p, w, options.json_errors));
+            }
+
+            if options.manifest.is_some() {
+                p.set_record_manifest(true);
+            }
+
+            let mut stats = EvalStats::default();
+            if options.export {
+                if let Err(err) = p.export(&options.export_options, &mut io::stdout()) {
+                    report(&mut p, err, options.json_errors);
+                }
+            } else {
+                let (result, eval_stats) = p.eval_with_stats();
+                stats = eval_stats;
+                match result {
+                    Ok(t) => println!("Done: {:?}", t),
+                    Err(err) => report(&mut p, err, options.json_errors),
+                }
+            }
+
+            if options.trace_imports {
+                eprint!("{}", p.import_report(&stats));
+            }
+
+            if let (Some(path), Some(manifest)) = (options.manifest, p.manifest()) {
+                if let Err(err) = std::fs::write(&path, manifest.to_json()) {
+                    eprintln!("Error when writing the manifest to {}: {}", path, err);
+                }
+            }
+        }
         Err(msg) => eprintln!("Error when reading the source: {}", msg),
     };
 }
@@ -0,0 +1,144 @@
+//! Record of the interactions of an evaluation with the outside world.
+//!
+//! The result of a Nickel program only depends on its source and on the inputs it gets from its
+//! environment. A [`Manifest`](struct.Manifest.html) records all of them during an evaluation
+//! (see [`Program::set_record_manifest`]):
+//!
+//! - the files read, that is the main file, the imported files and directories, and the interface
+//!   files, with the SHA-256 hash of their content,
+//! - the values of the external terms provided by the host,
+//! - the calls to the functions registered by the host, with their arguments and result.
+//!
+//! Nickel programs cannot read environment variables nor the clock, such that these inputs are
+//! sufficient to reproduce an evaluation, and to check that a configuration was built from the
+//! expected files. Values are recorded as JSON, with the fields of records sorted by name, and
+//! functions or other values which cannot be represented replaced by a short description.
+//!
+//! [`Program::set_record_manifest`]: ../program/struct.Program.html#method.set_record_manifest
+use crate::term::Term;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+/// A file read during an evaluation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileRecord {
+    pub path: PathBuf,
+    /// The SHA-256 hash of the content of the file, in hexadecimal. The content of a directory is
+    /// the index generated for it.
+    pub sha256: String,
+}
+
+impl FileRecord {
+    pub fn new(path: PathBuf, content: &str) -> Self {
+        let sha256 = Sha256::digest(content.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        FileRecord { path, sha256 }
+    }
+}
+
+/// A call to a host function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostCallRecord {
+    pub name: String,
+    pub args: Vec<Value>,
+    /// The returned value, or the error message.
+    pub result: Result<Value, String>,
+}
+
+/// The interactions of an evaluation with the outside world, in the order they happened.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Manifest {
+    pub files: Vec<FileRecord>,
+    /// The external values, by name.
+    pub externals: Vec<(String, Value)>,
+    pub host_calls: Vec<HostCallRecord>,
+}
+
+impl Manifest {
+    /// Render the manifest in JSON.
+    pub fn to_json(&self) -> String {
+        let files: Vec<Value> = self
+            .files
+            .iter()
+            .map(|file| json!({"path": file.path.to_string_lossy(), "sha256": file.sha256}))
+            .collect();
+        let externals: Vec<Value> = self
+            .externals
+            .iter()
+            .map(|(name, value)| json!({"name": name, "value": value}))
+            .collect();
+        let host_calls: Vec<Value> = self
+            .host_calls
+            .iter()
+            .map(|call| match &call.result {
+                Ok(value) => json!({"name": call.name, "args": call.args, "result": value}),
+                Err(msg) => json!({"name": call.name, "args": call.args, "error": msg}),
+            })
+            .collect();
+
+        serde_json::to_string_pretty(&json!({
+            "files": files,
+            "externals": externals,
+            "host_calls": host_calls,
+        }))
+        .unwrap()
+    }
+}
+
+/// Represent a value in JSON for the manifest. Enum tags are prefixed with a backquote, as in the
+/// source, to distinguish them from strings.
+pub fn to_value(t: &Term) -> Value {
+    match t {
+        Term::Bool(b) => Value::Bool(*b),
+        Term::Num(n) => serde_json::Number::from_f64(*n)
+            .map(Value::Number)
+            .unwrap_or_else(|| Value::String(format!("{}", n))),
+        Term::Str(s) => Value::String(s.clone()),
+        Term::Enum(tag) => Value::String(format!("`{}", tag)),
+        Term::List(ts) => Value::Array(ts.iter().map(|t| to_value(t.as_ref())).collect()),
+        Term::Record(fields) | Term::RecRecord(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(id, t)| (id.to_string(), to_value(t.as_ref())))
+                .collect(),
+        ),
+        t => Value::String(t.shallow_repr()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identifier::Ident;
+    use crate::term::{RecordData, RichTerm};
+
+    #[test]
+    fn file_hash() {
+        assert_eq!(
+            FileRecord::new(PathBuf::from("a.ncl"), "abc").sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn values() {
+        let mut fields = RecordData::new();
+        fields.insert(Ident::from("b"), RichTerm::from(Term::Num(1.5)));
+        fields.insert(
+            Ident::from("a"),
+            Term::List(vec![
+                Term::Enum(Ident::from("x")).into(),
+                Term::Str(String::from("s")).into(),
+            ])
+            .into(),
+        );
+
+        assert_eq!(
+            serde_json::to_string(&to_value(&Term::Record(fields))).unwrap(),
+            r#"{"a":["`x","s"],"b":1.5}"#
+        );
+    }
+}
@@ -27,37 +27,60 @@
 //!
 //! ## On enriched values
 //!
-//! Enriched values (currently `Contract`, `Default`, `ContractDefault` or `Docstring`) get their
-//! special powers from their interaction with the merge operator.
+//! Enriched values are metavalues, which attach metadata to a value: a documentation, a list of
+//! contracts and a merge priority, currently either `Default` or normal. They get their special
+//! powers from their interaction with the merge operator. A simple value is merged as a metavalue
+//! without any metadata and with the normal priority.
 //!
-//! ### Enriched/Enriched
+//! - *Values*: if the two sides have the same priority, their values are merged. Otherwise, the
+//! value with the highest priority is kept, and the other one is dropped: merging a `Default`
+//! with a simple value evaluates to the simple value. A side without a value, such as a bare
+//! `Contract`, keeps the value of the other side.
+//! - *Contracts*: the contracts of both sides are accumulated, such that the value must satisfy
+//! all of them. Each contract keeps its own label for blame. They are checked when the value is
+//! eventually forced (see [eval](../eval/index.html)).
+//! - *Documentation*: merging two docstrings gives one docstring. If the two documentations are
+//! the same, it is kept as is, and otherwise they are concatenated, left first, separated by an
+//! empty line.
 //!
-//! - *Contract/contract*: merging two contracts evaluates to a contract which is the composition
-//! of the two
-//! - *Default/default*: merging two default values evaluates to a default which value is the merge
-//! of the two
-//! - *Contract/default*: merging a `Default` with a `Contract` evaluates to a `ContractDefault`
-//! - *ContractDefault/_*: Merging `ContractDefault` is done component-wise: with another
-//! `ContractDefault`, it evaluates to a `ContractDefault` where the two contracts as well as the
-//! two default values are respectively merged together. With either just a `Contract` or a
-//! `Default`, it simply merges the corresponding component and let the other unchanged.
+//! If the result carries no metadata at all, the merge evaluates to the merged value directly.
 //!
-//! ### Enriched/Simple
+//! Two default values which are different constants are ambiguous, as neither is preferred over
+//! the other, and give a [`ConflictingDefaults`](../error/enum.EvalError.html#variant.ConflictingDefaults)
+//! error. To choose between them, the field must be given a definitive, non default value.
 //!
-//! - *Docstring*: merging a docstring (with inner term `inner`) with another term `t` recursively merges
-//! `inner` and `t`, and evaluates to this result wrapped in the original docstring (`t` may be a simple value or an
-//! enriched one here)
-//! - *Default erasure*: merging a `Default` with a simple value drops the default value and
-//! evaluates to the simple value
-//! - *Contract check*: merging a `Contract` or a `ContractDefault` with a simple value `t`
-//! evaluates to a contract check, that is an `Assume(..., t)`
+//! ## Overriding merge
+//!
+//! The internal operator `MergeOverride` behaves as merge, except that it never fails on
+//! incompatible simple values, or on a record and a simple value: the right operand is kept
+//! instead. It is thus of maximum priority, above definitive values, but it is still checked by
+//! the contracts of the left operand. Records are merged recursively with `MergeOverride`.
+use crate::collections::HashMap;
 use crate::error::EvalError;
 use crate::eval::{Closure, Environment};
 use crate::position::RawSpan;
-use crate::term::{BinaryOp, RichTerm, Term};
+use crate::term::{BinaryOp, Contract, MergePriority, MetaValue, RecordData, RichTerm, Term};
 use crate::transformations::Closurizable;
-use crate::types::{AbsType, Types};
-use std::collections::HashMap;
+
+/// The variant of the merge operator being evaluated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeMode {
+    /// The merge operator `&`.
+    Standard,
+    /// The overriding merge, where the right operand wins (see the [module
+    /// documentation](index.html)).
+    Override,
+}
+
+impl MergeMode {
+    /// The operator used to merge subterms in this mode.
+    fn op<Ty>(self) -> BinaryOp<Ty> {
+        match self {
+            MergeMode::Standard => BinaryOp::Merge(),
+            MergeMode::Override => BinaryOp::MergeOverride(),
+        }
+    }
+}
 
 /// Compute the merge of two evaluated operands.
 pub fn merge(
@@ -66,15 +89,27 @@ pub fn merge(
     t2: RichTerm,
     env2: Environment,
     pos_op: Option<RawSpan>,
+    mode: MergeMode,
 ) -> Result<Closure, EvalError> {
     let RichTerm {
         term: t1,
         pos: pos1,
+        ..
     } = t1;
     let RichTerm {
         term: t2,
         pos: pos2,
+        ..
     } = t2;
+
+    let both_records = matches!((&*t1, &*t2), (Term::Record(_), Term::Record(_)));
+    if mode == MergeMode::Override && !t1.is_enriched() && !t2.is_enriched() && !both_records {
+        return Ok(Closure {
+            body: RichTerm::with_pos(t2, pos2),
+            env: env2,
+        });
+    }
+
     match (*t1, *t2) {
         // Merge is idempotent on basic terms
         (Term::Bool(b1), Term::Bool(b2)) => {
@@ -82,14 +117,8 @@ pub fn merge(
                 Ok(Closure::atomic_closure(Term::Bool(b1).into()))
             } else {
                 Err(EvalError::MergeIncompatibleArgs(
-                    RichTerm {
-                        term: Box::new(Term::Bool(b1)),
-                        pos: pos1,
-                    },
-                    RichTerm {
-                        term: Box::new(Term::Bool(b2)),
-                        pos: pos2,
-                    },
+                    RichTerm::with_pos(Box::new(Term::Bool(b1)), pos1),
+                    RichTerm::with_pos(Box::new(Term::Bool(b2)), pos2),
                     pos_op,
                 ))
             }
@@ -99,14 +128,8 @@ pub fn merge(
                 Ok(Closure::atomic_closure(Term::Num(n1).into()))
             } else {
                 Err(EvalError::MergeIncompatibleArgs(
-                    RichTerm {
-                        term: Box::new(Term::Num(n1)),
-                        pos: pos1,
-                    },
-                    RichTerm {
-                        term: Box::new(Term::Num(n2)),
-                        pos: pos2,
-                    },
+                    RichTerm::with_pos(Box::new(Term::Num(n1)), pos1),
+                    RichTerm::with_pos(Box::new(Term::Num(n2)), pos2),
                     pos_op,
                 ))
             }
@@ -116,14 +139,8 @@ pub fn merge(
                 Ok(Closure::atomic_closure(Term::Str(s1).into()))
             } else {
                 Err(EvalError::MergeIncompatibleArgs(
-                    RichTerm {
-                        term: Box::new(Term::Str(s1)),
-                        pos: pos1,
-                    },
-                    RichTerm {
-                        term: Box::new(Term::Str(s2)),
-                        pos: pos2,
-                    },
+                    RichTerm::with_pos(Box::new(Term::Str(s1)), pos1),
+                    RichTerm::with_pos(Box::new(Term::Str(s2)), pos2),
                     pos_op,
                 ))
             }
@@ -133,154 +150,22 @@ pub fn merge(
                 Ok(Closure::atomic_closure(Term::Lbl(l1).into()))
             } else {
                 Err(EvalError::MergeIncompatibleArgs(
-                    RichTerm {
-                        term: Box::new(Term::Lbl(l1)),
-                        pos: pos1,
-                    },
-                    RichTerm {
-                        term: Box::new(Term::Lbl(l2)),
-                        pos: pos2,
-                    },
+                    RichTerm::with_pos(Box::new(Term::Lbl(l1)), pos1),
+                    RichTerm::with_pos(Box::new(Term::Lbl(l2)), pos2),
                     pos_op,
                 ))
             }
         }
-        // Right-biased: when merging two docstrings (s1,t2) and (s2,t2), the right one will end up
-        // as the outermost position in the resulting term (s2,(s1,merge t1 t2))
-        (t1, Term::Docstring(s, t2)) => {
-            let Closure { body, env } = mk_merge_closure(
-                RichTerm {
-                    term: Box::new(t1),
-                    pos: pos1,
-                },
-                env1,
-                t2,
-                env2,
-            );
-            let body = Term::Docstring(s, body).into();
-            Ok(Closure { body, env })
-        }
-        (Term::Docstring(s, t1), t2) => {
-            let Closure { body, env } = mk_merge_closure(
-                t1,
-                env1,
-                RichTerm {
-                    term: Box::new(t2),
-                    pos: pos2,
-                },
-                env2,
-            );
-            let body = Term::Docstring(s, body).into();
-            Ok(Closure { body, env })
-        }
-        // Default merging
-        (Term::DefaultValue(t1), Term::DefaultValue(t2)) => {
-            let Closure { body, env } = mk_merge_closure(t1, env1, t2, env2);
-            let body = Term::DefaultValue(body).into();
-            Ok(Closure { body, env })
-        }
-        (Term::DefaultValue(t1), Term::ContractWithDefault(ty, lbl, t2))
-        | (Term::ContractWithDefault(ty, lbl, t2), Term::DefaultValue(t1)) => {
-            let Closure { body, mut env } = mk_merge_closure(t1, env1, t2, env2.clone());
-            let ty_closure = ty.closurize(&mut env, env2);
-            let body = Term::ContractWithDefault(ty_closure, lbl, body).into();
-            Ok(Closure { body, env })
-        }
-        (Term::ContractWithDefault(ty1, lbl1, t1), Term::ContractWithDefault(ty2, _lbl2, t2)) => {
-            //FIXME: The choice of lbl1 is totally arbitrary, to please the compiler. Ideally
-            //labels should also be mergeable, but the current PR is already getting too big, and
-            //this is left for future work
-            let Closure { body, mut env } = mk_merge_closure(t1, env1.clone(), t2, env2.clone());
-            let body = Term::ContractWithDefault(
-                merge_types_closure(&mut env, ty1, env1, ty2, env2),
-                lbl1,
-                body,
-            )
-            .into();
-            Ok(Closure { body, env })
-        }
-        // We need to keep the environment of contracts as well: custom contracts may use variables
-        // from the environment, and even standard contracts need access to builtins contracts (see
-        // issue https://github.com/tweag/nickel/issues/117)
-        (Term::DefaultValue(t), Term::Contract(ty, lbl)) => {
-            let mut env = HashMap::new();
-            let t_closure = t.closurize(&mut env, env1);
-            let ty_closure = ty.closurize(&mut env, env2);
-            let body = Term::ContractWithDefault(ty_closure, lbl, t_closure).into();
-            Ok(Closure { body, env })
-        }
-        (Term::Contract(ty, lbl), Term::DefaultValue(t)) => {
-            let mut env = HashMap::new();
-            let ty_closure = ty.closurize(&mut env, env1);
-            let t_closure = t.closurize(&mut env, env2);
-            let body = Term::ContractWithDefault(ty_closure, lbl, t_closure).into();
-            Ok(Closure { body, env })
-        }
-        (Term::DefaultValue(_), t) => {
-            let body = RichTerm {
-                term: Box::new(t),
-                pos: pos2,
-            };
-            Ok(Closure { body, env: env2 })
-        }
-        (t, Term::DefaultValue(_)) => {
-            let body = RichTerm {
-                term: Box::new(t),
-                pos: pos1,
-            };
-            Ok(Closure { body, env: env1 })
-        }
-        // Contracts merging
-        (Term::Contract(ty1, lbl1), Term::Contract(ty2, _lbl2)) => {
-            //FIXME: The choice of lbl1 is totally arbitrary, to please the compiler. Ideally
-            //labels should also be mergeable, but the current PR is already getting too big, and
-            //this is left for future work
-            let mut env = HashMap::new();
-            let body =
-                Term::Contract(merge_types_closure(&mut env, ty1, env1, ty2, env2), lbl1).into();
-            Ok(Closure { body, env })
-        }
-        (Term::Contract(ty1, lbl1), Term::ContractWithDefault(ty2, _lbl2, t)) => {
-            //FIXME: The choice of lbl1 is totally arbitrary, to please the compiler. Ideally
-            //labels should also be mergeable, but the current PR is already getting too big, and
-            //this is left for future work
-            let mut env = HashMap::new();
-            let ty_closure = merge_types_closure(&mut env, ty1, env1, ty2, env2.clone());
-            let t_closure = t.closurize(&mut env, env2);
-            let body = Term::ContractWithDefault(ty_closure, lbl1, t_closure).into();
-            Ok(Closure { body, env })
+        (Term::MetaValue(meta1), Term::MetaValue(meta2)) => {
+            merge_meta(meta1, env1, meta2, env2, pos_op, mode)
         }
-        (Term::ContractWithDefault(ty1, lbl1, t), Term::Contract(ty2, _lbl2)) => {
-            //FIXME: The choice of lbl1 is totally arbitrary, to please the compiler. Ideally
-            //labels should also be mergeable, but the current PR is already getting too big, and
-            //this is left for future work
-            let mut env = HashMap::new();
-            let ty_closure = merge_types_closure(&mut env, ty1, env1.clone(), ty2, env2);
-            let t_closure = t.closurize(&mut env, env1);
-            let body = Term::ContractWithDefault(ty_closure, lbl1, t_closure).into();
-            Ok(Closure { body, env })
+        (Term::MetaValue(meta1), t2) => {
+            let meta2 = MetaValue::from_value(RichTerm::with_pos(Box::new(t2), pos2));
+            merge_meta(meta1, env1, meta2, env2, pos_op, mode)
         }
-        (Term::Contract(ty, lbl), t) | (Term::ContractWithDefault(ty, lbl, _), t) => {
-            let mut env = HashMap::new();
-            let t = RichTerm {
-                term: Box::new(t),
-                pos: pos2,
-            };
-            let ty_closure = ty.closurize(&mut env, env1);
-            let t_closure = t.closurize(&mut env, env2);
-            let body = Term::Assume(ty_closure, lbl, t_closure).into();
-            Ok(Closure { body, env })
-        }
-        (t, Term::Contract(ty, lbl)) | (t, Term::ContractWithDefault(ty, lbl, _)) => {
-            let mut env = HashMap::new();
-            let t = RichTerm {
-                term: Box::new(t),
-                pos: pos1,
-            };
-            let t_closure = t.closurize(&mut env, env1);
-            let ty_closure = ty.closurize(&mut env, env2);
-            let body = Term::Assume(ty_closure, lbl, t_closure).into();
-            Ok(Closure { body, env })
+        (t1, Term::MetaValue(meta2)) => {
+            let meta1 = MetaValue::from_value(RichTerm::with_pos(Box::new(t1), pos1));
+            merge_meta(meta1, env1, meta2, env2, pos_op, mode)
         }
         // Merge put together the fields of records, and recursively merge
         // fields that are present in both terms
@@ -290,28 +175,30 @@ pub fn merge(
              * the same trick as in the evaluation of the operator DynExtend, and replace each such
              * term by a variable bound to an appropriate closure in the environment
              */
-            let mut m = HashMap::new();
-            let mut env = HashMap::new();
-            let (mut left, mut center, mut right) = hashmap::split(m1, m2);
+            let mut m = RecordData::new();
+            let mut env = HashMap::default();
+            let (left, center, right) = m1.split(m2);
 
-            for (field, t) in left.drain() {
+            for (field, t) in left {
                 m.insert(field, t.closurize(&mut env, env1.clone()));
             }
 
-            for (field, t) in right.drain() {
+            for (field, t) in right {
                 m.insert(field, t.closurize(&mut env, env2.clone()));
             }
 
-            for (field, (t1, t2)) in center.drain() {
-                m.insert(
-                    field,
-                    Term::Op2(
-                        BinaryOp::Merge(),
-                        t1.closurize(&mut env, env1.clone()),
-                        t2.closurize(&mut env, env2.clone()),
-                    )
-                    .into(),
-                );
+            // The merge of common fields is itself put in a thunk, such that it is evaluated at
+            // most once, and that forcing the fields of the result, as `deepSeq` does, updates
+            // them in place.
+            for (field, (t1, t2)) in center {
+                let mut merge_env = HashMap::default();
+                let merged: RichTerm = Term::Op2(
+                    mode.op(),
+                    t1.closurize(&mut merge_env, env1.clone()),
+                    t2.closurize(&mut merge_env, env2.clone()),
+                )
+                .into();
+                m.insert(field, merged.closurize(&mut env, merge_env));
             }
 
             Ok(Closure {
@@ -321,77 +208,142 @@ pub fn merge(
         }
         //The following cases are either errors or not yet implemented
         (t1_, t2_) => Err(EvalError::MergeIncompatibleArgs(
-            RichTerm {
-                term: Box::new(t1_),
-                pos: pos1,
-            },
-            RichTerm {
-                term: Box::new(t2_),
-                pos: pos2,
-            },
+            RichTerm::with_pos(Box::new(t1_), pos1),
+            RichTerm::with_pos(Box::new(t2_), pos2),
             pos_op,
         )),
     }
 }
 
-/// Take two terms together with their environment, and return a closure representing their merge.
-fn mk_merge_closure(t1: RichTerm, env1: Environment, t2: RichTerm, env2: Environment) -> Closure {
-    let mut env = HashMap::new();
-
-    let body = Term::Op2(
-        BinaryOp::Merge(),
-        t1.closurize(&mut env, env1),
-        t2.closurize(&mut env, env2),
-    )
-    .into();
-
-    Closure { body, env }
+/// Combine the documentation of the two sides of a merge.
+fn combine_docs(s1: String, s2: String) -> String {
+    if s1 == s2 {
+        s1
+    } else {
+        format!("{}\n\n{}", s1, s2)
+    }
 }
 
-/// Compose the contract (as terms) `c1` and `c2`, that is construct the term `fun l x => c1 l (c2
-/// l x)`, and return the corresponding type.
+/// Merge two meta values, a simple value being a meta value without metadata.
 ///
-/// This type corresponds to the intersection of the types associated to `c1` and `c2`.  This
-/// function is not correct for the intersection of higher-order contracts, which is way more
-/// involved (see the [corresponding
-/// notes](https://github.com/tweag/nickel/blob/master/notes/intersection-and-union-types.md) in
-/// the repository).
-fn merge_contracts(c1: RichTerm, c2: RichTerm) -> Types {
-    let contract = RichTerm::fun(
-        "l".to_string(),
-        RichTerm::fun(
-            "x".to_string(),
-            RichTerm::app(
-                RichTerm::app(c1, RichTerm::var("l".to_string())),
-                RichTerm::app(
-                    RichTerm::app(c2, RichTerm::var("l".to_string())),
-                    RichTerm::var("x".to_string()),
-                ),
-            ),
+/// The metadata are combined (see the [module documentation](index.html)). Of the two values, the
+/// one with the highest priority is kept, and they are merged if they have the same. If the
+/// result has no metadata left, as when a default value is merged with a simple value, the
+/// resulting value is returned as is.
+fn merge_meta(
+    meta1: MetaValue,
+    env1: Environment,
+    meta2: MetaValue,
+    env2: Environment,
+    pos_op: Option<RawSpan>,
+    mode: MergeMode,
+) -> Result<Closure, EvalError> {
+    let (priority, value) = match (meta1.value, meta2.value) {
+        (Some(t1), Some(t2))
+            if mode == MergeMode::Standard
+                && meta1.priority == MergePriority::Default
+                && meta2.priority == MergePriority::Default
+                && t1.term.is_constant()
+                && t2.term.is_constant()
+                && t1.term != t2.term =>
+        {
+            return Err(EvalError::ConflictingDefaults(t1, t2, pos_op));
+        }
+        (Some(t1), Some(t2)) if meta1.priority == meta2.priority => (
+            meta1.priority,
+            Some(mk_merge_closure(t1, env1.clone(), t2, env2.clone(), mode)),
+        ),
+        (Some(t1), Some(_)) if meta1.priority > meta2.priority => (
+            meta1.priority,
+            Some(Closure {
+                body: t1,
+                env: env1.clone(),
+            }),
         ),
-    );
+        (_, Some(t2)) => (
+            meta2.priority,
+            Some(Closure {
+                body: t2,
+                env: env2.clone(),
+            }),
+        ),
+        (Some(t1), None) => (
+            meta1.priority,
+            Some(Closure {
+                body: t1,
+                env: env1.clone(),
+            }),
+        ),
+        (None, None) => (std::cmp::max(meta1.priority, meta2.priority), None),
+    };
+
+    let doc = match (meta1.doc, meta2.doc) {
+        (Some(s1), Some(s2)) => Some(combine_docs(s1, s2)),
+        (doc1, doc2) => doc1.or(doc2),
+    };
+
+    if doc.is_none()
+        && meta1.contracts.is_empty()
+        && meta2.contracts.is_empty()
+        && priority == MergePriority::Normal
+    {
+        if let Some(clos) = value {
+            return Ok(clos);
+        }
+    }
+
+    let (value, mut env) = match value {
+        Some(Closure { body, env }) => (Some(body), env),
+        None => (None, HashMap::default()),
+    };
 
-    Types(AbsType::Flat(contract.into()))
+    // We need to keep the environment of contracts as well: custom contracts may use variables
+    // from the environment, and even standard contracts need access to builtins contracts (see
+    // issue https://github.com/tweag/nickel/issues/117)
+    let contracts = meta1
+        .contracts
+        .into_iter()
+        .map(|ctr| (ctr, &env1))
+        .chain(meta2.contracts.into_iter().map(|ctr| (ctr, &env2)))
+        .map(|(ctr, with_env)| Contract {
+            types: ctr.types.closurize(&mut env, with_env.clone()),
+            label: ctr.label,
+        })
+        .collect();
+
+    let body = Term::MetaValue(MetaValue {
+        doc,
+        contracts,
+        priority,
+        value,
+    })
+    .into();
+    Ok(Closure { body, env })
 }
 
-/// [Closurize](../transformations/trait.Closurizable.html) two types with their respective
-/// environment and merge them by composing their underlying contracts.
-///
-/// See [`merge_contracts`](./fn.merge_contracts.html).
-fn merge_types_closure(
-    env: &mut Environment,
-    ty1: Types,
+/// Take two terms together with their environment, and return a closure representing their merge
+/// in the given mode.
+fn mk_merge_closure(
+    t1: RichTerm,
     env1: Environment,
-    ty2: Types,
+    t2: RichTerm,
     env2: Environment,
-) -> Types {
-    let c1 = ty1.contract().closurize(env, env1);
-    let c2 = ty2.contract().closurize(env, env2);
-    merge_contracts(c1, c2)
+    mode: MergeMode,
+) -> Closure {
+    let mut env = HashMap::default();
+
+    let body = Term::Op2(
+        mode.op(),
+        t1.closurize(&mut env, env1),
+        t2.closurize(&mut env, env2),
+    )
+    .into();
+
+    Closure { body, env }
 }
 
 pub mod hashmap {
-    use std::collections::HashMap;
+    use crate::collections::HashMap;
 
     /// Split two hashmaps m1 and m2 in three parts (left,center,right), where left holds bindings
     /// `(key,value)` where key is not in `m2.keys()`, right is the dual (keys of m2 that are not
@@ -403,8 +355,8 @@ pub mod hashmap {
     where
         K: std::hash::Hash + Eq,
     {
-        let mut left = HashMap::new();
-        let mut center = HashMap::new();
+        let mut left = HashMap::default();
+        let mut center = HashMap::default();
         let mut right = m2;
 
         for (key, value) in m1 {
@@ -424,8 +376,8 @@ pub mod hashmap {
 
         #[test]
         fn all_left() -> Result<(), String> {
-            let mut m1 = HashMap::new();
-            let m2 = HashMap::<isize, isize>::new();
+            let mut m1 = HashMap::default();
+            let m2 = HashMap::<isize, isize>::default();
 
             m1.insert(1, 1);
             let (mut left, center, right) = split(m1, m2);
@@ -443,8 +395,8 @@ pub mod hashmap {
 
         #[test]
         fn all_right() -> Result<(), String> {
-            let m1 = HashMap::<isize, isize>::new();
-            let mut m2 = HashMap::new();
+            let m1 = HashMap::<isize, isize>::default();
+            let mut m2 = HashMap::default();
 
             m2.insert(1, 1);
             let (left, center, mut right) = split(m1, m2);
@@ -464,8 +416,8 @@ pub mod hashmap {
 
         #[test]
         fn all_center() -> Result<(), String> {
-            let mut m1 = HashMap::new();
-            let mut m2 = HashMap::new();
+            let mut m1 = HashMap::default();
+            let mut m2 = HashMap::default();
 
             m1.insert(1, 1);
             m2.insert(1, 2);
@@ -486,8 +438,8 @@ pub mod hashmap {
 
         #[test]
         fn mixed() -> Result<(), String> {
-            let mut m1 = HashMap::new();
-            let mut m2 = HashMap::new();
+            let mut m1 = HashMap::default();
+            let mut m2 = HashMap::default();
 
             m1.insert(1, 1);
             m1.insert(2, 1);
@@ -511,3 +463,98 @@ pub mod hashmap {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(s: &str, t: Term) -> RichTerm {
+        Term::MetaValue(MetaValue {
+            doc: Some(String::from(s)),
+            ..MetaValue::from_value(t.into())
+        })
+        .into()
+    }
+
+    fn default(t: Term) -> RichTerm {
+        Term::MetaValue(MetaValue {
+            priority: MergePriority::Default,
+            ..MetaValue::from_value(t.into())
+        })
+        .into()
+    }
+
+    fn merge_terms(t1: RichTerm, t2: RichTerm) -> Result<Term, EvalError> {
+        merge(
+            t1,
+            HashMap::default(),
+            t2,
+            HashMap::default(),
+            None,
+            MergeMode::Standard,
+        )
+        .map(|clos| *clos.body.term)
+    }
+
+    fn override_terms(t1: RichTerm, t2: RichTerm) -> Result<Term, EvalError> {
+        merge(
+            t1,
+            HashMap::default(),
+            t2,
+            HashMap::default(),
+            None,
+            MergeMode::Override,
+        )
+        .map(|clos| *clos.body.term)
+    }
+
+    #[test]
+    fn combine_docstrings() {
+        match merge_terms(doc("a", Term::Num(1.)), doc("b", Term::Num(1.))) {
+            Ok(Term::MetaValue(meta)) => assert_eq!(meta.doc.unwrap(), "a\n\nb"),
+            res => panic!("expected a docstring, got {:?}", res),
+        };
+        match merge_terms(doc("a", Term::Num(1.)), doc("a", Term::Num(1.))) {
+            Ok(Term::MetaValue(meta)) => assert_eq!(meta.doc.unwrap(), "a"),
+            res => panic!("expected a docstring, got {:?}", res),
+        };
+    }
+
+    #[test]
+    fn conflicting_defaults() {
+        match merge_terms(default(Term::Num(1.)), default(Term::Num(2.))) {
+            Err(EvalError::ConflictingDefaults(..)) => (),
+            res => panic!("expected conflicting defaults, got {:?}", res),
+        };
+        match merge_terms(default(Term::Num(1.)), default(Term::Num(1.))) {
+            Ok(Term::MetaValue(meta)) => assert_eq!(meta.priority, MergePriority::Default),
+            res => panic!("expected a default value, got {:?}", res),
+        };
+    }
+
+    #[test]
+    fn override_values() {
+        assert_eq!(
+            override_terms(Term::Num(1.).into(), Term::Num(2.).into()),
+            Ok(Term::Num(2.))
+        );
+        assert_eq!(
+            override_terms(
+                Term::Record(RecordData::new()).into(),
+                Term::Bool(true).into()
+            ),
+            Ok(Term::Bool(true))
+        );
+        match override_terms(default(Term::Num(1.)), default(Term::Num(2.))) {
+            Ok(Term::MetaValue(MetaValue {
+                priority: MergePriority::Default,
+                value: Some(t),
+                ..
+            })) => match *t.term {
+                Term::Op2(BinaryOp::MergeOverride(), _, _) => (),
+                t => panic!("expected an overriding merge, got {:?}", t),
+            },
+            res => panic!("expected a default value, got {:?}", res),
+        };
+    }
+}
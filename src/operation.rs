@@ -6,28 +6,66 @@
 //! the functions [`process_unary_operation`](fn.process_unary_operation.html) and
 //! [`process_binary_operation`](fn.process_binary_operation.html) receive evaluated operands and
 //! implement the actual semantics of operators.
+use crate::collections::HashMap;
 use crate::error::EvalError;
 use crate::eval::Environment;
-use crate::eval::{CallStack, Closure};
+use crate::eval::{subst, CallStack, Closure, IdentKind};
 use crate::identifier::Ident;
-use crate::label::ty_path;
-use crate::merge;
-use crate::merge::merge;
+use crate::label::{ty_path, Label};
+use crate::merge::{merge, MergeMode};
 use crate::position::RawSpan;
+use crate::serialize::NumFormat;
 use crate::stack::Stack;
-use crate::term::{BinaryOp, RichTerm, StrChunk, Term, UnaryOp};
+use crate::term::{BinaryOp, MergePriority, RecordData, RichTerm, StrChunk, Term, UnaryOp};
 use crate::transformations::Closurizable;
+use crate::types::{AbsType, Types};
+use regex::Regex;
 use simple_counter::*;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::rc::Rc;
 
 generate_counter!(FreshVariableCounter, usize);
 
+/// The maximum number of elements of a list built by `listRange`. Larger ranges are most likely
+/// the result of an error, and would exhaust the memory.
+const MAX_RANGE_LENGTH: usize = 10_000_000;
+// Symbols generated by `freshSym` are negative, while the ones of parametricity contracts are
+// non-negative, such that they never coincide.
+generate_counter!(FreshSymCounter, i32);
+
+/// Evaluate a closure to a weak head normal form in a separate abstract machine.
+///
+/// Given to the operations which call Nickel functions from Rust, such as the comparison function
+/// of `listSort`, which can't be expressed as continuations on the stack.
+pub type SubEval<'a> = dyn FnMut(Closure) -> Result<Closure, EvalError> + 'a;
+
+/// Return a term as an atomic closure, storing it in the allocation of an operand which is not
+/// used anymore.
+///
+/// Operations returning a constant, such as type tests, comparisons or arithmetic, are typically
+/// executed in hot loops. Reusing the memory of their operand instead of allocating a new term
+/// saves one allocation per operation.
+///
+/// This also covers the empty strings, lists and records, which therefore don't need shared
+/// instances: their contents are not allocated until they grow, such that the term itself is their
+/// only allocation. The operations building small strings or lists out of an operand, such as
+/// `pathExtension` or `labelPath`, whose result is often empty, use this function as well.
+fn reuse(mut operand: Box<Term>, t: Term) -> Closure {
+    *operand = t;
+    Closure::atomic_closure(RichTerm::with_pos(operand, None))
+}
+
 /// An operation continuation as stored on the stack.
 #[derive(Debug, PartialEq)]
 pub enum OperationCont {
     Op1(
         /* unary operation */ UnaryOp<Closure>,
         /* original position of the argument before evaluation */ Option<RawSpan>,
+        /* previous value of enriched_strict */ bool,
     ),
     // The last parameter saves the strictness mode before the evaluation of the operator
     Op2First(
@@ -50,17 +88,23 @@ pub enum OperationCont {
 /// Depending on the content of the stack, it either starts the evaluation of the first argument,
 /// starts the evaluation of the second argument, or finally process with the operation if both
 /// arguments are evaluated (for binary operators).
+///
+/// If the operation builds a list of more than `max_list_length` elements, a
+/// [`ListTooLong`](../error/enum.EvalError.html#variant.ListTooLong) error is raised.
 pub fn continuate_operation(
     mut clos: Closure,
     stack: &mut Stack,
     call_stack: &mut CallStack,
     enriched_strict: &mut bool,
+    max_list_length: Option<usize>,
+    sub_eval: &mut SubEval,
 ) -> Result<Closure, EvalError> {
     let (cont, cs_len, pos) = stack.pop_op_cont().expect("Condition already checked");
     call_stack.truncate(cs_len);
-    match cont {
-        OperationCont::Op1(u_op, arg_pos) => {
-            process_unary_operation(u_op, clos, arg_pos, stack, pos)
+    let result = match cont {
+        OperationCont::Op1(u_op, arg_pos, prev_strict) => {
+            *enriched_strict = prev_strict;
+            process_unary_operation(u_op, clos, arg_pos, stack, pos.clone(), sub_eval)
         }
         OperationCont::Op2First(b_op, mut snd_clos, fst_pos, prev_strict) => {
             std::mem::swap(&mut clos, &mut snd_clos);
@@ -75,14 +119,29 @@ pub fn continuate_operation(
                 cs_len,
                 pos,
             );
-            Ok(clos)
+            // The second argument is about to be evaluated: no list has been built yet.
+            return Ok(clos);
         }
         OperationCont::Op2Second(b_op, fst_clos, fst_pos, snd_pos, prev_strict) => {
-            let result =
-                process_binary_operation(b_op, fst_clos, fst_pos, clos, snd_pos, stack, pos);
+            let result = process_binary_operation(
+                b_op,
+                fst_clos,
+                fst_pos,
+                clos,
+                snd_pos,
+                pos.clone(),
+                sub_eval,
+            );
             *enriched_strict = prev_strict;
             result
         }
+    }?;
+
+    match (result.body.term.as_ref(), max_list_length) {
+        (Term::List(ts), Some(max)) if ts.len() > max => {
+            Err(EvalError::ListTooLong(ts.len(), max, pos))
+        }
+        _ => Ok(result),
     }
 }
 
@@ -96,9 +155,12 @@ fn process_unary_operation(
     arg_pos: Option<RawSpan>,
     stack: &mut Stack,
     pos_op: Option<RawSpan>,
+    sub_eval: &mut SubEval,
 ) -> Result<Closure, EvalError> {
     let Closure {
-        body: RichTerm { term: t, pos },
+        body: RichTerm {
+            term: mut t, pos, ..
+        },
         mut env,
     } = clos;
     match u_op {
@@ -117,64 +179,60 @@ fn process_unary_operation(
                     String::from("Bool"),
                     String::from("if"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
         UnaryOp::IsZero() => {
             if let Term::Num(n) = *t {
                 // TODO Discuss and decide on this comparison for 0 on f64
-                Ok(Closure::atomic_closure(Term::Bool(n == 0.).into()))
+                Ok(reuse(t, Term::Bool(n == 0.)))
             } else {
                 Err(EvalError::TypeError(
                     String::from("Num"),
                     String::from("isZero"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
         UnaryOp::IsNum() => {
-            if let Term::Num(_) = *t {
-                Ok(Closure::atomic_closure(Term::Bool(true).into()))
-            } else {
-                Ok(Closure::atomic_closure(Term::Bool(false).into()))
-            }
+            let result = matches!(*t, Term::Num(_));
+            Ok(reuse(t, Term::Bool(result)))
         }
         UnaryOp::IsBool() => {
-            if let Term::Bool(_) = *t {
-                Ok(Closure::atomic_closure(Term::Bool(true).into()))
-            } else {
-                Ok(Closure::atomic_closure(Term::Bool(false).into()))
-            }
+            let result = matches!(*t, Term::Bool(_));
+            Ok(reuse(t, Term::Bool(result)))
         }
         UnaryOp::IsStr() => {
-            if let Term::Str(_) = *t {
-                Ok(Closure::atomic_closure(Term::Bool(true).into()))
-            } else {
-                Ok(Closure::atomic_closure(Term::Bool(false).into()))
-            }
+            let result = matches!(*t, Term::Str(_));
+            Ok(reuse(t, Term::Bool(result)))
         }
         UnaryOp::IsFun() => {
-            if let Term::Fun(_, _) = *t {
-                Ok(Closure::atomic_closure(Term::Bool(true).into()))
-            } else {
-                Ok(Closure::atomic_closure(Term::Bool(false).into()))
-            }
+            let result = matches!(*t, Term::Fun(_, _));
+            Ok(reuse(t, Term::Bool(result)))
         }
         UnaryOp::IsList() => {
-            if let Term::List(_) = *t {
-                Ok(Closure::atomic_closure(Term::Bool(true).into()))
-            } else {
-                Ok(Closure::atomic_closure(Term::Bool(false).into()))
-            }
+            let result = matches!(*t, Term::List(_));
+            Ok(reuse(t, Term::Bool(result)))
+        }
+        UnaryOp::IsRecord() => {
+            let result = matches!(*t, Term::Record(_) | Term::RecRecord(_));
+            Ok(reuse(t, Term::Bool(result)))
+        }
+        UnaryOp::TypeOf() => {
+            let kind = match *t {
+                Term::Num(_) => "Num",
+                Term::Bool(_) => "Bool",
+                Term::Str(_) => "Str",
+                Term::Enum(_) => "Enum",
+                Term::Fun(..) => "Fun",
+                Term::List(_) => "List",
+                Term::Record(_) | Term::RecRecord(_) => "Record",
+                _ => "Other",
+            };
+            Ok(reuse(t, Term::Str(String::from(kind))))
         }
-        UnaryOp::IsRecord() => match *t {
-            Term::Record(_) | Term::RecRecord(_) => {
-                Ok(Closure::atomic_closure(Term::Bool(true).into()))
-            }
-            _ => Ok(Closure::atomic_closure(Term::Bool(false).into())),
-        },
         UnaryOp::Blame() => {
             if let Term::Lbl(l) = *t {
                 Err(EvalError::BlameError(l, None))
@@ -183,7 +241,7 @@ fn process_unary_operation(
                     String::from("Label"),
                     String::from("blame"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
@@ -195,7 +253,72 @@ fn process_unary_operation(
                     String::from("Enum"),
                     String::from("embed"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
+                ))
+            }
+        }
+        UnaryOp::EnumToStr() => {
+            if let Term::Enum(Ident(ref mut s)) = *t {
+                let s = mem::take(s);
+                Ok(reuse(t, Term::Str(s)))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("Enum"),
+                    String::from("enumToStr"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
+                ))
+            }
+        }
+        UnaryOp::EnumFromStr(tags) => {
+            if let Term::Str(s) = *t {
+                let tag = Ident(s);
+                if tags.contains(&tag) {
+                    Ok(Closure::atomic_closure(Term::Enum(tag).into()))
+                } else {
+                    let Ident(s) = tag;
+                    let tags: Vec<String> = tags.into_iter().map(|Ident(id)| id).collect();
+                    Err(EvalError::Other(
+                        format!(
+                            "enumFromStr: \"{}\" is not one of the tags <{}>",
+                            s,
+                            tags.join(", ")
+                        ),
+                        pos_op,
+                    ))
+                }
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("Str"),
+                    String::from("enumFromStr"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
+                ))
+            }
+        }
+        UnaryOp::PathBasename() => {
+            if let Term::Str(ref s) = *t {
+                let basename = String::from(path_basename(s));
+                Ok(reuse(t, Term::Str(basename)))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("Str"),
+                    String::from("pathBasename"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
+                ))
+            }
+        }
+        UnaryOp::PathExtension() => {
+            if let Term::Str(ref s) = *t {
+                let ext = String::from(path_extension(s));
+                Ok(reuse(t, Term::Str(ext)))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("Str"),
+                    String::from("pathExtension"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
@@ -209,10 +332,7 @@ fn process_unary_operation(
                             String::from("Enum"),
                             String::from("switch"),
                             arg_pos,
-                            RichTerm {
-                                term: Box::new(Term::Enum(en)),
-                                pos,
-                            },
+                            RichTerm::with_pos(Box::new(Term::Enum(en)), pos),
                         )),
                     },
                 }
@@ -223,7 +343,7 @@ fn process_unary_operation(
                         String::from("Enum"),
                         String::from("switch"),
                         arg_pos,
-                        RichTerm { term: t, pos },
+                        RichTerm::with_pos(t, pos),
                     )),
                 }
             }
@@ -237,19 +357,20 @@ fn process_unary_operation(
                     String::from("Label"),
                     String::from("changePolarity"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
         UnaryOp::Pol() => {
-            if let Term::Lbl(l) = *t {
-                Ok(Closure::atomic_closure(Term::Bool(l.polarity).into()))
+            if let Term::Lbl(ref l) = *t {
+                let polarity = l.polarity;
+                Ok(reuse(t, Term::Bool(polarity)))
             } else {
                 Err(EvalError::TypeError(
                     String::from("Label"),
                     String::from("polarity"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
@@ -262,7 +383,7 @@ fn process_unary_operation(
                     String::from("Label"),
                     String::from("goDom"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
@@ -275,7 +396,7 @@ fn process_unary_operation(
                     String::from("Label"),
                     String::from("goCodom"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
@@ -288,7 +409,67 @@ fn process_unary_operation(
                     String::from("Label"),
                     String::from("tag"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
+                ))
+            }
+        }
+        UnaryOp::LabelTag() => {
+            if let Term::Lbl(ref mut l) = *t {
+                let tag = mem::take(&mut l.tag);
+                Ok(reuse(t, Term::Str(tag)))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("Label"),
+                    String::from("labelTag"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
+                ))
+            }
+        }
+        UnaryOp::LabelPath() => {
+            if let Term::Lbl(ref l) = *t {
+                let path: Vec<RichTerm> = l
+                    .path
+                    .iter()
+                    .map(|elem| match elem {
+                        ty_path::Elem::Domain => Term::Enum(Ident::from("Domain")).into(),
+                        ty_path::Elem::Codomain => Term::Enum(Ident::from("Codomain")).into(),
+                        ty_path::Elem::Field(Ident(id)) => Term::Str(id.clone()).into(),
+                    })
+                    .collect();
+                Ok(reuse(t, Term::List(path)))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("Label"),
+                    String::from("labelPath"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
+                ))
+            }
+        }
+        UnaryOp::MkLabel() => {
+            if let Term::Str(tag) = *t {
+                let span = pos_op.or(arg_pos).ok_or_else(|| {
+                    EvalError::InternalError(
+                        String::from("mkLabel: the position of the call is unknown"),
+                        None,
+                    )
+                })?;
+                let label = Label {
+                    types: Types(AbsType::Dyn()),
+                    tag,
+                    span,
+                    polarity: true,
+                    path: Vec::new(),
+                    arg_pos: None,
+                };
+                Ok(Closure::atomic_closure(Term::Lbl(label).into()))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("Str"),
+                    String::from("mkLabel"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
@@ -306,7 +487,20 @@ fn process_unary_operation(
                     String::from("Sym"),
                     String::from("wrap"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
+                ))
+            }
+        }
+        UnaryOp::FreshSym() => {
+            if let Term::Str(_) = *t {
+                let sym = -1 - FreshSymCounter::next();
+                Ok(Closure::atomic_closure(Term::Sym(sym).into()))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("Str"),
+                    String::from("freshSym"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
@@ -318,10 +512,7 @@ fn process_unary_operation(
                     None => Err(EvalError::FieldMissing(
                         id.0,
                         String::from("(.)"),
-                        RichTerm {
-                            term: Box::new(Term::Record(static_map)),
-                            pos,
-                        },
+                        RichTerm::with_pos(Box::new(Term::Record(static_map)), pos),
                         pos_op,
                     )), //TODO include the position of operators on the stack
                 }
@@ -330,7 +521,42 @@ fn process_unary_operation(
                     String::from("Record"),
                     String::from("field access"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
+                ))
+            }
+        }
+        UnaryOp::Pick(ids) => {
+            if let Term::Record(mut static_map) = *t {
+                let mut picked = RecordData::new();
+
+                for id in ids {
+                    match static_map.remove(&id) {
+                        Some(e) => {
+                            picked.insert(id, e);
+                        }
+                        None => {
+                            // Put back the fields already picked, to report the original record
+                            static_map.extend(picked);
+                            return Err(EvalError::FieldMissing(
+                                id.0,
+                                String::from("pick"),
+                                RichTerm::with_pos(Box::new(Term::Record(static_map)), pos),
+                                pos_op,
+                            ));
+                        }
+                    }
+                }
+
+                Ok(Closure {
+                    body: Term::Record(picked).into(),
+                    env,
+                })
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("Record"),
+                    String::from("pick"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
@@ -345,7 +571,67 @@ fn process_unary_operation(
                     String::from("Record"),
                     String::from("fieldsOf"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
+                ))
+            }
+        }
+        UnaryOp::FieldsWithMetadata() => {
+            if let Term::Record(map) = *t {
+                let entries = map
+                    .iter()
+                    .map(|(Ident(id), t)| {
+                        Term::Op1(UnaryOp::FieldMetadata(id.clone()), t.clone()).into()
+                    })
+                    .collect();
+                Ok(Closure {
+                    body: Term::List(entries).into(),
+                    env,
+                })
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("Record"),
+                    String::from("fieldsWithMetadata"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
+                ))
+            }
+        }
+        UnaryOp::FieldMetadata(name) => {
+            let (has_default, has_doc, contracts) = match *t {
+                Term::MetaValue(meta) => (
+                    meta.priority == MergePriority::Default,
+                    meta.doc.is_some(),
+                    // The type of a contract may have been closurized by a merge: report the
+                    // original one, which is kept by its label.
+                    meta.contracts
+                        .iter()
+                        .map(|ctr| Term::Str(format!("{}", ctr.label.types)).into())
+                        .collect(),
+                ),
+                _ => (false, false, Vec::new()),
+            };
+
+            let entry = vec![
+                ("name", Term::Str(name)),
+                ("hasDefault", Term::Bool(has_default)),
+                ("hasDoc", Term::Bool(has_doc)),
+                ("contracts", Term::List(contracts)),
+            ]
+            .into_iter()
+            .map(|(id, t)| (Ident::from(id), t.into()))
+            .collect();
+            Ok(Closure::atomic_closure(Term::Record(entry).into()))
+        }
+        UnaryOp::HostCall(f) => {
+            if let Term::List(ts) = *t {
+                let args = ts.into_iter().map(|t| subst(t, &env)).collect();
+                (f.fun)(args)
+                    .map(Closure::atomic_closure)
+                    .map_err(|msg| EvalError::Other(format!("{}: {}", f.name, msg), pos_op))
+            } else {
+                Err(EvalError::InternalError(
+                    format!("host function {} was not called on a list", f.name),
+                    pos_op,
                 ))
             }
         }
@@ -377,10 +663,14 @@ fn process_unary_operation(
                     String::from("Record"),
                     String::from("map on record"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
+        UnaryOp::CatchBlame(_) => Ok(Closure {
+            body: RichTerm::with_pos(t, pos),
+            env,
+        }),
         UnaryOp::Seq() => {
             if stack.count_args() >= 1 {
                 let (next, _) = stack.pop_arg().expect("Condition already checked.");
@@ -437,7 +727,7 @@ fn process_unary_operation(
                     String::from("List"),
                     String::from("head"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
@@ -457,7 +747,7 @@ fn process_unary_operation(
                     String::from("List"),
                     String::from("tail"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
@@ -466,19 +756,247 @@ fn process_unary_operation(
                 // A num does not have any free variable so we can drop the environment
                 Ok(Closure {
                     body: Term::Num(ts.len() as f64).into(),
-                    env: HashMap::new(),
+                    env: HashMap::default(),
                 })
             } else {
                 Err(EvalError::TypeError(
                     String::from("List"),
                     String::from("length"),
                     arg_pos,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
+                ))
+            }
+        }
+        UnaryOp::ListUnique() => {
+            if let Term::List(ts) = *t {
+                // The values seen so far, grouped by hash, such that each value is only compared
+                // to the few ones which have the same hash.
+                let mut seen: HashMap<u64, Vec<RichTerm>> = HashMap::default();
+                let mut unique = Vec::new();
+
+                for thunk in list_thunks(ts, &env) {
+                    let x = Ident::from("%x");
+                    let mut forced_env = HashMap::default();
+                    forced_env.insert(x.clone(), thunk.clone());
+                    let forced = sub_eval(Closure {
+                        body: RichTerm::app(
+                            Term::Op1(UnaryOp::DeepSeq(), Term::Var(x.clone()).into()).into(),
+                            Term::Var(x).into(),
+                        ),
+                        env: forced_env,
+                    })?;
+
+                    let mut value = subst(forced.body, &forced.env);
+                    value.clean_pos();
+                    let mut hasher = DefaultHasher::new();
+                    hash_value(&value, &mut hasher);
+                    let bucket = seen.entry(hasher.finish()).or_default();
+                    if !bucket.contains(&value) {
+                        bucket.push(value);
+                        unique.push(thunk);
+                    }
+                }
+
+                Ok(thunks_to_list(unique))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("List"),
+                    String::from("listUnique"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
+        // The two lists are taken from the stack, as for `if-then-else`.
+        UnaryOp::ListZipWith() if stack.count_args() < 2 => Ok(curry(
+            UnaryOp::ListZipWith(),
+            RichTerm::with_pos(t, pos),
+            env,
+            2,
+        )),
+        UnaryOp::ListZipWith() => {
+            let f = thunk(RichTerm::with_pos(t, pos), env);
+            let xs = pop_list_arg(stack, sub_eval, ("listZipWith", "2nd"), &pos_op)?;
+            let ys = pop_list_arg(stack, sub_eval, ("listZipWith", "3rd"), &pos_op)?;
+
+            let ts = xs
+                .iter()
+                .zip(ys.iter())
+                .map(|(x, y)| closure_thunk(apply_thunks(&f, &[x, y])))
+                .collect();
+
+            Ok(thunks_to_list(ts))
+        }
+        // The end and the step are taken from the stack, as for `if-then-else`.
+        UnaryOp::ListRange() if stack.count_args() < 2 => Ok(curry(
+            UnaryOp::ListRange(),
+            RichTerm::with_pos(t, pos),
+            env,
+            2,
+        )),
+        UnaryOp::ListRange() => {
+            let start = if let Term::Num(n) = *t {
+                n
+            } else {
+                return Err(EvalError::TypeError(
+                    String::from("Num"),
+                    String::from("listRange, 1st argument"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
+                ));
+            };
+            let end = pop_num_arg(stack, sub_eval, ("listRange", "2nd"), &pos_op)?;
+            let step = pop_num_arg(stack, sub_eval, ("listRange", "3rd"), &pos_op)?;
+
+            if step == 0.0 || !(start.is_finite() && end.is_finite() && step.is_finite()) {
+                return Err(EvalError::Other(
+                    format!(
+                        "listRange: expected finite bounds and a non-zero step, got {} {} {}",
+                        start, end, step
+                    ),
+                    pos_op,
+                ));
+            }
+
+            let len = ((end - start) / step).ceil().max(0.0);
+            if len > MAX_RANGE_LENGTH as f64 {
+                return Err(EvalError::Other(
+                    format!(
+                        "listRange: the range would have {} elements, more than the maximum of {}",
+                        len, MAX_RANGE_LENGTH
+                    ),
+                    pos_op,
+                ));
+            }
+
+            let ts = (0..len as usize)
+                .map(|i| Term::Num(start + (i as f64) * step).into())
+                .collect();
+
+            // Numbers don't have any free variable, so we can drop the environment.
+            Ok(Closure {
+                body: Term::List(ts).into(),
+                env: HashMap::default(),
+            })
+        }
+        // The function and the initial value are taken from the stack, as for `if-then-else`.
+        UnaryOp::Iterate() if stack.count_args() < 2 => Ok(curry(
+            UnaryOp::Iterate(),
+            RichTerm::with_pos(t, pos),
+            env,
+            2,
+        )),
+        UnaryOp::Iterate() => {
+            let steps = match *t {
+                Term::Num(n) if n >= 0.0 && n.fract() == 0.0 => n as usize,
+                Term::Num(n) => {
+                    return Err(EvalError::Other(
+                        format!(
+                            "iterate: expected a non-negative integer number of steps, got {}",
+                            n
+                        ),
+                        pos_op,
+                    ))
+                }
+                t => {
+                    return Err(EvalError::TypeError(
+                        String::from("Num"),
+                        String::from("iterate, 1st argument"),
+                        arg_pos,
+                        RichTerm::with_pos(Box::new(t), pos),
+                    ))
+                }
+            };
+            let (f, _) = stack.pop_arg().ok_or_else(|| {
+                EvalError::NotEnoughArgs(3, String::from("iterate"), pos_op.clone())
+            })?;
+            let (init, _) = stack.pop_arg().ok_or_else(|| {
+                EvalError::NotEnoughArgs(3, String::from("iterate"), pos_op.clone())
+            })?;
+
+            let f = closure_thunk(f);
+            let mut acc = init;
+            for _ in 0..steps {
+                acc = sub_eval(apply_thunks(&f, &[&closure_thunk(acc)]))?;
+            }
+
+            Ok(acc)
+        }
+        // The new name and the record are taken from the stack, as for `if-then-else`.
+        UnaryOp::RecordRenameField() if stack.count_args() < 2 => Ok(curry(
+            UnaryOp::RecordRenameField(),
+            RichTerm::with_pos(t, pos),
+            env,
+            2,
+        )),
+        UnaryOp::RecordRenameField() => {
+            let old = if let Term::Str(s) = *t {
+                Ident(s)
+            } else {
+                return Err(EvalError::TypeError(
+                    String::from("Str"),
+                    String::from("recordRenameField, 1st argument"),
+                    arg_pos,
+                    RichTerm::with_pos(t, pos),
+                ));
+            };
+            let new = pop_arg_as(
+                stack,
+                sub_eval,
+                ("recordRenameField", "2nd"),
+                "Str",
+                &pos_op,
+                |t, _| match t {
+                    Term::Str(s) => Ok(Ident(s)),
+                    t => Err(Box::new(t)),
+                },
+            )?;
+            let (mut map, env) = pop_arg_as(
+                stack,
+                sub_eval,
+                ("recordRenameField", "3rd"),
+                "Record",
+                &pos_op,
+                |t, env| match t {
+                    Term::Record(map) => Ok((map, env)),
+                    t => Err(Box::new(t)),
+                },
+            )?;
+
+            if old != new && map.contains_key(&new) {
+                return Err(EvalError::Other(
+                    format!("recordRenameField: the field {} already exists", new),
+                    pos_op,
+                ));
+            }
+
+            match map.remove(&old) {
+                Some(t) => {
+                    map.insert(new, t);
+                    Ok(Closure {
+                        body: Term::Record(map).into(),
+                        env,
+                    })
+                }
+                None => Err(EvalError::FieldMissing(
+                    old.to_string(),
+                    String::from("recordRenameField"),
+                    RichTerm::new(Term::Record(map)),
+                    pos_op,
+                )),
+            }
+        }
         UnaryOp::ChunksConcat(mut acc, mut tail) => {
-            if let Term::Str(s) = *t {
+            // Numbers, booleans and enum tags are converted to strings automatically. Other values
+            // must be converted explicitly.
+            let s = match t.as_ref() {
+                Term::Str(s) | Term::Enum(Ident(s)) => Some(s.clone()),
+                Term::Num(n) => Some(NumFormat::default().format(*n)),
+                Term::Bool(b) => Some(format!("{}", b)),
+                _ => None,
+            };
+
+            if let Some(s) = s {
                 acc.push_str(&s);
                 let mut next_opt = tail.pop();
 
@@ -499,30 +1017,30 @@ fn process_unary_operation(
                         .collect();
 
                     Ok(Closure {
-                        body: RichTerm {
-                            term: Box::new(Term::Op1(
+                        body: RichTerm::with_pos(
+                            Box::new(Term::Op1(
                                 UnaryOp::ChunksConcat(acc, tail_closure),
                                 arg_closure,
                             )),
-                            pos: pos_op,
-                        },
+                            pos_op,
+                        ),
                         env,
                     })
                 } else {
                     Ok(Closure {
-                        body: RichTerm {
-                            term: Box::new(Term::Str(acc)),
-                            pos: pos_op,
-                        },
-                        env: HashMap::new(),
+                        body: RichTerm::with_pos(Box::new(Term::Str(acc)), pos_op),
+                        env: HashMap::default(),
                     })
                 }
             } else {
                 Err(EvalError::TypeError(
-                    String::from("String"),
-                    String::from("interpolated string"),
+                    String::from("Str, Num, Bool or an enum tag"),
+                    String::from(
+                        "interpolated string: only strings, numbers, booleans and enum tags are \
+                        converted to strings automatically",
+                    ),
                     pos_op,
-                    RichTerm { term: t, pos },
+                    RichTerm::with_pos(t, pos),
                 ))
             }
         }
@@ -539,13 +1057,14 @@ fn process_binary_operation(
     fst_pos: Option<RawSpan>,
     clos: Closure,
     snd_pos: Option<RawSpan>,
-    _stack: &mut Stack,
     pos_op: Option<RawSpan>,
+    sub_eval: &mut SubEval,
 ) -> Result<Closure, EvalError> {
     let Closure {
         body: RichTerm {
-            term: t1,
+            term: mut t1,
             pos: pos1,
+            ..
         },
         env: env1,
     } = fst_clos;
@@ -553,6 +1072,7 @@ fn process_binary_operation(
         body: RichTerm {
             term: t2,
             pos: pos2,
+            ..
         },
         env: mut env2,
     } = clos;
@@ -561,16 +1081,13 @@ fn process_binary_operation(
         BinaryOp::Plus() => {
             if let Term::Num(n1) = *t1 {
                 if let Term::Num(n2) = *t2 {
-                    Ok(Closure::atomic_closure(Term::Num(n1 + n2).into()))
+                    Ok(reuse(t1, Term::Num(n1 + n2)))
                 } else {
                     Err(EvalError::TypeError(
                         String::from("Num"),
                         String::from("+, 2nd argument"),
                         snd_pos,
-                        RichTerm {
-                            term: t2,
-                            pos: pos2,
-                        },
+                        RichTerm::with_pos(t2, pos2),
                     ))
                 }
             } else {
@@ -578,10 +1095,7 @@ fn process_binary_operation(
                     String::from("Num"),
                     String::from("+, 1st argument"),
                     fst_pos,
-                    RichTerm {
-                        term: t1,
-                        pos: pos1,
-                    },
+                    RichTerm::with_pos(t1, pos1),
                 ))
             }
         }
@@ -594,10 +1108,7 @@ fn process_binary_operation(
                         String::from("Str"),
                         String::from("++, 2nd argument"),
                         snd_pos,
-                        RichTerm {
-                            term: t2,
-                            pos: pos2,
-                        },
+                        RichTerm::with_pos(t2, pos2),
                     ))
                 }
             } else {
@@ -605,10 +1116,7 @@ fn process_binary_operation(
                     String::from("Str"),
                     String::from("++, 1st argument"),
                     fst_pos,
-                    RichTerm {
-                        term: t1,
-                        pos: pos1,
-                    },
+                    RichTerm::with_pos(t1, pos1),
                 ))
             }
         }
@@ -639,10 +1147,7 @@ fn process_binary_operation(
                     String::from("Sym"),
                     String::from("unwrap, 1st argument"),
                     fst_pos,
-                    RichTerm {
-                        term: t1,
-                        pos: pos1,
-                    },
+                    RichTerm::with_pos(t1, pos1),
                 ))
             }
         }
@@ -683,7 +1188,7 @@ fn process_binary_operation(
                 )
             }
 
-            let mut env: Environment = HashMap::new();
+            let mut env: Environment = HashMap::default();
             let res = match (*t1, *t2) {
                 (Term::Bool(b1), Term::Bool(b2)) => Term::Bool(b1 == b2),
                 (Term::Num(n1), Term::Num(n2)) => Term::Bool(n1 == n2),
@@ -691,7 +1196,7 @@ fn process_binary_operation(
                 (Term::Lbl(l1), Term::Lbl(l2)) => Term::Bool(l1 == l2),
                 (Term::Sym(s1), Term::Sym(s2)) => Term::Bool(s1 == s2),
                 (Term::Record(m1), Term::Record(m2)) => {
-                    let (left, center, right) = merge::hashmap::split(m1, m2);
+                    let (left, center, right) = m1.split(m2);
 
                     if !left.is_empty() || !right.is_empty() {
                         Term::Bool(false)
@@ -710,8 +1215,11 @@ fn process_binary_operation(
                 (_, _) => Term::Bool(false),
             };
 
+            // The operands have been moved out: store the result in the allocation of the first
+            // one, as `reuse` does.
+            *t1 = res;
             Ok(Closure {
-                body: res.into(),
+                body: RichTerm::with_pos(t1, None),
                 env,
             })
         }
@@ -723,10 +1231,7 @@ fn process_binary_operation(
                         None => Err(EvalError::FieldMissing(
                             format!("{}", id),
                             String::from("(.$)"),
-                            RichTerm {
-                                term: Box::new(Term::Record(static_map)),
-                                pos: pos2,
-                            },
+                            RichTerm::with_pos(Box::new(Term::Record(static_map)), pos2),
                             pos_op,
                         )),
                     }
@@ -735,10 +1240,7 @@ fn process_binary_operation(
                         String::from("Record"),
                         String::from(".$"),
                         snd_pos,
-                        RichTerm {
-                            term: t2,
-                            pos: pos2,
-                        },
+                        RichTerm::with_pos(t2, pos2),
                     ))
                 }
             } else {
@@ -746,10 +1248,29 @@ fn process_binary_operation(
                     String::from("Str"),
                     String::from(".$"),
                     fst_pos,
-                    RichTerm {
-                        term: t1,
-                        pos: pos1,
-                    },
+                    RichTerm::with_pos(t1, pos1),
+                ))
+            }
+        }
+        BinaryOp::DynAccessOpt() => {
+            if let Term::Str(id) = *t1 {
+                if let Term::Record(mut static_map) = *t2 {
+                    let value = static_map.remove(&Ident(id)).map(|body| thunk(body, env2));
+                    Ok(option_record(value))
+                } else {
+                    Err(EvalError::TypeError(
+                        String::from("Record"),
+                        String::from(".$?"),
+                        snd_pos,
+                        RichTerm::with_pos(t2, pos2),
+                    ))
+                }
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("Str"),
+                    String::from(".$?"),
+                    fst_pos,
+                    RichTerm::with_pos(t1, pos1),
                 ))
             }
         }
@@ -769,10 +1290,7 @@ fn process_binary_operation(
                         String::from("Record"),
                         String::from("$[ .. ]"),
                         snd_pos,
-                        RichTerm {
-                            term: t2,
-                            pos: pos2,
-                        },
+                        RichTerm::with_pos(t2, pos2),
                     ))
                 }
             } else {
@@ -780,10 +1298,7 @@ fn process_binary_operation(
                     String::from("Str"),
                     String::from("$[ .. ]"),
                     fst_pos,
-                    RichTerm {
-                        term: t1,
-                        pos: pos1,
-                    },
+                    RichTerm::with_pos(t1, pos1),
                 ))
             }
         }
@@ -794,10 +1309,7 @@ fn process_binary_operation(
                         None => Err(EvalError::FieldMissing(
                             format!("{}", id),
                             String::from("(-$)"),
-                            RichTerm {
-                                term: Box::new(Term::Record(static_map)),
-                                pos: pos2,
-                            },
+                            RichTerm::with_pos(Box::new(Term::Record(static_map)), pos2),
                             pos_op,
                         )),
                         Some(_) => Ok(Closure {
@@ -810,10 +1322,7 @@ fn process_binary_operation(
                         String::from("Record"),
                         String::from("-$"),
                         snd_pos,
-                        RichTerm {
-                            term: t2,
-                            pos: pos2,
-                        },
+                        RichTerm::with_pos(t2, pos2),
                     ))
                 }
             } else {
@@ -821,28 +1330,141 @@ fn process_binary_operation(
                     String::from("Str"),
                     String::from("-$"),
                     fst_pos,
-                    RichTerm {
-                        term: t1,
-                        pos: pos1,
-                    },
+                    RichTerm::with_pos(t1, pos1),
                 ))
             }
         }
+        BinaryOp::RecordIntersect() | BinaryOp::RecordDifference() => {
+            let op_name = if let BinaryOp::RecordIntersect() = b_op {
+                "recordIntersect"
+            } else {
+                "recordDifference"
+            };
+
+            match (*t1, *t2) {
+                (Term::Record(map1), Term::Record(map2)) => {
+                    let keep = matches!(b_op, BinaryOp::RecordIntersect());
+                    let map = map1
+                        .into_iter()
+                        .filter(|(id, _)| map2.contains_key(id) == keep)
+                        .collect();
+
+                    Ok(Closure {
+                        body: Term::Record(map).into(),
+                        env: env1,
+                    })
+                }
+                (Term::Record(_), t2) => Err(EvalError::TypeError(
+                    String::from("Record"),
+                    format!("{}, 2nd argument", op_name),
+                    snd_pos,
+                    RichTerm::with_pos(Box::new(t2), pos2),
+                )),
+                (t1, _) => Err(EvalError::TypeError(
+                    String::from("Record"),
+                    format!("{}, 1st argument", op_name),
+                    fst_pos,
+                    RichTerm::with_pos(Box::new(t1), pos1),
+                )),
+            }
+        }
+        BinaryOp::RecordFindFields() => match (*t1, *t2) {
+            (Term::Str(pattern), Term::Record(map)) => {
+                let regex = Regex::new(&pattern).map_err(|err| {
+                    EvalError::Other(
+                        format!("recordFindFields: invalid regular expression: {}", err),
+                        fst_pos,
+                    )
+                })?;
+                let mut fields: Vec<String> = map
+                    .keys()
+                    .filter(|Ident(id)| regex.is_match(id))
+                    .map(|Ident(id)| id.clone())
+                    .collect();
+                fields.sort();
+                let terms = fields.into_iter().map(|id| Term::Str(id).into()).collect();
+                Ok(Closure::atomic_closure(Term::List(terms).into()))
+            }
+            (Term::Str(_), t2) => Err(EvalError::TypeError(
+                String::from("Record"),
+                String::from("recordFindFields, 2nd argument"),
+                snd_pos,
+                RichTerm::with_pos(Box::new(t2), pos2),
+            )),
+            (t1, _) => Err(EvalError::TypeError(
+                String::from("Str"),
+                String::from("recordFindFields, 1st argument"),
+                fst_pos,
+                RichTerm::with_pos(Box::new(t1), pos1),
+            )),
+        },
+        // The path is forced element by element, and the intermediate records are evaluated in a
+        // separate abstract machine. The value at the end of the path is left unevaluated.
+        BinaryOp::RecordGetPath() => match (*t1, *t2) {
+            (Term::List(path), Term::Record(map)) => {
+                let mut ids = Vec::with_capacity(path.len());
+                for x in list_thunks(path, &env1) {
+                    let id = sub_eval(apply_thunks(&x, &[]))?;
+                    match *id.body.term {
+                        Term::Str(s) => ids.push(Ident(s)),
+                        t => {
+                            return Err(EvalError::TypeError(
+                                String::from("Str"),
+                                String::from("recordGetPath, element of the path"),
+                                fst_pos,
+                                RichTerm::with_pos(Box::new(t), id.body.pos),
+                            ))
+                        }
+                    }
+                }
+
+                fn field(clos: Closure, id: &Ident) -> Option<Closure> {
+                    let Closure { body, env } = clos;
+                    match *body.term {
+                        Term::Record(mut map) => map.remove(id).map(|body| Closure { body, env }),
+                        _ => None,
+                    }
+                }
+
+                let mut current = Closure {
+                    body: Term::Record(map).into(),
+                    env: env2,
+                };
+                let mut ids = ids.into_iter().peekable();
+                while let Some(id) = ids.next() {
+                    current = match field(current, &id) {
+                        None => return Ok(option_record(None)),
+                        Some(clos) if ids.peek().is_none() => clos,
+                        Some(clos) => sub_eval(clos)?,
+                    };
+                }
+
+                Ok(option_record(Some(closure_thunk(current))))
+            }
+            (Term::List(_), t2) => Err(EvalError::TypeError(
+                String::from("Record"),
+                String::from("recordGetPath, 2nd argument"),
+                snd_pos,
+                RichTerm::with_pos(Box::new(t2), pos2),
+            )),
+            (t1, _) => Err(EvalError::TypeError(
+                String::from("List"),
+                String::from("recordGetPath, 1st argument"),
+                fst_pos,
+                RichTerm::with_pos(Box::new(t1), pos1),
+            )),
+        },
         BinaryOp::HasField() => {
             if let Term::Str(id) = *t1 {
-                if let Term::Record(static_map) = *t2 {
-                    Ok(Closure::atomic_closure(
-                        Term::Bool(static_map.contains_key(&Ident(id))).into(),
-                    ))
+                if let Term::Record(ref static_map) = *t2 {
+                    let result = static_map.contains_key(&Ident(id));
+                    Ok(reuse(t2, Term::Bool(result)))
                 } else {
                     Err(EvalError::TypeError(
                         String::from("Record"),
                         String::from("hasField, 2nd argument"),
                         snd_pos,
-                        RichTerm {
-                            term: t2,
-                            pos: pos2,
-                        },
+                        RichTerm::with_pos(t2, pos2),
                     ))
                 }
             } else {
@@ -850,16 +1472,106 @@ fn process_binary_operation(
                     String::from("Str"),
                     String::from("hasField, 1st argument"),
                     fst_pos,
-                    RichTerm {
-                        term: t1,
-                        pos: pos1,
-                    },
+                    RichTerm::with_pos(t1, pos1),
                 ))
             }
         }
+        BinaryOp::WithArgPos() => {
+            if let Term::Lbl(mut l) = *t2 {
+                // The value may have been computed, in which case it has no position of its own.
+                if pos1.is_some() {
+                    l.arg_pos = pos1;
+                }
+                Ok(Closure::atomic_closure(Term::Lbl(l).into()))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("Label"),
+                    String::from("withArgPos, 2nd argument"),
+                    snd_pos,
+                    RichTerm::with_pos(t2, pos2),
+                ))
+            }
+        }
+        BinaryOp::WithTag() => match (*t1, *t2) {
+            (Term::Str(tag), Term::Lbl(mut l)) => {
+                l.tag = tag;
+                Ok(Closure::atomic_closure(Term::Lbl(l).into()))
+            }
+            (Term::Str(_), t2) => Err(EvalError::TypeError(
+                String::from("Label"),
+                String::from("withTag, 2nd argument"),
+                snd_pos,
+                RichTerm::with_pos(Box::new(t2), pos2),
+            )),
+            (t1, _) => Err(EvalError::TypeError(
+                String::from("Str"),
+                String::from("withTag, 1st argument"),
+                fst_pos,
+                RichTerm::with_pos(Box::new(t1), pos1),
+            )),
+        },
+        BinaryOp::RecordContract(fields, open) => {
+            let mut l = if let Term::Lbl(l) = *t1 {
+                l
+            } else {
+                return Err(EvalError::TypeError(
+                    String::from("Label"),
+                    String::from("recordContract, 1st argument"),
+                    fst_pos,
+                    RichTerm::with_pos(t1, pos1),
+                ));
+            };
+
+            // Mirror the checks and the tags of the builtin contract `record`, which this
+            // operation replaces.
+            let mut blame = |tag: &str| {
+                if pos2.is_some() {
+                    l.arg_pos = pos2.clone();
+                }
+                l.tag = String::from(tag);
+                EvalError::BlameError(l.clone(), None)
+            };
+
+            let record = match *t2 {
+                Term::Record(record) => record,
+                _ => return Err(blame("not a record")),
+            };
+
+            if fields.iter().any(|(id, _)| !record.contains_key(id)) {
+                return Err(blame("missing field"));
+            }
+
+            if !open && record.len() > fields.len() {
+                return Err(blame("extra field"));
+            }
+
+            let types: HashMap<Ident, Types> = fields.into_iter().collect();
+            let mut env = Environment::default();
+            let record = record
+                .into_iter()
+                .map(|(id, t)| {
+                    // Each field is checked with its own label, such that a blame points to the
+                    // type of the field rather than to the whole record type.
+                    let t = match types.get(&id) {
+                        Some(Types(AbsType::Dyn())) | None => t,
+                        Some(ty) => {
+                            let mut l = l.clone();
+                            l.path.push(ty_path::Elem::Field(id.clone()));
+                            Term::Assume(ty.clone(), l, t).into()
+                        }
+                    };
+                    (id, t.closurize(&mut env, env2.clone()))
+                })
+                .collect();
+
+            Ok(Closure {
+                body: Term::Record(record).into(),
+                env,
+            })
+        }
         BinaryOp::ListConcat() => match (*t1, *t2) {
             (Term::List(ts1), Term::List(ts2)) => {
-                let mut env = Environment::new();
+                let mut env = Environment::default();
                 let mut ts: Vec<RichTerm> = ts1
                     .into_iter()
                     .map(|t| t.closurize(&mut env, env1.clone()))
@@ -875,28 +1587,19 @@ fn process_binary_operation(
                 String::from("List"),
                 String::from("@, 2nd operand"),
                 snd_pos,
-                RichTerm {
-                    term: Box::new(t2),
-                    pos: pos2,
-                },
+                RichTerm::with_pos(Box::new(t2), pos2),
             )),
             (t1, _) => Err(EvalError::TypeError(
                 String::from("List"),
                 String::from("@, 1st operand"),
                 fst_pos,
-                RichTerm {
-                    term: Box::new(t1),
-                    pos: pos1,
-                },
+                RichTerm::with_pos(Box::new(t1), pos1),
             )),
         },
         // This one should not be strict in the first argument (f)
         BinaryOp::ListMap() => {
             if let Term::List(ts) = *t2 {
-                let f = RichTerm {
-                    term: t1,
-                    pos: pos1,
-                };
+                let f = RichTerm::with_pos(t1, pos1);
                 let f_as_var = f.closurize(&mut env2, env1);
 
                 let ts = ts
@@ -913,10 +1616,192 @@ fn process_binary_operation(
                     String::from("List"),
                     String::from("map, 2nd argument"),
                     snd_pos,
-                    RichTerm {
-                        term: t2,
-                        pos: pos2,
+                    RichTerm::with_pos(t2, pos2),
+                ))
+            }
+        }
+        BinaryOp::ListZip() => match (*t1, *t2) {
+            (Term::List(xs), Term::List(ys)) => {
+                let pairs = list_thunks(xs, &env1)
+                    .into_iter()
+                    .zip(list_thunks(ys, &env2))
+                    .map(|(x, y)| closure_thunk(thunks_to_list(vec![x, y])))
+                    .collect();
+
+                Ok(thunks_to_list(pairs))
+            }
+            (Term::List(_), t2) => Err(EvalError::TypeError(
+                String::from("List"),
+                String::from("listZip, 2nd argument"),
+                snd_pos,
+                RichTerm::with_pos(Box::new(t2), pos2),
+            )),
+            (t1, _) => Err(EvalError::TypeError(
+                String::from("List"),
+                String::from("listZip, 1st argument"),
+                fst_pos,
+                RichTerm::with_pos(Box::new(t1), pos1),
+            )),
+        },
+        BinaryOp::ListGroupBy() => {
+            if let Term::List(ts) = *t2 {
+                let key = thunk(RichTerm::with_pos(t1, pos1), env1);
+                // Groups are kept in the order of their first element, such that the result
+                // doesn't depend on the iteration order of a hash map.
+                let mut groups: Vec<(Ident, Vec<Thunk>)> = Vec::new();
+                let mut indices: HashMap<Ident, usize> = HashMap::default();
+
+                for x in list_thunks(ts, &env2) {
+                    let k = sub_eval(apply_thunks(&key, &[&x]))?;
+                    let id = match *k.body.term {
+                        Term::Str(s) => Ident(s),
+                        t => {
+                            return Err(EvalError::TypeError(
+                                String::from("Str"),
+                                String::from("listGroupBy, key"),
+                                fst_pos,
+                                RichTerm::with_pos(Box::new(t), k.body.pos),
+                            ))
+                        }
+                    };
+
+                    match indices.get(&id) {
+                        Some(i) => groups[*i].1.push(x),
+                        None => {
+                            indices.insert(id.clone(), groups.len());
+                            groups.push((id, vec![x]));
+                        }
+                    }
+                }
+
+                Ok(thunks_to_record(
+                    groups
+                        .into_iter()
+                        .map(|(id, xs)| (id, closure_thunk(thunks_to_list(xs))))
+                        .collect(),
+                ))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("List"),
+                    String::from("listGroupBy, 2nd argument"),
+                    snd_pos,
+                    RichTerm::with_pos(t2, pos2),
+                ))
+            }
+        }
+        BinaryOp::ListPartition() => {
+            if let Term::List(ts) = *t2 {
+                let pred = thunk(RichTerm::with_pos(t1, pos1), env1);
+                let mut matched = Vec::new();
+                let mut rest = Vec::new();
+
+                for x in list_thunks(ts, &env2) {
+                    let b = sub_eval(apply_thunks(&pred, &[&x]))?;
+                    match *b.body.term {
+                        Term::Bool(true) => matched.push(x),
+                        Term::Bool(false) => rest.push(x),
+                        t => {
+                            return Err(EvalError::TypeError(
+                                String::from("Bool"),
+                                String::from("listPartition, result of the predicate"),
+                                fst_pos,
+                                RichTerm::with_pos(Box::new(t), b.body.pos),
+                            ))
+                        }
+                    }
+                }
+
+                Ok(thunks_to_record(vec![
+                    (
+                        Ident::from("matched"),
+                        closure_thunk(thunks_to_list(matched)),
+                    ),
+                    (Ident::from("rest"), closure_thunk(thunks_to_list(rest))),
+                ]))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("List"),
+                    String::from("listPartition, 2nd argument"),
+                    snd_pos,
+                    RichTerm::with_pos(t2, pos2),
+                ))
+            }
+        }
+        // The comparison function is called from Rust, in a separate abstract machine.
+        BinaryOp::ListSort() => {
+            if let Term::List(ts) = *t2 {
+                let cmp = thunk(RichTerm::with_pos(t1, pos1), env1);
+
+                let sorted = merge_sort_by(list_thunks(ts, &env2), &mut |x, y| {
+                    let result = sub_eval(apply_thunks(&cmp, &[x, y]))?;
+                    match result.body.term.as_ref() {
+                        Term::Enum(Ident(tag)) if tag == "Lesser" => Ok(Ordering::Less),
+                        Term::Enum(Ident(tag)) if tag == "Equal" => Ok(Ordering::Equal),
+                        Term::Enum(Ident(tag)) if tag == "Greater" => Ok(Ordering::Greater),
+                        _ => Err(EvalError::TypeError(
+                            String::from("<Lesser, Equal, Greater>"),
+                            String::from("listSort, result of the comparison function"),
+                            fst_pos.clone(),
+                            result.body,
+                        )),
+                    }
+                })?;
+
+                Ok(thunks_to_list(sorted))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("List"),
+                    String::from("listSort, 2nd argument"),
+                    snd_pos,
+                    RichTerm::with_pos(t2, pos2),
+                ))
+            }
+        }
+        // Keys are computed once for each element, and then compared natively.
+        BinaryOp::ListSortBy() => {
+            if let Term::List(ts) = *t2 {
+                let key = thunk(RichTerm::with_pos(t1, pos1), env1);
+
+                let mut keyed = list_thunks(ts, &env2)
+                    .into_iter()
+                    .map(|x| {
+                        let k = sub_eval(apply_thunks(&key, &[&x]))?;
+                        Ok((k.body, x))
+                    })
+                    .collect::<Result<Vec<_>, EvalError>>()?;
+
+                let (expected, is_expected): (&str, fn(&Term) -> bool) =
+                    match keyed.first().map(|(k, _)| k.term.as_ref()) {
+                        Some(Term::Str(_)) => ("Str", |t| matches!(t, Term::Str(_))),
+                        _ => ("Num", |t| matches!(t, Term::Num(_))),
+                    };
+                if let Some(pos) = keyed.iter().position(|(k, _)| !is_expected(&k.term)) {
+                    let (k, _) = keyed.swap_remove(pos);
+                    return Err(EvalError::TypeError(
+                        String::from(expected),
+                        String::from(
+                            "listSortBy, key (keys must be either all numbers or all strings)",
+                        ),
+                        fst_pos,
+                        k,
+                    ));
+                }
+
+                keyed.sort_by(
+                    |(k1, _), (k2, _)| match (k1.term.as_ref(), k2.term.as_ref()) {
+                        (Term::Num(n1), Term::Num(n2)) => total_cmp(*n1, *n2),
+                        (Term::Str(s1), Term::Str(s2)) => s1.cmp(s2),
+                        _ => unreachable!("keys have been checked to be of the same type"),
                     },
+                );
+
+                Ok(thunks_to_list(keyed.into_iter().map(|(_, x)| x).collect()))
+            } else {
+                Err(EvalError::TypeError(
+                    String::from("List"),
+                    String::from("listSortBy, 2nd argument"),
+                    snd_pos,
+                    RichTerm::with_pos(t2, pos2),
                 ))
             }
         }
@@ -925,8 +1810,8 @@ fn process_binary_operation(
                 let n_int = n as usize;
                 if n.fract() != 0.0 {
                     Err(EvalError::Other(format!("elemAt: expected the 2nd agument to be an integer, got the floating-point value {}", n), pos_op))
-                } else if n_int >= ts.len() {
-                    Err(EvalError::Other(format!("elemAt: index out of bounds. Expected a value between 0 and {}, got {})", ts.len(), n_int), pos_op))
+                } else if n < 0.0 || n_int >= ts.len() {
+                    Err(EvalError::Other(format!("elemAt: index out of bounds. Expected a value between 0 and {}, got {})", ts.len(), n), pos_op))
                 } else {
                     Ok(Closure {
                         body: ts.swap_remove(n_int),
@@ -938,34 +1823,345 @@ fn process_binary_operation(
                 String::from("Num"),
                 String::from("elemAt, 2nd argument"),
                 snd_pos,
-                RichTerm {
-                    term: Box::new(t2),
-                    pos: pos2,
-                },
+                RichTerm::with_pos(Box::new(t2), pos2),
             )),
             (t1, _) => Err(EvalError::TypeError(
                 String::from("List"),
                 String::from("elemAt, 1st argument"),
                 fst_pos,
-                RichTerm {
-                    term: Box::new(t1),
-                    pos: pos1,
-                },
+                RichTerm::with_pos(Box::new(t1), pos1),
             )),
         },
         BinaryOp::Merge() => merge(
-            RichTerm {
-                term: t1,
-                pos: pos1,
-            },
+            RichTerm::with_pos(t1, pos1),
             env1,
-            RichTerm {
-                term: t2,
-                pos: pos2,
-            },
+            RichTerm::with_pos(t2, pos2),
             env2,
             pos_op,
+            MergeMode::Standard,
         ),
+        BinaryOp::MergeOverride() => merge(
+            RichTerm::with_pos(t1, pos1),
+            env1,
+            RichTerm::with_pos(t2, pos2),
+            env2,
+            pos_op,
+            MergeMode::Override,
+        ),
+        BinaryOp::PathJoin() => match (*t1, *t2) {
+            (Term::Str(s1), Term::Str(s2)) => Ok(Closure::atomic_closure(
+                Term::Str(path_join(&s1, &s2)).into(),
+            )),
+            (Term::Str(_), t2) => Err(EvalError::TypeError(
+                String::from("Str"),
+                String::from("pathJoin, 2nd argument"),
+                snd_pos,
+                RichTerm::with_pos(Box::new(t2), pos2),
+            )),
+            (t1, _) => Err(EvalError::TypeError(
+                String::from("Str"),
+                String::from("pathJoin, 1st argument"),
+                fst_pos,
+                RichTerm::with_pos(Box::new(t1), pos1),
+            )),
+        },
+    }
+}
+
+/// A thunk, as bound to a variable in an environment.
+type Thunk = (Rc<RefCell<Closure>>, IdentKind);
+
+fn thunk(body: RichTerm, env: Environment) -> Thunk {
+    (
+        Rc::new(RefCell::new(Closure { body, env })),
+        IdentKind::Lam(),
+    )
+}
+
+/// Turn the elements of a list into thunks, which can be evaluated independently of each other.
+/// Elements which are variables share the thunk they are bound to, such that they are still
+/// evaluated at most once.
+fn list_thunks(ts: Vec<RichTerm>, env: &Environment) -> Vec<Thunk> {
+    ts.into_iter()
+        .map(|t| match t.term.as_ref() {
+            Term::Var(id) if env.contains_key(id) => env.get(id).unwrap().clone(),
+            // Constants are closed, and don't need to capture the environment.
+            Term::Bool(_) | Term::Num(_) | Term::Str(_) | Term::Enum(_) => {
+                thunk(t, HashMap::default())
+            }
+            _ => thunk(t, env.clone()),
+        })
+        .collect()
+}
+
+/// Build a list whose elements are the given thunks.
+fn thunks_to_list(thunks: Vec<Thunk>) -> Closure {
+    let mut env = HashMap::default();
+    let ts = thunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, thunk)| {
+            let id = Ident(format!("%{}", i));
+            env.insert(id.clone(), thunk);
+            Term::Var(id).into()
+        })
+        .collect();
+
+    Closure {
+        body: Term::List(ts).into(),
+        env,
+    }
+}
+
+/// Build a record whose fields are bound to the given thunks.
+fn thunks_to_record(fields: Vec<(Ident, Thunk)>) -> Closure {
+    let mut env = HashMap::default();
+    let map = fields
+        .into_iter()
+        .enumerate()
+        .map(|(i, (id, thunk))| {
+            let var = Ident(format!("%{}", i));
+            env.insert(var.clone(), thunk);
+            (id, Term::Var(var).into())
+        })
+        .collect();
+
+    Closure {
+        body: Term::Record(map).into(),
+        env,
+    }
+}
+
+/// Build an optional value as a record, for lack of enum tags with a payload: `{status = `Some;
+/// value}` if there is a value, and `{status = `None}` otherwise.
+fn option_record(value: Option<Thunk>) -> Closure {
+    let (tag, value) = match value {
+        Some(thunk) => ("Some", Some((Ident::from("value"), thunk))),
+        None => ("None", None),
+    };
+    let status = thunk(Term::Enum(Ident::from(tag)).into(), HashMap::default());
+
+    thunks_to_record(
+        std::iter::once((Ident::from("status"), status))
+            .chain(value)
+            .collect(),
+    )
+}
+
+/// Delay the evaluation of a closure, such that it can be put in a list or a record.
+fn closure_thunk(clos: Closure) -> Thunk {
+    thunk(clos.body, clos.env)
+}
+
+/// Build the partial application of an operation whose `extra` additional arguments are taken
+/// from the stack, for when fewer arguments are available, as in `let g = listZipWith f in g xs
+/// ys`. The result is the function `fun %0 %1 => op %x %0 %1`, where `%x` is bound to the
+/// operand, which is evaluated again when the function is applied.
+fn curry(op: UnaryOp<RichTerm>, operand: RichTerm, env: Environment, extra: usize) -> Closure {
+    let x = Ident::from("%x");
+    let mut curried_env = HashMap::default();
+    curried_env.insert(x.clone(), thunk(operand, env));
+
+    let params: Vec<Ident> = (0..extra).map(|i| Ident(format!("%{}", i))).collect();
+    let app = params
+        .iter()
+        .fold(Term::Op1(op, Term::Var(x).into()).into(), |acc, id| {
+            RichTerm::app(acc, Term::Var(id.clone()).into())
+        });
+    let body = params
+        .into_iter()
+        .rev()
+        .fold(app, |acc, id| Term::Fun(id, acc).into());
+
+    Closure {
+        body,
+        env: curried_env,
+    }
+}
+
+/// Build the application of a function to arguments, all given as thunks. Without arguments,
+/// this is a closure which evaluates and updates the thunk of the function.
+fn apply_thunks(f: &Thunk, args: &[&Thunk]) -> Closure {
+    let f_id = Ident::from("%f");
+    let mut env = HashMap::default();
+    env.insert(f_id.clone(), f.clone());
+
+    let body = args
+        .iter()
+        .enumerate()
+        .fold(Term::Var(f_id).into(), |acc, (i, arg)| {
+            let id = Ident(format!("%{}", i));
+            env.insert(id.clone(), (*arg).clone());
+            RichTerm::app(acc, Term::Var(id).into())
+        });
+
+    Closure { body, env }
+}
+
+/// Pop the next argument of an operation from the stack, evaluate it, and extract a value with
+/// `extract`, which gives the term back if it doesn't have the `expected` type.
+///
+/// Used by the operations which take more than two arguments, whose additional arguments are not
+/// evaluated by the abstract machine.
+fn pop_arg_as<T, F>(
+    stack: &mut Stack,
+    sub_eval: &mut SubEval,
+    (op, nth): (&str, &str),
+    expected: &str,
+    pos_op: &Option<RawSpan>,
+    extract: F,
+) -> Result<T, EvalError>
+where
+    F: FnOnce(Term, Environment) -> Result<T, Box<Term>>,
+{
+    let (arg, arg_pos) = stack
+        .pop_arg()
+        .ok_or_else(|| EvalError::NotEnoughArgs(3, String::from(op), pos_op.clone()))?;
+    let Closure {
+        body: RichTerm { term, pos, .. },
+        env,
+    } = sub_eval(arg)?;
+
+    extract(*term, env).map_err(|t| {
+        EvalError::TypeError(
+            String::from(expected),
+            format!("{}, {} argument", op, nth),
+            arg_pos,
+            RichTerm::with_pos(t, pos),
+        )
+    })
+}
+
+fn pop_list_arg(
+    stack: &mut Stack,
+    sub_eval: &mut SubEval,
+    op_nth: (&str, &str),
+    pos_op: &Option<RawSpan>,
+) -> Result<Vec<Thunk>, EvalError> {
+    pop_arg_as(stack, sub_eval, op_nth, "List", pos_op, |t, env| match t {
+        Term::List(ts) => Ok(list_thunks(ts, &env)),
+        t => Err(Box::new(t)),
+    })
+}
+
+fn pop_num_arg(
+    stack: &mut Stack,
+    sub_eval: &mut SubEval,
+    op_nth: (&str, &str),
+    pos_op: &Option<RawSpan>,
+) -> Result<f64, EvalError> {
+    pop_arg_as(stack, sub_eval, op_nth, "Num", pos_op, |t, _| match t {
+        Term::Num(n) => Ok(n),
+        t => Err(Box::new(t)),
+    })
+}
+
+/// Hash a value consistently with the equality of terms, such that equal values have the same
+/// hash. Data values are hashed structurally, while other terms, such as functions, only hash
+/// their kind, and are told apart by equality.
+fn hash_value<H: Hasher>(rt: &RichTerm, state: &mut H) {
+    mem::discriminant(rt.term.as_ref()).hash(state);
+
+    match rt.term.as_ref() {
+        // `0.` and `-0.` are equal, but have different representations.
+        Term::Num(n) if *n == 0. => 0u64.hash(state),
+        Term::Num(n) => n.to_bits().hash(state),
+        Term::Bool(b) => b.hash(state),
+        Term::Str(s) => s.hash(state),
+        Term::Enum(id) => id.hash(state),
+        Term::List(ts) => {
+            ts.len().hash(state);
+            ts.iter().for_each(|t| hash_value(t, state));
+        }
+        Term::Record(fields) | Term::RecRecord(fields) => {
+            for (id, t) in fields.iter() {
+                id.hash(state);
+                hash_value(t, state);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Compare two numbers according to the total order of IEEE 754, as `f64::total_cmp`, which is
+/// not available in the version of Rust supported. NaNs are thus ordered too, which is required by
+/// sorting functions.
+fn total_cmp(n1: f64, n2: f64) -> Ordering {
+    // Flip all the bits but the sign of negative numbers, such that their representations as
+    // signed integers are ordered as the numbers.
+    let key = |n: f64| {
+        let bits = n.to_bits() as i64;
+        bits ^ ((((bits >> 63) as u64) >> 1) as i64)
+    };
+
+    key(n1).cmp(&key(n2))
+}
+
+/// Stable merge sort with a fallible comparison function, which stops at the first error.
+///
+/// The comparison function of `listSort` is an arbitrary Nickel function: it may fail, or not be a
+/// total order. `slice::sort_by` can neither propagate errors nor be relied on not to panic on an
+/// inconsistent order.
+fn merge_sort_by<T, E, F>(mut v: Vec<T>, cmp: &mut F) -> Result<Vec<T>, E>
+where
+    F: FnMut(&T, &T) -> Result<Ordering, E>,
+{
+    if v.len() <= 1 {
+        return Ok(v);
+    }
+
+    let right = v.split_off(v.len() / 2);
+    let left = merge_sort_by(v, cmp)?;
+    let right = merge_sort_by(right, cmp)?;
+
+    let mut result = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        // Take from the right only if strictly smaller, to keep the sort stable.
+        if cmp(r, l)? == Ordering::Less {
+            result.push(right.next().unwrap());
+        } else {
+            result.push(left.next().unwrap());
+        }
+    }
+
+    result.extend(left);
+    result.extend(right);
+    Ok(result)
+}
+
+// Path primitives only manipulate strings, and always use `/` as a separator, such that they
+// behave the same on every platform.
+
+/// Join two paths with a separator. If the second path is absolute, it is returned as is.
+fn path_join(base: &str, path: &str) -> String {
+    if base.is_empty() || path.starts_with('/') {
+        String::from(path)
+    } else if base.ends_with('/') {
+        format!("{}{}", base, path)
+    } else {
+        format!("{}/{}", base, path)
+    }
+}
+
+/// Return the last component of a path, ignoring trailing separators.
+fn path_basename(path: &str) -> &str {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(index) => &path[index + 1..],
+        None => path,
+    }
+}
+
+/// Return the extension of the last component of a path, without the dot. A leading dot, as in
+/// `.bashrc`, does not start an extension.
+fn path_extension(path: &str) -> &str {
+    let basename = path_basename(path);
+    match basename.rfind('.') {
+        Some(index) if index > 0 => &basename[index + 1..],
+        _ => "",
     }
 }
 
@@ -975,12 +2171,16 @@ mod tests {
     use crate::eval::{CallStack, Environment};
 
     fn some_env() -> Environment {
-        HashMap::new()
+        HashMap::default()
+    }
+
+    fn no_sub_eval(_: Closure) -> Result<Closure, EvalError> {
+        panic!("unexpected evaluation in a separate machine")
     }
 
     #[test]
     fn ite_operation() {
-        let cont = OperationCont::Op1(UnaryOp::Ite(), None);
+        let cont = OperationCont::Op1(UnaryOp::Ite(), None, true);
         let mut stack = Stack::new();
         stack.push_arg(Closure::atomic_closure(Term::Num(5.0).into()), None);
         stack.push_arg(Closure::atomic_closure(Term::Num(46.0).into()), None);
@@ -994,7 +2194,15 @@ mod tests {
         let mut call_stack = CallStack::new();
         let mut strict = true;
 
-        clos = continuate_operation(clos, &mut stack, &mut call_stack, &mut strict).unwrap();
+        clos = continuate_operation(
+            clos,
+            &mut stack,
+            &mut call_stack,
+            &mut strict,
+            None,
+            &mut no_sub_eval,
+        )
+        .unwrap();
 
         assert_eq!(
             clos,
@@ -1027,7 +2235,15 @@ mod tests {
         let mut call_stack = CallStack::new();
         let mut strict = true;
 
-        clos = continuate_operation(clos, &mut stack, &mut call_stack, &mut strict).unwrap();
+        clos = continuate_operation(
+            clos,
+            &mut stack,
+            &mut call_stack,
+            &mut strict,
+            None,
+            &mut no_sub_eval,
+        )
+        .unwrap();
 
         assert_eq!(
             clos,
@@ -1078,7 +2294,15 @@ mod tests {
         let mut call_stack = CallStack::new();
         let mut strict = false;
 
-        clos = continuate_operation(clos, &mut stack, &mut call_stack, &mut strict).unwrap();
+        clos = continuate_operation(
+            clos,
+            &mut stack,
+            &mut call_stack,
+            &mut strict,
+            None,
+            &mut no_sub_eval,
+        )
+        .unwrap();
 
         assert_eq!(
             clos,
@@ -1088,4 +2312,23 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn path_primitives() {
+        assert_eq!(path_join("a", "b"), "a/b");
+        assert_eq!(path_join("a/", "b"), "a/b");
+        assert_eq!(path_join("", "b"), "b");
+        assert_eq!(path_join("a", "/b"), "/b");
+        assert_eq!(path_join("a\\b", "c"), "a\\b/c");
+
+        assert_eq!(path_basename("a/b.ncl"), "b.ncl");
+        assert_eq!(path_basename("a/b//"), "b");
+        assert_eq!(path_basename("b"), "b");
+        assert_eq!(path_basename("/"), "");
+
+        assert_eq!(path_extension("a/b.tar.gz"), "gz");
+        assert_eq!(path_extension("a.d/b"), "");
+        assert_eq!(path_extension(".bashrc"), "");
+        assert_eq!(path_extension("b."), "");
+    }
 }
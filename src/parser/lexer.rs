@@ -30,6 +30,7 @@
 //! When parsing an interpolated expression, the closing `}` (if any) matching the starting `${`
 //! will pop the `Str` mode from the stack. Then, the lexer knows that it should not try to lex the
 //! next tokens as normal Nickel expressions, but rather as a string.
+use crate::identifier::Ident;
 use std::fmt;
 use std::str::CharIndices;
 
@@ -42,6 +43,8 @@ pub enum Token<'input> {
     BinaryOp(&'input str),
     /// A base type (Num, Str, etc.).
     Type(&'input str),
+    /// The list type, which may be applied to the type of its elements.
+    List,
 
     /// A string literal (which does not contain interpolated expressions).
     StrLiteral(String),
@@ -54,7 +57,9 @@ pub enum Token<'input> {
     Forall,
     In,
     Let,
+    Rec,
     Switch,
+    Strict,
 
     True,
     False,
@@ -66,13 +71,17 @@ pub enum Token<'input> {
     SemiCol,
     Dot,
     DotDollar,
+    DotDollarQuestion,
     DollarBracket,
     DollarEquals,
+    AmpEquals,
     DollarBrace,
     DoubleQuote,
     MinusDollar,
     Fun,
     Import,
+    Pick,
+    External,
     Pipe,
     SimpleArrow,
     DoubleArrow,
@@ -95,26 +104,54 @@ pub enum Token<'input> {
     IsFun,
     IsList,
     IsRecord,
+    TypeOf,
     Blame,
     ChangePol,
     Polarity,
     GoDom,
     GoCodom,
+    LabelTag,
+    LabelPath,
+    MkLabel,
     Wrap,
+    FreshSym,
     Embed,
     MapRec,
+    CatchBlame,
     Seq,
     DeepSeq,
     Head,
     Tail,
     Length,
+    ListUnique,
+    ListZipWith,
+    ListRange,
+    Iterate,
     FieldsOf,
+    FieldsWithMetadata,
+    RecordRenameField,
+    EnumToStr,
+    EnumFromStr,
+    PathBasename,
+    PathExtension,
 
     Unwrap,
     HasField,
+    WithArgPos,
+    WithTag,
+    RecordGetPath,
+    RecordIntersect,
+    RecordFindFields,
+    RecordDifference,
     Map,
     ElemAt,
+    ListZip,
+    ListGroupBy,
+    ListPartition,
+    ListSort,
+    ListSortBy,
     Merge,
+    PathJoin,
 
     LBrace,
     RBrace,
@@ -145,10 +182,16 @@ pub enum LexicalError {
     UnexpectedChar(usize),
     /// An alphanumeric character directly follows a number literal.
     NumThenIdent(usize),
+    /// A hexadecimal, octal or binary literal has no digit, has an invalid digit, or is too large
+    /// to be represented exactly.
+    InvalidNumLiteral(usize, usize),
     /// Invalid escape sequence in a string literal.
     InvalidEscapeSequence(usize),
     /// Unexpected end of input.
     UnexpectedEOF(Vec<String>),
+    /// A field is defined twice in a record literal. Reported by the parser, with the offsets of
+    /// both definitions.
+    DuplicateField(Ident, (usize, usize), (usize, usize)),
 }
 
 /// User for error reporting.
@@ -165,9 +208,12 @@ impl<'input> fmt::Display for Token<'input> {
             Token::Then => "then",
             Token::Else => "else",
             Token::Forall => "forall",
+            Token::List => "List",
             Token::In => "in",
             Token::Let => "let",
+            Token::Rec => "rec",
             Token::Switch => "switch",
+            Token::Strict => "strict",
 
             Token::True => "true",
             Token::False => "false",
@@ -179,12 +225,16 @@ impl<'input> fmt::Display for Token<'input> {
             Token::SemiCol => ";",
             Token::Dot => ".",
             Token::DotDollar => ".$",
+            Token::DotDollarQuestion => ".$?",
             Token::DollarBracket => "$[",
             Token::DollarBrace => "${",
             Token::MinusDollar => "-$",
             Token::DollarEquals => "$=",
+            Token::AmpEquals => "&=",
             Token::Fun => "fun",
             Token::Import => "import",
+            Token::Pick => "pick",
+            Token::External => "%external%",
             Token::Pipe => "|",
             Token::SimpleArrow => "->",
             Token::DoubleArrow => "=>",
@@ -208,26 +258,54 @@ impl<'input> fmt::Display for Token<'input> {
             Token::IsFun => "isFun",
             Token::IsList => "isList",
             Token::IsRecord => "isRecord",
+            Token::TypeOf => "typeOf",
             Token::Blame => "blame",
             Token::ChangePol => "chngPol",
             Token::Polarity => "polarity",
             Token::GoDom => "goDom",
             Token::GoCodom => "goCodom",
+            Token::LabelTag => "labelTag",
+            Token::LabelPath => "labelPath",
+            Token::MkLabel => "mkLabel",
             Token::Wrap => "wrap",
+            Token::FreshSym => "freshSym",
             Token::Unwrap => "unwrap",
             Token::Embed => "embed",
             Token::MapRec => "mapRec",
+            Token::CatchBlame => "catchBlame",
             Token::Seq => "seq",
             Token::DeepSeq => "deepSeq",
             Token::Head => "head",
             Token::Tail => "tail",
             Token::Length => "length",
+            Token::ListUnique => "listUnique",
+            Token::ListZipWith => "listZipWith",
+            Token::ListRange => "listRange",
+            Token::Iterate => "iterate",
             Token::FieldsOf => "fieldsOf",
+            Token::FieldsWithMetadata => "fieldsWithMetadata",
+            Token::RecordRenameField => "recordRenameField",
+            Token::EnumToStr => "enumToStr",
+            Token::EnumFromStr => "enumFromStr",
+            Token::PathBasename => "pathBasename",
+            Token::PathExtension => "pathExtension",
 
             Token::HasField => "hasField",
+            Token::WithArgPos => "withArgPos",
+            Token::WithTag => "withTag",
+            Token::RecordGetPath => "recordGetPath",
+            Token::RecordIntersect => "recordIntersect",
+            Token::RecordFindFields => "recordFindFields",
+            Token::RecordDifference => "recordDifference",
             Token::Map => "map",
             Token::ElemAt => "elemAt",
+            Token::ListZip => "listZip",
+            Token::ListGroupBy => "listGroupBy",
+            Token::ListPartition => "listPartition",
+            Token::ListSort => "listSort",
+            Token::ListSortBy => "listSortBy",
             Token::Merge => "merge",
+            Token::PathJoin => "pathJoin",
 
             Token::LBrace => "{",
             Token::RBrace => "}",
@@ -254,10 +332,18 @@ pub struct Lexer<'input> {
     mode: Mode,
 }
 
+/// The byte order mark, which some editors write at the beginning of UTF-8 files.
+pub const BOM: char = '\u{feff}';
+
 impl<'input> Lexer<'input> {
+    /// Create a lexer for `input`. A leading byte order mark is skipped, such that the positions of
+    /// the tokens are still the byte offsets in the original input.
     pub fn new(input: &'input str) -> Self {
         let mut chars = input.char_indices();
-        let look_ahead = chars.next();
+        let look_ahead = match chars.next() {
+            Some((_, BOM)) => chars.next(),
+            first => first,
+        };
         Lexer {
             input,
             chars,
@@ -314,6 +400,15 @@ fn is_digit(chr: char) -> bool {
     }
 }
 
+// Underscores can be used to separate the digits of a number literal
+fn is_digit_or_sep(chr: char) -> bool {
+    is_digit(chr) || chr == '_'
+}
+
+/// The largest integer `n` such that every integer between `0` and `n` is exactly representable
+/// as a 64 bits float.
+const MAX_EXACT_INT: u64 = 1 << 53;
+
 fn escape_char(chr: char) -> Option<char> {
     match chr {
         '\'' => Some('\''),
@@ -391,6 +486,14 @@ impl<'input> Iterator for Lexer<'input> {
                         Ok((index, Token::RBrace, index + 1))
                     }
                 }
+                '&' => match self.look_ahead {
+                    Some((_, '=')) => {
+                        self.consume();
+                        Ok((index, Token::AmpEquals, index + 2))
+                    }
+                    _ => Err(LexicalError::UnexpectedChar(index)),
+                },
+                '%' => self.percent_keyword(index),
                 '[' => Ok((index, Token::LBracket, index + 1)),
                 ']' => Ok((index, Token::RBracket, index + 1)),
                 '(' => Ok((index, Token::LParen, index + 1)),
@@ -472,11 +575,7 @@ impl<'input> Lexer<'input> {
     where
         F: Fn(char) -> bool,
     {
-        let mut end = start;
-
         while let Some((index, chr)) = self.look_ahead {
-            end = index;
-
             if pred(chr) {
                 self.consume();
             } else {
@@ -484,7 +583,8 @@ impl<'input> Lexer<'input> {
             }
         }
 
-        end += 1;
+        // We reached the end of the input: the slice extends until the last character.
+        let end = self.input.len();
         (end, &self.input[start..end])
     }
 
@@ -503,10 +603,13 @@ impl<'input> Lexer<'input> {
             "forall" => Token::Forall,
             "in" => Token::In,
             "let" => Token::Let,
+            "rec" => Token::Rec,
             "switch" => Token::Switch,
+            "strict" => Token::Strict,
             "tag" => Token::Tag,
             "fun" => Token::Fun,
             "import" => Token::Import,
+            "pick" => Token::Pick,
             "true" => Token::True,
             "false" => Token::False,
             "Assume" if is_next_lparen => {
@@ -546,41 +649,122 @@ impl<'input> Lexer<'input> {
             "isFun" => Token::IsFun,
             "isList" => Token::IsList,
             "isRecord" => Token::IsRecord,
+            "typeOf" => Token::TypeOf,
             "blame" => Token::Blame,
             "chngPol" => Token::ChangePol,
             "polarity" => Token::Polarity,
             "goDom" => Token::GoDom,
             "goCodom" => Token::GoCodom,
+            "labelTag" => Token::LabelTag,
+            "labelPath" => Token::LabelPath,
+            "mkLabel" => Token::MkLabel,
             "wrap" => Token::Wrap,
+            "freshSym" => Token::FreshSym,
             "embed" => Token::Embed,
             "mapRec" => Token::MapRec,
+            "catchBlame" => Token::CatchBlame,
             "seq" => Token::Seq,
             "deepSeq" => Token::DeepSeq,
             "head" => Token::Head,
             "tail" => Token::Tail,
             "length" => Token::Length,
+            "listUnique" => Token::ListUnique,
+            "listZipWith" => Token::ListZipWith,
+            "listRange" => Token::ListRange,
+            "iterate" => Token::Iterate,
             "unwrap" => Token::Unwrap,
             "hasField" => Token::HasField,
+            "withArgPos" => Token::WithArgPos,
+            "withTag" => Token::WithTag,
+            "recordGetPath" => Token::RecordGetPath,
+            "recordIntersect" => Token::RecordIntersect,
+            "recordFindFields" => Token::RecordFindFields,
+            "recordDifference" => Token::RecordDifference,
             "map" => Token::Map,
             "elemAt" => Token::ElemAt,
+            "listZip" => Token::ListZip,
+            "listGroupBy" => Token::ListGroupBy,
+            "listPartition" => Token::ListPartition,
+            "listSort" => Token::ListSort,
+            "listSortBy" => Token::ListSortBy,
             "merge" => Token::Merge,
             "fieldsOf" => Token::FieldsOf,
-            ty @ "Dyn" | ty @ "Num" | ty @ "Bool" | ty @ "Str" | ty @ "List" => Token::Type(ty),
+            "fieldsWithMetadata" => Token::FieldsWithMetadata,
+            "recordRenameField" => Token::RecordRenameField,
+            "enumToStr" => Token::EnumToStr,
+            "enumFromStr" => Token::EnumFromStr,
+            "pathBasename" => Token::PathBasename,
+            "pathExtension" => Token::PathExtension,
+            "pathJoin" => Token::PathJoin,
+            ty @ "Dyn" | ty @ "Num" | ty @ "Bool" | ty @ "Str" => Token::Type(ty),
+            "List" => Token::List,
             id => Token::Identifier(id),
         };
 
         Ok((start, token, end))
     }
 
+    /// Try to lex the next token as a keyword enclosed in percent signs, such as `%external%`.
+    ///
+    /// Contrary to the other keywords, these do not reserve an identifier.
+    pub fn percent_keyword(&mut self, start: usize) -> Result<Spanned<'input>, LexicalError> {
+        let (end, slice) = self.take_while(start + 1, is_ident_char);
+
+        if !self.look_ahead_is('%') {
+            return Err(LexicalError::UnexpectedChar(start));
+        }
+        self.consume();
+
+        match slice {
+            "external" => Ok((start, Token::External, end + 1)),
+            _ => Err(LexicalError::UnexpectedChar(start)),
+        }
+    }
+
     /// Try to lex the next token as a number literal.
+    ///
+    /// Besides decimal literals, such as `-1_000.5`, hexadecimal (`0xff`), octal (`0o755`) and
+    /// binary (`0b1010`) integer literals are supported. Digits can be separated by underscores.
     pub fn num_literal(&mut self, start: usize) -> Result<Spanned<'input>, LexicalError> {
-        let (end, num) = self.take_while(start, is_digit);
+        // The first character, which is either a digit or a heading '-', has already been
+        // consumed.
+        let negative = self.input[start..].starts_with('-');
+        let first = if negative {
+            self.consume().map(|(index, _)| index).unwrap()
+        } else {
+            start
+        };
+
+        let radix = match self.look_ahead {
+            Some((_, 'x')) if self.input[first..].starts_with('0') => Some(16),
+            Some((_, 'o')) if self.input[first..].starts_with('0') => Some(8),
+            Some((_, 'b')) if self.input[first..].starts_with('0') => Some(2),
+            _ => None,
+        };
+
+        if let Some(radix) = radix {
+            self.consume();
+            let (end, digits) = self.take_while(first + 2, is_ident_char);
+            let value = u64::from_str_radix(&digits.replace('_', ""), radix)
+                .ok()
+                .filter(|n| *n <= MAX_EXACT_INT)
+                .ok_or(LexicalError::InvalidNumLiteral(start, end))?;
+            let value = value as f64;
+
+            return Ok((
+                start,
+                Token::NumLiteral(if negative { -value } else { value }),
+                end,
+            ));
+        }
+
+        let (end, num) = self.take_while(start, is_digit_or_sep);
 
         // Take the fractional part into account, if there is one
         let (end, num) = match self.look_ahead {
             Some((_, '.')) => {
                 self.consume();
-                self.take_while(start, is_digit)
+                self.take_while(start, is_digit_or_sep)
             }
             _ => (end, num),
         };
@@ -588,7 +772,11 @@ impl<'input> Lexer<'input> {
         match self.look_ahead {
             // Number literals must not be followed directly by an identifier character
             Some((index, chr)) if is_ident_char(chr) => Err(LexicalError::NumThenIdent(index)),
-            _ => Ok((start, Token::NumLiteral(num.parse().unwrap()), end)),
+            _ => Ok((
+                start,
+                Token::NumLiteral(num.replace('_', "").parse().unwrap()),
+                end,
+            )),
         }
     }
 
@@ -603,7 +791,14 @@ impl<'input> Lexer<'input> {
             "." if self.look_ahead_is('$') => {
                 self.consume();
                 end += 1;
-                Token::DotDollar
+
+                if self.look_ahead_is('?') {
+                    self.consume();
+                    end += 1;
+                    Token::DotDollarQuestion
+                } else {
+                    Token::DotDollar
+                }
             }
             "." => Token::Dot,
             "-" if self.look_ahead_is('$') => {
@@ -634,7 +829,7 @@ impl<'input> Lexer<'input> {
             }
 
             if let Some((index, chr)) = self.consume() {
-                eof = index + 1;
+                eof = index + chr.len_utf8();
                 match chr {
                     '\\' => {
                         let (i, c) = self.consume().ok_or(LexicalError::UnexpectedEOF(vec![
@@ -669,6 +864,9 @@ impl<'input> Lexer<'input> {
                             acc.push('$');
                         }
                     }
+                    // Line endings are normalized, such that the value of a string does not
+                    // depend on the convention of the platform where the file was edited.
+                    '\r' if self.look_ahead_is('\n') => (),
                     chr => acc.push(chr),
                 }
             } else {
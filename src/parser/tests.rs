@@ -1,7 +1,7 @@
 use super::lexer::{Lexer, LexicalError, Token};
 use crate::identifier::Ident;
 use crate::term::Term::*;
-use crate::term::{BinaryOp, RichTerm, UnaryOp};
+use crate::term::{BinaryOp, Pattern, RichTerm, UnaryOp};
 use codespan::Files;
 
 fn parse(s: &str) -> Option<RichTerm> {
@@ -33,6 +33,26 @@ fn numbers() {
     assert_eq!(parse_without_pos("22.22"), Num(22.22).into());
     assert_eq!(parse_without_pos("(22)"), Num(22.0).into());
     assert_eq!(parse_without_pos("((22))"), Num(22.0).into());
+    assert_eq!(parse_without_pos("1_000_000"), Num(1000000.0).into());
+    assert_eq!(parse_without_pos("0.000_1"), Num(0.0001).into());
+    assert_eq!(parse_without_pos("0xff"), Num(255.0).into());
+    assert_eq!(parse_without_pos("0xFF_FF"), Num(65535.0).into());
+    assert_eq!(parse_without_pos("0o755"), Num(493.0).into());
+    assert_eq!(parse_without_pos("0b1010"), Num(10.0).into());
+    assert_eq!(parse_without_pos("-0x10"), Num(-16.0).into());
+    assert_eq!(
+        parse_without_pos("0x20_0000_0000_0000"),
+        Num(9007199254740992.0).into()
+    );
+
+    assert_eq!(
+        lex("0x20_0000_0000_0001"),
+        Err(LexicalError::InvalidNumLiteral(0, 19))
+    );
+    assert_eq!(lex("0x"), Err(LexicalError::InvalidNumLiteral(0, 2)));
+    assert_eq!(lex("0b102"), Err(LexicalError::InvalidNumLiteral(0, 5)));
+    assert_eq!(lex("0o8"), Err(LexicalError::InvalidNumLiteral(0, 3)));
+    assert_eq!(lex("0z1"), Err(LexicalError::NumThenIdent(1)));
 }
 
 #[test]
@@ -145,6 +165,62 @@ fn lets() {
     assert!(parse("x (let x1 = x2 in x3) y").is_some());
 }
 
+#[test]
+fn patterns() {
+    let var = |id: &str| Pattern::Var(Ident::from(id));
+    let pat = Pattern::Record(vec![
+        (Ident::from("a"), var("a")),
+        (Ident::from("b"), Pattern::List(vec![var("x"), var("y")])),
+    ]);
+    assert_eq!(
+        parse_without_pos("let {a, b = [x, y]} = r in a"),
+        LetPattern(pat, RichTerm::var("r".into()), RichTerm::var("a".into())).into(),
+    );
+    assert_eq!(
+        parse_without_pos("let x = r in x"),
+        Let(
+            Ident::from("x"),
+            RichTerm::var("r".into()),
+            RichTerm::var("x".into())
+        )
+        .into(),
+    );
+
+    match *parse_without_pos("fun x {y,} [] => x").term {
+        Fun(x, body) => match *body.term {
+            Fun(arg, body) => match *body.term {
+                LetPattern(Pattern::Record(fields), bound, _) => {
+                    assert_eq!(x, Ident::from("x"));
+                    assert_eq!(fields, vec![(Ident::from("y"), var("y"))]);
+                    assert_eq!(bound, RichTerm::var(arg.0));
+                }
+                t => panic!("expected a pattern, got {:?}", t),
+            },
+            t => panic!("expected a function, got {:?}", t),
+        },
+        t => panic!("expected a function, got {:?}", t),
+    }
+
+    assert!(parse("let {a = } = r in a").is_none());
+    assert!(parse("fun {a.b} => a").is_none());
+}
+
+#[test]
+fn import_contracts() {
+    let is_checked_import = |s: &str| match *parse_without_pos(s).term {
+        Assume(_, _, t) => *t.term == Import(String::from("a.json")),
+        _ => false,
+    };
+
+    assert!(is_checked_import("import \"a.json\" | Schema"));
+    assert!(is_checked_import("import \"a.json\" | ({ {| a : Num |} })"));
+    assert!(parse("let x = import \"a.json\" | Schema in x").is_some());
+    assert!(parse("{a = import \"a.json\" | Num; b = 1}").is_some());
+    // Not allowed in the body of a function, which would be ambiguous inside a row type.
+    assert!(parse("fun x => import \"a.json\" | Schema").is_none());
+    assert!(parse("Promise({| a : #(import \"a.json\") |}, {a = 1})").is_some());
+}
+
 #[test]
 fn unary_op() {
     assert_eq!(
@@ -226,6 +302,58 @@ fn record_terms() {
     );
 }
 
+#[test]
+fn dyn_access() {
+    assert_eq!(
+        parse_without_pos("r.$x"),
+        Op2(
+            BinaryOp::DynAccess(),
+            RichTerm::var(String::from("x")),
+            RichTerm::var(String::from("r"))
+        )
+        .into()
+    );
+    assert_eq!(
+        parse_without_pos("r.$?x"),
+        Op2(
+            BinaryOp::DynAccessOpt(),
+            RichTerm::var(String::from("x")),
+            RichTerm::var(String::from("r"))
+        )
+        .into()
+    );
+}
+
+#[test]
+fn duplicate_fields() {
+    let mut files = Files::new();
+    let id = files.add("<test>", String::from("{a = 1; b = 2; a = 3}"));
+    let result = super::grammar::TermParser::new().parse(id, Lexer::new("{a = 1; b = 2; a = 3}"));
+    match result {
+        Err(lalrpop_util::ParseError::User {
+            error: LexicalError::DuplicateField(Ident(ref field), (1, 2), (15, 16)),
+        }) if field == "a" => (),
+        res => panic!("expected a duplicate field error, got {:?}", res),
+    };
+
+    assert_eq!(
+        parse_without_pos("{a = 1; a &= 2; b &= 3}"),
+        RecRecord(
+            vec![
+                (
+                    Ident("a".to_string()),
+                    Op2(BinaryOp::Merge(), Num(1.).into(), Num(2.).into()).into()
+                ),
+                (Ident("b".to_string()), Num(3.).into()),
+            ]
+            .into_iter()
+            .collect()
+        )
+        .into()
+    );
+    assert!(parse("{a &= 1; a = 2; a = 3}").is_none());
+}
+
 #[test]
 fn string_lexing() {
     assert_eq!(
@@ -292,3 +420,65 @@ fn str_escape() {
         Str(String::from("$${ }$")).into(),
     );
 }
+
+#[test]
+fn bom_and_line_endings() {
+    assert_eq!(
+        lex("\u{feff}x + 1"),
+        Ok(vec![
+            (3, Token::Identifier("x"), 4),
+            (5, Token::BinaryOp("+"), 6),
+            (7, Token::NumLiteral(1.0), 8),
+        ])
+    );
+    assert_eq!(
+        parse_without_pos("\"a\r\nb\nc\rd\\r\n\""),
+        Str(String::from("a\nb\nc\rd\r\n")).into(),
+    );
+}
+
+#[test]
+fn unknown_operators() {
+    assert!(parse("1 - 2").is_none());
+    assert!(parse("1 +- 2").is_none());
+    assert!(parse("true ==> false").is_none());
+    assert_eq!(
+        lex("1 - 2"),
+        Ok(vec![
+            (0, Token::NumLiteral(1.0), 1),
+            (2, Token::BinaryOp("-"), 3),
+            (4, Token::NumLiteral(2.0), 5),
+        ])
+    );
+}
+
+#[test]
+fn eof_spans() {
+    assert_eq!(lex("10."), Ok(vec![(0, Token::NumLiteral(10.0), 3)]));
+    assert_eq!(
+        lex("\"é"),
+        Ok(vec![
+            (0, Token::DoubleQuote, 1),
+            (1, Token::StrLiteral(String::from("é")), 3)
+        ])
+    );
+}
+
+#[test]
+fn externals() {
+    assert_eq!(
+        parse_without_pos("%external% \"port\""),
+        External(String::from("port")).into()
+    );
+    assert_eq!(lex("%external%"), Ok(vec![(0, Token::External, 10)]));
+
+    // `external` is not reserved, and can be used as an identifier or a field name
+    assert_eq!(
+        parse_without_pos("external"),
+        Var(Ident::from("external")).into()
+    );
+    assert!(parse("{ external = 1; }.external").is_some());
+
+    assert_eq!(lex("%foo%"), Err(LexicalError::UnexpectedChar(0)));
+    assert_eq!(lex("%external"), Err(LexicalError::UnexpectedChar(0)));
+}
@@ -1,8 +1,13 @@
 /// A few helpers to generate position spans and labels easily during parsing
+use super::lexer::LexicalError;
+use crate::collections::HashMap;
+use crate::identifier::Ident;
 use crate::label::Label;
 use crate::position::RawSpan;
+use crate::term::{BinaryOp, MetaValue, Pattern, RecordData, RichTerm, Term, UnaryOp};
 use crate::types::Types;
 use codespan::FileId;
+use std::collections::hash_map::Entry;
 
 /// Make a span from parser byte offsets.
 pub fn mk_span(src_id: FileId, l: usize, r: usize) -> RawSpan {
@@ -21,5 +26,128 @@ pub fn mk_label(types: Types, src_id: FileId, l: usize, r: usize) -> Label {
         span: mk_span(src_id, l, r),
         polarity: true,
         path: Vec::new(),
+        arg_pos: None,
     }
 }
+
+/// Attach metadata to a term. If the term is already a meta value, as in `Default(Docstring("a",
+/// 1))`, its metadata are updated instead, such that nested annotations give a single meta value.
+pub fn with_meta<F>(t: RichTerm, update: F) -> RichTerm
+where
+    F: FnOnce(&mut MetaValue),
+{
+    let RichTerm { term, pos, id, .. } = t;
+    match *term {
+        Term::MetaValue(mut meta) => {
+            update(&mut meta);
+            RichTerm {
+                term: Box::new(Term::MetaValue(meta)),
+                pos,
+                id,
+                free_vars: None,
+            }
+        }
+        term => {
+            let mut meta = MetaValue::from_value(RichTerm {
+                term: Box::new(term),
+                pos,
+                id,
+                free_vars: None,
+            });
+            update(&mut meta);
+            RichTerm::new(Term::MetaValue(meta))
+        }
+    }
+}
+
+/// A definition of a static field in a record literal.
+pub struct FieldDef {
+    pub id: Ident,
+    pub value: RichTerm,
+    /// The offsets of the field name.
+    pub span: (usize, usize),
+    /// If the field was defined with `&=`, in which case the value is merged with the other
+    /// definitions of the same field.
+    pub merge: bool,
+    /// If the field was marked as `strict`, in which case it is evaluated as soon as the record is.
+    pub strict: bool,
+}
+
+/// Force the evaluation of the given fields of a record as soon as the record itself is evaluated,
+/// instead of when they are accessed.
+///
+/// `{strict a = t; b = u}` is rewritten to `let %strict = {a = t; b = u} in seq %strict.a %strict`.
+/// The name of the variable can not clash with user variables, which can not contain `%`.
+pub fn force_fields(record: RichTerm, fields: Vec<Ident>) -> RichTerm {
+    if fields.is_empty() {
+        return record;
+    }
+
+    let var = Ident(String::from("%strict"));
+    let body = fields
+        .into_iter()
+        .rev()
+        .fold(RichTerm::var(var.0.clone()), |acc, id| {
+            let access = Term::Op1(UnaryOp::StaticAccess(id), RichTerm::var(var.0.clone()));
+            RichTerm::app(Term::Op1(UnaryOp::Seq(), access.into()).into(), acc)
+        });
+
+    Term::Let(var, record, body).into()
+}
+
+/// Build a function whose argument is matched against a pattern.
+///
+/// `fun {a, b} => body` is rewritten to `fun %arg => let {a, b} = %arg in body`. As for
+/// [`force_fields`](fn.force_fields.html), the name of the variable can not clash with user
+/// variables. The nested functions of `fun {a} {b} => body` all use this name, which is shadowed
+/// once the previous argument has been destructured.
+pub fn mk_fun(pat: Pattern, body: RichTerm) -> Term {
+    match pat {
+        Pattern::Var(id) => Term::Fun(id, body),
+        pat => {
+            let var = Ident(String::from("%arg"));
+            let arg = RichTerm::var(var.0.clone());
+            Term::Fun(var, Term::LetPattern(pat, arg, body).into())
+        }
+    }
+}
+
+/// Build the bindings of the static fields of a record literal.
+///
+/// A field can be defined at most once with `=`. Definitions introduced by `&=` are merged, in
+/// order, with the other definitions of the same field, such that `{a = x; a &= y}` is the same as
+/// `{a = merge x y}`. Another definition with `=` is an error, reporting both definitions.
+pub fn mk_record(defs: Vec<FieldDef>) -> Result<RecordData, LexicalError> {
+    // For each field, its value and the span of its definition using `=`, if any.
+    let mut fields: HashMap<Ident, (RichTerm, Option<(usize, usize)>)> = HashMap::default();
+
+    for def in defs {
+        let span = if def.merge { None } else { Some(def.span) };
+
+        match fields.entry(def.id) {
+            Entry::Vacant(entry) => {
+                entry.insert((def.value, span));
+            }
+            Entry::Occupied(mut entry) => {
+                let (prev, prev_span) = entry.get_mut();
+
+                if let (Some(first), Some(second)) = (*prev_span, span) {
+                    return Err(LexicalError::DuplicateField(
+                        entry.key().clone(),
+                        first,
+                        second,
+                    ));
+                }
+
+                let merged = Term::Op2(BinaryOp::Merge(), prev.clone(), def.value);
+                *prev = merged.into();
+                *prev_span = prev_span.or(span);
+            }
+        }
+    }
+
+    Ok(fields
+        .into_iter()
+        .map(|(id, (value, _))| (id, value))
+        .collect())
+}
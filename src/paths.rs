@@ -0,0 +1,193 @@
+//! Normalization of the paths of imported files.
+//!
+//! The import resolver identifies a file by the normalized form of its path, which is also the
+//! name under which the file is reported in diagnostics. Normalization is lexical: it does not
+//! access the file system, and is thus implemented for the conventions of both Unix and Windows,
+//! whatever the host platform is. On Windows, it handles:
+//!
+//! - both `\` and `/` as separators, the former being used in the normalized path,
+//! - drive letters, as in `C:\dir`, normalized to upper case,
+//! - UNC paths, as in `\\server\share\dir`,
+//! - verbatim paths, as in `\\?\C:\dir` or `\\?\UNC\server\share\dir`, which are returned by the
+//!   canonicalization of a path. They are converted back to their usual form, which is more
+//!   readable and to which relative paths using `/` can be appended,
+//! - case-insensitive file systems: the key identifying a file is case folded (see
+//!   [`cache_key`](fn.cache_key.html)).
+
+/// The conventions of a file system for paths.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathStyle {
+    Unix,
+    Windows,
+}
+
+impl PathStyle {
+    /// The conventions of the host platform.
+    pub fn host() -> PathStyle {
+        if cfg!(windows) {
+            PathStyle::Windows
+        } else {
+            PathStyle::Unix
+        }
+    }
+
+    fn is_separator(&self, c: char) -> bool {
+        match self {
+            PathStyle::Unix => c == '/',
+            PathStyle::Windows => c == '/' || c == '\\',
+        }
+    }
+
+    fn separator(&self) -> &'static str {
+        match self {
+            PathStyle::Unix => "/",
+            PathStyle::Windows => "\\",
+        }
+    }
+}
+
+/// Split a path in its prefix, that is the drive letter or the server and share of a UNC path on
+/// Windows, whether it has a root, and the rest of the path.
+fn split_prefix(path: &str, style: PathStyle) -> (String, bool, &str) {
+    if style == PathStyle::Unix {
+        return match strip_prefix(path, "/") {
+            Some(rest) => (String::new(), true, rest),
+            None => (String::new(), false, path),
+        };
+    }
+
+    // Verbatim paths: `\\?\C:\dir` or `\\?\UNC\server\share\dir`.
+    let path = match strip_prefix(path, r"\\?\") {
+        Some(rest) => match strip_prefix(rest, r"UNC\") {
+            Some(unc) => return split_unc(unc, style),
+            None => rest,
+        },
+        None => path,
+    };
+
+    let mut chars = path.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(c1), Some(c2), _) if style.is_separator(c1) && style.is_separator(c2) => {
+            split_unc(&path[2..], style)
+        }
+        (Some(letter), Some(':'), sep) if letter.is_ascii_alphabetic() => {
+            let drive = format!("{}:", letter.to_ascii_uppercase());
+            match sep {
+                Some(c) if style.is_separator(c) => (drive, true, &path[3..]),
+                _ => (drive, false, &path[2..]),
+            }
+        }
+        (Some(c), _, _) if style.is_separator(c) => (String::new(), true, &path[1..]),
+        _ => (String::new(), false, path),
+    }
+}
+
+/// Return the rest of `path` if it starts with `prefix`, as `str::strip_prefix`, which is not
+/// available in the version of Rust supported.
+fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    if path.starts_with(prefix) {
+        Some(&path[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+/// Split the rest of a UNC path, after the leading `\\`, in its prefix and the rest of the path.
+fn split_unc(path: &str, style: PathStyle) -> (String, bool, &str) {
+    let mut parts = path.splitn(3, |c| style.is_separator(c));
+    let server = parts.next().unwrap_or("");
+    let share = parts.next().unwrap_or("");
+    let rest = parts.next().unwrap_or("");
+
+    (format!(r"\\{}\{}", server, share), true, rest)
+}
+
+/// Normalize a path lexically, without accessing the file system.
+///
+/// Remove `.` components and cancel out `..` components with the preceding one. Contrary to
+/// canonicalization, symbolic links are not followed: `a/link/../b` may not point to `a/b`.
+/// On Windows, the result uses `\` as a separator (see the [module documentation](index.html)).
+pub fn normalize(path: &str, style: PathStyle) -> String {
+    let (prefix, has_root, rest) = split_prefix(path, style);
+    let mut components: Vec<&str> = Vec::new();
+
+    for component in rest.split(|c| style.is_separator(c)) {
+        match component {
+            "" | "." => (),
+            ".." => match components.last() {
+                Some(&last) if last != ".." => {
+                    components.pop();
+                }
+                // `..` cancels out at the root.
+                _ if has_root => (),
+                _ => components.push(".."),
+            },
+            component => components.push(component),
+        }
+    }
+
+    let sep = style.separator();
+    let mut result = prefix;
+    if has_root {
+        result.push_str(sep);
+    }
+    result.push_str(&components.join(sep));
+
+    if result.is_empty() {
+        result.push('.');
+    }
+
+    result
+}
+
+/// The key identifying a file, given its normalized path. Paths are case-insensitive on Windows,
+/// so the key is case folded there.
+pub fn cache_key(normalized: &str, style: PathStyle) -> String {
+    match style {
+        PathStyle::Unix => String::from(normalized),
+        PathStyle::Windows => normalized.to_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix() {
+        let norm = |path| normalize(path, PathStyle::Unix);
+
+        assert_eq!(norm("a/./b//c/../d"), "a/b/d");
+        assert_eq!(norm("/a/../../b"), "/b");
+        assert_eq!(norm("../a/../../b"), "../../b");
+        assert_eq!(norm("a/.."), ".");
+        assert_eq!(norm(r"a\b"), r"a\b");
+        assert_eq!(cache_key("/A/b", PathStyle::Unix), "/A/b");
+    }
+
+    #[test]
+    fn windows() {
+        let norm = |path| normalize(path, PathStyle::Windows);
+
+        assert_eq!(norm(r"a\.\b/c\..\d"), r"a\b\d");
+        assert_eq!(norm(r"c:\dir\..\..\a.ncl"), r"C:\a.ncl");
+        assert_eq!(norm("C:/dir/sub/../a.ncl"), r"C:\dir\a.ncl");
+        assert_eq!(norm(r"C:dir\a.ncl"), r"C:dir\a.ncl");
+        assert_eq!(norm(r"\dir\a.ncl"), r"\dir\a.ncl");
+        assert_eq!(
+            norm(r"\\server\share\dir\..\a.ncl"),
+            r"\\server\share\a.ncl"
+        );
+        assert_eq!(norm("//server/share/a.ncl"), r"\\server\share\a.ncl");
+
+        // Verbatim paths, as returned by canonicalization.
+        assert_eq!(norm(r"\\?\C:\dir\a.ncl"), r"C:\dir\a.ncl");
+        assert_eq!(norm(r"\\?\C:\dir/sub/../a.ncl"), r"C:\dir\a.ncl");
+        assert_eq!(norm(r"\\?\UNC\server\share\a.ncl"), r"\\server\share\a.ncl");
+
+        assert_eq!(
+            cache_key(&norm(r"c:\Dir\A.ncl"), PathStyle::Windows),
+            cache_key(&norm(r"C:\dir\a.NCL"), PathStyle::Windows)
+        );
+    }
+}
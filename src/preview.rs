@@ -0,0 +1,349 @@
+//! Bounded rendering of terms, for debugging.
+//!
+//! A preview renders any term, evaluated or not, in a syntax close to the one of the source, as
+//! `{ a = [1, 2]; b = fun x => x + 1 }`, without evaluating anything. The variables bound by the
+//! interpreter, such as the fields of an evaluated record, are replaced by the content of their
+//! thunk, whether it has already been evaluated or not, such that a preview shows what a value is
+//! made of. The variables of the program are rendered by name.
+//!
+//! Previews are meant for debugging interfaces, which cannot afford to display large values: the
+//! subterms nested deeper than a maximal depth are elided as `...`, and the rendering stops as
+//! soon as it reaches a maximal length, in characters. Truncation never splits a character.
+use crate::eval::{Closure, Environment, IdentKind};
+use crate::identifier::Ident;
+use crate::serialize::NumFormat;
+use crate::term::{BinaryOp, MergePriority, RichTerm, StrChunk, Term, UnaryOp};
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::rc::Rc;
+
+/// The maximal depth of the previews of the [`api`](../api/index.html) module.
+pub const DEFAULT_DEPTH: usize = 8;
+
+/// Render a term in at most `max_len` characters, eliding the subterms nested deeper than
+/// `max_depth`. A truncated rendering ends with `...`.
+pub fn preview(t: &Term, max_depth: usize, max_len: usize) -> String {
+    preview_in_env(t, &Environment::default(), max_depth, max_len)
+}
+
+/// Same as [`preview`](fn.preview.html), but for a closure: the variables bound by the interpreter
+/// in its environment are replaced by their content.
+pub fn preview_closure(clos: &Closure, max_depth: usize, max_len: usize) -> String {
+    preview_in_env(clos.body.as_ref(), &clos.env, max_depth, max_len)
+}
+
+fn preview_in_env(t: &Term, env: &Environment, max_depth: usize, max_len: usize) -> String {
+    let mut out = Preview {
+        buffer: String::new(),
+        // A character takes at least one byte: once the buffer holds more than `max_len` bytes,
+        // it may be long enough, and the rendering can stop.
+        limit: max_len + 1,
+    };
+    out.term(t, env, max_depth);
+    truncate(out.buffer, max_len)
+}
+
+/// Truncate a string to at most `max_len` characters, replacing the end by `...` if it is longer.
+pub fn truncate(s: String, max_len: usize) -> String {
+    match s.char_indices().nth(max_len) {
+        Some(_) => {
+            let keep = max_len.saturating_sub(3);
+            let end = s.char_indices().nth(keep).map_or(s.len(), |(i, _)| i);
+            format!("{}...", &s[..end])
+        }
+        None => s,
+    }
+}
+
+/// The state of a rendering.
+struct Preview {
+    buffer: String,
+    /// The length, in bytes, after which the rendering stops.
+    limit: usize,
+}
+
+impl Preview {
+    fn full(&self) -> bool {
+        self.buffer.len() >= self.limit
+    }
+
+    fn push(&mut self, s: &str) {
+        if !self.full() {
+            self.buffer.push_str(s);
+        }
+    }
+
+    /// Render a subterm, in parentheses if it is not atomic.
+    fn atom(&mut self, rt: &RichTerm, env: &Environment, depth: usize) {
+        let atomic = match rt.as_ref() {
+            Term::Var(id) => lookup(id, env).is_none(),
+            Term::Fun(_, _)
+            | Term::Let(_, _, _)
+            | Term::LetRec(_, _, _)
+            | Term::LetPattern(_, _, _)
+            | Term::App(_, _)
+            | Term::Op1(_, _)
+            | Term::Op2(_, _, _) => false,
+            _ => true,
+        };
+
+        if atomic {
+            self.term(rt.as_ref(), env, depth);
+        } else {
+            self.push("(");
+            self.term(rt.as_ref(), env, depth);
+            self.push(")");
+        }
+    }
+
+    fn term(&mut self, t: &Term, env: &Environment, depth: usize) {
+        if self.full() {
+            return;
+        }
+
+        match t {
+            Term::Var(id) => match lookup(id, env) {
+                Some(clos) => self.term(clos.body.as_ref(), &clos.env, depth),
+                None => self.push(&id.0),
+            },
+            Term::Bool(_) | Term::Num(_) | Term::Enum(_) | Term::Str(_) => {
+                self.push(&leaf(t));
+            }
+            _ if depth == 0 => self.push("..."),
+            Term::StrChunks(chunks) => {
+                self.push("\"");
+                // Chunks are stored in reverse order.
+                for chunk in chunks.iter().rev() {
+                    match chunk {
+                        StrChunk::Literal(s) => self.push(&escape(s)),
+                        StrChunk::Expr(rt) => {
+                            self.push("${");
+                            self.term(rt.as_ref(), env, depth - 1);
+                            self.push("}");
+                        }
+                    }
+                }
+                self.push("\"");
+            }
+            Term::Fun(Ident(x), body) => {
+                self.push(&format!("fun {} => ", x));
+                self.term(body.as_ref(), env, depth - 1);
+            }
+            // The bindings introduced by the program transformations are inlined.
+            Term::Let(id, bound, body) if id.0.starts_with('%') => {
+                let mut env = env.clone();
+                let thunk = Closure {
+                    body: bound.clone(),
+                    env: env.clone(),
+                };
+                env.insert(id.clone(), (Rc::new(RefCell::new(thunk)), IdentKind::Let()));
+                self.term(body.as_ref(), &env, depth);
+            }
+            Term::Let(Ident(x), bound, body) | Term::LetRec(Ident(x), bound, body) => {
+                let rec = if let Term::LetRec(..) = t { "rec " } else { "" };
+                self.push(&format!("let {}{} = ", rec, x));
+                self.term(bound.as_ref(), env, depth - 1);
+                self.push(" in ");
+                self.term(body.as_ref(), env, depth - 1);
+            }
+            Term::LetPattern(pat, bound, body) => {
+                self.push(&format!("let {} = ", pat));
+                self.term(bound.as_ref(), env, depth - 1);
+                self.push(" in ");
+                self.term(body.as_ref(), env, depth - 1);
+            }
+            Term::App(f, arg) => {
+                self.atom(f, env, depth - 1);
+                self.push(" ");
+                self.atom(arg, env, depth - 1);
+            }
+            Term::Record(fields) | Term::RecRecord(fields) => {
+                if fields.is_empty() {
+                    return self.push("{}");
+                }
+                self.push("{ ");
+                for (i, (Ident(id), rt)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        self.push("; ");
+                    }
+                    self.push(&format!("{} = ", id));
+                    self.term(rt.as_ref(), env, depth - 1);
+                }
+                self.push(" }");
+            }
+            Term::List(ts) => {
+                self.push("[");
+                for (i, rt) in ts.iter().enumerate() {
+                    if i > 0 {
+                        self.push(", ");
+                    }
+                    self.term(rt.as_ref(), env, depth - 1);
+                }
+                self.push("]");
+            }
+            Term::Op1(UnaryOp::StaticAccess(Ident(id)), rt) => {
+                self.atom(rt, env, depth - 1);
+                self.push(&format!(".{}", id));
+            }
+            Term::Op1(op, rt) => {
+                self.push(&op_name(op));
+                self.push(" ");
+                self.atom(rt, env, depth - 1);
+            }
+            Term::Op2(op, fst, snd) if infix(op).is_some() => {
+                self.atom(fst, env, depth - 1);
+                self.push(&format!(" {} ", infix(op).unwrap()));
+                self.atom(snd, env, depth - 1);
+            }
+            Term::Op2(op, fst, snd) => {
+                self.push(&op_name(op));
+                self.push(" ");
+                self.atom(fst, env, depth - 1);
+                self.push(" ");
+                self.atom(snd, env, depth - 1);
+            }
+            Term::Promise(ty, _, rt) | Term::Assume(ty, _, rt) => {
+                let name = if let Term::Promise(..) = t {
+                    "Promise"
+                } else {
+                    "Assume"
+                };
+                self.push(&format!("{}({}, ", name, ty));
+                self.term(rt.as_ref(), env, depth - 1);
+                self.push(")");
+            }
+            Term::MetaValue(meta) => match (&meta.value, meta.priority) {
+                (Some(rt), MergePriority::Default) => {
+                    self.push("Default(");
+                    self.term(rt.as_ref(), env, depth - 1);
+                    self.push(")");
+                }
+                (Some(rt), MergePriority::Normal) => self.term(rt.as_ref(), env, depth),
+                (None, _) => self.push("<no value>"),
+            },
+            Term::Wrapped(_, _) | Term::Lbl(_) | Term::Sym(_) | Term::External(_) => {
+                self.push(&t.shallow_repr())
+            }
+            Term::Import(path) => self.push(&format!("import {:?}", path)),
+            Term::ResolvedImport(_) => self.push("<import>"),
+        }
+    }
+}
+
+/// Get the content of a variable bound by the interpreter, whose name is generated. The thunk is
+/// skipped if it is being updated.
+fn lookup(id: &Ident, env: &Environment) -> Option<Closure> {
+    if !id.0.starts_with('%') {
+        return None;
+    }
+
+    let (thunk, _) = env.get(id)?;
+    let clos = thunk.try_borrow().ok()?;
+    Some(clos.clone())
+}
+
+/// Render a constant.
+fn leaf(t: &Term) -> String {
+    match t {
+        Term::Bool(b) => format!("{}", b),
+        Term::Num(n) => NumFormat::default().format(*n),
+        Term::Enum(Ident(tag)) => format!("`{}", tag),
+        Term::Str(s) => format!("\"{}\"", escape(s)),
+        t => t.shallow_repr(),
+    }
+}
+
+/// Escape the quotes, backslashes and control characters of a string literal.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => write!(escaped, "\\u{{{:x}}}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The symbol of an infix operator.
+fn infix(op: &BinaryOp<RichTerm>) -> Option<&'static str> {
+    match op {
+        BinaryOp::Plus() => Some("+"),
+        BinaryOp::PlusStr() => Some("++"),
+        BinaryOp::Eq() => Some("=="),
+        BinaryOp::ListConcat() => Some("@"),
+        _ => None,
+    }
+}
+
+/// The name of a primitive operator, as the name of its variant in camel case, such as `isZero`.
+fn op_name<Op: std::fmt::Debug>(op: &Op) -> String {
+    let repr = format!("{:?}", op);
+    let name = repr.split('(').next().unwrap_or("");
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::grammar::TermParser;
+    use crate::parser::lexer::Lexer;
+    use crate::term::RecordData;
+    use codespan::Files;
+
+    fn parse(s: &str) -> RichTerm {
+        let id = Files::new().add("<test>", String::from(s));
+        TermParser::new().parse(id, Lexer::new(s)).unwrap()
+    }
+
+    #[test]
+    fn terms() {
+        let t = parse("{ b = fun x => x + 1; a = [1, \"é\\\"\", `foo]; c = let y = f 2 in y.z }");
+        assert_eq!(
+            preview(t.as_ref(), 10, 100),
+            "{ a = [1, \"é\\\"\", `foo]; b = fun x => x + 1; c = let y = f 2 in y.z }"
+        );
+        assert_eq!(
+            preview(t.as_ref(), 2, 100),
+            "{ a = [1, \"é\\\"\", `foo]; b = fun x => ...; c = let y = ... in ... }"
+        );
+    }
+
+    #[test]
+    fn truncation() {
+        let t = Term::Str(String::from("ééééé"));
+        assert_eq!(preview(&t, 1, 7), "\"ééééé\"");
+        assert_eq!(preview(&t, 1, 6), "\"éé...");
+
+        let long = Term::List((0..1000).map(|i| Term::Num(i as f64).into()).collect());
+        assert_eq!(preview(&long, 2, 12), "[0, 1, 2,...");
+    }
+
+    #[test]
+    fn thunks() {
+        let mut env = Environment::default();
+        let thunk = Closure {
+            body: parse("1 + 1"),
+            env: Environment::default(),
+        };
+        env.insert(
+            Ident::from("%1"),
+            (Rc::new(RefCell::new(thunk)), IdentKind::Record()),
+        );
+        let mut fields = RecordData::new();
+        fields.insert(Ident::from("a"), RichTerm::var(String::from("%1")));
+        fields.insert(Ident::from("b"), RichTerm::var(String::from("x")));
+        let clos = Closure {
+            body: Term::Record(fields).into(),
+            env,
+        };
+        assert_eq!(preview_closure(&clos, 5, 50), "{ a = 1 + 1; b = x }");
+    }
+}
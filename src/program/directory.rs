@@ -0,0 +1,141 @@
+//! Import of directories.
+//!
+//! Importing a directory gives a record with a field for each Nickel, JSON or YAML file and for
+//! each subdirectory of the directory (see [`list_dir`](fn.list_dir.html)). The entries are
+//! imported lazily, when the corresponding field is evaluated.
+use super::{Program, ResolvedTerm};
+use crate::error::ImportError;
+use crate::identifier::Ident;
+use crate::position::RawSpan;
+use crate::serialize::InputFormat;
+use crate::term::{RecordData, RichTerm, Term};
+use codespan::FileId;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The name of the virtual file holding the record corresponding to an imported directory. Imports
+/// inside this record are resolved relatively to this file, that is inside the directory.
+pub(super) const DIR_INDEX: &str = "<directory>";
+
+/// List the content of a directory to import it as a record.
+///
+/// Each Nickel, JSON or YAML file of the directory is mapped to a field named after the file
+/// without its extension, and each subdirectory to a field of the same name. Other files are
+/// ignored. The content of a field is the import of the corresponding path, which is left
+/// unresolved: a file is only loaded when its field is evaluated, such that a malformed file only
+/// causes an error if it is used. Return the record together with the corresponding Nickel source,
+/// which is used for error reporting.
+pub(super) fn list_dir(path: &Path) -> Result<(RichTerm, String), String> {
+    let mut entries = fs::read_dir(path)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .map_err(|err| format!("{}", err))?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut fields = RecordData::new();
+    let mut listing = Vec::new();
+
+    for entry in entries {
+        let entry_path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        let (field, import) = if name.starts_with('.') {
+            continue;
+        } else if entry_path.is_dir() {
+            (name.clone(), format!("{}/", name))
+        } else if InputFormat::from_path(&entry_path).is_some() {
+            let stem = entry_path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            (stem, name)
+        } else {
+            continue;
+        };
+
+        // The import is resolved during the evaluation, without knowing the file it comes from,
+        // so it holds the full path of the entry.
+        let full_path = path.join(&import).to_string_lossy().into_owned();
+        listing.push(format!("  {} = import \"{}\";", field, import));
+        let prev = fields.insert(Ident(field.clone()), Term::Import(full_path).into());
+        if prev.is_some() {
+            return Err(format!(
+                "several entries of the directory would define the field {}",
+                field
+            ));
+        }
+    }
+
+    let source = format!("{{\n{}\n}}", listing.join("\n"));
+    Ok((Term::Record(fields).into(), source))
+}
+
+impl Program {
+    /// Resolve the import of a directory. See [`list_dir`](directory/fn.list_dir.html).
+    pub(super) fn resolve_dir(
+        &mut self,
+        path: &str,
+        path_buf: PathBuf,
+        normalized: String,
+        pos: &Option<RawSpan>,
+    ) -> Result<(ResolvedTerm, FileId), ImportError> {
+        let (t, source) = list_dir(&path_buf)
+            .map_err(|msg| ImportError::IOError(String::from(path), msg, pos.clone()))?;
+        let index = path_buf.join(DIR_INDEX);
+        let file_id = self.files.add(index.as_os_str(), source);
+        self.file_cache.insert(normalized, file_id);
+        self.record_file(&path_buf, file_id);
+
+        // The record is not transformed, such that the imports of the entries are left unresolved
+        // until they are evaluated.
+        self.term_cache.insert(file_id, t);
+        Ok((ResolvedTerm::FromCache(), file_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::eval_string;
+    use super::*;
+    use crate::error::{Error, EvalError, ParseError};
+
+    #[test]
+    fn import_dir() {
+        let dir = std::env::temp_dir().join(format!("nickel-import-dir-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.ncl"), "1 + (import \"b.json\").x").unwrap();
+        fs::write(dir.join("b.json"), "{\"x\": 2}").unwrap();
+        fs::write(dir.join("sub").join("c.yaml"), "y: [true]").unwrap();
+        fs::write(dir.join("sub").join("README"), "ignored").unwrap();
+        fs::write(dir.join("sub").join("d.ncl"), "import \"../b.json\"").unwrap();
+        let import = |s: &str| format!("(import \"{}/\"){}", dir.display(), s);
+
+        assert_eq!(eval_string(&import(".a")), Ok(Term::Num(3.)));
+        assert_eq!(
+            eval_string(&format!("head ({})", import(".sub.c.y"))),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(eval_string(&import(".sub.d.x")), Ok(Term::Num(2.)));
+        eval_string(&import(".sub.README")).unwrap_err();
+
+        fs::write(dir.join("b.yml"), "x: 3").unwrap();
+        match eval_string(&import(".b.x")) {
+            Err(Error::ImportError(ImportError::IOError(..))) => (),
+            err => panic!("expected an import error, got {:?}", err),
+        };
+        fs::remove_file(dir.join("b.yml")).unwrap();
+
+        // Files are only loaded when their field is accessed.
+        fs::write(dir.join("bad.json"), "{\"x\": ").unwrap();
+        assert_eq!(eval_string(&import(".a")), Ok(Term::Num(3.)));
+        match eval_string(&import(".bad.x")) {
+            Err(Error::EvalError(EvalError::ImportError(ImportError::ParseError(
+                ParseError::ForeignFormatError(..),
+                _,
+            )))) => (),
+            err => panic!("expected a parse error, got {:?}", err),
+        };
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
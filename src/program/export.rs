@@ -0,0 +1,385 @@
+//! Export of programs, possibly in parallel.
+//!
+//! An export evaluates the program and serializes the result (see the
+//! [`serialize`](../../serialize/index.html) module). The fields of the top-level record can be
+//! evaluated in several threads (see
+//! [`Program::set_export_jobs`](../struct.Program.html#method.set_export_jobs)). Terms are not
+//! thread-safe, so each thread rebuilds the program from its source and configuration, and
+//! serializes its share of the fields to strings, which the calling thread then writes in order.
+use super::{Program, TraceEvents, TraceSink};
+use crate::error::Error;
+use crate::serialize::{self, ExportOptions};
+use crate::typecheck::CancellationToken;
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+
+/// The stack size of the threads of a parallel export. The default size of spawned threads is
+/// smaller than the one of the main thread, and the parser and the typechecker are recursive.
+const WORKER_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// A function configuring the programs of the threads of a parallel export. See
+/// [`Program::set_worker_setup`](../struct.Program.html#method.set_worker_setup).
+pub(super) type WorkerSetup = Arc<dyn Fn(&mut Program) + Send + Sync>;
+
+/// A field serialized by a thread of a parallel export, together with the trace output of its
+/// evaluation, line by line.
+type RenderedField = (String, Vec<String>);
+
+/// What the threads of a parallel export need to rebuild a program. See
+/// [`Program::set_export_jobs`](../struct.Program.html#method.set_export_jobs).
+#[derive(Clone)]
+struct WorkerConfig {
+    source: String,
+    name: OsString,
+    canonicalize_imports: bool,
+    stack_limit: Option<usize>,
+    import_paths: Vec<PathBuf>,
+    inline_threshold: Option<usize>,
+    cancellation_token: Option<CancellationToken>,
+    setup: Option<WorkerSetup>,
+    /// Whether the trace output is passed as structured events to the calling thread.
+    trace_events: bool,
+}
+
+impl WorkerConfig {
+    /// Serialize the given fields of the program in `jobs` threads (see
+    /// [`serialize::render_field`](../../serialize/fn.render_field.html)). A thread which cannot
+    /// be started or which panics renders none of its fields.
+    fn render_fields(
+        &self,
+        fields: &[String],
+        jobs: usize,
+        options: &ExportOptions,
+    ) -> Vec<Option<RenderedField>> {
+        // The fields are interleaved, such that costly adjacent fields go to different threads.
+        let handles: Vec<_> = (0..jobs.min(fields.len()))
+            .map(|job| {
+                let fields: Vec<String> = fields.iter().skip(job).step_by(jobs).cloned().collect();
+                let config = self.clone();
+                let options = options.clone();
+                thread::Builder::new()
+                    .stack_size(WORKER_STACK_SIZE)
+                    .spawn(move || config.render(&fields, &options))
+            })
+            .collect();
+
+        let mut result = vec![None; fields.len()];
+        for (job, handle) in handles.into_iter().enumerate() {
+            if let Some(rendered) = handle.ok().and_then(|handle| handle.join().ok()) {
+                for (i, field) in rendered.into_iter().enumerate() {
+                    result[job + i * jobs] = field;
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Rebuild the program, whose trace output is collected in `traces`.
+    fn program(&self, traces: &Rc<RefCell<Vec<String>>>) -> Program {
+        let mut p = Program::new_from_source(self.source.as_bytes(), self.name.clone())
+            .expect("reading a string cannot fail");
+        p.canonicalize_imports = self.canonicalize_imports;
+        p.stack_limit = self.stack_limit;
+        p.import_paths = self.import_paths.clone();
+        p.inline_threshold = self.inline_threshold;
+        p.cancellation_token = self.cancellation_token.clone();
+        if let Some(setup) = &self.setup {
+            setup(&mut p);
+        }
+
+        let traces = Rc::clone(traces);
+        let collect = move |line: &str| traces.borrow_mut().push(String::from(line));
+        if self.trace_events {
+            p.set_trace_event_callback(collect);
+        } else {
+            p.set_trace_callback(collect);
+        }
+
+        p
+    }
+
+    /// Rebuild the program and serialize the given fields, together with their trace output.
+    fn render(&self, fields: &[String], options: &ExportOptions) -> Vec<Option<RenderedField>> {
+        let traces = Rc::new(RefCell::new(Vec::new()));
+        let mut p = self.program(&traces);
+        let stack_limit = p.stack_limit;
+
+        let record = p
+            .prepare_eval_with(None, false)
+            .ok()
+            .and_then(|(t, global_env)| {
+                serialize::eval_record(t, &global_env, &mut p, stack_limit, options)
+                    .map(|record| (record, global_env))
+            });
+        let (record, global_env) = match record {
+            Some(record) => record,
+            None => return vec![None; fields.len()],
+        };
+        // The calling thread already evaluated the record and wrote the corresponding traces.
+        traces.borrow_mut().clear();
+
+        fields
+            .iter()
+            .map(|id| {
+                let rendered =
+                    serialize::render_field(&record, id, &global_env, &mut p, stack_limit, options);
+                let traces = traces.replace(Vec::new());
+                rendered.map(|rendered| (rendered, traces))
+            })
+            .collect()
+    }
+}
+
+/// Write a line of trace output, which is passed as is to the callback of the trace events if
+/// they are enabled. Used to replay the traces of the threads of a parallel export, whose lines
+/// are already events in this case. A failure to write is ignored.
+fn write_trace_line(trace: &TraceSink, events: &RefCell<Option<TraceEvents>>, line: &str) {
+    match events.borrow().as_ref() {
+        Some(events) => {
+            let callback = &mut *events.callback.borrow_mut();
+            callback(line);
+        }
+        None => {
+            let _ =
+                io::Write::write_all(&mut *trace.borrow_mut(), format!("{}\n", line).as_bytes());
+        }
+    }
+}
+
+impl Program {
+    /// Set the number of threads used by [`export`](#method.export) to evaluate the fields of the
+    /// top-level record in parallel. The default is 1, that is no parallelism.
+    ///
+    /// Terms are not thread-safe, so each thread rebuilds the program from its source, and
+    /// evaluates a share of the fields. The output is the same as with one thread. The threads
+    /// only serialize the fields which succeed: the other ones are evaluated again by the calling
+    /// thread, which reports the error of the first of them in the order of the output, as a
+    /// sequential export would. The serialized fields are kept in memory until they are written.
+    ///
+    /// Each thread parses the program and evaluates the top-level record again, without
+    /// typechecking it, which only pays off when the fields are costly to evaluate compared to
+    /// the program itself. The threads get the settings of the program, and their trace output is
+    /// written to the trace output of the program in the order of the fields, the traces of the
+    /// evaluation of the top-level record being only written once. A value shared by fields
+    /// rendered by different threads is still evaluated, and traced, by each of them.
+    ///
+    /// Host functions and overrides cannot be moved to another thread: the export of a program
+    /// which has some is sequential, unless they are registered again in each thread by
+    /// [`set_worker_setup`](#method.set_worker_setup). The export is also sequential if the
+    /// program records a manifest.
+    pub fn set_export_jobs(&mut self, jobs: usize) {
+        self.export_jobs = jobs.max(1);
+    }
+
+    /// Set the function configuring the program rebuilt by each thread of a parallel export (see
+    /// [`set_export_jobs`](#method.set_export_jobs)), which is called before the program is
+    /// evaluated. It must register the same host functions and overrides as this program.
+    pub fn set_worker_setup<F>(&mut self, setup: F)
+    where
+        F: Fn(&mut Program) + Send + Sync + 'static,
+    {
+        self.worker_setup = Some(Arc::new(setup));
+    }
+
+    /// Evaluate the program and serialize the result in the format of the options, writing it to
+    /// `out` incrementally (see [`serialize::to_writer`](../serialize/fn.to_writer.html)).
+    pub fn export<W: io::Write>(
+        &mut self,
+        options: &ExportOptions,
+        out: &mut W,
+    ) -> Result<(), Error> {
+        let (t, global_env) = self.prepare_eval()?;
+        let stack_limit = self.stack_limit;
+        match self.worker_config() {
+            Some(config) if self.export_jobs > 1 => {
+                let jobs = self.export_jobs;
+                let trace = Rc::clone(&self.trace);
+                let events = Rc::clone(&self.trace_events);
+                // The traces of the threads are written in the order of the fields, as a
+                // sequential export would.
+                let render = |fields: &[String]| {
+                    let rendered = config.render_fields(fields, jobs, options);
+                    rendered
+                        .into_iter()
+                        .map(|field| {
+                            let (rendered, traces) = field?;
+                            for line in traces {
+                                write_trace_line(&trace, &events, &line);
+                            }
+                            Some(rendered)
+                        })
+                        .collect()
+                };
+                serialize::to_writer_with(t, &global_env, self, stack_limit, options, out, render)
+            }
+            _ => serialize::to_writer(t, &global_env, self, stack_limit, options, out),
+        }
+    }
+
+    /// The configuration of the threads of a parallel export, or `None` if the program cannot be
+    /// rebuilt in another thread (see [`set_export_jobs`](#method.set_export_jobs)).
+    fn worker_config(&self) -> Option<WorkerConfig> {
+        let host_config = !self.host_functions.is_empty() || !self.overrides.is_empty();
+        if (host_config && self.worker_setup.is_none()) || self.manifest.borrow().is_some() {
+            return None;
+        }
+
+        Some(WorkerConfig {
+            source: self.files.source(self.main_id).clone(),
+            name: self.files.name(self.main_id).to_os_string(),
+            canonicalize_imports: self.canonicalize_imports,
+            stack_limit: self.stack_limit,
+            import_paths: self.import_paths.clone(),
+            inline_threshold: self.inline_threshold,
+            cancellation_token: self.cancellation_token.clone(),
+            setup: self.worker_setup.clone(),
+            trace_events: self.trace_events.borrow().is_some(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{EvalError, SerializationError};
+    use crate::serialize::{KeyOrder, Newline};
+    use crate::term::Term;
+    use crate::types::{AbsType, Types};
+    use std::io::Cursor;
+
+    #[test]
+    fn export_parallel() {
+        let export = |s: &str, jobs: usize, options: &ExportOptions| {
+            let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+            p.set_export_jobs(jobs);
+            let mut out = Vec::new();
+            let result = p.export(options, &mut out);
+            (result, String::from_utf8(out).unwrap())
+        };
+
+        let config = "let x = 1 in {kind = \"a\"; b = x + 1; a = {z = true; y = [x, 2]}; \
+            e = {}; version = iterate 100 (fun n => n + 1) 0}";
+        let options = ExportOptions {
+            key_order: KeyOrder::Definition,
+            newline: Newline::CrLf,
+            ..Default::default()
+        };
+        let (result, sequential) = export(config, 1, &options);
+        result.unwrap();
+        for jobs in 2..7 {
+            let (result, parallel) = export(config, jobs, &options);
+            result.unwrap();
+            assert_eq!(parallel, sequential);
+        }
+
+        // The threads render the fields which succeed
+        let p = Program::new_from_source(Cursor::new("{a = 1; b = 1 + true; c = [2]}"), "<test>")
+            .unwrap();
+        let fields: Vec<String> = vec!["c", "b", "a", "d"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let rendered =
+            p.worker_config()
+                .unwrap()
+                .render_fields(&fields, 3, &ExportOptions::default());
+        assert_eq!(
+            rendered,
+            vec![
+                Some((String::from("[\n    2\n  ]"), Vec::new())),
+                None,
+                Some((String::from("1"), Vec::new())),
+                None
+            ]
+        );
+
+        // The error of the first failing field in the output is reported
+        let config = "{a = 1; b = 1 + true; c = Assume(Num, true); d = 2}";
+        match export(config, 3, &ExportOptions::default()) {
+            (Err(Error::EvalError(EvalError::TypeError(..))), out) => {
+                assert_eq!(out, "{\n  \"a\": 1,\n  \"b\": ")
+            }
+            res => panic!("expected a type error, got {:?}", res),
+        };
+        let options = ExportOptions {
+            aggregate: true,
+            ..Default::default()
+        };
+        let config = "{a = Assume(Num, true); b = {c = [1, Assume(Bool, 2)]; d = 3}; e = 4}";
+        let (result, parallel) = export(config, 4, &options);
+        let (_, sequential) = export(config, 1, &options);
+        assert_eq!(parallel, sequential);
+        match result {
+            Err(Error::SerializationError(SerializationError::ContractViolations(violations))) => {
+                let paths: Vec<&str> = violations.iter().map(|(path, _)| path.as_str()).collect();
+                assert_eq!(paths, vec!["a", "b.c[1]"]);
+            }
+            res => panic!("expected contract violations, got {:?}", res),
+        };
+
+        // Programs with host functions are exported sequentially, unless the threads register
+        // them again
+        let register = |p: &mut Program| {
+            let num_ty = || Box::new(Types(AbsType::Num()));
+            p.register_function(
+                "double",
+                Types(AbsType::Arrow(num_ty(), num_ty())),
+                |args| match args[0].as_ref() {
+                    Term::Num(n) => Ok(Term::Num(2.0 * n).into()),
+                    _ => Err(String::from("expected a number")),
+                },
+            )
+        };
+        let mut p = Program::new_from_source(Cursor::new("{a = double 1; b = double 2}"), "<test>")
+            .unwrap();
+        register(&mut p);
+        assert!(p.worker_config().is_none());
+        p.set_worker_setup(register);
+        p.set_export_jobs(2);
+        assert!(p.worker_config().is_some());
+        let mut out = Vec::new();
+        p.export(&ExportOptions::default(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\n  \"a\": 2,\n  \"b\": 4\n}\n"
+        );
+
+        // The traces of the threads are written in the order of the fields, and the ones of the
+        // top-level record only once
+        let traces = |jobs: usize, events: bool| {
+            let lines = Rc::new(RefCell::new(Vec::new()));
+            let lines_ = Rc::clone(&lines);
+            let mut p = Program::new_from_source(
+                Cursor::new(
+                    "if trace \"r\" true then {c = trace \"c\" 3; a = trace \"a\" 1; b = 2} \
+                    else {}",
+                ),
+                "<test>",
+            )
+            .unwrap();
+            let collect = move |line: &str| lines_.borrow_mut().push(String::from(line));
+            if events {
+                p.set_trace_event_callback(collect);
+            } else {
+                p.set_trace_callback(collect);
+            }
+            p.set_export_jobs(jobs);
+            p.export(&ExportOptions::default(), &mut Vec::new())
+                .unwrap();
+            lines.replace(Vec::new())
+        };
+        let sequential = traces(1, false);
+        assert_eq!(
+            sequential,
+            vec!["trace: r: true", "trace: a: 1.0", "trace: c: 3.0"]
+        );
+        assert_eq!(traces(2, false), sequential);
+        assert_eq!(traces(3, true), traces(1, true));
+    }
+}
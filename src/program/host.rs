@@ -0,0 +1,363 @@
+//! Host functions and contracts.
+//!
+//! The host can extend a program with functions written in Rust (see
+//! [`Program::register_function`](../struct.Program.html#method.register_function)) and with
+//! contracts whose predicate is written in Rust (see
+//! [`Program::register_contract`](../struct.Program.html#method.register_contract)). They are
+//! bound in the global environment, behind a Nickel wrapper which evaluates their arguments and
+//! checks their type.
+use super::Program;
+use crate::identifier::Ident;
+use crate::label::Label;
+use crate::manifest::{self, HostCallRecord};
+use crate::position::RawSpan;
+use crate::term::{BinaryOp, HostFunction, RecordData, RichTerm, Term, UnaryOp};
+use crate::types::{AbsType, Types};
+use std::rc::Rc;
+
+/// A function or a contract registered by the host.
+pub(super) struct HostBinding {
+    /// The components of the dotted name of the binding.
+    path: Vec<Ident>,
+    /// The Nickel wrapper of the function or the contract.
+    term: RichTerm,
+    /// The type of a host function, as declared by the host.
+    ty: Option<Types>,
+}
+
+impl Program {
+    /// Wrap a host function such that its calls are recorded in the manifest, if enabled.
+    fn recorded_host_function<F>(&self, name: &str, fun: F) -> HostFunction
+    where
+        F: Fn(Vec<RichTerm>) -> Result<RichTerm, String> + 'static,
+    {
+        let recorder = Rc::clone(&self.manifest);
+        let fun_name = String::from(name);
+        HostFunction {
+            name: String::from(name),
+            fun: Rc::new(move |args| {
+                if recorder.borrow().is_none() {
+                    return fun(args);
+                }
+
+                let recorded_args = args
+                    .iter()
+                    .map(|t| manifest::to_value(t.as_ref()))
+                    .collect();
+                let result = fun(args);
+                if let Some(manifest) = recorder.borrow_mut().as_mut() {
+                    manifest.host_calls.push(HostCallRecord {
+                        name: fun_name.clone(),
+                        args: recorded_args,
+                        result: result
+                            .as_ref()
+                            .map(|t| manifest::to_value(t.as_ref()))
+                            .map_err(String::clone),
+                    });
+                }
+                result
+            }),
+        }
+    }
+
+    /// Register a host function, which is then accessible from the program as a global variable.
+    ///
+    /// A dotted name such as `host.getSecret` is bound as a field of nested records: the program
+    /// calls the function as `host.getSecret`, and the functions sharing a prefix are fields of
+    /// the same record. The first component of the name shadows any other global variable. A
+    /// function registered again under the same name, or under a prefix or an extension of the
+    /// name of another host function, replaces it.
+    ///
+    /// The number of arguments of the function is the number of arrows of its type `ty`. The
+    /// arguments are fully evaluated before being passed to `fun`, which must return a closed
+    /// term. The type is used by the typechecker, and is also enforced at run time as a contract
+    /// on the function, such that `fun` can rely on the shape of its arguments.
+    pub fn register_function<F>(&mut self, name: &str, ty: Types, fun: F)
+    where
+        F: Fn(Vec<RichTerm>) -> Result<RichTerm, String> + 'static,
+    {
+        let f = self.recorded_host_function(name, fun);
+        let wrapper = self.host_function_wrapper(ty.clone(), f);
+        self.add_host_binding(HostBinding {
+            path: name.split('.').map(Ident::from).collect(),
+            term: wrapper,
+            ty: Some(ty),
+        });
+    }
+
+    /// Add a host binding, replacing the ones whose name is a prefix or an extension of its name.
+    fn add_host_binding(&mut self, binding: HostBinding) {
+        self.host_functions.retain(|other| {
+            let len = other.path.len().min(binding.path.len());
+            other.path[..len] != binding.path[..len]
+        });
+        self.host_functions.push(binding);
+    }
+
+    /// Build the global variables holding the host bindings, with their type if it is known. The
+    /// bindings with a dotted name are grouped in nested records by the components of their name.
+    pub(super) fn host_globals(&self) -> Vec<(Ident, RichTerm, Option<Types>)> {
+        // A binding together with the remaining components of its name.
+        type Suffix<'a> = (&'a [Ident], &'a HostBinding);
+
+        fn nest(bindings: Vec<Suffix>) -> Vec<(Ident, RichTerm, Option<Types>)> {
+            let mut groups: Vec<(Ident, Vec<Suffix>)> = Vec::new();
+            for (path, binding) in bindings {
+                let (id, rest) = match path.split_first() {
+                    Some(split) => split,
+                    None => continue,
+                };
+                match groups.iter_mut().find(|(other, _)| other == id) {
+                    Some((_, group)) => group.push((rest, binding)),
+                    None => groups.push((id.clone(), vec![(rest, binding)])),
+                }
+            }
+
+            groups
+                .into_iter()
+                .map(|(id, group)| match group.as_slice() {
+                    [([], binding)] => (id, binding.term.clone(), binding.ty.clone()),
+                    _ => {
+                        let fields = nest(group);
+                        // The record is typed if all its fields are.
+                        let row = fields.iter().rev().try_fold(
+                            Types(AbsType::RowEmpty()),
+                            |tail, (id, _, ty)| {
+                                Some(Types(AbsType::RowExtend(
+                                    id.clone(),
+                                    Some(Box::new(ty.clone()?)),
+                                    Box::new(tail),
+                                )))
+                            },
+                        );
+                        let ty = row.map(|row| Types(AbsType::StaticRecord(Box::new(row))));
+                        let record: RecordData =
+                            fields.into_iter().map(|(id, t, _)| (id, t)).collect();
+                        (id, Term::Record(record).into(), ty)
+                    }
+                })
+                .collect()
+        }
+
+        nest(
+            self.host_functions
+                .iter()
+                .map(|binding| (binding.path.as_slice(), binding))
+                .collect(),
+        )
+    }
+
+    /// Build the Nickel wrapper of a host function of type `ty`, which forces the arguments and
+    /// calls `f`, under a contract checking the type.
+    pub(super) fn host_function_wrapper(&mut self, ty: Types, f: HostFunction) -> RichTerm {
+        let name = f.name.clone();
+        let mut body_ty = &ty;
+        while let AbsType::Forall(_, ref body) = body_ty.0 {
+            body_ty = body.as_ref();
+        }
+        let mut arity = 0;
+        while let AbsType::Arrow(_, ref codom) = body_ty.0 {
+            arity += 1;
+            body_ty = codom.as_ref();
+        }
+
+        let params: Vec<String> = (0..arity).map(|i| format!("%arg{}", i)).collect();
+        let args = || Term::List(params.iter().cloned().map(RichTerm::var).collect());
+        // fun %arg0 ... %argn => deepSeq [%arg0, ..., %argn] (hostCall [%arg0, ..., %argn])
+        let call = RichTerm::app(
+            Term::Op1(UnaryOp::DeepSeq(), args().into()).into(),
+            Term::Op1(UnaryOp::HostCall(f), args().into()).into(),
+        );
+        let wrapper = params
+            .iter()
+            .cloned()
+            .rev()
+            .fold(call, |body, param| RichTerm::fun(param, body));
+
+        let repr = format!("{}", ty);
+        let len = repr.len();
+        let src_id = self.files.add(format!("<host function {}>", name), repr);
+        let label = Label {
+            types: ty.clone(),
+            tag: name,
+            span: RawSpan {
+                src_id,
+                start: 0.into(),
+                end: (len as u32).into(),
+            },
+            polarity: true,
+            path: Vec::new(),
+            arg_pos: None,
+        };
+
+        Term::Assume(ty, label, wrapper).into()
+    }
+
+    /// Register a host contract, which is then usable in the program as the flat type `#name`.
+    ///
+    /// The checked value is fully evaluated before being passed to `pred`, and the contract blames
+    /// it if `pred` returns `false`. As all flat types, `#name` is only equal to itself for the
+    /// typechecker: a value of this type is neither accepted where the underlying type of the
+    /// value, such as `Str`, is expected, nor where another contract is.
+    pub fn register_contract<F>(&mut self, name: &str, pred: F)
+    where
+        F: Fn(&Term) -> bool + 'static,
+    {
+        let f = self.recorded_host_function(name, move |args| {
+            Ok(Term::Bool(pred(args[0].as_ref())).into())
+        });
+        let value = || RichTerm::var(String::from("%t"));
+        let label = RichTerm::var(String::from("%l"));
+        // fun %l %t => deepSeq %t (if hostCall [%t] then %t else blame (tag name (withArgPos %t %l)))
+        let blame = Term::Op1(
+            UnaryOp::Blame(),
+            Term::Op1(
+                UnaryOp::Tag(String::from(name)),
+                Term::Op2(BinaryOp::WithArgPos(), value(), label).into(),
+            )
+            .into(),
+        );
+        let check = RichTerm::ite(
+            Term::Op1(UnaryOp::HostCall(f), Term::List(vec![value()]).into()).into(),
+            value(),
+            blame.into(),
+        );
+        let body = RichTerm::app(Term::Op1(UnaryOp::DeepSeq(), value()).into(), check);
+        let contract = RichTerm::fun(String::from("%l"), RichTerm::fun(String::from("%t"), body));
+
+        self.add_host_binding(HostBinding {
+            path: vec![Ident::from(name)],
+            term: contract,
+            ty: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{Error, EvalError};
+    use std::io::Cursor;
+
+    #[test]
+    fn host_functions() {
+        let eval_with_host = |s: &str| {
+            let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+            let str_ty = || Box::new(Types(AbsType::Str()));
+            p.register_function("greet", Types(AbsType::Arrow(str_ty(), str_ty())), |args| {
+                match args[0].as_ref() {
+                    Term::Str(s) if s == "nobody" => Err(String::from("unknown name")),
+                    Term::Str(s) => Ok(Term::Str(format!("hello, {}", s)).into()),
+                    _ => Err(String::from("expected a string")),
+                }
+            });
+            p.eval()
+        };
+
+        assert_eq!(
+            eval_with_host("greet (\"wor\" ++ \"ld\")"),
+            Ok(Term::Str(String::from("hello, world")))
+        );
+        assert_eq!(
+            eval_with_host("Promise(Str, greet \"you\")"),
+            Ok(Term::Str(String::from("hello, you")))
+        );
+        match eval_with_host("Promise(Num, greet \"you\")") {
+            Err(Error::TypecheckError(..)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        };
+        match eval_with_host("greet 1") {
+            Err(Error::EvalError(EvalError::BlameError(..))) => (),
+            r => panic!("unexpected result: {:?}", r),
+        };
+        match eval_with_host("greet \"nobody\"") {
+            Err(Error::EvalError(EvalError::Other(..))) => (),
+            r => panic!("unexpected result: {:?}", r),
+        };
+    }
+
+    #[test]
+    fn dotted_host_functions() {
+        let eval_with_host = |s: &str| {
+            let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+            let str_ty = || Box::new(Types(AbsType::Str()));
+            p.register_function(
+                "host.getSecret",
+                Types(AbsType::Arrow(str_ty(), str_ty())),
+                |args| match args[0].as_ref() {
+                    Term::Str(s) => Ok(Term::Str(format!("secret of {}", s)).into()),
+                    _ => Err(String::from("expected a string")),
+                },
+            );
+            p.register_function(
+                "host.env.user",
+                Types(AbsType::Arrow(str_ty(), str_ty())),
+                |_| Ok(Term::Str(String::from("root")).into()),
+            );
+            p.eval()
+        };
+
+        assert_eq!(
+            eval_with_host("host.getSecret \"db\""),
+            Ok(Term::Str(String::from("secret of db")))
+        );
+        assert_eq!(
+            eval_with_host("host.env.user \"me\""),
+            Ok(Term::Str(String::from("root")))
+        );
+        assert_eq!(
+            eval_with_host("Promise(Str, host.getSecret \"db\")"),
+            Ok(Term::Str(String::from("secret of db")))
+        );
+        match eval_with_host("Promise(Num, host.getSecret \"db\")") {
+            Err(Error::TypecheckError(..)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        };
+    }
+
+    #[test]
+    fn host_contracts() {
+        let eval_with_host = |s: &str| {
+            let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+            p.register_contract("Hostname", |t| match t {
+                Term::Str(s) => {
+                    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '.')
+                }
+                _ => false,
+            });
+            p.register_contract(
+                "Port",
+                |t| matches!(t, Term::Num(n) if *n >= 1.0 && *n <= 65535.0),
+            );
+            p.eval()
+        };
+
+        assert_eq!(
+            eval_with_host("Assume(#Hostname, \"example.\" ++ \"org\")"),
+            Ok(Term::Str(String::from("example.org")))
+        );
+        assert_eq!(
+            eval_with_host("(Assume({ {| port: #Port |} }, {port = 80})).port"),
+            Ok(Term::Num(80.0))
+        );
+        assert!(matches!(
+            eval_with_host("Assume(#Port, 0)"),
+            Err(Error::EvalError(EvalError::BlameError(l, _))) if l.tag.contains("Port")
+        ));
+        assert!(eval_with_host("Assume(#Hostname, \"not a hostname\")").is_err());
+
+        // Named contracts are only equal to themselves.
+        assert_eq!(
+            eval_with_host("let f = Promise(#Port -> #Port, fun p => p) in f 8080"),
+            Ok(Term::Num(8080.0))
+        );
+        assert!(matches!(
+            eval_with_host("Promise(#Hostname -> #Port, fun p => p)"),
+            Err(Error::TypecheckError(..))
+        ));
+        assert!(matches!(
+            eval_with_host("Promise(#Port -> Num, fun p => p)"),
+            Err(Error::TypecheckError(..))
+        ));
+    }
+}
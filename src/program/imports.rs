@@ -0,0 +1,133 @@
+//! Location of imported files.
+//!
+//! The path of an import is relative to the importing file. When it doesn't exist there, it is
+//! searched in the import paths of the program (see
+//! [`Program::set_import_paths`](../struct.Program.html#method.set_import_paths)). Import paths
+//! are then canonicalized or only normalized lexically, following the canonicalization policy of
+//! the program (see
+//! [`Program::set_canonicalize_imports`](../struct.Program.html#method.set_canonicalize_imports)),
+//! and the resulting path gives the key of the file in the file cache.
+use super::Program;
+use crate::paths::{self, PathStyle};
+use std::path::{Path, PathBuf};
+
+impl Program {
+    /// Enable or disable the canonicalization of import paths, which is enabled by default.
+    ///
+    /// When enabled, import paths are resolved to their canonical form, following symbolic links,
+    /// such that a file reached through different paths is loaded, typechecked and evaluated only
+    /// once. Relative imports inside an imported file are then resolved with respect to its real
+    /// location, and diagnostics report absolute paths. When disabled, paths are only normalized
+    /// lexically, which keeps the paths reported in diagnostics independent of where the program
+    /// lives on the file system, at the cost of loading a file once per distinct path.
+    pub fn set_canonicalize_imports(&mut self, canonicalize: bool) {
+        self.canonicalize_imports = canonicalize;
+    }
+
+    /// Set the directories in which imports are searched, in order, when a relative import does
+    /// not exist relatively to the importing file.
+    pub fn set_import_paths(&mut self, paths: Vec<PathBuf>) {
+        self.import_paths = paths;
+    }
+
+    /// Compute the normalized path of an import, following the canonicalization policy, and the
+    /// corresponding key of the file cache (see the [`paths`](../paths/index.html) module).
+    pub(super) fn locate(&self, path: &str, parent: Option<PathBuf>) -> (PathBuf, String) {
+        let style = PathStyle::host();
+        let mut path_buf = with_parent(path, parent);
+        if !path_buf.exists() && Path::new(path).is_relative() {
+            if let Some(found) = self
+                .import_paths
+                .iter()
+                .map(|dir| dir.join(path))
+                .find(|candidate| candidate.exists())
+            {
+                path_buf = found;
+            }
+        }
+
+        let path_buf = if self.canonicalize_imports {
+            path_buf.canonicalize().unwrap_or(path_buf)
+        } else {
+            path_buf
+        };
+
+        // Paths which are not valid unicode are kept as they are.
+        match path_buf.to_str() {
+            Some(path) => {
+                let normalized = paths::normalize(path, style);
+                let key = paths::cache_key(&normalized, style);
+                (PathBuf::from(normalized), key)
+            }
+            None => {
+                let key = paths::cache_key(&path_buf.to_string_lossy(), style);
+                (path_buf, key)
+            }
+        }
+    }
+}
+
+/// Compute the path of a file relatively to a parent. If the path is absolute or if the parent is
+/// `None`, the result is the same as `Path::new(path).to_path_buf()`.
+fn with_parent(path: &str, parent: Option<PathBuf>) -> PathBuf {
+    let mut path_buf = parent.unwrap_or_default();
+    path_buf.pop();
+    path_buf.push(Path::new(path));
+    path_buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{Error, ImportError};
+    use crate::program::ImportResolver;
+    use crate::term::Term;
+    use std::fs;
+    use std::io::Cursor;
+
+    #[test]
+    fn import_canonicalization() {
+        let dir = std::env::temp_dir().join(format!("nickel-import-canon-{}", std::process::id()));
+        fs::create_dir_all(dir.join("real")).unwrap();
+        fs::create_dir_all(dir.join("other")).unwrap();
+        fs::write(dir.join("real").join("b.ncl"), "1 + (import \"c.ncl\")").unwrap();
+        fs::write(dir.join("real").join("c.ncl"), "2").unwrap();
+        std::os::unix::fs::symlink(
+            dir.join("real").join("b.ncl"),
+            dir.join("other").join("b.ncl"),
+        )
+        .unwrap();
+
+        let paths = ["real/b.ncl", "other/../real/./b.ncl", "other/b.ncl"];
+        let src = paths
+            .iter()
+            .map(|path| format!("(import \"{}/{}\")", dir.display(), path))
+            .collect::<Vec<_>>()
+            .join(" + ");
+        let ids = |p: &Program| -> Vec<_> {
+            paths
+                .iter()
+                .map(|path| p.get_id(&format!("{}/{}", dir.display(), path), None))
+                .collect()
+        };
+
+        let mut p = Program::new_from_source(Cursor::new(src.clone()), "<test>").unwrap();
+        assert_eq!(p.eval(), Ok(Term::Num(9.)));
+        let ids_canon = ids(&p);
+        assert!(ids_canon[0].is_some());
+        assert!(ids_canon.iter().all(|id| *id == ids_canon[0]));
+
+        let mut p = Program::new_from_source(Cursor::new(src), "<test>").unwrap();
+        p.set_canonicalize_imports(false);
+        match p.eval() {
+            Err(Error::ImportError(ImportError::IOError(..))) => (),
+            res => panic!("expected an import error, got {:?}", res),
+        };
+        let ids_lexical = ids(&p);
+        assert!(ids_lexical[0].is_some());
+        assert_eq!(ids_lexical[0], ids_lexical[1]);
+        assert_ne!(ids_lexical[0], ids_lexical[2]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,4575 @@
+//! Program handling, from file reading to evaluation.
+//!
+//! A program is Nickel source code loaded from an input. This module offers an interface to load a
+//! program source, parse it, evaluate it and report errors.
+//!
+//! # Standard library
+//!
+//! Some essential functions required for evaluation, such as builtin contracts, are written in
+//! pure Nickel. Standard library files must be record literals:
+//!
+//! ```
+//! {
+//!     val1 = ...
+//!     val2 = ...
+//! }
+//! ```
+//!
+//! These .ncl file are not actually distributed as files, instead they are embedded, as plain
+//! text, in the Nickel executable. The embedding is done by way of the [stdlib
+//! module](../stdlib/index.html), which exposes the standard library files as strings. The
+//! embedded strings are then parsed by the functions in this module (see
+//! [`mk_global_env`](./struct.Program.html#method.mk_global_env)).  Each such value is added to
+//! the global environment before the evaluation of the program.
+//!
+//! # Interface files
+//!
+//! An imported Nickel file `foo.ncl` can be accompanied by an interface file `foo.ncli` in the same
+//! directory, which contains the type of the file, such as:
+//!
+//! ```text
+//! { {| add: Num -> Num -> Num, name: Str |} }
+//! ```
+//!
+//! The typechecker then uses this type for the import, without typechecking the implementation.
+//! At run time, the implementation is checked against the corresponding contract.
+use crate::analysis::{self, Completion};
+use crate::collections::HashMap;
+use crate::deps::{self, ImportGraph, LoadedFile};
+use crate::error::{Error, ImportError, ParseError, ToDiagnostic, TypecheckError, Warning};
+use crate::eval;
+use crate::identifier::Ident;
+use crate::label::Label;
+use crate::manifest::{self, FileRecord, Manifest};
+use crate::parser;
+use crate::parser::lexer::Lexer;
+use crate::position::RawSpan;
+use crate::serialize::{self, InputFormat};
+use crate::specialize;
+use crate::term::{HostFunction, RichTerm, Term};
+use crate::transformations::{self, access_sharing, inline};
+use crate::typecheck::{self, type_check, CancellationToken};
+use crate::types::{AbsType, Types};
+use codespan::{FileId, Files};
+use codespan_reporting::diagnostic::LabelStyle;
+use codespan_reporting::term::termcolor::{ColorChoice, NoColor, StandardStream};
+use serde_json::json;
+use std::cell::RefCell;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::result::Result;
+use std::time::{Duration, Instant};
+
+mod directory;
+mod export;
+mod host;
+mod imports;
+mod overrides;
+
+use directory::{list_dir, DIR_INDEX};
+use export::WorkerSetup;
+use host::HostBinding;
+use overrides::with_override;
+
+/// The parts of the standard library, as pairs of a file name and a source.
+const STDLIB_PARTS: [(&str, &str); 5] = [
+    ("<stdlib/contracts.ncl>", crate::stdlib::CONTRACTS),
+    ("<stdlib/lists.ncl>", crate::stdlib::LISTS),
+    ("<stdlib/lazy.ncl>", crate::stdlib::LAZY),
+    ("<stdlib/nums.ncl>", crate::stdlib::NUMS),
+    ("<stdlib/seals.ncl>", crate::stdlib::SEALS),
+];
+
+/// The processed bindings of the standard library, together with their inferred types, if any.
+type StdlibBindings = Vec<(Ident, RichTerm, Option<Types>)>;
+
+thread_local! {
+    /// The bindings of the standard library, once parsed, typechecked and transformed.
+    ///
+    /// They are shared by all the programs of a thread, such that creating and evaluating many
+    /// programs does not process the standard library again each time. The positions in these
+    /// terms refer to the file ids of the standard library, which are the same in every program.
+    /// Terms are not thread-safe, which is why the cache is not shared across threads.
+    static STDLIB_CACHE: RefCell<Option<Rc<StdlibBindings>>> = RefCell::new(None);
+}
+
+/// A Nickel program.
+///
+/// Manage a file database, which stores the original source code of the program and eventually the
+/// code of imported expressions, and a dictionary which stores corresponding parsed terms.
+pub struct Program {
+    /// The id of the program source in the file database.
+    main_id: FileId,
+    /// The file database holding the content of the program source plus potential imports
+    /// made by the program (imports will be supported in a near future).
+    files: Files<String>,
+    /// Cache storing the id of files that are already stored in the database.
+    file_cache: HashMap<String, FileId>,
+    /// Cache storing parsed terms corresponding to the entries of the file database.
+    term_cache: HashMap<FileId, RichTerm>,
+    /// Functions and contracts registered by the host, together with the Nickel wrapper they are
+    /// bound to in the global environment.
+    host_functions: Vec<HostBinding>,
+    /// Whether import paths are canonicalized. See
+    /// [`set_canonicalize_imports`](#method.set_canonicalize_imports).
+    canonicalize_imports: bool,
+    /// The maximum size of the stack of the abstract machine. See
+    /// [`set_stack_limit`](#method.set_stack_limit).
+    stack_limit: Option<usize>,
+    /// Cache storing the interface files already loaded, as the label of the contract checking an
+    /// implementation against its interface.
+    interfaces: HashMap<PathBuf, Label>,
+    /// Directories in which imports are searched when they are not found relatively to the
+    /// importing file. See [`set_import_paths`](#method.set_import_paths).
+    import_paths: Vec<PathBuf>,
+    /// Values overriding fields of the program. See [`add_override`](#method.add_override).
+    overrides: Vec<(Vec<Ident>, RichTerm)>,
+    /// The maximum size of inlined functions. See
+    /// [`set_inline_threshold`](#method.set_inline_threshold).
+    inline_threshold: Option<usize>,
+    /// The number of threads of an export. See [`set_export_jobs`](#method.set_export_jobs).
+    export_jobs: usize,
+    /// The function configuring the programs of the threads of a parallel export. See
+    /// [`set_worker_setup`](#method.set_worker_setup).
+    worker_setup: Option<WorkerSetup>,
+    /// The destination of the trace output. See [`set_trace_sink`](#method.set_trace_sink).
+    trace: TraceSink,
+    /// The destination of the structured trace events, if enabled, which is shared with the
+    /// built-in `trace` function. See
+    /// [`set_trace_event_callback`](#method.set_trace_event_callback).
+    trace_events: Rc<RefCell<Option<TraceEvents>>>,
+    /// The functions provided by the program itself, such as `trace`, which are bound in the
+    /// global environment like host functions.
+    builtins: Vec<(Ident, RichTerm)>,
+    /// The token interrupting the typechecking. See
+    /// [`set_cancellation_token`](#method.set_cancellation_token).
+    cancellation_token: Option<CancellationToken>,
+    /// The record of the interactions with the outside world, if enabled, which is shared with the
+    /// wrappers of host functions. See [`set_record_manifest`](#method.set_record_manifest).
+    manifest: Rc<RefCell<Option<Manifest>>>,
+    /// Statistics about the imported files, in the order they were first imported. See
+    /// [`import_stats`](#method.import_stats).
+    import_stats: Vec<ImportStats>,
+}
+
+/// Statistics about a file or a directory imported by a program. See
+/// [`Program::import_stats`](struct.Program.html#method.import_stats).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportStats {
+    pub file_id: FileId,
+    pub path: PathBuf,
+    /// The time spent reading and parsing the file, when it was first imported.
+    pub load_time: Duration,
+    /// The number of later imports of the file, which were resolved from the cache.
+    pub cache_hits: usize,
+}
+
+/// A shared handle on the destination of the trace output of a program. See
+/// [`Program::trace_sink`](struct.Program.html#method.trace_sink).
+pub type TraceSink = Rc<RefCell<Box<dyn io::Write>>>;
+
+/// A sink calling a function on each complete line written to it. See
+/// [`Program::set_trace_callback`](struct.Program.html#method.set_trace_callback).
+struct TraceCallback<F: FnMut(&str)> {
+    callback: F,
+    /// The bytes of the current line, not terminated yet.
+    line: Vec<u8>,
+}
+
+impl<F: FnMut(&str)> io::Write for TraceCallback<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte == b'\n' {
+                (self.callback)(&String::from_utf8_lossy(&self.line));
+                self.line.clear();
+            } else {
+                self.line.push(byte);
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&str)> Drop for TraceCallback<F> {
+    // Do not lose a last line without a terminator.
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            (self.callback)(&String::from_utf8_lossy(&self.line));
+        }
+    }
+}
+
+/// The maximal length, in characters, of the representation of a value in a trace event.
+const TRACE_VALUE_LEN: usize = 60;
+
+/// A callback receiving the trace events, shared by the built-in `trace` function and the sink of
+/// the other trace lines.
+type EventCallback = Rc<RefCell<dyn FnMut(&str)>>;
+
+/// The destination of the structured trace events. See
+/// [`Program::set_trace_event_callback`](struct.Program.html#method.set_trace_event_callback).
+struct TraceEvents {
+    callback: EventCallback,
+    /// A copy of the file database, to locate the traced values.
+    files: Files<String>,
+}
+
+impl TraceEvents {
+    fn locate(&self, span: &RawSpan) -> Option<serde_json::Value> {
+        let loc = self.files.location(span.src_id, span.start).ok()?;
+        Some(json!({
+            "file": self.files.name(span.src_id).to_string_lossy(),
+            "line": loc.line.to_usize() + 1,
+            "column": loc.column.to_usize() + 1,
+        }))
+    }
+}
+
+/// The location of an error in a source file, in terms of lines and columns.
+///
+/// Lines and columns are zero-based, and columns are counted in bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    /// The name of the file, as stored in the file database of the program.
+    pub file: String,
+    /// The line and the column of the start of the span.
+    pub start: (usize, usize),
+    /// The line and the column of the end of the span.
+    pub end: (usize, usize),
+}
+
+/// Return status indicating if an import has been resolved from a file (first encounter), or was
+/// retrieved from the cache.
+///
+/// See [`resolve`](./fn.resolve.html).
+#[derive(Debug, PartialEq)]
+pub enum ResolvedTerm {
+    FromFile(
+        /* the parsed term */ RichTerm,
+        /* the path of the loaded file */ PathBuf,
+    ),
+    FromCache(),
+}
+
+/// Abstract the access to imported files and the import cache. Used by the evaluator, the
+/// typechecker and at [import resolution](../transformations/import_resolution/index.html) phase.
+///
+/// The standard implementation use 2 caches, the file cache for raw contents and the term cache
+/// for parsed contents, mirroring the 2 steps when resolving an import:
+/// 1. When an import is encountered for the first time, the content of the corresponding file is
+///    read and stored in the file cache (consisting of the file database plus a map between paths
+///    and ids in the database). The content is parsed, and this term is queued somewhere so that
+///    it can undergo the standard [transformations](../transformations/index.html) first, but is
+///    not stored in the term cache yet.
+/// 2. When it is finally processed, the term cache is updated with the transformed term.
+pub trait ImportResolver {
+    /// Resolve an import.
+    ///
+    /// Read and store the content of an import, put it in the file cache (or get it from there if
+    /// it is cached), then parse it and return the corresponding term and file id.
+    ///
+    /// The term and the path are provided only if the import is processed for the first time.
+    /// Indeed, at import resolution phase, the term of an import encountered for the first time is
+    /// queued to be processed (e.g. having its own imports resolved). The path is needed to
+    /// resolve nested imports relatively to this parent. Only after this processing the term is
+    /// inserted back in the cache via [`insert`](#tymethod.insert). On the other hand, if it has
+    /// been resolved before, it is already transformed in the cache and do not need further
+    /// processing.
+    fn resolve(
+        &mut self,
+        path: &String,
+        parent: Option<PathBuf>,
+        pos: &Option<RawSpan>,
+    ) -> Result<(ResolvedTerm, FileId), ImportError>;
+
+    /// Insert an entry in the term cache after transformation.
+    fn insert(&mut self, file_id: FileId, term: RichTerm);
+
+    /// Get a resolved import from the term cache.
+    fn get(&self, file_id: FileId) -> Option<RichTerm>;
+
+    /// Get a file id from the file cache.
+    fn get_id(&self, path: &String, parent: Option<PathBuf>) -> Option<FileId>;
+
+    /// Get the type declared by the interface file of an import, if it has one.
+    fn get_interface(&mut self, _path: &String, _parent: Option<PathBuf>) -> Option<Types> {
+        None
+    }
+}
+
+impl Program {
+    /// Create a program by reading it from the standard input.
+    pub fn new_from_stdin() -> std::io::Result<Program> {
+        Program::new_from_source(io::stdin(), "<stdin>")
+    }
+
+    /// Create a program by reading from a file.
+    pub fn new_from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Program> {
+        let file = fs::File::open(&path)?;
+        Program::new_from_source(file, path.as_ref())
+    }
+
+    /// Create a program by reading it from a generic source.
+    pub fn new_from_source<T: Read>(
+        mut source: T,
+        source_name: impl Into<OsString>,
+    ) -> std::io::Result<Program> {
+        let mut buffer = String::new();
+        let mut files = Files::<String>::new();
+
+        // The standard library is added first, such that its files have the same ids in all
+        // programs, which is required to share its processed terms (see `STDLIB_CACHE`).
+        let file_cache = STDLIB_PARTS
+            .iter()
+            .map(|(name, source)| {
+                let id = files.add(String::from(*name), String::from(*source));
+                (String::from(*name), id)
+            })
+            .collect();
+
+        source.read_to_string(&mut buffer)?;
+        let main_id = files.add(source_name, buffer);
+
+        let mut program = Program {
+            main_id,
+            files,
+            file_cache,
+            term_cache: HashMap::default(),
+            host_functions: Vec::new(),
+            canonicalize_imports: true,
+            stack_limit: Some(eval::DEFAULT_STACK_LIMIT),
+            interfaces: HashMap::default(),
+            import_paths: Vec::new(),
+            overrides: Vec::new(),
+            inline_threshold: Some(inline::DEFAULT_THRESHOLD),
+            export_jobs: 1,
+            worker_setup: None,
+            trace: Rc::new(RefCell::new(Box::new(io::stderr()))),
+            trace_events: Rc::new(RefCell::new(None)),
+            builtins: Vec::new(),
+            cancellation_token: None,
+            manifest: Rc::new(RefCell::new(None)),
+            import_stats: Vec::new(),
+        };
+
+        let trace_ty = Types(AbsType::Arrow(
+            Box::new(Types(AbsType::Str())),
+            Box::new(Types(AbsType::Arrow(
+                Box::new(Types(AbsType::Dyn())),
+                Box::new(Types(AbsType::Dyn())),
+            ))),
+        ));
+        let trace = program.host_function_wrapper(trace_ty, program.trace_function());
+        program.builtins.push((Ident::from("trace"), trace));
+
+        Ok(program)
+    }
+
+    /// Set the maximum size of the stack of the abstract machine, or remove the limit with
+    /// `None`. The default is [`eval::DEFAULT_STACK_LIMIT`](../eval/constant.DEFAULT_STACK_LIMIT.html).
+    ///
+    /// The stack grows with the depth of non tail-recursive calls. Exceeding the limit stops the
+    /// evaluation with a [`StackOverflow`](../error/enum.EvalError.html#variant.StackOverflow)
+    /// error instead of exhausting the memory of the host. The limit also bounds the nesting of the
+    /// primitive operations calling Nickel functions, such as `iterate`, which run on the stack of
+    /// the host.
+    pub fn set_stack_limit(&mut self, limit: Option<usize>) {
+        self.stack_limit = limit;
+    }
+
+    /// Set the maximum size of the functions which are inlined, in number of nodes of their AST,
+    /// or disable inlining with `None` (see the [`inline`](../transformations/inline/index.html)
+    /// module). The default is
+    /// [`inline::DEFAULT_THRESHOLD`](../transformations/inline/constant.DEFAULT_THRESHOLD.html).
+    ///
+    /// Inlining does not change the result of a program, but disabling it keeps the evaluated
+    /// terms and the [statistics](#method.eval_with_stats) closer to the source, which can help
+    /// when debugging the interpreter.
+    pub fn set_inline_threshold(&mut self, threshold: Option<usize>) {
+        self.inline_threshold = threshold;
+    }
+
+    /// Set the token used to cancel the typechecking of the program, or remove it with `None`.
+    ///
+    /// When the token is cancelled, possibly from another thread, a typechecking in progress stops
+    /// early with a [`TypecheckError::Cancelled`](../error/enum.TypecheckError.html) error, as do
+    /// the next ones until another token is set. This lets an editor drop a stale request, such as
+    /// [`type_table`](#method.type_table), as soon as the file has changed.
+    pub fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.cancellation_token = token;
+    }
+
+    /// Enable or disable the recording of the interactions of the program with the outside world
+    /// in a manifest (see the [manifest module](../manifest/index.html)). The main file is
+    /// recorded when the recording starts, and disabling it discards the manifest.
+    pub fn set_record_manifest(&mut self, record: bool) {
+        let mut manifest = self.manifest.borrow_mut();
+        if !record {
+            *manifest = None;
+        } else if manifest.is_none() {
+            let main = FileRecord::new(
+                PathBuf::from(self.files.name(self.main_id)),
+                self.files.source(self.main_id),
+            );
+            *manifest = Some(Manifest {
+                files: vec![main],
+                ..Manifest::default()
+            });
+        }
+    }
+
+    /// Return the interactions recorded so far, if the recording is enabled (see
+    /// [`set_record_manifest`](#method.set_record_manifest)).
+    pub fn manifest(&self) -> Option<Manifest> {
+        self.manifest.borrow().clone()
+    }
+
+    /// Record a file read from the file system in the manifest, if enabled.
+    fn record_file(&self, path: &Path, file_id: FileId) {
+        if let Some(manifest) = self.manifest.borrow_mut().as_mut() {
+            manifest.files.push(FileRecord::new(
+                path.to_path_buf(),
+                self.files.source(file_id),
+            ));
+        }
+    }
+
+    /// The built-in `trace` function, which writes a message and a value to the trace output and
+    /// returns the value. See [`set_trace_event_callback`](#method.set_trace_event_callback).
+    fn trace_function(&self) -> HostFunction {
+        let sink = Rc::clone(&self.trace);
+        let events = Rc::clone(&self.trace_events);
+        HostFunction {
+            name: String::from("trace"),
+            fun: Rc::new(move |args| {
+                let message = match args[0].as_ref() {
+                    Term::Str(s) => s,
+                    t => return Err(format!("expected a string, got {}", t.shallow_repr())),
+                };
+                let value = manifest::to_value(args[1].as_ref()).to_string();
+                let value = match value.char_indices().nth(TRACE_VALUE_LEN) {
+                    Some((end, _)) => format!("{}...", &value[..end]),
+                    None => value,
+                };
+
+                match events.borrow().as_ref() {
+                    Some(events) => {
+                        let span = args[1].pos.as_ref().and_then(|span| events.locate(span));
+                        let event = json!({"message": message, "span": span, "value": value});
+                        let callback = &mut *events.callback.borrow_mut();
+                        callback(&event.to_string());
+                    }
+                    None => io::Write::write_all(
+                        &mut *sink.borrow_mut(),
+                        format!("trace: {}: {}\n", message, value).as_bytes(),
+                    )
+                    .map_err(|err| err.to_string())?,
+                }
+
+                Ok(args[1].clone())
+            }),
+        }
+    }
+
+    /// Apply the optimizing transformations to a term: inline small functions, following the
+    /// inlining threshold, and share repeated record accesses (see the
+    /// [`access_sharing`](../transformations/access_sharing/index.html) module).
+    fn optimize(&self, t: RichTerm) -> RichTerm {
+        let t = match self.inline_threshold {
+            Some(threshold) => inline::transform(t, threshold),
+            None => t,
+        };
+        access_sharing::transform(t)
+    }
+
+    /// Redirect the trace output of the program, which goes to the standard error by default.
+    ///
+    /// The built-in `trace` function, of type `Str -> Dyn -> Dyn`, which writes a message and a
+    /// representation of a value and returns the value, as well as host functions reporting on
+    /// the evaluation, write through [`trace_sink`](#method.trace_sink) rather than directly to
+    /// the standard error, such that the host can capture or silence this output.
+    pub fn set_trace_sink<W: io::Write + 'static>(&mut self, sink: W) {
+        *self.trace.borrow_mut() = Box::new(sink);
+        *self.trace_events.borrow_mut() = None;
+    }
+
+    /// Same as [`set_trace_sink`](#method.set_trace_sink), but call `callback` on each line of the
+    /// trace output, without its terminator.
+    pub fn set_trace_callback<F: FnMut(&str) + 'static>(&mut self, callback: F) {
+        self.set_trace_sink(TraceCallback {
+            callback,
+            line: Vec::new(),
+        });
+    }
+
+    /// Same as [`set_trace_callback`](#method.set_trace_callback), but pass each trace event to
+    /// `callback` as a JSON object, on one line, instead of free text.
+    ///
+    /// The events of the built-in `trace` function have the fields `message`, `span`, which is
+    /// the location of the traced value as an object with the fields `file`, `line` and `column`,
+    /// or `null` if it is unknown, and `value`, a JSON representation of the traced value,
+    /// truncated to a few dozen characters. The other lines of the trace output, such as the ones
+    /// written by host functions, are passed as an object with the single field `message`.
+    pub fn set_trace_event_callback<F: FnMut(&str) + 'static>(&mut self, callback: F) {
+        let callback = Rc::new(RefCell::new(callback));
+        let on_line = Rc::clone(&callback);
+        self.set_trace_callback(move |line| {
+            let on_line = &mut *on_line.borrow_mut();
+            on_line(&json!({ "message": line }).to_string())
+        });
+        *self.trace_events.borrow_mut() = Some(TraceEvents {
+            callback,
+            files: self.files.clone(),
+        });
+    }
+
+    /// Return a handle on the destination of the trace output. The handle follows the changes of
+    /// destination: writing through a handle obtained before a call to
+    /// [`set_trace_sink`](#method.set_trace_sink) goes to the new sink.
+    pub fn trace_sink(&self) -> TraceSink {
+        Rc::clone(&self.trace)
+    }
+
+    /// Load a part of the Nickel standard library in the given global environment.
+    ///
+    /// The source must be a string representing a record literal. Each binding of this record is
+    /// then inserted in `global_env`.
+    fn load_stdlib(
+        &mut self,
+        name: &str,
+        global_env: &mut eval::Environment,
+    ) -> Result<(), ImportError> {
+        let src_id = *self.file_cache.get(name).unwrap_or_else(|| {
+            panic!(
+                "program::load_stdlib(): the builtin {} is not in the file database",
+                name
+            )
+        });
+        let rt = self
+            .parse_with_cache(src_id)
+            .map_err(|err| ImportError::ParseError(err, None))?;
+
+        match *rt.term {
+            Term::Record(bindings) | Term::RecRecord(bindings) => {
+                let ext = bindings.into_iter().map(|(id, t)| {
+                    let closure = eval::Closure {
+                        body: t,
+                        env: HashMap::default(),
+                    };
+                    (
+                        id,
+                        (Rc::new(RefCell::new(closure)), eval::IdentKind::Record()),
+                    )
+                });
+                global_env.extend(ext);
+            }
+            _ => panic!(
+                "program::load_stdlib(): the builtin {} must be a record.",
+                name
+            ),
+        };
+
+        Ok(())
+    }
+
+    /// Parse, typecheck and transform the standard library, and return its bindings.
+    fn process_stdlib(&mut self) -> Result<StdlibBindings, Error> {
+        let mut global_env = HashMap::default();
+
+        for (name, _) in STDLIB_PARTS.iter() {
+            self.load_stdlib(name, &mut global_env)
+                .map_err(Error::from)?;
+        }
+
+        // Typecheck each entry of the global environment (may be removed later, but as long as the
+        // standard library is unstable, this is useful for debugging purpose)
+        global_env
+            .values()
+            .try_for_each(|(rc, _)| type_check(&rc.borrow().body, &global_env, self).map(|_| ()))?;
+
+        // Infer the precise type of each entry, which is then used when typechecking programs
+        // instead of the apparent type `Dyn` of unannotated entries. Entries which rely on dynamic
+        // typing, such as some contract combinators, can't be typed statically and keep their
+        // apparent type.
+        let global_types =
+            typecheck::Envs::mk_global(&global_env, &mut typecheck::UnifTable::new());
+        let mut types: HashMap<Ident, Types> = global_env
+            .iter()
+            .filter_map(|(id, (rc, _))| {
+                typecheck::infer_type_in_env(&rc.borrow().body, &global_types, self)
+                    .ok()
+                    .map(|ty| (id.clone(), ty))
+            })
+            .collect();
+
+        // After typechecking, we have to apply standard tranformations as well
+        global_env
+            .into_iter()
+            .map(|(id, (rc, _))| {
+                let body = rc.borrow().body.clone();
+                let ty = types.remove(&id);
+                Ok((id, transformations::transform(body, self)?, ty))
+            })
+            .collect::<Result<Vec<_>, ImportError>>()
+            .map_err(Error::from)
+    }
+
+    /// Generate a global environment with values from the standard library parts.
+    ///
+    /// The standard library is only processed once per thread: the resulting terms are then shared
+    /// by all the programs (see [`STDLIB_CACHE`](constant.STDLIB_CACHE.html)), and each program
+    /// only allocates fresh thunks for them.
+    fn mk_global_env(&mut self) -> Result<eval::Environment, Error> {
+        let bindings = match STDLIB_CACHE.with(|cache| cache.borrow().clone()) {
+            Some(bindings) => bindings,
+            None => {
+                let bindings = Rc::new(self.process_stdlib()?);
+                STDLIB_CACHE.with(|cache| *cache.borrow_mut() = Some(Rc::clone(&bindings)));
+                bindings
+            }
+        };
+
+        let mut global_env: eval::Environment = bindings
+            .iter()
+            .map(|(id, t, _)| {
+                let closure = eval::Closure::atomic_closure(t.clone());
+                (
+                    id.clone(),
+                    (Rc::new(RefCell::new(closure)), eval::IdentKind::Record()),
+                )
+            })
+            .collect();
+
+        let host_globals = self.host_globals().into_iter().map(|(id, t, _)| (id, t));
+        for (id, t) in self.builtins.iter().cloned().chain(host_globals) {
+            global_env.insert(
+                id,
+                (
+                    Rc::new(RefCell::new(eval::Closure::atomic_closure(t))),
+                    eval::IdentKind::Let(),
+                ),
+            );
+        }
+
+        Ok(global_env)
+    }
+
+    /// Build the global typing environment, using the inferred types of the standard library (see
+    /// [`typecheck::infer_type_in_env`](../typecheck/fn.infer_type_in_env.html)) and the declared
+    /// types of host functions. The standard library must have been processed by
+    /// [`mk_global_env`](#method.mk_global_env) before.
+    fn mk_global_typing_env(&self, global_env: &eval::Environment) -> typecheck::Environment {
+        let mut types: HashMap<Ident, Types> = STDLIB_CACHE
+            .with(|cache| cache.borrow().clone())
+            .map(|bindings| {
+                bindings
+                    .iter()
+                    .filter_map(|(id, _, ty)| Some((id.clone(), ty.clone()?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        for (id, _, ty) in self.host_globals() {
+            match ty {
+                Some(ty) => types.insert(id, ty),
+                None => types.remove(&id),
+            };
+        }
+        typecheck::Envs::mk_global_with_types(global_env, &types, &mut typecheck::UnifTable::new())
+    }
+
+    /// Typecheck a term in the global typing environment (see
+    /// [`mk_global_typing_env`](#method.mk_global_typing_env)).
+    fn type_check(
+        &mut self,
+        t: &RichTerm,
+        global_env: &eval::Environment,
+    ) -> Result<Types, TypecheckError> {
+        let global = self.mk_global_typing_env(global_env);
+        let cancel = self.cancellation_token.clone();
+        typecheck::type_check_in_env(t, &global, self, cancel.as_ref())
+    }
+
+    /// Parse if necessary, typecheck and then evaluate the program.
+    pub fn eval(&mut self) -> Result<Term, Error> {
+        let (t, global_env) = self.prepare_eval()?;
+        eval::eval(t, global_env, self, self.stack_limit).map_err(|e| e.into())
+    }
+
+    /// Same as [`eval`](#method.eval), but also return statistics about the evaluation (see
+    /// [`eval::EvalStats`](../eval/struct.EvalStats.html)). The statistics are empty if the
+    /// program could not be evaluated at all, for example because of a parse error.
+    pub fn eval_with_stats(&mut self) -> (Result<Term, Error>, eval::EvalStats) {
+        match self.prepare_eval() {
+            Ok((t, global_env)) => {
+                let (result, stats) = eval::eval_with_stats(t, global_env, self, self.stack_limit);
+                (result.map_err(|e| e.into()), stats)
+            }
+            Err(err) => (Err(err), eval::EvalStats::default()),
+        }
+    }
+
+    /// Same as [`eval`](#method.eval), but fully evaluate the program (see
+    /// [`eval::eval_full`](../eval/fn.eval_full.html)).
+    pub fn eval_full(&mut self) -> Result<Term, Error> {
+        let (t, global_env) = self.prepare_eval()?;
+        eval::eval_full(t, global_env, self, self.stack_limit).map_err(|e| e.into())
+    }
+
+    /// Same as [`eval_full`](#method.eval_full), but continue with the other fields when the
+    /// evaluation of a record field fails, and return the errors together with the path of their
+    /// field (see [`eval::eval_partial`](../eval/fn.eval_partial.html)).
+    pub fn eval_partial(&mut self) -> Result<eval::PartialResult, Error> {
+        let (t, global_env) = self.prepare_eval()?;
+        eval::eval_partial(t, global_env, self, self.stack_limit).map_err(|e| e.into())
+    }
+
+    /// Evaluate the program to a weak head normal form, and return it as a closure together with
+    /// the global environment (see [`eval::eval_closure`](../eval/fn.eval_closure.html)). The
+    /// fields of a record or the elements of a list can then be evaluated on demand with
+    /// [`force`](#method.force) and [`force_full`](#method.force_full).
+    pub fn eval_closure(&mut self) -> Result<(eval::Closure, eval::Environment), Error> {
+        let (t, global_env) = self.prepare_eval()?;
+        let clos = eval::eval_closure(
+            eval::Closure::atomic_closure(t),
+            &global_env,
+            self,
+            self.stack_limit,
+        )?;
+        Ok((clos, global_env))
+    }
+
+    /// Evaluate a subterm of the result of [`eval_closure`](#method.eval_closure) to a weak head
+    /// normal form.
+    pub fn force(
+        &mut self,
+        clos: eval::Closure,
+        global_env: &eval::Environment,
+    ) -> Result<eval::Closure, Error> {
+        let stack_limit = self.stack_limit;
+        eval::eval_closure(clos, global_env, self, stack_limit).map_err(|e| e.into())
+    }
+
+    /// Fully evaluate a subterm of the result of [`eval_closure`](#method.eval_closure) (see
+    /// [`eval_full`](#method.eval_full)).
+    pub fn force_full(
+        &mut self,
+        clos: eval::Closure,
+        global_env: &eval::Environment,
+    ) -> Result<Term, Error> {
+        let stack_limit = self.stack_limit;
+        eval::eval_closure_full(clos, global_env, self, stack_limit).map_err(|e| e.into())
+    }
+
+    /// Same as [`eval`](#method.eval), but suspend the evaluation instead of failing when an
+    /// external term is encountered. The evaluation can then be continued with
+    /// [`resume`](#method.resume).
+    pub fn eval_resumable(&mut self) -> Result<eval::EvalStatus, Error> {
+        let (t, global_env) = self.prepare_eval()?;
+        eval::eval_resumable(t, global_env, self, self.stack_limit).map_err(|e| e.into())
+    }
+
+    /// Resume a suspended evaluation of the program, providing `value` for the pending external
+    /// term. `value` must be a closed term.
+    pub fn resume(
+        &mut self,
+        suspension: eval::Suspension,
+        value: RichTerm,
+    ) -> Result<eval::EvalStatus, Error> {
+        if let Some(manifest) = self.manifest.borrow_mut().as_mut() {
+            manifest
+                .externals
+                .push((suspension.name.clone(), manifest::to_value(value.as_ref())));
+        }
+
+        let value = transformations::transform(value, self).map_err(Error::ImportError)?;
+        suspension.resume(value, self).map_err(|e| e.into())
+    }
+
+    /// Parse if necessary and run the static analyses on the program (see the [analysis
+    /// module](../analysis/index.html)). Return the warnings found.
+    pub fn warnings(&mut self) -> Result<Vec<Warning>, Error> {
+        let t = self.parse_with_cache(self.main_id).map_err(Error::from)?;
+        let mut warnings = analysis::unused_contracts(&t);
+
+        let overrides: Vec<Vec<Ident>> = self
+            .overrides
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect();
+        warnings.extend(analysis::dead_fields(
+            &t,
+            &overrides,
+            &mut |path, parent| self.load_unresolved(path, parent),
+        ));
+        warnings.extend(analysis::arity_mismatches(&t));
+
+        Ok(warnings)
+    }
+
+    /// Parse if necessary and return the import graph of the program (see the [deps
+    /// module](../deps/index.html)). The imported files are parsed, but their imports are not
+    /// resolved.
+    pub fn import_graph(&mut self) -> Result<ImportGraph, Error> {
+        let t = self.parse_with_cache(self.main_id).map_err(Error::from)?;
+        let path = PathBuf::from(self.files.name(self.main_id));
+
+        Ok(deps::import_graph(&t, path, &mut |path, parent| {
+            let (path_buf, _) = self.locate(path, parent.clone());
+            let interface = self.get_interface(&String::from(path), parent.clone());
+            LoadedFile {
+                path: path_buf,
+                term: self.load_unresolved(path, parent).map(|(t, _)| t),
+                interface: interface.is_some(),
+            }
+        }))
+    }
+
+    /// Parse an imported file for the static analyses, without resolving it. The import resolution
+    /// populates the caches with transformed terms, which must not be done before evaluation.
+    /// Return `None` if the file cannot be read or parsed, or if it is a directory.
+    fn load_unresolved(
+        &mut self,
+        path: &str,
+        parent: Option<PathBuf>,
+    ) -> Option<(RichTerm, PathBuf)> {
+        let (path_buf, normalized) = self.locate(path, parent);
+        let file_id = match self.file_cache.get(&normalized) {
+            Some(file_id) => *file_id,
+            None => {
+                let source = fs::read_to_string(&path_buf).ok()?;
+                self.files.add(path_buf.as_os_str(), source)
+            }
+        };
+
+        let format = InputFormat::from_path(&path_buf).unwrap_or(InputFormat::Nickel);
+        self.parse_as(file_id, format).ok().map(|t| (t, path_buf))
+    }
+
+    /// Parse if necessary and typecheck the program. Return the type of each subterm of the
+    /// program with a position, indexed by its span (see
+    /// [`typecheck::type_check_with_table`](../typecheck/fn.type_check_with_table.html)).
+    pub fn type_table(&mut self) -> Result<typecheck::TypeTable, Error> {
+        let t = self.parse_with_cache(self.main_id).map_err(Error::from)?;
+        let global_env = self.mk_global_env()?;
+        let global = self.mk_global_typing_env(&global_env);
+        let cancel = self.cancellation_token.clone();
+        typecheck::type_check_with_table(&t, &global, self, cancel.as_ref())
+            .map(|(_, table)| table)
+            .map_err(Error::from)
+    }
+
+    /// Same as [`type_table`](#method.type_table), but index the types by the identity of the
+    /// subterms (see [`typecheck::NodeTypeTable`](../typecheck/type.NodeTypeTable.html)).
+    pub fn node_type_table(&mut self) -> Result<typecheck::NodeTypeTable, Error> {
+        let t = self.parse_with_cache(self.main_id).map_err(Error::from)?;
+        let global_env = self.mk_global_env()?;
+        let global = self.mk_global_typing_env(&global_env);
+        let cancel = self.cancellation_token.clone();
+        typecheck::type_check_with_node_table(&t, &global, self, cancel.as_ref())
+            .map(|(_, table)| table)
+            .map_err(Error::from)
+    }
+
+    /// Parse if necessary and typecheck the program. Return all the `Promise` and `Assume` blocks
+    /// of the program with their declared and inferred types, and whether they typecheck (see
+    /// [`typecheck::check_annotations`](../typecheck/fn.check_annotations.html)).
+    pub fn check_annotations(&mut self) -> Result<Vec<typecheck::CheckedAnnotation>, Error> {
+        let t = self.parse_with_cache(self.main_id).map_err(Error::from)?;
+        let global_env = self.mk_global_env()?;
+        let global = self.mk_global_typing_env(&global_env);
+        let cancel = self.cancellation_token.clone();
+        typecheck::check_annotations(&t, &global, self, cancel.as_ref()).map_err(Error::from)
+    }
+
+    /// Parse if necessary and typecheck the program. Return the fields that the record expression
+    /// at the given span must have, because they are either defined or required by the way this
+    /// expression is used (see [`type_table`](#method.type_table)).
+    ///
+    /// Return `None` if there is no subterm at this span, or if its type is not known to be a
+    /// record type.
+    pub fn record_fields_at(&mut self, span: &RawSpan) -> Result<Option<Vec<Ident>>, Error> {
+        Ok(self.type_table()?.get(span).and_then(Types::record_fields))
+    }
+
+    /// Parse if necessary and suggest values for the record field being written at the given span,
+    /// from the contracts of this field (see [`analysis::completions`](../analysis/fn.completions.html)).
+    ///
+    /// Contrary to [`record_fields_at`](#method.record_fields_at), the program is not typechecked:
+    /// this works on untyped configurations, but only uses the contracts written in the program.
+    pub fn completions_at(&mut self, span: &RawSpan) -> Result<Vec<Completion>, Error> {
+        let t = self.parse_with_cache(self.main_id).map_err(Error::from)?;
+        Ok(analysis::completions(&t, span))
+    }
+
+    /// Specialize the program against known values for some of its external inputs, and return
+    /// the residual program (see the [specialize module](../specialize/index.html)).
+    ///
+    /// The values must be closed terms. The residual program is not transformed, and can be
+    /// evaluated as any other parsed program.
+    pub fn specialize(&mut self, inputs: HashMap<String, RichTerm>) -> Result<RichTerm, Error> {
+        let t = self.parse_with_cache(self.main_id).map_err(Error::from)?;
+        let global_env = self.mk_global_env()?;
+        self.type_check(&t, &global_env).map_err(Error::from)?;
+        Ok(specialize::specialize(t, &inputs, &global_env, self))
+    }
+
+    /// Parse if necessary, typecheck and transform the program, and build the global
+    /// environment, in order to prepare for evaluation.
+    fn prepare_eval(&mut self) -> Result<(RichTerm, eval::Environment), Error> {
+        self.prepare_eval_with(None, true)
+    }
+
+    /// Same as [`prepare_eval`](#method.prepare_eval), but merge `overrides` on top of the program
+    /// after the overrides of [`add_override`](#method.add_override). The program is only
+    /// typechecked if `typecheck` is true: the threads of a parallel export don't typecheck the
+    /// program again, as the calling thread already did.
+    fn prepare_eval_with(
+        &mut self,
+        overrides: Option<RichTerm>,
+        typecheck: bool,
+    ) -> Result<(RichTerm, eval::Environment), Error> {
+        let t = self
+            .parse_with_cache(self.main_id)
+            .map_err(|e| Error::from(e))?;
+        let global_env = self.mk_global_env()?;
+        if typecheck {
+            self.type_check(&t, &global_env).map_err(Error::from)?;
+        }
+        let t = self
+            .overrides
+            .iter()
+            .map(|(path, value)| (path.as_slice(), value.clone()))
+            .chain(overrides.map(|value| (&[][..], value)))
+            .fold(t, |t, (path, value)| with_override(t, path, value));
+        let t = self.optimize(t);
+        let t = transformations::transform(t, self).map_err(|err| Error::ImportError(err))?;
+        // The imports are loaded now: update the files used to locate the trace events.
+        if let Some(events) = self.trace_events.borrow_mut().as_mut() {
+            events.files = self.files.clone();
+        }
+        Ok((t, global_env))
+    }
+
+    /// Parse a source file and number its nodes (see [`NodeId`](../term/struct.NodeId.html)). Do
+    /// not try to get it from the cache, and do not populate the cache at the end either.
+    fn parse(&mut self, file_id: FileId) -> Result<RichTerm, ParseError> {
+        let buf = self.files.source(file_id).clone();
+        let mut t = parser::grammar::TermParser::new()
+            .parse(file_id, Lexer::new(&buf))
+            .map_err(|err| ParseError::from_lalrpop(err, file_id))?;
+        t.assign_ids(file_id);
+        Ok(t)
+    }
+
+    /// Parse a source file and populate the corresponding entry in the cache, or just get it from
+    /// the term cache if it is there. Return a copy of the cached term.
+    fn parse_with_cache(&mut self, file_id: FileId) -> Result<RichTerm, ParseError> {
+        Ok(match self.term_cache.get(&file_id) {
+            Some(t) => t.clone(),
+            None => {
+                let t = self.parse(file_id)?;
+                self.term_cache.insert(file_id, t.clone());
+                t
+            }
+        })
+    }
+
+    /// Parse a source file in the given format. Do not try to get it from the cache, and do not
+    /// populate the cache at the end either.
+    fn parse_as(&mut self, file_id: FileId, format: InputFormat) -> Result<RichTerm, ParseError> {
+        let result = match format {
+            InputFormat::Nickel => return self.parse(file_id),
+            InputFormat::Json => serialize::from_json(self.files.source(file_id), file_id),
+            InputFormat::Yaml => serialize::from_yaml(self.files.source(file_id), file_id),
+        };
+
+        let mut t = result.map_err(|msg| {
+            ParseError::ForeignFormatError(file_id, String::from(format.name()), msg)
+        })?;
+        t.assign_ids(file_id);
+        Ok(t)
+    }
+
+    /// Load the interface file of an imported file, if it is a Nickel file and if it has one. Return
+    /// the label of the contract checking the implementation against its interface.
+    fn load_interface(&mut self, path: &Path) -> Result<Option<Label>, ParseError> {
+        if InputFormat::from_path(path).unwrap_or(InputFormat::Nickel) != InputFormat::Nickel {
+            return Ok(None);
+        }
+
+        let path = path.with_extension(INTERFACE_EXT);
+        if let Some(label) = self.interfaces.get(&path) {
+            return Ok(Some(label.clone()));
+        }
+
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(_) => return Ok(None),
+        };
+        let len = source.len();
+        let file_id = self.files.add(path.as_os_str(), source);
+        self.record_file(&path, file_id);
+        let buf = self.files.source(file_id).clone();
+        let ty = parser::grammar::InterfaceParser::new()
+            .parse(file_id, Lexer::new(&buf))
+            .map_err(|err| ParseError::from_lalrpop(err, file_id))?;
+
+        let label = parser::utils::mk_label(ty, file_id, 0, len);
+        self.interfaces.insert(path, label.clone());
+        Ok(Some(label))
+    }
+
+    /// Check the term of an imported file against its interface, if it has one.
+    fn with_interface(&mut self, path: &Path, t: RichTerm) -> Result<RichTerm, ParseError> {
+        Ok(match self.load_interface(path)? {
+            Some(label) => Term::Assume(label.types.clone(), label, t).into(),
+            None => t,
+        })
+    }
+
+    /// Record the statistics of a file imported for the first time, whose loading started at
+    /// `start`.
+    fn record_import(&mut self, file_id: FileId, path: PathBuf, start: Instant) {
+        self.import_stats.push(ImportStats {
+            file_id,
+            path,
+            load_time: start.elapsed(),
+            cache_hits: 0,
+        });
+    }
+
+    /// Return the statistics about the files and directories imported so far, in the order they
+    /// were first imported. Imports are resolved when the program is prepared for evaluation.
+    pub fn import_stats(&self) -> Vec<ImportStats> {
+        self.import_stats.clone()
+    }
+
+    /// Format a report on the cost of the imports, from the statistics of an evaluation: for each
+    /// file, the time spent loading it, the number of imports resolved from the cache, and the
+    /// number of steps spent evaluating its terms (see
+    /// [`EvalStats::reductions_by_file`](../eval/struct.EvalStats.html#structfield.reductions_by_file)).
+    /// The main file and the standard library are included, and files are sorted from the most to
+    /// the least evaluation steps.
+    pub fn import_report(&self, stats: &eval::EvalStats) -> String {
+        let mut ids: Vec<FileId> = self.import_stats.iter().map(|s| s.file_id).collect();
+        ids.extend(
+            stats
+                .reductions_by_file
+                .keys()
+                .filter(|id| self.import_stats.iter().all(|s| s.file_id != **id)),
+        );
+        let steps = |id: &FileId| stats.reductions_by_file.get(id).copied().unwrap_or(0);
+        ids.sort_by_key(|id| std::cmp::Reverse(steps(id)));
+
+        let rows: Vec<(String, String, String, String)> = ids
+            .iter()
+            .map(|id| {
+                let import = self.import_stats.iter().find(|s| s.file_id == *id);
+                let share = if stats.reductions == 0 {
+                    0.
+                } else {
+                    steps(id) as f64 * 100. / stats.reductions as f64
+                };
+                (
+                    import
+                        .map(|s| s.path.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| self.files.name(*id).to_string_lossy().into_owned()),
+                    import
+                        .map(|s| format!("{:.2}ms", s.load_time.as_secs_f64() * 1000.))
+                        .unwrap_or_else(|| String::from("-")),
+                    import
+                        .map(|s| s.cache_hits.to_string())
+                        .unwrap_or_else(|| String::from("-")),
+                    format!("{} ({:.1}%)", steps(id), share),
+                )
+            })
+            .collect();
+
+        let width = rows
+            .iter()
+            .map(|(file, _, _, _)| file.len())
+            .chain(std::iter::once(4))
+            .max()
+            .unwrap_or(0);
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{:width$}  {:>10}  {:>10}  steps",
+            "file",
+            "load",
+            "cache hits",
+            width = width
+        )
+        .unwrap();
+        for (file, load, hits, steps) in rows {
+            writeln!(
+                out,
+                "{:width$}  {:>10}  {:>10}  {}",
+                file,
+                load,
+                hits,
+                steps,
+                width = width
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// Return the files and directories loaded from the file system by the program, together with
+    /// their path.
+    ///
+    /// These are the main file, if the program has been read from a file, and the files and
+    /// directories it imports, directly or transitively. Imports are only loaded when the program
+    /// is prepared for evaluation, so that this list is complete only after a call to
+    /// [`eval`](#method.eval) or a similar method.
+    pub fn source_files(&self) -> Vec<(FileId, PathBuf)> {
+        let mut ids: Vec<FileId> = self.file_cache.values().copied().collect();
+        ids.push(self.main_id);
+
+        ids.into_iter()
+            .filter_map(|file_id| {
+                let path = Path::new(self.files.name(file_id));
+                if path.file_name() == Some(OsStr::new(DIR_INDEX)) {
+                    path.parent().map(|dir| (file_id, dir.to_path_buf()))
+                } else if path.is_file() {
+                    Some((file_id, path.to_path_buf()))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Reload a file or a directory of the program from the file system after it changed.
+    ///
+    /// The file is parsed again and its imports are resolved, loading the files which were not
+    /// imported before. Other files are left untouched, and the term of the file is only replaced
+    /// in the cache if these steps succeed. `file_id` must be one of the ids returned by
+    /// [`source_files`](#method.source_files).
+    pub fn reload(&mut self, file_id: FileId) -> Result<(), Error> {
+        let path = PathBuf::from(self.files.name(file_id));
+        let mk_error = |msg: String| {
+            Error::ImportError(ImportError::IOError(
+                path.to_string_lossy().into_owned(),
+                msg,
+                None,
+            ))
+        };
+
+        if path.file_name() == Some(OsStr::new(DIR_INDEX)) {
+            let dir = path.parent().unwrap_or_else(|| Path::new("."));
+            let (t, source) = list_dir(dir).map_err(mk_error)?;
+            self.files.update(file_id, source);
+            self.term_cache.insert(file_id, t);
+            return Ok(());
+        }
+
+        let source = fs::read_to_string(&path).map_err(|err| mk_error(format!("{}", err)))?;
+        self.files.update(file_id, source);
+
+        if file_id == self.main_id {
+            // The main program is transformed anew at each evaluation.
+            self.term_cache.remove(&file_id);
+            self.parse_with_cache(file_id)?;
+        } else {
+            let format = InputFormat::from_path(&path).unwrap_or(InputFormat::Nickel);
+            let t = self.parse_as(file_id, format)?;
+            let t = self.with_interface(&path, t)?;
+            let t = transformations::transform_from(t, Some(path), self)?;
+            self.term_cache.insert(file_id, t);
+        }
+
+        Ok(())
+    }
+
+    /// Pretty-print an error or a warning.
+    ///
+    /// This function is located here in `Program` because errors need a reference to `files` in
+    /// order to produce a diagnostic (see [`label_alt`](../error/fn.label_alt.html)).
+    pub fn report<E>(&mut self, error: E)
+    where
+        E: ToDiagnostic<FileId>,
+    {
+        let writer = StandardStream::stderr(ColorChoice::Always);
+        let config = codespan_reporting::term::Config::default();
+        let diagnostics = error.to_diagnostic(
+            &mut self.files,
+            self.file_cache.get("<stdlib/contracts.ncl>").copied(),
+        );
+
+        let result = diagnostics.iter().try_for_each(|d| {
+            codespan_reporting::term::emit(&mut writer.lock(), &config, &self.files, &d)
+        });
+        match result {
+            Ok(()) => (),
+            Err(err) => panic!(
+                "Program::report: could not print an error on stderr: {}",
+                err
+            ),
+        };
+    }
+
+    /// Format an error or a warning as [`report`](#method.report) does, but without colors, and
+    /// return the result instead of printing it.
+    ///
+    /// This lets a host display errors by its own means. A host using wide strings, as the Windows
+    /// API does, can transcode the result with [`str::encode_utf16`].
+    pub fn report_to_string<E>(&mut self, error: E) -> String
+    where
+        E: ToDiagnostic<FileId>,
+    {
+        let mut writer = NoColor::new(Vec::new());
+        let config = codespan_reporting::term::Config::default();
+        let diagnostics = error.to_diagnostic(
+            &mut self.files,
+            self.file_cache.get("<stdlib/contracts.ncl>").copied(),
+        );
+
+        for d in diagnostics.iter() {
+            // Writing to a vector can't fail.
+            codespan_reporting::term::emit(&mut writer, &config, &self.files, d)
+                .expect("Program::report_to_string: could not format a diagnostic");
+        }
+
+        String::from_utf8_lossy(&writer.into_inner()).into_owned()
+    }
+
+    /// Format an error or a warning in JSON, for tools consuming the errors of a program.
+    ///
+    /// The result is an array with one object per diagnostic, giving its severity, its error code
+    /// (see the [error_codes](../error_codes/index.html) module), if any, its message, its notes
+    /// and its labels. The location of a label is given as in [`SourceLocation`], by a file name
+    /// and zero-based lines and columns.
+    pub fn report_to_json<E>(&mut self, error: E) -> String
+    where
+        E: ToDiagnostic<FileId>,
+    {
+        let diagnostics = error.to_diagnostic(
+            &mut self.files,
+            self.file_cache.get("<stdlib/contracts.ncl>").copied(),
+        );
+
+        let files = &self.files;
+        let line_col = |file_id: FileId, index: usize| {
+            files
+                .location(file_id, index as u32)
+                .ok()
+                .map(|loc| json!({"line": loc.line.to_usize(), "column": loc.column.to_usize()}))
+        };
+
+        let diagnostics: Vec<_> = diagnostics
+            .iter()
+            .map(|d| {
+                let labels: Vec<_> = d
+                    .labels
+                    .iter()
+                    .map(|label| {
+                        json!({
+                            "primary": label.style == LabelStyle::Primary,
+                            "file": files.name(label.file_id).to_string_lossy(),
+                            "start": line_col(label.file_id, label.range.start),
+                            "end": line_col(label.file_id, label.range.end),
+                            "message": label.message,
+                        })
+                    })
+                    .collect();
+
+                json!({
+                    "severity": format!("{:?}", d.severity).to_lowercase(),
+                    "code": d.code,
+                    "message": d.message,
+                    "notes": d.notes,
+                    "labels": labels,
+                })
+            })
+            .collect();
+
+        serde_json::to_string_pretty(&diagnostics).unwrap()
+    }
+
+    /// Return the location of the primary span of an error, if any.
+    ///
+    /// This is the span highlighted first when the error is reported. It lets a host display
+    /// errors in its own interface, such as an editor, without going through the file database.
+    pub fn primary_location<E>(&mut self, error: &E) -> Option<SourceLocation>
+    where
+        E: ToDiagnostic<FileId>,
+    {
+        let diagnostics = error.to_diagnostic(
+            &mut self.files,
+            self.file_cache.get("<stdlib/contracts.ncl>").copied(),
+        );
+        let label = diagnostics
+            .iter()
+            .flat_map(|d| d.labels.iter())
+            .find(|label| label.style == LabelStyle::Primary)?;
+
+        let line_col = |index: usize| {
+            self.files
+                .location(label.file_id, index as u32)
+                .ok()
+                .map(|loc| (loc.line.to_usize(), loc.column.to_usize()))
+        };
+
+        Some(SourceLocation {
+            file: self
+                .files
+                .name(label.file_id)
+                .to_string_lossy()
+                .into_owned(),
+            start: line_col(label.range.start)?,
+            end: line_col(label.range.end)?,
+        })
+    }
+}
+
+impl ImportResolver for Program {
+    fn resolve(
+        &mut self,
+        path: &String,
+        parent: Option<PathBuf>,
+        pos: &Option<RawSpan>,
+    ) -> Result<(ResolvedTerm, FileId), ImportError> {
+        let start = Instant::now();
+        let (path_buf, normalized) = self.locate(path, parent);
+
+        if let Some(file_id) = self.file_cache.get(&normalized) {
+            if let Some(stats) = self.import_stats.iter_mut().find(|s| s.file_id == *file_id) {
+                stats.cache_hits += 1;
+            }
+            return Ok((ResolvedTerm::FromCache(), *file_id));
+        }
+
+        if path_buf.is_dir() {
+            let result = self.resolve_dir(path, path_buf.clone(), normalized, pos)?;
+            self.record_import(result.1, path_buf, start);
+            return Ok(result);
+        }
+
+        let mut buffer = String::new();
+        let file_id = fs::File::open(&path_buf)
+            .and_then(|mut file| file.read_to_string(&mut buffer))
+            .map(|_| self.files.add(path_buf.as_os_str(), buffer))
+            .map_err(|err| ImportError::IOError(path.clone(), format!("{}", err), pos.clone()))?;
+        self.file_cache.insert(normalized, file_id.clone());
+        self.record_file(&path_buf, file_id);
+
+        let format = InputFormat::from_path(&path_buf).unwrap_or(InputFormat::Nickel);
+        let t = self
+            .parse_as(file_id, format)
+            .map_err(|err| ImportError::ParseError(err, pos.clone()))?;
+        let t = self
+            .with_interface(&path_buf, t)
+            .map_err(|err| ImportError::ParseError(err, pos.clone()))?;
+        let t = self.optimize(t);
+        self.record_import(file_id, path_buf.clone(), start);
+        Ok((ResolvedTerm::FromFile(t, path_buf), file_id))
+    }
+
+    fn get(&self, file_id: FileId) -> Option<RichTerm> {
+        self.term_cache.get(&file_id).cloned()
+    }
+
+    fn get_id(&self, path: &String, parent: Option<PathBuf>) -> Option<FileId> {
+        let (_, normalized) = self.locate(path, parent);
+        self.file_cache.get(&normalized).cloned()
+    }
+
+    fn insert(&mut self, file_id: FileId, term: RichTerm) {
+        self.term_cache.insert(file_id, term);
+    }
+
+    fn get_interface(&mut self, path: &String, parent: Option<PathBuf>) -> Option<Types> {
+        // Errors are reported when the import is resolved.
+        let (path_buf, _) = self.locate(path, parent);
+        match self.load_interface(&path_buf) {
+            Ok(label) => label.map(|label| label.types),
+            Err(_) => None,
+        }
+    }
+}
+
+/// The extension of interface files (see the [module documentation](index.html)).
+const INTERFACE_EXT: &str = "ncli";
+
+/// Provide mockup import resolvers for testing purpose.
+#[cfg(test)]
+pub mod resolvers {
+    use super::*;
+
+    /// A dummy resolver that panics when asked to do something. Used to test code that contains no
+    /// import.
+    pub struct DummyResolver {}
+
+    impl ImportResolver for DummyResolver {
+        fn resolve(
+            &mut self,
+            _path: &String,
+            _parent: Option<PathBuf>,
+            _pos: &Option<RawSpan>,
+        ) -> Result<(ResolvedTerm, FileId), ImportError> {
+            panic!("program::resolvers: dummy resolver should not have been invoked");
+        }
+
+        fn insert(&mut self, _file_id: FileId, _term: RichTerm) {
+            panic!("program::resolvers: dummy resolver should not have been invoked");
+        }
+
+        fn get(&self, _file_id: FileId) -> Option<RichTerm> {
+            panic!("program::resolvers: dummy resolver should not have been invoked");
+        }
+
+        fn get_id(&self, _path: &String, _parent: Option<PathBuf>) -> Option<FileId> {
+            panic!("program::resolvers: dummy resolver should not have been invoked");
+        }
+    }
+
+    /// Resolve imports from a mockup file database. Used to test imports without accessing the
+    /// file system.
+    #[derive(Default)]
+    pub struct SimpleResolver {
+        files: Files<String>,
+        file_cache: HashMap<String, FileId>,
+        term_cache: HashMap<FileId, Option<RichTerm>>,
+    }
+
+    impl SimpleResolver {
+        pub fn new() -> SimpleResolver {
+            SimpleResolver {
+                files: Files::new(),
+                file_cache: HashMap::default(),
+                term_cache: HashMap::default(),
+            }
+        }
+
+        /// Add a mockup file to available imports.
+        pub fn add_source(&mut self, name: String, source: String) {
+            let id = self.files.add(&name, source);
+            self.file_cache.insert(name, id);
+        }
+    }
+
+    impl ImportResolver for SimpleResolver {
+        fn resolve(
+            &mut self,
+            path: &String,
+            _parent: Option<PathBuf>,
+            pos: &Option<RawSpan>,
+        ) -> Result<(ResolvedTerm, FileId), ImportError> {
+            let file_id =
+                self.file_cache
+                    .get(path)
+                    .map(|id| id.clone())
+                    .ok_or(ImportError::IOError(
+                        path.clone(),
+                        String::from("Import not found by the mockup resolver."),
+                        pos.clone(),
+                    ))?;
+
+            if self.term_cache.contains_key(&file_id) {
+                Ok((ResolvedTerm::FromCache(), file_id))
+            } else {
+                self.term_cache.insert(file_id, None);
+                let buf = self.files.source(file_id);
+                let t = parser::grammar::TermParser::new()
+                    .parse(file_id, Lexer::new(&buf))
+                    .map_err(|e| ParseError::from_lalrpop(e, file_id))
+                    .map_err(|e| ImportError::ParseError(e, pos.clone()))?;
+                Ok((ResolvedTerm::FromFile(t, PathBuf::new()), file_id))
+            }
+        }
+
+        fn insert(&mut self, file_id: FileId, term: RichTerm) {
+            self.term_cache.insert(file_id, Some(term));
+        }
+
+        fn get(&self, file_id: FileId) -> Option<RichTerm> {
+            self.term_cache
+                .get(&file_id)
+                .map(|opt| opt.as_ref())
+                .flatten()
+                .cloned()
+        }
+
+        fn get_id(&self, path: &String, _parent: Option<PathBuf>) -> Option<FileId> {
+            self.file_cache.get(path).copied()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{EvalError, SerializationError};
+    use crate::identifier::Ident;
+    use crate::label::ty_path;
+    use crate::serialize::{ExportFormat, ExportOptions, KeyOrder, Newline, NonFinitePolicy};
+    use crate::term::BinaryOp;
+    use std::io::Cursor;
+
+    pub(super) fn eval_string(s: &str) -> Result<Term, Error> {
+        let src = Cursor::new(s);
+
+        let mut p = Program::new_from_source(src, "<test>").map_err(|io_err| {
+            Error::EvalError(EvalError::Other(format!("IO error: {}", io_err), None))
+        })?;
+        p.eval()
+    }
+
+    /// Assert if a given Nickel expression evaluates to a record, given as a vector of bindings
+    /// Records are lazy, thus we need to force the evaluation of each field. Since `merge`
+    /// replaces subterms with dummy fresh variables, we have to re-evaluate the whole expression
+    /// to verify each field
+    fn assert_eval_to_record(s: &str, res: Vec<(&str, Term)>) {
+        let mut term = eval_string(s);
+
+        //Check that the record does not contain extra keys
+        if let Ok(Term::Record(ref mut m)) = term {
+            for (i, t) in res {
+                m.remove(&Ident::from(i))
+                    .unwrap_or_else(|| panic!(format!("Could not find field {} in result", i)));
+
+                let proj = format!("({}).{}", s, i);
+                if let Ok(proj_res) = eval_string(&proj) {
+                    assert_eq!(proj_res, t);
+                } else {
+                    panic!(format!("evaluation of the projection on {} failed", i));
+                }
+            }
+
+            assert!(m.is_empty());
+        } else if let Err(err) = term {
+            panic!("evaluation failed: {:?}", err);
+        }
+    }
+
+    #[test]
+    fn function_app() {
+        let res = eval_string("(fun h => h) 3");
+
+        assert_eq!(Ok(Term::Num(3.0)), res);
+    }
+
+    #[test]
+    fn let_binding() {
+        let res = eval_string("let f = fun f => fun y => f (f y) in f (fun h => h) 3");
+
+        assert_eq!(Ok(Term::Num(3.0)), res);
+    }
+
+    #[test]
+    fn let_rec_binding() {
+        assert_eq!(
+            eval_string("let rec sum = fun n => if isZero n then 0 else n + sum (n + -1) in sum 4"),
+            Ok(Term::Num(10.0))
+        );
+        assert_eq!(
+            eval_string("let rec l = [1, l] in length (elemAt (elemAt l 1) 1)"),
+            Ok(Term::Num(2.0))
+        );
+        // A plain let binding is not recursive.
+        assert_eq!(
+            eval_string("let f = 1 in let f = fun n => f in f 0"),
+            Ok(Term::Num(1.0))
+        );
+        // The binder shadows the enclosing bindings in the bound expression.
+        assert_eq!(
+            eval_string(
+                "let f = 1 in let rec f = fun n => if isZero n then 0 else f (n + -1) in f 3"
+            ),
+            Ok(Term::Num(0.0))
+        );
+    }
+
+    #[test]
+    fn destructuring() {
+        assert_eq!(
+            eval_string("let {a, b} = {a = 1; b = 2} in a + b"),
+            Ok(Term::Num(3.0))
+        );
+        assert_eq!(
+            eval_string("let [x, y] = [1, 2] in x + y + y"),
+            Ok(Term::Num(5.0))
+        );
+        assert_eq!(
+            eval_string("let {a = [x, {b}]} = {a = [1, {b = 2}]} in x + b"),
+            Ok(Term::Num(3.0))
+        );
+        assert_eq!(
+            eval_string("(fun {host, port} => port) {host = \"h\"; port = 80}"),
+            Ok(Term::Num(80.0))
+        );
+        assert_eq!(
+            eval_string("(fun {a} [b] => a + b) {a = 1} [2]"),
+            Ok(Term::Num(3.0))
+        );
+        assert_eq!(
+            eval_string("let {a = x} = {a = 1; b = 2} in x"),
+            Ok(Term::Num(1.0))
+        );
+
+        // Fields and elements are only accessed when the corresponding variable is used.
+        assert_eq!(eval_string("let {a, b} = {a = 1} in a"), Ok(Term::Num(1.0)));
+        assert!(eval_string("let {a, b} = {a = 1} in b").is_err());
+        assert!(eval_string("let [x] = [] in x").is_err());
+    }
+
+    #[test]
+    fn plus() {
+        let res = eval_string("34 + (if true then 2 else 222)");
+
+        assert_eq!(Ok(Term::Num(36.0)), res);
+    }
+
+    #[test]
+    fn dynamic_if() {
+        let res =
+            eval_string("let g  = fun x => if x then 0 else false in g ((fun x => true) 23 )");
+
+        assert_eq!(Ok(Term::Num(0.0)), res);
+    }
+
+    #[test]
+    fn parse_error() {
+        let res = eval_string("let g  = funky x => x in g true");
+
+        if let Ok(_) = res {
+            panic!("This test should have returned Err()!");
+        }
+    }
+
+    #[test]
+    fn simple_type_check() {
+        let res = eval_string("let x = 5 in if isNum x then true else 1");
+
+        assert_eq!(Ok(Term::Bool(true)), res);
+    }
+
+    #[test]
+    fn fixpoint() {
+        let res = eval_string(
+            "let Y = (fun f => (fun x => f (x x)) (fun x => f (x x))) in
+let g = Y (fun g => (fun x => if x  then (g false) else 4)) in
+g true",
+        );
+
+        assert_eq!(Ok(Term::Num(4.)), res);
+    }
+
+    #[test]
+    fn type_contracts() {
+        let res = eval_string(
+            "let safePlus = Promise(Num -> Num -> Num , fun x => fun y => x + y) in
+safePlus Promise(Num , 54) Promise(Num , 6)",
+        );
+
+        assert_eq!(Ok(Term::Num(60.)), res);
+    }
+
+    #[test]
+    fn fibonacci() {
+        let res = eval_string(
+            "let Y = Assume(((Num -> Num) -> Num -> Num) -> Num -> Num, fun f => (fun x => f (x x)) (fun x => f (x x))) in
+let dec = Promise(Num -> Num, fun x => x + (-1)) in
+let or = Promise(Bool -> Bool -> Bool, fun x => fun y => if x then x else y) in
+
+let fibo = Promise(Num -> Num, Y (fun fibo =>
+    (fun x => if or (isZero x) (isZero (dec x)) then 1 else (fibo (dec x)) + (fibo (dec (dec x)))))) in
+let val = Promise(Num, 4) in
+fibo val",
+        );
+
+        assert_eq!(Ok(Term::Num(5.)), res);
+    }
+
+    #[test]
+    fn promise_fail() {
+        let res = eval_string(
+            "let bool = fun l t => if isBool t then t else blame l in
+
+Promise(Bool, 5)
+            ",
+        );
+
+        if let Ok(_) = res {
+            panic!("This expression should return an error!");
+        }
+    }
+
+    #[test]
+    fn flat_contract_fail() {
+        let res = eval_string(
+            "let alwaysTrue = fun l t => let boolT = Assume(Bool, t) in
+    if boolT then boolT else blame l in
+Assume(#alwaysTrue, false)
+",
+        );
+        if let Ok(_) = res {
+            panic!("This expression should return an error!");
+        }
+    }
+
+    #[test]
+    fn flat_higher_order_contract() {
+        let res = eval_string(
+            "let alwaysTrue = fun l t => let boolT = Assume(Bool, t) in
+    if boolT then boolT else blame l in
+let alwaysFalse = fun l t => let boolT = Assume(Bool, t) in
+    if boolT then  blame l else boolT in
+let not = fun b => if b then false else true in
+Assume(#alwaysTrue -> #alwaysFalse, not ) true
+",
+        );
+
+        assert_eq!(Ok(Term::Bool(false)), res);
+    }
+
+    #[test]
+    fn parametric_contract() {
+        let equals = "let Equals = fun n l t => if t == n then t else blame l in ";
+
+        assert_eq!(
+            eval_string(&format!("{} Assume(#(Equals 1), 1)", equals)),
+            Ok(Term::Num(1.))
+        );
+        eval_string(&format!("{} Assume(#(Equals 1), 2)", equals)).unwrap_err();
+        assert_eq!(
+            eval_string(&format!(
+                "{} let f = Promise(#(Equals 1) -> #(Equals 1), fun x => x) in f 1",
+                equals
+            )),
+            Ok(Term::Num(1.))
+        );
+    }
+
+    #[test]
+    fn safe_id() {
+        let res = eval_string(
+            "let id = Assume(forall a . a -> a, fun x => x) in
+            id false",
+        );
+        assert_eq!(Ok(Term::Bool(false)), res);
+    }
+
+    #[test]
+    fn id_fail() {
+        let res = eval_string(
+            "let id = Assume(forall a . a -> a, fun x => false) in
+            id false",
+        );
+        if let Ok(_) = res {
+            panic!("This expression should return an error!");
+        }
+    }
+
+    #[test]
+    fn safe_higher_order() {
+        let res = eval_string(
+            "let to_bool = Assume(forall a . (a -> Bool) -> a -> Bool,
+            fun f => fun x => f x) in
+            to_bool (fun x => true) 4 ",
+        );
+        assert_eq!(Ok(Term::Bool(true)), res);
+    }
+
+    #[test]
+    fn apply_twice() {
+        let res = eval_string(
+            "let twice = Assume(forall a . (a -> a) -> a -> a,
+            fun f => fun x => f (f x)) in
+            twice (fun x => x + 1) 3",
+        );
+        assert_eq!(Ok(Term::Num(5.)), res);
+    }
+
+    #[test]
+    fn string_contracts() {
+        let res = eval_string("Assume(Str, \"hello\")");
+        assert_eq!(res, Ok(Term::Str("hello".to_string())));
+
+        let res = eval_string("Assume(Str, \"hello\" ++ \" world!\")");
+        assert_eq!(res, Ok(Term::Str("hello world!".to_string())));
+    }
+
+    #[test]
+    fn enum_simple() {
+        let res = eval_string("Promise(< (| foo, bar, |) >, `foo)");
+        assert_eq!(res, Ok(Term::Enum(Ident("foo".to_string()))));
+
+        let res = eval_string("Promise(forall r. (< (| foo, bar, | r ) >), `bar)");
+        assert_eq!(res, Ok(Term::Enum(Ident("bar".to_string()))));
+
+        eval_string("Promise(< (| foo, bar, |) >, `far)").unwrap_err();
+    }
+
+    #[test]
+    fn enum_complex() {
+        let res = eval_string(
+            "let f = Promise(forall r. < (| foo, bar, | r ) > -> Num,
+        fun x => switch { foo => 1, bar => 2, _ => 3, } x) in
+        f `bar",
+        );
+        assert_eq!(res, Ok(Term::Num(2.)));
+
+        let res = eval_string(
+            "let f = Promise(forall r. < (| foo, bar, | r ) > -> Num,
+        fun x => switch { foo => 1, bar => 2, _ => 3, } x) in
+        f `boo",
+        );
+        assert_eq!(res, Ok(Term::Num(3.)));
+
+        eval_string(
+            "let f = Promise(< (| foo, bar, |) > -> Num,
+        fun x => switch { foo => 1, bar => 2, } x) in
+        f `boo",
+        )
+        .unwrap_err();
+
+        // This test checks that the terms of a switch are closured
+        assert_eq!(
+            eval_string(
+                "let x = 3 in
+            switch { foo => 1, _ => x, } (3 + 2)"
+            ),
+            Ok(Term::Num(3.))
+        );
+    }
+
+    #[test]
+    fn enum_str_conversions() {
+        assert_eq!(
+            eval_string("enumToStr `foo"),
+            Ok(Term::Str(String::from("foo")))
+        );
+        assert_eq!(
+            eval_string("enumFromStr <(| foo, bar, |)> \"bar\""),
+            Ok(Term::Enum(Ident::from("bar")))
+        );
+        assert_eq!(
+            eval_string("enumToStr (enumFromStr <(| foo, bar, |)> (\"fo\" ++ \"o\"))"),
+            Ok(Term::Str(String::from("foo")))
+        );
+
+        eval_string("enumFromStr <(| foo, bar, |)> \"baz\"").unwrap_err();
+        eval_string("enumToStr \"foo\"").unwrap_err();
+    }
+
+    #[test]
+    fn path_primops() {
+        let eval_str = |s: &str| match eval_string(s) {
+            Ok(Term::Str(s)) => s,
+            res => panic!("expected a string, got {:?}", res),
+        };
+
+        assert_eq!(eval_str("pathJoin \"conf\" \"app.ncl\""), "conf/app.ncl");
+        assert_eq!(eval_str("pathJoin \"conf\" \"/etc/app\""), "/etc/app");
+        assert_eq!(
+            eval_str("pathBasename (pathJoin \"/srv/\" \"www/\")"),
+            "www"
+        );
+        assert_eq!(eval_str("pathExtension \"a/b.tar.gz\""), "gz");
+        assert_eq!(eval_str("pathExtension \"a/.bashrc\" ++ \"!\""), "!");
+
+        eval_string("pathJoin \"conf\" 1").unwrap_err();
+        eval_string("pathBasename 1").unwrap_err();
+    }
+
+    #[test]
+    fn list_sort() {
+        assert_eq!(
+            eval_string("listSortBy (fun x => x) [3, 1, 2] == [1, 2, 3]"),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string("listSortBy (fun x => x) [\"b\", \"c\", \"a\"] == [\"a\", \"b\", \"c\"]"),
+            Ok(Term::Bool(true))
+        );
+        // The sort is stable.
+        assert_eq!(
+            eval_string(
+                "map (fun r => r.v) (listSortBy (fun r => r.k) \
+                    [{k = 2; v = 1}, {k = 1; v = 2}, {k = 2; v = 3}]) == [2, 1, 3]"
+            ),
+            Ok(Term::Bool(true))
+        );
+
+        let desc = "fun x y => if x == y then `Equal \
+            else if head (listSortBy (fun z => z) [x, y]) == x then `Greater \
+            else `Lesser";
+        assert_eq!(
+            eval_string(&format!(
+                "listSort ({}) [2, 5, 1, 4, 3] == [5, 4, 3, 2, 1]",
+                desc
+            )),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string("listSort (fun x y => `Equal) [] == []"),
+            Ok(Term::Bool(true))
+        );
+
+        assert_eq!(
+            eval_string("listSortBy (fun x => x) [0.5, -1, 0, -0.5] == [-1, -0.5, 0, 0.5]"),
+            Ok(Term::Bool(true))
+        );
+
+        eval_string("listSort (fun x y => 1) [1, 2]").unwrap_err();
+        eval_string("listSortBy (fun x => x) [1, \"a\"]").unwrap_err();
+        eval_string("listSortBy (fun x => x) [true, false]").unwrap_err();
+        eval_string("listSort (fun x y => `Equal) 1").unwrap_err();
+    }
+
+    #[test]
+    fn list_zip_range() {
+        assert_eq!(
+            eval_string("listZip [1, 2, 3] [\"a\", \"b\"] == [[1, \"a\"], [2, \"b\"]]"),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string("listZipWith (fun x y => x + y) [1, 2] [10, 20, 30] == [11, 22]"),
+            Ok(Term::Bool(true))
+        );
+        // Elements are not evaluated if not needed.
+        assert_eq!(
+            eval_string("length (listZipWith (fun x y => x + y) [1, blame 1] [1, 2])"),
+            Ok(Term::Num(2.))
+        );
+
+        assert_eq!(
+            eval_string("listRange 0 10 3 == [0, 3, 6, 9]"),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string("listRange 3 0 (-1) == [3, 2, 1]"),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string("listRange 8080 (8080 + 2) 1 == [8080, 8081]"),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(eval_string("length (listRange 5 0 1)"), Ok(Term::Num(0.)));
+
+        // The primitives taking several arguments can be partially applied.
+        assert_eq!(
+            eval_string("let g = listZipWith (fun x y => x + y) in g [1] [2] == [3]"),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string("let from0 = listRange 0 in from0 2 1 == [0, 1]"),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string("map (fun f => f 1) [listRange 0 3] == [[0, 1, 2]]"),
+            Ok(Term::Bool(true))
+        );
+
+        match eval_string("length (listRange 0 0x20_0000_0000_0000 1)") {
+            Err(Error::EvalError(EvalError::Other(msg, _))) => assert!(msg.contains("maximum")),
+            err => panic!(
+                "expected an error on the length of the range, got {:?}",
+                err
+            ),
+        };
+        eval_string("listRange 0 10 0").unwrap_err();
+        eval_string("listRange 0 \"10\" 1").unwrap_err();
+        eval_string("listZip [1] 2").unwrap_err();
+        eval_string("listZipWith (fun x y => x) [1] 2").unwrap_err();
+    }
+
+    #[test]
+    fn iterate() {
+        assert_eq!(
+            eval_string("iterate 3 (fun l => l @ [length l]) [] == [0, 1, 2]"),
+            Ok(Term::Bool(true))
+        );
+        // The initial value is not evaluated if there is no step.
+        assert_eq!(eval_string("iterate 0 (fun x => x) 1"), Ok(Term::Num(1.)));
+        assert_eq!(
+            eval_string("length [iterate 0 (fun x => x) (1 + true)]"),
+            Ok(Term::Num(1.))
+        );
+
+        // The loop is native: it doesn't overflow the stack, unlike the equivalent recursion.
+        assert_eq!(
+            eval_string("iterate 100000 (fun x => x + 1) 0"),
+            Ok(Term::Num(100000.))
+        );
+
+        assert_eq!(
+            eval_string("let twice = iterate 2 in twice (fun x => x + x) 1"),
+            Ok(Term::Num(4.))
+        );
+
+        eval_string("iterate (-1) (fun x => x) 0").unwrap_err();
+        eval_string("iterate 1.5 (fun x => x) 0").unwrap_err();
+        eval_string("iterate 2 (fun x => x + 1) \"a\"").unwrap_err();
+    }
+
+    #[test]
+    fn list_group_partition() {
+        let groups = "listGroupBy (fun r => r.env) [ \
+            {env = \"prod\"; port = 1}, \
+            {env = \"dev\"; port = 2}, \
+            {env = \"prod\"; port = 3}]";
+        assert_eq!(
+            eval_string(&format!(
+                "let g = {} in map (fun r => r.port) (g.prod) == [1, 3]",
+                groups
+            )),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string(&format!("length (fieldsOf ({}))", groups)),
+            Ok(Term::Num(2.))
+        );
+
+        let parts = "listPartition (fun x => isZero x) [0, 1, 0, 2]";
+        assert_eq!(
+            eval_string(&format!("let p = {} in p.matched == [0, 0]", parts)),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string(&format!("let p = {} in p.rest == [1, 2]", parts)),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string(
+                "Promise(List Num, let p = listPartition (fun x => isZero x) [0, 1] in p.rest) == [1]"
+            ),
+            Ok(Term::Bool(true))
+        );
+
+        eval_string("listGroupBy (fun x => x) [1]").unwrap_err();
+        eval_string("listPartition (fun x => x) [1]").unwrap_err();
+    }
+
+    #[test]
+    fn record_intersect_difference_rename() {
+        let r = "{a = 1; b = 2; c = 3}";
+        assert_eq!(
+            eval_string(&format!(
+                "fieldsOf (recordIntersect {} {{b = 0; c = 0; d = 0}}) == [\"b\", \"c\"]",
+                r
+            )),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string(&format!(
+                "let r = recordDifference {} {{b = 0}} in r.a + r.c",
+                r
+            )),
+            Ok(Term::Num(4.))
+        );
+        assert_eq!(
+            eval_string(&format!(
+                "let r = recordRenameField \"a\" \"z\" {} in r.z + (if hasField \"a\" r then 10 else 0)",
+                r
+            )),
+            Ok(Term::Num(1.))
+        );
+
+        assert_eq!(
+            eval_string(&format!(
+                "let rename = recordRenameField \"a\" in (rename \"z\" {}).z",
+                r
+            )),
+            Ok(Term::Num(1.))
+        );
+
+        eval_string(&format!("recordRenameField \"x\" \"y\" {}", r)).unwrap_err();
+        eval_string(&format!("recordRenameField \"a\" \"b\" {}", r)).unwrap_err();
+        eval_string("recordIntersect {a = 1} 1").unwrap_err();
+    }
+
+    #[test]
+    fn record_get_path() {
+        let r = "{a = {b = {c = 1}; d = 2}; e = blame 0}";
+        let get = |path: &str, field: &str| format!("(recordGetPath {} {}).{}", path, r, field);
+
+        assert_eq!(
+            eval_string(&get("[\"a\", \"b\", \"c\"]", "status")),
+            Ok(Term::Enum(Ident::from("Some")))
+        );
+        assert_eq!(
+            eval_string(&get("[\"a\", \"b\", \"c\"]", "value")),
+            Ok(Term::Num(1.))
+        );
+        assert_eq!(
+            eval_string(&get("[\"a\", \"x\"]", "status")),
+            Ok(Term::Enum(Ident::from("None")))
+        );
+        eval_string(&get("[\"a\", \"x\"]", "value")).unwrap_err();
+        assert_eq!(
+            eval_string(&get("[\"a\", \"d\", \"f\"]", "status")),
+            Ok(Term::Enum(Ident::from("None")))
+        );
+        // The value at the end of the path is not evaluated.
+        assert_eq!(
+            eval_string(&get("[\"e\"]", "status")),
+            Ok(Term::Enum(Ident::from("Some")))
+        );
+
+        eval_string(&format!("recordGetPath [1] {}", r)).unwrap_err();
+        eval_string(&format!("recordGetPath \"a\" {}", r)).unwrap_err();
+        eval_string("recordGetPath [] 1").unwrap_err();
+    }
+
+    #[test]
+    fn dyn_access_opt() {
+        assert_eq!(
+            eval_string("({a = 1}.$?\"a\").status"),
+            Ok(Term::Enum(Ident::from("Some")))
+        );
+        assert_eq!(eval_string("({a = 1}.$?\"a\").value"), Ok(Term::Num(1.)));
+        assert_eq!(
+            eval_string("({a = 1}.$?\"b\").status"),
+            Ok(Term::Enum(Ident::from("None")))
+        );
+        eval_string("({a = 1}.$?\"b\").value").unwrap_err();
+        // The value of the field is not evaluated.
+        assert_eq!(
+            eval_string("({a = blame 0}.$?\"a\").status"),
+            Ok(Term::Enum(Ident::from("Some")))
+        );
+        eval_string("let r = 1 in r.$?\"a\"").unwrap_err();
+    }
+
+    #[test]
+    fn list_unique() {
+        assert_eq!(
+            eval_string("listUnique [1, 2, 1, 3, 2] == [1, 2, 3]"),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string("length (listUnique [{a = [1]}, {a = [0 + 1]}, {a = [2]}])"),
+            Ok(Term::Num(2.))
+        );
+        assert_eq!(
+            eval_string("length (listUnique [{a = 1; b = `x}, {b = `x; a = 1}, 0, -0, \"0\"])"),
+            Ok(Term::Num(3.))
+        );
+        assert_eq!(
+            eval_string("length (listUnique [(fun x => x), (fun x => x), (fun y => y)])"),
+            Ok(Term::Num(2.))
+        );
+        eval_string("listUnique 1").unwrap_err();
+    }
+
+    #[test]
+    fn row_types() {
+        eval_string("Assume((| |), 123)").unwrap_err();
+    }
+
+    #[test]
+    fn record_contracts() {
+        assert_eq!(
+            eval_string("Assume({ {| a: Num, b: Bool, |} }, { a = 1; b = true; }).a"),
+            Ok(Term::Num(1.))
+        );
+        assert_eq!(
+            eval_string("Assume({ _ : Num }, { a = 1; b = 2; }).b"),
+            Ok(Term::Num(2.))
+        );
+        assert_eq!(
+            eval_string("Assume(forall r. { {| a: Num, | r} }, { a = 1; b = true; }).b"),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string("Assume({ {| f: Num -> Num, |} }, { f = fun x => x + 1; }).f 1"),
+            Ok(Term::Num(2.))
+        );
+
+        eval_string("Assume({ {| a: Num, b: Bool, |} }, { a = 1; b = 2; }).b").unwrap_err();
+        eval_string("Assume({ {| a: Num, |} }, { a = 1; b = 2; }).a").unwrap_err();
+        eval_string("Assume({ {| a: Num, |} }, { b = 2; }).b").unwrap_err();
+        eval_string("Assume({ {| a: Num, |} }, 1)").unwrap_err();
+        eval_string("Assume({ _ : Num }, { a = 1; b = true; }).b").unwrap_err();
+        eval_string("Assume({ {| f: Num -> Num, |} }, { f = fun x => x; }).f true").unwrap_err();
+    }
+
+    #[test]
+    fn list_contracts() {
+        let port = "let Port = fun l t => if isNum t then t else blame (tag \"not a port\" l) in ";
+
+        // Elements are only checked when they are accessed.
+        assert_eq!(
+            eval_string(&format!(
+                "{}let ports = Assume(List #Port, [80, \"a\", 443]) in elemAt ports 2",
+                port
+            )),
+            Ok(Term::Num(443.))
+        );
+        assert_eq!(
+            eval_string("length (Assume(List Num, [1, true]))"),
+            Ok(Term::Num(2.))
+        );
+        assert_eq!(
+            eval_string("elemAt (Assume(List (Num -> Num), [fun x => x + 1])) 0 1"),
+            Ok(Term::Num(2.))
+        );
+        assert_eq!(
+            eval_string("Promise(List Num -> Num, fun l => length l) [1, 2]"),
+            Ok(Term::Num(2.))
+        );
+
+        match eval_string(&format!(
+            "{}let ports = Assume(List #Port, [80, \"a\"]) in elemAt ports 1",
+            port
+        )) {
+            Err(Error::EvalError(EvalError::BlameError(l, _))) => assert_eq!(l.tag, "not a port"),
+            res => panic!("expected a blame error, got {:?}", res),
+        }
+        eval_string("Assume(List Num, 1)").unwrap_err();
+        eval_string("head (Assume(List { {| a: Num, |} }, [{b = 1;}]))").unwrap_err();
+        eval_string("elemAt (Assume(List (Num -> Num), [fun x => x])) 0 true").unwrap_err();
+    }
+
+    #[test]
+    fn compiled_record_contracts() {
+        // Only the shape of the record is checked by the contract itself: the fields are checked
+        // when they are evaluated.
+        assert_eq!(
+            eval_string("Assume({ {| a: Num, b: Str, |} }, { a = 1; b = 2; }).a"),
+            Ok(Term::Num(1.))
+        );
+        assert_eq!(
+            eval_string(
+                "let l = map (fun r => Assume({ {| a: Num, b: List, |} }, r).a) \
+                    [{ a = 1; b = []; }, { a = 2; b = [1]; }] in
+                elemAt l 1"
+            ),
+            Ok(Term::Num(2.))
+        );
+        let blame_tag = |s: &str| match eval_string(s) {
+            Err(Error::EvalError(EvalError::BlameError(l, _))) => l.tag,
+            res => panic!("expected a blame error, got {:?}", res),
+        };
+        assert_eq!(
+            blame_tag("Assume({ {| a: Num, |} }, { a = 1; b = 2; }).a"),
+            "extra field"
+        );
+        assert_eq!(
+            blame_tag("Assume({ {| a: Num, |} }, { b = 2; }).b"),
+            "missing field"
+        );
+        assert_eq!(blame_tag("Assume({ {| a: Num, |} }, 1)"), "not a record");
+        assert_eq!(
+            blame_tag("(merge {a = 1;} {a = Contract({ {| b: Num, |} });}).a"),
+            "not a record"
+        );
+
+        // The blame of a field points to the type of the field.
+        let mut p = Program::new_from_source(
+            Cursor::new("Assume({ {| a: Num, b: Str, |} }, { a = 1; b = 2; }).b"),
+            "<test>",
+        )
+        .unwrap();
+        let err = p.eval().unwrap_err();
+        match &err {
+            Error::EvalError(EvalError::BlameError(l, _)) => {
+                assert_eq!(l.path, vec![ty_path::Elem::Field(Ident::from("b"))]);
+                let (start, end) = ty_path::span(l.path.iter().peekable(), &l.types);
+                assert_eq!(&format!("{}", l.types)[start..end], "Str");
+            }
+            err => panic!("expected a blame error, got {:?}", err),
+        }
+        assert!(p
+            .report_to_string(err)
+            .contains("expected type of the field `b`"));
+    }
+
+    #[test]
+    fn records_accessing() {
+        assert_eq!(
+            eval_string("({ foo = 3; bar = true; }).bar"),
+            Ok(Term::Bool(true)),
+        );
+
+        assert_eq!(
+            eval_string("({ $(if true then \"foo\" else \"bar\") = false; bar = true; }).foo"),
+            Ok(Term::Bool(false)),
+        );
+
+        assert_eq!(
+            eval_string("({ foo = 3; bar = true; }).$(\"bar\")"),
+            Ok(Term::Bool(true)),
+        );
+
+        assert_eq!(
+            eval_string(
+                "({ $(if true then \"foo\" else \"bar\") = false; bar = true; }).$(\"foo\")"
+            ),
+            Ok(Term::Bool(false)),
+        );
+
+        eval_string("({ $(if false then \"foo\" else \"bar\") = false; bar = true; }).foo")
+            .unwrap_err();
+    }
+
+    #[test]
+    fn records_prims() {
+        assert_eq!(
+            eval_string("hasField \"foo\" { foo = 1; bar = 2; }"),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string("hasField \"fop\" { foo = 1; bar = 2; }"),
+            Ok(Term::Bool(false))
+        );
+
+        assert_eq!(
+            eval_string("(mapRec (fun y => fun x => x + 1) { foo = 1; bar = \"it's lazy\"; }).foo"),
+            Ok(Term::Num(2.)),
+        );
+        assert_eq!(
+            eval_string(
+                "let r = mapRec
+                    (fun y x => if isNum x then x + 1 else 0)
+                    { foo = 1; bar = \"it's lazy\"; }
+                in
+                (r.foo) + (r.bar)"
+            ),
+            Ok(Term::Num(2.)),
+        );
+
+        assert_eq!(
+            eval_string("hasField \"foo\" ( { foo = 2; bar = 3; }-$(\"foo\"))"),
+            Ok(Term::Bool(false))
+        );
+
+        assert_eq!(
+            eval_string("hasField \"foo\" ( { bar = 3; }$[\"foo\" = 1])"),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string("( { bar = 3; }$[\"foo\" = true]).foo"),
+            Ok(Term::Bool(true))
+        );
+    }
+
+    /// This currently do not check that subexpressions are actually forced,
+    /// just that the evaluation succeeds
+    #[test]
+    fn seq_expressions() {
+        assert_eq!(eval_string("seq 1 true"), Ok(Term::Bool(true)));
+        assert_eq!(
+            eval_string("let x = (1 + 1) in seq x x"),
+            Ok(Term::Num(2.0))
+        );
+
+        assert_eq!(
+            eval_string("let r = {a=(1 + 1);} in deepSeq r (r.a)"),
+            Ok(Term::Num(2.0))
+        );
+        assert_eq!(
+            eval_string("let r = {a=(1 + 1);b=(\"a\" ++ \"b\");} in deepSeq r (r.b)"),
+            Ok(Term::Str(String::from("ab")))
+        );
+        assert_eq!(
+            eval_string("let r = {a={b=(1 + 1);};} in deepSeq r ((r.a).b)"),
+            Ok(Term::Num(2.0))
+        );
+
+        assert_eq!(
+            eval_string(
+                "let inj = fun x => {b=(x + 2);} in
+                let cat = fun x => fun y => x ++ y in
+                let r = {a=(inj 1);b=(cat \"a\" \"b\");} in deepSeq r ((r.a).b)"
+            ),
+            Ok(Term::Num(3.0))
+        )
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(eval_string("elemAt [1,2,3] 1"), Ok(Term::Num(2.0)));
+        assert_eq!(
+            eval_string("elemAt (map (fun x => x + 1) [1,2,3]) 1"),
+            Ok(Term::Num(3.0))
+        );
+
+        eval_string("elemAt [1,2,3] (-1)").unwrap_err();
+        eval_string("elemAt [1,2,3] 4").unwrap_err();
+
+        assert_eq!(eval_string("length []"), Ok(Term::Num(0.0)));
+        assert_eq!(eval_string("length [1,2,3]"), Ok(Term::Num(3.0)));
+
+        assert_eq!(
+            eval_string("length ([] @ [1,2] @ [3,4] @ [])"),
+            Ok(Term::Num(4.0))
+        );
+        // Test case added after https://github.com/tweag/nickel/issues/154
+        assert_eq!(
+            eval_string("let x = 1 in let l = [x] @ [2] in head l"),
+            Ok(Term::Num(1.0))
+        );
+
+        assert_eq!(
+            eval_string("head [\"a\",\"b\",\"c\"]"),
+            Ok(Term::Str(String::from("a")))
+        );
+        eval_string("head []").unwrap_err();
+
+        assert_eq!(
+            eval_string("length (tail [true,false,1])"),
+            Ok(Term::Num(2.0))
+        );
+        eval_string("tail []").unwrap_err();
+
+        assert_eq!(
+            eval_string(
+                "let Y = fun f => (fun x => f (x x)) (fun x => f (x x)) in
+                let foldr_ =
+                    fun self => fun f => fun acc => fun l =>
+                        if isZero (length l) then acc
+                        else
+                            let h = head l in
+                            let t = tail l in
+                            let next_acc = self f acc t in
+                            f next_acc h
+                in
+                let foldr = Y foldr_ in
+                let and = Promise(Bool -> Bool -> Bool,
+                    fun x => fun y =>
+                        if x then
+                            if y then true else false
+                        else false)
+                in
+                let all = fun pred => fun l => foldr and true (map pred l) in
+                let isZ = fun x => isZero x in
+                all isZ [0, 0, 0, 1]"
+            ),
+            Ok(Term::Bool(false))
+        );
+    }
+
+    #[test]
+    fn merge_record_simple() {
+        assert_eval_to_record(
+            "merge {a=1;} {b=true;}",
+            vec![("a", Term::Num(1.0)), ("b", Term::Bool(true))],
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn merge_record_failure() {
+        eval_string("(merge {a=1} {a=2}).a").unwrap();
+    }
+
+    #[test]
+    fn merge_record_intersection() {
+        assert_eval_to_record(
+            "merge {a=1;b=2;} {b=2;c=3;}",
+            vec![
+                ("a", Term::Num(1.0)),
+                ("b", Term::Num(2.0)),
+                ("c", Term::Num(3.0)),
+            ],
+        );
+    }
+
+    #[test]
+    fn merge_record_nested() {
+        assert_eval_to_record(
+            "(merge {a={b=1;};} {a={c=true;};}).a ",
+            vec![("b", Term::Num(1.0)), ("c", Term::Bool(true))],
+        );
+    }
+
+    #[test]
+    fn merge_record_complex() {
+        assert_eval_to_record(
+            "let rec1 = {a=false;b=(if true then (1 + 1) else (2 + 0)); c=(((fun x => x) (fun y => y)) 2);} in
+             let rec2 = {b=(((fun x => x) (fun y => y)) 2); c=(if true then (1 + 1) else (2 + 0)); d=true;} in
+             merge rec1 rec2",
+             vec![("a", Term::Bool(false)), ("b", Term::Num(2.0)), ("c", Term::Num(2.0)), ("d", Term::Bool(true))]
+         );
+    }
+
+    #[test]
+    fn merge_record_with_env() {
+        assert_eq!(
+            eval_string("((fun y => merge ((fun x => {a=y;}) 1) ({b=false;})) 2).a"),
+            Ok(Term::Num(2.0))
+        );
+    }
+
+    #[test]
+    fn merge_record_with_env_nested() {
+        assert_eq!(
+            eval_string(
+                "let r = merge ({b={c=10;};}) ((fun x => {a=x; b={c=x;};}) 10) in
+                         (r.b).c"
+            ),
+            Ok(Term::Num(10.0))
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn enriched_terms_contract_default_fail() {
+        eval_string("ContractDefault(Num, true)").unwrap();
+    }
+
+    #[test]
+    fn enriched_terms_contract_default() {
+        assert_eq!(eval_string("ContractDefault(Num, 10)"), Ok(Term::Num(10.0)));
+    }
+
+    // Check the correct handling of the update of thunks containing enriched values (see issue
+    // https://github.com/tweag/nickel/issues/123)
+    #[test]
+    fn enriched_terms_thunk_update() {
+        assert_eq!(
+            eval_string("let x = {a=(fun x => Default(1)) 1} in seq (x.a) ((merge x {a=2}).a)"),
+            Ok(Term::Num(2.0))
+        );
+    }
+
+    #[test]
+    fn merge_default() {
+        assert_eval_to_record(
+            "merge {a=2;} {a=Default(0);b=Default(true);}",
+            vec![("a", Term::Num(2.0)), ("b", Term::Bool(true))],
+        );
+
+        assert_eval_to_record(
+            "(merge {a=Default({x=1;});} {a=Default({y=\"y\";});}).a",
+            vec![("x", Term::Num(1.0)), ("y", Term::Str(String::from("y")))],
+        );
+
+        eval_string("(merge {a=Default(1);} {a=Default(2);}).a").unwrap_err();
+    }
+
+    #[test]
+    fn merge_contract() {
+        assert_eval_to_record(
+            "merge {a=2;b=Contract(Bool);} {a=Contract(Num);b=Default(true);}",
+            vec![("a", Term::Num(2.0)), ("b", Term::Bool(true))],
+        );
+
+        eval_string("let r = merge {a=2;} {a=Contract(Bool)} in r.a").unwrap_err();
+    }
+
+    #[test]
+    fn merge_default_contract() {
+        assert_eval_to_record(
+            "merge {a=2;} {a=ContractDefault(Num, 0);b=Default(true);}",
+            vec![("a", Term::Num(2.0)), ("b", Term::Bool(true))],
+        );
+
+        assert_eval_to_record(
+            "merge (merge {a=2;} {a=Contract(Num);}) {a=Default(3);}",
+            vec![("a", Term::Num(2.0))],
+        );
+
+        assert_eq!(
+            eval_string("(merge (merge {a=2;} {b=Contract(Num);}) {b=Default(3);}).b"),
+            Ok(Term::Num(3.0)),
+        );
+
+        assert_eq!(
+            eval_string("(merge (merge {a=Default(1);} {b=Contract(Num);}) {a=Default(1);}).a"),
+            Ok(Term::Num(1.0)),
+        );
+
+        eval_string("(merge (merge {a=2;} {b=Contract(Num);}) {b=Default(true);}).b").unwrap_err();
+    }
+
+    fn make_composed_contract(value: &str) -> Result<Term, Error> {
+        let s = format!(
+            "let Y = fun f => (fun x => f (x x)) (fun x => f (x x)) in
+             let dec = fun x => x + (-1) in
+             let or = fun x => fun y => if x then x else y in
+             let isEven_ = Y (fun f =>
+                 (fun x =>
+                     if (isZero x) then true
+                     else (
+                         if (isZero (dec x)) then false
+                         else (f (dec (dec x)))
+                     )
+                 )
+             ) in
+             let isDivBy3_ = Y (fun f =>
+                 (fun x =>
+                     if (isZero x) then true
+                     else (
+                         if or (isZero (dec (dec x))) (isZero (dec x)) then false
+                         else (f (dec (dec (dec x))))
+                     )
+                 )
+             ) in
+             let toCtr = fun f => fun l => fun x => (
+               if (isNum x) then (
+                 if (f x) then x else blame l)
+               else blame l
+             ) in
+             let isEven = toCtr isEven_ in
+             let isDivBy3 = toCtr isDivBy3_ in
+             let composed = merge {{a=Contract(#isEven);}} {{a=Contract(#isDivBy3);}} in
+             (merge {{a={};}} composed).a",
+            value
+        );
+
+        eval_string(s.as_str())
+    }
+
+    #[test]
+    fn merge_compose_contract() {
+        assert_eq!(make_composed_contract("6"), Ok(Term::Num(6.0)));
+        assert_eq!(make_composed_contract("12"), Ok(Term::Num(12.0)));
+
+        make_composed_contract("1").unwrap_err();
+        make_composed_contract("14").unwrap_err();
+        make_composed_contract("27").unwrap_err();
+        make_composed_contract("10").unwrap_err();
+        make_composed_contract("35").unwrap_err();
+    }
+
+    /// Check that the environments of contracts are correctly saved and restored when merging. See
+    /// issue [#117](https://github.com/tweag/nickel/issues/117).
+    #[test]
+    fn merge_contracts_with_env() {
+        let definitions = "
+            let ctr_num = let x = num in {a = Contract(#x)} in
+            let ctr_id = let x = fun l x => x in {a = Contract(#x)} in
+            let val = let x = 1 in {a = x} in
+            let def = let x = 2 in {a = Default(x)} in
+            let def2 = let x = (1 + 1) in {a = Default(x)} in
+            ";
+
+        // Build a term which recursively merges the elements of `elts` in the environment set by
+        // `definitions`, and then access the `a` field
+        let merge_elts = |elts: Vec<&str>| -> Result<Term, Error> {
+            let term = elts.into_iter().fold(String::from("{}"), |term, op| {
+                format!("merge ({}) ({})", op, term)
+            });
+            eval_string(&format!("{} ({}).a", definitions, term))
+        };
+
+        // contract/contract -> contract/value -> value/default
+        assert_eq!(
+            merge_elts(vec!["ctr_num", "ctr_id", "val", "def"]),
+            Ok(Term::Num(1.0))
+        );
+        // default/value <- value/contract
+        assert_eq!(
+            merge_elts(vec!["def", "merge val ctr_num"]),
+            Ok(Term::Num(1.0))
+        );
+        // default/contract-> contract-default/contract-default <- contract/default
+        assert_eq!(
+            merge_elts(vec!["merge def ctr_num", "merge ctr_id def2"]),
+            Ok(Term::Num(2.0))
+        );
+        // default/contract -> contract-default/contract -> contract-default/value
+        assert_eq!(
+            merge_elts(vec!["def", "ctr_num", "ctr_id", "val"]),
+            Ok(Term::Num(1.0))
+        );
+        // default/contract -> contract-default/default
+        assert_eq!(
+            merge_elts(vec!["def", "ctr_num", "def2"]),
+            Ok(Term::Num(2.0))
+        );
+        // value/contract-default <- contract/contract-default
+        assert_eq!(
+            merge_elts(vec!["val", "merge ctr_num def"]),
+            Ok(Term::Num(1.0))
+        );
+    }
+
+    #[test]
+    fn string_chunks() {
+        fn assert_eval_str(term: &str, result: &str) {
+            assert_eq!(eval_string(term), Ok(Term::Str(String::from(result))));
+        }
+
+        assert_eval_str(
+            r#""simple ${"interp" ++ "olation"} here""#,
+            "simple interpolation here",
+        );
+        assert_eval_str(r#""${"alone"}""#, "alone");
+        assert_eval_str(
+            r#""nested ${ "${(fun x => "${x}") "expression"}" }""#,
+            "nested expression",
+        );
+        assert_eval_str(
+            r#""${"some"}${" " ++ "se" ++ "qu"}${"${"ence"}"}""#,
+            "some sequence",
+        );
+        assert_eval_str(
+            r#""nested ${ {str = {a = "braces"}.a}.str } !""#,
+            "nested braces !",
+        );
+        assert_eval_str(
+            r#"let x = "world" in "Hello, ${x}! Welcome in ${let y = "universe" in "the ${x}-${y}"}""#,
+            "Hello, world! Welcome in the world-universe",
+        );
+
+        assert_eval_str(
+            r#""${1 + 1} ${0.5} ${true} ${`foo} ${-3}""#,
+            "2 0.5 true foo -3",
+        );
+
+        match eval_string(r#""bad type ${{a = 1}}""#) {
+            Err(Error::EvalError(EvalError::TypeError(_, _, _, _))) => (),
+            _ => assert!(false),
+        };
+        match eval_string(r#""bad type ${fun x => x}""#) {
+            Err(Error::EvalError(EvalError::TypeError(_, _, _, _))) => (),
+            res => panic!("expected a type error, got {:?}", res),
+        };
+    }
+
+    #[test]
+    fn strict_fields() {
+        assert_eq!(
+            eval_string("{strict a = 1 + 1; b = a + 1}.b"),
+            Ok(Term::Num(3.0))
+        );
+        assert_eq!(
+            eval_string("{strict a = b + 1; b = 1; strict c = a + b}.c"),
+            Ok(Term::Num(3.0))
+        );
+
+        // Lazy fields are only evaluated when accessed, while strict ones are evaluated as soon as
+        // the record is.
+        assert_eq!(eval_string("{a = 1 + true; b = 2}.b"), Ok(Term::Num(2.0)));
+        match eval_string("{strict a = 1 + true; b = 2}.b") {
+            Err(Error::EvalError(EvalError::TypeError(..))) => (),
+            res => panic!("expected a type error, got {:?}", res),
+        };
+        match eval_string("seq {strict a = Assume(Num, true)} 1") {
+            Err(Error::EvalError(EvalError::BlameError(..))) => (),
+            res => panic!("expected a blame error, got {:?}", res),
+        };
+
+        // Strict fields are compatible with merging
+        assert_eq!(
+            eval_string("(merge {strict a = 1; b = a + 1} {c = 3}).b"),
+            Ok(Term::Num(2.0))
+        );
+    }
+
+    #[test]
+    fn recursive_records() {
+        assert_eq!(
+            eval_string("{a = 1; b = a + 1; c = b + a}.c"),
+            Ok(Term::Num(3.0))
+        );
+        assert_eq!(
+            eval_string("{f = fun x y => if isZero x then y else f (x + (-1)) (y + 1)}.f 5 5"),
+            Ok(Term::Num(10.0))
+        );
+        assert_eq!(
+            eval_string("
+                let with_res = fun res => { f = fun x => if isZero x then res else g x; g = fun y => f (y + (-1)) }.f 10 in
+                with_res \"done\""),
+            Ok(Term::Str(String::from("done")))
+        );
+    }
+
+    /// Assert that the polymorphic equality between two terms, given as strings, evaluates to the
+    /// term `true`.
+    ///
+    /// This is made as a macro such that a failure points directly at the failing test line.  With
+    /// a standard function, a failing assertion would always point at this function's body,
+    /// instead of the callsite.
+    macro_rules! assert_peq {
+        ($t1:expr, $t2:expr) => {
+            assert_eq!(
+                eval_string(&format!("({}) == ({})", $t1, $t2)),
+                Ok(Term::Bool(true)),
+            );
+        };
+    }
+
+    /// Same as `assert_peq!`, but succeeds if terms are **not** equal.
+    macro_rules! assert_npeq {
+        ($t1:expr, $t2:expr) => {
+            assert_eq!(
+                eval_string(&format!("({}) == ({})", $t1, $t2)),
+                Ok(Term::Bool(false)),
+            );
+        };
+    }
+
+    #[test]
+    fn poly_eq() {
+        assert_peq!("0", "0 + 0 + 0");
+        assert_peq!("true", "if true then true else false");
+        assert_peq!("\"a\" ++ \"b\" ++ \"c\"", "\"${\"a\" ++ \"b\"}\" ++ \"c\"");
+
+        assert_npeq!("1 + 1", "0");
+        assert_npeq!("true", "if true then false else true");
+        assert_npeq!("\"a\"", "\"a\" ++ \" \"");
+        assert_npeq!("1", "true");
+        assert_npeq!("\"1\"", "1");
+        assert_npeq!("\"true\"", "true");
+    }
+
+    #[test]
+    fn poly_eq_lists() {
+        assert_peq!("[]", "[]");
+        assert_peq!("[(1 + 0), (1 + 1), (1 + 1 + 1)]", "[1, 2, 3]");
+        assert_peq!(
+            "[(1 + 0), (\"a\" ++ \"b\"), (if true then true else false)]",
+            "[1, \"ab\", true]"
+        );
+
+        assert_peq!("[[[]]]", "[[[]]]");
+        assert_peq!("[[1], [[2]]]", "[[2 + (-1)], [[1 + 1]]]");
+        assert_peq!("[[true, false]]", "lists.flatten [[[true, false]]]");
+
+        assert_npeq!("[]", "[1]");
+        assert_npeq!("[]", "1");
+        assert_npeq!("[]", "{}");
+        assert_npeq!("[1, \"a\", true]", "[1, \"a\", false]");
+        assert_npeq!("[[true]]", "[[[true]]]");
+    }
+
+    #[test]
+    fn poly_eq_records() {
+        assert_peq!("{}", "{}");
+        assert_peq!("{}$[\"a\" = 1]$[\"b\" = true]", "{a = 1; b = true}");
+        assert_peq!(
+            "{a = 1 + 0; b = 1 + 1; c = 1 + 1 + 1}",
+            "{ a = 1; b = 2; c = 3 }"
+        );
+        assert_peq!(
+            "{ foo = 1 + 0; bar = \"a\" ++ \"b\"; baz = if true then true else false }",
+            "{ foo = 1; bar = \"ab\"; baz = true}"
+        );
+
+        assert_peq!(
+            "{}$[\"a\" = { a = { a = {} } }]",
+            "{ a = { a = { a = {} } } }"
+        );
+        assert_peq!(
+            "{ foo = { bar = 2 + (-1) }; baz = { foo = { bar = 1 + 1 } } }",
+            "{ foo = { bar = 1 }; baz = { foo = { bar = 2 } } }"
+        );
+
+        assert_npeq!("{}", "{a = true}");
+        assert_npeq!("{a = 1}", "{a = 2}");
+        assert_npeq!("{ a = \"a\"; b = true }", "{ a = true; b = \"a\"}");
+        assert_npeq!("{ a = { a = true } }", "{a = { a = { a = true } } }");
+    }
+
+    #[test]
+    fn fields_of() {
+        assert_peq!("fieldsOf {}", "[]");
+        assert_peq!("fieldsOf {a = 1; b = 2; c = 3}", "[\"a\", \"b\", \"c\"]");
+        assert_peq!("fieldsOf {aAa = 1; Zzz = 2;}", "[\"Zzz\", \"aAa\"]");
+        assert_peq!(
+            "fieldsOf {foo = {bar = 0}; baz = Default(true)}",
+            "[\"baz\", \"foo\"]"
+        );
+    }
+
+    #[test]
+    fn fields_with_metadata() {
+        assert_peq!("fieldsWithMetadata {}", "[]");
+        assert_peq!(
+            "fieldsWithMetadata {a = 1; b = Docstring(\"port\", ContractDefault(Num, 80)); \
+             c = Contract(Str)}",
+            "[{name = \"a\"; hasDefault = false; hasDoc = false; contracts = []}, \
+             {name = \"b\"; hasDefault = true; hasDoc = true; contracts = [\"Num\"]}, \
+             {name = \"c\"; hasDefault = false; hasDoc = false; contracts = [\"Str\"]}]"
+        );
+        // The metadata of both sides of a merge are combined.
+        assert_peq!(
+            "fieldsWithMetadata (merge {a = Contract(Num)} {a = Default(1)})",
+            "[{name = \"a\"; hasDefault = true; hasDoc = false; contracts = [\"Num\"]}]"
+        );
+        // The entries are lazy, and don't force the value of the fields.
+        assert_peq!("length (fieldsWithMetadata {a = 1 + true})", "1");
+        assert_peq!(
+            "(head (fieldsWithMetadata {a = Contract(Num); b = 1 + true})).contracts",
+            "[\"Num\"]"
+        );
+    }
+
+    #[test]
+    fn record_find_fields() {
+        let headers = "{ContentType = 1; contenttype = 2; ContentLength = 3; Host = 4}";
+        assert_peq!(
+            format!("recordFindFields \"(?i)^contenttype$\" {}", headers),
+            "[\"ContentType\", \"contenttype\"]"
+        );
+        assert_peq!(
+            format!("recordFindFields \"Content\" {}", headers),
+            "[\"ContentLength\", \"ContentType\"]"
+        );
+        assert_peq!(format!("recordFindFields \"^x\" {}", headers), "[]");
+        assert_peq!("recordFindFields \".\" {}", "[]");
+
+        assert!(matches!(
+            eval_string("recordFindFields \"(\" {a = 1}"),
+            Err(Error::EvalError(EvalError::Other(..)))
+        ));
+        assert!(matches!(
+            eval_string("recordFindFields 1 {a = 1}"),
+            Err(Error::EvalError(EvalError::TypeError(..)))
+        ));
+    }
+
+    #[test]
+    fn externals() {
+        let src = Cursor::new("let x = %external% \"secret\" in x + x + (%external% \"other\")");
+        let mut p = Program::new_from_source(src, "<test>").unwrap();
+
+        let mut requested = Vec::new();
+        let mut status = p.eval_resumable().unwrap();
+        let result = loop {
+            match status {
+                eval::EvalStatus::Done(t) => break t,
+                eval::EvalStatus::Suspended(susp) => {
+                    requested.push(susp.name.clone());
+                    let value = if susp.name == "secret" { 1.0 } else { 2.0 };
+                    status = p.resume(susp, Term::Num(value).into()).unwrap();
+                }
+            }
+        };
+
+        assert_eq!(result, Term::Num(4.0));
+        // The variable `x` is updated, the host must be asked only once.
+        assert_eq!(requested, vec!["secret", "other"]);
+
+        // Without a host to provide values, external terms are errors.
+        assert!(eval_string("1 + %external% \"secret\"").is_err());
+    }
+
+    #[test]
+    fn typed_interface_of_untyped_code() {
+        let lib = "let lib = {id = fun x => x; bad = fun x => x} in ";
+        assert_eq!(
+            eval_string(&format!(
+                "{}Promise(Num, (Assume(forall a. a -> a, lib.id)) 1)",
+                lib
+            )),
+            Ok(Term::Num(1.0))
+        );
+        // The static type is trusted by the typechecker, and enforced by the contract.
+        assert!(matches!(
+            eval_string(&format!(
+                "{}Promise(Str, (Assume(Num -> Str, lib.bad)) 1)",
+                lib
+            )),
+            Err(Error::EvalError(EvalError::BlameError(..)))
+        ));
+    }
+
+    #[test]
+    fn trace_sink() {
+        use std::io::Write;
+
+        let mut p =
+            Program::new_from_source(Cursor::new("log \"a\" ++ log \"b\""), "<test>").unwrap();
+        let sink = p.trace_sink();
+        let str_ty = || Box::new(Types(AbsType::Str()));
+        p.register_function(
+            "log",
+            Types(AbsType::Arrow(str_ty(), str_ty())),
+            move |args| {
+                writeln!(sink.borrow_mut(), "log: {:?}", args[0].as_ref()).unwrap();
+                Ok(args[0].clone())
+            },
+        );
+
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let lines_ = Rc::clone(&lines);
+        p.set_trace_callback(move |line| lines_.borrow_mut().push(String::from(line)));
+
+        assert_eq!(p.eval(), Ok(Term::Str(String::from("ab"))));
+        assert_eq!(
+            *lines.borrow(),
+            vec![
+                String::from("log: Str(\"a\")"),
+                String::from("log: Str(\"b\")")
+            ]
+        );
+    }
+
+    #[test]
+    fn trace_events() {
+        use std::io::Write;
+
+        let mut p = Program::new_from_source(
+            Cursor::new(
+                "trace \"n\" (1 + 1) + length (trace \"l\" [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15])",
+            ),
+            "<test>",
+        )
+        .unwrap();
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let lines_ = Rc::clone(&lines);
+        p.set_trace_callback(move |line| lines_.borrow_mut().push(String::from(line)));
+        assert_eq!(p.eval(), Ok(Term::Num(18.0)));
+        assert_eq!(
+            *lines.borrow(),
+            vec![
+                String::from("trace: n: 2.0"),
+                String::from(
+                    "trace: l: [0.0,1.0,2.0,3.0,4.0,5.0,6.0,7.0,8.0,9.0,10.0,11.0,12.0,13.0..."
+                )
+            ]
+        );
+
+        let source = "let r = trace \"r\" {a = [1, 2]} in\nlength (log \"a\" (r.a))";
+        let mut p = Program::new_from_source(Cursor::new(source), "<test>").unwrap();
+        let sink = p.trace_sink();
+        p.register_function(
+            "log",
+            Types(AbsType::Arrow(
+                Box::new(Types(AbsType::Str())),
+                Box::new(Types(AbsType::Arrow(
+                    Box::new(Types(AbsType::Dyn())),
+                    Box::new(Types(AbsType::Dyn())),
+                ))),
+            )),
+            move |args| {
+                writeln!(sink.borrow_mut(), "log: {:?}", args[0].as_ref()).unwrap();
+                Ok(args[1].clone())
+            },
+        );
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_ = Rc::clone(&events);
+        p.set_trace_event_callback(move |event| {
+            events_
+                .borrow_mut()
+                .push(serde_json::from_str::<serde_json::Value>(event).unwrap())
+        });
+        assert_eq!(p.eval(), Ok(Term::Num(2.0)));
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                serde_json::json!({
+                    "message": "r",
+                    "span": {"file": "<test>", "line": 1, "column": 19},
+                    "value": "{\"a\":[1.0,2.0]}",
+                }),
+                serde_json::json!({"message": "log: Str(\"a\")"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn dead_fields() {
+        let dir = std::env::temp_dir().join(format!("nickel-dead-fields-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(
+            dir.join("base.ncl"),
+            "merge {port = Default(80); host = \"localhost\"} (import \"sub/tls.ncl\")",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("sub").join("tls.ncl"),
+            "{tls = {enabled = Default(false); cert = Contract(Str)}}",
+        )
+        .unwrap();
+        let source = format!(
+            "merge (import \"{}\") {{port = 8080; tls = {{enabled = true; cert = \"c\"}}}}",
+            dir.join("base.ncl").display()
+        );
+
+        let mut p = Program::new_from_source(Cursor::new(source), "<test>").unwrap();
+        p.add_override(
+            vec![Ident::from("host")],
+            Term::Str(String::from("a")).into(),
+        );
+        let dead: Vec<String> = p
+            .warnings()
+            .unwrap()
+            .into_iter()
+            .filter_map(|w| match w {
+                Warning::DeadField(path, Some(_), _) => Some(path),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(dead, vec!["host", "port", "tls.enabled"]);
+
+        // Loading the imports for the analysis does not prevent their evaluation.
+        p.eval_full().unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn manifest() {
+        use crate::manifest::FileRecord;
+
+        let dir = std::env::temp_dir().join(format!("nickel-manifest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.ncl"), "{x = 1}").unwrap();
+        let s = format!(
+            "(import \"{}\").x + double (%external% \"y\") + (import \"{}\").x",
+            dir.join("a.ncl").display(),
+            dir.join("a.ncl").display()
+        );
+        let mut p = Program::new_from_source(Cursor::new(s.as_str()), "<test>").unwrap();
+        let num_ty = || Box::new(Types(AbsType::Num()));
+        p.register_function(
+            "double",
+            Types(AbsType::Arrow(num_ty(), num_ty())),
+            |args| match args[0].as_ref() {
+                Term::Num(n) => Ok(Term::Num(2.0 * n).into()),
+                _ => Err(String::from("expected a number")),
+            },
+        );
+        assert_eq!(p.manifest(), None);
+        p.set_record_manifest(true);
+
+        let mut status = p.eval_resumable().unwrap();
+        let result = loop {
+            match status {
+                eval::EvalStatus::Done(t) => break t,
+                eval::EvalStatus::Suspended(susp) => {
+                    status = p.resume(susp, Term::Num(3.0).into()).unwrap();
+                }
+            }
+        };
+        assert_eq!(result, Term::Num(8.0));
+
+        let manifest = p.manifest().unwrap();
+        // The imported file is only read once.
+        assert_eq!(
+            manifest.files,
+            vec![
+                FileRecord::new(PathBuf::from("<test>"), &s),
+                FileRecord::new(dir.join("a.ncl").canonicalize().unwrap(), "{x = 1}"),
+            ]
+        );
+        assert_eq!(
+            manifest.externals,
+            vec![(String::from("y"), serde_json::json!(3.0))]
+        );
+        assert_eq!(manifest.host_calls.len(), 1);
+        assert_eq!(manifest.host_calls[0].name, "double");
+        assert_eq!(manifest.host_calls[0].args, vec![serde_json::json!(3.0)]);
+        assert_eq!(manifest.host_calls[0].result, Ok(serde_json::json!(6.0)));
+
+        p.set_record_manifest(false);
+        assert_eq!(p.manifest(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn import_contract() {
+        let dir =
+            std::env::temp_dir().join(format!("nickel-import-contract-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("a.json"),
+            "{\"port\": \"8080\", \"tags\": [\"a\"]}",
+        )
+        .unwrap();
+        fs::write(dir.join("a.yaml"), "port: 8080\ntags:\n  - 1\n").unwrap();
+        let import = |file: &str, ty: &str| {
+            format!(
+                "let x = import \"{}\" | ({}) in x.port",
+                dir.join(file).display(),
+                ty
+            )
+        };
+
+        assert_eq!(
+            eval_string(&import("a.json", "{ {| port : Str, tags : List |} }")),
+            Ok(Term::Str(String::from("8080")))
+        );
+        assert_eq!(
+            eval_string(&import("a.yaml", "{ {| port : Num, tags : List |} }")),
+            Ok(Term::Num(8080.))
+        );
+
+        let path = dir.join("a.json").display().to_string();
+        let mut p = Program::new_from_source(
+            Cursor::new(import("a.json", "{ {| port : Num, tags : List |} }")),
+            "<test>",
+        )
+        .unwrap();
+        match p.eval() {
+            Err(Error::EvalError(EvalError::BlameError(l, _))) => {
+                let arg_pos = l
+                    .arg_pos
+                    .expect("expected the position of the faulty value");
+                assert_eq!(Some(arg_pos.src_id), p.get_id(&path, None));
+                assert_eq!((arg_pos.start.to_usize(), arg_pos.end.to_usize()), (9, 15));
+            }
+            res => panic!("expected a blame error, got {:?}", res),
+        };
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn import_interface() {
+        let dir = std::env::temp_dir().join(format!("nickel-import-ncli-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // The implementation is not well-typed, but the ill-typed branch is never taken.
+        fs::write(
+            dir.join("lib.ncl"),
+            "{ add = fun x y => Promise(Num, if true then x + y else \"a\"); name = 1 }",
+        )
+        .unwrap();
+        let import = format!("(import \"{}\")", dir.join("lib.ncl").display());
+
+        let src = format!("Promise(Num, {}.add 1 2)", import);
+        match eval_string(&src) {
+            Err(Error::TypecheckError(_)) => (),
+            res => panic!("expected a type error, got {:?}", res),
+        };
+
+        fs::write(
+            dir.join("lib.ncli"),
+            "{ {| add: Num -> Num -> Num, name: Str |} }",
+        )
+        .unwrap();
+        assert_eq!(eval_string(&src), Ok(Term::Num(3.)));
+
+        // The interface is used by the typechecker
+        match eval_string(&format!("Promise(Str, {}.add 1 2)", import)) {
+            Err(Error::TypecheckError(_)) => (),
+            res => panic!("expected a type error, got {:?}", res),
+        };
+
+        // The implementation is checked against the interface at run time
+        match eval_string(&format!("{}.name", import)) {
+            Err(Error::EvalError(EvalError::BlameError(..))) => (),
+            res => panic!("expected a blame error, got {:?}", res),
+        };
+
+        fs::write(dir.join("lib.ncli"), "{ {| add: |} }").unwrap();
+        match eval_string(&format!("{}.add 1 2", import)) {
+            Err(Error::ImportError(ImportError::ParseError(..))) => (),
+            res => panic!("expected a parse error, got {:?}", res),
+        };
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn import_pick() {
+        let dir = std::env::temp_dir().join(format!("nickel-import-pick-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.ncl"), "{ a = 1; b = a + 1; c = 1 + true }").unwrap();
+        let import = |fields: &str| {
+            format!(
+                "(import \"{}\" pick [{}])",
+                dir.join("lib.ncl").display(),
+                fields
+            )
+        };
+
+        assert_eq!(
+            eval_string(&format!("{}.b", import("a, b"))),
+            Ok(Term::Num(2.))
+        );
+        assert_eq!(
+            eval_string(&format!("length (fieldsOf {})", import("b,"))),
+            Ok(Term::Num(1.))
+        );
+        match eval_string(&format!("{}.c", import("a"))) {
+            Err(Error::EvalError(EvalError::FieldMissing(..))) => (),
+            res => panic!("expected a missing field error, got {:?}", res),
+        };
+        match eval_string(&format!("{}.a", import("a, d"))) {
+            Err(Error::EvalError(EvalError::FieldMissing(..))) => (),
+            res => panic!("expected a missing field error, got {:?}", res),
+        };
+
+        // With an interface, only the type of the picked fields is used
+        fs::write(dir.join("lib.ncli"), "{ {| a: Num, b: Num, c: Num |} }").unwrap();
+        assert_eq!(
+            eval_string(&format!("Promise(Num, {}.a)", import("a"))),
+            Ok(Term::Num(1.))
+        );
+        match eval_string(&format!("Promise(Num, {}.b)", import("a"))) {
+            Err(Error::TypecheckError(_)) => (),
+            res => panic!("expected a type error, got {:?}", res),
+        };
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn export_string(s: &str) -> Result<String, Error> {
+        export_string_with(s, &ExportOptions::default()).map_err(|(err, _)| err)
+    }
+
+    /// Export a program, returning the output written so far in case of error.
+    fn export_string_with(s: &str, options: &ExportOptions) -> Result<String, (Error, String)> {
+        let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+        let mut out = Vec::new();
+        let result = p.export(options, &mut out);
+        let out = String::from_utf8(out).unwrap();
+        result.map(|()| out.clone()).map_err(|err| (err, out))
+    }
+
+    #[test]
+    fn merge_field_definitions() {
+        assert_eq!(
+            eval_string("{a = {x = 1}; b = 2; a &= {y = x + b}; x = 3}.a.y"),
+            Ok(Term::Num(5.))
+        );
+        match eval_string("{a = {x = 1}; a = {y = 2}}") {
+            Err(Error::ParseError(ParseError::DuplicateField(..))) => (),
+            res => panic!("expected a duplicate field error, got {:?}", res),
+        };
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(
+            eval_string("labelTag (mkLabel \"a\")"),
+            Ok(Term::Str(String::from("a")))
+        );
+        assert_eq!(
+            eval_string("labelTag (tag \"b\" (mkLabel \"a\"))"),
+            Ok(Term::Str(String::from("b")))
+        );
+        assert_eq!(
+            eval_string("polarity (chngPol (mkLabel \"a\"))"),
+            Ok(Term::Bool(false))
+        );
+        assert_eq!(
+            eval_string("length (labelPath (goCodom (goDom (mkLabel \"a\"))))"),
+            Ok(Term::Num(2.))
+        );
+        assert_eq!(
+            eval_string("head (labelPath (goCodom (goDom (mkLabel \"a\"))))"),
+            Ok(Term::Enum(Ident::from("Domain")))
+        );
+
+        // A contract blaming a fresh label to report a custom message
+        let contract = "let c = fun l t => if isNum t then t else \
+                        blame (mkLabel (labelTag l ++ \": not a number\")) in";
+        assert_eq!(
+            eval_string(&format!("{} Assume(#c, 1)", contract)),
+            Ok(Term::Num(1.))
+        );
+        match eval_string(&format!("{} c (mkLabel \"port\") true", contract)) {
+            Err(Error::EvalError(EvalError::BlameError(l, _))) => {
+                assert_eq!(l.tag, "port: not a number")
+            }
+            res => panic!("expected a blame error, got {:?}", res),
+        };
+        eval_string("labelTag 1").unwrap_err();
+        eval_string("mkLabel 1").unwrap_err();
+    }
+
+    #[test]
+    fn contract_combinators() {
+        let equals = "let Equals = fun n l t => if t == n then t else blame l in ";
+
+        assert_eq!(
+            eval_string("Assume(#(anyOf [num, bool]), true)"),
+            Ok(Term::Bool(true))
+        );
+        assert_eq!(
+            eval_string(&format!(
+                "{} Assume(#(anyOf [(Equals 1), (Equals 2)]), 2)",
+                equals
+            )),
+            Ok(Term::Num(2.))
+        );
+        match eval_string("Assume(#(anyOf [num, bool]), \"a\")") {
+            Err(Error::EvalError(EvalError::BlameError(l, _))) => {
+                assert_eq!(l.tag, "no alternative matched")
+            }
+            res => panic!("expected a blame error, got {:?}", res),
+        };
+        eval_string("Assume(#(anyOf []), 1)").unwrap_err();
+
+        // A blame raised inside an operation is discarded as well
+        assert_eq!(
+            eval_string("Assume(#(anyOf [(fun l t => 1 + (blame l)), num]), 1)"),
+            Ok(Term::Num(1.))
+        );
+        // The fields and the elements checked lazily by an alternative are forced before trying
+        // the next one
+        let records = "let A = record (record_extend \"a\" num empty_tail) in \
+            let B = record (record_extend \"a\" bool empty_tail) in ";
+        assert_eq!(
+            eval_string(&format!(
+                "{} (Assume(#(anyOf [A, B]), {{a = true}})).a",
+                records
+            )),
+            Ok(Term::Bool(true))
+        );
+        eval_string(&format!(
+            "{} (Assume(#(anyOf [A, B]), {{a = \"a\"}})).a",
+            records
+        ))
+        .unwrap_err();
+        assert_eq!(
+            eval_string("head Assume(#(anyOf [(list_of num), (list_of bool)]), [true])"),
+            Ok(Term::Bool(true))
+        );
+        eval_string("head Assume(#(anyOf [(list_of num), (list_of bool)]), [\"a\"])").unwrap_err();
+
+        // Errors of the checked value itself are not hidden by the alternatives
+        match eval_string("Assume(#(anyOf [num, bool]), Assume(Num, true))") {
+            Err(Error::EvalError(EvalError::BlameError(l, _))) => assert_eq!(l.tag, ""),
+            res => panic!("expected a blame error, got {:?}", res),
+        };
+
+        assert_eq!(
+            eval_string(&format!("{} Assume(#(allOf [num, (Equals 1)]), 1)", equals)),
+            Ok(Term::Num(1.))
+        );
+        eval_string(&format!("{} Assume(#(allOf [num, (Equals 1)]), 2)", equals)).unwrap_err();
+        eval_string(&format!(
+            "{} Assume(#(allOf [num, (Equals 1)]), true)",
+            equals
+        ))
+        .unwrap_err();
+        assert_eq!(eval_string("Assume(#(allOf []), 1)"), Ok(Term::Num(1.)));
+
+        assert_eq!(
+            eval_string("catchBlame (fun l => 2) (Assume(Num, true))"),
+            Ok(Term::Num(2.))
+        );
+        assert_eq!(
+            eval_string(
+                "catchBlame (fun l => labelTag l) (Assume(#(fun l t => blame (tag \"a\" l)), 1))"
+            ),
+            Ok(Term::Str(String::from("a")))
+        );
+        eval_string("catchBlame (fun l => 2) (1 + true)").unwrap_err();
+    }
+
+    #[test]
+    fn contract_check() {
+        assert_eq!(
+            eval_string("(contract.check 1 num).status"),
+            Ok(Term::Enum(Ident::from("Ok")))
+        );
+        assert_eq!(
+            eval_string("(contract.check 1 num).value"),
+            Ok(Term::Num(1.))
+        );
+        assert_eq!(
+            eval_string("(contract.check true num).status"),
+            Ok(Term::Enum(Ident::from("Err")))
+        );
+        assert_eq!(
+            eval_string("(contract.check true num).message"),
+            Ok(Term::Str(String::from("contract broken")))
+        );
+        assert_eq!(
+            eval_string(
+                "(contract.check 1 (fun l t => if isNum t then blame (tag \"too big\" l) \
+                 else t)).message"
+            ),
+            Ok(Term::Str(String::from("too big")))
+        );
+
+        // The value is checked deeply
+        assert_eq!(
+            eval_string("(contract.check {a = {b = Assume(Num, true)}} dyn).status"),
+            Ok(Term::Enum(Ident::from("Err")))
+        );
+
+        // Errors can be aggregated instead of stopping at the first one
+        assert_eq!(
+            eval_string(
+                "lists.foldl (fun acc r => switch { Ok => acc, Err => (acc + 1), } (r.status)) 0 \
+                 [(contract.check 1 num), (contract.check true num), (contract.check \"a\" num)]"
+            ),
+            Ok(Term::Num(2.))
+        );
+    }
+
+    #[test]
+    fn record_check() {
+        let same = "let same = contract.recordCheck [\"password\", \"confirm\"] \
+                    (fun r => r.password == r.confirm) in ";
+        assert_eq!(
+            eval_string(&format!(
+                "{}(contract.check {{password = \"a\"; confirm = \"a\"}} same).status",
+                same
+            )),
+            Ok(Term::Enum(Ident::from("Ok")))
+        );
+        assert_eq!(
+            eval_string(&format!(
+                "{}(contract.check {{password = \"a\"; confirm = \"b\"}} same).message",
+                same
+            )),
+            Ok(Term::Str(String::from(
+                "the fields password, confirm do not satisfy the check"
+            )))
+        );
+        assert_eq!(
+            eval_string(&format!("{}(contract.check 1 same).message", same)),
+            Ok(Term::Str(String::from("not a record")))
+        );
+
+        // The blame points at the record literal.
+        let source = format!(
+            "{}Assume(#same, {{password = \"a\"; confirm = \"b\"}})",
+            same
+        );
+        let start = source.find("{password").unwrap();
+        match eval_string(&source) {
+            Err(Error::EvalError(EvalError::BlameError(l, _))) => {
+                let span = l.arg_pos.unwrap();
+                assert_eq!(span.start.to_usize(), start);
+                assert_eq!(span.end.to_usize(), source.len() - 1);
+            }
+            res => panic!("expected a blame error, got {:?}", res),
+        }
+
+        // The contracts of the fields are checked first, when the predicate accesses them.
+        match eval_string(&format!(
+            "{}Assume(#same, {{password = Assume(Str, 1); confirm = 1}})",
+            same
+        )) {
+            Err(Error::EvalError(EvalError::BlameError(l, _))) => {
+                assert_eq!(l.types, Types(AbsType::Str()))
+            }
+            res => panic!("expected a blame error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn type_of() {
+        assert_eq!(eval_string("typeOf 1"), Ok(Term::Str(String::from("Num"))));
+        assert_eq!(
+            eval_string("typeOf (fun x => x)"),
+            Ok(Term::Str(String::from("Fun")))
+        );
+        assert_eq!(
+            eval_string("typeOf {a = 1;}"),
+            Ok(Term::Str(String::from("Record")))
+        );
+        assert_eq!(
+            eval_string("typeOf (mkLabel \"a\")"),
+            Ok(Term::Str(String::from("Other")))
+        );
+    }
+
+    #[test]
+    fn contract_from_example() {
+        let check = |value: &str| {
+            eval_string(&format!(
+                "(contract.check {} (contract.fromExample \
+                 {{name = \"a\"; port = 80; tags = [\"x\"]; tls = {{on = true;}};}})).message",
+                value
+            ))
+        };
+
+        assert!(check("{name = \"b\"; port = 8080; tags = []; tls = {on = false;};}").is_err());
+        assert_eq!(
+            check("{name = \"b\"; port = \"8080\"; tags = []; tls = {on = false;};}"),
+            Ok(Term::Str(String::from("contract broken")))
+        );
+        assert_eq!(
+            check("{name = \"b\"; tags = []; tls = {on = false;};}"),
+            Ok(Term::Str(String::from("missing field")))
+        );
+        assert_eq!(
+            check("{name = \"b\"; port = 1; extra = 1; tags = []; tls = {on = false;};}"),
+            Ok(Term::Str(String::from("extra field")))
+        );
+        // Lists are checked against their first element, and nested records recursively
+        assert_eq!(
+            check("{name = \"b\"; port = 1; tags = [1]; tls = {on = false;};}"),
+            Ok(Term::Str(String::from("contract broken")))
+        );
+        assert_eq!(
+            check("{name = \"b\"; port = 1; tags = []; tls = {on = 1;};}"),
+            Ok(Term::Str(String::from("contract broken")))
+        );
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut p = Program::new_from_source(Cursor::new("{f = fun n => 1 + (f n)}.f 0"), "<test>")
+            .unwrap();
+        p.set_stack_limit(Some(1000));
+        match p.eval() {
+            Err(Error::EvalError(EvalError::StackOverflow(1000, calls, _))) => {
+                assert!(!calls.is_empty() && calls.len() <= 10)
+            }
+            res => panic!("expected a stack overflow, got {:?}", res),
+        };
+
+        let source = "{f = fun n => if isZero n then 0 else 1 + (f (n + (-1)))}.f 100";
+        let mut p = Program::new_from_source(Cursor::new(source), "<test>").unwrap();
+        p.set_stack_limit(Some(100));
+        match p.eval() {
+            Err(Error::EvalError(EvalError::StackOverflow(..))) => (),
+            res => panic!("expected a stack overflow, got {:?}", res),
+        };
+        p.set_stack_limit(None);
+        assert_eq!(p.eval(), Ok(Term::Num(100.)));
+
+        // The evaluations nested in primitive operations count toward the limit of the stack
+        let source = "{f = fun n => if isZero n then 0 \
+            else 1 + (iterate 1 (fun x => f (n + (-1))) 0)}.f 20";
+        let mut p = Program::new_from_source(Cursor::new(source), "<test>").unwrap();
+        p.set_stack_limit(Some(20));
+        match p.eval() {
+            Err(Error::EvalError(EvalError::StackOverflow(20, ..))) => (),
+            res => panic!("expected a stack overflow, got {:?}", res),
+        };
+        p.set_stack_limit(None);
+        assert_eq!(p.eval(), Ok(Term::Num(20.)));
+
+        // and their nesting is bounded, instead of overflowing the stack of the host
+        let source = "{f = fun n => if isZero n then 0 \
+            else iterate 1 (fun x => f (n + (-1))) 0}.f 100000";
+        let mut p = Program::new_from_source(Cursor::new(source), "<test>").unwrap();
+        match p.eval() {
+            Err(Error::EvalError(EvalError::StackOverflow(..))) => (),
+            res => panic!("expected a stack overflow, got {:?}", res),
+        };
+    }
+
+    #[test]
+    fn eval_stats() {
+        let mut p = Program::new_from_source(
+            Cursor::new("let f = fun x => x + 1 in let y = f 1 in y + y"),
+            "<test>",
+        )
+        .unwrap();
+        // Keep `f`, which would otherwise be inlined.
+        p.set_inline_threshold(None);
+        let (result, stats) = p.eval_with_stats();
+        assert_eq!(result, Ok(Term::Num(4.)));
+        assert!(stats.reductions > 0);
+        // `y` is forced once, and shared afterwards
+        assert!(stats.thunk_forcings >= 1);
+        assert!(stats.peak_env_size >= 2);
+
+        let mut p = Program::new_from_source(Cursor::new("1 + true"), "<test>").unwrap();
+        let (result, stats) = p.eval_with_stats();
+        result.unwrap_err();
+        assert!(stats.reductions > 0);
+
+        let mut p = Program::new_from_source(Cursor::new("1 +"), "<test>").unwrap();
+        assert_eq!(p.eval_with_stats().1, eval::EvalStats::default());
+
+        // The steps of the functions called by primitive operations are counted
+        let reductions = |key: &str| {
+            let s = format!("head (listSortBy ({}) [3, 1, 2])", key);
+            let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+            let (result, stats) = p.eval_with_stats();
+            assert_eq!(result, Ok(Term::Num(1.)));
+            stats.reductions
+        };
+        assert!(reductions("fun x => x + 0 + 0 + 0") > reductions("fun x => x") + 9);
+    }
+
+    #[test]
+    fn import_stats() {
+        let dir = std::env::temp_dir().join(format!("nickel-import-stats-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("heavy.ncl"),
+            "{ f = fun n => if isZero n then 0 else f (n + (-1)); r = f 100; }.r",
+        )
+        .unwrap();
+        fs::write(dir.join("light.ncl"), "1").unwrap();
+        let s = format!(
+            "(import \"{0}\") + (import \"{1}\") + (import \"{1}\")",
+            dir.join("light.ncl").display(),
+            dir.join("heavy.ncl").display()
+        );
+        let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+        let (result, stats) = p.eval_with_stats();
+        assert_eq!(result, Ok(Term::Num(1.)));
+
+        let imports = p.import_stats();
+        let paths: Vec<_> = imports
+            .iter()
+            .map(|s| s.path.file_name().unwrap())
+            .collect();
+        assert_eq!(paths, vec!["light.ncl", "heavy.ncl"]);
+        assert_eq!(imports[0].cache_hits, 0);
+        assert_eq!(imports[1].cache_hits, 1);
+
+        // Each step is attributed to at most one file.
+        let steps = |file_id| stats.reductions_by_file.get(&file_id).copied();
+        assert!(steps(imports[1].file_id) > steps(imports[0].file_id));
+        assert!(steps(p.main_id).is_some());
+        assert!(stats.reductions_by_file.values().sum::<usize>() <= stats.reductions);
+
+        let report = p.import_report(&stats);
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[0].starts_with("file"));
+        // The most expensive file comes first.
+        assert!(lines[1].contains("heavy.ncl"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn multi_argument_functions() {
+        let reductions = |s: &str| {
+            let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+            p.set_inline_threshold(None);
+            let (result, stats) = p.eval_with_stats();
+            assert_eq!(result, Ok(Term::Num(1.)));
+            stats.reductions
+        };
+
+        // The three parameters are bound in one step, and only the applications cost one more
+        // step each.
+        assert_eq!(
+            reductions("(fun x y z => x) 1 2 3"),
+            reductions("(fun x => x) 1") + 2
+        );
+
+        // Partial applications are still shared.
+        assert_eq!(
+            eval_string("let add = fun x y z => x + y + z in let g = add 1 2 in g 3 + g (-3)"),
+            Ok(Term::Num(6.))
+        );
+        assert_eq!(
+            eval_string("let f = fun x y => fun z => x + y + z in f 1 2 3"),
+            Ok(Term::Num(6.))
+        );
+    }
+
+    #[test]
+    fn inlining() {
+        let source =
+            "let f = fun x => x + 1 in let g = fun x => f x in {a = g 1; b = let z = 1 in f}.a";
+        let eval_with = |threshold| {
+            let mut p = Program::new_from_source(Cursor::new(source), "<test>").unwrap();
+            p.set_inline_threshold(threshold);
+            p.eval_with_stats()
+        };
+
+        let (result, inlined) = eval_with(Some(inline::DEFAULT_THRESHOLD));
+        assert_eq!(result, Ok(Term::Num(2.)));
+        let (result, not_inlined) = eval_with(None);
+        assert_eq!(result, Ok(Term::Num(2.)));
+        assert!(inlined.reductions < not_inlined.reductions);
+
+        // A free variable of the function is bound again at its use.
+        assert_eq!(
+            eval_string("let y = 1 in let f = fun x => x + y in let y = 2 in f 0"),
+            Ok(Term::Num(1.))
+        );
+        // The variable is shadowed.
+        assert_eq!(
+            eval_string("let f = fun x => x + 1 in (fun f => f 0) (fun x => x)"),
+            Ok(Term::Num(0.))
+        );
+        // Functions used several times or too large are kept.
+        assert_eq!(
+            eval_string("let f = fun x => x + 1 in f (f 0)"),
+            Ok(Term::Num(2.))
+        );
+        assert_eq!(
+            eval_string(
+                "let f = fun x => x + 1 + 1 + 1 + 1 + 1 + 1 + 1 + 1 + 1 + 1 + 1 + 1 + 1 in f 0"
+            ),
+            Ok(Term::Num(13.))
+        );
+    }
+
+    #[test]
+    fn access_sharing() {
+        let config = "let config = {network = {dns = {servers = [\"a\"]; port = 53}}} in";
+        assert_eq!(
+            eval_string(&format!(
+                "{} {{servers = config.network.dns.servers; port = config.network.dns.port}}.port",
+                config
+            )),
+            Ok(Term::Num(53.))
+        );
+
+        // Errors still point to the failing access.
+        let mut p = Program::new_from_source(
+            Cursor::new(format!(
+                "{} {{a = config.network.dhcp.port; b = config.network.dhcp.host}}.a",
+                config
+            )),
+            "<test>",
+        )
+        .unwrap();
+        let err = p.eval().unwrap_err();
+        assert!(matches!(err, Error::EvalError(EvalError::FieldMissing(..))));
+        assert!(p.primary_location(&err).is_some());
+    }
+
+    #[test]
+    fn sealing() {
+        assert_eq!(
+            eval_string("let k = seals.key \"secret\" in seals.unseal k (seals.seal k 1) + 1"),
+            Ok(Term::Num(2.))
+        );
+
+        // A sealed value is opaque
+        eval_string("let k = seals.key \"secret\" in (seals.seal k 1) + 1").unwrap_err();
+
+        // Keys are distinct, even when they have the same description
+        match eval_string(
+            "let k1 = seals.key \"secret\" in let k2 = seals.key \"secret\" in
+            seals.unseal k2 (seals.seal k1 1)",
+        ) {
+            Err(Error::EvalError(EvalError::BlameError(..))) => (),
+            res => panic!("expected a blame error, got {:?}", res),
+        };
+
+        // A user-defined abstract type
+        assert_eq!(
+            eval_string(
+                "let money = let k = seals.key \"money\" in {
+                    make = fun n => seals.seal k n;
+                    add = fun m1 m2 => seals.seal k (seals.unseal k m1 + seals.unseal k m2);
+                    value = fun m => seals.unseal k m;
+                } in
+                money.value (money.add (money.make 1) (money.make 2))"
+            ),
+            Ok(Term::Num(3.))
+        );
+    }
+
+    #[test]
+    fn laziness() {
+        assert_eq!(
+            eval_string("let s = lazy (1 + 2) in force s + force s"),
+            Ok(Term::Num(6.))
+        );
+        // A suspension is a value, which is not evaluated by `deepSeq`
+        assert_eq!(
+            eval_string("deepSeq {a = lazy (1 + true);} 1"),
+            Ok(Term::Num(1.))
+        );
+        eval_string("force (lazy (1 + true))").unwrap_err();
+        match eval_string("force 1") {
+            Err(Error::EvalError(EvalError::BlameError(..))) => (),
+            res => panic!("expected a blame error, got {:?}", res),
+        };
+
+        // The suspended expression is evaluated at most once
+        let reductions = |s: &str| {
+            let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+            let (result, stats) = p.eval_with_stats();
+            result.unwrap();
+            stats.reductions
+        };
+        let sum = "lists.foldl (fun acc x => acc + x) 0 (listRange 0 10 1)";
+        let unused = reductions(&format!("let s = lazy ({}) in 0", sum));
+        let once = reductions(&format!("let s = lazy ({}) in force s", sum));
+        let twice = reductions(&format!("let s = lazy ({}) in force s + force s", sum));
+        assert!(twice - once < (once - unused) / 10);
+    }
+
+    #[test]
+    fn stdlib_types() {
+        assert_eq!(
+            eval_string("Promise(List, lists.concat [1] [2]) == [1, 2]"),
+            Ok(Term::Bool(true))
+        );
+        // Polymorphic fields are instantiated at each use.
+        assert_eq!(
+            eval_string(
+                "Promise(Num, lists.foldl (fun acc x => acc) 0 [true]) \
+                + Promise(Num, lists.fold (fun x acc => acc) [\"a\"] 1)"
+            ),
+            Ok(Term::Num(1.))
+        );
+
+        match eval_string("Promise(Num, lists.concat [1] [2])") {
+            Err(Error::TypecheckError(_)) => (),
+            res => panic!("expected a type error, got {:?}", res),
+        };
+        match eval_string("Promise(Num, seals.key \"a\")") {
+            Err(Error::TypecheckError(_)) => (),
+            res => panic!("expected a type error, got {:?}", res),
+        };
+
+        // Only the entries relying on dynamic typing keep the type `Dyn`.
+        eval_string("1").unwrap();
+        let mut untyped: Vec<String> = STDLIB_CACHE
+            .with(|cache| cache.borrow().clone())
+            .unwrap()
+            .iter()
+            .filter(|(_, _, ty)| ty.is_none())
+            .map(|(Ident(id), _, _)| id.clone())
+            .collect();
+        untyped.sort();
+        assert_eq!(untyped, vec!["allOf", "anyOf", "contract", "record_extend"]);
+    }
+
+    #[test]
+    fn shared_stdlib() {
+        let src = "lists.foldl (fun x y => x + y) 0 [1, 2, 3]";
+        let mut p = Program::new_from_source(Cursor::new(src), "<test>").unwrap();
+        assert_eq!(p.eval(), Ok(Term::Num(6.)));
+        let cached = STDLIB_CACHE.with(|cache| cache.borrow().clone()).unwrap();
+
+        // The second program reuses the processed standard library
+        let mut p = Program::new_from_source(Cursor::new(src), "<test>").unwrap();
+        assert_eq!(p.eval(), Ok(Term::Num(6.)));
+        let reused = STDLIB_CACHE.with(|cache| cache.borrow().clone()).unwrap();
+        assert!(Rc::ptr_eq(&cached, &reused));
+
+        // Positions of the shared terms are valid in any program
+        let pos = cached.iter().find_map(|(_, t, _)| t.pos.as_ref()).unwrap();
+        assert!(p
+            .files
+            .name(pos.src_id)
+            .to_string_lossy()
+            .starts_with("<stdlib/"));
+    }
+
+    #[test]
+    fn primary_location() {
+        let mut p =
+            Program::new_from_source(Cursor::new("let x = 1 in\nx + true"), "<test>").unwrap();
+        let err = p.eval().unwrap_err();
+        assert_eq!(
+            p.primary_location(&err),
+            Some(SourceLocation {
+                file: String::from("<test>"),
+                start: (1, 4),
+                end: (1, 8),
+            })
+        );
+
+        let mut p = Program::new_from_source(Cursor::new("1"), "<test>").unwrap();
+        let err = Error::from(SerializationError::IOError(String::from("closed")));
+        assert_eq!(p.primary_location(&err), None);
+    }
+
+    #[test]
+    fn report_to_string() {
+        let mut p =
+            Program::new_from_source(Cursor::new("let x = \"é\" in\nx + 1"), "<test>").unwrap();
+        let err = p.eval().unwrap_err();
+        let report = p.report_to_string(err);
+
+        assert!(report.starts_with("error[E0002]: Type error"));
+        assert!(report.contains("<test>:1:9"));
+        assert!(report.contains("let x = \"é\" in"));
+        assert!(!report.contains('\u{1b}'));
+    }
+
+    #[test]
+    fn report_to_json() {
+        let mut p =
+            Program::new_from_source(Cursor::new("let x = 1 in\nx + true"), "<test>").unwrap();
+        let err = p.eval().unwrap_err();
+        let report: serde_json::Value = serde_json::from_str(&p.report_to_json(err)).unwrap();
+
+        assert_eq!(report[0]["severity"], "error");
+        assert_eq!(report[0]["code"], "E0002");
+        assert_eq!(report[0]["message"], "Type error");
+
+        let primary = report[0]["labels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|label| label["primary"] == true)
+            .unwrap();
+        assert_eq!(primary["file"], "<test>");
+        assert_eq!(primary["start"], json!({"line": 1, "column": 4}));
+        assert_eq!(primary["end"], json!({"line": 1, "column": 8}));
+    }
+
+    #[test]
+    fn blame_explanation() {
+        let report = |s: &str| {
+            let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+            let err = p.eval().unwrap_err();
+            p.report_to_string(err)
+        };
+
+        assert!(report("let f = Assume(Num -> Num, fun x => x) in\nf true")
+            .contains("Blame: the caller of `f` at <test>:2:1 provided an invalid argument."));
+        assert!(
+            report("let f = Assume(Num -> Num -> Num, fun x y => x + y) in\n1 + (f 1 true)")
+                .contains("the caller of `f` at <test>:2:5 provided an invalid argument.")
+        );
+        assert!(report("let f = Assume(Num -> Num, fun x => true) in f 1")
+            .contains("the function `f` at <test>:1:46 returned an invalid value."));
+
+        // Higher-order contracts.
+        assert!(
+            report("let f = Assume((Num -> Num) -> Num, fun g => g 0) in f (fun x => true)")
+                .contains(
+                    "the caller of `f` at <test>:1:54 provided a function which returned an \
+                     invalid value."
+                )
+        );
+        assert!(
+            report("let f = Assume((Num -> Num) -> Num, fun g => g true) in f (fun x => x)")
+                .contains(
+                    "the function `f` at <test>:1:57 applied one of its function arguments to an \
+                     invalid value."
+                )
+        );
+        assert!(report(
+            "let f = Assume(((Num -> Num) -> Num) -> Num, fun h => h (fun x => true)) in \
+             f (fun g => g 0)"
+        )
+        .contains("the function `f` at <test>:1:77 passed a function which returned"));
+
+        assert!(!report("Assume(Num, true)").contains("Blame:"));
+    }
+
+    #[test]
+    fn eval_partial() {
+        let path = |ids: &[&str]| -> Vec<Ident> { ids.iter().map(|id| Ident::from(*id)).collect() };
+        let mut p = Program::new_from_source(
+            Cursor::new(
+                "{a = 1; b = {c = Assume(Num, true); d = \"d\"}; e = [1, {}.f]; \
+                 g = Contract(Str); h = {i = 1 + 1}}",
+            ),
+            "<test>",
+        )
+        .unwrap();
+        let result = p.eval_partial().unwrap();
+
+        let failed: Vec<_> = result.errors.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(failed, vec![path(&["b", "c"]), path(&["e"]), path(&["g"])]);
+        assert!(matches!(result.errors[0].1, EvalError::BlameError(..)));
+        assert!(matches!(result.errors[1].1, EvalError::FieldMissing(..)));
+
+        match result.term {
+            Term::Record(fields) => {
+                assert_eq!(*fields[&Ident::from("a")].term, Term::Num(1.0));
+                assert_eq!(
+                    *fields[&Ident::from("g")].term,
+                    Term::MetaValue(crate::term::MetaValue::default())
+                );
+                match fields[&Ident::from("b")].as_ref() {
+                    Term::Record(inner) => {
+                        assert_eq!(*inner[&Ident::from("d")].term, Term::Str(String::from("d")))
+                    }
+                    t => panic!("expected a record, got {:?}", t),
+                }
+                match fields[&Ident::from("h")].as_ref() {
+                    Term::Record(inner) => {
+                        assert_eq!(*inner[&Ident::from("i")].term, Term::Num(2.0))
+                    }
+                    t => panic!("expected a record, got {:?}", t),
+                }
+            }
+            t => panic!("expected a record, got {:?}", t),
+        }
+
+        // The root itself must evaluate.
+        let mut p = Program::new_from_source(Cursor::new("1 + true"), "<test>").unwrap();
+        assert!(matches!(
+            p.eval_partial(),
+            Err(Error::EvalError(EvalError::TypeError(..)))
+        ));
+    }
+
+    #[test]
+    fn export_json() {
+        assert_eq!(
+            export_string("{b = [1, (0.5 + 1), \"x\\\"y\"]; a = {}; c = {d = isZero 0; e = []}}"),
+            Ok(String::from(
+                r#"{
+  "a": {},
+  "b": [
+    1,
+    1.5,
+    "x\"y"
+  ],
+  "c": {
+    "d": true,
+    "e": []
+  }
+}
+"#
+            ))
+        );
+
+        let range = "{r = fun n => if isZero n then [] else (r (n + (-1))) @ [n]}.r 100";
+        let exported = export_string(range).unwrap();
+        assert_eq!(exported.lines().count(), 102);
+        assert_eq!(exported.lines().nth(100), Some("  100"));
+
+        match export_string("{a = [1, (fun x => x)]}") {
+            Err(Error::SerializationError(SerializationError::NonSerializable(_))) => (),
+            res => panic!("expected a serialization error, got {:?}", res),
+        };
+        match export_string("{a = 1 + true}") {
+            Err(Error::EvalError(EvalError::TypeError(..))) => (),
+            res => panic!("expected a type error, got {:?}", res),
+        };
+    }
+
+    #[test]
+    fn export_aggregate() {
+        let options = ExportOptions {
+            aggregate: true,
+            ..Default::default()
+        };
+        let config = "{a = Assume(Num, true); b = {c = [1, Assume(Bool, 2)]; d = 3}; e = 4}";
+
+        let (err, out) = export_string_with(config, &options).unwrap_err();
+        match err {
+            Error::SerializationError(SerializationError::ContractViolations(violations)) => {
+                let paths: Vec<&str> = violations.iter().map(|(path, _)| path.as_str()).collect();
+                assert_eq!(paths, vec!["a", "b.c[1]"]);
+            }
+            err => panic!("expected contract violations, got {:?}", err),
+        };
+        assert_eq!(
+            out,
+            "{\n  \"a\": null,\n  \"b\": {\n    \"c\": [\n      1,\n      null\n    ],\n    \
+             \"d\": 3\n  },\n  \"e\": 4\n}\n"
+        );
+
+        // Without the aggregate mode, the export stops at the first violation
+        match export_string(config) {
+            Err(Error::EvalError(EvalError::BlameError(..))) => (),
+            res => panic!("expected a blame error, got {:?}", res),
+        };
+        // Other errors are not aggregated
+        match export_string_with("{a = 1 + true; b = Assume(Num, true)}", &options) {
+            Err((Error::EvalError(EvalError::TypeError(..)), _)) => (),
+            res => panic!("expected a type error, got {:?}", res),
+        };
+        assert_eq!(
+            export_string_with("[1, 2]", &options),
+            Ok(String::from("[\n  1,\n  2\n]\n"))
+        );
+    }
+
+    #[test]
+    fn export_key_order() {
+        let config = "let x = 1 in {kind = \"a\"; b = x + 1; a = {z = true; y = x}; version = 2}";
+        let export = |key_order: KeyOrder| {
+            export_string_with(
+                config,
+                &ExportOptions {
+                    key_order,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .lines()
+            .map(|line| line.trim().split(':').next().unwrap().to_string())
+            .filter(|line| line.starts_with('"'))
+            .collect::<Vec<_>>()
+            .join(" ")
+        };
+
+        assert_eq!(
+            export(KeyOrder::Alphabetical),
+            r#""a" "y" "z" "b" "kind" "version""#
+        );
+        assert_eq!(
+            export(KeyOrder::Definition),
+            r#""kind" "b" "a" "z" "y" "version""#
+        );
+        assert_eq!(
+            export(KeyOrder::Priority(vec![
+                String::from("version"),
+                String::from("kind"),
+                String::from("z"),
+            ])),
+            r#""version" "kind" "a" "z" "y" "b""#
+        );
+        // The output is the same from one export to the next.
+        assert_eq!(export(KeyOrder::Definition), export(KeyOrder::Definition));
+    }
+
+    #[test]
+    fn export_header() {
+        let options = |format: ExportFormat| ExportOptions {
+            format,
+            header: Some(String::from(
+                "generated by Nickel, do not edit\n\nsee config.ncl",
+            )),
+            ..Default::default()
+        };
+
+        let out = export_string_with("{a = [1]}", &options(ExportFormat::Yaml)).unwrap();
+        assert_eq!(
+            out,
+            "# generated by Nickel, do not edit\n#\n# see config.ncl\n\
+             {\n  \"a\": [\n    1\n  ]\n}\n"
+        );
+        let mut value =
+            serialize::from_yaml(&out, Files::new().add("<test>", String::from(""))).unwrap();
+        value.clean_pos();
+        assert_eq!(
+            *value.term,
+            Term::Record(
+                vec![(
+                    Ident::from("a"),
+                    Term::List(vec![Term::Num(1.).into()]).into()
+                )]
+                .into_iter()
+                .collect()
+            )
+        );
+
+        // JSON has no comments.
+        assert_eq!(
+            export_string_with("{a = [1]}", &options(ExportFormat::Json)),
+            export_string("{a = [1]}").map_err(|err| (err, String::new()))
+        );
+    }
+
+    #[test]
+    fn export_newline() {
+        let options = ExportOptions {
+            format: ExportFormat::Yaml,
+            header: Some(String::from("generated")),
+            newline: Newline::CrLf,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            export_string_with("{a = [\"x\\ny\"]}", &options),
+            Ok(String::from(
+                "# generated\r\n{\r\n  \"a\": [\r\n    \"x\\ny\"\r\n  ]\r\n}\r\n"
+            ))
+        );
+    }
+
+    #[test]
+    fn export_limits() {
+        let options = ExportOptions {
+            max_list_length: Some(3),
+            max_size: Some(100),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            export_string_with("{a = [1, 2, 3]}", &options),
+            Ok(String::from(
+                "{\n  \"a\": [\n    1,\n    2,\n    3\n  ]\n}\n"
+            ))
+        );
+        match export_string_with("{a = [1, 2]; b = {c = listRange 0 10 1}}", &options) {
+            Err((
+                Error::SerializationError(SerializationError::ListTooLong(path, 10, 3, _)),
+                _,
+            )) => assert_eq!(path, "b.c"),
+            res => panic!("expected a list too long error, got {:?}", res),
+        };
+        // The intermediate lists are checked too, before they would blow up the memory.
+        match export_string_with("{a = length (iterate 64 (fun l => l @ l) [1])}", &options) {
+            Err((
+                Error::SerializationError(SerializationError::ListTooLong(path, 4, 3, Some(_))),
+                _,
+            )) => assert_eq!(path, "a"),
+            res => panic!("expected a list too long error, got {:?}", res),
+        };
+        match export_string_with(
+            "{a = [\"0123456789\", \"0123456789\"]; b = {c = \"0123456789\"; d = \"0123456789\"}}",
+            &options,
+        ) {
+            Err((
+                Error::SerializationError(SerializationError::SizeLimitExceeded(path, 100)),
+                out,
+            )) => {
+                assert_eq!(path, "b.d");
+                assert!(out.len() <= 100);
+            }
+            res => panic!("expected a size limit error, got {:?}", res),
+        };
+    }
+
+    #[test]
+    fn export_non_finite() {
+        let pow = |x: &str| {
+            format!(
+                "({{d = fun n x => if isZero n then x else d (n + (-1)) (x + x)}}.d 1100 {})",
+                x
+            )
+        };
+        let (inf, minus_inf) = (pow("1"), pow("(-1)"));
+        let nan = format!("({} + {})", inf, minus_inf);
+        let list = format!("[{}, {}, {}]", inf, minus_inf, nan);
+
+        assert_eq!(
+            export_string_with(
+                &list,
+                &ExportOptions {
+                    non_finite: NonFinitePolicy::Null,
+                    ..Default::default()
+                }
+            ),
+            Ok(String::from("[\n  null,\n  null,\n  null\n]\n"))
+        );
+        assert_eq!(
+            export_string_with(
+                &list,
+                &ExportOptions {
+                    non_finite: NonFinitePolicy::String,
+                    ..Default::default()
+                }
+            ),
+            Ok(String::from(
+                "[\n  \"Infinity\",\n  \"-Infinity\",\n  \"NaN\"\n]\n"
+            ))
+        );
+        match export_string(&list) {
+            Err(Error::SerializationError(SerializationError::NonFiniteNumber(n, _)))
+                if n == f64::INFINITY => {}
+            res => panic!("expected a serialization error, got {:?}", res),
+        };
+
+        assert_eq!(
+            eval_string("Assume(#nums.IsFinite, 0.5 + 1)"),
+            Ok(Term::Num(1.5))
+        );
+        assert_eq!(eval_string("Assume(#nums.IsFinite, 0)"), Ok(Term::Num(0.)));
+        for t in [inf, nan, String::from("true")].iter() {
+            match eval_string(&format!("Assume(#nums.IsFinite, {})", t)) {
+                Err(Error::EvalError(EvalError::BlameError(..))) => (),
+                res => panic!("expected a blame error, got {:?}", res),
+            };
+        }
+    }
+
+    #[test]
+    fn export_num_format() {
+        use crate::serialize::NumFormat;
+
+        let list = "[(0.1 + 0.2), 0.00000001, 1500]";
+        assert_eq!(
+            export_string(list),
+            Ok(String::from(
+                "[\n  0.30000000000000004,\n  1e-8,\n  1500\n]\n"
+            ))
+        );
+        assert_eq!(
+            export_string_with(
+                list,
+                &ExportOptions {
+                    num_format: NumFormat {
+                        precision: Some(6),
+                        max_exponent: 3,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }
+            ),
+            Ok(String::from("[\n  0.3,\n  1e-8,\n  1.5e3\n]\n"))
+        );
+        // Interpolated numbers are written with the default format.
+        assert_eq!(
+            eval_string("\"${0.00000001} ${0.25}\""),
+            Ok(Term::Str(String::from("1e-8 0.25")))
+        );
+    }
+
+    #[test]
+    fn type_table() {
+        use crate::types::AbsType;
+
+        let s = "let f = Promise(Num -> Num, fun x => x + 1) in f 2";
+        let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+        let table = p.type_table().unwrap();
+        let type_of = |snippet: &str| {
+            table
+                .iter()
+                .find(|(span, _)| &s[span.start.to_usize()..span.end.to_usize()] == snippet)
+                .map(|(_, ty)| ty.clone())
+        };
+
+        let num = || Box::new(Types(AbsType::Num()));
+        assert_eq!(type_of("x + 1"), Some(*num()));
+        assert_eq!(
+            type_of("fun x => x + 1"),
+            Some(Types(AbsType::arrow(num(), num())))
+        );
+        // Outside of `Promise`, terms are not typechecked.
+        assert!(matches!(type_of("2"), Some(Types(AbsType::Var(_)))));
+
+        let mut p = Program::new_from_source(Cursor::new("Promise(Num, \"a\")"), "<test>").unwrap();
+        assert!(matches!(p.type_table(), Err(Error::TypecheckError(_))));
+    }
+
+    #[test]
+    fn node_ids() {
+        use crate::term::NodeId;
+        use crate::types::AbsType;
+
+        let s = "Promise(Num, let x = 1 in {a = x + 1; b = [x]}.a)";
+        let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+        let t = p.parse(p.main_id).unwrap();
+        assert_eq!(t.id, Some(NodeId(p.main_id, 0)));
+
+        // Identities are unique, and the same when parsing again.
+        let mut ids = Vec::new();
+        let mut collect = |rt: RichTerm, ids: &mut Vec<NodeId>| -> Result<RichTerm, ()> {
+            ids.extend(rt.id);
+            Ok(rt)
+        };
+        t.clone().traverse(&mut collect, &mut ids).unwrap();
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+        let again = p.parse(p.main_id).unwrap();
+        let mut ids_again = Vec::new();
+        again.traverse(&mut collect, &mut ids_again).unwrap();
+        assert_eq!(ids, ids_again);
+
+        let sum = ids
+            .iter()
+            .filter_map(|id| t.find_id(*id))
+            .find(|rt| matches!(rt.as_ref(), Term::Op2(BinaryOp::Plus(), _, _)))
+            .unwrap()
+            .clone();
+        let sum_id = sum.id.unwrap();
+
+        // The nodes keep their identity through the program transformations: the record is put
+        // in share normal form, and its fields are bound to fresh variables.
+        let transformed = transformations::transform(t, &mut p).unwrap();
+        assert_eq!(transformed.find_id(sum_id), Some(&sum));
+
+        let table = p.node_type_table().unwrap();
+        assert_eq!(table.get(&sum_id), Some(&Types(AbsType::Num())));
+    }
+
+    #[test]
+    fn check_annotations() {
+        use crate::typecheck::AnnotationKind;
+        use crate::types::AbsType;
+
+        let s = "let f = Promise(Num -> Num, fun x => x + 1) in \
+            let g = Assume(Str, 1) in \
+            Promise(Num, f Promise(Str, \"a\"))";
+        let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+        let annotations = p.check_annotations().unwrap();
+        let snippets: Vec<&str> = annotations
+            .iter()
+            .map(|annot| {
+                let span = annot.span.as_ref().unwrap();
+                &s[span.start.to_usize()..span.end.to_usize()]
+            })
+            .collect();
+
+        assert_eq!(
+            snippets,
+            vec![
+                "Promise(Num -> Num, fun x => x + 1)",
+                "Assume(Str, 1)",
+                "Promise(Num, f Promise(Str, \"a\"))",
+                "Promise(Str, \"a\")",
+            ]
+        );
+        assert_eq!(annotations[1].kind, AnnotationKind::Assume);
+        assert_eq!(
+            annotations
+                .iter()
+                .map(|annot| annot.is_ok())
+                .collect::<Vec<_>>(),
+            vec![true, true, true, false]
+        );
+
+        // The error of the innermost block is confined to it.
+        let inner = &annotations[3];
+        assert_eq!(inner.declared, Types(AbsType::Str()));
+        assert_eq!(inner.inferred, Types(AbsType::Num()));
+        assert!(matches!(
+            inner.error,
+            Some(TypecheckError::TypeMismatch(..))
+        ));
+        assert!(matches!(annotations[2].inferred, Types(AbsType::Var(_))));
+
+        // Errors outside of any block are still reported.
+        let mut p = Program::new_from_source(Cursor::new("Promise(Num, 1) + y"), "<test>").unwrap();
+        assert!(matches!(
+            p.check_annotations(),
+            Err(Error::TypecheckError(_))
+        ));
+
+        // An error in the scope of a polymorphic annotation doesn't prevent the generalization of
+        // the enclosing let binding.
+        let s = "Promise(Num, \
+            let id = fun x => (let f = Promise(forall a. a -> a, fun y => y + 1) in x) in \
+            if id true then id 1 else 0)";
+        let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+        let annotations = p.check_annotations().unwrap();
+        assert_eq!(
+            annotations
+                .iter()
+                .map(|annot| annot.is_ok())
+                .collect::<Vec<_>>(),
+            vec![true, false]
+        );
+    }
+
+    #[test]
+    fn cancellation_token() {
+        let s = "Promise(Num, let f = fun x => x + 1 in f 2)";
+        let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+        let token = CancellationToken::new();
+        p.set_cancellation_token(Some(token.clone()));
+        assert!(p.type_table().is_ok());
+
+        token.cancel();
+        assert!(matches!(
+            p.type_table(),
+            Err(Error::TypecheckError(TypecheckError::Cancelled(_)))
+        ));
+        assert!(matches!(
+            p.eval(),
+            Err(Error::TypecheckError(TypecheckError::Cancelled(_)))
+        ));
+
+        p.set_cancellation_token(None);
+        assert_eq!(p.eval(), Ok(Term::Num(3.)));
+    }
+
+    #[test]
+    fn record_fields_at() {
+        use codespan::ByteIndex;
+
+        let s = "Promise(Num, let f = fun r => r.a + r.b in let x = {a = 1; b = 2; c = 3} in 0)";
+        let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+        let src_id = p.main_id;
+        let mut fields_at = |snippet: &str, len: usize| {
+            let start = s.find(snippet).unwrap();
+            let span = RawSpan {
+                src_id,
+                start: ByteIndex::from(start as u32),
+                end: ByteIndex::from((start + len) as u32),
+            };
+
+            p.record_fields_at(&span).unwrap().map(|fields| {
+                let mut fields: Vec<String> = fields.into_iter().map(|Ident(id)| id).collect();
+                fields.sort();
+                fields
+            })
+        };
+
+        // The fields of `r` are inferred from the way it is used.
+        assert_eq!(
+            fields_at("r.a", 1),
+            Some(vec![String::from("a"), String::from("b")])
+        );
+        assert_eq!(
+            fields_at("{a = 1", 21),
+            Some(vec![
+                String::from("a"),
+                String::from("b"),
+                String::from("c")
+            ])
+        );
+        assert_eq!(fields_at("1;", 1), None);
+    }
+}
@@ -0,0 +1,136 @@
+//! Overrides of the fields of a program.
+//!
+//! The fields of a program can be overridden by values given by the host, such as values given on
+//! the command line over a configuration file. The program is merged with the overriding values
+//! using the overriding merge (see the [merge module](../../merge/index.html)).
+use super::Program;
+use crate::error::Error;
+use crate::eval;
+use crate::identifier::Ident;
+use crate::term::{BinaryOp, RichTerm, Term};
+
+impl Program {
+    /// Override the field of the program at `path` with `value`, which must be a closed term.
+    ///
+    /// The program is merged with a record defining only this field, using the same overriding
+    /// merge as [`eval_with_overrides`](#method.eval_with_overrides): `value` has a priority above
+    /// the definitive values of the program, but is still checked by its contracts. The
+    /// intermediate fields of the path are created if they do not exist. An empty path overrides
+    /// the whole program.
+    pub fn add_override(&mut self, path: Vec<Ident>, value: RichTerm) {
+        self.overrides.push((path, value));
+    }
+
+    /// Same as [`eval_full`](#method.eval_full), but merge the record `overrides` on top of the
+    /// program, with a priority above the definitive values of the program: fields defined in
+    /// `overrides` always win, but are still checked by the contracts of the program (see the
+    /// [merge module](../merge/index.html)). `overrides` must be a closed term.
+    ///
+    /// This implements the layering of configuration sources, such as values given on the command
+    /// line over a configuration file.
+    ///
+    /// The overrides added with [`add_override`](#method.add_override) are applied first.
+    pub fn eval_with_overrides(&mut self, overrides: RichTerm) -> Result<Term, Error> {
+        let (t, global_env) = self.prepare_eval_with(Some(overrides), true)?;
+        eval::eval_full(t, global_env, self, self.stack_limit).map_err(|e| e.into())
+    }
+}
+
+/// Override the field at `path` of the record `t` with `value`, by merging `t` with a record
+/// defining only this field, using the overriding merge (see the [merge
+/// module](../../merge/index.html)). For the path `a.b`:
+///
+/// ```text
+/// t <override> {a = {b = value}}
+/// ```
+pub(super) fn with_override(t: RichTerm, path: &[Ident], value: RichTerm) -> RichTerm {
+    let value = path.iter().rev().fold(value, |value, id| {
+        Term::Record(std::iter::once((id.clone(), value)).collect()).into()
+    });
+    Term::Op2(BinaryOp::MergeOverride(), t, value).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::EvalError;
+    use std::io::Cursor;
+
+    #[test]
+    fn eval_with_overrides() {
+        let record = |fields: Vec<(&str, Term)>| -> RichTerm {
+            Term::Record(
+                fields
+                    .into_iter()
+                    .map(|(id, t)| (Ident(String::from(id)), t.into()))
+                    .collect(),
+            )
+            .into()
+        };
+        let clean = |t: Term| {
+            let mut rt = RichTerm::from(t);
+            rt.clean_pos();
+            rt
+        };
+        let eval = |src: &str, overrides: RichTerm| {
+            Program::new_from_source(Cursor::new(src), "<test>")
+                .unwrap()
+                .eval_with_overrides(overrides)
+        };
+
+        let src = "{a = 1; b = {c = Default(\"x\"); d = 2}; e = Contract(Num); f = true}";
+        let overrides = record(vec![
+            ("a", Term::Num(2.0)),
+            (
+                "b",
+                Term::Record(
+                    vec![(
+                        Ident(String::from("c")),
+                        Term::Str(String::from("y")).into(),
+                    )]
+                    .into_iter()
+                    .collect(),
+                ),
+            ),
+            ("e", Term::Num(3.0)),
+            ("g", Term::Bool(false)),
+        ]);
+        let expected = record(vec![
+            ("a", Term::Num(2.0)),
+            (
+                "b",
+                Term::Record(
+                    vec![
+                        (
+                            Ident(String::from("c")),
+                            Term::Str(String::from("y")).into(),
+                        ),
+                        (Ident(String::from("d")), Term::Num(2.0).into()),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ),
+            ),
+            ("e", Term::Num(3.0)),
+            ("f", Term::Bool(true)),
+            ("g", Term::Bool(false)),
+        ]);
+        assert_eq!(eval(src, overrides).map(clean), Ok(clean(expected.into())));
+
+        // A record can be replaced by a simple value, and conversely.
+        assert_eq!(
+            eval(
+                src,
+                record(vec![("b", Term::Num(0.0)), ("e", Term::Num(3.0))])
+            )
+            .map(clean),
+            eval("{a = 1; b = 0; e = 3; f = true}", record(vec![])).map(clean)
+        );
+
+        // Overrides are checked by the contracts of the program.
+        match eval(src, record(vec![("e", Term::Bool(true))])) {
+            Err(Error::EvalError(EvalError::BlameError(..))) => (),
+            res => panic!("expected a blame error, got {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,1166 @@
+//! Conversion between Nickel values and foreign data formats.
+//!
+//! Foreign data, such as JSON or YAML files, can be imported into a Nickel program. A foreign
+//! value is converted to the corresponding Nickel value: objects become records, arrays become
+//! lists, and so on. Null values have no counterpart in Nickel and are currently rejected. The
+//! converted terms keep the position of the corresponding node in the foreign file, such that a
+//! contract broken by an imported value, as in `import "data.json" | Schema`, is reported there.
+//!
+//! Conversely, the result of a program can be exported to JSON or YAML. Only booleans, numbers,
+//! strings, lists and records can be exported. A YAML export may start with a header comment,
+//! typically to warn that the file is generated.
+//!
+//! By default, the export stops at the first error. For validating large configurations, the
+//! export can instead run in aggregate mode (see [`ExportOptions`](struct.ExportOptions.html)):
+//! a field or an element whose evaluation breaks a contract is exported as `null`, the export
+//! carries on with the rest of the value, and all the contract violations are reported together
+//! at the end, each with the path of the offending value.
+use crate::collections::HashMap;
+use crate::error::{Error, EvalError, SerializationError};
+use crate::eval::{self, Closure, Environment};
+use crate::identifier::Ident;
+use crate::parser::lexer::BOM;
+use crate::position::RawSpan;
+use crate::program::ImportResolver;
+use crate::term::{RecordData, RichTerm, Term};
+use codespan::{ByteIndex, FileId};
+use std::io::Write;
+use std::path::Path;
+
+/// The format of an imported file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Nickel,
+    Json,
+    Yaml,
+}
+
+impl InputFormat {
+    /// Determine the format of a file from the extension of its path. Return `None` if the
+    /// extension is not one of `ncl`, `json`, `yaml` or `yml`.
+    pub fn from_path(path: &Path) -> Option<InputFormat> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ncl") => Some(InputFormat::Nickel),
+            Some("json") => Some(InputFormat::Json),
+            Some("yaml") | Some("yml") => Some(InputFormat::Yaml),
+            _ => None,
+        }
+    }
+
+    /// The name of the format, used in error messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            InputFormat::Nickel => "Nickel",
+            InputFormat::Json => "JSON",
+            InputFormat::Yaml => "YAML",
+        }
+    }
+}
+
+/// Parse a JSON document to a Nickel value. The terms of the result are annotated with their
+/// position in the document, identified by `file_id`.
+pub fn from_json(source: &str, file_id: FileId) -> Result<RichTerm, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(skip_bom(source)).map_err(|err| format!("{}", err))?;
+    let mut rt = json_to_term(value)?;
+    set_positions(&mut rt, JsonSpans::new(source, file_id).value());
+    Ok(rt)
+}
+
+/// Skip the byte order mark at the beginning of a document, if any. serde_json rejects it, and
+/// serde_yaml considers it as part of the content.
+fn skip_bom(source: &str) -> &str {
+    if source.starts_with(BOM) {
+        &source[BOM.len_utf8()..]
+    } else {
+        source
+    }
+}
+
+/// Parse a YAML document to a Nickel value. The terms of the result are annotated with their
+/// position in the document, identified by `file_id`.
+pub fn from_yaml(source: &str, file_id: FileId) -> Result<RichTerm, String> {
+    let value: serde_yaml::Value =
+        serde_yaml::from_str(skip_bom(source)).map_err(|err| format!("{}", err))?;
+    let mut rt = yaml_to_term(value)?;
+    if let Some(spans) = YamlSpans::parse(source, file_id) {
+        set_positions(&mut rt, spans);
+    }
+    Ok(rt)
+}
+
+fn json_to_term(value: serde_json::Value) -> Result<RichTerm, String> {
+    use serde_json::Value;
+
+    let t = match value {
+        Value::Null => return Err(String::from("null values are not supported")),
+        Value::Bool(b) => Term::Bool(b),
+        Value::Number(n) => Term::Num(
+            n.as_f64()
+                .ok_or_else(|| format!("the number {} cannot be represented", n))?,
+        ),
+        Value::String(s) => Term::Str(s),
+        Value::Array(vs) => Term::List(
+            vs.into_iter()
+                .map(json_to_term)
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        Value::Object(map) => Term::Record(
+            map.into_iter()
+                .map(|(id, v)| Ok((Ident(id), json_to_term(v)?)))
+                .collect::<Result<RecordData, String>>()?,
+        ),
+    };
+
+    Ok(t.into())
+}
+
+fn yaml_to_term(value: serde_yaml::Value) -> Result<RichTerm, String> {
+    use serde_yaml::Value;
+
+    let t = match value {
+        Value::Null => return Err(String::from("null values are not supported")),
+        Value::Bool(b) => Term::Bool(b),
+        Value::Number(n) => Term::Num(
+            n.as_f64()
+                .ok_or_else(|| format!("the number {} cannot be represented", n))?,
+        ),
+        Value::String(s) => Term::Str(s),
+        Value::Sequence(vs) => Term::List(
+            vs.into_iter()
+                .map(yaml_to_term)
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        Value::Mapping(map) => Term::Record(
+            map.into_iter()
+                .map(|(k, v)| match k {
+                    Value::String(id) => Ok((Ident(id), yaml_to_term(v)?)),
+                    k => Err(format!("unsupported non-string key {:?}", k)),
+                })
+                .collect::<Result<RecordData, String>>()?,
+        ),
+    };
+
+    Ok(t.into())
+}
+
+/// The positions of the nodes of a foreign document, following the structure of the document.
+///
+/// serde does not keep track of positions, so they are recovered by a second pass on the source
+/// and attached to the converted term afterwards (see [`set_positions`](fn.set_positions.html)).
+/// This allows blame errors raised on imported data to point inside the imported file.
+#[derive(Debug, Clone, PartialEq)]
+enum SpanTree {
+    Leaf(RawSpan),
+    List(RawSpan, Vec<SpanTree>),
+    Record(RawSpan, Vec<(String, SpanTree)>),
+}
+
+impl SpanTree {
+    fn span(&self) -> &RawSpan {
+        match self {
+            SpanTree::Leaf(span) | SpanTree::List(span, _) | SpanTree::Record(span, _) => span,
+        }
+    }
+}
+
+/// Annotate a term converted from foreign data with the positions of the corresponding nodes.
+///
+/// If a key is defined several times in an object, the last definition is the one which is kept
+/// by the conversion, and its position is the last one to be set.
+fn set_positions(rt: &mut RichTerm, spans: SpanTree) {
+    let RichTerm { term, pos, .. } = rt;
+
+    *pos = Some(spans.span().clone());
+    match spans {
+        SpanTree::List(_, children) => {
+            if let Term::List(ts) = term.as_mut() {
+                ts.iter_mut()
+                    .zip(children)
+                    .for_each(|(t, spans)| set_positions(t, spans));
+            }
+        }
+        SpanTree::Record(_, fields) => {
+            if let Term::Record(map) = term.as_mut() {
+                for (id, spans) in fields {
+                    if let Some(t) = map.get_mut(&Ident(id)) {
+                        set_positions(t, spans);
+                    }
+                }
+            }
+        }
+        SpanTree::Leaf(_) => (),
+    }
+}
+
+/// Recover the positions of the nodes of a JSON document. The document is assumed to be valid,
+/// which is the case once serde has successfully parsed it.
+struct JsonSpans<'a> {
+    source: &'a [u8],
+    offset: usize,
+    file_id: FileId,
+}
+
+impl<'a> JsonSpans<'a> {
+    fn new(source: &'a str, file_id: FileId) -> Self {
+        JsonSpans {
+            source: source.as_bytes(),
+            offset: source.len() - skip_bom(source).len(),
+            file_id,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.source.get(self.offset).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.offset += 1;
+        }
+    }
+
+    fn span(&self, start: usize) -> RawSpan {
+        RawSpan {
+            src_id: self.file_id,
+            start: ByteIndex(start as u32),
+            end: ByteIndex(self.offset as u32),
+        }
+    }
+
+    /// Skip a string literal, and return its decoded content.
+    fn string(&mut self) -> String {
+        let start = self.offset;
+        self.offset += 1;
+
+        while let Some(c) = self.peek() {
+            self.offset += if c == b'\\' { 2 } else { 1 };
+            if c == b'"' {
+                break;
+            }
+        }
+
+        let literal =
+            String::from_utf8_lossy(&self.source[start..self.offset.min(self.source.len())]);
+        serde_json::from_str(&literal).unwrap_or_default()
+    }
+
+    fn value(&mut self) -> SpanTree {
+        self.skip_whitespace();
+        let start = self.offset;
+
+        match self.peek() {
+            Some(b'[') => {
+                let mut children = Vec::new();
+                self.offset += 1;
+
+                loop {
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b']') | None => break,
+                        Some(b',') => self.offset += 1,
+                        Some(_) => children.push(self.value()),
+                    }
+                }
+
+                self.offset += 1;
+                SpanTree::List(self.span(start), children)
+            }
+            Some(b'{') => {
+                let mut fields = Vec::new();
+                self.offset += 1;
+
+                loop {
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b'}') | None => break,
+                        Some(b',') => self.offset += 1,
+                        Some(_) => {
+                            let key = self.string();
+                            self.skip_whitespace();
+                            // Skip the colon.
+                            self.offset += 1;
+                            fields.push((key, self.value()));
+                        }
+                    }
+                }
+
+                self.offset += 1;
+                SpanTree::Record(self.span(start), fields)
+            }
+            Some(b'"') => {
+                self.string();
+                SpanTree::Leaf(self.span(start))
+            }
+            _ => {
+                while let Some(c) = self.peek() {
+                    if c == b',' || c == b']' || c == b'}' || c.is_ascii_whitespace() {
+                        break;
+                    }
+                    self.offset += 1;
+                }
+
+                SpanTree::Leaf(self.span(start))
+            }
+        }
+    }
+}
+
+/// Recover the positions of the nodes of a YAML document from the events of the YAML parser.
+///
+/// The parser only reports the position where a node starts. The span of a scalar ends with the
+/// scalar when it is written plainly, and at the end of the line otherwise. The span of a sequence
+/// or of a mapping ends where the parser reports its end, trailing whitespace excluded.
+struct YamlSpans<'a> {
+    source: &'a str,
+    /// The byte offset of each character of the source, as the parser counts in characters.
+    offsets: Vec<usize>,
+    file_id: FileId,
+    /// The sequences and mappings being parsed, together with their starting offset. The key of
+    /// the next value is stored alongside a mapping.
+    stack: Vec<(usize, SpanTree, Option<String>)>,
+    root: Option<SpanTree>,
+}
+
+impl<'a> YamlSpans<'a> {
+    /// Return the positions of the nodes of the first document of the source, if any.
+    fn parse(source: &'a str, file_id: FileId) -> Option<SpanTree> {
+        let body = skip_bom(source);
+        let start = source.len() - body.len();
+        let mut spans = YamlSpans {
+            source,
+            offsets: body
+                .char_indices()
+                .map(|(i, _)| start + i)
+                .chain(std::iter::once(source.len()))
+                .collect(),
+            file_id,
+            stack: Vec::new(),
+            root: None,
+        };
+
+        yaml_rust::parser::Parser::new(body.chars())
+            .load(&mut spans, false)
+            .ok()?;
+        spans.root
+    }
+
+    fn byte_offset(&self, mark: yaml_rust::scanner::Marker) -> usize {
+        self.offsets
+            .get(mark.index())
+            .copied()
+            .unwrap_or(self.source.len())
+    }
+
+    fn span(&self, start: usize, end: usize) -> RawSpan {
+        RawSpan {
+            src_id: self.file_id,
+            start: ByteIndex(start as u32),
+            end: ByteIndex(end as u32),
+        }
+    }
+
+    /// Add a parsed node to the enclosing sequence or mapping, or set it as the root.
+    fn push(&mut self, node: SpanTree) {
+        match self.stack.last_mut() {
+            Some((_, SpanTree::List(_, children), _)) => children.push(node),
+            Some((_, SpanTree::Record(_, fields), key)) => match key.take() {
+                Some(key) => fields.push((key, node)),
+                // A complex key, which can't be converted anyway.
+                None => *key = Some(String::new()),
+            },
+            _ => self.root = Some(node),
+        }
+    }
+
+    fn close(&mut self, end: usize) {
+        if let Some((start, node, _)) = self.stack.pop() {
+            let end = start.max(self.source[..end].trim_end().len());
+            let span = self.span(start, end);
+            self.push(match node {
+                SpanTree::List(_, children) => SpanTree::List(span, children),
+                SpanTree::Record(_, fields) => SpanTree::Record(span, fields),
+                leaf => leaf,
+            });
+        }
+    }
+}
+
+impl<'a> yaml_rust::parser::MarkedEventReceiver for YamlSpans<'a> {
+    fn on_event(&mut self, event: yaml_rust::Event, mark: yaml_rust::scanner::Marker) {
+        use yaml_rust::Event;
+
+        let start = self.byte_offset(mark);
+        match event {
+            Event::Scalar(value, _, _, _) => {
+                // The key of a field of a mapping.
+                if let Some((_, SpanTree::Record(..), key @ None)) = self.stack.last_mut() {
+                    *key = Some(value);
+                    return;
+                }
+
+                let rest = &self.source[start..];
+                let len = if rest.starts_with(value.as_str()) {
+                    value.len()
+                } else {
+                    rest.lines().next().unwrap_or("").trim_end().len()
+                };
+                let span = self.span(start, start + len);
+                self.push(SpanTree::Leaf(span));
+            }
+            Event::Alias(_) => {
+                let span = self.span(start, start);
+                self.push(SpanTree::Leaf(span));
+            }
+            Event::SequenceStart(_) => {
+                let span = self.span(start, start);
+                self.stack
+                    .push((start, SpanTree::List(span, Vec::new()), None));
+            }
+            Event::MappingStart(_) => {
+                let span = self.span(start, start);
+                self.stack
+                    .push((start, SpanTree::Record(span, Vec::new()), None));
+            }
+            Event::SequenceEnd | Event::MappingEnd => self.close(start),
+            _ => (),
+        }
+    }
+}
+
+/// How to export numbers which have no JSON representation, that is NaN and infinities.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NonFinitePolicy {
+    /// Fail with a serialization error.
+    Error,
+    /// Export them as `null`.
+    Null,
+    /// Export them as the strings `"NaN"`, `"Infinity"` and `"-Infinity"`.
+    String,
+}
+
+impl Default for NonFinitePolicy {
+    fn default() -> Self {
+        NonFinitePolicy::Error
+    }
+}
+
+/// How to write finite numbers, in exports and in the representation of values.
+///
+/// Numbers are written in decimal notation, as `0.001` or `1500`, except for very small or very
+/// large ones, which are written in scientific notation, as `1e-9` or `1.5e300`. Both are valid
+/// JSON and YAML numbers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NumFormat {
+    /// The maximum number of significant digits. Numbers are rounded to this precision, which
+    /// hides the rounding errors of floating point arithmetic, as in `0.1 + 0.2`. By default,
+    /// numbers are written with the fewest digits which read back as the same number.
+    pub precision: Option<usize>,
+    /// The nonzero numbers whose absolute value is less than `10^min_exponent` are written in
+    /// scientific notation. -7 by default.
+    pub min_exponent: i32,
+    /// The numbers whose absolute value is at least `10^max_exponent` are written in scientific
+    /// notation. 21 by default.
+    pub max_exponent: i32,
+}
+
+impl Default for NumFormat {
+    fn default() -> Self {
+        NumFormat {
+            precision: None,
+            min_exponent: -7,
+            max_exponent: 21,
+        }
+    }
+}
+
+impl NumFormat {
+    /// Write a finite number. Non finite numbers are written as by their `Display`
+    /// implementation.
+    pub fn format(&self, n: f64) -> String {
+        if !n.is_finite() || n == 0. {
+            return format!("{}", n);
+        }
+
+        let n = match self.precision {
+            Some(digits) => format!("{:.*e}", digits.saturating_sub(1), n)
+                .parse()
+                .unwrap_or(n),
+            None => n,
+        };
+
+        // The `LowerExp` implementation of `f64` gives the shortest mantissa which reads back as
+        // the same number, and the `Display` one never uses an exponent.
+        let sci = format!("{:e}", n);
+        let exponent: i32 = sci
+            .rsplit('e')
+            .next()
+            .and_then(|exp| exp.parse().ok())
+            .unwrap_or(0);
+
+        if exponent < self.min_exponent || exponent >= self.max_exponent {
+            sci
+        } else {
+            format!("{}", n)
+        }
+    }
+}
+
+/// How to order the fields of records in the output. Whatever the order, the output of a given
+/// program is always the same, such that successive exports can be compared with a textual diff.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyOrder {
+    /// Sort the fields alphabetically.
+    Alphabetical,
+    /// Keep the fields in the order of their definition in the source. Records do not remember
+    /// the order in which their fields were inserted, so the order is the one of the positions of
+    /// the values of the fields. Fields whose value has no position, such as the ones computed by
+    /// a primitive operation, come last and are sorted alphabetically.
+    Definition,
+    /// Write the given fields first, in the given order, and then the other fields sorted
+    /// alphabetically. This applies to the fields of all the records of the output.
+    Priority(Vec<String>),
+}
+
+impl Default for KeyOrder {
+    fn default() -> Self {
+        KeyOrder::Alphabetical
+    }
+}
+
+/// The format of an exported document.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    /// YAML. JSON being a subset of YAML, the document is written with the same syntax as for
+    /// JSON. Contrary to JSON, YAML supports comments.
+    Yaml,
+}
+
+impl Default for ExportFormat {
+    fn default() -> Self {
+        ExportFormat::Json
+    }
+}
+
+impl ExportFormat {
+    /// Get a format from its name, `json` or `yaml`.
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        match name {
+            "json" => Some(ExportFormat::Json),
+            "yaml" | "yml" => Some(ExportFormat::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Whether the format supports comments.
+    pub fn supports_comments(&self) -> bool {
+        match self {
+            ExportFormat::Json => false,
+            ExportFormat::Yaml => true,
+        }
+    }
+}
+
+/// The line endings of an exported document.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Newline {
+    /// `\n`, as on Unix.
+    Lf,
+    /// `\r\n`, as on Windows.
+    CrLf,
+}
+
+impl Default for Newline {
+    fn default() -> Self {
+        Newline::Lf
+    }
+}
+
+impl Newline {
+    /// Get a line ending convention from its name, `lf` or `crlf`.
+    pub fn from_name(name: &str) -> Option<Newline> {
+        match name {
+            "lf" => Some(Newline::Lf),
+            "crlf" => Some(Newline::CrLf),
+            _ => None,
+        }
+    }
+}
+
+/// The options of the export.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExportOptions {
+    /// The format of the output. JSON by default.
+    pub format: ExportFormat,
+    /// A comment to write at the beginning of the output, such as `generated by Nickel from
+    /// config.ncl, do not edit`. It is only written if the format supports comments, and ignored
+    /// otherwise. None by default.
+    pub header: Option<String>,
+    /// How to export NaN and infinities. Fail by default.
+    pub non_finite: NonFinitePolicy,
+    /// How to write the other numbers.
+    pub num_format: NumFormat,
+    /// How to order the fields of records. Alphabetical by default.
+    pub key_order: KeyOrder,
+    /// The line endings of the output. `\n` by default.
+    pub newline: Newline,
+    /// The maximum number of elements of the lists of the output. The export fails with a
+    /// [`ListTooLong`](../error/enum.SerializationError.html#variant.ListTooLong) error on a
+    /// longer list. Unlimited by default.
+    pub max_list_length: Option<usize>,
+    /// The maximum size of the output, in bytes. The export fails with a
+    /// [`SizeLimitExceeded`](../error/enum.SerializationError.html#variant.SizeLimitExceeded)
+    /// error as soon as the output would exceed this size. Unlimited by default.
+    pub max_size: Option<usize>,
+    /// Keep exporting after a contract failure, and report all the failures at the end as a
+    /// [`ContractViolations`](../error/enum.SerializationError.html#variant.ContractViolations)
+    /// error. Disabled by default.
+    pub aggregate: bool,
+}
+
+/// Evaluate a term and serialize the result in the format of the options, writing the output to
+/// `out` as it is produced.
+///
+/// The value is forced during serialization: each element of a list or field of a record is
+/// evaluated only when it is about to be written, and is dropped right after. The serialized
+/// document is thus never held in memory as a whole, and exporting a long list does not require
+/// to keep all of its evaluated elements around, as long as they are not shared with another part
+/// of the program.
+pub fn to_writer<W, R>(
+    t: RichTerm,
+    global_env: &Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+    options: &ExportOptions,
+    out: &mut W,
+) -> Result<(), Error>
+where
+    W: Write,
+    R: ImportResolver,
+{
+    to_writer_with(t, global_env, resolver, stack_limit, options, out, |_| {
+        Vec::new()
+    })
+}
+
+/// Same as [`to_writer`](fn.to_writer.html), but if the value is a record, the names of its
+/// fields are first given to `render`, in the order of the output. `render` can serialize some of
+/// them beforehand, for example in other threads with
+/// [`render_field`](fn.render_field.html). The fields for which it returns `None` are evaluated
+/// and written as by `to_writer`, which reports their errors.
+pub fn to_writer_with<W, R, F>(
+    t: RichTerm,
+    global_env: &Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+    options: &ExportOptions,
+    out: &mut W,
+    render: F,
+) -> Result<(), Error>
+where
+    W: Write,
+    R: ImportResolver,
+    F: FnOnce(&[String]) -> Vec<Option<String>>,
+{
+    let mut writer = JsonWriter {
+        global_env,
+        resolver,
+        stack_limit,
+        options,
+        out,
+        indent: 0,
+        size: 0,
+        path: String::new(),
+        violations: Vec::new(),
+        rendered: HashMap::default(),
+    };
+
+    if let (Some(header), true) = (&options.header, options.format.supports_comments()) {
+        for line in header.lines() {
+            let comment = format!("# {}", line);
+            writer.write_raw(comment.trim_end())?;
+            writer.write_raw("\n")?;
+        }
+    }
+
+    let clos = writer.eval(Closure::atomic_closure(t))?;
+    if let Term::Record(ref fields) = *clos.body.term {
+        let mut fields: Vec<_> = fields
+            .iter()
+            .map(|(id, t)| (id.clone(), t.clone()))
+            .collect();
+        writer.sort_fields(&mut fields, &clos.env);
+        let names: Vec<String> = fields.into_iter().map(|(Ident(id), _)| id).collect();
+        let rendered = render(&names);
+        writer.rendered = names
+            .into_iter()
+            .zip(rendered)
+            .filter_map(|(id, s)| s.map(|s| (id, s)))
+            .collect();
+    }
+
+    writer.write_value(clos)?;
+    writer.write_raw("\n")?;
+
+    if writer.violations.is_empty() {
+        Ok(())
+    } else {
+        Err(SerializationError::ContractViolations(writer.violations).into())
+    }
+}
+
+/// The state of the streaming serializer.
+struct JsonWriter<'a, W, R> {
+    global_env: &'a Environment,
+    resolver: &'a mut R,
+    stack_limit: Option<usize>,
+    options: &'a ExportOptions,
+    out: &'a mut W,
+    /// The current indentation level.
+    indent: usize,
+    /// The number of bytes written so far.
+    size: usize,
+    /// The path of the value being written, as in `a.b[2]`.
+    path: String,
+    /// The contract violations encountered so far in aggregate mode, with their path.
+    violations: Vec<(String, EvalError)>,
+    /// The fields of the top-level record which were already serialized, by name.
+    rendered: HashMap<String, String>,
+}
+
+/// A record evaluated by [`eval_record`](fn.eval_record.html), whose fields can then be
+/// serialized separately by [`render_field`](fn.render_field.html).
+pub struct EvaluatedRecord {
+    fields: RecordData,
+    env: Environment,
+}
+
+/// Evaluate a term to a record, whose fields can then be serialized separately. Return `None` if
+/// the evaluation fails or if the value is not a record.
+pub fn eval_record<R>(
+    t: RichTerm,
+    global_env: &Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+    options: &ExportOptions,
+) -> Option<EvaluatedRecord>
+where
+    R: ImportResolver,
+{
+    let Closure { body, env } = eval::eval_closure_bounded(
+        Closure::atomic_closure(t),
+        global_env,
+        resolver,
+        stack_limit,
+        options.max_list_length,
+    )
+    .ok()?;
+
+    match *body.term {
+        Term::Record(fields) => Some(EvaluatedRecord { fields, env }),
+        _ => None,
+    }
+}
+
+/// Serialize a field of an evaluated record as it would be written by
+/// [`to_writer`](fn.to_writer.html) at the first level of indentation, with `\n` line endings.
+/// The size limit of the options is not checked, and the serialization stops at the first error
+/// even in aggregate mode.
+///
+/// Return `None` if the field doesn't exist or if its serialization fails for any reason, such
+/// that [`to_writer_with`](fn.to_writer_with.html) writes it again and reports the error.
+pub fn render_field<R>(
+    record: &EvaluatedRecord,
+    id: &str,
+    global_env: &Environment,
+    resolver: &mut R,
+    stack_limit: Option<usize>,
+    options: &ExportOptions,
+) -> Option<String>
+where
+    R: ImportResolver,
+{
+    let options = ExportOptions {
+        header: None,
+        newline: Newline::Lf,
+        max_size: None,
+        aggregate: false,
+        ..options.clone()
+    };
+    let t = record.fields.get(&Ident(String::from(id)))?;
+
+    let mut out = Vec::new();
+    let mut writer = JsonWriter {
+        global_env,
+        resolver,
+        stack_limit,
+        options: &options,
+        out: &mut out,
+        indent: 1,
+        size: 0,
+        path: String::from(id),
+        violations: Vec::new(),
+        rendered: HashMap::default(),
+    };
+    writer
+        .write_value(subclosure(t.clone(), &record.env))
+        .ok()?;
+    String::from_utf8(out).ok()
+}
+
+impl<'a, W, R> JsonWriter<'a, W, R>
+where
+    W: Write,
+    R: ImportResolver,
+{
+    fn write_raw(&mut self, s: &str) -> Result<(), Error> {
+        let crlf;
+        let s = match self.options.newline {
+            Newline::CrLf if s.contains('\n') => {
+                crlf = s.replace('\n', "\r\n");
+                crlf.as_str()
+            }
+            _ => s,
+        };
+
+        self.size += s.len();
+        match self.options.max_size {
+            Some(max) if self.size > max => {
+                return Err(SerializationError::SizeLimitExceeded(self.path.clone(), max).into())
+            }
+            _ => (),
+        };
+
+        self.out
+            .write_all(s.as_bytes())
+            .map_err(|err| SerializationError::IOError(format!("{}", err)).into())
+    }
+
+    /// Start a new line at the current indentation level.
+    fn newline(&mut self) -> Result<(), Error> {
+        let indent = format!("\n{:width$}", "", width = 2 * self.indent);
+        self.write_raw(&indent)
+    }
+
+    fn write_num(&mut self, n: f64, pos: Option<RawSpan>) -> Result<(), Error> {
+        if !n.is_finite() {
+            return match self.options.non_finite {
+                NonFinitePolicy::Error => Err(SerializationError::NonFiniteNumber(n, pos).into()),
+                NonFinitePolicy::Null => self.write_raw("null"),
+                NonFinitePolicy::String if n.is_nan() => self.write_raw("\"NaN\""),
+                NonFinitePolicy::String if n > 0. => self.write_raw("\"Infinity\""),
+                NonFinitePolicy::String => self.write_raw("\"-Infinity\""),
+            };
+        }
+
+        let formatted = self.options.num_format.format(n);
+        self.write_raw(&formatted)
+    }
+
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        let quoted = serde_json::to_string(s)
+            .map_err(|err| Error::from(SerializationError::IOError(format!("{}", err))))?;
+        self.write_raw(&quoted)
+    }
+
+    /// Write a field or an element of the value being written. `component` is appended to the
+    /// current path while writing it.
+    ///
+    /// In aggregate mode, a contract violation is recorded and `null` is written instead.
+    /// Contract violations are raised by the evaluation of the value, before anything is written,
+    /// such that the output remains valid.
+    fn write_child(&mut self, clos: Closure, component: &str) -> Result<(), Error> {
+        let len = self.path.len();
+        self.path.push_str(component);
+
+        let result = match self.write_value(clos) {
+            Err(Error::EvalError(err @ EvalError::BlameError(..))) if self.options.aggregate => {
+                self.violations.push((self.path.clone(), err));
+                self.write_raw("null")
+            }
+            result => result,
+        };
+
+        self.path.truncate(len);
+        result
+    }
+
+    /// Sort the fields of an evaluated record according to the key order of the options.
+    fn sort_fields(&self, fields: &mut [(Ident, RichTerm)], env: &Environment) {
+        match &self.options.key_order {
+            KeyOrder::Alphabetical => {
+                fields.sort_by(|(Ident(id1), _), (Ident(id2), _)| id1.cmp(id2))
+            }
+            KeyOrder::Definition => fields.sort_by_cached_key(|(Ident(id), t)| {
+                let pos = match *t.term {
+                    Term::Var(ref var) => env
+                        .get(var)
+                        .and_then(|(thunk, _)| definition_pos(&thunk.borrow().body)),
+                    _ => definition_pos(t),
+                };
+                (
+                    pos.is_none(),
+                    pos.map(|span| (span.src_id, span.start)),
+                    id.clone(),
+                )
+            }),
+            KeyOrder::Priority(keys) => fields.sort_by_cached_key(|(Ident(id), _)| {
+                (
+                    keys.iter().position(|key| key == id).unwrap_or(keys.len()),
+                    id.clone(),
+                )
+            }),
+        }
+    }
+
+    /// Evaluate a closure, enforcing the maximum list length of the options on the lists built
+    /// along the way, and not only on the final value.
+    fn eval(&mut self, clos: Closure) -> Result<Closure, Error> {
+        eval::eval_closure_bounded(
+            clos,
+            self.global_env,
+            self.resolver,
+            self.stack_limit,
+            self.options.max_list_length,
+        )
+        .map_err(|err| match err {
+            EvalError::ListTooLong(len, max, pos) => {
+                SerializationError::ListTooLong(self.path.clone(), len, max, pos).into()
+            }
+            err => err.into(),
+        })
+    }
+
+    /// Evaluate a closure and write the result.
+    fn write_value(&mut self, clos: Closure) -> Result<(), Error> {
+        let Closure { body, env } = self.eval(clos)?;
+        let RichTerm { term, pos, .. } = body;
+
+        match *term {
+            Term::Bool(b) => self.write_raw(if b { "true" } else { "false" }),
+            Term::Num(n) => self.write_num(n, pos),
+            Term::Str(s) => self.write_str(&s),
+            Term::List(ts) if ts.is_empty() => self.write_raw("[]"),
+            Term::List(ts) => {
+                match self.options.max_list_length {
+                    Some(max) if ts.len() > max => {
+                        return Err(SerializationError::ListTooLong(
+                            self.path.clone(),
+                            ts.len(),
+                            max,
+                            pos,
+                        )
+                        .into())
+                    }
+                    _ => (),
+                };
+
+                self.write_raw("[")?;
+                self.indent += 1;
+
+                for (i, t) in ts.into_iter().enumerate() {
+                    if i > 0 {
+                        self.write_raw(",")?;
+                    }
+                    self.newline()?;
+                    self.write_child(subclosure(t, &env), &format!("[{}]", i))?;
+                }
+
+                self.indent -= 1;
+                self.newline()?;
+                self.write_raw("]")
+            }
+            Term::Record(fields) if fields.is_empty() => self.write_raw("{}"),
+            Term::Record(fields) => {
+                let mut fields: Vec<_> = fields.into_iter().collect();
+                self.sort_fields(&mut fields, &env);
+
+                self.write_raw("{")?;
+                self.indent += 1;
+
+                for (i, (Ident(id), t)) in fields.into_iter().enumerate() {
+                    if i > 0 {
+                        self.write_raw(",")?;
+                    }
+                    self.newline()?;
+                    self.write_str(&id)?;
+                    self.write_raw(": ")?;
+
+                    if self.path.is_empty() {
+                        if let Some(rendered) = self.rendered.remove(&id) {
+                            self.write_raw(&rendered)?;
+                            continue;
+                        }
+                    }
+
+                    let component = if self.path.is_empty() {
+                        id
+                    } else {
+                        format!(".{}", id)
+                    };
+                    self.write_child(subclosure(t, &env), &component)?;
+                }
+
+                self.indent -= 1;
+                self.newline()?;
+                self.write_raw("}")
+            }
+            term => Err(SerializationError::NonSerializable(RichTerm::with_pos(
+                Box::new(term),
+                pos,
+            ))
+            .into()),
+        }
+    }
+}
+
+/// The position of the definition of a value. The share normal form transformation wraps lists
+/// and records in let-bindings without position, which are looked through.
+fn definition_pos(mut t: &RichTerm) -> Option<RawSpan> {
+    while let (None, Term::Let(_, _, body)) = (&t.pos, t.term.as_ref()) {
+        t = body;
+    }
+
+    t.pos.clone()
+}
+
+/// Build the closure of a subterm of an evaluated list or record.
+///
+/// The elements of evaluated lists and records are variables bound in the environment, whose
+/// closures are fetched directly instead of copying the whole environment for each element. This
+/// also avoids updating the corresponding thunks, such that an element can be freed once written.
+fn subclosure(t: RichTerm, env: &Environment) -> Closure {
+    if let Term::Var(ref id) = *t.term {
+        if let Some((thunk, _)) = env.get(id) {
+            return thunk.borrow().clone();
+        }
+    }
+
+    Closure {
+        body: t,
+        env: env.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codespan::Files;
+
+    fn file_id() -> FileId {
+        Files::new().add("<test>", String::from(""))
+    }
+
+    fn without_pos(mut rt: RichTerm) -> Term {
+        rt.clean_pos();
+        *rt.term
+    }
+
+    fn record(fields: Vec<(&str, Term)>) -> Term {
+        Term::Record(
+            fields
+                .into_iter()
+                .map(|(id, t)| (Ident::from(id), t.into()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(
+            without_pos(from_json("{\"a\": [1, true, \"s\"], \"b\": {}}", file_id()).unwrap()),
+            record(vec![
+                (
+                    "a",
+                    Term::List(vec![
+                        Term::Num(1.).into(),
+                        Term::Bool(true).into(),
+                        Term::Str(String::from("s")).into()
+                    ])
+                ),
+                ("b", record(vec![]))
+            ])
+        );
+        from_json("{\"a\": null}", file_id()).unwrap_err();
+        from_json("{\"a\": ", file_id()).unwrap_err();
+    }
+
+    #[test]
+    fn yaml() {
+        assert_eq!(
+            without_pos(from_yaml("a:\n  - 1\n  - true\nb: s\n", file_id()).unwrap()),
+            record(vec![
+                (
+                    "a",
+                    Term::List(vec![Term::Num(1.).into(), Term::Bool(true).into()])
+                ),
+                ("b", Term::Str(String::from("s")))
+            ])
+        );
+        from_yaml("1: a", file_id()).unwrap_err();
+    }
+
+    #[test]
+    fn positions() {
+        let span = |rt: &RichTerm| {
+            rt.pos
+                .as_ref()
+                .map(|span| (span.start.to_usize(), span.end.to_usize()))
+        };
+        let field = |rt: &RichTerm, id: &str| match rt.term.as_ref() {
+            Term::Record(map) => map.get(&Ident::from(id)).cloned().unwrap(),
+            t => panic!("expected a record, got {:?}", t),
+        };
+        let elem = |rt: &RichTerm, i: usize| match rt.term.as_ref() {
+            Term::List(ts) => ts[i].clone(),
+            t => panic!("expected a list, got {:?}", t),
+        };
+
+        let src = "{\"a\": [1, \"s\\\"\"], \"b\": {\"c\": true}, \"b\": {\"c\": false}}";
+        let rt = from_json(src, file_id()).unwrap();
+        assert_eq!(span(&rt), Some((0, src.len())));
+        assert_eq!(span(&field(&rt, "a")), Some((6, 16)));
+        assert_eq!(span(&elem(&field(&rt, "a"), 1)), Some((10, 15)));
+        assert_eq!(span(&field(&field(&rt, "b"), "c")), Some((47, 52)));
+
+        // A byte order mark is skipped, but still counted in the positions.
+        let src = "\u{feff}{\"a\": 1}";
+        let rt = from_json(src, file_id()).unwrap();
+        assert_eq!(span(&field(&rt, "a")), Some((9, 10)));
+        let src = "\u{feff}a: 1\r\nb: 2\r\n";
+        let rt = from_yaml(src, file_id()).unwrap();
+        assert_eq!(span(&field(&rt, "a")), Some((6, 7)));
+        assert_eq!(span(&field(&rt, "b")), Some((12, 13)));
+
+        let src = "a:\n  - 1\n  - \"s\"\nb: text\n";
+        let rt = from_yaml(src, file_id()).unwrap();
+        assert_eq!(span(&elem(&field(&rt, "a"), 0)), Some((7, 8)));
+        assert_eq!(span(&elem(&field(&rt, "a"), 1)), Some((13, 16)));
+        assert_eq!(span(&field(&rt, "b")), Some((20, 24)));
+    }
+    #[test]
+    fn num_format() {
+        let default = NumFormat::default();
+        assert_eq!(default.format(1500.), "1500");
+        assert_eq!(default.format(-0.001), "-0.001");
+        assert_eq!(default.format(1e-7), "0.0000001");
+        assert_eq!(default.format(1.5e-8), "1.5e-8");
+        assert_eq!(default.format(1e20), "100000000000000000000");
+        assert_eq!(default.format(-2.5e21), "-2.5e21");
+        assert_eq!(default.format(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(default.format(0.), "0");
+
+        let rounded = NumFormat {
+            precision: Some(3),
+            min_exponent: -2,
+            max_exponent: 4,
+        };
+        assert_eq!(rounded.format(0.1 + 0.2), "0.3");
+        assert_eq!(rounded.format(2. / 3.), "0.667");
+        assert_eq!(rounded.format(0.001234), "1.23e-3");
+        assert_eq!(rounded.format(9999.), "1e4");
+        assert_eq!(rounded.format(999.9), "1000");
+        assert_eq!(rounded.format(f64::INFINITY), "inf");
+    }
+}
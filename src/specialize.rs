@@ -0,0 +1,471 @@
+//! Partial evaluation of a program against fixed inputs.
+//!
+//! Given values for some of the external inputs of a program (see `%external%` terms), the
+//! specialization pass replaces these inputs by their values and folds every subterm which only
+//! depends on known values, leaving a smaller residual program. The residual program can then be
+//! shipped as a pre-specialized template, which only waits for the remaining inputs.
+//!
+//! The pass is a simple online partial evaluator, which works on the parsed term. A subterm is
+//! said to be static if it is a constant, or a list or a record of static subterms. The following
+//! rewritings are performed bottom-up:
+//!
+//! - **Known inputs**: `%external% "name"` is replaced by the provided value
+//! - **Let binding**: `let x = v in t`, where `v` is static, is replaced by `t` where `x` has been
+//!   substituted with `v`
+//! - **Application**: `(fun x => t) v`, where `v` is static, is reduced in the same way
+//! - **Conditional**: `if b then t else e`, where `b` is a boolean, is replaced by the selected
+//!   branch
+//! - **Switch**: a switch on an enum tag is replaced by the selected case
+//! - **Field access**: `r.a`, where `r` is a record literal and its field `a` is static, is
+//!   replaced by the content of the field
+//! - **Operators**: an operator whose arguments are all static is evaluated
+//! - **String interpolation**: static interpolated expressions are inlined in the string
+//!
+//! Anything that cannot be folded, including operations which fail at evaluation, are left
+//! unchanged, such that errors are still reported when the residual program is run.
+use crate::collections::HashMap;
+use crate::eval::{self, Environment};
+use crate::identifier::Ident;
+use crate::position::RawSpan;
+use crate::program::ImportResolver;
+use crate::term::{BinaryOp, MetaValue, RecordData, RichTerm, StrChunk, Term, UnaryOp};
+use crate::transformations;
+use crate::types::{AbsType, Types};
+
+/// The state of the specialization pass.
+struct Specializer<'a, R> {
+    /// The known inputs.
+    inputs: &'a HashMap<String, RichTerm>,
+    /// The global environment used to evaluate static operations.
+    global_env: &'a Environment,
+    /// The import resolver used to evaluate static operations.
+    resolver: &'a mut R,
+}
+
+/// Specialize a term against the known inputs `inputs`, mapping names of externals to closed
+/// terms.
+pub fn specialize<R>(
+    rt: RichTerm,
+    inputs: &HashMap<String, RichTerm>,
+    global_env: &Environment,
+    resolver: &mut R,
+) -> RichTerm
+where
+    R: ImportResolver,
+{
+    Specializer {
+        inputs,
+        global_env,
+        resolver,
+    }
+    .fold(rt)
+}
+
+impl<'a, R> Specializer<'a, R>
+where
+    R: ImportResolver,
+{
+    /// Fold a term, after having folded its subterms.
+    fn fold(&mut self, rt: RichTerm) -> RichTerm {
+        let RichTerm {
+            term,
+            pos,
+            id: node_id,
+            ..
+        } = rt;
+        let term = match *term {
+            Term::External(name) => match self.inputs.get(&name) {
+                Some(value) => return with_pos(value.clone(), pos),
+                None => Term::External(name),
+            },
+            Term::Let(id, t1, t2) => {
+                let t1 = self.fold(t1);
+                if is_static(t1.as_ref()) {
+                    let mut t2 = t2;
+                    subst_var(&mut t2, &id, &t1);
+                    return self.fold(t2);
+                }
+
+                Term::Let(id, t1, self.fold(t2))
+            }
+            Term::App(t1, t2) => {
+                let t1 = self.fold(t1);
+                let t2 = self.fold(t2);
+                let RichTerm {
+                    term: head,
+                    pos: head_pos,
+                    id: head_id,
+                    ..
+                } = t1;
+                match *head {
+                    // `if b then t else e` is parsed as `((ite b) t) e`
+                    Term::App(ite, t) if static_cond(&ite).is_some() => {
+                        return if static_cond(&ite) == Some(true) {
+                            t
+                        } else {
+                            t2
+                        };
+                    }
+                    Term::Fun(id, mut body) if is_static(t2.as_ref()) => {
+                        subst_var(&mut body, &id, &t2);
+                        return self.fold(body);
+                    }
+                    head => Term::App(
+                        RichTerm {
+                            term: Box::new(head),
+                            pos: head_pos,
+                            id: head_id,
+                            free_vars: None,
+                        },
+                        t2,
+                    ),
+                }
+            }
+            Term::Op1(UnaryOp::Switch(cases, default), t) => {
+                let t = self.fold(t);
+                if let Term::Enum(ref id) = *t.term {
+                    let selected = cases.get(id).cloned().or_else(|| default.clone());
+                    if let Some(case) = selected {
+                        return self.fold(case);
+                    }
+                }
+
+                let cases = cases
+                    .into_iter()
+                    .map(|(id, case)| (id, self.fold(case)))
+                    .collect();
+                let default = default.map(|t| self.fold(t));
+                Term::Op1(UnaryOp::Switch(cases, default), t)
+            }
+            Term::Op1(UnaryOp::StaticAccess(id), t) => {
+                let t = self.fold(t);
+                let field = match t.as_ref() {
+                    Term::Record(map) | Term::RecRecord(map) => map.get(&id),
+                    _ => None,
+                };
+
+                match field {
+                    Some(field) if is_static(field.as_ref()) => {
+                        return with_pos(field.clone(), pos);
+                    }
+                    _ => Term::Op1(UnaryOp::StaticAccess(id), t),
+                }
+            }
+            Term::Op1(op, t) => {
+                let op = match op {
+                    UnaryOp::MapRec(f) => UnaryOp::MapRec(self.fold(f)),
+                    UnaryOp::CatchBlame(f) => UnaryOp::CatchBlame(self.fold(f)),
+                    op => op,
+                };
+                let t = self.fold(t);
+                let foldable = match op {
+                    UnaryOp::Ite()
+                    | UnaryOp::Seq()
+                    | UnaryOp::DeepSeq()
+                    | UnaryOp::ListZipWith()
+                    | UnaryOp::ListRange()
+                    | UnaryOp::Iterate()
+                    | UnaryOp::FieldsWithMetadata()
+                    | UnaryOp::FieldMetadata(_)
+                    | UnaryOp::RecordRenameField()
+                    | UnaryOp::HostCall(_) => false,
+                    _ => is_static(t.as_ref()),
+                };
+
+                let result = Term::Op1(op, t);
+                if foldable {
+                    return self.eval_static(result, pos);
+                }
+                result
+            }
+            Term::Op2(op, t1, t2) => {
+                let op = match op {
+                    BinaryOp::DynExtend(t) => BinaryOp::DynExtend(self.fold(t)),
+                    op => op,
+                };
+                let t1 = self.fold(t1);
+                let t2 = self.fold(t2);
+                let foldable = is_static(t1.as_ref())
+                    && is_static(t2.as_ref())
+                    && match op {
+                        BinaryOp::DynExtend(ref t) => is_static(t.as_ref()),
+                        _ => true,
+                    };
+
+                let result = Term::Op2(op, t1, t2);
+                if foldable {
+                    return self.eval_static(result, pos);
+                }
+                result
+            }
+            Term::StrChunks(chunks) => {
+                // Chunks are stored in reverse order, see the definition of `Term::StrChunks`.
+                let mut folded: Vec<StrChunk<RichTerm>> = Vec::with_capacity(chunks.len());
+                for chunk in chunks.into_iter().rev() {
+                    let chunk = match chunk {
+                        StrChunk::Expr(e) => match *self.fold(e).term {
+                            Term::Str(s) => StrChunk::Literal(s),
+                            t => StrChunk::Expr(t.into()),
+                        },
+                        lit => lit,
+                    };
+
+                    match (folded.last_mut(), &chunk) {
+                        (Some(StrChunk::Literal(acc)), StrChunk::Literal(s)) => acc.push_str(s),
+                        _ => folded.push(chunk),
+                    }
+                }
+
+                match folded.as_slice() {
+                    [] => Term::Str(String::new()),
+                    [StrChunk::Literal(s)] => Term::Str(s.clone()),
+                    _ => {
+                        folded.reverse();
+                        Term::StrChunks(folded)
+                    }
+                }
+            }
+            Term::Fun(id, t) => Term::Fun(id, self.fold(t)),
+            Term::LetRec(id, t1, t2) => Term::LetRec(id, self.fold(t1), self.fold(t2)),
+            Term::LetPattern(pat, t1, t2) => Term::LetPattern(pat, self.fold(t1), self.fold(t2)),
+            Term::Record(map) => Term::Record(self.fold_map(map)),
+            Term::RecRecord(map) => Term::RecRecord(self.fold_map(map)),
+            Term::List(ts) => Term::List(ts.into_iter().map(|t| self.fold(t)).collect()),
+            Term::Promise(ty, l, t) => Term::Promise(ty, l, self.fold(t)),
+            Term::Assume(ty, l, t) => Term::Assume(ty, l, self.fold(t)),
+            Term::Wrapped(i, t) => Term::Wrapped(i, self.fold(t)),
+            Term::MetaValue(meta) => Term::MetaValue(MetaValue {
+                value: meta.value.map(|t| self.fold(t)),
+                ..meta
+            }),
+            t => t,
+        };
+
+        RichTerm {
+            term: Box::new(term),
+            pos,
+            id: node_id,
+            free_vars: None,
+        }
+    }
+
+    fn fold_map(&mut self, map: RecordData) -> RecordData {
+        map.into_iter().map(|(id, t)| (id, self.fold(t))).collect()
+    }
+
+    /// Evaluate an operation whose arguments are static. If the evaluation fails or does not
+    /// produce a static value, the operation is left unevaluated.
+    fn eval_static(&mut self, t: Term, pos: Option<RawSpan>) -> RichTerm {
+        let rt = RichTerm::with_pos(Box::new(t), pos);
+
+        let result = transformations::transform(rt.clone(), self.resolver)
+            .ok()
+            .and_then(|t| {
+                eval::eval_full(
+                    t,
+                    self.global_env.clone(),
+                    self.resolver,
+                    Some(eval::DEFAULT_STACK_LIMIT),
+                )
+                .ok()
+            })
+            .filter(is_static);
+
+        match result {
+            Some(value) => with_pos(value.into(), rt.pos),
+            None => rt,
+        }
+    }
+}
+
+/// Determine if a term is static, that is if it is a constant, or a list or a record of static
+/// terms.
+pub fn is_static(t: &Term) -> bool {
+    match t {
+        Term::List(ts) => ts.iter().all(|t| is_static(t.as_ref())),
+        Term::Record(map) | Term::RecRecord(map) => map.values().all(|t| is_static(t.as_ref())),
+        t => t.is_constant(),
+    }
+}
+
+/// If a term is the partial application of if-then-else to a boolean, return this boolean.
+fn static_cond(rt: &RichTerm) -> Option<bool> {
+    match rt.as_ref() {
+        Term::Op1(UnaryOp::Ite(), cond) => match cond.as_ref() {
+            Term::Bool(b) => Some(*b),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Replace the position of a term by the one of the term it substitutes, if any.
+fn with_pos(rt: RichTerm, pos: Option<RawSpan>) -> RichTerm {
+    RichTerm::with_pos(rt.term, pos.or(rt.pos))
+}
+
+/// Substitute the free occurrences of a variable with a closed term, in place.
+fn subst_var(rt: &mut RichTerm, id: &Ident, value: &RichTerm) {
+    if let Term::Var(ref x) = *rt.term {
+        if x == id {
+            *rt = with_pos(value.clone(), rt.pos.clone());
+        }
+        return;
+    }
+
+    match rt.term.as_mut() {
+        Term::Fun(x, _) | Term::LetRec(x, _, _) if x == id => (),
+        Term::Let(x, t1, t2) => {
+            subst_var(t1, id, value);
+            if x != id {
+                subst_var(t2, id, value);
+            }
+        }
+        Term::LetPattern(pat, t1, t2) => {
+            subst_var(t1, id, value);
+            if !pat.bindings().contains(&id) {
+                subst_var(t2, id, value);
+            }
+        }
+        Term::RecRecord(map) if map.contains_key(id) => (),
+        Term::Op1(UnaryOp::MapRec(f), t) | Term::Op1(UnaryOp::CatchBlame(f), t) => {
+            subst_var(f, id, value);
+            subst_var(t, id, value);
+        }
+        Term::Promise(ty, _, t) | Term::Assume(ty, _, t) => {
+            subst_var_types(ty, id, value);
+            subst_var(t, id, value);
+        }
+        Term::MetaValue(meta) => {
+            for ctr in meta.contracts.iter_mut() {
+                subst_var_types(&mut ctr.types, id, value);
+            }
+            if let Some(t) = &mut meta.value {
+                subst_var(t, id, value);
+            }
+        }
+        t => t.apply_to_rich_terms(|rt| subst_var(rt, id, value)),
+    }
+}
+
+/// Substitute the free occurrences of a variable with a closed term inside the contracts of a
+/// type.
+fn subst_var_types(ty: &mut Types, id: &Ident, value: &RichTerm) {
+    let Types(abs) = ty;
+    match abs {
+        AbsType::Flat(t) => subst_var(t, id, value),
+        AbsType::Arrow(s, t) => {
+            subst_var_types(s, id, value);
+            subst_var_types(t, id, value);
+        }
+        AbsType::RowExtend(_, field, tail) => {
+            if let Some(field) = field {
+                subst_var_types(field, id, value);
+            }
+            subst_var_types(tail, id, value);
+        }
+        AbsType::Forall(_, t)
+        | AbsType::Enum(t)
+        | AbsType::StaticRecord(t)
+        | AbsType::DynRecord(t)
+        | AbsType::List(t) => subst_var_types(t, id, value),
+        AbsType::Dyn()
+        | AbsType::Num()
+        | AbsType::Bool()
+        | AbsType::Str()
+        | AbsType::Sym()
+        | AbsType::Var(_)
+        | AbsType::RowEmpty() => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+    use std::io::Cursor;
+
+    fn specialize_source(s: &str, inputs: Vec<(&str, Term)>) -> Term {
+        let mut p = Program::new_from_source(Cursor::new(s), "<test>").unwrap();
+        let inputs = inputs
+            .into_iter()
+            .map(|(name, t)| (String::from(name), t.into()))
+            .collect();
+        let mut rt = p.specialize(inputs).unwrap();
+        rt.clean_pos();
+        *rt.term
+    }
+
+    #[test]
+    fn folds_known_inputs() {
+        assert_eq!(
+            specialize_source(
+                "let port = %external% \"port\" in port + 1",
+                vec![("port", Term::Num(80.))]
+            ),
+            Term::Num(81.)
+        );
+        assert_eq!(
+            specialize_source(
+                "if %external% \"debug\" then \"${%external% \"name\"}-dbg\" else \"release\"",
+                vec![
+                    ("debug", Term::Bool(true)),
+                    ("name", Term::Str("app".into()))
+                ]
+            ),
+            Term::Str(String::from("app-dbg"))
+        );
+        assert_eq!(
+            specialize_source(
+                "switch { prod => 443, dev => 8080, } (%external% \"env\")",
+                vec![("env", Term::Enum(Ident::from("dev")))]
+            ),
+            Term::Num(8080.)
+        );
+    }
+
+    #[test]
+    fn keeps_residual() {
+        let t = specialize_source(
+            "let x = %external% \"x\" in {a = x + 1; b = (fun y => y + 1) 2}",
+            vec![],
+        );
+        match t {
+            Term::Let(_, t1, body) => {
+                assert_eq!(*t1.term, Term::External(String::from("x")));
+                match *body.term {
+                    Term::RecRecord(map) => {
+                        assert_eq!(*map[&Ident::from("b")].term, Term::Num(3.));
+                        assert!(!is_static(map[&Ident::from("a")].as_ref()));
+                    }
+                    t => panic!("expected a record, got {:?}", t),
+                }
+            }
+            t => panic!("expected a let binding, got {:?}", t),
+        }
+    }
+
+    #[test]
+    fn respects_shadowing() {
+        assert_eq!(
+            specialize_source("let x = 1 in (fun x => x + 1) 2", vec![]),
+            Term::Num(3.)
+        );
+
+        match specialize_source("let x = 1 in let f = fun x => x + 1 in f", vec![]) {
+            Term::Let(_, f, _) => match *f.term {
+                Term::Fun(_, body) => assert!(!is_static(body.as_ref())),
+                t => panic!("expected a function, got {:?}", t),
+            },
+            t => panic!("expected a let binding, got {:?}", t),
+        }
+    }
+
+    #[test]
+    fn keeps_failing_operations() {
+        match specialize_source("1 + \"a\"", vec![]) {
+            Term::Op2(BinaryOp::Plus(), _, _) => (),
+            t => panic!("expected an unevaluated addition, got {:?}", t),
+        }
+    }
+}
@@ -4,6 +4,7 @@
 use crate::eval::Closure;
 use crate::operation::OperationCont;
 use crate::position::RawSpan;
+use crate::term::UnaryOp;
 use std::cell::RefCell;
 use std::rc::Weak;
 
@@ -49,7 +50,7 @@ impl Marker {
 }
 
 /// The evaluation stack.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Stack(Vec<Marker>);
 
 impl IntoIterator for Stack {
@@ -82,6 +83,15 @@ impl Stack {
         count
     }
 
+    /// The total number of elements of the stack.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     /// Count the number of arguments at the top of the stack.
     pub fn count_args(&self) -> usize {
         Stack::count(self, Marker::is_arg)
@@ -132,6 +142,37 @@ impl Stack {
         }
     }
 
+    /// Pop all the elements of the stack up to the innermost `catchBlame` continuation, included.
+    ///
+    /// Return the fallback of this continuation, the size of the call stack when it was pushed,
+    /// and the value of `enriched_strict` saved by the outermost operation which was popped, if
+    /// any. If there is no such continuation, the stack is left unchanged and `None`
+    /// is returned.
+    pub fn unwind_to_catch(&mut self) -> Option<(Closure, usize, Option<bool>)> {
+        let index = self.0.iter().rposition(|marker| {
+            matches!(
+                marker,
+                Marker::Cont(OperationCont::Op1(UnaryOp::CatchBlame(_), _, _), _, _)
+            )
+        })?;
+
+        let prev_strict = self.0.drain(index + 1..).find_map(|marker| match marker {
+            Marker::Cont(OperationCont::Op1(_, _, prev_strict), _, _)
+            | Marker::Cont(OperationCont::Op2First(_, _, _, prev_strict), _, _)
+            | Marker::Cont(OperationCont::Op2Second(_, _, _, _, prev_strict), _, _) => {
+                Some(prev_strict)
+            }
+            _ => None,
+        });
+
+        match self.0.pop() {
+            Some(Marker::Cont(OperationCont::Op1(UnaryOp::CatchBlame(fallback), _, _), len, _)) => {
+                Some((fallback, len, prev_strict))
+            }
+            _ => panic!("stack::unwind_to_catch(): expected a catchBlame continuation"),
+        }
+    }
+
     /// Check if the top element is an argument.
     pub fn is_top_thunk(&self) -> bool {
         self.0.last().map(Marker::is_thunk).unwrap_or(false)
@@ -166,7 +207,7 @@ mod tests {
     }
 
     fn some_cont() -> OperationCont {
-        OperationCont::Op1(UnaryOp::IsZero(), None)
+        OperationCont::Op1(UnaryOp::IsZero(), None, true)
     }
 
     fn some_arg_marker() -> Marker {
@@ -1,3 +1,6 @@
 //! Load the Nickel standard library in strings at compile-time.
 pub const CONTRACTS: &str = include_str!("../stdlib/contracts.ncl");
 pub const LISTS: &str = include_str!("../stdlib/lists.ncl");
+pub const LAZY: &str = include_str!("../stdlib/lazy.ncl");
+pub const NUMS: &str = include_str!("../stdlib/nums.ncl");
+pub const SEALS: &str = include_str!("../stdlib/seals.ncl");
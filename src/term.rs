@@ -5,7 +5,7 @@
 //! At its core, Nickel is a lazy JSON with higher-order functions. It includes:
 //! - Basic values: booleans, numerals, string
 //! - Data structures: lists and records
-//! - Binders: functions and let bindings
+//! - Binders: functions and let bindings, which may destructure records and lists
 //!
 //! It also features type annotations (promise and assume), and other typechecking related
 //! constructs (label, symbols, etc.).
@@ -16,12 +16,18 @@
 //! contracts, default values, documentation, etc. They bring such usually external object down to
 //! the term level, and together with [merge](../merge/index.html), they allow for flexible and
 //! modular definitions of contracts, record and metadata all together.
+use crate::collections::{HashMap, HashSet};
 use crate::identifier::Ident;
 use crate::label::Label;
 use crate::position::RawSpan;
+use crate::serialize::NumFormat;
 use crate::types::{AbsType, Types};
 use codespan::FileId;
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::iter::FromIterator;
+use std::rc::Rc;
 
 /// The AST of a Nickel expression.
 ///
@@ -52,6 +58,12 @@ pub enum Term {
 
     /// A let binding.
     Let(Ident, RichTerm, RichTerm),
+    /// A recursive let binding, where the bound expression may refer to the binder itself.
+    LetRec(Ident, RichTerm, RichTerm),
+    /// A let binding destructuring the bound expression, as in `let {a, b} = r in body`. It is
+    /// desugared to plain let bindings before evaluation (see
+    /// [`destructuring`](../transformations/destructuring/index.html)).
+    LetPattern(Pattern, RichTerm, RichTerm),
     /// An application.
     App(RichTerm, RichTerm),
     /// A variable.
@@ -61,9 +73,9 @@ pub enum Term {
     Enum(Ident),
 
     /// A record, mapping identifiers to terms.
-    Record(HashMap<Ident, RichTerm>),
+    Record(RecordData),
     /// A recursive record, where the fields can reference each others.
-    RecRecord(HashMap<Ident, RichTerm>),
+    RecRecord(RecordData),
 
     /// A list.
     List(Vec<RichTerm>),
@@ -110,31 +122,23 @@ pub enum Term {
     /// expected `Wrapped(1, _)`, hence it raises a positive blame.
     Wrapped(i32, RichTerm),
 
-    /// A contract. Enriched value.
+    /// A value together with its metadata. Enriched value.
     ///
-    /// A contract at the term level. This contract is enforced when merged with a value.
-    Contract(Types, Label),
-
-    /// A default value. Enriched value.
-    ///
-    /// An enriched term representing a default value. It is dropped as soon as it is merged with a
-    /// concrete value. Otherwise, if it lives long enough to be accessed, it evaluates to the
-    /// underlying term.
-    DefaultValue(RichTerm),
-
-    /// A contract with combined with default value. Enriched value.
-    ///
-    /// This is a combination generated during evaluation, when merging a contract and a default
-    /// value, as both need to be remembered.
-    ContractWithDefault(Types, Label, RichTerm),
-
-    /// A term together with its documentation string. Enriched value.
-    Docstring(String, RichTerm),
+    /// See [`MetaValue`](struct.MetaValue.html).
+    MetaValue(MetaValue),
 
     /// An unresolved import.
     Import(String),
     /// A resolved import (which has already been loaded and parsed).
     ResolvedImport(FileId),
+
+    /// An external value, identified by a name, which is provided by the host at run time. It is
+    /// written `%external% "name"`.
+    ///
+    /// When the evaluation reaches an external term, the abstract machine suspends and gives
+    /// control back to the host, which can then resume the evaluation with the requested value.
+    /// See [`eval::Suspension`](../eval/struct.Suspension.html).
+    External(String),
 }
 
 /// A chunk of a string with interpolated expressions inside. Same as `Either<String,
@@ -145,6 +149,464 @@ pub enum StrChunk<E> {
     Expr(E),
 }
 
+/// A destructuring pattern, as in `let {host, port = [p, _]} = server in ..` or `fun {a, b} =>
+/// ..`. Patterns are lazy: a missing field or element is only reported when the corresponding
+/// variable is used.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Pattern {
+    /// A variable, bound to the whole value.
+    Var(Ident),
+    /// A record with at least the given fields, whose values are matched against the
+    /// sub-patterns. `{a}` is short for `{a = a}`.
+    Record(Vec<(Ident, Pattern)>),
+    /// A list, whose elements are matched against the sub-patterns in order.
+    List(Vec<Pattern>),
+}
+
+impl Pattern {
+    /// The variables bound by the pattern, in order.
+    pub fn bindings(&self) -> Vec<&Ident> {
+        match self {
+            Pattern::Var(id) => vec![id],
+            Pattern::Record(fields) => fields.iter().flat_map(|(_, p)| p.bindings()).collect(),
+            Pattern::List(ps) => ps.iter().flat_map(|p| p.bindings()).collect(),
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Var(id) => write!(f, "{}", id),
+            Pattern::Record(fields) => {
+                write!(f, "{{")?;
+                for (i, (id, p)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match p {
+                        Pattern::Var(x) if x == id => write!(f, "{}", id)?,
+                        p => write!(f, "{} = {}", id, p)?,
+                    }
+                }
+                write!(f, "}}")
+            }
+            Pattern::List(ps) => {
+                write!(f, "[")?;
+                for (i, p) in ps.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", p)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// A contract attached to a value by a [`MetaValue`](struct.MetaValue.html), with the label
+/// blamed when it fails.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Contract {
+    pub types: Types,
+    pub label: Label,
+}
+
+/// The priority of the value of a [`MetaValue`](struct.MetaValue.html) when it is merged.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum MergePriority {
+    /// A default value, which is dropped when merged with a value of normal priority.
+    Default,
+    Normal,
+}
+
+impl Default for MergePriority {
+    fn default() -> Self {
+        MergePriority::Normal
+    }
+}
+
+/// The metadata of a value: its documentation, its contracts and whether it is a default value.
+///
+/// Metadata are introduced by `Docstring(..)`, `Contract(..)`, `Default(..)` and
+/// `ContractDefault(..)`, and are combined when merged (see the [merge](../merge/index.html)
+/// module). A contract without a value is a meta value without a value. When a meta value with a
+/// value is evaluated, it evaluates to its value, checked by its contracts.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct MetaValue {
+    pub doc: Option<String>,
+    /// The contracts, in the order they are applied: the last one is checked first.
+    pub contracts: Vec<Contract>,
+    pub priority: MergePriority,
+    pub value: Option<RichTerm>,
+}
+
+impl MetaValue {
+    /// A meta value without metadata, wrapping the value `t`.
+    pub fn from_value(t: RichTerm) -> Self {
+        MetaValue {
+            value: Some(t),
+            ..Default::default()
+        }
+    }
+
+    /// Wrap the value of the meta value, if any, in the checks of its contracts.
+    pub fn checked_value(self) -> Option<RichTerm> {
+        let contracts = self.contracts;
+        self.value.map(|t| {
+            contracts
+                .into_iter()
+                .rev()
+                .fold(t, |acc, ctr| Term::Assume(ctr.types, ctr.label, acc).into())
+        })
+    }
+}
+
+/// The fields of a record.
+///
+/// # Iteration order
+///
+/// The fields are iterated in the order of their names, that is the lexicographic order of their
+/// bytes. This order does not depend on the order in which the fields were inserted, nor on the
+/// run, such that code iterating over the fields, as the serialization or the reporting of
+/// errors, gives reproducible results without sorting them.
+#[derive(PartialEq, Clone, Default)]
+pub struct RecordData {
+    fields: BTreeMap<Ident, RichTerm>,
+}
+
+impl RecordData {
+    /// Create an empty record.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn contains_key(&self, id: &Ident) -> bool {
+        self.fields.contains_key(id)
+    }
+
+    pub fn get(&self, id: &Ident) -> Option<&RichTerm> {
+        self.fields.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: &Ident) -> Option<&mut RichTerm> {
+        self.fields.get_mut(id)
+    }
+
+    /// Insert a field, returning the previous value of the field if it was already defined.
+    pub fn insert(&mut self, id: Ident, t: RichTerm) -> Option<RichTerm> {
+        self.fields.insert(id, t)
+    }
+
+    pub fn remove(&mut self, id: &Ident) -> Option<RichTerm> {
+        self.fields.remove(id)
+    }
+
+    /// Keep only the fields satisfying a predicate.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&Ident, &mut RichTerm) -> bool,
+    {
+        let fields = std::mem::take(&mut self.fields);
+        self.fields = fields
+            .into_iter()
+            .filter_map(|(id, mut t)| if f(&id, &mut t) { Some((id, t)) } else { None })
+            .collect();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Ident, &RichTerm)> {
+        self.fields.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&Ident, &mut RichTerm)> {
+        self.fields.iter_mut()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Ident> {
+        self.fields.keys()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &RichTerm> {
+        self.fields.values()
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut RichTerm> {
+        self.fields.values_mut()
+    }
+
+    /// Split two records in three parts: the fields only defined in `self`, the fields defined in
+    /// both records together with their two values, in the order of their names, and the fields
+    /// only defined in `other`.
+    pub fn split(self, other: RecordData) -> (RecordData, CommonFields, RecordData) {
+        let mut left = BTreeMap::new();
+        let mut center = Vec::new();
+        let mut right = other.fields;
+
+        for (id, t1) in self.fields {
+            match right.remove(&id) {
+                Some(t2) => center.push((id, (t1, t2))),
+                None => {
+                    left.insert(id, t1);
+                }
+            }
+        }
+
+        (
+            RecordData { fields: left },
+            center,
+            RecordData { fields: right },
+        )
+    }
+}
+
+// Records are displayed as the map of their fields.
+impl fmt::Debug for RecordData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.fields.iter()).finish()
+    }
+}
+
+impl FromIterator<(Ident, RichTerm)> for RecordData {
+    fn from_iter<I: IntoIterator<Item = (Ident, RichTerm)>>(iter: I) -> Self {
+        RecordData {
+            fields: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl Extend<(Ident, RichTerm)> for RecordData {
+    fn extend<I: IntoIterator<Item = (Ident, RichTerm)>>(&mut self, iter: I) {
+        self.fields.extend(iter)
+    }
+}
+
+impl std::ops::Index<&Ident> for RecordData {
+    type Output = RichTerm;
+
+    fn index(&self, id: &Ident) -> &RichTerm {
+        &self.fields[id]
+    }
+}
+
+/// The fields defined in both records given to [`RecordData::split`], with their two values.
+pub type CommonFields = Vec<(Ident, (RichTerm, RichTerm))>;
+
+/// An owning iterator over the fields of a record, in the order of their names.
+#[derive(Debug)]
+pub struct IntoIter(std::collections::btree_map::IntoIter<Ident, RichTerm>);
+
+impl Iterator for IntoIter {
+    type Item = (Ident, RichTerm);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl IntoIterator for RecordData {
+    type Item = (Ident, RichTerm);
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter(self.fields.into_iter())
+    }
+}
+
+/// An iterator over the fields of a record, in the order of their names.
+#[derive(Debug)]
+pub struct Iter<'a>(std::collections::btree_map::Iter<'a, Ident, RichTerm>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Ident, &'a RichTerm);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<'a> IntoIterator for &'a RecordData {
+    type Item = (&'a Ident, &'a RichTerm);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter(self.fields.iter())
+    }
+}
+
+/// A direct subterm of a term (see [`Term::children`](enum.Term.html#method.children)).
+#[derive(Debug)]
+pub struct Child<T> {
+    /// The subterm, as a shared or a mutable reference.
+    pub term: T,
+    /// The variables bound by the parent term whose scope includes the subterm.
+    pub bound: Vec<Ident>,
+    /// Whether the subterm is a flat contract inside a type, as in `Assume(#c -> Num, f)`.
+    pub in_type: bool,
+}
+
+impl<T> Child<T> {
+    fn new(term: T) -> Self {
+        Child {
+            term,
+            bound: Vec::new(),
+            in_type: false,
+        }
+    }
+
+    fn scoped(term: T, bound: Vec<Ident>) -> Self {
+        Child {
+            term,
+            bound,
+            in_type: false,
+        }
+    }
+}
+
+/// The common body of [`Term::children`](enum.Term.html#method.children) and
+/// [`Term::children_mut`](enum.Term.html#method.children_mut), which only differ by the
+/// mutability of the references. `$iter` and `$values` are the methods iterating over lists and
+/// records, and `$types` gives the children of a type.
+macro_rules! children {
+    ($term:expr, $iter:ident, $values:ident, $types:expr) => {{
+        let types = $types;
+        match $term {
+            Term::Fun(id, t) => vec![Child::scoped(t, vec![id.clone()])],
+            // Let bindings are not recursive: the bound expression is outside the scope of the
+            // variable.
+            Term::Let(id, t1, t2) => vec![Child::new(t1), Child::scoped(t2, vec![id.clone()])],
+            Term::LetRec(id, t1, t2) => vec![
+                Child::scoped(t1, vec![id.clone()]),
+                Child::scoped(t2, vec![id.clone()]),
+            ],
+            Term::LetPattern(pat, t1, t2) => {
+                let ids = pat.bindings().into_iter().cloned().collect();
+                vec![Child::new(t1), Child::scoped(t2, ids)]
+            }
+            Term::RecRecord(fields) => {
+                let ids: Vec<Ident> = fields.keys().cloned().collect();
+                fields
+                    .$values()
+                    .map(|t| Child::scoped(t, ids.clone()))
+                    .collect()
+            }
+            Term::Record(fields) => fields.$values().map(Child::new).collect(),
+            Term::List(ts) => ts.$iter().map(Child::new).collect(),
+            Term::StrChunks(chunks) => chunks
+                .$iter()
+                .filter_map(|chunk| match chunk {
+                    StrChunk::Literal(_) => None,
+                    StrChunk::Expr(t) => Some(Child::new(t)),
+                })
+                .collect(),
+            Term::App(t1, t2) => vec![Child::new(t1), Child::new(t2)],
+            Term::Op1(op, t) => {
+                let mut children: Vec<_> = match op {
+                    UnaryOp::Switch(cases, default) => cases
+                        .$values()
+                        .chain(default.$iter())
+                        .map(Child::new)
+                        .collect(),
+                    UnaryOp::MapRec(f) | UnaryOp::CatchBlame(f) => vec![Child::new(f)],
+                    UnaryOp::ChunksConcat(_, chunks) => chunks
+                        .$iter()
+                        .filter_map(|chunk| match chunk {
+                            StrChunk::Literal(_) => None,
+                            StrChunk::Expr(t) => Some(Child::new(t)),
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                children.push(Child::new(t));
+                children
+            }
+            Term::Op2(op, t1, t2) => {
+                let mut children = match op {
+                    BinaryOp::DynExtend(t) => vec![Child::new(t)],
+                    _ => Vec::new(),
+                };
+                children.push(Child::new(t1));
+                children.push(Child::new(t2));
+                children
+            }
+            Term::Promise(ty, _, t) | Term::Assume(ty, _, t) => {
+                let mut children = types(ty);
+                children.push(Child::new(t));
+                children
+            }
+            Term::MetaValue(meta) => {
+                let mut children: Vec<_> = meta
+                    .contracts
+                    .$iter()
+                    .flat_map(|ctr| types(&ctr.types))
+                    .collect();
+                children.extend(meta.value.$iter().map(Child::new));
+                children
+            }
+            Term::Wrapped(_, t) => vec![Child::new(t)],
+            Term::Bool(_)
+            | Term::Num(_)
+            | Term::Str(_)
+            | Term::Lbl(_)
+            | Term::Var(_)
+            | Term::Enum(_)
+            | Term::Sym(_)
+            | Term::Import(_)
+            | Term::ResolvedImport(_)
+            | Term::External(_) => Vec::new(),
+        }
+    }};
+}
+
+/// The flat contracts of a type, as children of the term containing it.
+fn type_children(ty: &Types) -> Vec<Child<&RichTerm>> {
+    match &ty.0 {
+        AbsType::Flat(t) => vec![Child {
+            term: t,
+            bound: Vec::new(),
+            in_type: true,
+        }],
+        AbsType::Arrow(s, t) => {
+            let mut children = type_children(s);
+            children.extend(type_children(t));
+            children
+        }
+        AbsType::RowExtend(_, field, tail) => {
+            let mut children: Vec<_> = field.iter().flat_map(|t| type_children(t)).collect();
+            children.extend(type_children(tail));
+            children
+        }
+        AbsType::Forall(_, t)
+        | AbsType::Enum(t)
+        | AbsType::StaticRecord(t)
+        | AbsType::DynRecord(t)
+        | AbsType::List(t) => type_children(t),
+        AbsType::Dyn()
+        | AbsType::Num()
+        | AbsType::Bool()
+        | AbsType::Str()
+        | AbsType::Sym()
+        | AbsType::Var(_)
+        | AbsType::RowEmpty() => Vec::new(),
+    }
+}
+
 impl Term {
     /// Recursively apply a function to all `Term`s contained in a `RichTerm`.
     pub fn apply_to_rich_terms<F>(&mut self, func: F)
@@ -169,33 +631,34 @@ impl Term {
                     func(t);
                 });
             }
+            Op1(UnaryOp::MapRec(ref mut f), ref mut t)
+            | Op1(UnaryOp::CatchBlame(ref mut f), ref mut t) => {
+                func(f);
+                func(t);
+            }
             Op2(BinaryOp::DynExtend(ref mut t), ref mut t1, ref mut t2) => {
                 func(t);
                 func(t1);
                 func(t2)
             }
 
-            Bool(_)
-            | Num(_)
-            | Str(_)
-            | Lbl(_)
-            | Var(_)
-            | Sym(_)
-            | Enum(_)
-            | Contract(_, _)
-            | Import(_)
-            | ResolvedImport(_) => {}
+            Bool(_) | Num(_) | Str(_) | Lbl(_) | Var(_) | Sym(_) | Enum(_) | Import(_)
+            | ResolvedImport(_) | External(_) => {}
             Fun(_, ref mut t)
             | Op1(_, ref mut t)
             | Promise(_, _, ref mut t)
             | Assume(_, _, ref mut t)
-            | Wrapped(_, ref mut t)
-            | DefaultValue(ref mut t)
-            | Docstring(_, ref mut t)
-            | ContractWithDefault(_, _, ref mut t) => {
+            | Wrapped(_, ref mut t) => {
                 func(t);
             }
+            MetaValue(ref mut meta) => {
+                if let Some(ref mut t) = meta.value {
+                    func(t);
+                }
+            }
             Let(_, ref mut t1, ref mut t2)
+            | LetRec(_, ref mut t1, ref mut t2)
+            | LetPattern(_, ref mut t1, ref mut t2)
             | App(ref mut t1, ref mut t2)
             | Op2(_, ref mut t1, ref mut t2) => {
                 func(t1);
@@ -211,6 +674,21 @@ impl Term {
         }
     }
 
+    /// Return the direct subterms of a term, together with the variables they are in the scope
+    /// of. This is the basis of the traversals of the analyses and the
+    /// transformations which need to know the binders, such as the computation of free variables.
+    ///
+    /// The flat contracts inside types are included, and marked as such.
+    pub fn children(&self) -> Vec<Child<&RichTerm>> {
+        children!(self, iter, values, type_children)
+    }
+
+    /// Same as [`children`](#method.children), but return mutable references. The flat contracts
+    /// inside types are not included.
+    pub fn children_mut(&mut self) -> Vec<Child<&mut RichTerm>> {
+        children!(self, iter_mut, values_mut, |_: &Types| Vec::new())
+    }
+
     /// Return the class of an expression in WHNF.
     ///
     /// The class of an expression is an approximation of its type used in error reporting. Class
@@ -229,11 +707,10 @@ impl Term {
             Term::List(_) => Some("List"),
             Term::Sym(_) => Some("Sym"),
             Term::Wrapped(_, _) => Some("Wrapped"),
-            Term::Contract(_, _)
-            | Term::ContractWithDefault(_, _, _)
-            | Term::Docstring(_, _)
-            | Term::DefaultValue(_) => Some("EnrichedValue"),
+            Term::MetaValue(_) => Some("EnrichedValue"),
             Term::Let(_, _, _)
+            | Term::LetRec(_, _, _)
+            | Term::LetPattern(_, _, _)
             | Term::App(_, _)
             | Term::Var(_)
             | Term::Op1(_, _)
@@ -242,6 +719,7 @@ impl Term {
             | Term::Assume(_, _, _)
             | Term::Import(_)
             | Term::ResolvedImport(_)
+            | Term::External(_)
             | Term::StrChunks(_) => None,
         }
         .map(|s| String::from(s))
@@ -252,7 +730,7 @@ impl Term {
         match self {
             Term::Bool(true) => String::from("true"),
             Term::Bool(false) => String::from("false"),
-            Term::Num(n) => format!("{}", n),
+            Term::Num(n) => NumFormat::default().format(*n),
             Term::Str(s) => format!("\"{}\"", s),
             Term::StrChunks(chunks) => {
                 let chunks_str: Vec<String> = chunks
@@ -273,16 +751,29 @@ impl Term {
             Term::List(_) => String::from("[ ... ]"),
             Term::Sym(_) => String::from("<sym>"),
             Term::Wrapped(_, _) => String::from("<wrapped>"),
-            Term::Contract(_, _) => String::from("<enriched:contract>"),
-            Term::ContractWithDefault(_, _, ref t) => {
-                format!("<enriched:contract,default={}>", (*t.term).shallow_repr())
-            }
-            Term::Docstring(_, ref t) => {
-                format!("<enriched:doc,term={}>", (*t.term).shallow_repr())
+            Term::MetaValue(ref meta) => {
+                let mut parts = Vec::new();
+                if meta.doc.is_some() {
+                    parts.push(String::from("doc"));
+                }
+                if !meta.contracts.is_empty() {
+                    parts.push(String::from("contract"));
+                }
+                match (&meta.value, meta.priority) {
+                    (Some(t), MergePriority::Default) => {
+                        parts.push(format!("default={}", t.as_ref().shallow_repr()))
+                    }
+                    (Some(t), MergePriority::Normal) => {
+                        parts.push(format!("term={}", t.as_ref().shallow_repr()))
+                    }
+                    (None, _) => (),
+                }
+                format!("<enriched:{}>", parts.join(","))
             }
-            Term::DefaultValue(ref t) => format!("<enriched:default={}", (*t.term).shallow_repr()),
             Term::Var(Ident(id)) => id.clone(),
             Term::Let(_, _, _)
+            | Term::LetRec(_, _, _)
+            | Term::LetPattern(_, _, _)
             | Term::App(_, _)
             | Term::Op1(_, _)
             | Term::Op2(_, _, _)
@@ -290,6 +781,7 @@ impl Term {
             | Term::Assume(_, _, _)
             | Term::Import(_)
             | Term::ResolvedImport(_) => String::from("<unevaluated>"),
+            Term::External(name) => format!("<external:{}>", name),
         }
     }
 
@@ -306,6 +798,8 @@ impl Term {
             | Term::List(_)
             | Term::Sym(_) => true,
             Term::Let(_, _, _)
+            | Term::LetRec(_, _, _)
+            | Term::LetPattern(_, _, _)
             | Term::App(_, _)
             | Term::Var(_)
             | Term::Op1(_, _)
@@ -313,12 +807,10 @@ impl Term {
             | Term::Promise(_, _, _)
             | Term::Assume(_, _, _)
             | Term::Wrapped(_, _)
-            | Term::Contract(_, _)
-            | Term::DefaultValue(_)
-            | Term::ContractWithDefault(_, _, _)
-            | Term::Docstring(_, _)
+            | Term::MetaValue(_)
             | Term::Import(_)
             | Term::ResolvedImport(_)
+            | Term::External(_)
             | Term::StrChunks(_)
             | Term::RecRecord(_) => false,
         }
@@ -327,10 +819,7 @@ impl Term {
     /// Determine if a term is an enriched value.
     pub fn is_enriched(&self) -> bool {
         match self {
-            Term::Contract(_, _)
-            | Term::DefaultValue(_)
-            | Term::ContractWithDefault(_, _, _)
-            | Term::Docstring(_, _) => true,
+            Term::MetaValue(_) => true,
             Term::Bool(_)
             | Term::Num(_)
             | Term::Str(_)
@@ -344,6 +833,8 @@ impl Term {
             | Term::Sym(_)
             | Term::Wrapped(_, _)
             | Term::Let(_, _, _)
+            | Term::LetRec(_, _, _)
+            | Term::LetPattern(_, _, _)
             | Term::App(_, _)
             | Term::Var(_)
             | Term::Op1(_, _)
@@ -351,7 +842,8 @@ impl Term {
             | Term::Promise(_, _, _)
             | Term::Assume(_, _, _)
             | Term::Import(_)
-            | Term::ResolvedImport(_) => false,
+            | Term::ResolvedImport(_)
+            | Term::External(_) => false,
         }
     }
 
@@ -368,6 +860,8 @@ impl Term {
             | Term::Enum(_)
             | Term::Sym(_) => true,
             Term::Let(_, _, _)
+            | Term::LetRec(_, _, _)
+            | Term::LetPattern(_, _, _)
             | Term::Record(_)
             | Term::List(_)
             | Term::Fun(_, _)
@@ -378,12 +872,10 @@ impl Term {
             | Term::Promise(_, _, _)
             | Term::Assume(_, _, _)
             | Term::Wrapped(_, _)
-            | Term::Contract(_, _)
-            | Term::DefaultValue(_)
-            | Term::ContractWithDefault(_, _, _)
-            | Term::Docstring(_, _)
+            | Term::MetaValue(_)
             | Term::Import(_)
             | Term::ResolvedImport(_)
+            | Term::External(_)
             | Term::StrChunks(_)
             | Term::RecRecord(_) => false,
         }
@@ -420,6 +912,10 @@ pub enum UnaryOp<CapturedTerm> {
     IsList(),
     /// Test if a term is a record.
     IsRecord(),
+    /// Return the kind of a term as a string, one of `"Num"`, `"Bool"`, `"Str"`, `"Enum"`, `"Fun"`,
+    /// `"List"`, `"Record"`, or `"Other"` for labels, symbols and wrapped terms. The names of the
+    /// types are keywords, and cannot be used as enum tags.
+    TypeOf(),
 
     /// Raise a blame, which stops the execution and prints an error according to the label argument.
     Blame(),
@@ -441,6 +937,11 @@ pub enum UnaryOp<CapturedTerm> {
     ///
     /// Static means that the field identifier is a statically known string inside the source.
     StaticAccess(Ident),
+    /// Restrict a record to the given fields, which must all be defined.
+    ///
+    /// Generated by the `import "file.ncl" pick [a, b]` syntax, which imports only some fields of
+    /// a file.
+    Pick(Vec<Ident>),
 
     /// Map a function on a record.
     ///
@@ -449,6 +950,15 @@ pub enum UnaryOp<CapturedTerm> {
     /// function: i.e., `mapRec f {a=2;}` evaluates to `{a=(f "a" 2);}`.
     MapRec(CapturedTerm),
 
+    /// Recover from a contract failure.
+    ///
+    /// `catchBlame fallback t` evaluates `t` to a weak head normal form. If a contract blames
+    /// during this evaluation, the blame error is discarded and `fallback` is applied to the
+    /// label of the error instead.
+    /// Contract failures which happen later, for example when a function returned by `t` is
+    /// called, are not caught.
+    CatchBlame(CapturedTerm),
+
     /// Inverse the polarity of a label.
     ChangePolarity(),
 
@@ -479,9 +989,24 @@ pub enum UnaryOp<CapturedTerm> {
     GoCodom(),
     /// Append text to the tag of a label.
     Tag(String),
+    /// Get the tag of a label, as a string.
+    LabelTag(),
+    /// Get the type path of a label, as a list of the enum tags `` `Domain `` and `` `Codomain ``,
+    /// and of the names of the fields of records, as strings.
+    LabelPath(),
+    /// Create a fresh label from a string, used as its tag.
+    ///
+    /// The label has the type `Dyn`, a positive polarity and an empty type path. Its position is
+    /// the one of the call to `mkLabel`, which is thus reported when the label is blamed.
+    MkLabel(),
 
     /// Wrap a term with a type tag (see `Wrapped` in [`Term`](enum.Term.html)).
     Wrap(),
+    /// Generate a fresh symbol, which can be used to wrap terms with `wrap` and `unwrap`.
+    ///
+    /// The argument is a string describing the symbol, which is only informative. The generated
+    /// symbols are distinct from each other, and from the ones of parametricity contracts.
+    FreshSym(),
 
     /// Force the evaluation of its argument and proceed with the second.
     Seq(),
@@ -497,6 +1022,21 @@ pub enum UnaryOp<CapturedTerm> {
     ListTail(),
     /// Return the length of a list.
     ListLength(),
+    /// Remove the duplicates of a list, keeping the first occurrence of each element. Elements are
+    /// compared structurally, and are thus evaluated deeply.
+    ListUnique(),
+    /// Combine the elements of two lists with a function, stopping at the end of the shortest
+    /// one. Take the function as argument, and the two lists from the stack.
+    ListZipWith(),
+    /// Generate the list of numbers from a start (inclusive) to an end (exclusive) with a given
+    /// step: `listRange 0 10 3` evaluates to `[0, 3, 6, 9]`. Take the start as argument, and the
+    /// end and the step from the stack.
+    ListRange(),
+    /// Apply a function a given number of times to an initial value: `iterate 3 f x` evaluates to
+    /// `f (f (f x))`. The intermediate values are evaluated in a loop, one after the other, which
+    /// doesn't grow the stack. Take the number of steps as argument, and the function and the
+    /// initial value from the stack.
+    Iterate(),
 
     /// Only generated during the evaluation of a string with interpolated expressions. It holds a
     /// string accumulator, the remaining chunks to be evaluated, and is applied to the current
@@ -505,6 +1045,38 @@ pub enum UnaryOp<CapturedTerm> {
 
     /// Return the names of the fields of a record as a string list.
     FieldsOf(),
+    /// Return the metadata of the fields of a record, as a list of records `{name; hasDefault;
+    /// hasDoc; contracts}` sorted by name, where `contracts` is the list of the types of the
+    /// contracts of the field, as strings. The entries are lazy: the metadata of a field are only
+    /// computed when its entry is evaluated.
+    FieldsWithMetadata(),
+    /// Only generated by `fieldsWithMetadata`. Return the metadata of its argument, which is the
+    /// field of the given name, as an entry of `fieldsWithMetadata`. The argument is evaluated
+    /// without extracting the value of a metavalue, as the operands of `merge`.
+    FieldMetadata(String),
+    /// Rename a field of a record: `recordRenameField "a" "b" {a = 1}` evaluates to `{b = 1}`.
+    /// Take the old name as argument, and the new name and the record from the stack.
+    RecordRenameField(),
+
+    /// Convert an enum tag to the string of its name: `enumToStr `foo` evaluates to `"foo"`.
+    EnumToStr(),
+    /// Convert a string to the enum tag of the same name, which must belong to the given list of
+    /// tags: `enumFromStr <(| foo, bar |)> "foo"` evaluates to `` `foo ``.
+    EnumFromStr(Vec<Ident>),
+
+    /// Return the last component of a path, ignoring trailing slashes: `pathBasename "a/b.ncl"`
+    /// evaluates to `"b.ncl"`.
+    PathBasename(),
+    /// Return the extension of the last component of a path, or the empty string if there is
+    /// none: `pathExtension "a/b.tar.gz"` evaluates to `"gz"`.
+    PathExtension(),
+
+    /// Call a host function on a list of arguments.
+    ///
+    /// Only generated by the wrappers of host functions (see
+    /// [`Program::register_function`](../program/struct.Program.html#method.register_function)),
+    /// which force the arguments deeply before the call.
+    HostCall(HostFunction),
 }
 
 impl<Ty> UnaryOp<Ty> {
@@ -522,6 +1094,7 @@ impl<Ty> UnaryOp<Ty> {
                 op.map(f),
             ),
             MapRec(t) => MapRec(f(t)),
+            CatchBlame(t) => CatchBlame(f(t)),
 
             Ite() => Ite(),
 
@@ -533,20 +1106,26 @@ impl<Ty> UnaryOp<Ty> {
             IsFun() => IsFun(),
             IsList() => IsList(),
             IsRecord() => IsRecord(),
+            TypeOf() => TypeOf(),
 
             Blame() => Blame(),
 
             Embed(id) => Embed(id),
 
             StaticAccess(id) => StaticAccess(id),
+            Pick(ids) => Pick(ids),
 
             ChangePolarity() => ChangePolarity(),
             Pol() => Pol(),
             GoDom() => GoDom(),
             GoCodom() => GoCodom(),
             Tag(s) => Tag(s),
+            LabelTag() => LabelTag(),
+            LabelPath() => LabelPath(),
+            MkLabel() => MkLabel(),
 
             Wrap() => Wrap(),
+            FreshSym() => FreshSym(),
 
             Seq() => Seq(),
             DeepSeq() => DeepSeq(),
@@ -554,6 +1133,10 @@ impl<Ty> UnaryOp<Ty> {
             ListHead() => ListHead(),
             ListTail() => ListTail(),
             ListLength() => ListLength(),
+            ListUnique() => ListUnique(),
+            ListZipWith() => ListZipWith(),
+            ListRange() => ListRange(),
+            Iterate() => Iterate(),
 
             ChunksConcat(s, chunks) => ChunksConcat(
                 s,
@@ -567,8 +1150,48 @@ impl<Ty> UnaryOp<Ty> {
             ),
 
             FieldsOf() => FieldsOf(),
+            FieldsWithMetadata() => FieldsWithMetadata(),
+            FieldMetadata(name) => FieldMetadata(name),
+            RecordRenameField() => RecordRenameField(),
+
+            EnumToStr() => EnumToStr(),
+            EnumFromStr(tags) => EnumFromStr(tags),
+
+            PathBasename() => PathBasename(),
+            PathExtension() => PathExtension(),
+
+            HostCall(f) => HostCall(f),
         }
     }
+
+    /// Return false if the argument is evaluated without extracting the value of a metavalue (see
+    /// [eval](../eval/index.html)).
+    pub fn is_strict(&self) -> bool {
+        !matches!(self, UnaryOp::FieldMetadata(_))
+    }
+}
+
+/// A function provided by the host, callable from Nickel code.
+///
+/// A host function receives its arguments as fully evaluated, closed terms, and returns either a
+/// closed term or an error message.
+#[derive(Clone)]
+pub struct HostFunction {
+    /// The name under which the function is registered.
+    pub name: String,
+    pub fun: Rc<dyn Fn(Vec<RichTerm>) -> Result<RichTerm, String>>,
+}
+
+impl fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HostFunction({})", self.name)
+    }
+}
+
+impl PartialEq for HostFunction {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && Rc::ptr_eq(&self.fun, &other.fun)
+    }
 }
 
 /// Primitive binary operators
@@ -594,16 +1217,69 @@ pub enum BinaryOp<CapturedTerm> {
     DynRemove(),
     /// Access the field of record. The field name is given as an arbitrary Nickel expression.
     DynAccess(),
+    /// Access the field of a record if it exists, as an optional value: `{status = `Some; value =
+    /// v}` if the field is defined, and `{status = `None}` otherwise. The field name is given as
+    /// an arbitrary Nickel expression.
+    DynAccessOpt(),
     /// Test if a record has a specific field.
     HasField(),
+    /// Record the position of a value in a label, as the position of the value which broke the
+    /// corresponding contract. Used by the builtin contracts before blaming.
+    WithArgPos(),
+    /// Set the tag of a label to a string computed at run time, as in `withTag msg l`. Same as the
+    /// unary operator `tag`, whose tag is a literal.
+    WithTag(),
+    /// Apply a record contract whose fields only have primitive types, in one step: the first
+    /// argument is the label of the contract, and the second one the checked value. Has no
+    /// syntax, and is introduced by the
+    /// [`contract_compilation`](../transformations/contract_compilation/index.html)
+    /// transformation.
+    RecordContract(
+        /* the fields with their types */ Vec<(Ident, Types)>,
+        /* whether other fields are allowed */ bool,
+    ),
+    /// Query the value at a path of field names in nested records, as an optional value: `{status
+    /// = `Some; value = v}` if the path exists, and `{status = `None}` otherwise. The value itself
+    /// is not evaluated.
+    RecordGetPath(),
+    /// Restrict a record to the fields which also belong to a second record.
+    RecordIntersect(),
+    /// Remove from a record the fields which belong to a second record.
+    RecordDifference(),
+    /// Return the names of the fields of a record which match a regular expression, sorted:
+    /// `recordFindFields "(?i)^content-type$" headers`. The expression matches anywhere in the name
+    /// unless it is anchored.
+    RecordFindFields(),
     /// Concatenate two lists.
     ListConcat(),
     /// Map a function on each element of a list.
     ListMap(),
     /// Access the n-th element of a list.
     ListElemAt(),
+    /// Pair the elements of two lists as two-element lists, stopping at the end of the shortest
+    /// one: `listZip [1, 2] ["a", "b"]` evaluates to `[[1, "a"], [2, "b"]]`.
+    ListZip(),
+    /// Group the elements of a list in a record, according to the field name computed by a
+    /// function for each element. Elements keep their relative order inside each group.
+    ListGroupBy(),
+    /// Split a list according to a predicate, as a record `{matched, rest}`.
+    ListPartition(),
+    /// Sort a list using a comparison function, which returns one of the tags `Lesser`, `Equal`
+    /// or `Greater`. The sort is stable.
+    ListSort(),
+    /// Sort a list according to the key computed by a function for each element. Keys must be
+    /// either all numbers or all strings. The sort is stable.
+    ListSortBy(),
     /// The merge operator (see the [merge module](../merge/index.html)).
     Merge(),
+    /// Merge where the second operand takes precedence over the first one (see the [merge
+    /// module](../merge/index.html)). It has no syntax, and is used to layer the values of the
+    /// host on top of a program (see
+    /// [`Program::eval_with_overrides`](../program/struct.Program.html#method.eval_with_overrides)).
+    MergeOverride(),
+    /// Join two paths: `pathJoin "a" "b.ncl"` evaluates to `"a/b.ncl"`. If the second path is
+    /// absolute, it is returned as is.
+    PathJoin(),
 }
 
 impl<Ty> BinaryOp<Ty> {
@@ -618,35 +1294,111 @@ impl<Ty> BinaryOp<Ty> {
             Eq() => Eq(),
             DynRemove() => DynRemove(),
             DynAccess() => DynAccess(),
+            DynAccessOpt() => DynAccessOpt(),
             HasField() => HasField(),
+            WithArgPos() => WithArgPos(),
+            WithTag() => WithTag(),
+            RecordContract(fields, open) => RecordContract(fields, open),
+            RecordGetPath() => RecordGetPath(),
+            RecordIntersect() => RecordIntersect(),
+            RecordFindFields() => RecordFindFields(),
+            RecordDifference() => RecordDifference(),
             ListConcat() => ListConcat(),
             ListMap() => ListMap(),
             ListElemAt() => ListElemAt(),
+            ListZip() => ListZip(),
+            ListGroupBy() => ListGroupBy(),
+            ListPartition() => ListPartition(),
+            ListSort() => ListSort(),
+            ListSortBy() => ListSortBy(),
             Merge() => Merge(),
+            MergeOverride() => MergeOverride(),
+            PathJoin() => PathJoin(),
         }
     }
 
     pub fn is_strict(&self) -> bool {
         match self {
-            BinaryOp::Merge() => false,
+            BinaryOp::Merge() | BinaryOp::MergeOverride() => false,
             _ => true,
         }
     }
 }
 
+/// The identity of a node of the AST of a source file.
+///
+/// Identifiers are assigned when a file is parsed, by numbering its nodes in pre-order (see
+/// [`RichTerm::assign_ids`](struct.RichTerm.html#method.assign_ids)), such that parsing the same
+/// source again gives the same identifiers. Contrary to positions, they are unique: the nodes
+/// generated by the parser for a single construct, such as the nested functions of `fun x y =>
+/// e`, share a position but not an identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub FileId, pub u32);
+
 /// Wrap [terms](type.Term.html) with positional information.
-#[derive(Debug, PartialEq, Clone)]
+///
+/// A term also carries the identity of the node it comes from, if any (see
+/// [`NodeId`](struct.NodeId.html)). Program transformations keep the identity of the nodes they
+/// rewrite, such that analyses of the transformed program can report their results on the source
+/// nodes. The nodes built by the transformations or during evaluation don't have an identity.
+/// Identities are not compared by `==`, which only compares the terms and their positions.
+///
+/// The program transformations also store the [free
+/// variables](../transformations/free_vars/index.html) of the nodes they produce, such that they
+/// are not computed again each time a term is packed as a closure. A node built from another one
+/// doesn't inherit its free variables, which are then computed on demand.
+#[derive(Debug, Clone)]
 pub struct RichTerm {
     pub term: Box<Term>,
     pub pos: Option<RawSpan>,
+    pub id: Option<NodeId>,
+    pub free_vars: Option<Rc<HashSet<Ident>>>,
+}
+
+impl PartialEq for RichTerm {
+    fn eq(&self, other: &Self) -> bool {
+        self.term == other.term && self.pos == other.pos
+    }
 }
 
 impl RichTerm {
     pub fn new(t: Term) -> RichTerm {
+        RichTerm::with_pos(Box::new(t), None)
+    }
+
+    /// Build a node at the given position, without an identity and free variables.
+    pub fn with_pos(term: Box<Term>, pos: Option<RawSpan>) -> RichTerm {
         RichTerm {
-            term: Box::new(t),
-            pos: None,
+            term,
+            pos,
+            id: None,
+            free_vars: None,
+        }
+    }
+
+    /// Number the nodes of a term parsed from the file `file_id` in pre-order, starting from 0.
+    /// The nodes inside types, as the terms of flat contracts, are not numbered.
+    pub fn assign_ids(&mut self, file_id: FileId) {
+        fn assign(rt: &mut RichTerm, file_id: FileId, next: &Cell<u32>) {
+            rt.id = Some(NodeId(file_id, next.get()));
+            next.set(next.get() + 1);
+            rt.term
+                .apply_to_rich_terms(|rt: &mut RichTerm| assign(rt, file_id, next));
         }
+
+        assign(self, file_id, &Cell::new(0));
+    }
+
+    /// Return the subterm with the given identity, if any. The nodes inside types are not
+    /// searched.
+    pub fn find_id(&self, id: NodeId) -> Option<&RichTerm> {
+        if self.id == Some(id) {
+            return Some(self);
+        }
+
+        crate::analysis::subterms(&self.term)
+            .into_iter()
+            .find_map(|t| t.find_id(id))
     }
 
     /// Erase recursively the positional information.
@@ -685,11 +1437,36 @@ impl RichTerm {
     /// Apply a transformation on a whole term by mapping a function `f` on each node in a
     /// bottom-up manner. `f` may return a generic error `E` and use the state `S` which is
     /// passed around.
+    ///
+    /// The contracts, that is the metavalues without a value, are left untouched, although the
+    /// terms of their flat types are traversed: see
+    /// [`traverse_with_contracts`](#method.traverse_with_contracts).
     pub fn traverse<F, S, E>(self, f: &mut F, state: &mut S) -> Result<RichTerm, E>
     where
         F: FnMut(RichTerm, &mut S) -> Result<RichTerm, E>,
     {
-        let RichTerm { term, pos } = self;
+        self.traverse_with_contracts(f, &mut |rt, _| Ok(rt), state)
+    }
+
+    /// Same as [`traverse`](#method.traverse), but map the function `g` on the contracts instead
+    /// of leaving them untouched. As a contract is not evaluated by itself, most transformations
+    /// don't apply to it, but the ones acting on types do.
+    pub fn traverse_with_contracts<F, G, S, E>(
+        self,
+        f: &mut F,
+        g: &mut G,
+        state: &mut S,
+    ) -> Result<RichTerm, E>
+    where
+        F: FnMut(RichTerm, &mut S) -> Result<RichTerm, E>,
+        G: FnMut(RichTerm, &mut S) -> Result<RichTerm, E>,
+    {
+        let RichTerm {
+            term,
+            pos,
+            id: node_id,
+            ..
+        } = self;
         match *term {
             v @ Term::Bool(_)
             | v @ Term::Num(_)
@@ -699,41 +1476,76 @@ impl RichTerm {
             | v @ Term::Var(_)
             | v @ Term::Enum(_)
             | v @ Term::Import(_)
-            | v @ Term::ResolvedImport(_) => f(
+            | v @ Term::ResolvedImport(_)
+            | v @ Term::External(_) => f(
                 RichTerm {
                     term: Box::new(v),
                     pos,
+                    id: node_id,
+                    free_vars: None,
                 },
                 state,
             ),
             Term::Fun(id, t) => {
-                let t = t.traverse(f, state)?;
+                let t = t.traverse_with_contracts(f, g, state)?;
                 f(
                     RichTerm {
                         term: Box::new(Term::Fun(id, t)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
             }
             Term::Let(id, t1, t2) => {
-                let t1 = t1.traverse(f, state)?;
-                let t2 = t2.traverse(f, state)?;
+                let t1 = t1.traverse_with_contracts(f, g, state)?;
+                let t2 = t2.traverse_with_contracts(f, g, state)?;
                 f(
                     RichTerm {
                         term: Box::new(Term::Let(id, t1, t2)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
+                    },
+                    state,
+                )
+            }
+            Term::LetRec(id, t1, t2) => {
+                let t1 = t1.traverse_with_contracts(f, g, state)?;
+                let t2 = t2.traverse_with_contracts(f, g, state)?;
+                f(
+                    RichTerm {
+                        term: Box::new(Term::LetRec(id, t1, t2)),
+                        pos,
+                        id: node_id,
+                        free_vars: None,
+                    },
+                    state,
+                )
+            }
+            Term::LetPattern(pat, t1, t2) => {
+                let t1 = t1.traverse_with_contracts(f, g, state)?;
+                let t2 = t2.traverse_with_contracts(f, g, state)?;
+                f(
+                    RichTerm {
+                        term: Box::new(Term::LetPattern(pat, t1, t2)),
+                        pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
             }
             Term::App(t1, t2) => {
-                let t1 = t1.traverse(f, state)?;
-                let t2 = t2.traverse(f, state)?;
+                let t1 = t1.traverse_with_contracts(f, g, state)?;
+                let t2 = t2.traverse_with_contracts(f, g, state)?;
                 f(
                     RichTerm {
                         term: Box::new(Term::App(t1, t2)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
@@ -744,72 +1556,87 @@ impl RichTerm {
                 let cases_res: Result<HashMap<Ident, RichTerm>, E> = cases
                     .into_iter()
                     // For the conversion to work, note that we need a Result<(Ident,RichTerm), E>
-                    .map(|(id, t)| t.traverse(f, state).map(|t_ok| (id.clone(), t_ok)))
+                    .map(|(id, t)| {
+                        t.traverse_with_contracts(f, g, state)
+                            .map(|t_ok| (id.clone(), t_ok))
+                    })
                     .collect();
 
                 let default = default
-                    .map(|t| t.traverse(f, state))
+                    .map(|t| t.traverse_with_contracts(f, g, state))
                     // Transpose from Option<Result> to Result<Option>. There is a `transpose`
                     // method in Rust, but it has currently not make it to the stable version yet
                     .map_or(Ok(None), |res| res.map(Some))?;
 
-                let t = t.traverse(f, state)?;
+                let t = t.traverse_with_contracts(f, g, state)?;
 
                 f(
                     RichTerm {
                         term: Box::new(Term::Op1(UnaryOp::Switch(cases_res?, default), t)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
             }
             Term::Op1(op, t) => {
-                let t = t.traverse(f, state)?;
+                let t = t.traverse_with_contracts(f, g, state)?;
                 f(
                     RichTerm {
                         term: Box::new(Term::Op1(op, t)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
             }
             Term::Op2(op, t1, t2) => {
-                let t1 = t1.traverse(f, state)?;
-                let t2 = t2.traverse(f, state)?;
+                let t1 = t1.traverse_with_contracts(f, g, state)?;
+                let t2 = t2.traverse_with_contracts(f, g, state)?;
                 f(
                     RichTerm {
                         term: Box::new(Term::Op2(op, t1, t2)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
             }
             Term::Promise(ty, l, t) => {
-                let t = t.traverse(f, state)?;
+                let t = t.traverse_with_contracts(f, g, state)?;
                 f(
                     RichTerm {
                         term: Box::new(Term::Promise(ty, l, t)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
             }
             Term::Assume(ty, l, t) => {
-                let t = t.traverse(f, state)?;
+                let t = t.traverse_with_contracts(f, g, state)?;
                 f(
                     RichTerm {
                         term: Box::new(Term::Assume(ty, l, t)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
             }
             Term::Wrapped(i, t) => {
-                let t = t.traverse(f, state)?;
+                let t = t.traverse_with_contracts(f, g, state)?;
                 f(
                     RichTerm {
                         term: Box::new(Term::Wrapped(i, t)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
@@ -817,15 +1644,20 @@ impl RichTerm {
             Term::Record(map) => {
                 // The annotation on `map_res` uses Result's corresponding trait to convert from
                 // Iterator<Result> to a Result<Iterator>
-                let map_res: Result<HashMap<Ident, RichTerm>, E> = map
+                let map_res: Result<RecordData, E> = map
                     .into_iter()
                     // For the conversion to work, note that we need a Result<(Ident,RichTerm), E>
-                    .map(|(id, t)| t.traverse(f, state).map(|t_ok| (id.clone(), t_ok)))
+                    .map(|(id, t)| {
+                        t.traverse_with_contracts(f, g, state)
+                            .map(|t_ok| (id.clone(), t_ok))
+                    })
                     .collect();
                 f(
                     RichTerm {
                         term: Box::new(Term::Record(map_res?)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
@@ -833,27 +1665,36 @@ impl RichTerm {
             Term::RecRecord(map) => {
                 // The annotation on `map_res` uses Result's corresponding trait to convert from
                 // Iterator<Result> to a Result<Iterator>
-                let map_res: Result<HashMap<Ident, RichTerm>, E> = map
+                let map_res: Result<RecordData, E> = map
                     .into_iter()
                     // For the conversion to work, note that we need a Result<(Ident,RichTerm), E>
-                    .map(|(id, t)| t.traverse(f, state).map(|t_ok| (id.clone(), t_ok)))
+                    .map(|(id, t)| {
+                        t.traverse_with_contracts(f, g, state)
+                            .map(|t_ok| (id.clone(), t_ok))
+                    })
                     .collect();
                 f(
                     RichTerm {
                         term: Box::new(Term::RecRecord(map_res?)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
             }
             Term::List(ts) => {
-                let ts_res: Result<Vec<RichTerm>, E> =
-                    ts.into_iter().map(|t| t.traverse(f, state)).collect();
+                let ts_res: Result<Vec<RichTerm>, E> = ts
+                    .into_iter()
+                    .map(|t| t.traverse_with_contracts(f, g, state))
+                    .collect();
 
                 f(
                     RichTerm {
                         term: Box::new(Term::List(ts_res?)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
@@ -863,7 +1704,9 @@ impl RichTerm {
                     .into_iter()
                     .map(|chunk| match chunk {
                         chunk @ StrChunk::Literal(_) => Ok(chunk),
-                        StrChunk::Expr(t) => Ok(StrChunk::Expr(t.traverse(f, state)?)),
+                        StrChunk::Expr(t) => {
+                            Ok(StrChunk::Expr(t.traverse_with_contracts(f, g, state)?))
+                        }
                     })
                     .collect();
 
@@ -871,55 +1714,51 @@ impl RichTerm {
                     RichTerm {
                         term: Box::new(Term::StrChunks(chunks_res?)),
                         pos,
+                        id: node_id,
+                        free_vars: None,
                     },
                     state,
                 )
             }
-            Term::Contract(ty, label) => {
-                let ty = match ty {
-                    Types(AbsType::Flat(t)) => Types(AbsType::Flat(t.traverse(f, state)?)),
-                    ty => ty,
+            Term::MetaValue(meta) => {
+                let contracts: Result<Vec<Contract>, E> = meta
+                    .contracts
+                    .into_iter()
+                    .map(|ctr| {
+                        let types = match ctr.types {
+                            Types(AbsType::Flat(t)) => {
+                                Types(AbsType::Flat(t.traverse_with_contracts(f, g, state)?))
+                            }
+                            ty => ty,
+                        };
+                        Ok(Contract {
+                            types,
+                            label: ctr.label,
+                        })
+                    })
+                    .collect();
+                let value = meta
+                    .value
+                    .map(|t| t.traverse_with_contracts(f, g, state))
+                    .transpose()?;
+                let meta = MetaValue {
+                    contracts: contracts?,
+                    value,
+                    ..meta
                 };
-
-                Ok(RichTerm {
-                    term: Box::new(Term::Contract(ty, label)),
+                let is_contract = meta.value.is_none();
+                let rt = RichTerm {
+                    term: Box::new(Term::MetaValue(meta)),
                     pos,
-                })
-            }
-            Term::DefaultValue(t) => {
-                let t = t.traverse(f, state)?;
-                f(
-                    RichTerm {
-                        term: Box::new(Term::DefaultValue(t)),
-                        pos,
-                    },
-                    state,
-                )
-            }
-            Term::ContractWithDefault(ty, lbl, t) => {
-                let ty = match ty {
-                    Types(AbsType::Flat(t)) => Types(AbsType::Flat(t.traverse(f, state)?)),
-                    ty => ty,
+                    id: node_id,
+                    free_vars: None,
                 };
 
-                let t = t.traverse(f, state)?;
-                f(
-                    RichTerm {
-                        term: Box::new(Term::ContractWithDefault(ty, lbl, t)),
-                        pos,
-                    },
-                    state,
-                )
-            }
-            Term::Docstring(s, t) => {
-                let t = t.traverse(f, state)?;
-                f(
-                    RichTerm {
-                        term: Box::new(Term::Docstring(s, t)),
-                        pos,
-                    },
-                    state,
-                )
+                if is_contract {
+                    g(rt, state)
+                } else {
+                    f(rt, state)
+                }
             }
         }
     }
@@ -931,6 +1770,36 @@ impl From<RichTerm> for Term {
     }
 }
 
+impl From<f64> for Term {
+    fn from(n: f64) -> Self {
+        Term::Num(n)
+    }
+}
+
+impl From<i32> for Term {
+    fn from(n: i32) -> Self {
+        Term::Num(f64::from(n))
+    }
+}
+
+impl From<bool> for Term {
+    fn from(b: bool) -> Self {
+        Term::Bool(b)
+    }
+}
+
+impl From<&str> for Term {
+    fn from(s: &str) -> Self {
+        Term::Str(String::from(s))
+    }
+}
+
+impl From<String> for Term {
+    fn from(s: String) -> Self {
+        Term::Str(s)
+    }
+}
+
 impl AsRef<Term> for RichTerm {
     fn as_ref(&self) -> &Term {
         &self.term
@@ -47,7 +47,7 @@ pub mod share_normal_form {
     use super::fresh_var;
     use crate::identifier::Ident;
     use crate::position::RawSpan;
-    use crate::term::{RichTerm, Term};
+    use crate::term::{MetaValue, NodeId, RichTerm, Term};
 
     /// Transform the top-level term of an AST to a share normal form, if it can.
     ///
@@ -58,7 +58,12 @@ pub mod share_normal_form {
     /// the transformation is implemented as rewrite rules, and must be used in conjunction a
     /// traversal to obtain a full transformation.
     pub fn transform_one(rt: RichTerm) -> RichTerm {
-        let RichTerm { term, pos } = rt;
+        let RichTerm {
+            term,
+            pos,
+            id: node_id,
+            ..
+        } = rt;
         let pos = pos.clone();
         match *term {
             Term::Record(map) => {
@@ -77,7 +82,7 @@ pub mod share_normal_form {
                     })
                     .collect();
 
-                with_bindings(Term::Record(map), bindings, pos)
+                with_bindings(Term::Record(map), bindings, pos, node_id)
             }
             Term::RecRecord(map) => {
                 // When a recursive record is evaluated, all fields need to be turned to closures
@@ -101,7 +106,7 @@ pub mod share_normal_form {
                     })
                     .collect();
 
-                with_bindings(Term::RecRecord(map), bindings, pos)
+                with_bindings(Term::RecRecord(map), bindings, pos, node_id)
             }
             Term::List(ts) => {
                 let mut bindings = Vec::with_capacity(ts.len());
@@ -119,60 +124,33 @@ pub mod share_normal_form {
                     })
                     .collect();
 
-                with_bindings(Term::List(ts), bindings, pos)
-            }
-            Term::DefaultValue(t) => {
-                if should_share(&t.term) {
-                    let fresh_var = fresh_var();
-                    let inner = RichTerm {
-                        term: Box::new(Term::DefaultValue(Term::Var(fresh_var.clone()).into())),
-                        pos,
-                    };
-                    Term::Let(fresh_var, t, inner).into()
-                } else {
-                    RichTerm {
-                        term: Box::new(Term::DefaultValue(t)),
-                        pos,
-                    }
-                }
-            }
-            Term::ContractWithDefault(ty, lbl, t) => {
-                if should_share(&t.term) {
-                    let fresh_var = fresh_var();
-                    let inner = RichTerm {
-                        term: Box::new(Term::ContractWithDefault(
-                            ty,
-                            lbl,
-                            Term::Var(fresh_var.clone()).into(),
-                        )),
-                        pos,
-                    };
-                    Term::Let(fresh_var, t, inner).into()
-                } else {
-                    RichTerm {
-                        term: Box::new(Term::ContractWithDefault(ty, lbl, t)),
-                        pos,
-                    }
-                }
+                with_bindings(Term::List(ts), bindings, pos, node_id)
             }
-            Term::Docstring(s, t) => {
-                if should_share(&t.term) {
-                    let fresh_var = fresh_var();
-                    let inner = RichTerm {
-                        term: Box::new(Term::Docstring(s, Term::Var(fresh_var.clone()).into())),
-                        pos,
-                    };
-                    Term::Let(fresh_var, t, inner).into()
-                } else {
-                    RichTerm {
-                        term: Box::new(Term::Docstring(s, t)),
-                        pos,
-                    }
-                }
+            Term::MetaValue(MetaValue {
+                value: Some(t),
+                doc,
+                contracts,
+                priority,
+            }) if should_share(&t.term) => {
+                let fresh_var = fresh_var();
+                let inner = RichTerm {
+                    term: Box::new(Term::MetaValue(MetaValue {
+                        value: Some(Term::Var(fresh_var.clone()).into()),
+                        doc,
+                        contracts,
+                        priority,
+                    })),
+                    pos,
+                    id: node_id,
+                    free_vars: None,
+                };
+                Term::Let(fresh_var, t, inner).into()
             }
             t => RichTerm {
                 term: Box::new(t),
                 pos,
+                id: node_id,
+                free_vars: None,
             },
         }
     }
@@ -205,11 +183,14 @@ pub mod share_normal_form {
         body: Term,
         bindings: Vec<(Ident, RichTerm)>,
         pos: Option<RawSpan>,
+        id: Option<NodeId>,
     ) -> RichTerm {
         let result = bindings.into_iter().fold(
             RichTerm {
                 term: Box::new(body),
                 pos,
+                id,
+                free_vars: None,
             },
             |acc, (id, t)| Term::Let(id, t, acc).into(),
         );
@@ -224,6 +205,86 @@ pub mod share_normal_form {
 /// - The path of the file, to resolve relative imports.
 type PendingImport = (RichTerm, FileId, PathBuf);
 
+/// Desugaring of destructuring patterns.
+///
+/// A let binding with a pattern is rewritten to plain let bindings: the destructured value is
+/// bound to a fresh variable, and each variable of the pattern to a field or an element of it. For
+/// example,
+///
+/// ```
+/// let {host, port = [p, q]} = server in body
+/// ```
+///
+/// becomes
+///
+/// ```
+/// let %0 = server in let host = %0.host in let %1 = %0.port in
+/// let p = elemAt %1 0 in let q = elemAt %1 1 in body
+/// ```
+///
+/// As the bindings are lazy, a missing field or element is only reported when the corresponding
+/// variable is used. The accesses have the position of the destructured expression. Functions
+/// with patterns are already expressed with a let binding by the parser.
+pub mod destructuring {
+    use super::fresh_var;
+    use crate::position::RawSpan;
+    use crate::term::{BinaryOp, Pattern, RichTerm, Term, UnaryOp};
+
+    /// Desugar the top-level node of an AST, if it is a let binding with a pattern. As
+    /// [`share_normal_form::transform_one`](../share_normal_form/fn.transform_one.html), this
+    /// function is not recursive.
+    pub fn transform_one(rt: RichTerm) -> RichTerm {
+        let RichTerm { term, pos, id, .. } = rt;
+        match *term {
+            Term::LetPattern(pat, bound, body) => {
+                let RichTerm { term, .. } = desugar(pat, bound, body);
+                RichTerm {
+                    term,
+                    pos,
+                    id,
+                    free_vars: None,
+                }
+            }
+            t => RichTerm {
+                term: Box::new(t),
+                pos,
+                id,
+                free_vars: None,
+            },
+        }
+    }
+
+    fn desugar(pat: Pattern, bound: RichTerm, body: RichTerm) -> RichTerm {
+        let var = fresh_var();
+        let pos = bound.pos.clone();
+        let value = || RichTerm::var(var.0.clone());
+
+        let body = match pat {
+            Pattern::Var(id) => return Term::Let(id, bound, body).into(),
+            Pattern::Record(fields) => fields.into_iter().rev().fold(body, |body, (field, sub)| {
+                let access = Term::Op1(UnaryOp::StaticAccess(field), value());
+                desugar(sub, with_pos(access, &pos), body)
+            }),
+            Pattern::List(subs) => {
+                subs.into_iter()
+                    .enumerate()
+                    .rev()
+                    .fold(body, |body, (i, sub)| {
+                        let index = Term::Num(i as f64).into();
+                        let elem = Term::Op2(BinaryOp::ListElemAt(), value(), index);
+                        desugar(sub, with_pos(elem, &pos), body)
+                    })
+            }
+        };
+
+        Term::Let(var, bound, body).into()
+    }
+
+    fn with_pos(t: Term, pos: &Option<RawSpan>) -> RichTerm {
+        RichTerm::with_pos(Box::new(t), pos.clone())
+    }
+}
+
 pub mod import_resolution {
     use super::{ImportResolver, PathBuf, PendingImport, RichTerm, Term};
     use crate::error::ImportError;
@@ -243,7 +304,7 @@ pub mod import_resolution {
     where
         R: ImportResolver,
     {
-        let RichTerm { term, pos } = rt;
+        let RichTerm { term, pos, id, .. } = rt;
         match *term {
             Term::Import(path) => {
                 let (res_term, file_id) = resolver.resolve(&path, parent.clone(), &pos)?;
@@ -256,6 +317,8 @@ pub mod import_resolution {
                     RichTerm {
                         term: Box::new(Term::ResolvedImport(file_id)),
                         pos,
+                        id,
+                        free_vars: None,
                     },
                     ret,
                 ))
@@ -264,6 +327,8 @@ pub mod import_resolution {
                 RichTerm {
                     term: Box::new(t),
                     pos,
+                    id,
+                    free_vars: None,
                 },
                 None,
             )),
@@ -271,6 +336,597 @@ pub mod import_resolution {
     }
 }
 
+/// Free variables.
+///
+/// Compute the variables of a term which are not bound inside the term itself, and which must
+/// thus be provided by its environment. When a term is packed as a closure (see
+/// [`Closurizable`](../trait.Closurizable.html)), only these variables are captured instead of
+/// the whole environment: thunks then don't keep alive unrelated, potentially huge, values, and
+/// their environment is cheaper to clone.
+///
+/// The analysis is conservative: a variable may be reported as free while it is not, which only
+/// makes the captured environment larger than necessary, but never the converse.
+///
+/// The free variables of the nodes of a program are computed once by the program transformations
+/// (see [`annotate`](fn.annotate.html)) and stored in the nodes, instead of being computed each
+/// time a term is closurized during evaluation.
+pub mod free_vars {
+    use crate::collections::HashSet;
+    use crate::eval::Environment;
+    use crate::identifier::Ident;
+    use crate::term::{RichTerm, Term};
+    use std::rc::Rc;
+
+    /// Compute the free variables of a term.
+    pub fn free_vars(rt: &RichTerm) -> HashSet<Ident> {
+        let mut free = HashSet::default();
+        collect(rt, &mut Vec::new(), &mut free);
+        free
+    }
+
+    /// Store the free variables of the top-level node of a term, unless it is a variable or a
+    /// constant, for which they are trivial. The free variables already stored in the subterms
+    /// are reused, such that annotating all the nodes of a term during a bottom-up traversal
+    /// doesn't traverse the same subterms again and again.
+    pub fn annotate(mut rt: RichTerm) -> RichTerm {
+        let trivial = matches!(rt.as_ref(), Term::Var(_)) || rt.as_ref().is_constant();
+        if rt.free_vars.is_none() && !trivial {
+            rt.free_vars = Some(Rc::new(free_vars(&rt)));
+        }
+        rt
+    }
+
+    /// Restrict an environment to the free variables of a term. If they are not stored in the
+    /// term, they are computed.
+    pub fn restrict(rt: &RichTerm, env: &Environment) -> Environment {
+        let select = |id: &Ident| env.get(id).map(|binding| (id.clone(), binding.clone()));
+        match (&rt.free_vars, rt.as_ref()) {
+            (Some(free), _) => free.iter().filter_map(select).collect(),
+            (None, Term::Var(id)) => select(id).into_iter().collect(),
+            (None, t) => match shallow_vars(t) {
+                Some(vars) => vars.into_iter().filter_map(select).collect(),
+                None => free_vars(rt).iter().filter_map(select).collect(),
+            },
+        }
+    }
+
+    /// Return the free variables of a node whose children are only variables and constants, as
+    /// the applications and the lists of thunks built at runtime, without a full traversal.
+    /// Return `None` if the node has deeper children or binds variables.
+    fn shallow_vars(t: &Term) -> Option<Vec<&Ident>> {
+        let mut vars = Vec::new();
+        for child in t.children() {
+            match child.term.as_ref() {
+                _ if !child.bound.is_empty() => return None,
+                Term::Var(id) => vars.push(id),
+                t if t.is_constant() => (),
+                _ => return None,
+            }
+        }
+        Some(vars)
+    }
+
+    /// Add the free variables of `rt` to `free`, `bound` being the variables bound by the
+    /// enclosing terms. The contracts inside types are included. The free variables stored in
+    /// `rt`, if any, are used instead of traversing it.
+    fn collect(rt: &RichTerm, bound: &mut Vec<Ident>, free: &mut HashSet<Ident>) {
+        if let Some(stored) = &rt.free_vars {
+            free.extend(stored.iter().filter(|id| !bound.contains(id)).cloned());
+            return;
+        }
+
+        match rt.as_ref() {
+            Term::Var(id) => {
+                if !bound.contains(id) {
+                    free.insert(id.clone());
+                }
+            }
+            t => {
+                for child in t.children() {
+                    let len = bound.len();
+                    bound.extend(child.bound);
+                    collect(child.term, bound, free);
+                    bound.truncate(len);
+                }
+            }
+        }
+    }
+}
+
+/// Inlining of small functions.
+///
+/// Replace a function bound by a `let` and used exactly once by its definition, if it is small
+/// enough. When the function is directly applied, the application is reduced at the same time:
+///
+/// ```
+/// let f = fun x => x + 1 in f 2
+/// ```
+///
+/// becomes `let x = 2 in x + 1`, which saves the allocation of a thunk for `f` and the
+/// application at run time. Such functions are common in generated or merged code.
+///
+/// A function is not inlined if one of its free variables is bound again between its definition
+/// and its use, which would change its meaning, or if it is used inside a type.
+pub mod inline {
+    use super::free_vars::free_vars;
+    use crate::collections::HashSet;
+    use crate::identifier::Ident;
+    use crate::term::{RichTerm, Term};
+
+    /// The default maximum size of an inlined function, in number of nodes of its AST.
+    pub const DEFAULT_THRESHOLD: usize = 16;
+
+    /// Inline the small functions of a whole term.
+    pub fn transform(rt: RichTerm, threshold: usize) -> RichTerm {
+        let result: Result<RichTerm, ()> =
+            rt.traverse(&mut |rt, _| Ok(transform_one(rt, threshold)), &mut ());
+        result.expect("inlining cannot fail")
+    }
+
+    /// Inline the function bound by the top-level node of an AST, if it is a `let` binding a
+    /// small function used once. As
+    /// [`share_normal_form::transform_one`](../share_normal_form/fn.transform_one.html), this
+    /// function is not recursive.
+    pub fn transform_one(rt: RichTerm, threshold: usize) -> RichTerm {
+        let RichTerm {
+            term,
+            pos,
+            id: node_id,
+            ..
+        } = rt;
+        match *term {
+            Term::Let(id, value, mut body)
+                if matches!(value.as_ref(), Term::Fun(_, _)) && size_at_most(&value, threshold) =>
+            {
+                let fv = free_vars(&value);
+                let mut count = Action::Count {
+                    occurrences: 0,
+                    captured: false,
+                };
+                visit(&mut body, &id, &fv, &mut Vec::new(), &mut count);
+
+                match count {
+                    Action::Count {
+                        occurrences: 1,
+                        captured: false,
+                    } => {
+                        let mut replace = Action::Replace(Some(value));
+                        visit(&mut body, &id, &fv, &mut Vec::new(), &mut replace);
+                        body
+                    }
+                    _ => RichTerm {
+                        term: Box::new(Term::Let(id, value, body)),
+                        pos,
+                        id: node_id,
+                        free_vars: None,
+                    },
+                }
+            }
+            t => RichTerm {
+                term: Box::new(t),
+                pos,
+                id: node_id,
+                free_vars: None,
+            },
+        }
+    }
+
+    /// What to do with the occurrences of the inlined variable.
+    enum Action {
+        /// Count the occurrences, and determine if a free variable of the function is bound at
+        /// one of them or if one is inside a type, in which case the function cannot be inlined.
+        Count { occurrences: usize, captured: bool },
+        /// Replace the occurrence by the function.
+        Replace(Option<RichTerm>),
+    }
+
+    /// Apply `action` to the occurrences of `id` in `rt` which are not shadowed, `fv` being the
+    /// free variables of the function and `bound` the variables bound by the enclosing terms.
+    fn visit(
+        rt: &mut RichTerm,
+        id: &Ident,
+        fv: &HashSet<Ident>,
+        bound: &mut Vec<Ident>,
+        action: &mut Action,
+    ) {
+        match (rt.term.as_mut(), &mut *action) {
+            (
+                Term::Var(x),
+                Action::Count {
+                    occurrences,
+                    captured,
+                },
+            ) if x == id => {
+                *occurrences += 1;
+                *captured = *captured || bound.iter().any(|b| fv.contains(b));
+            }
+            (Term::Var(x), Action::Replace(value)) if x == id => {
+                if let Some(value) = value.take() {
+                    *rt = value;
+                }
+            }
+            // Direct application: reduce it.
+            (Term::App(f, arg), Action::Replace(value)) if matches!(f.as_ref(), Term::Var(x) if x == id) => {
+                if let Some(RichTerm {
+                    term,
+                    pos,
+                    id: value_id,
+                    free_vars: None,
+                }) = value.take()
+                {
+                    match *term {
+                        Term::Fun(param, fun_body) => {
+                            let arg = std::mem::replace(arg, Term::Bool(false).into());
+                            *rt = RichTerm {
+                                term: Box::new(Term::Let(param, arg, fun_body)),
+                                pos: rt.pos.take(),
+                                id: rt.id.take(),
+                                free_vars: None,
+                            };
+                        }
+                        t => {
+                            *f = RichTerm {
+                                term: Box::new(t),
+                                pos,
+                                id: value_id,
+                                free_vars: None,
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {
+                // The function can't be inlined inside a type.
+                for child in rt.term.children() {
+                    if child.in_type {
+                        forbid(free_vars(child.term), id, action);
+                    }
+                }
+
+                for child in rt.term.children_mut() {
+                    // If the variable is shadowed, there is nothing to do below.
+                    if child.bound.contains(id) {
+                        continue;
+                    }
+
+                    let len = bound.len();
+                    bound.extend(child.bound);
+                    visit(child.term, id, fv, bound, action);
+                    bound.truncate(len);
+                }
+            }
+        }
+    }
+
+    /// Prevent inlining if `id` occurs in a subterm where it cannot be replaced.
+    fn forbid(free: HashSet<Ident>, id: &Ident, action: &mut Action) {
+        if let Action::Count { captured, .. } = action {
+            *captured = *captured || free.contains(id);
+        }
+    }
+
+    /// Determine if the number of nodes of a term is at most `limit`. Terms containing types are
+    /// considered too large.
+    fn size_at_most(rt: &RichTerm, limit: usize) -> bool {
+        fn size(rt: &RichTerm, remaining: &mut usize) -> bool {
+            if *remaining == 0 {
+                return false;
+            }
+            *remaining -= 1;
+
+            match rt.as_ref() {
+                Term::Promise(..) | Term::Assume(..) => false,
+                Term::MetaValue(meta) if !meta.contracts.is_empty() => false,
+                t => t
+                    .children()
+                    .into_iter()
+                    .all(|child| size(child.term, remaining)),
+            }
+        }
+
+        size(rt, &mut { limit })
+    }
+}
+
+/// Sharing of record accesses.
+///
+/// Inside a record, the same chain of field accesses, such as `config.network.dns`, is often
+/// repeated in several fields. Each occurrence traverses the same records again. This
+/// transformation binds each chain of at least two accesses which occurs several times in a
+/// record to a fresh variable, introduced by a `let` put around the record, and replaces the
+/// occurrences by this variable:
+///
+/// ```
+/// { servers = config.network.dns.servers; port = config.network.dns.port }
+/// ```
+///
+/// becomes
+///
+/// ```
+/// let %0 = config.network.dns in { servers = %0.servers; port = %0.port }
+/// ```
+///
+/// As the variable is bound to a thunk, the chain is evaluated at most once, and only if one of
+/// the fields using it is evaluated. Only the chains starting from a variable which is not bound
+/// inside the record are shared.
+pub mod access_sharing {
+    use super::fresh_var;
+    use crate::collections::{HashMap, HashSet};
+    use crate::identifier::Ident;
+    use crate::term::{RichTerm, Term, UnaryOp};
+
+    /// A chain of field accesses: the variable it starts from and the accessed fields.
+    type Chain = (Ident, Vec<Ident>);
+
+    /// Share the access chains of all the records of a term.
+    pub fn transform(rt: RichTerm) -> RichTerm {
+        let result: Result<RichTerm, ()> = rt.traverse(&mut |rt, _| Ok(transform_one(rt)), &mut ());
+        result.expect("sharing accesses cannot fail")
+    }
+
+    /// Share the access chains of the top-level node of an AST, if it is a record. As
+    /// [`share_normal_form::transform_one`](../share_normal_form/fn.transform_one.html), this
+    /// function is not recursive.
+    ///
+    /// The shared chains are bound in the order of their names, such that the result does not
+    /// depend on the order of the fields of the record.
+    pub fn transform_one(mut rt: RichTerm) -> RichTerm {
+        match rt.as_ref() {
+            Term::Record(_) | Term::RecRecord(_) => (),
+            _ => return rt,
+        };
+
+        let mut occurrences = Vec::new();
+        collect(&rt, &mut Vec::new(), &mut occurrences);
+        let mut shared = select(occurrences);
+        if shared.is_empty() {
+            return rt;
+        }
+        shared.sort_by(|(chain1, _), (chain2, _)| chain1.cmp(chain2));
+
+        let shared: Vec<(Chain, Ident, RichTerm)> = shared
+            .into_iter()
+            .map(|(chain, t)| (chain, fresh_var(), t))
+            .collect();
+        let vars: HashMap<Chain, Ident> = shared
+            .iter()
+            .map(|(chain, id, _)| (chain.clone(), id.clone()))
+            .collect();
+        replace(&mut rt, &mut Vec::new(), &vars);
+
+        shared
+            .into_iter()
+            .rev()
+            .fold(rt, |rt, (_, id, t)| Term::Let(id, t, rt).into())
+    }
+
+    /// Decompose a term as a chain of field accesses, if it is one.
+    fn as_chain(rt: &RichTerm) -> Option<Chain> {
+        match rt.as_ref() {
+            Term::Var(var) => Some((var.clone(), Vec::new())),
+            Term::Op1(UnaryOp::StaticAccess(field), t) => as_chain(t).map(|(var, mut fields)| {
+                fields.push(field.clone());
+                (var, fields)
+            }),
+            _ => None,
+        }
+    }
+
+    /// The prefix of `len` accesses of an occurrence of `chain`.
+    fn prefix(mut rt: &RichTerm, chain: &Chain, len: usize) -> RichTerm {
+        for _ in len..chain.1.len() {
+            rt = match rt.as_ref() {
+                Term::Op1(_, t) => t,
+                _ => unreachable!("expected a field access"),
+            };
+        }
+        rt.clone()
+    }
+
+    /// Collect, in order, the occurrences of the chains of at least two accesses of `rt` which
+    /// start from a variable not in `bound`.
+    fn collect(rt: &RichTerm, bound: &mut Vec<Ident>, occurrences: &mut Vec<(Chain, RichTerm)>) {
+        match as_chain(rt) {
+            Some(chain) if chain.1.len() >= 2 && !bound.contains(&chain.0) => {
+                occurrences.push((chain, rt.clone()))
+            }
+            _ => {
+                for child in rt.term.children() {
+                    if child.in_type {
+                        continue;
+                    }
+
+                    let len = bound.len();
+                    bound.extend(child.bound);
+                    collect(child.term, bound, occurrences);
+                    bound.truncate(len);
+                }
+            }
+        }
+    }
+
+    /// Select the prefixes to share, together with the term of their first occurrence, such that
+    /// the term has a position.
+    ///
+    /// Each occurrence is attributed to its longest prefix shared with at least another
+    /// occurrence. The prefixes are thus considered from the longest to the shortest, and a
+    /// prefix is only counted for the occurrences which are not already attributed to a longer
+    /// one. This way, each selected prefix replaces at least two occurrences, and no binding is
+    /// left unused.
+    fn select(mut occurrences: Vec<(Chain, RichTerm)>) -> Vec<(Chain, RichTerm)> {
+        let max = occurrences
+            .iter()
+            .map(|((_, fields), _)| fields.len())
+            .max()
+            .unwrap_or(0);
+        let mut shared = Vec::new();
+
+        for len in (2..=max).rev() {
+            let prefix_of = |chain: &Chain| (chain.0.clone(), chain.1[..len].to_vec());
+            let mut counts: HashMap<Chain, usize> = HashMap::default();
+            for (chain, _) in occurrences.iter().filter(|(chain, _)| chain.1.len() >= len) {
+                *counts.entry(prefix_of(chain)).or_default() += 1;
+            }
+
+            let mut selected = HashSet::default();
+            occurrences.retain(|(chain, t)| {
+                if chain.1.len() < len || counts[&prefix_of(chain)] < 2 {
+                    return true;
+                }
+
+                let p = prefix_of(chain);
+                if !selected.contains(&p) {
+                    shared.push((p.clone(), prefix(t, chain, len)));
+                    selected.insert(p);
+                }
+                false
+            });
+        }
+
+        shared
+    }
+
+    /// Replace the longest shared prefix of the chains of `rt` which start from a variable not in
+    /// `bound` by the corresponding variable.
+    fn replace(rt: &mut RichTerm, bound: &mut Vec<Ident>, shared: &HashMap<Chain, Ident>) {
+        match as_chain(rt) {
+            Some((var, fields)) if fields.len() >= 2 && !bound.contains(&var) => {
+                let longest = (2..=fields.len()).rev().find_map(|len| {
+                    shared
+                        .get(&(var.clone(), fields[..len].to_vec()))
+                        .map(|id| (len, id))
+                });
+
+                if let Some((len, id)) = longest {
+                    let mut prefix = rt;
+                    for _ in len..fields.len() {
+                        prefix = match prefix.term.as_mut() {
+                            Term::Op1(_, t) => t,
+                            _ => unreachable!("expected a field access"),
+                        };
+                    }
+                    *prefix.term = Term::Var(id.clone());
+                }
+            }
+            _ => {
+                for child in rt.term.children_mut() {
+                    let len = bound.len();
+                    bound.extend(child.bound);
+                    replace(child.term, bound, shared);
+                    bound.truncate(len);
+                }
+            }
+        }
+    }
+}
+
+/// Compilation of record contracts.
+///
+/// The contract of a record type is built from the builtin contracts `record`, `record_extend`
+/// and `empty_tail`: each field is checked by a separate application, which removes the field from
+/// the record and adds it back once the rest of the record is checked. This is costly for records
+/// with many fields, and adds up when validating long lists of records.
+///
+/// When all the fields of a record type have a primitive type, that is `Num`, `Bool`, `Str`,
+/// `List` or `Dyn`, as in `{ {| name : Str, port : Num, |} }`, the contract is replaced by a
+/// single operation, [`RecordContract`](../../term/enum.BinaryOp.html#variant.RecordContract),
+/// which checks the fields of the record in one pass. As with the builtin contracts, the value of
+/// each field is only checked when the field is evaluated, and the errors have the same tags. The
+/// label of a field has the field in its [type path](../../label/ty_path/index.html), such that a
+/// blame points to the type of the field instead of the whole record type.
+///
+/// A record type nested in another type, such as the type of a field or the domain of a function,
+/// is compiled on its own, even if the enclosing type is not.
+pub mod contract_compilation {
+    use crate::identifier::Ident;
+    use crate::term::{BinaryOp, Contract, MetaValue, RichTerm, Term};
+    use crate::types::{AbsType, Types};
+
+    /// Compile the record contracts of the type annotating the top-level node of an AST, if any.
+    /// As [`share_normal_form::transform_one`](../share_normal_form/fn.transform_one.html), this
+    /// function is not recursive.
+    pub fn transform_one(rt: RichTerm) -> RichTerm {
+        let RichTerm { term, pos, id, .. } = rt;
+        let term = match *term {
+            Term::Promise(ty, l, t) => Term::Promise(compile(ty), l, t),
+            Term::Assume(ty, l, t) => Term::Assume(compile(ty), l, t),
+            Term::MetaValue(meta) => Term::MetaValue(MetaValue {
+                contracts: meta
+                    .contracts
+                    .into_iter()
+                    .map(|ctr| Contract {
+                        types: compile(ctr.types),
+                        label: ctr.label,
+                    })
+                    .collect(),
+                ..meta
+            }),
+            t => t,
+        };
+
+        RichTerm {
+            term: Box::new(term),
+            pos,
+            id,
+            free_vars: None,
+        }
+    }
+
+    /// Compile the record contracts of a type, from the innermost to the outermost one. A
+    /// compiled contract is an opaque type wrapping a call to `RecordContract`.
+    pub fn compile(ty: Types) -> Types {
+        let ty = Types(ty.0.map(|ty| Box::new(compile(*ty))));
+
+        match primitive_fields(&ty) {
+            Some((fields, open)) => {
+                let (l, t) = (Ident(String::from("l")), Ident(String::from("t")));
+                let body = Term::Op2(
+                    BinaryOp::RecordContract(fields, open),
+                    Term::Var(l.clone()).into(),
+                    Term::Var(t.clone()).into(),
+                );
+                let contract = Term::Fun(l, Term::Fun(t, body.into()).into());
+                Types(AbsType::Flat(contract.into()))
+            }
+            None => ty,
+        }
+    }
+
+    /// Return the fields of a record type, together with whether the record may have other
+    /// fields, if all of them have a primitive type.
+    fn primitive_fields(ty: &Types) -> Option<(Vec<(Ident, Types)>, bool)> {
+        let mut row = match &ty.0 {
+            AbsType::StaticRecord(row) => row.as_ref(),
+            _ => return None,
+        };
+        let mut fields: Vec<(Ident, Types)> = Vec::new();
+
+        loop {
+            match &row.0 {
+                AbsType::RowExtend(id, Some(field_ty), tail)
+                    if fields.iter().all(|(other, _)| other != id) =>
+                {
+                    match field_ty.0 {
+                        AbsType::Num() | AbsType::Bool() | AbsType::Str() | AbsType::Dyn() => {
+                            fields.push((id.clone(), (**field_ty).clone()))
+                        }
+                        AbsType::List(ref elem) if matches!(elem.0, AbsType::Dyn()) => {
+                            fields.push((id.clone(), (**field_ty).clone()))
+                        }
+                        _ => return None,
+                    }
+                    row = tail;
+                }
+                AbsType::RowEmpty() => return Some((fields, false)),
+                // As with the builtin contracts, the other fields of an open record are left
+                // unchecked.
+                AbsType::Var(_) => return Some((fields, true)),
+                _ => return None,
+            }
+        }
+    }
+}
+
 /// The state passed around during the program transformation. It holds a reference to the import
 /// resolver, to a stack of pending imported term to be transformed and the path of the import
 /// currently being processed, if any.
@@ -280,19 +936,34 @@ struct TransformState<'a, R> {
     parent: Option<PathBuf>,
 }
 
-/// Apply all program transformations, which are currently the share normal form transformation and
-/// import resolution.
+/// Apply all program transformations, which are currently the desugaring of patterns, the share
+/// normal form transformation, import resolution and the compilation of record contracts. The
+/// free variables of the transformed nodes are stored at the same time.
 ///
 /// All resolved imports are stacked during the transformation. Once the term has been traversed,
 /// the elements of this stack are processed (and so on, if these elements also have non resolved
 /// imports).
 pub fn transform<R>(rt: RichTerm, resolver: &mut R) -> Result<RichTerm, ImportError>
+where
+    R: ImportResolver,
+{
+    transform_from(rt, None, resolver)
+}
+
+/// Apply all program transformations to a term coming from the file `parent`, such that the
+/// imports it contains are resolved relatively to this file. See
+/// [`transform`](./fn.transform.html).
+pub fn transform_from<R>(
+    rt: RichTerm,
+    parent: Option<PathBuf>,
+    resolver: &mut R,
+) -> Result<RichTerm, ImportError>
 where
     R: ImportResolver,
 {
     let mut stack = Vec::new();
 
-    let result = transform_pass(rt, resolver, &mut stack, None);
+    let result = transform_pass(rt, resolver, &mut stack, parent);
 
     while let Some((t, file_id, parent)) = stack.pop() {
         let result = transform_pass(t, resolver, &mut stack, Some(parent))?;
@@ -320,8 +991,12 @@ where
     };
 
     // Apply one step of each transformation. If an import is resolved, then stack it.
-    rt.traverse(
+    // Only the compilation of record contracts applies to contracts. The free variables of each
+    // node are stored once its subterms are transformed.
+    rt.traverse_with_contracts(
         &mut |rt: RichTerm, state: &mut TransformState<R>| -> Result<RichTerm, ImportError> {
+            let rt = destructuring::transform_one(rt);
+            let rt = contract_compilation::transform_one(rt);
             let rt = share_normal_form::transform_one(rt);
             let (rt, pending) =
                 import_resolution::transform_one(rt, state.resolver, &state.parent)?;
@@ -330,8 +1005,9 @@ where
                 state.stack.push((t, file_id, p));
             }
 
-            Ok(rt)
+            Ok(free_vars::annotate(rt))
         },
+        &mut |rt, _| Ok(free_vars::annotate(contract_compilation::transform_one(rt))),
         &mut state,
     )
 }
@@ -356,12 +1032,13 @@ impl Closurizable for RichTerm {
     /// Pack a term together with an environment as a closure.
     ///
     /// Generate a fresh variable, bind it to the corresponding closure `(t,with_env)` in `env`,
-    /// and return this variable as a fresh term.
+    /// and return this variable as a fresh term. Only the [free
+    /// variables](free_vars/index.html) of the term are kept in `with_env`.
     fn closurize(self, env: &mut Environment, with_env: Environment) -> RichTerm {
         let var = fresh_var();
         let c = Closure {
+            env: free_vars::restrict(&self, &with_env),
             body: self,
-            env: with_env,
         };
 
         env.insert(var.clone(), (Rc::new(RefCell::new(c)), IdentKind::Record()));
@@ -379,3 +1056,319 @@ impl Closurizable for Types {
         Types(AbsType::Flat(self.contract().closurize(env, with_env)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::free_vars::free_vars;
+    use super::*;
+    use crate::collections::{HashMap, HashSet};
+    use crate::term::{RecordData, UnaryOp};
+
+    fn vars(ids: &[&str]) -> HashSet<Ident> {
+        ids.iter().map(|id| Ident(String::from(*id))).collect()
+    }
+
+    #[test]
+    fn free_variables() {
+        let var = |id: &str| RichTerm::var(String::from(id));
+
+        // fun x => let y = x z in y w
+        let t = RichTerm::fun(
+            String::from("x"),
+            RichTerm::let_in(
+                "y",
+                RichTerm::app(var("x"), var("z")),
+                RichTerm::app(var("y"), var("w")),
+            ),
+        );
+        assert_eq!(free_vars(&t), vars(&["z", "w"]));
+
+        // Let bindings are not recursive.
+        let t = RichTerm::let_in("x", var("x"), var("x"));
+        assert_eq!(free_vars(&t), vars(&["x"]));
+
+        let fields: RecordData = vec![
+            (Ident(String::from("a")), var("b")),
+            (Ident(String::from("b")), var("c")),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            free_vars(&Term::RecRecord(fields.clone()).into()),
+            vars(&["c"])
+        );
+        assert_eq!(free_vars(&Term::Record(fields).into()), vars(&["b", "c"]));
+    }
+
+    #[test]
+    fn inline_functions() {
+        let var = |id: &str| RichTerm::var(String::from(id));
+        let fun = RichTerm::fun(String::from("x"), RichTerm::app(var("g"), var("x")));
+
+        // let f = fun x => g x in f 2  ~>  let x = 2 in g x
+        let t = RichTerm::let_in(
+            "f",
+            fun.clone(),
+            RichTerm::app(var("f"), Term::Num(2.).into()),
+        );
+        assert_eq!(
+            inline::transform(t, inline::DEFAULT_THRESHOLD),
+            RichTerm::let_in("x", Term::Num(2.).into(), RichTerm::app(var("g"), var("x")))
+        );
+
+        // let f = fun x => g x in [f]  ~>  [fun x => g x]
+        let t = RichTerm::let_in("f", fun.clone(), Term::List(vec![var("f")]).into());
+        assert_eq!(
+            inline::transform(t, inline::DEFAULT_THRESHOLD),
+            Term::List(vec![fun.clone()]).into()
+        );
+
+        // The free variable `g` is bound at the use of `f`.
+        let t = RichTerm::let_in(
+            "f",
+            fun.clone(),
+            RichTerm::let_in("g", Term::Num(1.).into(), var("f")),
+        );
+        assert_eq!(inline::transform(t.clone(), inline::DEFAULT_THRESHOLD), t);
+
+        // The function is too large.
+        let t = RichTerm::let_in("f", fun, var("f"));
+        assert_eq!(inline::transform(t.clone(), 2), t);
+    }
+
+    #[test]
+    fn compile_record_contracts() {
+        use contract_compilation::compile;
+
+        let row = |fields: Vec<(&str, Types)>, tail: Types| {
+            fields.into_iter().rev().fold(tail, |tail, (id, ty)| {
+                Types(AbsType::RowExtend(
+                    Ident(String::from(id)),
+                    Some(Box::new(ty)),
+                    Box::new(tail),
+                ))
+            })
+        };
+        let record = |fields, tail| Types(AbsType::StaticRecord(Box::new(row(fields, tail))));
+        let empty = || Types(AbsType::RowEmpty());
+        let num = || Types(AbsType::Num());
+        let is_compiled = |ty: &Types| match &ty.0 {
+            AbsType::Flat(t) => matches!(t.as_ref(), Term::Fun(_, _)),
+            _ => false,
+        };
+
+        assert!(is_compiled(&compile(record(
+            vec![("a", num()), ("b", Types(AbsType::Dyn()))],
+            empty()
+        ))));
+        assert!(is_compiled(&compile(record(
+            vec![("a", num())],
+            Types(AbsType::Var(Ident(String::from("r"))))
+        ))));
+
+        // A field with a function type is checked by the builtin contracts.
+        let arrow = Types(AbsType::arrow(Box::new(num()), Box::new(num())));
+        let ty = record(vec![("f", arrow)], empty());
+        assert_eq!(compile(ty.clone()), ty);
+
+        // The nested record is compiled, but not the enclosing one.
+        let ty = compile(record(
+            vec![("a", record(vec![("b", num())], empty()))],
+            empty(),
+        ));
+        match ty.0 {
+            AbsType::StaticRecord(row) => {
+                assert!(is_compiled(
+                    &row.row_find(&Ident(String::from("a"))).unwrap()
+                ))
+            }
+            _ => panic!("expected a record type, got {}", ty),
+        }
+    }
+
+    #[test]
+    fn share_accesses() {
+        let access = |t: RichTerm, field: &str| -> RichTerm {
+            Term::Op1(UnaryOp::StaticAccess(Ident(String::from(field))), t).into()
+        };
+        let chain = |var: &str, fields: &[&str]| {
+            fields
+                .iter()
+                .fold(RichTerm::var(String::from(var)), |t, field| {
+                    access(t, field)
+                })
+        };
+        let record = |fields: Vec<(&str, RichTerm)>| -> RichTerm {
+            Term::RecRecord(
+                fields
+                    .into_iter()
+                    .map(|(id, t)| (Ident(String::from(id)), t))
+                    .collect(),
+            )
+            .into()
+        };
+
+        // { a = c.x.y.z; b = c.x.y.w; d = c.x }
+        let t = record(vec![
+            ("a", chain("c", &["x", "y", "z"])),
+            ("b", chain("c", &["x", "y", "w"])),
+            ("d", chain("c", &["x"])),
+        ]);
+        match *access_sharing::transform(t).term {
+            Term::Let(id, bound, body) => {
+                assert_eq!(bound, chain("c", &["x", "y"]));
+                let var = || RichTerm::from(Term::Var(id.clone()));
+                assert_eq!(
+                    body,
+                    record(vec![
+                        ("a", access(var(), "z")),
+                        ("b", access(var(), "w")),
+                        ("d", chain("c", &["x"])),
+                    ])
+                );
+            }
+            t => panic!("expected a let binding, got {:?}", t),
+        }
+
+        // Each binding is used at least twice, and the bindings are sorted by chain.
+        let bindings = |t: RichTerm| {
+            let mut bindings = Vec::new();
+            let mut rt = access_sharing::transform(t);
+            while let Term::Let(_, bound, body) = *rt.term {
+                bindings.push(bound);
+                rt = body;
+            }
+            bindings
+        };
+        let t = record(vec![
+            ("a", chain("c", &["x", "y", "z"])),
+            ("b", chain("c", &["x", "y", "z", "w"])),
+        ]);
+        assert_eq!(bindings(t), vec![chain("c", &["x", "y", "z"])]);
+        let t = record(vec![
+            ("a", chain("c", &["x", "y", "z"])),
+            ("b", chain("c", &["x", "y", "z", "w"])),
+            ("d", chain("c", &["x", "y", "v"])),
+            ("e", chain("c", &["x", "y", "u"])),
+        ]);
+        assert_eq!(
+            bindings(t),
+            vec![chain("c", &["x", "y"]), chain("c", &["x", "y", "z"])]
+        );
+
+        // The chains start from a field of the record, or from a variable bound inside it.
+        let t = record(vec![
+            ("a", chain("b", &["x", "y"])),
+            ("b", chain("b", &["x", "y"])),
+        ]);
+        assert_eq!(access_sharing::transform(t.clone()), t);
+        let t = record(vec![(
+            "a",
+            RichTerm::fun(
+                String::from("c"),
+                RichTerm::app(chain("c", &["x", "y"]), chain("c", &["x", "y"])),
+            ),
+        )]);
+        assert_eq!(access_sharing::transform(t.clone()), t);
+    }
+
+    #[test]
+    fn closurize_free_variables() {
+        let thunk = |n: f64| {
+            let clos = Closure::atomic_closure(Term::Num(n).into());
+            (Rc::new(RefCell::new(clos)), IdentKind::Let())
+        };
+        let mut with_env = HashMap::default();
+        with_env.insert(Ident(String::from("a")), thunk(1.));
+        with_env.insert(Ident(String::from("b")), thunk(2.));
+
+        let mut env = HashMap::default();
+        let t = RichTerm::var(String::from("a")).closurize(&mut env, with_env.clone());
+        let id = match *t.term {
+            Term::Var(id) => id,
+            t => panic!("expected a variable, got {:?}", t),
+        };
+
+        let (thunk, _) = env.get(&id).unwrap();
+        let captured: HashSet<Ident> = thunk.borrow().env.keys().cloned().collect();
+        assert_eq!(captured, vars(&["a"]));
+
+        // The nodes built at runtime don't store their free variables.
+        let restricted = |rt: RichTerm| -> HashSet<Ident> {
+            free_vars::restrict(&rt, &with_env)
+                .keys()
+                .cloned()
+                .collect()
+        };
+        let var = |id: &str| RichTerm::var(String::from(id));
+        assert_eq!(
+            restricted(RichTerm::app(var("b"), Term::Num(0.).into())),
+            vars(&["b"])
+        );
+        assert_eq!(
+            restricted(Term::List(vec![var("a"), var("b"), var("c")]).into()),
+            vars(&["a", "b"])
+        );
+        assert_eq!(
+            restricted(RichTerm::fun(
+                String::from("a"),
+                RichTerm::app(var("a"), var("b"))
+            )),
+            vars(&["b"])
+        );
+    }
+
+    #[test]
+    fn store_free_variables() {
+        use crate::program::resolvers::DummyResolver;
+
+        let var = |id: &str| RichTerm::var(String::from(id));
+        let stored = |rt: &RichTerm| rt.free_vars.as_deref().cloned();
+
+        // fun x => let y = x z in y w
+        let t = RichTerm::fun(
+            String::from("x"),
+            RichTerm::let_in(
+                "y",
+                RichTerm::app(var("x"), var("z")),
+                RichTerm::app(var("y"), var("w")),
+            ),
+        );
+        let t = transform(t, &mut DummyResolver {}).unwrap();
+        assert_eq!(stored(&t), Some(vars(&["z", "w"])));
+
+        let (bound, body) = match t.as_ref() {
+            Term::Fun(_, body) => match body.as_ref() {
+                Term::Let(_, bound, body) => (bound, body),
+                t => panic!("expected a let binding, got {:?}", t),
+            },
+            t => panic!("expected a function, got {:?}", t),
+        };
+        assert_eq!(stored(bound), Some(vars(&["x", "z"])));
+        assert_eq!(stored(body), Some(vars(&["y", "w"])));
+
+        // The stored free variables are the ones captured by a closure, even if they are a
+        // conservative approximation.
+        let mut t = RichTerm::app(var("a"), var("b"));
+        t.free_vars = Some(Rc::new(vars(&["a"])));
+
+        let thunk = |n: f64| {
+            let clos = Closure::atomic_closure(Term::Num(n).into());
+            (Rc::new(RefCell::new(clos)), IdentKind::Let())
+        };
+        let mut with_env = HashMap::default();
+        with_env.insert(Ident(String::from("a")), thunk(1.));
+        with_env.insert(Ident(String::from("b")), thunk(2.));
+
+        let mut env = HashMap::default();
+        let t = t.closurize(&mut env, with_env);
+        let id = match *t.term {
+            Term::Var(id) => id,
+            t => panic!("expected a variable, got {:?}", t),
+        };
+
+        let (thunk, _) = env.get(&id).unwrap();
+        let captured: HashSet<Ident> = thunk.borrow().env.keys().cloned().collect();
+        assert_eq!(captured, vars(&["a"]));
+    }
+}
@@ -14,40 +14,82 @@
 //! and `Assume` thus serve both two purposes: annotate a term with a type, and set the
 //! typechecking mode.
 //!
+//! In both cases, the surrounding code sees the annotated term as a variable of the annotated
+//! type, whose quantified variables are instantiated at each use. `Assume` thus gives a static
+//! type to untyped code, which is enforced at run time by a contract:
+//!
+//! ```
+//! let lib = {id = fun x => x} in
+//! Promise(Num, let id = Assume(forall a. a -> a, lib.id) in seq (id "a") (id 5))
+//! ```
+//!
 //! # Type inference
 //!
 //! Type inference is done via a standard unification algorithm. The type of unannotated let-bound
 //! expressions (the type of `bound_exp` in `let x = bound_exp in body`) is inferred in strict
-//! mode, but it is never implicitly generalized. For example, the following program is rejected:
+//! mode, and then generalized, as in Hindley-Milner type systems. For example, the following
+//! program is accepted:
 //!
 //! ```
-//! // Rejected
 //! Promise(Num, let id = fun x => x in seq (id "a") (id 5))
 //! ```
 //!
-//! Indeed, `id` is given the type `_a -> _a`, where `_a` is a unification variable, but is not
-//! generalized to `forall a. a -> a`. At the first call site, `_a` is unified with `Str`, and at the second
-//! call site the typechecker complains that `5` is not of type `Str`.
+//! Indeed, `id` is first given the type `_a -> _a`, where `_a` is a unification variable, which
+//! is then generalized to `forall a. a -> a`. Each use of `id` instantiates this type with fresh
+//! variables, which are unified with `Str` at the first call site and with `Num` at the second.
 //!
-//! This restriction is on purpose, as generalization is not trivial to implement efficiently and
-//! can interact with other parts of type inference. If polymorphism is required, a simple
-//! annotation is sufficient:
+//! Only the variables which are not reachable from the enclosing scope are generalized. This is
+//! tracked by levels (see [`UnifTable`](struct.UnifTable.html)). In the following program, the
+//! type of `g` is `forall b. b -> _a`, where `_a` is the type of the argument `y`, and is not
+//! generalized. The program is thus rejected, as `y` would have to be both a `Num` and a `Str`:
 //!
 //! ```
-//! // Accepted
-//! Promise(Num, let id = Promise(forall a. a -> a, fun x => x) in seq (id "a") (id 5))
+//! // Rejected
+//! Promise(Num, let f = fun y => let g = fun x => y in g 0 + (g 1 ++ "a") in 1)
 //! ```
 //!
+//! Function parameters are not generalized either: a polymorphic argument requires an annotation
+//! (see [Higher-rank types](#higher-rank-types)).
+//!
 //! In non-strict mode, all let-bound expressions are given type `Dyn`, unless annotated.
+//!
+//! # Higher-rank types
+//!
+//! A term checked against a polymorphic type `forall a. T` is checked against `T`, where `a` is
+//! replaced by a fresh type constant. This rule applies anywhere, and not only at the top of a
+//! `Promise`: an argument is thus checked against a polymorphic domain, as in
+//!
+//! ```
+//! Promise(((forall a. a -> a) -> Num) -> Num, fun f => f (fun x => x))
+//! ```
+//!
+//! The quantifiers in the codomain of an arrow are also replaced, which is known as deep
+//! skolemization. The type `Num -> forall a. a -> a` being equivalent to `forall a. Num -> a ->
+//! a`, a term of the latter type is accepted for the former one:
+//!
+//! ```
+//! let k = Promise(forall a. Num -> a -> a, fun n x => x) in
+//! Promise(Num -> (forall a. a -> a), k)
+//! ```
+//!
+//! # Cancellation
+//!
+//! Typechecking a large program takes time, and its result may become useless before the end, as
+//! when a file is edited again in an editor. A [`CancellationToken`](struct.CancellationToken.html)
+//! given to the typechecker is checked at each step of the traversal and of the unification:
+//! once cancelled, typechecking stops early with a
+//! [`Cancelled`](../error/enum.TypecheckError.html#variant.Cancelled) error.
+use crate::collections::{HashMap, HashSet};
 use crate::error::TypecheckError;
 use crate::eval;
 use crate::identifier::Ident;
 use crate::label::ty_path;
 use crate::position::RawSpan;
 use crate::program::ImportResolver;
-use crate::term::{BinaryOp, RichTerm, StrChunk, Term, UnaryOp};
+use crate::term::{BinaryOp, MetaValue, NodeId, Pattern, RichTerm, StrChunk, Term, UnaryOp};
 use crate::types::{AbsType, Types};
-use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 /// Error during the unification of two row types.
 #[derive(Debug, PartialEq)]
@@ -72,6 +114,11 @@ pub enum RowUnifError {
     WithConst(usize, TypeWrapper),
     /// Tried to unify two distinct type constants.
     ConstMismatch(usize, usize),
+    /// Tried to unify a unification variable with a type containing a type constant introduced in
+    /// a deeper scope.
+    ConstEscape(usize, TypeWrapper),
+    /// An unexpected internal error.
+    InternalError(String),
 }
 
 impl RowUnifError {
@@ -98,6 +145,31 @@ impl RowUnifError {
             RowUnifError::UnsatConstr(id, tyw) => UnifError::RowConflict(id, tyw, left, right),
             RowUnifError::WithConst(c, tyw) => UnifError::WithConst(c, tyw),
             RowUnifError::ConstMismatch(c1, c2) => UnifError::ConstMismatch(c1, c2),
+            RowUnifError::ConstEscape(c, tyw) => UnifError::ConstEscape(c, tyw),
+            RowUnifError::InternalError(msg) => UnifError::InternalError(msg),
+        }
+    }
+}
+
+impl From<UnifError> for RowUnifError {
+    /// Convert a unification error occurring inside the unification of rows, typically when
+    /// unifying row tails, to a row unification error.
+    ///
+    /// Since we are then unifying types with a constant or a unification variable somewhere, only
+    /// errors related to constants, row constraints or ill-formed rows should be possible. Any other
+    /// error is turned into an internal error.
+    fn from(err: UnifError) -> Self {
+        match err {
+            UnifError::ConstMismatch(c1, c2) => RowUnifError::ConstMismatch(c1, c2),
+            UnifError::WithConst(c, tyw) => RowUnifError::WithConst(c, tyw),
+            UnifError::ConstEscape(c, tyw) => RowUnifError::ConstEscape(c, tyw),
+            UnifError::RowConflict(id, tyw, _, _) => RowUnifError::UnsatConstr(id, tyw),
+            UnifError::IllformedRow(tyw) => RowUnifError::IllformedRow(tyw),
+            UnifError::InternalError(msg) => RowUnifError::InternalError(msg),
+            err => RowUnifError::InternalError(format!(
+                "typechecker::unify_rows(): unexpected error while unifying row tails {:?}",
+                err
+            )),
         }
     }
 }
@@ -124,8 +196,12 @@ pub enum UnifError {
     RowConflict(Ident, Option<TypeWrapper>, TypeWrapper, TypeWrapper),
     /// Tried to unify a type constant with another different type.
     WithConst(usize, TypeWrapper),
+    /// Tried to unify a unification variable with a type containing a type constant introduced in
+    /// a deeper scope. See [`UnifTable`](./struct.UnifTable.html).
+    ConstEscape(usize, TypeWrapper),
     /// A flat type, which is an opaque type corresponding to custom contracts, contained a Nickel
-    /// term different from a variable. Only a variables is a legal inner term of a flat type.
+    /// term which is neither a variable nor a variable applied to literals, as in `#(Between 1
+    /// 10)`. Only such terms are legal inner terms of a flat type.
     IllformedFlatType(RichTerm),
     /// A generic type was ill-formed. Currently, this happens if a `StatRecord` or `Enum` type
     /// does not contain a row type.
@@ -136,6 +212,10 @@ pub enum UnifError {
     DomainMismatch(TypeWrapper, TypeWrapper, Box<UnifError>),
     /// An error occurred when unifying the codomains of two arrows.
     CodomainMismatch(TypeWrapper, TypeWrapper, Box<UnifError>),
+    /// The typechecking was cancelled (see [`CancellationToken`](./struct.CancellationToken.html)).
+    Cancelled(),
+    /// An unexpected internal error.
+    InternalError(String),
 }
 
 impl UnifError {
@@ -171,6 +251,13 @@ impl UnifError {
         pos_opt: &Option<RawSpan>,
     ) -> TypecheckError {
         let pos_opt = pos_opt.as_ref().cloned();
+
+        // A cancellation may surface as another error, such as a mismatch of the domains of two
+        // arrows caused by the cancellation of their unification.
+        if state.is_cancelled() {
+            return TypecheckError::Cancelled(pos_opt);
+        }
+
         match self {
             UnifError::TypeMismatch(ty1, ty2) => TypecheckError::TypeMismatch(
                 reporting::to_type(state, names, ty1),
@@ -203,6 +290,11 @@ impl UnifError {
                 reporting::to_type(state, names, ty),
                 pos_opt,
             ),
+            UnifError::ConstEscape(c, ty) => TypecheckError::ConstantEscape(
+                reporting::to_type(state, names, TypeWrapper::Constant(c)),
+                reporting::to_type(state, names, ty),
+                pos_opt,
+            ),
             UnifError::IllformedFlatType(rt) => {
                 TypecheckError::IllformedType(Types(AbsType::Flat(rt)))
             }
@@ -235,16 +327,23 @@ impl UnifError {
                 TypecheckError::UnboundTypeVariable(ident, pos_opt)
             }
             err @ UnifError::CodomainMismatch(_, _, _)
-            | err @ UnifError::DomainMismatch(_, _, _) => {
-                let (expd, actual, path, err_final) = err.to_type_path().unwrap();
-                TypecheckError::ArrowTypeMismatch(
+            | err @ UnifError::DomainMismatch(_, _, _) => match err.to_type_path() {
+                Some((expd, actual, path, err_final)) => TypecheckError::ArrowTypeMismatch(
                     reporting::to_type(state, names, expd),
                     reporting::to_type(state, names, actual),
                     path,
                     Box::new(err_final.to_typecheck_err_(state, names, &None)),
                     pos_opt,
-                )
-            }
+                ),
+                None => TypecheckError::InternalError(
+                    String::from(
+                        "typechecking::to_type_path(): (co)domain mismatch error on a non arrow type",
+                    ),
+                    pos_opt,
+                ),
+            },
+            UnifError::Cancelled() => TypecheckError::Cancelled(pos_opt),
+            UnifError::InternalError(msg) => TypecheckError::InternalError(msg, pos_opt),
         }
     }
 
@@ -260,7 +359,8 @@ impl UnifError {
     ///
     /// # Return
     ///
-    /// Return `None` if `self` is not a `DomainMismatch` nor a `CodomainMismatch`.
+    /// Return `None` if `self` is not a `DomainMismatch` nor a `CodomainMismatch`, or if it is an
+    /// ill-formed one whose types are not arrows.
     ///
     /// Otherwise, return the following tuple:
     ///  - the original expected type.
@@ -285,9 +385,7 @@ impl UnifError {
                     path.push(ty_path::Elem::Domain);
                     curr = *err;
                 }
-                UnifError::DomainMismatch(_, _, _) => panic!(
-                    "typechecking::to_type_path(): domain mismatch error on a non arrow type"
-                ),
+                UnifError::DomainMismatch(_, _, _) => break None,
                 UnifError::CodomainMismatch(
                     tyw1 @ TypeWrapper::Concrete(AbsType::Arrow(_, _)),
                     tyw2 @ TypeWrapper::Concrete(AbsType::Arrow(_, _)),
@@ -297,9 +395,7 @@ impl UnifError {
                     path.push(ty_path::Elem::Codomain);
                     curr = *err;
                 }
-                UnifError::CodomainMismatch(_, _, _) => panic!(
-                    "typechecking::to_type_path(): codomain mismatch error on a non arrow type"
-                ),
+                UnifError::CodomainMismatch(_, _, _) => break None,
                 // tyws equals to `None` iff we did not even enter the case above once, i.e. if
                 // `self` was indeed neither a `DomainMismatch` nor a `CodomainMismatch`
                 _ => break tyws.map(|(expd, actual)| (expd, actual, path, curr)),
@@ -327,7 +423,7 @@ impl<'a> Envs<'a> {
     pub fn from_global(global: &'a Environment) -> Self {
         Envs {
             global,
-            local: Environment::new(),
+            local: Environment::default(),
         }
     }
 
@@ -344,6 +440,23 @@ impl<'a> Envs<'a> {
             .collect()
     }
 
+    /// Same as [`mk_global`](#method.mk_global), but use the given types for the bindings which
+    /// have one, such as the items of the standard library, instead of their apparent type.
+    pub fn mk_global_with_types(
+        eval_env: &eval::Environment,
+        types: &HashMap<Ident, Types>,
+        table: &mut UnifTable,
+    ) -> Environment {
+        let mut global = Envs::mk_global(eval_env, table);
+        global.extend(
+            types
+                .iter()
+                .filter(|(id, _)| eval_env.contains_key(id))
+                .map(|(id, ty)| (id.clone(), to_typewrapper(ty.clone()))),
+        );
+        global
+    }
+
     /// Fetch a binding from the environment. Try first in the local environment, and then in the
     /// global.
     pub fn get(&self, ident: &Ident) -> Option<TypeWrapper> {
@@ -372,12 +485,68 @@ pub struct State<'a> {
     ///
     /// Used for error reporting.
     names: &'a mut HashMap<usize, Ident>,
+    /// If set, the type of each subterm with a position or an identity is recorded there, to
+    /// build a [type table](./type.TypeTable.html) or a [node type
+    /// table](./type.NodeTypeTable.html).
+    recorded: Option<&'a mut Vec<Recorded>>,
+    /// If set, typechecking stops as soon as this token is cancelled.
+    cancel: Option<&'a CancellationToken>,
+    /// If set, the `Promise` and `Assume` blocks are recorded there, and an error inside a block
+    /// is recorded with it instead of stopping the typechecking (see
+    /// [`check_annotations`](fn.check_annotations.html)).
+    annotations: Option<&'a mut Vec<RecordedAnnotation>>,
+}
+
+impl<'a> State<'a> {
+    fn is_cancelled(&self) -> bool {
+        matches!(self.cancel, Some(token) if token.is_cancelled())
+    }
+}
+
+/// A flag to interrupt a typechecking in progress, possibly from another thread.
+///
+/// Clones of a token share the same flag: cancelling one of them cancels all typecheckings using
+/// any of the clones.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Cancel the typecheckings using this token. A cancelled token stays cancelled.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
 }
 
+/// The type of a subterm recorded during typechecking, with its position and its identity.
+type Recorded = (Option<RawSpan>, Option<NodeId>, TypeWrapper);
+
+/// The types of the subterms of a program, indexed by their position.
+///
+/// When several subterms share the same position, as for terms generated by the parser, the type
+/// of the outermost one is kept.
+pub type TypeTable = HashMap<RawSpan, Types>;
+
+/// The types of the subterms of a program, indexed by their [identity](../term/struct.NodeId.html).
+///
+/// Contrary to a [`TypeTable`](type.TypeTable.html), each node has its own entry, even when it
+/// shares its position with other nodes.
+pub type NodeTypeTable = HashMap<NodeId, Types>;
+
 /// Typecheck a term.
 ///
 /// Return the inferred type in case of success. This is just a wrapper that calls
 /// [`type_check_`](fn.type_check_.html) with a fresh unification variable as goal.
+///
+/// The free unification variables and the type constants of the result are given the same names
+/// as in error messages (see [`reporting::to_type`](./reporting/fn.to_type.html)).
 pub fn type_check(
     t: &RichTerm,
     global_eval_env: &eval::Environment,
@@ -386,14 +555,21 @@ pub fn type_check(
     let mut state = State {
         resolver,
         table: &mut UnifTable::new(),
-        constr: &mut RowConstr::new(),
-        names: &mut HashMap::new(),
+        constr: &mut RowConstr::default(),
+        names: &mut HashMap::default(),
+        recorded: None,
+        cancel: None,
+        annotations: None,
     };
     let ty = TypeWrapper::Ptr(new_var(state.table));
     let global = Envs::mk_global(global_eval_env, state.table);
     type_check_(&mut state, Envs::from_global(&global), false, t, ty.clone())?;
 
-    Ok(to_type(&state.table, ty))
+    Ok(reporting::to_type(
+        &state,
+        &mut reporting::NameReg::new(),
+        ty,
+    ))
 }
 
 /// Typecheck a term using the given global typing environment. Same as
@@ -404,23 +580,218 @@ pub fn type_check(
 /// to the original term environment anymore, and hence cannot call `type_check` directly, but we
 /// already have built a global typing environment.
 ///
-/// Return the inferred type in case of success. This is just a wrapper that calls
-/// [`type_check_`](fn.type_check_.html) with a fresh unification variable as goal.
+/// Return the inferred type in case of success, as [`type_check`](./fn.type_check.html). If
+/// `cancel` is set, typechecking stops as soon as it is cancelled.
 pub fn type_check_in_env(
     t: &RichTerm,
     global: &Environment,
     resolver: &mut dyn ImportResolver,
+    cancel: Option<&CancellationToken>,
 ) -> Result<Types, TypecheckError> {
     let mut state = State {
         resolver,
         table: &mut UnifTable::new(),
-        constr: &mut RowConstr::new(),
-        names: &mut HashMap::new(),
+        constr: &mut RowConstr::default(),
+        names: &mut HashMap::default(),
+        recorded: None,
+        cancel,
+        annotations: None,
+    };
+    let ty = TypeWrapper::Ptr(new_var(state.table));
+    type_check_(&mut state, Envs::from_global(global), false, t, ty.clone())?;
+
+    Ok(reporting::to_type(
+        &state,
+        &mut reporting::NameReg::new(),
+        ty,
+    ))
+}
+
+/// Typecheck a term using the given global typing environment, as
+/// [`type_check_in_env`](./fn.type_check_in_env.html), and return the type of each of its subterms
+/// with a position together with the type of the whole term.
+///
+/// The free unification variables and the type constants have consistent names across all the
+/// returned types. Outside of `Promise` blocks, subterms are not typechecked and their type is
+/// most of the time a free variable.
+pub fn type_check_with_table(
+    t: &RichTerm,
+    global: &Environment,
+    resolver: &mut dyn ImportResolver,
+    cancel: Option<&CancellationToken>,
+) -> Result<(Types, TypeTable), TypecheckError> {
+    type_check_recorded(t, global, resolver, cancel, |span, _| span.clone())
+}
+
+/// Same as [`type_check_with_table`](fn.type_check_with_table.html), but index the types by the
+/// identity of the subterms instead of their position.
+pub fn type_check_with_node_table(
+    t: &RichTerm,
+    global: &Environment,
+    resolver: &mut dyn ImportResolver,
+    cancel: Option<&CancellationToken>,
+) -> Result<(Types, NodeTypeTable), TypecheckError> {
+    type_check_recorded(t, global, resolver, cancel, |_, id| *id)
+}
+
+/// Typecheck a term and index the types of its subterms by the key computed by `key` from their
+/// position and their identity. Subterms without a key are not recorded, and when several
+/// subterms have the same key, the type of the first visited one is kept.
+fn type_check_recorded<K, F>(
+    t: &RichTerm,
+    global: &Environment,
+    resolver: &mut dyn ImportResolver,
+    cancel: Option<&CancellationToken>,
+    key: F,
+) -> Result<(Types, HashMap<K, Types>), TypecheckError>
+where
+    K: Eq + std::hash::Hash,
+    F: Fn(&Option<RawSpan>, &Option<NodeId>) -> Option<K>,
+{
+    let mut recorded = Vec::new();
+    let mut state = State {
+        resolver,
+        table: &mut UnifTable::new(),
+        constr: &mut RowConstr::default(),
+        names: &mut HashMap::default(),
+        recorded: Some(&mut recorded),
+        cancel,
+        annotations: None,
     };
     let ty = TypeWrapper::Ptr(new_var(state.table));
     type_check_(&mut state, Envs::from_global(global), false, t, ty.clone())?;
 
-    Ok(to_type(&state.table, ty))
+    let recorded = state
+        .recorded
+        .take()
+        .map(std::mem::take)
+        .unwrap_or_default();
+    let mut names = reporting::NameReg::new();
+    let ty = reporting::to_type(&state, &mut names, ty);
+    let mut table = HashMap::default();
+
+    for (span, id, tyw) in recorded {
+        if let Some(k) = key(&span, &id) {
+            table
+                .entry(k)
+                .or_insert_with(|| reporting::to_type(&state, &mut names, tyw));
+        }
+    }
+
+    Ok((ty, table))
+}
+
+/// The kind of a type annotation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnotationKind {
+    Promise,
+    Assume,
+}
+
+/// The result of the typechecking of a `Promise` or an `Assume` block (see
+/// [`check_annotations`](fn.check_annotations.html)).
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckedAnnotation {
+    pub kind: AnnotationKind,
+    /// The position of the whole block.
+    pub span: Option<RawSpan>,
+    /// The type written in the annotation.
+    pub declared: Types,
+    /// The type of the block deduced from the way it is used by the surrounding code. It is a
+    /// free type variable if nothing is known, as outside of a `Promise` block.
+    pub inferred: Types,
+    /// The first error raised by the typechecking of the block, if any. This is either an error
+    /// inside the block or a mismatch between the inferred and the declared types.
+    pub error: Option<TypecheckError>,
+}
+
+impl CheckedAnnotation {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// A block recorded during typechecking, whose inferred type is not converted yet.
+struct RecordedAnnotation {
+    kind: AnnotationKind,
+    span: Option<RawSpan>,
+    declared: Types,
+    inferred: TypeWrapper,
+    error: Option<TypecheckError>,
+}
+
+/// Typecheck a term using the given global typing environment, and return all its `Promise` and
+/// `Assume` blocks, nested ones included, in the order of the source.
+///
+/// Contrary to [`type_check_in_env`](fn.type_check_in_env.html), an error inside a block does not
+/// stop the typechecking: it is recorded with the block, and the surrounding code is checked as
+/// if the block had its declared type. Only the errors outside of any block are returned as an
+/// `Err`, as well as the cancellation of the typechecking.
+///
+/// Unification variables bound before an error are not reset, so that an error in a block may
+/// cause other errors in blocks depending on it.
+pub fn check_annotations(
+    t: &RichTerm,
+    global: &Environment,
+    resolver: &mut dyn ImportResolver,
+    cancel: Option<&CancellationToken>,
+) -> Result<Vec<CheckedAnnotation>, TypecheckError> {
+    let mut recorded = Vec::new();
+    let mut state = State {
+        resolver,
+        table: &mut UnifTable::new(),
+        constr: &mut RowConstr::default(),
+        names: &mut HashMap::default(),
+        recorded: None,
+        cancel,
+        annotations: Some(&mut recorded),
+    };
+    let ty = TypeWrapper::Ptr(new_var(state.table));
+    type_check_(&mut state, Envs::from_global(global), false, t, ty)?;
+
+    let recorded = state
+        .annotations
+        .take()
+        .map(std::mem::take)
+        .unwrap_or_default();
+    let mut names = reporting::NameReg::new();
+
+    Ok(recorded
+        .into_iter()
+        .map(|annot| CheckedAnnotation {
+            kind: annot.kind,
+            span: annot.span,
+            declared: annot.declared,
+            inferred: reporting::to_type(&state, &mut names, annot.inferred),
+            error: annot.error,
+        })
+        .collect())
+}
+
+/// Infer the type of a term in strict mode, as if it was enclosed in a `Promise` with an unknown
+/// type, using the given global typing environment. The remaining unification variables of the
+/// result are generalized.
+///
+/// This is used to give a precise type to the items of the standard library, which are not all
+/// annotated, instead of the `Dyn` type given by [`apparent_type`](fn.apparent_type.html).
+pub fn infer_type_in_env(
+    t: &RichTerm,
+    global: &Environment,
+    resolver: &mut dyn ImportResolver,
+) -> Result<Types, TypecheckError> {
+    let mut state = State {
+        resolver,
+        table: &mut UnifTable::new(),
+        constr: &mut RowConstr::default(),
+        names: &mut HashMap::default(),
+        recorded: None,
+        cancel: None,
+        annotations: None,
+    };
+    let ty = TypeWrapper::Ptr(new_var(state.table));
+    type_check_(&mut state, Envs::from_global(global), true, t, ty.clone())?;
+
+    Ok(generalize(state.table, ty))
 }
 
 /// Typecheck a term against a specific type.
@@ -439,7 +810,29 @@ fn type_check_(
     rt: &RichTerm,
     ty: TypeWrapper,
 ) -> Result<(), TypecheckError> {
-    let RichTerm { term: t, pos } = rt;
+    let RichTerm {
+        term: t, pos, id, ..
+    } = rt;
+
+    if state.is_cancelled() {
+        return Err(TypecheckError::Cancelled(pos.clone()));
+    }
+
+    if has_skolemizable_forall(state.table, &ty) {
+        // The type constants only live in the scope of the checked term.
+        state.table.enter_level();
+        let skolemized =
+            skolemize(state, ty).map_err(|err| err.to_typecheck_err(state, &rt.pos))?;
+        type_check_(state, envs, strict, rt, skolemized)?;
+        state.table.leave_level();
+        return Ok(());
+    }
+
+    if let Some(recorded) = state.recorded.as_mut() {
+        if pos.is_some() || id.is_some() {
+            recorded.push((pos.clone(), *id, ty.clone()));
+        }
+    }
 
     match t.as_ref() {
         Term::Bool(_) => unify(state, strict, ty, TypeWrapper::Concrete(AbsType::Bool()))
@@ -468,49 +861,84 @@ fn type_check_(
                 })
         }
         Term::Fun(x, t) => {
-            let src = TypeWrapper::Ptr(new_var(state.table));
-            // TODO what to do here, this makes more sense to me, but it means let x = foo in bar
-            // behaves quite different to (\x.bar) foo, worth considering if it's ok to type these two differently
-            // let src = TypeWrapper::The(AbsType::Dyn());
-            let trg = TypeWrapper::Ptr(new_var(state.table));
-            let arr =
-                TypeWrapper::Concrete(AbsType::arrow(Box::new(src.clone()), Box::new(trg.clone())));
+            // When the expected type is already an arrow, as for the inner functions of `fun x y
+            // => body` checked against an annotation, its domain and codomain are used directly
+            // instead of being unified with fresh variables.
+            let known_arrow = match resolve(state.table, &ty) {
+                TypeWrapper::Concrete(AbsType::Arrow(src, trg)) if strict => {
+                    Some(((**src).clone(), (**trg).clone()))
+                }
+                _ => None,
+            };
 
-            unify(state, strict, ty, arr).map_err(|err| err.to_typecheck_err(state, &rt.pos))?;
+            let (src, trg) = match known_arrow {
+                Some(arrow) => arrow,
+                None => {
+                    let src = TypeWrapper::Ptr(new_var(state.table));
+                    // TODO what to do here, this makes more sense to me, but it means let x = foo in bar
+                    // behaves quite different to (\x.bar) foo, worth considering if it's ok to type these two differently
+                    // let src = TypeWrapper::The(AbsType::Dyn());
+                    let trg = TypeWrapper::Ptr(new_var(state.table));
+                    let arr = TypeWrapper::Concrete(AbsType::arrow(
+                        Box::new(src.clone()),
+                        Box::new(trg.clone()),
+                    ));
+
+                    unify(state, strict, ty, arr)
+                        .map_err(|err| err.to_typecheck_err(state, &rt.pos))?;
+                    (src, trg)
+                }
+            };
 
             envs.insert(x.clone(), src);
             type_check_(state, envs, strict, t, trg)
         }
         Term::List(terms) => {
-            unify(state, strict, ty, TypeWrapper::Concrete(AbsType::List()))
+            // All the elements must have the same type in strict mode, as in `List Num`.
+            let elt = if strict {
+                TypeWrapper::Ptr(new_var(state.table))
+            } else {
+                TypeWrapper::Concrete(AbsType::Dyn())
+            };
+            unify(state, strict, ty, TypeWrapper::list_of(elt.clone()))
                 .map_err(|err| err.to_typecheck_err(state, &rt.pos))?;
 
+            // The elements of a list of type `List`, that is `List Dyn`, may have any type. As
+            // checking them against `Dyn` would always fail in strict mode, they are checked in
+            // non strict mode instead.
+            let strict_elts = strict
+                && !matches!(
+                    resolve(state.table, &elt),
+                    TypeWrapper::Concrete(AbsType::Dyn())
+                );
+
             terms
                 .iter()
-                .try_for_each(|t| -> Result<(), TypecheckError> {
-                    // Since lists elements are checked against the type `Dyn`, it does not make sense
-                    // to typecheck them even in strict mode, as this will always fails, unless they
-                    // are annotated with an `Assume(Dyn, ..)`, which will always succeed.
-                    type_check_(
-                        state,
-                        envs.clone(),
-                        false,
-                        t,
-                        TypeWrapper::Concrete(AbsType::Dyn()),
-                    )
-                })
+                .try_for_each(|t| type_check_(state, envs.clone(), strict_elts, t, elt.clone()))
         }
         Term::Lbl(_) => {
             // TODO implement lbl type
             unify(state, strict, ty, TypeWrapper::Concrete(AbsType::Dyn()))
                 .map_err(|err| err.to_typecheck_err(state, &rt.pos))
         }
-        Term::Let(x, re, rt) => {
-            let ty_let = apparent_type(re.as_ref(), state.table, strict);
-            type_check_(state, envs.clone(), strict, re, ty_let.clone())?;
+        Term::Let(x, re, rt) | Term::LetRec(x, re, rt) => {
+            let binding = if let Term::Let(..) = t.as_ref() {
+                Binding::Let
+            } else {
+                Binding::LetRec
+            };
 
-            // TODO move this up once lets are rec
-            envs.insert(x.clone(), ty_let);
+            let bindings = [(Pattern::Var(x.clone()), re)];
+            for (id, ty_let) in check_bindings(state, &envs, strict, &bindings, &binding)? {
+                envs.insert(id, ty_let);
+            }
+            type_check_(state, envs, strict, rt, ty)
+        }
+        Term::LetPattern(pat, re, rt) => {
+            let bindings = [(pat.clone(), re)];
+            for (id, ty_let) in check_bindings(state, &envs, strict, &bindings, &Binding::Let)? {
+                envs.insert(id, ty_let);
+            }
             type_check_(state, envs, strict, rt, ty)
         }
         Term::App(e, t) => {
@@ -528,8 +956,9 @@ fn type_check_(
                 .get(&x)
                 .ok_or_else(|| TypecheckError::UnboundIdentifier(x.clone(), pos.clone()))?;
 
-            let instantiated = instantiate_foralls_with(state, x_ty.clone(), TypeWrapper::Ptr);
-            unify(state, strict, ty, instantiated)
+            let instantiated = instantiate_foralls_with(state, x_ty.clone(), TypeWrapper::Ptr)
+                .map_err(|err| err.to_typecheck_err(state, &rt.pos))?;
+            subsume(state, strict, ty, instantiated)
                 .map_err(|err| err.to_typecheck_err(state, &rt.pos))
         }
         Term::Enum(id) => {
@@ -545,51 +974,38 @@ fn type_check_(
             .map_err(|err| err.to_typecheck_err(state, &rt.pos))
         }
         Term::Record(stat_map) | Term::RecRecord(stat_map) => {
-            // For recursive records, we look at the apparent type of each field and bind it in
-            // env before actually typechecking the content of fields
-            if let Term::RecRecord(_) = t.as_ref() {
-                envs.local.extend(
-                    stat_map.iter().map(|(id, rt)| {
-                        (id.clone(), apparent_type(rt.as_ref(), state.table, strict))
-                    }),
-                );
-            }
+            let rec = matches!(t.as_ref(), Term::RecRecord(_));
+            let fields: Vec<(Pattern, &RichTerm)> = stat_map
+                .iter()
+                .map(|(id, field)| (Pattern::Var(id.clone()), field))
+                .collect();
 
             let root_ty = if let TypeWrapper::Ptr(p) = ty {
-                get_root(state.table, p)
+                get_root(state.table, p).map_err(|err| err.to_typecheck_err(state, &rt.pos))?
             } else {
                 ty.clone()
             };
 
-            if let TypeWrapper::Concrete(AbsType::DynRecord(rec_ty)) = root_ty.clone() {
-                // Checking for an dynamic record
-                stat_map
-                    .into_iter()
-                    .try_for_each(|(_, t)| -> Result<(), TypecheckError> {
-                        type_check_(state, envs.clone(), strict, t, (*rec_ty).clone())
-                    })
+            if let TypeWrapper::Concrete(AbsType::DynRecord(rec_ty)) = root_ty {
+                let binding = Binding::Fields {
+                    rec,
+                    elt: Some(*rec_ty),
+                };
+                check_bindings(state, &envs, strict, &fields, &binding).map(|_| ())
             } else {
-                let row = stat_map.into_iter().try_fold(
-                    TypeWrapper::Concrete(AbsType::RowEmpty()),
-                    |acc, (id, field)| -> Result<TypeWrapper, TypecheckError> {
-                        // In the case of a recursive record, new types (either type variables or
-                        // annotations) have already be determined and put in the typing
-                        // environment, and we need to use the same.
-                        let ty = if let Term::RecRecord(_) = t.as_ref() {
-                            envs.get(&id).unwrap().clone()
-                        } else {
-                            TypeWrapper::Ptr(new_var(state.table))
-                        };
-
-                        type_check_(state, envs.clone(), strict, field, ty.clone())?;
-
-                        Ok(TypeWrapper::Concrete(AbsType::RowExtend(
-                            id.clone(),
-                            Some(Box::new(ty)),
-                            Box::new(acc),
-                        )))
-                    },
-                )?;
+                let binding = Binding::Fields { rec, elt: None };
+                let row = check_bindings(state, &envs, strict, &fields, &binding)?
+                    .into_iter()
+                    .fold(
+                        TypeWrapper::Concrete(AbsType::RowEmpty()),
+                        |acc, (id, ty)| {
+                            TypeWrapper::Concrete(AbsType::RowExtend(
+                                id,
+                                Some(Box::new(ty)),
+                                Box::new(acc),
+                            ))
+                        },
+                    );
 
                 unify(
                     state,
@@ -600,6 +1016,22 @@ fn type_check_(
                 .map_err(|err| err.to_typecheck_err(state, &rt.pos))
             }
         }
+        // The type of a field is instantiated as the one of a variable, such that polymorphic
+        // functions stored in records can be used.
+        Term::Op1(UnaryOp::StaticAccess(id), t) => {
+            let row = TypeWrapper::Ptr(new_var(state.table));
+            let res = TypeWrapper::Ptr(new_var(state.table));
+            let rec =
+                TypeWrapper::Concrete(AbsType::StaticRecord(Box::new(TypeWrapper::Concrete(
+                    AbsType::RowExtend(id.clone(), Some(Box::new(res.clone())), Box::new(row)),
+                ))));
+
+            type_check_(state, envs.clone(), strict, t, rec)?;
+            let instantiated = instantiate_foralls_with(state, res, TypeWrapper::Ptr)
+                .map_err(|err| err.to_typecheck_err(state, &rt.pos))?;
+            unify(state, strict, ty, instantiated)
+                .map_err(|err| err.to_typecheck_err(state, &rt.pos))
+        }
         Term::Op1(op, t) => {
             let ty_op = get_uop_type(state, envs.clone(), strict, op)?;
 
@@ -627,89 +1059,441 @@ fn type_check_(
             type_check_(state, envs, strict, t, src2)
         }
         Term::Promise(ty2, _, t) => {
-            let tyw2 = to_typewrapper(ty2.clone());
-
-            let instantiated = instantiate_foralls_with(state, tyw2, TypeWrapper::Constant);
-
-            unify(state, strict, ty.clone(), to_typewrapper(ty2.clone()))
-                .map_err(|err| err.to_typecheck_err(state, &rt.pos))?;
-            type_check_(state, envs, true, t, instantiated)
+            let index = record_annotation(state, AnnotationKind::Promise, rt, ty2, &ty);
+            let level = state.table.level;
+            let result = check_annotation(ty2)
+                .map_err(|err| err.to_typecheck_err(state, &rt.pos))
+                .and_then(|()| {
+                    let tyw2 = to_typewrapper(ty2.clone());
+
+                    instantiate_foralls_with(state, tyw2.clone(), TypeWrapper::Ptr)
+                        .and_then(|instantiated| subsume(state, strict, ty.clone(), instantiated))
+                        .map_err(|err| err.to_typecheck_err(state, &rt.pos))?;
+
+                    // The quantified variables of the annotation are replaced by type constants when
+                    // checking the term (see `skolemize`).
+                    type_check_(state, envs, true, t, tyw2)
+                });
+            record_annotation_result(state, index, level, result)
         }
         Term::Assume(ty2, _, t) => {
-            unify(state, strict, ty.clone(), to_typewrapper(ty2.clone()))
-                .map_err(|err| err.to_typecheck_err(state, &rt.pos))?;
-            let new_ty = TypeWrapper::Ptr(new_var(state.table));
-            type_check_(state, envs, false, t, new_ty)
+            let index = record_annotation(state, AnnotationKind::Assume, rt, ty2, &ty);
+            let level = state.table.level;
+            let result = check_annotation(ty2)
+                .map_err(|err| err.to_typecheck_err(state, &rt.pos))
+                .and_then(|()| {
+                    // The surrounding code sees the annotated type, as for a variable of this type.
+                    instantiate_foralls_with(state, to_typewrapper(ty2.clone()), TypeWrapper::Ptr)
+                        .and_then(|instantiated| subsume(state, strict, ty.clone(), instantiated))
+                        .map_err(|err| err.to_typecheck_err(state, &rt.pos))?;
+                    let new_ty = TypeWrapper::Ptr(new_var(state.table));
+                    type_check_(state, envs, false, t, new_ty)
+                });
+            record_annotation_result(state, index, level, result)
         }
         Term::Sym(_) => unify(state, strict, ty, TypeWrapper::Concrete(AbsType::Sym()))
             .map_err(|err| err.to_typecheck_err(state, &rt.pos)),
-        Term::Wrapped(_, t)
-        | Term::DefaultValue(t)
-        | Term::ContractWithDefault(_, _, t)
-        | Term::Docstring(_, t) => type_check_(state, envs, strict, t, ty),
-        Term::Contract(_, _) => Ok(()),
-        Term::Import(_) => unify(state, strict, ty, TypeWrapper::Concrete(AbsType::Dyn()))
-            .map_err(|err| err.to_typecheck_err(state, &rt.pos)),
+        Term::MetaValue(meta) => {
+            for ctr in meta.contracts.iter() {
+                check_annotation(&ctr.types).map_err(|err| err.to_typecheck_err(state, &rt.pos))?;
+            }
+            match &meta.value {
+                Some(t) => type_check_(state, envs, strict, t, ty),
+                None => Ok(()),
+            }
+        }
+        Term::Wrapped(_, t) => type_check_(state, envs, strict, t, ty),
+        // The type of an import with an interface file is the declared one, and its implementation
+        // is not typechecked.
+        Term::Import(path) if state.resolver.get_interface(path, None).is_some() => {
+            let interface = state.resolver.get_interface(path, None).unwrap();
+            check_annotation(&interface).map_err(|err| err.to_typecheck_err(state, &rt.pos))?;
+            unify(state, strict, ty, to_typewrapper(interface))
+                .map_err(|err| err.to_typecheck_err(state, &rt.pos))
+        }
+        // The value of an external term is only known at run time, when provided by the host.
+        Term::Import(_) | Term::External(_) => {
+            unify(state, strict, ty, TypeWrapper::Concrete(AbsType::Dyn()))
+                .map_err(|err| err.to_typecheck_err(state, &rt.pos))
+        }
         Term::ResolvedImport(file_id) => {
-            let t = state
-                .resolver
-                .get(file_id.clone())
-                .expect("Internal error: resolved import not found ({:?}) during typechecking.");
-            type_check_in_env(&t, envs.global, state.resolver).map(|_ty| ())
+            let t = state.resolver.get(*file_id).ok_or_else(|| {
+                TypecheckError::InternalError(
+                    format!(
+                        "resolved import not found ({:?}) during typechecking",
+                        file_id
+                    ),
+                    pos.clone(),
+                )
+            })?;
+            type_check_in_env(&t, envs.global, state.resolver, state.cancel).map(|_ty| ())
         }
     }
 }
 
-/// Determine the apparent type of a let-bound expression.
+/// Check that a type annotation is well-formed.
 ///
-/// When a let-binding `let x = bound_exp in body` is processed, the type of `bound_exp` must be
-/// determined to be associated to the bound variable `x` in the typing environment (`typed_vars`).
-/// Then, future occurrences of `x` can be given this type when used in a `Promise` block.
+/// Type annotations are eventually converted to contracts, which requires that all type variables
+/// are bound by an enclosing `forall`, and that the rows of enum and record types are of the
+/// expected kind: rows without types for enums, and rows with types for records.
+fn check_annotation(ty: &Types) -> Result<(), Box<UnifError>> {
+    fn check_row(
+        row: &Types,
+        typed: bool,
+        whole: &Types,
+        bound: &mut Vec<Ident>,
+    ) -> Result<(), Box<UnifError>> {
+        match &row.0 {
+            AbsType::RowEmpty() => Ok(()),
+            AbsType::Var(_) => check(row, bound),
+            AbsType::RowExtend(_, None, tail) if !typed => check_row(tail, typed, whole, bound),
+            AbsType::RowExtend(_, Some(ty), tail) if typed => {
+                check(ty, bound)?;
+                check_row(tail, typed, whole, bound)
+            }
+            _ => Err(Box::new(UnifError::IllformedType(to_typewrapper(
+                whole.clone(),
+            )))),
+        }
+    }
+
+    fn check(ty: &Types, bound: &mut Vec<Ident>) -> Result<(), Box<UnifError>> {
+        match &ty.0 {
+            AbsType::Dyn()
+            | AbsType::Num()
+            | AbsType::Bool()
+            | AbsType::Str()
+            | AbsType::Sym()
+            | AbsType::Flat(_)
+            | AbsType::RowEmpty() => Ok(()),
+            AbsType::Var(id) if bound.contains(id) => Ok(()),
+            AbsType::Var(id) => Err(Box::new(UnifError::UnboundTypeVariable(id.clone()))),
+            AbsType::Forall(id, body) => {
+                bound.push(id.clone());
+                let result = check(body, bound);
+                bound.pop();
+                result
+            }
+            AbsType::Arrow(s, t) => {
+                check(s, bound)?;
+                check(t, bound)
+            }
+            AbsType::RowExtend(_, ty_opt, tail) => {
+                if let Some(ty) = ty_opt {
+                    check(ty, bound)?;
+                }
+                check(tail, bound)
+            }
+            AbsType::Enum(row) => check_row(row, false, ty, bound),
+            AbsType::StaticRecord(row) => check_row(row, true, ty, bound),
+            AbsType::DynRecord(ty) | AbsType::List(ty) => check(ty, bound),
+        }
+    }
+
+    check(ty, &mut Vec::new())
+}
+
+/// Record a `Promise` or an `Assume` block, if the annotations are recorded (see
+/// [`check_annotations`](fn.check_annotations.html)). Return the index of the record, to be given
+/// to [`record_annotation_result`](fn.record_annotation_result.html) once the block is checked.
 ///
-/// The role of `apparent_type` is precisely to determine the type of `bound_exp`:
-/// - if `bound_exp` is annotated by an `Assume` or a `Promise`, use the user-provided type.
-/// - Otherwise:
-///     * in non strict mode, we won't (and possibly can't) infer the type of `bound_exp`: just
-///       return `Dyn`.
-///     * in strict mode, we will typecheck `bound_exp`: return a new unification variable to be
-///       associated to `bound_exp`.
-fn apparent_type(t: &Term, table: &mut UnifTable, strict: bool) -> TypeWrapper {
-    match t {
-        Term::Assume(ty, _, _) | Term::Promise(ty, _, _) => to_typewrapper(ty.clone()),
-        _ if strict => TypeWrapper::Ptr(new_var(table)),
-        _ => TypeWrapper::Concrete(AbsType::Dyn()),
+/// The block is recorded before checking its content, so that the enclosing blocks come first.
+fn record_annotation(
+    state: &mut State,
+    kind: AnnotationKind,
+    rt: &RichTerm,
+    declared: &Types,
+    inferred: &TypeWrapper,
+) -> Option<usize> {
+    state.annotations.as_mut().map(|annotations| {
+        annotations.push(RecordedAnnotation {
+            kind,
+            span: rt.pos.clone(),
+            declared: declared.clone(),
+            inferred: inferred.clone(),
+            error: None,
+        });
+        annotations.len() - 1
+    })
+}
+
+/// Record the result of the typechecking of a block recorded by
+/// [`record_annotation`](fn.record_annotation.html). An error, except a cancellation, is recorded
+/// with the block instead of being returned. If the block was not recorded, return the result
+/// unchanged.
+///
+/// As the typechecking goes on after a recorded error, the level of the unification table is
+/// restored to `level`, the one before the block: an error may have interrupted the checking of
+/// the block inside a scope, before leaving it.
+fn record_annotation_result(
+    state: &mut State,
+    index: Option<usize>,
+    level: usize,
+    result: Result<(), TypecheckError>,
+) -> Result<(), TypecheckError> {
+    match (result, state.annotations.as_mut(), index) {
+        (Err(err), Some(annotations), Some(index))
+            if !matches!(err, TypecheckError::Cancelled(..)) =>
+        {
+            annotations[index].error = Some(err);
+            state.table.level = level;
+            Ok(())
+        }
+        (result, _, _) => result,
     }
 }
 
-/// The types on which the unification algorithm operates, which may be either a concrete type, a
-/// type constant or a unification variable.
-#[derive(Clone, PartialEq, Debug)]
-pub enum TypeWrapper {
-    /// A concrete type (like `Num` or `Str -> Str`).
-    Concrete(AbsType<Box<TypeWrapper>>),
-    /// A rigid type constant which cannot be unified with anything but itself.
-    Constant(usize),
-    /// A unification variable.
-    Ptr(usize),
+/// A kind of bindings of identifiers to expressions (see
+/// [`check_bindings`](fn.check_bindings.html)).
+enum Binding {
+    /// A let binding: the binder is only in scope in the body, and its type is generalized.
+    Let,
+    /// A recursive let binding: the binder is also in scope in the bound expression.
+    LetRec,
+    /// The fields of a record. The fields of a recursive record are in scope in each other. Their
+    /// types are not generalized, as they are part of the type of the record, and must all be
+    /// `elt` for a dictionary.
+    Fields { rec: bool, elt: Option<TypeWrapper> },
 }
 
-impl TypeWrapper {
-    pub fn subst(self, id: Ident, to: TypeWrapper) -> TypeWrapper {
-        use self::TypeWrapper::*;
-        match self {
-            Concrete(AbsType::Var(ref i)) if *i == id => to,
-            Concrete(AbsType::Var(i)) => Concrete(AbsType::Var(i)),
+/// Typecheck the expressions bound by a group of bindings, and return the types of the variables
+/// bound by their patterns, in order.
+///
+/// Whatever the binding, the type of a bound expression is its apparent type (see
+/// [`apparent_type`](fn.apparent_type.html)), such that its annotations are used uniformly, and
+/// the types of the variables are obtained by destructuring it (see
+/// [`destructure`](fn.destructure.html)). In a recursive binding, the variables are in scope in
+/// the bound expressions with these types, before they are generalized.
+fn check_bindings(
+    state: &mut State,
+    envs: &Envs,
+    strict: bool,
+    bindings: &[(Pattern, &RichTerm)],
+    binding: &Binding,
+) -> Result<Vec<(Ident, TypeWrapper)>, TypecheckError> {
+    let (rec, generalize) = match binding {
+        Binding::Let => (false, true),
+        Binding::LetRec => (true, true),
+        Binding::Fields { rec, .. } => (*rec, false),
+    };
 
-            Concrete(AbsType::Forall(i, t)) => {
-                if i == id {
-                    Concrete(AbsType::Forall(i, t))
-                } else {
-                    let tt = *t;
-                    Concrete(AbsType::Forall(i, Box::new(tt.subst(id, to))))
-                }
-            }
-            // Trivial recursion
-            Concrete(AbsType::Dyn()) => Concrete(AbsType::Dyn()),
+    if generalize {
+        state.table.enter_level();
+    }
+
+    let types: Vec<TypeWrapper> = bindings
+        .iter()
+        .map(|(_, bound)| apparent_type(bound.as_ref(), state.table, strict))
+        .collect();
+
+    // The variables bound by each pattern, with their type and the index of their binding.
+    let mut vars = Vec::new();
+    for (index, ((pat, bound), ty)) in bindings.iter().zip(types.iter()).enumerate() {
+        let mut pat_vars = Vec::new();
+        destructure(state, strict, pat, ty.clone(), &mut pat_vars)
+            .map_err(|err| err.to_typecheck_err(state, &bound.pos))?;
+        vars.extend(pat_vars.into_iter().map(|(id, ty)| (id, ty, index)));
+    }
+
+    let mut bound_envs = envs.clone();
+    if rec {
+        for (id, ty, _) in vars.iter() {
+            bound_envs.insert(id.clone(), ty.clone());
+        }
+    }
+
+    for ((_, bound), ty) in bindings.iter().zip(types.iter()) {
+        if let Binding::Fields { elt: Some(elt), .. } = binding {
+            unify(state, strict, elt.clone(), ty.clone())
+                .map_err(|err| err.to_typecheck_err(state, &bound.pos))?;
+        }
+        type_check_(state, bound_envs.clone(), strict, bound, ty.clone())?;
+    }
+
+    if !generalize {
+        return Ok(vars.into_iter().map(|(id, ty, _)| (id, ty)).collect());
+    }
+
+    // The free variables of the types which are still at a higher level are not reachable from
+    // the enclosing scope, and are generalized.
+    state.table.leave_level();
+    let level = state.table.level;
+    vars.into_iter()
+        .map(|(id, ty, index)| {
+            generalize_let(state, level, ty.clone())
+                .map(|ty| (id, ty))
+                .map_err(|c| {
+                    UnifError::ConstEscape(c, ty).to_typecheck_err(state, &bindings[index].1.pos)
+                })
+        })
+        .collect()
+}
+
+/// Match the type of a destructured expression against a pattern, and collect the types of the
+/// variables it binds. The row of the type of a record pattern is open, as the destructured record
+/// may have other fields.
+fn destructure(
+    state: &mut State,
+    strict: bool,
+    pat: &Pattern,
+    ty: TypeWrapper,
+    vars: &mut Vec<(Ident, TypeWrapper)>,
+) -> Result<(), UnifError> {
+    match pat {
+        Pattern::Var(id) => vars.push((id.clone(), ty)),
+        Pattern::Record(fields) => {
+            let field_types: Vec<TypeWrapper> = fields
+                .iter()
+                .map(|_| unknown_type(state.table, strict))
+                .collect();
+            let tail = unknown_type(state.table, strict);
+            let row = fields.iter().zip(field_types.iter()).rev().fold(
+                tail,
+                |row, ((id, _), field_ty)| {
+                    TypeWrapper::Concrete(AbsType::RowExtend(
+                        id.clone(),
+                        Some(Box::new(field_ty.clone())),
+                        Box::new(row),
+                    ))
+                },
+            );
+            unify(
+                state,
+                strict,
+                ty,
+                TypeWrapper::Concrete(AbsType::StaticRecord(Box::new(row))),
+            )?;
+
+            for (sub, field_ty) in fields.iter().map(|(_, sub)| sub).zip(field_types) {
+                destructure(state, strict, sub, field_ty, vars)?;
+            }
+        }
+        Pattern::List(subs) => {
+            let elt = unknown_type(state.table, strict);
+            unify(state, strict, ty, TypeWrapper::list_of(elt.clone()))?;
+
+            for sub in subs {
+                destructure(state, strict, sub, elt.clone(), vars)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Determine the apparent type of a let-bound expression.
+///
+/// When a let-binding `let x = bound_exp in body` is processed, the type of `bound_exp` must be
+/// determined to be associated to the bound variable `x` in the typing environment (`typed_vars`).
+/// Then, future occurrences of `x` can be given this type when used in a `Promise` block.
+///
+/// The role of `apparent_type` is precisely to determine the type of `bound_exp`:
+/// - if `bound_exp` is annotated by an `Assume`, a `Promise` or a contract, use the user-provided
+///   type.
+/// - if `bound_exp` is a let-binding or is wrapped in metadata (a documentation or a default
+///   value), look at the body.
+/// - if `bound_exp` is a record with some annotated fields, possibly nested, build the record type
+///   from the apparent types of the fields.
+/// - Otherwise:
+///     * in non strict mode, we won't (and possibly can't) infer the type of `bound_exp`: just
+///       return `Dyn`.
+///     * in strict mode, we will typecheck `bound_exp`: return a new unification variable to be
+///       associated to `bound_exp`.
+fn apparent_type(t: &Term, table: &mut UnifTable, strict: bool) -> TypeWrapper {
+    annotated_type(t, table, strict).unwrap_or_else(|| unknown_type(table, strict))
+}
+
+/// The type of a term of which nothing is known (see [`apparent_type`](fn.apparent_type.html)).
+fn unknown_type(table: &mut UnifTable, strict: bool) -> TypeWrapper {
+    if strict {
+        TypeWrapper::Ptr(new_var(table))
+    } else {
+        TypeWrapper::Concrete(AbsType::Dyn())
+    }
+}
+
+/// The type given by the annotations of a term, if any (see
+/// [`apparent_type`](fn.apparent_type.html)).
+fn annotated_type(t: &Term, table: &mut UnifTable, strict: bool) -> Option<TypeWrapper> {
+    match t {
+        Term::Assume(ty, _, _) | Term::Promise(ty, _, _) => Some(to_typewrapper(ty.clone())),
+        Term::MetaValue(MetaValue {
+            contracts,
+            value: Some(t),
+            ..
+        }) => match contracts.first() {
+            Some(ctr) => Some(to_typewrapper(ctr.types.clone())),
+            None => annotated_type(t.as_ref(), table, strict),
+        },
+        Term::Let(_, _, t) | Term::LetRec(_, _, t) | Term::LetPattern(_, _, t) => {
+            annotated_type(t.as_ref(), table, strict)
+        }
+        Term::Record(fields) | Term::RecRecord(fields) => {
+            let annotated: Vec<(&Ident, Option<TypeWrapper>)> = fields
+                .iter()
+                .map(|(id, field)| (id, annotated_type(field.as_ref(), table, strict)))
+                .collect();
+
+            if annotated.iter().all(|(_, ty)| ty.is_none()) {
+                return None;
+            }
+
+            let row = annotated.into_iter().fold(
+                TypeWrapper::Concrete(AbsType::RowEmpty()),
+                |row, (id, ty)| {
+                    let ty = ty.unwrap_or_else(|| unknown_type(table, strict));
+                    TypeWrapper::Concrete(AbsType::RowExtend(
+                        id.clone(),
+                        Some(Box::new(ty)),
+                        Box::new(row),
+                    ))
+                },
+            );
+            Some(TypeWrapper::Concrete(AbsType::StaticRecord(Box::new(row))))
+        }
+        _ => None,
+    }
+}
+
+/// The types on which the unification algorithm operates, which may be either a concrete type, a
+/// type constant or a unification variable.
+#[derive(Clone, PartialEq, Debug)]
+pub enum TypeWrapper {
+    /// A concrete type (like `Num` or `Str -> Str`).
+    Concrete(AbsType<Box<TypeWrapper>>),
+    /// A rigid type constant which cannot be unified with anything but itself.
+    Constant(usize),
+    /// A unification variable.
+    Ptr(usize),
+}
+
+impl TypeWrapper {
+    /// The type `List` of lists whose elements have any type, that is `List Dyn`.
+    pub fn list() -> Self {
+        TypeWrapper::list_of(TypeWrapper::Concrete(AbsType::Dyn()))
+    }
+
+    /// The type `List elt` of lists whose elements have the type `elt`.
+    pub fn list_of(elt: TypeWrapper) -> Self {
+        TypeWrapper::Concrete(AbsType::List(Box::new(elt)))
+    }
+
+    pub fn subst(self, id: Ident, to: TypeWrapper) -> TypeWrapper {
+        use self::TypeWrapper::*;
+        match self {
+            Concrete(AbsType::Var(ref i)) if *i == id => to,
+            Concrete(AbsType::Var(i)) => Concrete(AbsType::Var(i)),
+
+            Concrete(AbsType::Forall(i, t)) => {
+                if i == id {
+                    Concrete(AbsType::Forall(i, t))
+                } else {
+                    let tt = *t;
+                    Concrete(AbsType::Forall(i, Box::new(tt.subst(id, to))))
+                }
+            }
+            // Trivial recursion
+            Concrete(AbsType::Dyn()) => Concrete(AbsType::Dyn()),
             Concrete(AbsType::Num()) => Concrete(AbsType::Num()),
             Concrete(AbsType::Bool()) => Concrete(AbsType::Bool()),
             Concrete(AbsType::Str()) => Concrete(AbsType::Str()),
@@ -734,7 +1518,7 @@ impl TypeWrapper {
             Concrete(AbsType::DynRecord(def_ty)) => {
                 Concrete(AbsType::DynRecord(Box::new(def_ty.subst(id, to))))
             }
-            Concrete(AbsType::List()) => Concrete(AbsType::List()),
+            Concrete(AbsType::List(elem)) => Concrete(AbsType::List(Box::new(elem.subst(id, to)))),
             Constant(x) => Constant(x),
             Ptr(x) => Ptr(x),
         }
@@ -758,7 +1542,7 @@ fn row_add(
     mut r: TypeWrapper,
 ) -> Result<(Option<Box<TypeWrapper>>, TypeWrapper), RowUnifError> {
     if let TypeWrapper::Ptr(p) = r {
-        r = get_root(state.table, p);
+        r = get_root(state.table, p)?;
     }
     match r {
         TypeWrapper::Concrete(AbsType::RowEmpty()) => Err(RowUnifError::MissingRow(id.clone())),
@@ -781,14 +1565,15 @@ fn row_add(
             }
             let new_row = TypeWrapper::Ptr(new_var(state.table));
             constraint(state, new_row.clone(), id.clone())?;
-            state.table.insert(
+            assign(
+                state.table,
                 root,
-                Some(TypeWrapper::Concrete(AbsType::RowExtend(
+                TypeWrapper::Concrete(AbsType::RowExtend(
                     id.clone(),
                     ty.clone(),
                     Box::new(new_row.clone()),
-                ))),
-            );
+                )),
+            )?;
             Ok((ty, new_row))
         }
         other => Err(RowUnifError::IllformedRow(other)),
@@ -812,17 +1597,80 @@ pub fn unify(
     }
 }
 
+/// Check that the type of a term is compatible with its expected type, where an enum type can be
+/// widened.
+///
+/// A term of a closed enum type, such as `< (| bla, |) >`, is accepted where a closed enum type
+/// with more tags is expected, such as `< (| bla, blo, |) >`, without an explicit `embed`. Open
+/// enum types, whose tail is a type variable, still require `embed`. Otherwise, the two types are
+/// unified, as by [`unify`](fn.unify.html).
+fn subsume(
+    state: &mut State,
+    strict: bool,
+    expected: TypeWrapper,
+    inferred: TypeWrapper,
+) -> Result<(), UnifError> {
+    if strict {
+        let expected_tags = closed_enum_tags(state.table, &expected);
+        let inferred_tags = closed_enum_tags(state.table, &inferred);
+
+        if let (Some(expected_tags), Some(inferred_tags)) = (expected_tags, inferred_tags) {
+            if inferred_tags.iter().all(|id| expected_tags.contains(id)) {
+                return Ok(());
+            }
+        }
+    }
+
+    unify(state, strict, expected, inferred)
+}
+
+/// Follow the unification variables which have a value, without copying it as `get_root` does.
+fn resolve<'a>(table: &'a UnifTable, ty: &'a TypeWrapper) -> &'a TypeWrapper {
+    match ty {
+        TypeWrapper::Ptr(p) => match table.types.get(*p) {
+            Some(Some(ty)) => resolve(table, ty),
+            _ => ty,
+        },
+        ty => ty,
+    }
+}
+
+/// Return the tags of a closed enum type, or `None` if the type is not an enum type, or if its
+/// row is open.
+fn closed_enum_tags<'a>(table: &'a UnifTable, ty: &'a TypeWrapper) -> Option<Vec<&'a Ident>> {
+    let mut row = match resolve(table, ty) {
+        TypeWrapper::Concrete(AbsType::Enum(row)) => resolve(table, row),
+        _ => return None,
+    };
+    let mut tags = Vec::new();
+
+    loop {
+        match row {
+            TypeWrapper::Concrete(AbsType::RowExtend(id, None, tail)) => {
+                tags.push(id);
+                row = resolve(table, tail);
+            }
+            TypeWrapper::Concrete(AbsType::RowEmpty()) => return Some(tags),
+            _ => return None,
+        }
+    }
+}
+
 /// Try to unify two types.
 pub fn unify_(
     state: &mut State,
     mut t1: TypeWrapper,
     mut t2: TypeWrapper,
 ) -> Result<(), UnifError> {
+    if state.is_cancelled() {
+        return Err(UnifError::Cancelled());
+    }
+
     if let TypeWrapper::Ptr(pt1) = t1 {
-        t1 = get_root(state.table, pt1);
+        t1 = get_root(state.table, pt1)?;
     }
     if let TypeWrapper::Ptr(pt2) = t2 {
-        t2 = get_root(state.table, pt2);
+        t2 = get_root(state.table, pt2)?;
     }
 
     // t1 and t2 are roots of the type
@@ -832,7 +1680,7 @@ pub fn unify_(
             (AbsType::Num(), AbsType::Num()) => Ok(()),
             (AbsType::Bool(), AbsType::Bool()) => Ok(()),
             (AbsType::Str(), AbsType::Str()) => Ok(()),
-            (AbsType::List(), AbsType::List()) => Ok(()),
+            (AbsType::List(t1), AbsType::List(t2)) => unify_(state, *t1, *t2),
             (AbsType::Sym(), AbsType::Sym()) => Ok(()),
             (AbsType::Arrow(s1s, s1t), AbsType::Arrow(s2s, s2t)) => {
                 unify_(state, (*s1s).clone(), (*s2s).clone()).map_err(|err| {
@@ -850,15 +1698,20 @@ pub fn unify_(
                     )
                 })
             }
-            (AbsType::Flat(s), AbsType::Flat(t)) => match (s.as_ref(), t.as_ref()) {
-                (Term::Var(vs), Term::Var(ts)) if vs == ts => Ok(()),
-                (Term::Var(_), Term::Var(_)) => Err(UnifError::TypeMismatch(
-                    TypeWrapper::Concrete(AbsType::Flat(s)),
-                    TypeWrapper::Concrete(AbsType::Flat(t)),
-                )),
-                (Term::Var(_), _) => Err(UnifError::IllformedFlatType(t)),
-                _ => Err(UnifError::IllformedFlatType(s)),
-            },
+            (AbsType::Flat(s), AbsType::Flat(t)) => {
+                if !is_wellformed_flat(s.as_ref()) {
+                    Err(UnifError::IllformedFlatType(s))
+                } else if !is_wellformed_flat(t.as_ref()) {
+                    Err(UnifError::IllformedFlatType(t))
+                } else if flat_eq(s.as_ref(), t.as_ref()) {
+                    Ok(())
+                } else {
+                    Err(UnifError::TypeMismatch(
+                        TypeWrapper::Concrete(AbsType::Flat(s)),
+                        TypeWrapper::Concrete(AbsType::Flat(t)),
+                    ))
+                }
+            }
             (r1, r2) if r1.is_row_type() && r2.is_row_type() => {
                 unify_rows(state, r1.clone(), r2.clone()).map_err(|err| {
                     err.to_unif_err(TypeWrapper::Concrete(r1), TypeWrapper::Concrete(r2))
@@ -917,13 +1770,16 @@ pub fn unify_(
             (AbsType::DynRecord(t), AbsType::DynRecord(t2)) => unify_(state, *t, *t2),
             (AbsType::Forall(i1, t1t), AbsType::Forall(i2, t2t)) => {
                 // Very stupid (slow) implementation
+                state.table.enter_level();
                 let constant_type = TypeWrapper::Constant(new_var(state.table));
 
-                unify_(
+                let result = unify_(
                     state,
                     t1t.subst(i1, constant_type.clone()),
                     t2t.subst(i2, constant_type),
-                )
+                );
+                state.table.leave_level();
+                result
             }
             (AbsType::Var(ident), _) | (_, AbsType::Var(ident)) => {
                 Err(UnifError::UnboundTypeVariable(ident))
@@ -941,7 +1797,7 @@ pub fn unify_(
                     .constr
                     .insert(r1, r1_constr.drain().chain(r2_constr.drain()).collect());
 
-                state.table.insert(r1, Some(TypeWrapper::Ptr(r2)));
+                assign(state.table, r1, TypeWrapper::Ptr(r2))?;
             }
             Ok(())
         }
@@ -949,10 +1805,7 @@ pub fn unify_(
         (TypeWrapper::Ptr(p), s @ TypeWrapper::Concrete(_))
         | (TypeWrapper::Ptr(p), s @ TypeWrapper::Constant(_))
         | (s @ TypeWrapper::Concrete(_), TypeWrapper::Ptr(p))
-        | (s @ TypeWrapper::Constant(_), TypeWrapper::Ptr(p)) => {
-            state.table.insert(p, Some(s));
-            Ok(())
-        }
+        | (s @ TypeWrapper::Constant(_), TypeWrapper::Ptr(p)) => assign(state.table, p, s),
         (TypeWrapper::Constant(i1), TypeWrapper::Constant(i2)) if i1 == i2 => Ok(()),
         (TypeWrapper::Constant(i1), TypeWrapper::Constant(i2)) => {
             Err(UnifError::ConstMismatch(i1, i2))
@@ -963,6 +1816,34 @@ pub fn unify_(
     }
 }
 
+/// Determine if a term is a legal inner term of a flat type, that is either a variable or the
+/// partial application of a legal inner term to a literal, as in `#(Between 1 10)`.
+fn is_wellformed_flat(t: &Term) -> bool {
+    match t {
+        Term::Var(_) => true,
+        Term::App(f, arg) => {
+            is_wellformed_flat(f.as_ref())
+                && matches!(
+                    arg.as_ref(),
+                    Term::Bool(_) | Term::Num(_) | Term::Str(_) | Term::Enum(_)
+                )
+        }
+        _ => false,
+    }
+}
+
+/// Structural equality of two well-formed inner terms of flat types, ignoring positions. Two flat
+/// types are equal if they apply the same contract to the same literals.
+fn flat_eq(s: &Term, t: &Term) -> bool {
+    match (s, t) {
+        (Term::Var(x), Term::Var(y)) => x == y,
+        (Term::App(f1, arg1), Term::App(f2, arg2)) => {
+            flat_eq(f1.as_ref(), f2.as_ref()) && arg1.as_ref() == arg2.as_ref()
+        }
+        _ => false,
+    }
+}
+
 /// Try to unify two row types. Return an [`IllformedRow`](./enum.RowUnifError.html#variant.IllformedRow) error if one of the given type
 /// is not a row type.
 pub fn unify_rows(
@@ -997,17 +1878,10 @@ pub fn unify_rows(
                 // If one of the tail is not a concrete type, it is either a unification variable
                 // or a constant (rigid type variable). `unify` already knows how to treat these
                 // cases, so we delegate the work. However it returns `UnifError` instead of
-                // `RowUnifError`, hence we have a bit of wrapping and unwrapping to do. Note that
-                // since we are unifying types with a constant or a unification variable somewhere,
-                // the only unification errors that should be possible are related to constants.
-                (t1_tail, t2_tail) => unify_(state, t1_tail, t2_tail).map_err(|err| match err {
-                    UnifError::ConstMismatch(c1, c2) => RowUnifError::ConstMismatch(c1, c2),
-                    UnifError::WithConst(c1, tyw) => RowUnifError::WithConst(c1, tyw),
-                    err => panic!(
-                        "typechecker::unify_rows(): unexpected error while unifying row tails {:?}",
-                        err
-                    ),
-                }),
+                // `RowUnifError`, hence the conversion. Note that since we are unifying types with
+                // a constant or a unification variable somewhere, the only unification errors that
+                // should be possible are related to constants or row constraints.
+                (t1_tail, t2_tail) => unify_(state, t1_tail, t2_tail).map_err(RowUnifError::from),
             }
         }
         (ty, _) if !ty.is_row_type() => Err(RowUnifError::IllformedRow(TypeWrapper::Concrete(ty))),
@@ -1024,27 +1898,117 @@ fn to_typewrapper(t: Types) -> TypeWrapper {
     TypeWrapper::Concrete(t3)
 }
 
-/// Extract the concrete type corresponding to a type wrapper. Free unification variables as well
-/// as type constants are replaced with the type `Dyn`.
-fn to_type(table: &UnifTable, ty: TypeWrapper) -> Types {
-    match ty {
-        TypeWrapper::Ptr(p) => match get_root(table, p) {
-            t @ TypeWrapper::Concrete(_) => to_type(table, t),
-            _ => Types(AbsType::Dyn()),
-        },
-        TypeWrapper::Constant(_) => Types(AbsType::Dyn()),
-        TypeWrapper::Concrete(t) => {
-            let mapped = t.map(|btyp| Box::new(to_type(table, *btyp)));
-            Types(mapped)
+/// Extract the concrete type corresponding to a type wrapper, as
+/// [`reporting::to_type`](./reporting/fn.to_type.html), but quantify over its free unification
+/// variables and type constants instead of leaving them unbound.
+fn generalize(table: &UnifTable, ty: TypeWrapper) -> Types {
+    // Unification variables and type constants are numbered independently, hence the flag.
+    fn gen(table: &UnifTable, ty: TypeWrapper, vars: &mut Vec<((bool, usize), Ident)>) -> Types {
+        let mut var = |p: (bool, usize)| {
+            let id = match vars.iter().find(|(q, _)| *q == p) {
+                Some((_, id)) => id.clone(),
+                None => {
+                    let id = Ident(format!("_{}", vars.len()));
+                    vars.push((p, id.clone()));
+                    id
+                }
+            };
+            Types(AbsType::Var(id))
+        };
+
+        match ty {
+            TypeWrapper::Ptr(p) => match get_root(table, p) {
+                Ok(t @ TypeWrapper::Concrete(_)) => gen(table, t, vars),
+                Ok(TypeWrapper::Ptr(root)) => var((false, root)),
+                Ok(TypeWrapper::Constant(c)) => var((true, c)),
+                Err(_) => var((false, p)),
+            },
+            TypeWrapper::Constant(c) => var((true, c)),
+            TypeWrapper::Concrete(t) => Types(t.map(|btyp| Box::new(gen(table, *btyp, vars)))),
+        }
+    }
+
+    let mut vars = Vec::new();
+    let ty = gen(table, ty, &mut vars);
+    vars.into_iter()
+        .rev()
+        .fold(ty, |ty, (_, id)| Types(AbsType::Forall(id, Box::new(ty))))
+}
+
+/// Generalize the type of a let-bound expression, which was inferred at a level higher than
+/// `level`: the free unification variables of a higher level are quantified, while the others
+/// belong to the enclosing scope and are left as is.
+///
+/// A row variable with constraints is not generalized, as quantified variables don't carry
+/// constraints. It is brought down to `level` instead, as are the free variables of the
+/// enclosing scope. Return the first type constant of a higher level encountered, if any, which
+/// would escape its scope.
+///
+/// The language has no mutable references, such that any let-bound expression can be generalized,
+/// without a value restriction.
+fn generalize_let(state: &mut State, level: usize, ty: TypeWrapper) -> Result<TypeWrapper, usize> {
+    fn gen(
+        state: &mut State,
+        level: usize,
+        ty: TypeWrapper,
+        vars: &mut Vec<(usize, Ident)>,
+    ) -> Result<TypeWrapper, usize> {
+        match ty {
+            TypeWrapper::Ptr(p) => match get_root(state.table, p) {
+                Ok(TypeWrapper::Ptr(root))
+                    if state.table.levels[root] > level && !state.constr.contains_key(&root) =>
+                {
+                    let id = match vars.iter().find(|(q, _)| *q == root) {
+                        Some((_, id)) => id.clone(),
+                        None => {
+                            let id = Ident(format!("_{}", vars.len()));
+                            vars.push((root, id.clone()));
+                            id
+                        }
+                    };
+                    Ok(TypeWrapper::Concrete(AbsType::Var(id)))
+                }
+                Ok(TypeWrapper::Ptr(root)) => {
+                    state.table.levels[root] = state.table.levels[root].min(level);
+                    Ok(TypeWrapper::Ptr(root))
+                }
+                Ok(ty) => gen(state, level, ty, vars),
+                Err(_) => Ok(TypeWrapper::Ptr(p)),
+            },
+            TypeWrapper::Constant(c) if state.table.levels[c] > level => Err(c),
+            ty @ TypeWrapper::Constant(_) => Ok(ty),
+            TypeWrapper::Concrete(t) => {
+                let mut result = Ok(());
+                let t = t.map(|btyw| {
+                    let btyw = *btyw;
+                    match result {
+                        Ok(()) => match gen(state, level, btyw.clone(), vars) {
+                            Ok(ty) => Box::new(ty),
+                            Err(c) => {
+                                result = Err(c);
+                                Box::new(btyw)
+                            }
+                        },
+                        Err(_) => Box::new(btyw),
+                    }
+                });
+                result.map(|()| TypeWrapper::Concrete(t))
+            }
         }
     }
+
+    let mut vars = Vec::new();
+    let ty = gen(state, level, ty, &mut vars)?;
+    Ok(vars.into_iter().rev().fold(ty, |ty, (_, id)| {
+        TypeWrapper::Concrete(AbsType::Forall(id, Box::new(ty)))
+    }))
 }
 
-/// Helpers to convert a `TypeWrapper` to a human-readable `Types` representation for error
-/// reporting purpose.
+/// Helpers to convert a `TypeWrapper` to a human-readable `Types` representation, for error
+/// reporting purpose and for the result of [`type_check`](../fn.type_check.html).
 mod reporting {
     use super::*;
-    use std::collections::HashSet;
+    use crate::collections::HashSet;
 
     /// A name registry used to replace unification variables and type constants with human-readable
     /// and distinct names when reporting errors.
@@ -1058,8 +2022,8 @@ mod reporting {
     impl NameReg {
         pub fn new() -> Self {
             NameReg {
-                reg: HashMap::new(),
-                taken: HashSet::new(),
+                reg: HashMap::default(),
+                taken: HashSet::default(),
                 var_count: 0,
                 cst_count: 0,
             }
@@ -1146,16 +2110,16 @@ mod reporting {
 
     /// Extract a concrete type corresponding to a type wrapper for error reporting.
     ///
-    /// Similar to [`to_type`](./fn.to_type.html), excepted that free unification variables and
-    /// type constants are replaced by type variables which names are determined by the
-    /// [`var_to_type`](./fn.var_to_type.html) and [`cst_to_type`](./fn.cst_tot_type.html).
-    /// Distinguishing occurrences of unification variables and type constants is more informative
-    /// than having `Dyn` everywhere.
+    /// Free unification variables and type constants are replaced by type variables which names
+    /// are determined by the [`var_to_type`](./fn.var_to_type.html) and
+    /// [`cst_to_type`](./fn.cst_tot_type.html). Distinguishing occurrences of unification
+    /// variables and type constants is more informative than having `Dyn` everywhere.
     pub fn to_type(state: &State, names: &mut NameReg, ty: TypeWrapper) -> Types {
         match ty {
             TypeWrapper::Ptr(p) => match get_root(state.table, p) {
-                TypeWrapper::Ptr(p) => var_to_type(state.names, names, p),
-                tyw => to_type(state, names, tyw),
+                Ok(TypeWrapper::Ptr(p)) => var_to_type(state.names, names, p),
+                Ok(tyw) => to_type(state, names, tyw),
+                Err(_) => var_to_type(state.names, names, p),
             },
             TypeWrapper::Constant(c) => cst_to_type(state.names, names, c),
             TypeWrapper::Concrete(t) => {
@@ -1173,12 +2137,16 @@ mod reporting {
 /// typechecking `forall`s: all quantified type variables in head position are replaced by rigid
 /// type constants, and the term is then typechecked normally. As these constants cannot be unified
 /// with anything, this forces all the occurrences of a type variable to be the same type.
-fn instantiate_foralls_with<F>(state: &mut State, mut ty: TypeWrapper, f: F) -> TypeWrapper
+fn instantiate_foralls_with<F>(
+    state: &mut State,
+    mut ty: TypeWrapper,
+    f: F,
+) -> Result<TypeWrapper, UnifError>
 where
     F: Fn(usize) -> TypeWrapper,
 {
     if let TypeWrapper::Ptr(p) = ty {
-        ty = get_root(state.table, p);
+        ty = get_root(state.table, p)?;
     }
 
     while let TypeWrapper::Concrete(AbsType::Forall(id, forall_ty)) = ty {
@@ -1188,7 +2156,46 @@ where
         ty = forall_ty.subst(id, var);
     }
 
-    ty
+    Ok(ty)
+}
+
+/// Skolemize the type against which a term is checked: replace the type variables quantified in
+/// head position, or in head position of the codomain of an arrow, by fresh type constants.
+///
+/// For example, `forall a. a -> (forall b. b -> a)` is transformed to `cst1 -> cst2 -> cst1`,
+/// while `(forall a. a -> a) -> Num` is left unchanged: the quantifier of the domain is skolemized
+/// only when an argument is checked against it. See the [module documentation](index.html).
+fn skolemize(state: &mut State, ty: TypeWrapper) -> Result<TypeWrapper, UnifError> {
+    let ty = match ty {
+        TypeWrapper::Ptr(p) => get_root(state.table, p)?,
+        ty => ty,
+    };
+
+    match ty {
+        ty @ TypeWrapper::Concrete(AbsType::Forall(_, _)) => {
+            let instantiated = instantiate_foralls_with(state, ty, TypeWrapper::Constant)?;
+            skolemize(state, instantiated)
+        }
+        TypeWrapper::Concrete(AbsType::Arrow(src, tgt)) => Ok(TypeWrapper::Concrete(
+            AbsType::Arrow(src, Box::new(skolemize(state, *tgt)?)),
+        )),
+        ty => Ok(ty),
+    }
+}
+
+/// Determine if a type has a quantifier to replace by [`skolemize`](fn.skolemize.html). Contrary
+/// to the latter, unification variables are followed without copying their value, as this test is
+/// done for each checked term.
+fn has_skolemizable_forall(table: &UnifTable, ty: &TypeWrapper) -> bool {
+    match ty {
+        TypeWrapper::Ptr(p) => match table.types.get(*p) {
+            Some(Some(ty)) => has_skolemizable_forall(table, ty),
+            _ => false,
+        },
+        TypeWrapper::Concrete(AbsType::Forall(_, _)) => true,
+        TypeWrapper::Concrete(AbsType::Arrow(_, tgt)) => has_skolemizable_forall(table, tgt),
+        _ => false,
+    }
 }
 
 /// Type of unary operations.
@@ -1233,6 +2240,11 @@ pub fn get_uop_type(
                 Box::new(TypeWrapper::Concrete(AbsType::Bool())),
             ))
         }
+        // forall a. a -> Str
+        UnaryOp::TypeOf() => TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Ptr(new_var(state.table))),
+            Box::new(TypeWrapper::Concrete(AbsType::Str())),
+        )),
         // forall a. Dyn -> a
         UnaryOp::Blame() => {
             let res = TypeWrapper::Ptr(new_var(state.table));
@@ -1251,7 +2263,10 @@ pub fn get_uop_type(
         UnaryOp::Embed(id) => {
             let row = TypeWrapper::Ptr(new_var(state.table));
             // Constraining a freshly created variable should never fail.
-            constraint(state, row.clone(), id.clone()).unwrap();
+            constraint(state, row.clone(), id.clone()).map_err(|err| {
+                err.to_unif_err(row.clone(), row.clone())
+                    .to_typecheck_err(state, &None)
+            })?;
             TypeWrapper::Concrete(AbsType::Arrow(
                 Box::new(TypeWrapper::Concrete(AbsType::Enum(Box::new(row.clone())))),
                 Box::new(TypeWrapper::Concrete(AbsType::Enum(Box::new(
@@ -1302,6 +2317,21 @@ pub fn get_uop_type(
                 Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
             ))
         }
+        // Dyn -> Str
+        UnaryOp::LabelTag() => TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+            Box::new(TypeWrapper::Concrete(AbsType::Str())),
+        )),
+        // Dyn -> List
+        UnaryOp::LabelPath() => TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+            Box::new(TypeWrapper::list()),
+        )),
+        // Str -> Dyn
+        UnaryOp::MkLabel() => TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::Str())),
+            Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+        )),
         // Sym -> Dyn -> Dyn
         UnaryOp::Wrap() => TypeWrapper::Concrete(AbsType::arrow(
             Box::new(TypeWrapper::Concrete(AbsType::Sym())),
@@ -1310,6 +2340,11 @@ pub fn get_uop_type(
                 Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
             ))),
         )),
+        // Str -> Sym
+        UnaryOp::FreshSym() => TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::Str())),
+            Box::new(TypeWrapper::Concrete(AbsType::Sym())),
+        )),
         // forall rows a. { rows, id: a } -> a
         UnaryOp::StaticAccess(id) => {
             let row = TypeWrapper::Ptr(new_var(state.table));
@@ -1326,6 +2361,39 @@ pub fn get_uop_type(
                 Box::new(res),
             ))
         }
+        // forall rows a1 ... an. { id1: a1, ..., idn: an | rows } -> { id1: a1, ..., idn: an }
+        UnaryOp::Pick(ids) => {
+            let (row_in, row_out) = ids.iter().fold(
+                (
+                    TypeWrapper::Ptr(new_var(state.table)),
+                    TypeWrapper::Concrete(AbsType::RowEmpty()),
+                ),
+                |(row_in, row_out), id| {
+                    let field = TypeWrapper::Ptr(new_var(state.table));
+                    (
+                        TypeWrapper::Concrete(AbsType::RowExtend(
+                            id.clone(),
+                            Some(Box::new(field.clone())),
+                            Box::new(row_in),
+                        )),
+                        TypeWrapper::Concrete(AbsType::RowExtend(
+                            id.clone(),
+                            Some(Box::new(field)),
+                            Box::new(row_out),
+                        )),
+                    )
+                },
+            );
+
+            TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::StaticRecord(Box::new(
+                    row_in,
+                )))),
+                Box::new(TypeWrapper::Concrete(AbsType::StaticRecord(Box::new(
+                    row_out,
+                )))),
+            ))
+        }
         // { _ : a} -> { _ : b }
         // Unify f with Str -> a -> b.
         UnaryOp::MapRec(f) => {
@@ -1350,6 +2418,18 @@ pub fn get_uop_type(
                 Box::new(TypeWrapper::Concrete(AbsType::DynRecord(Box::new(b)))),
             ))
         }
+        // a -> a
+        // Unify the fallback with Dyn -> a.
+        UnaryOp::CatchBlame(f) => {
+            let a = TypeWrapper::Ptr(new_var(state.table));
+            let f_type = TypeWrapper::Concrete(AbsType::Arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+                Box::new(a.clone()),
+            ));
+            type_check_(state, envs.clone(), strict, f, f_type)?;
+
+            TypeWrapper::Concrete(AbsType::Arrow(Box::new(a.clone()), Box::new(a)))
+        }
         // forall a b. a -> b -> b
         UnaryOp::Seq() | UnaryOp::DeepSeq() => {
             let fst = TypeWrapper::Ptr(new_var(state.table));
@@ -1363,29 +2443,151 @@ pub fn get_uop_type(
                 ))),
             ))
         }
-        // List -> Dyn
-        UnaryOp::ListHead() => TypeWrapper::Concrete(AbsType::Arrow(
-            Box::new(TypeWrapper::Concrete(AbsType::List())),
-            Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
-        )),
-        // List -> List
-        UnaryOp::ListTail() => TypeWrapper::Concrete(AbsType::Arrow(
-            Box::new(TypeWrapper::Concrete(AbsType::List())),
-            Box::new(TypeWrapper::Concrete(AbsType::List())),
-        )),
-        // List -> Num
+        // forall a. List a -> a
+        UnaryOp::ListHead() => {
+            let elt = TypeWrapper::Ptr(new_var(state.table));
+
+            TypeWrapper::Concrete(AbsType::Arrow(
+                Box::new(TypeWrapper::list_of(elt.clone())),
+                Box::new(elt),
+            ))
+        }
+        // forall a. List a -> List a
+        UnaryOp::ListTail() | UnaryOp::ListUnique() => {
+            let list = TypeWrapper::list_of(TypeWrapper::Ptr(new_var(state.table)));
+
+            TypeWrapper::Concrete(AbsType::Arrow(Box::new(list.clone()), Box::new(list)))
+        }
+        // forall a. List a -> Num
         UnaryOp::ListLength() => TypeWrapper::Concrete(AbsType::Arrow(
-            Box::new(TypeWrapper::Concrete(AbsType::List())),
+            Box::new(TypeWrapper::list_of(TypeWrapper::Ptr(new_var(state.table)))),
             Box::new(TypeWrapper::Concrete(AbsType::Num())),
         )),
+        // forall a b c. (a -> b -> c) -> List a -> List b -> List c
+        UnaryOp::ListZipWith() => {
+            let fst = TypeWrapper::Ptr(new_var(state.table));
+            let snd = TypeWrapper::Ptr(new_var(state.table));
+            let tgt = TypeWrapper::Ptr(new_var(state.table));
+            let f = TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(fst.clone()),
+                Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                    Box::new(snd.clone()),
+                    Box::new(tgt.clone()),
+                ))),
+            ));
+
+            TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(f),
+                Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                    Box::new(TypeWrapper::list_of(fst)),
+                    Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                        Box::new(TypeWrapper::list_of(snd)),
+                        Box::new(TypeWrapper::list_of(tgt)),
+                    ))),
+                ))),
+            ))
+        }
+        // Num -> Num -> Num -> List Num
+        UnaryOp::ListRange() => TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::Num())),
+            Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::Num())),
+                Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                    Box::new(TypeWrapper::Concrete(AbsType::Num())),
+                    Box::new(TypeWrapper::list_of(TypeWrapper::Concrete(AbsType::Num()))),
+                ))),
+            ))),
+        )),
+        // forall a. Num -> (a -> a) -> a -> a
+        UnaryOp::Iterate() => {
+            let a = TypeWrapper::Ptr(new_var(state.table));
+            let f = TypeWrapper::Concrete(AbsType::arrow(Box::new(a.clone()), Box::new(a.clone())));
+
+            TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::Num())),
+                Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                    Box::new(f),
+                    Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                        Box::new(a.clone()),
+                        Box::new(a),
+                    ))),
+                ))),
+            ))
+        }
         // This should not happen, as ChunksConcat() is only produced during evaluation.
-        UnaryOp::ChunksConcat(_, _) => panic!("cannot type ChunksConcat()"),
-        // forall rows. { rows } -> List
+        UnaryOp::ChunksConcat(_, _) => {
+            return Err(TypecheckError::InternalError(
+                String::from("cannot type ChunksConcat()"),
+                None,
+            ))
+        }
+        // forall rows. { rows } -> List Str
         UnaryOp::FieldsOf() => TypeWrapper::Concrete(AbsType::arrow(
             Box::new(TypeWrapper::Concrete(AbsType::StaticRecord(Box::new(
                 TypeWrapper::Ptr(new_var(state.table)),
             )))),
-            Box::new(TypeWrapper::Concrete(AbsType::List())),
+            Box::new(TypeWrapper::list_of(TypeWrapper::Concrete(AbsType::Str()))),
+        )),
+        // forall a. Str -> Str -> {_: a} -> {_: a}
+        UnaryOp::RecordRenameField() => {
+            let res = TypeWrapper::Ptr(new_var(state.table));
+
+            TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::Str())),
+                Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                    Box::new(TypeWrapper::Concrete(AbsType::Str())),
+                    Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                        Box::new(TypeWrapper::Concrete(AbsType::DynRecord(Box::new(
+                            res.clone(),
+                        )))),
+                        Box::new(TypeWrapper::Concrete(AbsType::DynRecord(Box::new(res)))),
+                    ))),
+                ))),
+            ))
+        }
+        // forall rows. < rows > -> Str
+        UnaryOp::EnumToStr() => TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::Enum(Box::new(
+                TypeWrapper::Ptr(new_var(state.table)),
+            )))),
+            Box::new(TypeWrapper::Concrete(AbsType::Str())),
+        )),
+        // Str -> < tags >
+        UnaryOp::EnumFromStr(tags) => {
+            let row =
+                tags.iter()
+                    .rev()
+                    .fold(TypeWrapper::Concrete(AbsType::RowEmpty()), |row, tag| {
+                        TypeWrapper::Concrete(AbsType::RowExtend(tag.clone(), None, Box::new(row)))
+                    });
+            TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::Str())),
+                Box::new(TypeWrapper::Concrete(AbsType::Enum(Box::new(row)))),
+            ))
+        }
+        // Str -> Str
+        UnaryOp::PathBasename() | UnaryOp::PathExtension() => {
+            TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::Str())),
+                Box::new(TypeWrapper::Concrete(AbsType::Str())),
+            ))
+        }
+        // forall rows. { rows } -> List
+        UnaryOp::FieldsWithMetadata() => TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::StaticRecord(Box::new(
+                TypeWrapper::Ptr(new_var(state.table)),
+            )))),
+            Box::new(TypeWrapper::list()),
+        )),
+        // Dyn -> Dyn
+        UnaryOp::FieldMetadata(_) => TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+            Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+        )),
+        // forall a. List a -> Dyn
+        UnaryOp::HostCall(_) => TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::list_of(TypeWrapper::Ptr(new_var(state.table)))),
+            Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
         )),
     })
 }
@@ -1450,6 +2652,20 @@ pub fn get_bop_type(
                 ))),
             )))
         }
+        // forall a. Str -> { _ : a } -> Dyn
+        //
+        // The fields of the result depend on whether the field exists.
+        BinaryOp::DynAccessOpt() => {
+            let res = TypeWrapper::Ptr(new_var(state.table));
+
+            Ok(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::Str())),
+                Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                    Box::new(TypeWrapper::Concrete(AbsType::DynRecord(Box::new(res)))),
+                    Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+                ))),
+            )))
+        }
         // Str -> { _ : a } -> { _ : a }
         // Unify t with a.
         BinaryOp::DynExtend(t) => {
@@ -1485,52 +2701,226 @@ pub fn get_bop_type(
                 ))),
             )))
         }
+        // List Str -> Dyn -> Dyn
+        //
+        // The record is traversed at an arbitrary depth, so its type can't be related to the one
+        // of the result, whose fields also depend on whether the path exists.
+        BinaryOp::RecordGetPath() => Ok(TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::list_of(TypeWrapper::Concrete(AbsType::Str()))),
+            Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+                Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+            ))),
+        ))),
+        // forall a b. {_: a} -> {_: b} -> {_: a}
+        BinaryOp::RecordIntersect() | BinaryOp::RecordDifference() => {
+            let res = TypeWrapper::Ptr(new_var(state.table));
+            let other = TypeWrapper::Ptr(new_var(state.table));
+
+            Ok(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::DynRecord(Box::new(
+                    res.clone(),
+                )))),
+                Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                    Box::new(TypeWrapper::Concrete(AbsType::DynRecord(Box::new(other)))),
+                    Box::new(TypeWrapper::Concrete(AbsType::DynRecord(Box::new(res)))),
+                ))),
+            )))
+        }
+        // forall rows. Str -> { rows } -> List Str
+        BinaryOp::RecordFindFields() => Ok(TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::Str())),
+            Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::StaticRecord(Box::new(
+                    TypeWrapper::Ptr(new_var(state.table)),
+                )))),
+                Box::new(TypeWrapper::list_of(TypeWrapper::Concrete(AbsType::Str()))),
+            ))),
+        ))),
         // Str -> Dyn -> Bool
         BinaryOp::HasField() => Ok(TypeWrapper::Concrete(AbsType::Arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::Str())),
             Box::new(TypeWrapper::Concrete(AbsType::Arrow(
-                Box::new(TypeWrapper::Concrete(AbsType::Str())),
                 Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+                Box::new(TypeWrapper::Concrete(AbsType::Bool())),
             ))),
-            Box::new(TypeWrapper::Concrete(AbsType::Bool())),
         ))),
-        // List -> List -> List
-        BinaryOp::ListConcat() => Ok(TypeWrapper::Concrete(AbsType::Arrow(
-            Box::new(TypeWrapper::Concrete(AbsType::List())),
-            Box::new(TypeWrapper::Concrete(AbsType::Arrow(
-                Box::new(TypeWrapper::Concrete(AbsType::List())),
-                Box::new(TypeWrapper::Concrete(AbsType::List())),
+        // forall a. a -> Dyn -> Dyn
+        BinaryOp::WithArgPos() => Ok(TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Ptr(new_var(state.table))),
+            Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+                Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+            ))),
+        ))),
+        // Str -> Dyn -> Dyn
+        BinaryOp::WithTag() => Ok(TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::Str())),
+            Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+                Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
             ))),
         ))),
-        // forall a b. (a -> b) -> List -> List
+        // Dyn -> Dyn -> Dyn
+        BinaryOp::RecordContract(..) => Ok(TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+            Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+                Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
+            ))),
+        ))),
+        // forall a. List a -> List a -> List a
+        BinaryOp::ListConcat() => {
+            let list = TypeWrapper::list_of(TypeWrapper::Ptr(new_var(state.table)));
+
+            Ok(TypeWrapper::Concrete(AbsType::Arrow(
+                Box::new(list.clone()),
+                Box::new(TypeWrapper::Concrete(AbsType::Arrow(
+                    Box::new(list.clone()),
+                    Box::new(list),
+                ))),
+            )))
+        }
+        // forall a b. (a -> b) -> List a -> List b
         BinaryOp::ListMap() => {
             let src = TypeWrapper::Ptr(new_var(state.table));
             let tgt = TypeWrapper::Ptr(new_var(state.table));
-            let arrow = TypeWrapper::Concrete(AbsType::Arrow(Box::new(src), Box::new(tgt)));
+            let arrow =
+                TypeWrapper::Concrete(AbsType::Arrow(Box::new(src.clone()), Box::new(tgt.clone())));
+
+            Ok(TypeWrapper::Concrete(AbsType::Arrow(
+                Box::new(arrow),
+                Box::new(TypeWrapper::Concrete(AbsType::Arrow(
+                    Box::new(TypeWrapper::list_of(src)),
+                    Box::new(TypeWrapper::list_of(tgt)),
+                ))),
+            )))
+        }
+        // forall a b. List a -> List b -> List List
+        BinaryOp::ListZip() => Ok(TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::list_of(TypeWrapper::Ptr(new_var(state.table)))),
+            Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::list_of(TypeWrapper::Ptr(new_var(state.table)))),
+                Box::new(TypeWrapper::list_of(TypeWrapper::list())),
+            ))),
+        ))),
+        // forall a. (a -> Str) -> List a -> {_: List a}
+        BinaryOp::ListGroupBy() => {
+            let elt = TypeWrapper::Ptr(new_var(state.table));
+            let key = TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(elt.clone()),
+                Box::new(TypeWrapper::Concrete(AbsType::Str())),
+            ));
+
+            Ok(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(key),
+                Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                    Box::new(TypeWrapper::list_of(elt.clone())),
+                    Box::new(TypeWrapper::Concrete(AbsType::DynRecord(Box::new(
+                        TypeWrapper::list_of(elt),
+                    )))),
+                ))),
+            )))
+        }
+        // forall a. (a -> Bool) -> List a -> {matched: List a, rest: List a}
+        BinaryOp::ListPartition() => {
+            let elt = TypeWrapper::Ptr(new_var(state.table));
+            let pred = TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(elt.clone()),
+                Box::new(TypeWrapper::Concrete(AbsType::Bool())),
+            ));
+            let row = ["rest", "matched"].iter().fold(
+                TypeWrapper::Concrete(AbsType::RowEmpty()),
+                |row, id| {
+                    TypeWrapper::Concrete(AbsType::RowExtend(
+                        Ident::from(*id),
+                        Some(Box::new(TypeWrapper::list_of(elt.clone()))),
+                        Box::new(row),
+                    ))
+                },
+            );
+
+            Ok(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(pred),
+                Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                    Box::new(TypeWrapper::list_of(elt)),
+                    Box::new(TypeWrapper::Concrete(AbsType::StaticRecord(Box::new(row)))),
+                ))),
+            )))
+        }
+        // forall a. (a -> a -> <Lesser, Equal, Greater>) -> List a -> List a
+        BinaryOp::ListSort() => {
+            let elt = TypeWrapper::Ptr(new_var(state.table));
+            let ordering = ["Greater", "Equal", "Lesser"].iter().fold(
+                TypeWrapper::Concrete(AbsType::RowEmpty()),
+                |row, tag| {
+                    TypeWrapper::Concrete(AbsType::RowExtend(
+                        Ident::from(*tag),
+                        None,
+                        Box::new(row),
+                    ))
+                },
+            );
+            let cmp = TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(elt.clone()),
+                Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                    Box::new(elt.clone()),
+                    Box::new(TypeWrapper::Concrete(AbsType::Enum(Box::new(ordering)))),
+                ))),
+            ));
+            let list = TypeWrapper::list_of(elt);
+
+            Ok(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(cmp),
+                Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                    Box::new(list.clone()),
+                    Box::new(list),
+                ))),
+            )))
+        }
+        // forall a b. (a -> b) -> List a -> List a
+        BinaryOp::ListSortBy() => {
+            let src = TypeWrapper::Ptr(new_var(state.table));
+            let tgt = TypeWrapper::Ptr(new_var(state.table));
+            let key = TypeWrapper::Concrete(AbsType::arrow(Box::new(src.clone()), Box::new(tgt)));
+            let list = TypeWrapper::list_of(src);
+
+            Ok(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(key),
+                Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                    Box::new(list.clone()),
+                    Box::new(list),
+                ))),
+            )))
+        }
+        // forall a. List a -> Num -> a
+        BinaryOp::ListElemAt() => {
+            let elt = TypeWrapper::Ptr(new_var(state.table));
 
             Ok(TypeWrapper::Concrete(AbsType::Arrow(
-                Box::new(arrow),
+                Box::new(TypeWrapper::list_of(elt.clone())),
                 Box::new(TypeWrapper::Concrete(AbsType::Arrow(
-                    Box::new(TypeWrapper::Concrete(AbsType::List())),
-                    Box::new(TypeWrapper::Concrete(AbsType::List())),
+                    Box::new(TypeWrapper::Concrete(AbsType::Num())),
+                    Box::new(elt),
                 ))),
             )))
         }
-        // List -> Num -> Dyn
-        BinaryOp::ListElemAt() => Ok(TypeWrapper::Concrete(AbsType::Arrow(
-            Box::new(TypeWrapper::Concrete(AbsType::List())),
-            Box::new(TypeWrapper::Concrete(AbsType::Arrow(
-                Box::new(TypeWrapper::Concrete(AbsType::Num())),
-                Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
-            ))),
-        ))),
         // Dyn -> Dyn -> Dyn
-        BinaryOp::Merge() => Ok(TypeWrapper::Concrete(AbsType::arrow(
+        BinaryOp::Merge() | BinaryOp::MergeOverride() => Ok(TypeWrapper::Concrete(AbsType::arrow(
             Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
             Box::new(TypeWrapper::Concrete(AbsType::arrow(
                 Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
                 Box::new(TypeWrapper::Concrete(AbsType::Dyn())),
             ))),
         ))),
+        // Str -> Str -> Str
+        BinaryOp::PathJoin() => Ok(TypeWrapper::Concrete(AbsType::arrow(
+            Box::new(TypeWrapper::Concrete(AbsType::Str())),
+            Box::new(TypeWrapper::Concrete(AbsType::arrow(
+                Box::new(TypeWrapper::Concrete(AbsType::Str())),
+                Box::new(TypeWrapper::Concrete(AbsType::Str())),
+            ))),
+        ))),
     }
 }
 
@@ -1539,7 +2929,40 @@ pub fn get_bop_type(
 /// Map each unification variable to either another type variable or a concrete type it has been
 /// unified with. Each binding `(ty, var)` in this map should be thought of an edge in a
 /// unification graph.
-pub type UnifTable = HashMap<usize, Option<TypeWrapper>>;
+///
+/// Unification variables and type constants are also tagged with a level, which is the number of
+/// enclosing scopes -- the body of a `forall` or the bound expression of a let-binding -- at the
+/// point they were created. A variable can't be unified with a type containing a constant of a
+/// higher level, as the constant would then escape the scope of its `forall`. Conversely, when a
+/// variable is unified with a type, the free variables of this type are brought down to the level
+/// of the variable. A variable whose level is still higher than the current one when leaving the
+/// bound expression of a let-binding is thus not reachable from the enclosing scope, which is what
+/// allows to generalize it (see [`generalize_let`](fn.generalize_let.html)).
+#[derive(Clone, Debug, Default)]
+pub struct UnifTable {
+    /// The value of each unification variable, indexed by its number.
+    types: Vec<Option<TypeWrapper>>,
+    /// The level of each unification variable or type constant, indexed by its number.
+    levels: Vec<usize>,
+    /// The current level.
+    level: usize,
+}
+
+impl UnifTable {
+    pub fn new() -> Self {
+        UnifTable::default()
+    }
+
+    /// Enter a new scope: the variables and constants created from now on have a higher level.
+    fn enter_level(&mut self) {
+        self.level += 1;
+    }
+
+    /// Leave the current scope.
+    fn leave_level(&mut self) {
+        self.level -= 1;
+    }
+}
 
 /// Row constraints.
 ///
@@ -1549,19 +2972,61 @@ pub type UnifTable = HashMap<usize, Option<TypeWrapper>>;
 /// String}`.
 pub type RowConstr = HashMap<usize, HashSet<Ident>>;
 
-/// Create a fresh unification variable.
+/// Create a fresh unification variable at the current level.
+///
+/// This is also used to number type constants, which share the same namespace.
 fn new_var(table: &mut UnifTable) -> usize {
-    let next = table.len();
-    table.insert(next, None);
+    let next = table.types.len();
+    table.types.push(None);
+    table.levels.push(table.level);
     next
 }
 
+/// Assign a type to a unification variable.
+///
+/// Fail if the type contains a type constant of a higher level than the variable, and bring the
+/// free unification variables of the type down to the level of the variable otherwise. See
+/// [`UnifTable`](./struct.UnifTable.html).
+fn assign(table: &mut UnifTable, p: usize, tyw: TypeWrapper) -> Result<(), UnifError> {
+    let level = table.levels[p];
+    lower_levels(table, level, &tyw).map_err(|c| UnifError::ConstEscape(c, tyw.clone()))?;
+    table.types[p] = Some(tyw);
+    Ok(())
+}
+
+/// Bring the free unification variables of a type down to a given level.
+///
+/// Return the first type constant of a higher level encountered, if any.
+fn lower_levels(table: &mut UnifTable, level: usize, tyw: &TypeWrapper) -> Result<(), usize> {
+    match tyw {
+        TypeWrapper::Ptr(p) => match get_root(table, *p) {
+            Ok(TypeWrapper::Ptr(root)) => {
+                table.levels[root] = table.levels[root].min(level);
+                Ok(())
+            }
+            Ok(tyw) => lower_levels(table, level, &tyw),
+            Err(_) => Ok(()),
+        },
+        TypeWrapper::Constant(c) if table.levels[*c] > level => Err(*c),
+        TypeWrapper::Constant(_) => Ok(()),
+        TypeWrapper::Concrete(t) => {
+            let mut result = Ok(());
+            t.clone().map(|btyw| {
+                if result.is_ok() {
+                    result = lower_levels(table, level, &btyw);
+                }
+            });
+            result
+        }
+    }
+}
+
 /// Add a row constraint on a type.
 ///
 /// See [`RowConstr`](type.RowConstr.html).
 fn constraint(state: &mut State, x: TypeWrapper, id: Ident) -> Result<(), RowUnifError> {
     match x {
-        TypeWrapper::Ptr(p) => match get_root(state.table, p) {
+        TypeWrapper::Ptr(p) => match get_root(state.table, p)? {
             ty @ TypeWrapper::Concrete(_) => constraint(state, ty, id),
             TypeWrapper::Ptr(root) => {
                 if let Some(v) = state.constr.get_mut(&root) {
@@ -1590,15 +3055,19 @@ fn constraint(state: &mut State, x: TypeWrapper, id: Ident) -> Result<(), RowUni
 ///
 /// This corresponds to the find in union-find.
 // TODO This should be a union find like algorithm
-pub fn get_root(table: &UnifTable, x: usize) -> TypeWrapper {
+pub fn get_root(table: &UnifTable, x: usize) -> Result<TypeWrapper, UnifError> {
     // All queried variable must have been introduced by `new_var` and thus a corresponding entry
     // must always exist in `state`. If not, the typechecking algorithm is not correct, and we
-    // panic.
-    match table.get(&x).unwrap() {
-        None => TypeWrapper::Ptr(x),
-        Some(TypeWrapper::Ptr(y)) => get_root(table, *y),
-        Some(ty @ TypeWrapper::Concrete(_)) => ty.clone(),
-        Some(k @ TypeWrapper::Constant(_)) => k.clone(),
+    // return an internal error.
+    match table.types.get(x) {
+        None => Err(UnifError::InternalError(format!(
+            "typechecker::get_root(): unknown unification variable {}",
+            x
+        ))),
+        Some(None) => Ok(TypeWrapper::Ptr(x)),
+        Some(Some(TypeWrapper::Ptr(y))) => get_root(table, *y),
+        Some(Some(ty @ TypeWrapper::Concrete(_))) => Ok(ty.clone()),
+        Some(Some(k @ TypeWrapper::Constant(_))) => Ok(k.clone()),
     }
 }
 
@@ -1615,7 +3084,7 @@ mod tests {
     use crate::parser;
 
     fn type_check_no_import(rt: &RichTerm) -> Result<Types, TypecheckError> {
-        type_check_in_env(rt, &Environment::new(), &mut DummyResolver {})
+        type_check_in_env(rt, &Environment::default(), &mut DummyResolver {}, None)
     }
 
     fn parse_and_typecheck(s: &str) -> Result<Types, TypecheckError> {
@@ -1658,6 +3127,55 @@ mod tests {
         type_check_no_import(&RichTerm::var(String::from("x"))).unwrap_err();
     }
 
+    #[test]
+    fn internal_errors() {
+        let table = UnifTable::new();
+        match get_root(&table, 0) {
+            Err(UnifError::InternalError(_)) => (),
+            res => panic!("expected an internal error, got {:?}", res),
+        };
+
+        let err = UnifError::DomainMismatch(
+            TypeWrapper::Concrete(AbsType::Num()),
+            TypeWrapper::Concrete(AbsType::Bool()),
+            Box::new(UnifError::ConstMismatch(0, 1)),
+        );
+        assert_eq!(err.to_type_path(), None);
+
+        let mut resolver = DummyResolver {};
+        let state = State {
+            resolver: &mut resolver,
+            table: &mut UnifTable::new(),
+            constr: &mut RowConstr::default(),
+            names: &mut HashMap::default(),
+            recorded: None,
+            cancel: None,
+            annotations: None,
+        };
+        let err = UnifError::CodomainMismatch(
+            TypeWrapper::Concrete(AbsType::Num()),
+            TypeWrapper::Concrete(AbsType::Bool()),
+            Box::new(UnifError::ConstMismatch(0, 1)),
+        );
+        match err.to_typecheck_err(&state, &None) {
+            TypecheckError::InternalError(_, _) => (),
+            err => panic!("expected an internal error, got {:?}", err),
+        };
+    }
+
+    #[test]
+    fn illformed_annotations() {
+        parse_and_typecheck("Assume(a, 1)").unwrap_err();
+        parse_and_typecheck("Assume(forall a. a -> b, fun x => x)").unwrap_err();
+        parse_and_typecheck("ContractDefault(< (| foo, | r) >, `foo)").unwrap_err();
+        parse_and_typecheck("Contract({ {| a: Num, | r} })").unwrap_err();
+        parse_and_typecheck("Assume(< {| a: Num, |} >, `a)").unwrap_err();
+        parse_and_typecheck("Assume({ (| a, |) }, { a = 1 })").unwrap_err();
+
+        parse_and_typecheck("Assume(forall a. a -> a, fun x => x)").unwrap();
+        parse_and_typecheck("Contract(forall r. { {| a: Num, | r} })").unwrap();
+    }
+
     #[test]
     fn promise_simple_checks() {
         type_check_no_import(
@@ -1768,6 +3286,23 @@ mod tests {
         .unwrap();
         // Only if they're named the same way
         parse_and_typecheck("Promise(#(fun l t => t) -> #(fun l t => t), fun x => x)").unwrap_err();
+
+        // Contracts can be applied to literals
+        parse_and_typecheck(
+            "let Between = fun min max l t => t in
+        Promise(#(Between 1 10) -> #(Between 1 10), fun x => x)",
+        )
+        .unwrap();
+        parse_and_typecheck(
+            "let Between = fun min max l t => t in
+        Promise(#(Between 1 10) -> #(Between 1 11), fun x => x)",
+        )
+        .unwrap_err();
+        parse_and_typecheck(
+            "let Between = fun min max l t => t in let x = 1 in
+        Promise(#(Between x 10) -> #(Between x 10), fun x => x)",
+        )
+        .unwrap_err();
     }
 
     #[test]
@@ -1796,20 +3331,102 @@ mod tests {
         )
         .unwrap_err();
 
+        parse_and_typecheck(
+            "let g = Promise(Num -> Num, fun x => x) in
+        let f = Promise(forall a. a -> a, fun x =>  g x) in
+        f",
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn multi_argument_functions() {
+        parse_and_typecheck("Promise(Num -> Num -> Num, fun x y => x + y)").unwrap();
+        parse_and_typecheck("Promise(Num -> Str -> Num, fun x y => y)").unwrap_err();
+        parse_and_typecheck("Promise(Num -> Num, fun x y => x)").unwrap_err();
+        parse_and_typecheck("Promise(forall a b. a -> b -> a, fun x y => x)").unwrap();
+        parse_and_typecheck("Promise(forall a b. a -> b -> a, fun x y => y)").unwrap_err();
+        parse_and_typecheck("Promise(((forall a. a -> a) -> Num -> Num), fun f n => f (f n))")
+            .unwrap();
+    }
+
+    #[test]
+    fn annotation_instantiation() {
+        // Polymorphic annotations are instantiated where they are used, as variables are.
+        parse_and_typecheck(
+            "let lib = {id = fun x => x} in Promise(Num, (Assume(forall a. a -> a, lib.id)) 1)",
+        )
+        .unwrap();
+        parse_and_typecheck("Promise(Str, (Promise(forall a. a -> a, fun x => x)) \"a\")").unwrap();
+        parse_and_typecheck(
+            "let lib = {id = fun x => x} in Promise(Str, (Assume(forall a. a -> a, lib.id)) 1)",
+        )
+        .unwrap_err();
+
+        // Against a polymorphic type, the annotation must be at least as polymorphic.
+        parse_and_typecheck("Promise(forall a. a -> a, Assume(forall b. b -> b, fun x => x))")
+            .unwrap();
+        parse_and_typecheck("Promise(forall a. a -> a, Assume(Num -> Num, fun x => x))")
+            .unwrap_err();
+        parse_and_typecheck("Promise(forall a. a -> a, Assume(forall b c. b -> c, fun x => x))")
+            .unwrap();
+    }
+
+    #[test]
+    fn higher_rank() {
+        // A polymorphic argument.
         parse_and_typecheck(
             "Promise(
                 ((forall a. a -> a) -> Num) -> Num,
                 fun f => let g = Promise(forall b. b -> b, fun y => y) in f g)
             (fun x => 3)",
         )
+        .unwrap();
+        parse_and_typecheck("Promise(((forall a. a -> a) -> Num) -> Num, fun f => f (fun x => x))")
+            .unwrap();
+        parse_and_typecheck(
+            "Promise((forall a. a -> a) -> Num, fun f => if f true then f 1 else 0)",
+        )
+        .unwrap();
+        parse_and_typecheck("Promise(((forall a. a -> a) -> Num) -> Num, fun f => f (fun x => 1))")
+            .unwrap_err();
+
+        // Nested quantifiers in argument position.
+        parse_and_typecheck(
+            "Promise(
+                (((forall a. (forall b. b -> b) -> a -> a) -> Num) -> Num),
+                fun f => f (fun g x => g x))",
+        )
+        .unwrap();
+        parse_and_typecheck(
+            "Promise(
+                (((forall a. (forall b. b -> b) -> a -> a) -> Num) -> Num),
+                fun f => f (fun g x => g 1))",
+        )
         .unwrap_err();
 
+        // Deep skolemization: quantifiers in the codomain of an arrow.
         parse_and_typecheck(
-            "let g = Promise(Num -> Num, fun x => x) in
-        let f = Promise(forall a. a -> a, fun x =>  g x) in
-        f",
+            "let k = Promise(forall a. Num -> a -> a, fun n x => x) in
+            Promise(Num -> (forall a. a -> a), k)",
+        )
+        .unwrap();
+        parse_and_typecheck(
+            "let k = Promise(forall a. Num -> a -> a, fun n x => x) in
+            Promise(((Num -> (forall a. a -> a)) -> Num) -> Num, fun f => f k)",
+        )
+        .unwrap();
+        parse_and_typecheck(
+            "let k = Promise(Num -> Num -> Num, fun n x => x) in
+            Promise(Num -> (forall a. a -> a), k)",
         )
         .unwrap_err();
+
+        // The type constants do not escape their scope.
+        let res = parse_and_typecheck(
+            "fun y => Promise(((forall a. a -> a) -> Num) -> Num, fun f => f (fun x => y))",
+        );
+        assert!(matches!(res, Err(TypecheckError::ConstantEscape(..))));
     }
 
     #[test]
@@ -1849,16 +3466,46 @@ mod tests {
         parse_and_typecheck("Promise(Num, switch { bla => 3, ble => true, } `bla)").unwrap_err();
     }
 
+    #[test]
+    fn enum_str_conversions() {
+        parse_and_typecheck("Promise(Str, enumToStr `foo)").unwrap();
+        parse_and_typecheck(
+            "Promise(< (| foo, bar, |) > -> Str, fun x => enumToStr (embed baz x))",
+        )
+        .unwrap();
+        parse_and_typecheck("Promise(< (| foo, bar, |) >, enumFromStr <(| foo, bar, |)> \"foo\")")
+            .unwrap();
+        parse_and_typecheck(
+            "Promise(Num, switch { foo => 1, bar => 2, } (enumFromStr <(| foo, bar, |)> \"foo\"))",
+        )
+        .unwrap();
+
+        parse_and_typecheck("Promise(Str, enumToStr 1)").unwrap_err();
+        parse_and_typecheck("Promise(Str, typeOf (fun x => x))").unwrap();
+        parse_and_typecheck("Promise(Num, typeOf 1)").unwrap_err();
+        parse_and_typecheck("Promise(Str, labelTag (mkLabel \"a\"))").unwrap();
+        parse_and_typecheck("Promise(List, labelPath (mkLabel \"a\"))").unwrap();
+        parse_and_typecheck("Promise(Dyn, mkLabel 1)").unwrap_err();
+        parse_and_typecheck("Promise(< (| foo, |) >, enumFromStr <(| foo, bar, |)> \"foo\")")
+            .unwrap_err();
+    }
+
     #[test]
     fn enum_complex() {
         parse_and_typecheck(
             "Promise(< (| bla, ble, |) > -> Num, fun x => switch {bla => 1, ble => 2,} x)",
         )
         .unwrap();
+        // The closed enum type of `x` is widened to the labels of the switch.
         parse_and_typecheck(
             "Promise(< (| bla, ble, |) > -> Num,
         fun x => switch {bla => 1, ble => 2, bli => 4,} x)",
         )
+        .unwrap();
+        parse_and_typecheck(
+            "Promise(< (| bla, ble, bli, |) > -> Num,
+        fun x => switch {bla => 1, ble => 2,} x)",
+        )
         .unwrap_err();
         parse_and_typecheck(
             "Promise(< (| bla, ble, |) > -> Num,
@@ -1873,7 +3520,8 @@ mod tests {
                 (switch {bli => 6, bla => 20,} x) ) `bla)",
         )
         .unwrap();
-        // TODO typecheck this, I'm not sure how to do it with row variables
+        // A switch without a default case only accepts its own labels: the union of the labels of
+        // both switches would not be handled by either of them.
         parse_and_typecheck(
             "Promise(Num,
             (fun x =>
@@ -1913,6 +3561,43 @@ mod tests {
         .unwrap_err();
     }
 
+    #[test]
+    fn enum_widening() {
+        parse_and_typecheck(
+            "let x = Promise(< (| bla, |) >, `bla) in
+            Promise(< (| bla, blo, |) >, x)",
+        )
+        .unwrap();
+        parse_and_typecheck("Promise(< (| bla, blo, |) >, Promise(< (| bla, |) >, `bla))").unwrap();
+        parse_and_typecheck(
+            "let f = Promise(< (| bla, blo, |) > -> Num, fun x => switch {bla => 1, blo => 2,} x) in
+            let x = Promise(< (| bla, |) >, `bla) in
+            Promise(Num, f x)",
+        )
+        .unwrap();
+
+        parse_and_typecheck(
+            "let x = Promise(< (| bla, bli, |) >, `bla) in
+            Promise(< (| bla, blo, |) >, x)",
+        )
+        .unwrap_err();
+        parse_and_typecheck(
+            "let x = Promise(< (| bla, blo, |) >, `bla) in
+            Promise(< (| bla, |) >, x)",
+        )
+        .unwrap_err();
+
+        // Open enum types are not widened.
+        parse_and_typecheck(
+            "Promise(forall r. < (| bla, | r) > -> < (| bla, blo, | r) >, fun x => x)",
+        )
+        .unwrap_err();
+        parse_and_typecheck(
+            "Promise(forall r. < (| bla, | r) > -> < (| bla, blo, | r) >, fun x => embed blo x)",
+        )
+        .unwrap();
+    }
+
     #[test]
     fn static_record_simple() {
         parse_and_typecheck("Promise({ {| bla : Num, |} }, { bla = 1; })").unwrap();
@@ -1988,6 +3673,26 @@ mod tests {
         parse_and_typecheck("Promise( { _ : Num}, { foo = 3; bar = 4; })").unwrap();
     }
 
+    #[test]
+    fn record_get_path() {
+        parse_and_typecheck(
+            "let r = { a = { b = 1 } } in Promise(Dyn, recordGetPath [\"a\", \"b\"] r)",
+        )
+        .unwrap();
+        parse_and_typecheck("let r = { a = 1 } in Promise(Num, recordGetPath [\"a\"] r)")
+            .unwrap_err();
+        parse_and_typecheck("let r = { a = 1 } in Promise(Dyn, recordGetPath \"a\" r)")
+            .unwrap_err();
+    }
+
+    #[test]
+    fn optional_access() {
+        parse_and_typecheck("Promise(Dyn, { foo = 1; }.$?\"foo\")").unwrap();
+
+        parse_and_typecheck("Promise(Num, { foo = 1; }.$?\"foo\")").unwrap_err();
+        parse_and_typecheck("let r = 1 in Promise(Dyn, r.$?\"foo\")").unwrap_err();
+    }
+
     #[test]
     fn seq() {
         parse_and_typecheck("Promise(Num, seq false 1)").unwrap();
@@ -1997,6 +3702,15 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn catch_blame() {
+        parse_and_typecheck("Promise(Num, catchBlame (fun l => 1) 2)").unwrap();
+        parse_and_typecheck("Promise(Str, catchBlame (fun l => labelTag l) \"a\")").unwrap();
+        parse_and_typecheck("Promise(Num, catchBlame (fun l => true) 2)").unwrap_err();
+        parse_and_typecheck("Promise(Num, catchBlame (fun l => 1) false)").unwrap_err();
+        parse_and_typecheck("Promise(Num, catchBlame 1 2)").unwrap_err();
+    }
+
     #[test]
     fn simple_list() {
         parse_and_typecheck("[1, \"2\", false]").unwrap();
@@ -2010,16 +3724,36 @@ mod tests {
         parse_and_typecheck("Promise(Num, [1, 2, \"3\"])").unwrap_err();
     }
 
+    #[test]
+    fn typed_list() {
+        parse_and_typecheck("Promise(List Num, [1, 2, 3])").unwrap();
+        parse_and_typecheck("Promise(List (List Str), [[\"a\"], []])").unwrap();
+        parse_and_typecheck("Promise(List Num, let l = [1, 2] in l @ [3])").unwrap();
+        parse_and_typecheck("Promise(forall a. a -> List a, fun x => [x, x])").unwrap();
+
+        parse_and_typecheck("Promise(List Num, [1, \"a\"])").unwrap_err();
+        parse_and_typecheck("Promise(List, let l = [1, 2] in l)").unwrap_err();
+        parse_and_typecheck("Promise(Num, let l = [1, true] in 0)").unwrap_err();
+        parse_and_typecheck("Promise(forall a. a -> List Num, fun x => [1, x])").unwrap_err();
+    }
+
     #[test]
     fn lists_operations() {
         parse_and_typecheck("Promise(List -> List, fun l => tail l)").unwrap();
         parse_and_typecheck("Promise(List -> Dyn, fun l => head l)").unwrap();
         parse_and_typecheck(
-            "Promise(forall a. (forall b. (a -> b) -> List -> List), fun f l => map f l)",
+            "Promise(forall a. (forall b. (a -> b) -> List a -> List b), fun f l => map f l)",
         )
         .unwrap();
         parse_and_typecheck("Promise(List -> List -> List, fun l1 => fun l2 => l1 @ l2)").unwrap();
         parse_and_typecheck("Promise(Num -> List -> Dyn , fun i l => elemAt l i)").unwrap();
+        parse_and_typecheck("Promise(List Num -> Num, fun l => head l)").unwrap();
+        parse_and_typecheck("Promise(Num, elemAt (map (fun x => x + 1) [1, 2]) 0)").unwrap();
+        parse_and_typecheck("Promise(forall a. List a -> List a, fun l => tail l)").unwrap();
+
+        parse_and_typecheck("Promise(List Str -> Num, fun l => head l)").unwrap_err();
+        parse_and_typecheck("Promise(Str, elemAt (map (fun x => x + 1) [1, 2]) 0)").unwrap_err();
+        parse_and_typecheck("Promise(List Num, map (fun x => x) [\"a\"])").unwrap_err();
 
         parse_and_typecheck("Promise(forall a. (List -> a), fun l => head l)").unwrap_err();
         parse_and_typecheck(
@@ -2028,6 +3762,15 @@ mod tests {
         .unwrap_err();
     }
 
+    #[test]
+    fn iterate() {
+        parse_and_typecheck("Promise(Num, iterate 3 (fun x => x + 1) 0)").unwrap();
+        parse_and_typecheck("Promise(forall a. (a -> a) -> a -> a, iterate 2)").unwrap();
+
+        parse_and_typecheck("Promise(Num, iterate 3 (fun x => x + 1) \"a\")").unwrap_err();
+        parse_and_typecheck("Promise(Num, iterate \"3\" (fun x => x) 0)").unwrap_err();
+    }
+
     #[test]
     fn imports() {
         let mut resolver = SimpleResolver::new();
@@ -2054,14 +3797,16 @@ mod tests {
 
         type_check_in_env(
             &mk_import("good", &mut resolver).unwrap(),
-            &Environment::new(),
+            &Environment::default(),
             &mut resolver,
+            None,
         )
         .unwrap();
         type_check_in_env(
             &mk_import("proxy", &mut resolver).unwrap(),
-            &Environment::new(),
+            &Environment::default(),
             &mut resolver,
+            None,
         )
         .unwrap_err();
     }
@@ -2099,4 +3844,183 @@ mod tests {
             "Promise({ {| f : Num -> Num, |} }, { f = fun x => if isZero x then false else 1 + (f (x + (-1)))})"
         ).unwrap_err();
     }
+
+    #[test]
+    fn apparent_types_nested() {
+        parse_and_typecheck("let x = {a = Promise(Num, 1)} in Promise(Num, x.a)").unwrap();
+        parse_and_typecheck("let x = {a = Promise(Num, 1)} in Promise(Str, x.a)").unwrap_err();
+        parse_and_typecheck("let x = let y = 1 in Promise(Num, 2) in Promise(Num, x)").unwrap();
+        parse_and_typecheck("let x = {a = {b = Assume(Str, \"s\")}} in Promise(Str, x.a.b)")
+            .unwrap();
+    }
+
+    #[test]
+    fn constant_escape() {
+        fn is_escape(res: Result<Types, TypecheckError>) -> bool {
+            match res {
+                Err(TypecheckError::ConstantEscape(..)) => true,
+                Err(TypecheckError::ArrowTypeMismatch(_, _, _, err, _)) => is_escape(Err(*err)),
+                _ => false,
+            }
+        }
+
+        assert!(is_escape(parse_and_typecheck(
+            "fun y => Promise(forall a. a -> a, fun x => if true then x else y)"
+        )));
+        // Lambda-bound variables are monomorphic
+        assert!(is_escape(parse_and_typecheck(
+            "Promise(Num, (fun f => let g = Promise(forall a. a -> a, f) in g 1) (fun x => x))"
+        )));
+        parse_and_typecheck("Promise(forall a. a -> a, let f = fun x => x in f)").unwrap();
+        parse_and_typecheck(
+            "Promise(forall a. a -> a, fun x => let y = Promise(forall b. b -> b, fun z => z) in y x)",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn let_generalization() {
+        parse_and_typecheck("Promise(Num, let id = fun x => x in seq (id \"a\") (id 5))").unwrap();
+        parse_and_typecheck(
+            "Promise(Num, let k = fun x y => x in let k1 = k 1 in k1 \"a\" + k1 true)",
+        )
+        .unwrap();
+        parse_and_typecheck(
+            "Promise(Num, let f = Promise(forall a. a -> a, fun x => x) in \
+             let g = Promise(forall a. a -> a, let h = fun x => f x in h) in g 1)",
+        )
+        .unwrap();
+
+        // The variables of the enclosing scope are not generalized.
+        parse_and_typecheck(
+            "Promise(Bool -> Num, fun y => let g = fun x => y in if g 0 then g 1 + 1 else 0)",
+        )
+        .unwrap_err();
+        parse_and_typecheck(
+            "Promise(Num, let f = fun y => let g = fun x => y in g in f 1 \"a\" + f 2 true)",
+        )
+        .unwrap();
+        parse_and_typecheck(
+            "Promise(Num, let f = fun y => let g = fun x => y in g in f \"b\" 0 + 1)",
+        )
+        .unwrap_err();
+
+        // Outside of `Promise` blocks, let-bound expressions still have the type `Dyn`.
+        parse_and_typecheck("let id = fun x => x in seq (id \"a\") (id 5)").unwrap();
+    }
+
+    #[test]
+    fn binder_annotations() {
+        // The annotations of the fields of records are used as for let-bound expressions.
+        parse_and_typecheck(
+            "Promise(Num, let r = {id = Promise(forall a. a -> a, fun x => x)} in \
+             seq (r.id \"a\") (r.id 1))",
+        )
+        .unwrap();
+        parse_and_typecheck(
+            "Promise(Num, let id = Promise(forall a. a -> a, fun x => x) in \
+             seq (id \"a\") (id 1))",
+        )
+        .unwrap();
+
+        // The fields of a recursive dictionary have the type of its elements.
+        parse_and_typecheck("Promise({_: Num}, {a = 1; b = a + 1})").unwrap();
+        parse_and_typecheck("Promise({_: Str}, {a = \"x\"; b = seq (a + 1) \"y\"})").unwrap_err();
+    }
+
+    #[test]
+    fn let_rec() {
+        parse_and_typecheck(
+            "Promise(Num, let rec f = fun n => if isZero n then 0 else f (n + -1) in f 3)",
+        )
+        .unwrap();
+        parse_and_typecheck(
+            "Promise(Num, let rec len = fun l => if isZero (length l) then 0 else 1 + len (tail l) \
+             in len [1, 2] + len [\"a\"])",
+        )
+        .unwrap();
+        parse_and_typecheck("Promise(Num, let rec f = Promise(Num -> Num, fun n => f n) in f 1)")
+            .unwrap();
+
+        parse_and_typecheck(
+            "Promise(Num, let rec f = fun n => if isZero n then 0 else f \"a\" in f 3)",
+        )
+        .unwrap_err();
+        parse_and_typecheck("Promise(Num, let rec f = Promise(Num -> Num, fun n => f) in 0)")
+            .unwrap_err();
+    }
+
+    #[test]
+    fn patterns() {
+        parse_and_typecheck("Promise(Num, let {a, b} = {a = 1; b = 2} in a + b)").unwrap();
+        parse_and_typecheck("Promise(Num, let [x, y] = [1, 2] in x + y)").unwrap();
+        parse_and_typecheck("Promise(Num, let {a = [x]} = {a = [1]} in x)").unwrap();
+        parse_and_typecheck("Promise(Num, (fun {x, y} => x + y) {x = 1; y = 2; z = \"a\"})")
+            .unwrap();
+        parse_and_typecheck("Promise(Str, let {a} = {a = 1; b = \"a\"} in a)").unwrap_err();
+
+        parse_and_typecheck("Promise(Num, let {a, b} = {a = 1; b = \"a\"} in a + b)").unwrap_err();
+        parse_and_typecheck("Promise(Num, (fun {x, y} => x + y) {x = 1})").unwrap_err();
+        parse_and_typecheck("Promise(Num, let [x, y] = [1, \"a\"] in x)").unwrap_err();
+    }
+
+    #[test]
+    fn named_result_type() {
+        let var = |name: &str| Box::new(Types(AbsType::Var(Ident(String::from(name)))));
+
+        assert_eq!(parse_and_typecheck("fun x => x"), Ok(*var("_a")));
+
+        let s = "fun x => fun y => x";
+        let rt = parser::grammar::TermParser::new()
+            .parse(Files::new().add("<test>", s), lexer::Lexer::new(s))
+            .unwrap();
+        let mut state = State {
+            resolver: &mut DummyResolver {},
+            table: &mut UnifTable::new(),
+            constr: &mut RowConstr::default(),
+            names: &mut HashMap::default(),
+            recorded: None,
+            cancel: None,
+            annotations: None,
+        };
+        let ty = TypeWrapper::Ptr(new_var(state.table));
+        type_check_(
+            &mut state,
+            Envs::from_global(&Environment::default()),
+            true,
+            &rt,
+            ty.clone(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            reporting::to_type(&state, &mut reporting::NameReg::new(), ty),
+            Types(AbsType::arrow(
+                var("_a"),
+                Box::new(Types(AbsType::arrow(var("_b"), var("_a"))))
+            ))
+        );
+    }
+
+    #[test]
+    fn cancellation() {
+        let s = "Promise(Num, let f = fun x => x + 1 in f 2)";
+        let rt = parser::grammar::TermParser::new()
+            .parse(Files::new().add("<test>", s), lexer::Lexer::new(s))
+            .unwrap();
+        let token = CancellationToken::new();
+        let check = |token| {
+            type_check_in_env(
+                &rt,
+                &Environment::default(),
+                &mut DummyResolver {},
+                Some(token),
+            )
+        };
+
+        check(&token).unwrap();
+        token.clone().cancel();
+        assert!(token.is_cancelled());
+        assert!(matches!(check(&token), Err(TypecheckError::Cancelled(..))));
+    }
 }
@@ -6,12 +6,15 @@
 //! - Bool: a boolean
 //! - Str: a string literal
 //! - Sym: a symbol, used by contracts when checking polymorphic types
-//! - List: an (heterogeneous) list
+//! - List: an heterogeneous list, whose elements may have any type
 //!
 //! # Higher-order types
 //!
 //! - `->`: the function type, or arrow
 //! - `forall a. type`: polymorphic type
+//! - `List type`: a list whose elements have the given type, as in `List #Port`. The typechecker
+//!   checks the elements of the lists in strict mode, while the contract of such a list checks
+//!   each element lazily, when it is accessed
 //! - `#customContract`: an opaque type created from an user-defined contract
 //!
 //! # Record types
@@ -51,9 +54,13 @@
 //! otherwise.  Contract checks are introduced by `Promise` and `Assume` blocks or alternatively by
 //! enriched values `Contract` or `ContractDefault`. They ensure sane interaction between typed and
 //! untyped parts.
+use crate::collections::HashMap;
+use crate::error::ParseError;
 use crate::identifier::Ident;
+use crate::parser;
+use crate::parser::lexer::Lexer;
 use crate::term::{RichTerm, Term, UnaryOp};
-use std::collections::HashMap;
+use codespan::Files;
 use std::fmt;
 
 /// A Nickel type.
@@ -95,8 +102,8 @@ pub enum AbsType<Ty> {
     /// A dynamic record type, where all fields must have the same type.
     // DynRecord will only have a default type, this is simpler for now, I don't think we lose much
     DynRecord(Ty /*, Ty  Row */),
-    /// An heterogeneous list.
-    List(),
+    /// A list, whose elements have the given type. The list type `List` is `List Dyn`.
+    List(Ty),
 }
 
 impl<Ty> AbsType<Ty> {
@@ -128,7 +135,7 @@ impl<Ty> AbsType<Ty> {
             AbsType::Enum(t) => AbsType::Enum(f(t)),
             AbsType::StaticRecord(t) => AbsType::StaticRecord(f(t)),
             AbsType::DynRecord(t) => AbsType::DynRecord(f(t)),
-            AbsType::List() => AbsType::List(),
+            AbsType::List(t) => AbsType::List(f(t)),
         }
     }
 
@@ -150,12 +157,27 @@ impl<Ty> AbsType<Ty> {
 pub struct Types(pub AbsType<Box<Types>>);
 
 impl Types {
+    /// Parse a type written in the syntax of type annotations, as in `forall a. { {| x: a |} } ->
+    /// a`.
+    ///
+    /// This is the inverse of the [`Display`](#impl-Display) implementation: formatting a type and
+    /// parsing the result gives back the same type, except for the internal type `Sym`, which has
+    /// no syntax, and for flat types whose contract is not a variable. The source is added to
+    /// `files` as a file named `<type>`, to which the positions of the contracts of flat types and
+    /// the ones of parse errors refer, such that they can be reported.
+    pub fn parse(s: &str, files: &mut Files<String>) -> Result<Types, ParseError> {
+        let file_id = files.add("<type>", String::from(s));
+        parser::grammar::InterfaceParser::new()
+            .parse(file_id, Lexer::new(s))
+            .map_err(|err| ParseError::from_lalrpop(err, file_id))
+    }
+
     /// Return the contract corresponding to a type.
     ///
     /// Wrapper for [`contract_open`](fn.contract_open.html).
     pub fn contract(&self) -> RichTerm {
         let mut sy = 0;
-        self.contract_open(HashMap::new(), true, &mut sy)
+        self.contract_open(HashMap::default(), true, &mut sy)
     }
 
     /// Return the contract corresponding to a type.
@@ -179,7 +201,13 @@ impl Types {
             AbsType::Num() => RichTerm::var("num".to_string()),
             AbsType::Bool() => RichTerm::var("bool".to_string()),
             AbsType::Str() => RichTerm::var("string".to_string()),
-            AbsType::List() => RichTerm::var("list".to_string()),
+            AbsType::List(ref ty) => match ty.0 {
+                AbsType::Dyn() => RichTerm::var("list".to_string()),
+                _ => RichTerm::app(
+                    RichTerm::var("list_of".to_string()),
+                    ty.contract_open(h, pol, sy),
+                ),
+            },
             AbsType::Sym() => panic!("Are you trying to check a Sym at runtime?"),
             AbsType::Arrow(ref s, ref t) => RichTerm::app(
                 RichTerm::app(
@@ -218,7 +246,7 @@ impl Types {
                         }
                         AbsType::RowExtend(id, None, rest) => {
                             let rest_contract = form(*rest, h);
-                            let mut map = HashMap::new();
+                            let mut map = HashMap::default();
                             map.insert(id, Term::Bool(true).into());
 
                             RichTerm::app(
@@ -249,8 +277,49 @@ impl Types {
 
                 form(*r.clone(), h)
             }
-            AbsType::StaticRecord(_) => panic!("TODO implement"),
-            AbsType::DynRecord(_) => panic!("TODO implement"),
+            AbsType::StaticRecord(ref r) => {
+                fn form(
+                    ty: Types,
+                    h: HashMap<Ident, RichTerm>,
+                    pol: bool,
+                    sy: &mut i32,
+                ) -> RichTerm {
+                    match ty.0 {
+                        AbsType::RowEmpty() => RichTerm::var("empty_tail".to_string()),
+                        AbsType::RowExtend(_, None, _) => {
+                            panic!("It should be a row with a type")
+                        }
+                        AbsType::RowExtend(Ident(id), Some(ty), rest) => {
+                            let field_contract = ty.contract_open(h.clone(), pol, sy);
+                            let rest_contract = form(*rest, h, pol, sy);
+
+                            RichTerm::app(
+                                RichTerm::app(
+                                    RichTerm::app(
+                                        RichTerm::var("record_extend".to_string()),
+                                        Term::Str(id).into(),
+                                    ),
+                                    field_contract,
+                                ),
+                                rest_contract,
+                            )
+                        }
+                        // Polymorphic tails are not enforced yet: the remaining fields are left
+                        // unchecked.
+                        AbsType::Var(_) => RichTerm::var("dyn".to_string()),
+                        not_row => panic!("It should be a row!! {:?}", not_row),
+                    }
+                }
+
+                RichTerm::app(
+                    RichTerm::var("record".to_string()),
+                    form(*r.clone(), h, pol, sy),
+                )
+            }
+            AbsType::DynRecord(ref ty) => RichTerm::app(
+                RichTerm::var("dyn_record".to_string()),
+                ty.contract_open(h, pol, sy),
+            ),
         }
     }
 
@@ -264,6 +333,46 @@ impl Types {
         }
     }
 
+    /// Return the fields of a static record type, in order. Return `None` if the type is not a
+    /// static record type.
+    ///
+    /// If the row is open, as in `{ {| a: Num | r |} }`, these are the fields which are known to
+    /// exist.
+    pub fn record_fields(&self) -> Option<Vec<Ident>> {
+        match &self.0 {
+            AbsType::StaticRecord(row) => {
+                let mut fields = Vec::new();
+                let mut row = row.as_ref();
+
+                while let AbsType::RowExtend(id, _, tail) = &row.0 {
+                    fields.push(id.clone());
+                    row = tail;
+                }
+
+                Some(fields)
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the tags of an enum type, in order. Return `None` if the type is not an enum type.
+    pub fn enum_tags(&self) -> Option<Vec<Ident>> {
+        match &self.0 {
+            AbsType::Enum(row) => {
+                let mut tags = Vec::new();
+                let mut row = row.as_ref();
+
+                while let AbsType::RowExtend(id, _, tail) = &row.0 {
+                    tags.push(id.clone());
+                    row = tail;
+                }
+
+                Some(tags)
+            }
+            _ => None,
+        }
+    }
+
     /// Find a nested binding in a record row type. The nested field is given as a list of
     /// successive fields, that is, as a path. Return `None` if there is no such binding, if the
     /// type is not a row type, or if the final row is an enum row.
@@ -291,6 +400,8 @@ impl Types {
     }
 }
 
+/// Format a type in the syntax of type annotations, which can be parsed back by
+/// [`Types::parse`](struct.Types.html#method.parse).
 impl fmt::Display for Types {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self.0 {
@@ -298,7 +409,13 @@ impl fmt::Display for Types {
             AbsType::Num() => write!(f, "Num"),
             AbsType::Bool() => write!(f, "Bool"),
             AbsType::Str() => write!(f, "Str"),
-            AbsType::List() => write!(f, "List"),
+            AbsType::List(ty) => match ty.0 {
+                AbsType::Dyn() => write!(f, "List"),
+                AbsType::Arrow(_, _) | AbsType::Forall(_, _) | AbsType::List(_) => {
+                    write!(f, "List ({})", ty)
+                }
+                _ => write!(f, "List {}", ty),
+            },
             AbsType::Sym() => write!(f, "Sym"),
             AbsType::Flat(ref t) => write!(f, "#{}", t.as_ref().shallow_repr()),
             AbsType::Var(Ident(ref var)) => write!(f, "{}", var),
@@ -328,10 +445,17 @@ impl fmt::Display for Types {
                     _ => write!(f, ", {}", tail),
                 }
             }
-            AbsType::Arrow(dom, codom) => match dom.0 {
-                AbsType::Arrow(_, _) => write!(f, "({}) -> {}", dom, codom),
-                _ => write!(f, "{} -> {}", dom, codom),
-            },
+            AbsType::Arrow(dom, codom) => {
+                match dom.0 {
+                    AbsType::Arrow(_, _) | AbsType::Forall(_, _) => write!(f, "({}) -> ", dom)?,
+                    _ => write!(f, "{} -> ", dom)?,
+                }
+
+                match codom.0 {
+                    AbsType::Forall(_, _) => write!(f, "({})", codom),
+                    _ => write!(f, "{}", codom),
+                }
+            }
         }
     }
 }
@@ -354,7 +478,9 @@ mod test {
         let rt = TermParser::new().parse(id, Lexer::new(&wrapper)).unwrap();
 
         match *rt.term {
-            Term::Contract(ty, _) => ty,
+            Term::MetaValue(mut meta) if meta.contracts.len() == 1 => {
+                meta.contracts.remove(0).types
+            }
             _ => panic!("types::test::parse_type(): expected contract"),
         }
     }
@@ -377,6 +503,9 @@ mod test {
         assert_format_eq("(Num -> Num) -> (Num -> Num) -> Num -> Num");
         assert_format_eq("((Num -> Num) -> Num) -> Num");
 
+        assert_format_eq("List Num -> List");
+        assert_format_eq("List (List Str)");
+
         assert_format_eq("{_: Str}");
         assert_format_eq("{_: (Str -> Str) -> Str}");
 
@@ -386,4 +515,46 @@ mod test {
         assert_format_eq("< (| a, b, c, d |) >");
         assert_format_eq("< (| tag1, tag2, tag3 | r) >");
     }
+
+    /// Assert that parsing a type, formatting it and parsing the result gives back the same type.
+    fn assert_round_trip(s: &str) {
+        // Each type is parsed in a file database of its own, such that the positions of the
+        // contracts of flat types are the same.
+        let ty = Types::parse(s, &mut Files::new()).unwrap();
+        assert_eq!(
+            Types::parse(&format!("{}", ty), &mut Files::new()).unwrap(),
+            ty
+        );
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        assert_round_trip("Num");
+        assert_round_trip("List -> Dyn");
+        assert_round_trip("List Num -> List (List #Port)");
+        assert_round_trip("List (Num -> Num) -> List");
+        assert_round_trip("forall a. List a -> { {| x: List {_: Num} |} }");
+        assert_round_trip("forall a. a -> a");
+        assert_round_trip("forall a b. a -> b -> a");
+        assert_round_trip("forall a. forall b. a -> b");
+        assert_round_trip("(forall a. a -> a) -> Num");
+        assert_round_trip("Num -> (forall a. a -> a)");
+        assert_round_trip("(Num -> Num) -> Num");
+        assert_round_trip("{_: forall a. a -> Num}");
+        assert_round_trip("{ {| x: Num, f: forall a. a -> a, y: Str | r} }");
+        assert_round_trip("{ {| |} }");
+        assert_round_trip("forall r. < (| a, b | r) > -> < (| c |) >");
+
+        assert_format_eq("forall a b. a -> b");
+        assert_format_eq("(forall a. a -> a) -> Num");
+        assert_format_eq("Num -> (forall a. a -> a)");
+        let mut files = Files::new();
+        assert_eq!(
+            Types::parse("forall a b. a -> b", &mut files).unwrap(),
+            parse_type("forall a b. a -> b")
+        );
+
+        assert!(Types::parse("Num ->", &mut files).is_err());
+        assert!(Types::parse("forall . Num", &mut files).is_err());
+    }
 }
@@ -0,0 +1,268 @@
+//! Live reloading of programs.
+//!
+//! A [`WatchedProgram`](./struct.WatchedProgram.html) monitors the files of a program, that is its
+//! main file and the files and directories it imports, and evaluates the program again whenever
+//! one of them changes. Only the modified files are reloaded: the other ones are not parsed again.
+//! Changes are detected by polling, which requires no support from the platform. A file whose size
+//! and modification time did not change is considered unchanged, unless it was modified shortly
+//! before it was last read: modification times may be too coarse to notice two writes in a row,
+//! so the content of such a file is hashed and compared instead. The content of a directory is
+//! the list of its entries, such that adding or removing a file is noticed.
+use crate::collections::{HashMap, HashSet};
+use crate::error::Error;
+use crate::program::Program;
+use crate::term::Term;
+use codespan::FileId;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// The coarsest granularity of modification times among common file systems, which is the one of
+/// FAT. A file modified less than this duration before it was read may be modified again without
+/// its modification time changing.
+const MTIME_GRANULARITY: Duration = Duration::from_secs(2);
+
+/// The observed state of a file or a directory.
+struct Stamp {
+    /// The size of the file, as reported by its metadata.
+    len: u64,
+    /// The modification time of the file, if supported by the platform.
+    modified: Option<SystemTime>,
+    /// The SHA-256 hash of the content of a file, or of the names of the entries of a directory.
+    hash: Vec<u8>,
+}
+
+impl Stamp {
+    /// Read the state of a file or a directory. Return `None` if it could not be read.
+    fn read(path: &Path) -> Option<Stamp> {
+        // The metadata is read first, such that a write happening in between is noticed at the
+        // next check.
+        let metadata = fs::metadata(path).ok()?;
+        let hash = if metadata.is_dir() {
+            let mut names = fs::read_dir(path)
+                .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+                .ok()?
+                .into_iter()
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            names.sort();
+            Sha256::digest(names.join("\n").as_bytes()).to_vec()
+        } else {
+            Sha256::digest(&fs::read(path).ok()?).to_vec()
+        };
+
+        Some(Stamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            hash,
+        })
+    }
+}
+
+/// A file or a directory of a watched program.
+struct WatchedFile {
+    path: PathBuf,
+    /// The last observed state of the file, or `None` if it could not be read.
+    stamp: Option<Stamp>,
+    /// The time at which the file was last read.
+    read_at: SystemTime,
+}
+
+impl WatchedFile {
+    fn new(path: PathBuf) -> WatchedFile {
+        let read_at = SystemTime::now();
+        let stamp = Stamp::read(&path);
+        WatchedFile {
+            path,
+            stamp,
+            read_at,
+        }
+    }
+
+    /// Check if the content of the file changed since it was last read, and update its state.
+    fn changed(&mut self) -> bool {
+        if let (Some(stamp), Ok(metadata)) = (self.stamp.as_ref(), fs::metadata(&self.path)) {
+            let modified = metadata.modified().ok();
+            let settled = modified
+                .and_then(|modified| modified.checked_add(MTIME_GRANULARITY))
+                .map_or(false, |settled| settled < self.read_at);
+
+            if settled && stamp.len == metadata.len() && stamp.modified == modified {
+                return false;
+            }
+        }
+
+        self.read_at = SystemTime::now();
+        let current = Stamp::read(&self.path);
+        let changed = current.as_ref().map(|stamp| &stamp.hash)
+            != self.stamp.as_ref().map(|stamp| &stamp.hash);
+        self.stamp = current;
+        changed
+    }
+}
+
+/// A program whose files are watched for changes.
+pub struct WatchedProgram {
+    program: Program,
+    /// The files and the directories of the program.
+    files: HashMap<FileId, WatchedFile>,
+    /// The files whose last reload failed, which are reloaded again at the next change.
+    failed: HashSet<FileId>,
+}
+
+impl WatchedProgram {
+    /// Create a watched program by reading from a file.
+    pub fn new_from_file<P: AsRef<Path>>(path: P) -> io::Result<WatchedProgram> {
+        Ok(WatchedProgram::new(Program::new_from_file(path)?))
+    }
+
+    /// Watch an existing program.
+    pub fn new(program: Program) -> WatchedProgram {
+        WatchedProgram {
+            program,
+            files: HashMap::default(),
+            failed: HashSet::default(),
+        }
+    }
+
+    /// Access the underlying program, for example to report errors.
+    pub fn program(&mut self) -> &mut Program {
+        &mut self.program
+    }
+
+    /// Evaluate the program, and start watching the files it has loaded.
+    pub fn eval(&mut self) -> Result<Term, Error> {
+        let result = self.program.eval();
+
+        for (file_id, path) in self.program.source_files() {
+            self.files
+                .entry(file_id)
+                .or_insert_with(|| WatchedFile::new(path));
+        }
+
+        result
+    }
+
+    /// Check if files of the program changed since the last evaluation. If this is the case,
+    /// reload them and evaluate the program again. Return `None` if no file changed.
+    pub fn poll(&mut self) -> Option<Result<Term, Error>> {
+        let mut changed = HashSet::default();
+
+        for (file_id, file) in self.files.iter_mut() {
+            if file.changed() {
+                changed.insert(*file_id);
+            }
+        }
+
+        if changed.is_empty() {
+            return None;
+        }
+
+        changed.extend(self.failed.drain());
+        let mut error = None;
+
+        for file_id in changed {
+            if let Err(err) = self.program.reload(file_id) {
+                self.failed.insert(file_id);
+                error.get_or_insert(err);
+            }
+        }
+
+        match error {
+            Some(err) => Some(Err(err)),
+            None => Some(self.eval()),
+        }
+    }
+
+    /// Evaluate the program, then evaluate it again each time some of its files change.
+    ///
+    /// Files are checked every `interval`. `callback` receives the program, which can be used to
+    /// report errors, and the result of each evaluation. It returns `false` to stop watching.
+    pub fn watch<F>(&mut self, interval: Duration, mut callback: F)
+    where
+        F: FnMut(&mut Program, Result<Term, Error>) -> bool,
+    {
+        let result = self.eval();
+        if !callback(&mut self.program, result) {
+            return;
+        }
+
+        loop {
+            thread::sleep(interval);
+
+            if let Some(result) = self.poll() {
+                if !callback(&mut self.program, result) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload() {
+        let dir = std::env::temp_dir().join(format!("nickel-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.ncl");
+        let imported = dir.join("a.ncl");
+        // Changes are noticed even if the size and the modification time of the file stay the
+        // same, as when writing "2" in place of "1" right after.
+        let write = |path: &Path, content: &str| fs::write(path, content).unwrap();
+
+        write(&imported, "1");
+        write(&main, &format!("(import \"{}\") + 1", imported.display()));
+
+        let mut w = WatchedProgram::new_from_file(&main).unwrap();
+        assert_eq!(w.eval(), Ok(Term::Num(2.)));
+        assert_eq!(w.poll(), None);
+
+        write(&imported, "2");
+        assert_eq!(w.poll(), Some(Ok(Term::Num(3.))));
+        assert_eq!(w.poll(), None);
+
+        write(&imported, "2 +");
+        match w.poll() {
+            Some(Err(Error::ParseError(_))) => (),
+            res => panic!("expected a parse error, got {:?}", res),
+        };
+        assert_eq!(w.poll(), None);
+
+        write(&imported, "5");
+        assert_eq!(w.poll(), Some(Ok(Term::Num(6.))));
+
+        write(&main, &format!("(import \"{}\") + 10", imported.display()));
+        assert_eq!(w.poll(), Some(Ok(Term::Num(15.))));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reload_dir() {
+        let dir = std::env::temp_dir().join(format!("nickel-watch-dir-{}", std::process::id()));
+        let sub = dir.join("sub");
+        fs::create_dir_all(&sub).unwrap();
+        let main = dir.join("main.ncl");
+        fs::write(sub.join("a.ncl"), "1").unwrap();
+        fs::write(&main, &format!("(import \"{}/\").b", sub.display())).unwrap();
+
+        let mut w = WatchedProgram::new_from_file(&main).unwrap();
+        w.eval().unwrap_err();
+        assert_eq!(w.poll(), None);
+
+        // Files added to an imported directory are noticed.
+        fs::write(sub.join("b.ncl"), "2").unwrap();
+        assert_eq!(w.poll(), Some(Ok(Term::Num(2.))));
+
+        fs::write(sub.join("b.ncl"), "3").unwrap();
+        assert_eq!(w.poll(), Some(Ok(Term::Num(3.))));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}